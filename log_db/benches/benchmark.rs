@@ -27,8 +27,7 @@ pub fn upsert_compacted(c: &mut Criterion) {
         group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &_size| {
             b.iter(|| {
                 let inst = random_inst(0, size as i64 + 1);
-                let result = db.upsert(black_box(inst)).unwrap();
-                assert!(result == ());
+                db.upsert(black_box(inst)).unwrap();
             });
         });
     }
@@ -68,7 +67,11 @@ pub fn delete_existing_compacted(c: &mut Criterion) {
 pub fn upsert_write_durability(c: &mut Criterion) {
     let mut group = c.benchmark_group("upsert_write_durability");
 
-    for mode in [WriteDurability::Flush, WriteDurability::FlushSync] {
+    for mode in [
+        WriteDurability::Flush,
+        WriteDurability::FlushSync,
+        WriteDurability::FlushDataSync,
+    ] {
         group.bench_with_input(BenchmarkId::from_parameter(&mode), &mode, |b, _mode| {
             let data_dir_obj = tempfile::tempdir().expect("Failed to get tmpdir");
             let data_dir = &data_dir_obj
@@ -83,8 +86,7 @@ pub fn upsert_write_durability(c: &mut Criterion) {
 
             b.iter(|| {
                 let inst = random_inst(0, 1000);
-                let result = db.upsert(black_box(inst)).unwrap();
-                assert!(result == ());
+                db.upsert(black_box(inst)).unwrap();
             });
         });
     }
@@ -0,0 +1,129 @@
+use super::*;
+
+/// An aggregation function for `DB::aggregate`. Null values are ignored by all aggregates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    /// The number of records with a non-null value, as an `Int`.
+    Count,
+    /// The smallest value, or `Null` if there are no values.
+    Min,
+    /// The greatest value, or `Null` if there are no values.
+    Max,
+    /// The sum of the values of a numeric field, of the same type as the field, or `Null` if there are no values.
+    Sum,
+    /// The average of the values of a numeric field as a `Decimal`, or `Null` if there are no values.
+    Avg,
+}
+
+/// Accumulates field values into the result of an aggregate.
+pub struct Accumulator {
+    aggregate: Aggregate,
+    primitive: PrimitiveType,
+    count: u64,
+    sum: Decimal,
+    extreme: Option<IndexableValue>,
+}
+
+impl Accumulator {
+    /// Create an accumulator for the values of a field of type `field_type`.
+    /// Returns an error if the aggregate is not applicable to the type.
+    pub fn new(aggregate: Aggregate, field_type: &Type) -> DBResult<Accumulator> {
+        match (aggregate, &field_type.primitive) {
+            (Aggregate::Sum | Aggregate::Avg, PrimitiveType::Int | PrimitiveType::Decimal) => {}
+            (Aggregate::Sum | Aggregate::Avg, _) => {
+                return Err(DBError::ValidationError(format!(
+                    "Cannot compute {:?} of non-numeric type {:?}",
+                    aggregate, field_type.primitive
+                )))
+            }
+            (
+                Aggregate::Min | Aggregate::Max,
+                PrimitiveType::Bytes
+                | PrimitiveType::FixedBytes(_)
+                | PrimitiveType::Json
+                | PrimitiveType::Record(_)
+                | PrimitiveType::GeoPoint,
+            ) => {
+                return Err(DBError::ValidationError(format!(
+                    "Cannot compute {:?} of {:?}",
+                    aggregate, field_type.primitive
+                )))
+            }
+            _ => {}
+        }
+
+        Ok(Accumulator {
+            aggregate,
+            primitive: field_type.primitive.clone(),
+            count: 0,
+            sum: Decimal::ZERO,
+            extreme: None,
+        })
+    }
+
+    /// Add `count` occurrences of `value`.
+    pub fn add(&mut self, value: &Value, count: u64) -> DBResult<()> {
+        if let Value::Null = value {
+            return Ok(());
+        }
+        self.count += count;
+
+        match self.aggregate {
+            Aggregate::Count => {}
+            Aggregate::Sum | Aggregate::Avg => {
+                let number = match value {
+                    Value::Int(i) => Decimal::from(*i),
+                    Value::Decimal(d) => *d,
+                    _ => return Ok(()),
+                };
+                self.sum = number
+                    .checked_mul(Decimal::from(count))
+                    .and_then(|product| self.sum.checked_add(product))
+                    .ok_or(DBError::ValidationError(format!(
+                        "{:?} overflowed",
+                        self.aggregate
+                    )))?;
+            }
+            Aggregate::Min | Aggregate::Max => {
+                let value = match value.as_indexable() {
+                    Some(value) => value,
+                    None => return Ok(()),
+                };
+                let replace = match &self.extreme {
+                    None => true,
+                    Some(extreme) if self.aggregate == Aggregate::Min => value < *extreme,
+                    Some(extreme) => value > *extreme,
+                };
+                if replace {
+                    self.extreme = Some(value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> DBResult<Value> {
+        if self.count == 0 && self.aggregate != Aggregate::Count {
+            return Ok(Value::Null);
+        }
+
+        match self.aggregate {
+            Aggregate::Count => Ok(Value::Int(self.count as i64)),
+            Aggregate::Min | Aggregate::Max => {
+                Ok(self.extreme.map(Value::from).unwrap_or(Value::Null))
+            }
+            Aggregate::Sum => match self.primitive {
+                PrimitiveType::Int => i64::try_from(self.sum)
+                    .map(Value::Int)
+                    .map_err(|_| DBError::ValidationError("Sum does not fit in an Int".to_owned())),
+                _ => Ok(Value::Decimal(self.sum)),
+            },
+            Aggregate::Avg => self
+                .sum
+                .checked_div(Decimal::from(self.count))
+                .map(Value::Decimal)
+                .ok_or(DBError::ValidationError("Avg overflowed".to_owned())),
+        }
+    }
+}
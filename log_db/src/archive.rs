@@ -0,0 +1,298 @@
+use super::*;
+
+/// Object storage that the data files of cold segments are moved to, e.g. S3, see
+/// `ConfigBuilder::archive`. Objects are named after the data files they hold.
+pub trait ArchiveStore: Send + Sync {
+    /// Store the file at `path` as the object `name`, replacing any object of the same name.
+    fn upload(&self, name: &str, path: &Path) -> io::Result<()>;
+
+    /// Write the object `name` into the file at `path`, which exists and is empty.
+    fn download(&self, name: &str, path: &Path) -> io::Result<()>;
+
+    /// Remove the object `name`. Removing an object that does not exist is not an error.
+    fn remove(&self, name: &str) -> io::Result<()>;
+}
+
+/// An `ArchiveStore` that keeps the objects as files in a directory, e.g. on a network filesystem.
+pub struct DirectoryArchive {
+    pub path: PathBuf,
+}
+
+impl ArchiveStore for DirectoryArchive {
+    fn upload(&self, name: &str, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(&self.path)?;
+        // Copied next to the object first, so that a failed upload leaves the old object in place
+        let tmp_file = fs::NamedTempFile::new_in(&self.path)?;
+        fs::copy(path, tmp_file.path())?;
+        tmp_file.as_file().sync_all()?;
+        tmp_file
+            .persist(self.path.join(name))
+            .map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    fn download(&self, name: &str, path: &Path) -> io::Result<()> {
+        fs::copy(self.path.join(name), path)?;
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        match fs::remove_file(self.path.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The data files that have been uploaded to the archive store, with the length and checksum of
+/// the records of their segment, kept in the `archived` file of the data directory. A listed data
+/// file may also have a local copy, fetched back for reading.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchivedFiles {
+    pub files: BTreeMap<Uuid, ArchivedFile>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchivedFile {
+    pub data_len: u64,
+    pub data_checksum: u32,
+}
+
+impl ArchivedFiles {
+    /// Read the archived files of a data directory, which has none if it has no `archived` file.
+    pub fn read(data_dir_path: &Path) -> DBResult<ArchivedFiles> {
+        let contents = match fs::read_to_string(data_dir_path.join(ARCHIVED_FILENAME)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ArchivedFiles::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut files = BTreeMap::new();
+        for line in contents.lines().filter(|line| !line.is_empty()) {
+            let invalid =
+                || DBError::ConsistencyError(format!("Invalid line in archived file: {}", line));
+            let mut parts = line.split(' ');
+            let (Some(uuid), Some(data_len), Some(data_checksum), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid());
+            };
+            files.insert(
+                Uuid::parse_str(uuid).map_err(|_| invalid())?,
+                ArchivedFile {
+                    data_len: data_len.parse().map_err(|_| invalid())?,
+                    data_checksum: data_checksum.parse().map_err(|_| invalid())?,
+                },
+            );
+        }
+        Ok(ArchivedFiles { files })
+    }
+
+    /// Atomically replace the `archived` file of the data directory. It is persisted along with the
+    /// directory, since a local data file may only be removed once its archival is on disk.
+    pub fn write(&self, data_dir_path: &Path) -> DBResult<()> {
+        let mut tmp_file = fs::NamedTempFile::new_in(data_dir_path)?;
+        for (uuid, file) in &self.files {
+            writeln!(
+                tmp_file,
+                "{} {} {}",
+                uuid, file.data_len, file.data_checksum
+            )?;
+        }
+        tmp_file.as_file().sync_all()?;
+        tmp_file
+            .persist(data_dir_path.join(ARCHIVED_FILENAME))
+            .map_err(|e| e.error)?;
+        fs::sync_dir(data_dir_path)?;
+        Ok(())
+    }
+
+    /// Whether the data file `uuid` is archived and has no local copy.
+    pub fn is_archived_only(data_dir_path: &Path, uuid: &Uuid) -> DBResult<bool> {
+        Ok(!fs::exists(data_dir_path.join(uuid.to_string()))?
+            && ArchivedFiles::read(data_dir_path)?.files.contains_key(uuid))
+    }
+}
+
+/// The records of the segment of an archived data file with only the values of the indexed fields,
+/// kept in the `archived_keys` directory of the data directory, so that the memtables can be built
+/// without fetching the data file. The other values are replaced with `Value::Null`. The keys are
+/// serialized as `[metadata length][field count][fields]`, followed by
+/// `[row index][record length][record]` for each record and a checksum.
+pub struct ArchivedKeys {
+    /// The length of the metadata file of the segment, which the keys are valid for
+    pub metadata_len: u64,
+    /// The indexes of the fields whose values are kept
+    pub fields: BTreeSet<usize>,
+    /// The records with the indexes of their metadata rows
+    pub records: Vec<(u64, Record)>,
+}
+
+impl ArchivedKeys {
+    pub fn new(
+        metadata_len: u64,
+        fields: BTreeSet<usize>,
+        records: impl Iterator<Item = (u64, Record)>,
+    ) -> ArchivedKeys {
+        let records = records
+            .map(|(index, mut record)| {
+                for (field_index, value) in record.values.iter_mut().enumerate() {
+                    if !fields.contains(&field_index) {
+                        *value = Value::Null;
+                    }
+                }
+                (index, record)
+            })
+            .collect();
+        ArchivedKeys {
+            metadata_len,
+            fields,
+            records,
+        }
+    }
+
+    fn path(data_dir_path: &Path, uuid: &Uuid) -> PathBuf {
+        data_dir_path
+            .join(ARCHIVED_KEYS_DIRNAME)
+            .join(uuid.to_string())
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(self.metadata_len.to_be_bytes());
+        bytes.extend((self.fields.len() as u16).to_be_bytes());
+        for &field_index in &self.fields {
+            bytes.extend((field_index as u16).to_be_bytes());
+        }
+        for (index, record) in &self.records {
+            let serialized = record.serialize();
+            bytes.extend(index.to_be_bytes());
+            bytes.extend((serialized.len() as u32).to_be_bytes());
+            bytes.extend(serialized);
+        }
+        bytes.extend(crc32fast::hash(&bytes).to_be_bytes());
+        bytes
+    }
+
+    /// Returns `None` if the keys were not completely written.
+    fn deserialize(bytes: &[u8]) -> Option<ArchivedKeys> {
+        let (content, checksum) = bytes.split_at_checked(bytes.len().checked_sub(4)?)?;
+        if crc32fast::hash(content).to_be_bytes() != checksum {
+            return None;
+        }
+
+        fn take<'b>(rest: &mut &'b [u8], len: usize) -> Option<&'b [u8]> {
+            let (taken, remaining) = rest.split_at_checked(len)?;
+            *rest = remaining;
+            Some(taken)
+        }
+        let mut rest = content;
+        let metadata_len = u64::from_be_bytes(take(&mut rest, 8)?.try_into().unwrap());
+        let fields_len = u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap());
+        let mut fields = BTreeSet::new();
+        for _ in 0..fields_len {
+            fields.insert(u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap()) as usize);
+        }
+        let mut records = vec![];
+        while !rest.is_empty() {
+            let index = u64::from_be_bytes(take(&mut rest, 8)?.try_into().unwrap());
+            let record_len = u32::from_be_bytes(take(&mut rest, 4)?.try_into().unwrap());
+            records.push((
+                index,
+                Record::deserialize(take(&mut rest, record_len as usize)?),
+            ));
+        }
+        Some(ArchivedKeys {
+            metadata_len,
+            fields,
+            records,
+        })
+    }
+
+    /// Read the keys of the archived data file `uuid`, if they were written completely.
+    pub fn read(data_dir_path: &Path, uuid: &Uuid) -> DBResult<Option<ArchivedKeys>> {
+        match fs::read(ArchivedKeys::path(data_dir_path, uuid)) {
+            Ok(bytes) => Ok(ArchivedKeys::deserialize(&bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Atomically write the keys of the archived data file `uuid`.
+    pub fn write(&self, data_dir_path: &Path, uuid: &Uuid) -> DBResult<()> {
+        let keys_dir_path = data_dir_path.join(ARCHIVED_KEYS_DIRNAME);
+        fs::create_dir_all(&keys_dir_path)?;
+        let mut tmp_file = fs::NamedTempFile::new_in(&keys_dir_path)?;
+        tmp_file.write_all(&self.serialize())?;
+        tmp_file.as_file().sync_all()?;
+        tmp_file
+            .persist(ArchivedKeys::path(data_dir_path, uuid))
+            .map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// Whether the keys of the archived data file `uuid` have been written.
+    pub fn exists(data_dir_path: &Path, uuid: &Uuid) -> DBResult<bool> {
+        Ok(fs::exists(ArchivedKeys::path(data_dir_path, uuid))?)
+    }
+
+    pub fn remove(data_dir_path: &Path, uuid: &Uuid) -> DBResult<()> {
+        match fs::remove_file(ArchivedKeys::path(data_dir_path, uuid)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Download the archived data file `uuid` into the data directory, unless another reader has
+/// already fetched it. The download is checked against the checksum recorded at archival.
+pub fn fetch_archived(
+    data_dir_path: &Path,
+    store: &dyn ArchiveStore,
+    uuid: &Uuid,
+    archived: &ArchivedFile,
+) -> DBResult<()> {
+    let tmp_file = fs::NamedTempFile::new_in(data_dir_path)?;
+    store.download(&uuid.to_string(), tmp_file.path())?;
+
+    let (read_len, data_checksum) =
+        crate::manifest::checksum_prefix(READ_MODE.open(tmp_file.path())?, archived.data_len)?;
+    if read_len != archived.data_len || data_checksum != archived.data_checksum {
+        return Err(DBError::ConsistencyError(format!(
+            "Archived data file {} does not match the checksum recorded when it was archived",
+            uuid
+        )));
+    }
+
+    match tmp_file.persist_noclobber(data_dir_path.join(uuid.to_string())) {
+        Err(e) if e.error.kind() != io::ErrorKind::AlreadyExists => Err(e.error.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Open the data file `uuid` for reading, fetching it from `store` first if it has been archived
+/// and has no local copy.
+pub fn open_data_file(
+    data_dir_path: &Path,
+    store: Option<&dyn ArchiveStore>,
+    uuid: &Uuid,
+) -> DBResult<fs::File> {
+    let data_path = data_dir_path.join(uuid.to_string());
+    let not_found = match READ_MODE.open(&data_path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => e,
+        result => return Ok(result?),
+    };
+    let Some(archived) = ArchivedFiles::read(data_dir_path)?.files.get(uuid).copied() else {
+        return Err(not_found.into());
+    };
+    let Some(store) = store else {
+        return Err(DBError::ValidationError(format!(
+            "Data file {} is archived, but no archive store is configured",
+            uuid
+        )));
+    };
+
+    debug!("Fetching archived data file {}", uuid);
+    fetch_archived(data_dir_path, store, uuid, &archived)?;
+    Ok(READ_MODE.open(&data_path)?)
+}
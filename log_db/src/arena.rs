@@ -0,0 +1,253 @@
+use super::*;
+
+/// A reusable buffer for the records read by `DB::batch_find_by_in` and `DB::scan_filter_in`.
+///
+/// The serialized records of a batch are read back to back into a single buffer, and their values
+/// are decoded on access into `ValueRef`s borrowing from it, instead of allocating a `Vec<Value>`
+/// and the strings and byte vectors of each record. The whole batch is freed at once when the
+/// arena is cleared for the next query, and the capacity of the buffer is reused, so a long-lived
+/// arena reaches a steady state where reads do not allocate at all.
+#[derive(Debug, Default)]
+pub struct RecordArena {
+    buf: Vec<u8>,
+    spans: Vec<ArenaSpan>,
+    /// How the values of the records are stored in the log, decoded on access.
+    log_encoding: LogEncoding,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ArenaSpan {
+    tag: usize,
+    start: usize,
+    end: usize,
+}
+
+impl RecordArena {
+    pub fn new() -> RecordArena {
+        RecordArena::default()
+    }
+
+    /// Create an arena whose buffer can hold `bytes` bytes of serialized records before growing.
+    pub fn with_capacity(bytes: usize) -> RecordArena {
+        RecordArena {
+            buf: Vec::with_capacity(bytes),
+            spans: vec![],
+            log_encoding: LogEncoding::default(),
+        }
+    }
+
+    /// Drop the records in the arena, keeping the allocated capacity.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.spans.clear();
+    }
+
+    /// Number of records in the arena.
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// Number of bytes of serialized records in the arena.
+    pub fn bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn get(&self, i: usize) -> Option<ArenaRecord<'_>> {
+        self.spans.get(i).map(|span| self.record(span))
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = ArenaRecord<'_>> {
+        self.spans.iter().map(|span| self.record(span))
+    }
+
+    fn record(&self, span: &ArenaSpan) -> ArenaRecord<'_> {
+        ArenaRecord {
+            tag: span.tag,
+            bytes: &self.buf[span.start..span.end],
+            log_encoding: &self.log_encoding,
+        }
+    }
+
+    /// Set how the values of the records that will be read into the arena are stored.
+    pub(crate) fn set_log_encoding(&mut self, log_encoding: &LogEncoding) {
+        self.log_encoding = log_encoding.clone();
+    }
+
+    /// The buffer that records are read into. Bytes appended to it become a record with `commit`.
+    pub(crate) fn buf_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+
+    /// Offset at which the next record will start.
+    pub(crate) fn next_start(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// The bytes appended to the buffer since `start`, as a record.
+    pub(crate) fn pending(&self, start: usize) -> ArenaRecord<'_> {
+        ArenaRecord {
+            tag: 0,
+            bytes: &self.buf[start..],
+            log_encoding: &self.log_encoding,
+        }
+    }
+
+    /// Turn the bytes appended since `start` into a record.
+    pub(crate) fn commit(&mut self, tag: usize, start: usize) {
+        self.spans.push(ArenaSpan {
+            tag,
+            start,
+            end: self.buf.len(),
+        });
+    }
+
+    /// Discard the bytes appended since `start`.
+    pub(crate) fn rollback(&mut self, start: usize) {
+        self.buf.truncate(start);
+    }
+
+    /// Replace the bytes appended since `start` with `record`.
+    pub(crate) fn replace_pending(&mut self, start: usize, record: &Record) {
+        self.buf.truncate(start);
+        self.buf
+            .extend_from_slice(&self.log_encoding.serialize(record));
+    }
+
+    /// Append a record that was read some other way.
+    pub(crate) fn push(&mut self, tag: usize, record: &Record) {
+        let start = self.next_start();
+        self.buf
+            .extend_from_slice(&self.log_encoding.serialize(record));
+        self.commit(tag, start);
+    }
+
+    /// Discard the last committed record.
+    pub(crate) fn pop(&mut self) {
+        if let Some(span) = self.spans.pop() {
+            self.buf.truncate(span.start);
+        }
+    }
+}
+
+/// A record in a `RecordArena`, with its values decoded on access.
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaRecord<'a> {
+    tag: usize,
+    bytes: &'a [u8],
+    log_encoding: &'a LogEncoding,
+}
+
+impl<'a> ArenaRecord<'a> {
+    /// Index of the queried value the record matched, like the tags of `DB::batch_find_by`.
+    /// Always 0 for records of a scan.
+    pub fn tag(&self) -> usize {
+        self.tag
+    }
+
+    pub fn meta(&self) -> RecordMeta {
+        RecordMeta {
+            version: u64::from_be_bytes(self.bytes[1..1 + 8].try_into().unwrap()),
+            timestamp: micros_to_timestamp(u64::from_be_bytes(
+                self.bytes[1 + 8..1 + 8 + 8].try_into().unwrap(),
+            )),
+            deleted: self.bytes[0] == B_DELETED,
+        }
+    }
+
+    pub(crate) fn is_tombstone(&self) -> bool {
+        self.bytes[0] == B_TOMBSTONE
+    }
+
+    pub(crate) fn is_delta(&self) -> bool {
+        self.bytes[0] == B_MERGE
+    }
+
+    /// The values of the record in schema order.
+    pub fn values(&self) -> ValueRefs<'a> {
+        ValueRefs {
+            bytes: &self.bytes[1 + 8 + 8..],
+            index: 0,
+            log_encoding: self.log_encoding,
+        }
+    }
+
+    /// The value of the field at `index` in schema order.
+    pub fn get(&self, index: usize) -> Option<ValueRef<'a>> {
+        self.values().nth(index)
+    }
+
+    /// Copy the values out of the arena, e.g. for `Recordable::from_record`.
+    pub fn to_values(&self) -> Vec<Value> {
+        self.values().map(|value| value.to_value()).collect()
+    }
+
+    pub(crate) fn to_record(self) -> Record {
+        let mut record = Record::deserialize(self.bytes);
+        self.log_encoding.decode(&mut record);
+        record
+    }
+}
+
+/// An iterator over the values of an `ArenaRecord`.
+pub struct ValueRefs<'a> {
+    bytes: &'a [u8],
+    index: usize,
+    log_encoding: &'a LogEncoding,
+}
+
+impl<'a> Iterator for ValueRefs<'a> {
+    type Item = ValueRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let (value, consumed) = ValueRef::deserialize(self.bytes);
+        self.bytes = &self.bytes[consumed..];
+        self.index += 1;
+        Some(self.log_encoding.decode_ref(self.index - 1, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arena_records() {
+        let mut arena = RecordArena::new();
+        let mut record = Record::from(&[
+            Value::Int(7),
+            Value::String("foo".to_owned()),
+            Value::Null,
+            Value::Bytes(vec![1, 2]),
+        ]);
+        record.version = 3;
+
+        let start = arena.next_start();
+        arena.buf_mut().extend_from_slice(&record.serialize());
+        arena.commit(1, start);
+
+        let start = arena.next_start();
+        arena.buf_mut().extend_from_slice(&record.serialize());
+        arena.rollback(start);
+
+        assert_eq!(arena.len(), 1);
+        let arena_record = arena.get(0).unwrap();
+        assert_eq!(arena_record.tag(), 1);
+        assert_eq!(arena_record.meta(), record.meta());
+        assert_eq!(arena_record.get(1), Some(ValueRef::String("foo")));
+        assert_eq!(arena_record.get(3), Some(ValueRef::Bytes(&[1, 2])));
+        assert_eq!(arena_record.get(4), None);
+        assert_eq!(arena_record.to_values(), record.values);
+
+        let capacity = arena.buf.capacity();
+        arena.clear();
+        assert!(arena.is_empty());
+        assert_eq!(arena.buf.capacity(), capacity);
+    }
+}
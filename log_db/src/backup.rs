@@ -0,0 +1,318 @@
+use super::*;
+
+/// Back up the segments of the database in `data_dir_path` into `backup_dir_path`, which must not
+/// exist or be empty. Sealed segments that are listed identically in `prev_manifest`, the manifest of
+/// the previous backup, are skipped: sealed segments are immutable until compacted, and compaction
+/// and merges change their manifest entry. The active segment is always copied. Returns the manifest
+/// of the backup, which is also written into the backup directory.
+///
+/// The backup is made in a temporary directory next to `backup_dir_path` and moved into place once
+/// complete, so the backup directory either does not exist or holds a complete backup.
+///
+/// The files of sealed segments are hard-linked rather than copied where possible. This is safe
+/// because they are never modified in place: compaction and merges write new files and rename them
+/// over the old ones, which leaves the links pointing at the old contents. The active segment and
+/// the data file that it appends to are copied.
+///
+/// The caller must hold a lock that prevents writes for the duration of the backup.
+pub fn backup(
+    data_dir_path: &Path,
+    backup_dir_path: &Path,
+    prev_manifest: Option<&Manifest>,
+) -> DBResult<Manifest> {
+    let tmp_dir = staging_dir(backup_dir_path, "Backup", ".backup-")?;
+    let manifest = backup_segments(data_dir_path, tmp_dir.path(), prev_manifest)?;
+    move_into_place(tmp_dir, backup_dir_path)?;
+    Ok(manifest)
+}
+
+/// Link or copy the segments into the empty directory `backup_dir_path`, see `backup`.
+fn backup_segments(
+    data_dir_path: &Path,
+    backup_dir_path: &Path,
+    prev_manifest: Option<&Manifest>,
+) -> DBResult<Manifest> {
+    let manifest = match Manifest::read(data_dir_path)? {
+        Some(manifest) => manifest,
+        None => Manifest::compute(data_dir_path)?,
+    };
+
+    // Length of the prefix to copy of each data file. Consecutive segments may share a data file,
+    // so the copy must cover every segment that is copied.
+    let mut data_lens: BTreeMap<Uuid, u64> = BTreeMap::new();
+
+    for segment in &manifest.segments {
+        let unchanged = prev_manifest.is_some_and(|prev| prev.segments.contains(segment));
+        if unchanged {
+            continue;
+        }
+
+        let filename = metadata_filename(segment.segment_num);
+        link_or_copy(
+            &data_dir_path.join(&filename),
+            &backup_dir_path.join(&filename),
+        )?;
+        let data_len = data_lens.entry(segment.data_uuid).or_default();
+        *data_len = (*data_len).max(segment.data_len);
+    }
+
+    let active_uuid =
+        copy_metadata_file(data_dir_path, backup_dir_path, manifest.active_segment_num)?;
+    let active_data_len = fs::metadata(data_dir_path.join(active_uuid.to_string()))?.len();
+    data_lens.insert(active_uuid, active_data_len);
+
+    for (uuid, len) in data_lens {
+        // Archived data files stay in the archive store, which the backup shares
+        if ArchivedFiles::is_archived_only(data_dir_path, &uuid)? {
+            continue;
+        }
+        let from_path = data_dir_path.join(uuid.to_string());
+        let to_path = backup_dir_path.join(uuid.to_string());
+        // Only the data file of the active segment is appended to
+        if uuid != active_uuid {
+            link_or_copy(&from_path, &to_path)?;
+        } else {
+            copy_prefix(&from_path, &to_path, len)?;
+        }
+    }
+
+    copy_sequences_and_schema(data_dir_path, backup_dir_path)?;
+    manifest.write(backup_dir_path)?;
+    Ok(manifest)
+}
+
+/// Restore a chain of backups made with `backup_incremental` into `data_dir_path`, which must not
+/// exist or be empty. The chain is given in the order the backups were made, starting from a backup
+/// made without a previous manifest. Each sealed segment is taken from the newest backup that has a
+/// copy of it, and the copy is checked against the manifest of the newest backup.
+///
+/// The data directory is assembled in a temporary directory next to `data_dir_path`, and moved into
+/// place only once the restored segments match the manifest and the checksums of their records.
+/// Until then it is not a data directory that could be opened and written to.
+pub fn restore_chain(backup_dir_paths: &[PathBuf], data_dir_path: &Path) -> DBResult<()> {
+    let newest_backup = backup_dir_paths
+        .last()
+        .ok_or(DBError::ValidationError("No backups to restore".to_owned()))?;
+    let manifest = Manifest::read(newest_backup)?.ok_or_else(|| {
+        DBError::ConsistencyError(format!(
+            "Backup {} has no manifest, it may be incomplete",
+            newest_backup.display()
+        ))
+    })?;
+
+    manifest.validate()?;
+    let tmp_dir = staging_dir(data_dir_path, "Data", ".restore-")?;
+    let restored_path = tmp_dir.path();
+
+    // The source and length of each data file to restore. The longest copy of a data file covers
+    // all the segments that share it.
+    let mut data_sources: BTreeMap<Uuid, (&Path, u64)> = BTreeMap::new();
+    fn add_data_source<'a>(
+        data_sources: &mut BTreeMap<Uuid, (&'a Path, u64)>,
+        uuid: Uuid,
+        backup_dir_path: &'a Path,
+        len: u64,
+    ) {
+        let (source, source_len) = data_sources.entry(uuid).or_insert((backup_dir_path, len));
+        if len > *source_len {
+            *source = backup_dir_path;
+            *source_len = len;
+        }
+    }
+
+    for segment in &manifest.segments {
+        let backup_dir_path = backup_dir_paths
+            .iter()
+            .rev()
+            .find(|backup_dir_path| {
+                ManifestSegment::compute(backup_dir_path, segment.segment_num)
+                    .is_ok_and(|copy| copy == *segment)
+            })
+            .ok_or_else(|| {
+                DBError::ConsistencyError(format!(
+                    "No backup has an intact copy of segment {}",
+                    segment.segment_num
+                ))
+            })?;
+
+        copy_metadata_file(backup_dir_path, restored_path, segment.segment_num)?;
+        add_data_source(
+            &mut data_sources,
+            segment.data_uuid,
+            backup_dir_path,
+            segment.data_len,
+        );
+    }
+
+    let active_uuid =
+        copy_metadata_file(newest_backup, restored_path, manifest.active_segment_num)?;
+    let active_data_len = fs::metadata(newest_backup.join(active_uuid.to_string()))?.len();
+    add_data_source(
+        &mut data_sources,
+        active_uuid,
+        newest_backup,
+        active_data_len,
+    );
+
+    for (uuid, (backup_dir_path, len)) in data_sources {
+        if ArchivedFiles::is_archived_only(backup_dir_path, &uuid)? {
+            continue;
+        }
+        copy_prefix(
+            &backup_dir_path.join(uuid.to_string()),
+            &restored_path.join(uuid.to_string()),
+            len,
+        )?;
+    }
+
+    copy_sequences_and_schema(newest_backup, restored_path)?;
+    set_active_segment(restored_path, manifest.active_segment_num)?;
+    manifest.write(restored_path)?;
+
+    // The restored directory becomes a data directory that can be opened only once verified
+    let mut problems = manifest.verify(restored_path)?;
+    for segment_num in list_segment_numbers(restored_path)? {
+        problems.extend(verify_record_checksums(restored_path, segment_num)?);
+    }
+    if !problems.is_empty() {
+        return Err(DBError::ConsistencyError(format!(
+            "Restored data does not match the backup: {}",
+            problems.join("; ")
+        )));
+    }
+
+    fs::File::create(restored_path.join(INITIALIZED_FILENAME))?;
+    move_into_place(tmp_dir, data_dir_path)
+}
+
+/// Check the checksums of the metadata rows and records of a restored segment. Sealed segments
+/// are also covered by their manifest entry, but the active segment only by these checksums.
+fn verify_record_checksums(data_dir_path: &Path, segment_num: u16) -> DBResult<Vec<String>> {
+    let mut metadata_file = READ_MODE.open(data_dir_path.join(metadata_filename(segment_num)))?;
+    let metadata_header = read_metadata_header(&mut metadata_file)?;
+    // The manifest covers the archived data files, which are checked again when fetched
+    if ArchivedFiles::is_archived_only(data_dir_path, &metadata_header.uuid)? {
+        return Ok(vec![]);
+    }
+    metadata_file.seek(SeekFrom::Start(0))?;
+    let mut metadata_buf = vec![];
+    metadata_file.read_to_end(&mut metadata_buf)?;
+    let data = fs::read(data_dir_path.join(metadata_header.uuid.to_string()))?;
+
+    let mut problems = vec![];
+    for (index, row) in metadata_buf[METADATA_FILE_HEADER_SIZE..]
+        .chunks_exact(metadata_row_length(metadata_header.version))
+        .enumerate()
+    {
+        let row = match MetadataRow::deserialize(metadata_header.version, row) {
+            Ok(row) => row,
+            Err(e) => {
+                problems.push(format!(
+                    "Metadata row {} of segment {} is damaged: {}",
+                    index, segment_num, e
+                ));
+                continue;
+            }
+        };
+        if row.is_unused() {
+            continue;
+        }
+        let record = usize::try_from(row.offset)
+            .ok()
+            .zip(usize::try_from(row.length).ok())
+            .and_then(|(offset, length)| data.get(offset..offset.checked_add(length)?));
+        match record {
+            Some(record) if row.matches(record) => {}
+            Some(_) => problems.push(format!(
+                "Record {} of segment {} is damaged: checksum mismatch",
+                index, segment_num
+            )),
+            None => problems.push(format!(
+                "Record {} of segment {} is missing from the data file",
+                index, segment_num
+            )),
+        }
+    }
+    Ok(problems)
+}
+
+/// Copy the metadata file of a segment and return the UUID of its data file.
+fn copy_metadata_file(
+    from_dir_path: &Path,
+    to_dir_path: &Path,
+    segment_num: u16,
+) -> DBResult<Uuid> {
+    let filename = metadata_filename(segment_num);
+    let mut metadata_file = READ_MODE.open(from_dir_path.join(&filename))?;
+    let header = read_metadata_header(&mut metadata_file)?;
+    fs::copy(from_dir_path.join(&filename), to_dir_path.join(&filename))?;
+    Ok(header.uuid)
+}
+
+/// Copy the sequence ledger, the schema file and the list of archived data files, if there are ones.
+fn copy_sequences_and_schema(from_dir_path: &Path, to_dir_path: &Path) -> DBResult<()> {
+    for filename in [SEQUENCES_FILENAME, SCHEMA_FILENAME, ARCHIVED_FILENAME] {
+        let from_path = from_dir_path.join(filename);
+        if fs::exists(&from_path)? {
+            fs::copy(&from_path, to_dir_path.join(filename))?;
+        }
+    }
+    Ok(())
+}
+
+/// Create a temporary directory next to `target_path` to build its contents in, see
+/// `move_into_place`. `target_path` must not exist or be empty.
+fn staging_dir(target_path: &Path, kind: &str, prefix: &str) -> DBResult<tempfile::TempDir> {
+    if fs::exists(target_path)? && fs::read_dir(target_path)?.next().is_some() {
+        return Err(DBError::ValidationError(format!(
+            "{} directory {} is not empty",
+            kind,
+            target_path.display()
+        )));
+    }
+    let parent_path = parent_dir(target_path);
+    fs::create_dir_all(parent_path)?;
+    Ok(tempfile::Builder::new()
+        .prefix(prefix)
+        .tempdir_in(parent_path)?)
+}
+
+/// Move a complete staging directory to `target_path`, replacing it if it is an empty directory.
+fn move_into_place(tmp_dir: tempfile::TempDir, target_path: &Path) -> DBResult<()> {
+    fs::rename(tmp_dir.keep(), target_path)?;
+    fs::sync_dir(parent_dir(target_path))?;
+    Ok(())
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent_path) if !parent_path.as_os_str().is_empty() => parent_path,
+        _ => Path::new("."),
+    }
+}
+
+/// Hard-link a file, or copy it if it cannot be linked, e.g. because the backup is on another filesystem.
+fn link_or_copy(from_path: &Path, to_path: &Path) -> DBResult<()> {
+    if fs::hard_link(from_path, to_path).is_err() {
+        fs::copy(from_path, to_path)?;
+        fs::File::open(to_path)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Copy the first `len` bytes of a file and sync the copy to disk.
+fn copy_prefix(from_path: &Path, to_path: &Path, len: u64) -> DBResult<()> {
+    let from_file = READ_MODE.open(from_path)?;
+    let mut to_file = fs::File::create(to_path)?;
+    let copied = io::copy(&mut from_file.take(len), &mut to_file)?;
+    if copied != len {
+        return Err(DBError::ConsistencyError(format!(
+            "File {} is truncated: {} bytes, expected at least {}",
+            from_path.display(),
+            copied,
+            len
+        )));
+    }
+    to_file.sync_all()?;
+    Ok(())
+}
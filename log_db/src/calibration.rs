@@ -0,0 +1,144 @@
+use super::*;
+use std::time::Instant;
+
+const CALIBRATION_DATA_FILENAME: &str = "calibration_data";
+const CALIBRATION_METADATA_FILENAME: &str = "calibration_metadata";
+
+/// The measured write performance of each `WriteDurability` mode, as returned by `DB::calibrate_durability`.
+#[derive(Debug, Clone)]
+pub struct DurabilityReport {
+    /// Size of the records written during the measurement, in bytes.
+    pub record_size: usize,
+    pub measurements: Vec<DurabilityMeasurement>,
+}
+
+/// The write performance of a single `WriteDurability` mode.
+#[derive(Debug, Clone)]
+pub struct DurabilityMeasurement {
+    pub write_durability: WriteDurability,
+    /// Number of records written.
+    pub writes: usize,
+    /// Total time taken by the writes.
+    pub total: Duration,
+    pub mean_latency: Duration,
+    pub p50_latency: Duration,
+    pub p99_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl DurabilityMeasurement {
+    pub fn writes_per_second(&self) -> f64 {
+        self.writes as f64 / self.total.as_secs_f64()
+    }
+}
+
+impl Display for DurabilityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Write durability calibration, {} byte records",
+            self.record_size
+        )?;
+        for m in &self.measurements {
+            writeln!(
+                f,
+                "{}: {:.0} writes/s, latency mean {:?}, p50 {:?}, p99 {:?}, max {:?}",
+                m.write_durability,
+                m.writes_per_second(),
+                m.mean_latency,
+                m.p50_latency,
+                m.p99_latency,
+                m.max_latency
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Measure `writes` single-record writes of `record_size` bytes with each durability mode.
+/// The writes go to scratch files in `data_dir_path` so that they hit the same disk as the log,
+/// and each write is persisted the same way as an upsert: a data write and a metadata row.
+pub fn calibrate_durability(
+    data_dir_path: &Path,
+    writes: usize,
+    record_size: usize,
+) -> DBResult<DurabilityReport> {
+    if writes == 0 {
+        return Err(DBError::ValidationError(
+            "Calibration requires at least one write".to_owned(),
+        ));
+    }
+
+    let data_path = data_dir_path.join(CALIBRATION_DATA_FILENAME);
+    let metadata_path = data_dir_path.join(CALIBRATION_METADATA_FILENAME);
+
+    let mut measurements = vec![];
+    for write_durability in [
+        WriteDurability::Flush,
+        WriteDurability::FlushSync,
+        WriteDurability::FlushDataSync,
+    ] {
+        let result = measure(
+            &data_path,
+            &metadata_path,
+            &write_durability,
+            writes,
+            record_size,
+        );
+        // Remove the scratch files even if the measurement failed
+        fs::remove_file(&data_path).ok();
+        fs::remove_file(&metadata_path).ok();
+
+        let mut latencies = result?;
+        latencies.sort_unstable();
+        let total: Duration = latencies.iter().sum();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+
+        measurements.push(DurabilityMeasurement {
+            write_durability,
+            writes,
+            total,
+            mean_latency: total.div_f64(writes as f64),
+            p50_latency: percentile(50),
+            p99_latency: percentile(99),
+            max_latency: latencies[latencies.len() - 1],
+        });
+    }
+
+    Ok(DurabilityReport {
+        record_size,
+        measurements,
+    })
+}
+
+fn measure(
+    data_path: &Path,
+    metadata_path: &Path,
+    write_durability: &WriteDurability,
+    writes: usize,
+    record_size: usize,
+) -> DBResult<Vec<Duration>> {
+    let mut options = fs::OpenOptions::new();
+    options.append(true).create(true);
+    let mut data_file = options.open(data_path)?;
+    let mut metadata_file = options.open(metadata_path)?;
+
+    let record = vec![0xA5; record_size];
+    let mut latencies = Vec::with_capacity(writes);
+    for i in 0..writes {
+        let start = Instant::now();
+
+        data_file.write_all(&record)?;
+        write_durability.persist(&mut data_file)?;
+
+        let mut metadata_row = vec![];
+        metadata_row.extend(((i * record_size) as u64).to_be_bytes());
+        metadata_row.extend((record_size as u64).to_be_bytes());
+        metadata_file.write_all(&metadata_row)?;
+        write_durability.persist(&mut metadata_file)?;
+
+        latencies.push(start.elapsed());
+    }
+
+    Ok(latencies)
+}
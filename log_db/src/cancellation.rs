@@ -0,0 +1,56 @@
+use super::*;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// A token for interrupting long-running queries, see `DB::with_cancellation`. The token is
+/// cancelled explicitly with `cancel`, which can be called from another thread through a clone
+/// of the token, or implicitly once its deadline has passed.
+///
+/// The engine checks the token between segment reads, so a query returns `DBError::Cancelled`
+/// shortly after the token is cancelled, but not in the middle of reading a segment.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl Cancellation {
+    /// Create a token that is only cancelled by calling `cancel`.
+    pub fn new() -> Cancellation {
+        Cancellation::default()
+    }
+
+    /// Create a token that is cancelled at `deadline`, or earlier by calling `cancel`.
+    pub fn with_deadline(deadline: Instant) -> Cancellation {
+        Cancellation {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            deadline: Some(deadline),
+        }
+    }
+
+    /// Create a token that is cancelled after `timeout` from now, or earlier by calling `cancel`.
+    pub fn with_timeout(timeout: Duration) -> Cancellation {
+        Cancellation::with_deadline(Instant::now() + timeout)
+    }
+
+    /// Cancel the token and all of its clones.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, AtomicOrdering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(AtomicOrdering::Relaxed)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Return `DBError::Cancelled` if the token has been cancelled.
+    pub(crate) fn check(&self) -> DBResult<()> {
+        if self.is_cancelled() {
+            return Err(DBError::Cancelled);
+        }
+        Ok(())
+    }
+}
@@ -1,31 +1,64 @@
 use super::*;
 
-// For Unix-like systems
-#[cfg(unix)]
-use std::os::unix::fs::MetadataExt;
-
-// For Windows
-#[cfg(windows)]
-use std::os::windows::fs::MetadataExt;
+pub use log_db_codec::{
+    IndexableValue, Value, ValueRef, B_DELETED, B_LIVE, B_MERGE, B_TOMBSTONE, RECORD_HEADER_SIZE,
+};
 
 pub const ACTIVE_SYMLINK_FILENAME: &str = "active";
 pub const LOCK_FILENAME: &str = "lock";
 pub const EXCL_LOCK_REQ_FILENAME: &str = "excl_lock_req";
 pub const INITIALIZED_FILENAME: &str = "initialized";
+pub const MANIFEST_FILENAME: &str = "manifest";
+pub const SEQUENCES_FILENAME: &str = "sequences";
+pub const SCHEMA_FILENAME: &str = "schema";
+pub const INSTANCES_DIRNAME: &str = "instances";
+pub const COLLECTIONS_DIRNAME: &str = "collections";
+pub const TRANSACTIONS_DIRNAME: &str = "transactions";
+pub const QUIESCE_FILENAME: &str = "quiesce";
+pub const SYNC_LOCK_FILENAME: &str = "sync_lock";
+pub const SYNCED_FILENAME: &str = "synced";
+pub const JOURNAL_FILENAME: &str = "journal";
+pub const ARCHIVED_FILENAME: &str = "archived";
+pub const ARCHIVED_KEYS_DIRNAME: &str = "archived_keys";
 
 pub const METADATA_FILE_HEADER_SIZE: usize = 24;
-pub const METADATA_ROW_LENGTH: usize = 16;
+/// The format that records are written in, stored as the version of the metadata header of each
+/// segment. Segments in an older format are read by upgrading their records with `upgrade_record`,
+/// and are rewritten in the current format when they are compacted.
+///
+/// 1. `[flag][values]`, the original format.
+/// 2. `[flag][version][timestamp][values]`, see `Record::serialize`. Records of format 1 are
+///    upgraded with a zero version and timestamp.
+/// 3. The same records, with checksums in the metadata rows, see `MetadataRow`.
+/// 4. The same records, compressed and encrypted if the metadata header says so, see `Compression`
+///    and `EncryptionProvider`, and with long values compressed on their own, see `compress_value`.
+pub const SEGMENT_FORMAT_VERSION: u8 = 4;
+/// The oldest segment format that can still be read.
+pub const MIN_SEGMENT_FORMAT_VERSION: u8 = 1;
+/// Upgrades of a serialized record from each readable format to the next one, starting from
+/// `MIN_SEGMENT_FORMAT_VERSION`. Each takes the buffer and the start of the record in it.
+const RECORD_UPGRADES: &[fn(&mut Vec<u8>, usize)] = &[
+    |buf, start| {
+        buf.splice(start + 1..start + 1, [0; 8 + 8]);
+    },
+    |_, _| {},
+    |_, _| {},
+];
+const _: () = assert!(
+    RECORD_UPGRADES.len() == (SEGMENT_FORMAT_VERSION - MIN_SEGMENT_FORMAT_VERSION) as usize
+);
+/// The length of a metadata row in the current format, see `MetadataRow`.
+pub const METADATA_ROW_LENGTH: usize = 24;
 pub const LOCK_WAIT_MAX_MS: u64 = 1000;
 
-// Serialized value tags
-pub const B_NULL: u8 = 0x0;
-pub const B_INT: u8 = 0x1;
-pub const B_DECIMAL: u8 = 0x2;
-pub const B_STRING: u8 = 0x3;
-pub const B_BYTES: u8 = 0x4;
-// Tombstone marker tags
-pub const B_LIVE: u8 = 0x0;
-pub const B_TOMBSTONE: u8 = 0xFF;
+/// The length of the metadata rows of a segment in `format_version`. Rows of format 1 and 2
+/// segments have no checksums.
+pub fn metadata_row_length(format_version: u8) -> usize {
+    match format_version {
+        1 | 2 => 16,
+        _ => METADATA_ROW_LENGTH,
+    }
+}
 
 pub fn metadata_filename(num: u16) -> String {
     format!("metadata.{}", num)
@@ -41,6 +74,25 @@ pub enum DBError {
     ValidationError(String),
     #[error("consistency check failed: {0}")]
     ConsistencyError(String),
+    #[error("version conflict: {0}")]
+    VersionConflict(String),
+    #[error("duplicate key: {0}")]
+    DuplicateKey(String),
+    #[error("database is read-only: {0}")]
+    ReadOnly(String),
+    #[error("query cancelled")]
+    Cancelled,
+    #[error("disk quota exceeded: {0}")]
+    QuotaExceeded(String),
+    /// The data directory was written with a different schema than the one it is opened with.
+    #[error("schema mismatch: {0}")]
+    SchemaMismatch(Box<SchemaMismatch>),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error: {0}")]
+    ParquetError(#[from] parquet::errors::ParquetError),
     #[error("unexpected IO error: {0}")]
     IOError(#[from] io::Error),
 }
@@ -155,23 +207,118 @@ pub static READ_MODE: Lazy<fs::OpenOptions> = Lazy::new(|| {
     options.read(true);
     options
 });
-pub static WRITE_MODE: Lazy<fs::OpenOptions> = Lazy::new(|| {
-    let mut options = fs::OpenOptions::new();
-    options.read(true).write(true);
-    options
-});
 
 pub struct MetadataHeader {
     pub version: u8,
+    /// The compression of the records in the data file, stored after the version
+    pub compression: Compression,
+    /// The id of the key that the records are encrypted with, stored after a flag byte following
+    /// the compression, or `None` if they are not encrypted. See `EncryptionProvider`.
+    pub key_id: Option<u32>,
     pub uuid: Uuid,
 }
 
-const METADATA_HEADER_PADDING: &[u8] = &[0; 7];
+/// A row of a metadata file, pointing at a record in the data file. A row is serialized as
+/// `[offset][length][record checksum][row checksum]`, where the checksums are the CRC32s of the
+/// serialized record and of the preceding bytes of the row. Rows of format 1 and 2 segments end
+/// after the length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataRow {
+    pub offset: u64,
+    pub length: u64,
+    /// The checksum of the record, or `None` in format 1 and 2 segments and in unused rows.
+    pub checksum: Option<u32>,
+}
+
+impl MetadataRow {
+    /// The row of `record`, written at `offset` in the data file.
+    pub fn new(offset: u64, record: &[u8]) -> MetadataRow {
+        MetadataRow {
+            offset,
+            length: record.len() as u64,
+            checksum: Some(crc32fast::hash(record)),
+        }
+    }
+
+    pub fn serialize(&self) -> [u8; METADATA_ROW_LENGTH] {
+        let mut row = [0; METADATA_ROW_LENGTH];
+        row[0..8].copy_from_slice(&self.offset.to_be_bytes());
+        row[8..16].copy_from_slice(&self.length.to_be_bytes());
+        row[16..20].copy_from_slice(&self.checksum.unwrap_or(0).to_be_bytes());
+        let row_checksum = crc32fast::hash(&row[0..20]);
+        row[20..24].copy_from_slice(&row_checksum.to_be_bytes());
+        row
+    }
+
+    /// Deserialize a row of a segment in `format_version`. A row of zeros is an unused row.
+    /// Returns an error if the checksum of the row does not match.
+    pub fn deserialize(format_version: u8, row: &[u8]) -> DBResult<MetadataRow> {
+        let offset = u64::from_be_bytes(row[0..8].try_into().unwrap());
+        let length = u64::from_be_bytes(row[8..16].try_into().unwrap());
+        if format_version <= 2 || row.iter().all(|&b| b == 0) {
+            return Ok(MetadataRow {
+                offset,
+                length,
+                checksum: None,
+            });
+        }
+
+        let row_checksum = u32::from_be_bytes(row[20..24].try_into().unwrap());
+        if row_checksum != crc32fast::hash(&row[0..20]) {
+            return Err(DBError::ConsistencyError(
+                "Metadata row checksum mismatch".to_owned(),
+            ));
+        }
+        Ok(MetadataRow {
+            offset,
+            length,
+            checksum: Some(u32::from_be_bytes(row[16..20].try_into().unwrap())),
+        })
+    }
+
+    /// Whether the row points at no record, see `MetadataRow::deserialize`.
+    pub fn is_unused(&self) -> bool {
+        self.offset == 0 && self.length == 0
+    }
+
+    /// Whether `record` matches the checksum of the row. Rows without a checksum match any record.
+    pub fn matches(&self, record: &[u8]) -> bool {
+        self.checksum
+            .is_none_or(|checksum| checksum == crc32fast::hash(record))
+    }
+}
+
+const METADATA_HEADER_PADDING: &[u8] = &[0; 1];
 impl MetadataHeader {
+    /// The header of an uncompressed segment written in the current format.
+    pub fn new(uuid: Uuid) -> MetadataHeader {
+        MetadataHeader {
+            version: SEGMENT_FORMAT_VERSION,
+            compression: Compression::None,
+            key_id: None,
+            uuid,
+        }
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> MetadataHeader {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_key_id(mut self, key_id: Option<u32>) -> MetadataHeader {
+        self.key_id = key_id;
+        self
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let uuid_bytes = self.uuid.as_bytes().to_vec();
 
-        let mut header = vec![self.version];
+        let mut header = vec![
+            self.version,
+            self.compression.code(),
+            self.key_id.is_some() as u8,
+        ];
+        header.extend(self.key_id.unwrap_or(0).to_be_bytes());
         header.extend(METADATA_HEADER_PADDING);
         header.extend(uuid_bytes);
 
@@ -180,24 +327,76 @@ impl MetadataHeader {
         header
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Self {
+    /// The bytes of a serialized record as stored in the segment, compressed and encrypted as
+    /// the header says. The inverse of `decode_record`.
+    pub fn encode_record<'a>(
+        &self,
+        record: &'a [u8],
+        encryption: Option<&dyn EncryptionProvider>,
+    ) -> DBResult<Cow<'a, [u8]>> {
+        let compressed = self.compression.compress(record);
+        match self.key_id {
+            Some(key_id) => Ok(Cow::Owned(
+                self.encryption_provider(encryption)?
+                    .encrypt(key_id, &compressed)?,
+            )),
+            None => Ok(compressed),
+        }
+    }
+
+    /// Turn the record stored at `start` in `buf`, which extends to the end of the buffer, into a
+    /// serialized record of the current format whose values are not compressed. The header must
+    /// have been checked with `validate_metadata_header`.
+    pub fn decode_record(
+        &self,
+        buf: &mut Vec<u8>,
+        start: usize,
+        encryption: Option<&dyn EncryptionProvider>,
+    ) -> DBResult<()> {
+        if let Some(key_id) = self.key_id {
+            let record = self
+                .encryption_provider(encryption)?
+                .decrypt(key_id, &buf[start..])?;
+            buf.truncate(start);
+            buf.extend(record);
+        }
+        self.compression.decompress(buf, start)?;
+        upgrade_record(self.version, buf, start);
+        expand_values(buf, start)
+    }
+
+    fn encryption_provider<'a>(
+        &self,
+        encryption: Option<&'a dyn EncryptionProvider>,
+    ) -> DBResult<&'a dyn EncryptionProvider> {
+        encryption.ok_or_else(|| {
+            DBError::ValidationError(
+                "Segment is encrypted, but no encryption provider is configured".to_owned(),
+            )
+        })
+    }
+
+    /// Returns an error if the compression is unknown.
+    pub fn deserialize(bytes: &[u8]) -> DBResult<Self> {
         assert_eq!(bytes.len(), METADATA_FILE_HEADER_SIZE);
 
         let version = bytes[0];
+        let compression = Compression::from_code(bytes[1])?;
+        let key_id = match bytes[2] {
+            0 => None,
+            _ => Some(u32::from_be_bytes(bytes[3..7].try_into().unwrap())),
+        };
         let uuid = Uuid::from_slice(&bytes[8..24]).expect("Failed to deserialize Uuid");
 
-        MetadataHeader { version, uuid }
+        Ok(MetadataHeader {
+            version,
+            compression,
+            key_id,
+            uuid,
+        })
     }
 }
 
-#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
-pub enum IndexableValue {
-    Null,
-    Int(i64),
-    Decimal(Decimal),
-    String(String),
-}
-
 /// A primitive type
 #[derive(Debug, Clone)]
 pub enum PrimitiveType {
@@ -205,6 +404,20 @@ pub enum PrimitiveType {
     Decimal,
     String,
     Bytes,
+    /// Microseconds since the Unix epoch, see `Value::Timestamp`.
+    Timestamp,
+    /// One of the listed strings, see `Type::enum_of`.
+    Enum(Vec<String>),
+    /// A JSON document, see `Value::Json`.
+    Json,
+    /// Bytes of exactly this length, see `Type::bytes_fixed`.
+    FixedBytes(usize),
+    /// A 64-bit floating point number, see `Value::Float`.
+    Float,
+    /// A nested record with named fields, see `Type::record`.
+    Record(Vec<(String, Type)>),
+    /// A latitude and longitude, see `Type::geo_point`.
+    GeoPoint,
 }
 
 /// A primitive type + a nullability bit
@@ -212,6 +425,8 @@ pub enum PrimitiveType {
 pub struct Type {
     pub primitive: PrimitiveType,
     pub nullable: bool,
+    /// The maximum length in bytes of string and bytes values, see `Type::max_len`.
+    pub max_len: Option<usize>,
 }
 
 impl Type {
@@ -219,6 +434,7 @@ impl Type {
         Type {
             primitive: PrimitiveType::Int,
             nullable: false,
+            max_len: None,
         }
     }
 
@@ -226,6 +442,17 @@ impl Type {
         Type {
             primitive: PrimitiveType::Decimal,
             nullable: false,
+            max_len: None,
+        }
+    }
+
+    /// A 64-bit floating point number. Floats can be keys: they are indexed in numeric order, with
+    /// NaN as a single key that sorts after infinity, and `-0.0` as the same key as `0.0`.
+    pub fn float() -> Self {
+        Type {
+            primitive: PrimitiveType::Float,
+            nullable: false,
+            max_len: None,
         }
     }
 
@@ -233,6 +460,7 @@ impl Type {
         Type {
             primitive: PrimitiveType::String,
             nullable: false,
+            max_len: None,
         }
     }
 
@@ -240,118 +468,104 @@ impl Type {
         Type {
             primitive: PrimitiveType::Bytes,
             nullable: false,
+            max_len: None,
         }
     }
 
-    pub fn nullable(&mut self) -> Self {
-        let mut new = self.clone();
-        new.nullable = true;
-        new
+    /// Bytes of exactly `len` bytes, e.g. hashes or keys. Values of up to 255 bytes are stored
+    /// with a one-byte length prefix instead of the eight bytes of other bytes values.
+    pub fn bytes_fixed(len: usize) -> Self {
+        Type {
+            primitive: PrimitiveType::FixedBytes(len),
+            nullable: false,
+            max_len: None,
+        }
     }
-}
 
-#[derive(Debug, Clone)]
-pub enum Value {
-    Null,
-    Int(i64),
-    Decimal(Decimal),
-    String(String),
-    Bytes(Vec<u8>),
-}
-
-impl PartialEq for Value {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Value::Int(a), Value::Int(b)) => a == b,
-            (Value::Decimal(a), Value::Decimal(b)) => a == b,
-            (Value::String(a), Value::String(b)) => a == b,
-            (Value::Bytes(a), Value::Bytes(b)) => a == b,
-            (Value::Null, Value::Null) => true,
-            _ => false,
+    pub fn timestamp() -> Self {
+        Type {
+            primitive: PrimitiveType::Timestamp,
+            nullable: false,
+            max_len: None,
         }
     }
-}
-impl Eq for Value {}
 
-impl Value {
-    pub fn serialize(&self) -> Vec<u8> {
-        match self {
-            Value::Null => {
-                vec![B_NULL]
-            }
-            Value::Int(i) => {
-                let mut bytes = vec![B_INT];
-                bytes.extend(i.to_be_bytes());
-                bytes
-            }
-            Value::Decimal(d) => {
-                let mut bytes = vec![B_DECIMAL];
-                bytes.extend(d.serialize()); // 16 bytes
-                bytes
-            }
-            Value::String(s) => {
-                let mut bytes = vec![B_STRING];
-                let length = s.len() as u64;
-                bytes.extend(length.to_be_bytes());
-                bytes.extend(s.as_bytes());
-                bytes
-            }
-            Value::Bytes(b) => {
-                let mut bytes = vec![B_BYTES];
-                let length = b.len() as u64;
-                bytes.extend(length.to_be_bytes());
-                bytes.extend(b);
-                bytes
-            }
+    /// A JSON document of any shape. With the `json` feature, values are checked to be valid
+    /// JSON when they are written. JSON fields cannot be keys.
+    pub fn json() -> Self {
+        Type {
+            primitive: PrimitiveType::Json,
+            nullable: false,
+            max_len: None,
         }
     }
 
-    /// Deserialize a Value from a byte slice.
-    /// Returns the deserialized Value and the number of bytes consumed.
-    pub fn deserialize(bytes: &[u8]) -> (Value, usize) {
-        match bytes[0] {
-            B_NULL => (Value::Null, 1),
-            B_INT => {
-                let mut int_bytes = [0; 8];
-                int_bytes.copy_from_slice(&bytes[1..1 + 8]);
-                (Value::Int(i64::from_be_bytes(int_bytes)), 1 + 8)
-            }
-            B_DECIMAL => {
-                let mut decimal_bytes = [0; 16];
-                decimal_bytes.copy_from_slice(&bytes[1..1 + 16]);
-                (Value::Decimal(Decimal::deserialize(decimal_bytes)), 1 + 16)
-            }
-            B_STRING => {
-                let length_bytes = &bytes[1..1 + 8];
-                let length = u64::from_be_bytes(length_bytes.try_into().unwrap()) as usize;
-                (
-                    Value::String(
-                        String::from_utf8(bytes[1 + 8..1 + 8 + length].to_vec()).unwrap(),
-                    ),
-                    1 + 8 + length,
-                )
-            }
-            B_BYTES => {
-                let length_bytes = &bytes[1..1 + 8];
-                let length = u64::from_be_bytes(length_bytes.try_into().unwrap()) as usize;
-                (
-                    Value::Bytes(bytes[1 + 8..1 + 8 + length].to_vec()),
-                    1 + 8 + length,
-                )
-            }
-            _ => panic!("Invalid tag: {}", bytes[0]),
+    /// A string that must be one of `variants`. Values are read and written as `Value::String`,
+    /// but stored in the log as the `Int` index of the variant, so the order of the variants
+    /// must not change once records have been written. New variants may be appended.
+    pub fn enum_of(variants: &[&str]) -> Self {
+        Type {
+            primitive: PrimitiveType::Enum(variants.iter().map(|v| v.to_string()).collect()),
+            nullable: false,
+            max_len: None,
+        }
+    }
+
+    /// A nested record with its own schema of named fields. Values are `Value::Record`s with their
+    /// values in the order of `fields`, stored inline in the record that contains them. Nested
+    /// fields can be read with dotted paths, see `Projection`, but they cannot be keys.
+    pub fn record(fields: &[(&str, Type)]) -> Self {
+        Type {
+            primitive: PrimitiveType::Record(
+                fields
+                    .iter()
+                    .map(|(name, t)| (name.to_string(), t.clone()))
+                    .collect(),
+            ),
+            nullable: false,
+            max_len: None,
         }
     }
 
-    pub fn as_indexable(&self) -> Option<IndexableValue> {
-        match self {
-            Value::Null => Some(IndexableValue::Null),
-            Value::Int(i) => Some(IndexableValue::Int(*i)),
-            Value::Decimal(d) => Some(IndexableValue::Decimal(d.clone())),
-            Value::String(s) => Some(IndexableValue::String(s.clone())),
-            _ => None,
+    /// A point on the globe, see `Value::GeoPoint`. Latitudes must be within -90 to 90 degrees
+    /// and longitudes within -180 to 180 degrees. A geo point secondary key is indexed in z-order,
+    /// so that the points in a bounding box can be found with `DB::find_in_bbox`.
+    pub fn geo_point() -> Self {
+        Type {
+            primitive: PrimitiveType::GeoPoint,
+            nullable: false,
+            max_len: None,
         }
     }
+
+    pub fn nullable(&mut self) -> Self {
+        let mut new = self.clone();
+        new.nullable = true;
+        new
+    }
+
+    /// Limit string and bytes values to at most `n` bytes, with strings measured in their UTF-8
+    /// encoding. Longer values are rejected when they are written, so that a single record cannot
+    /// grow the data file without bound. Only string and bytes types can have a maximum length.
+    pub fn max_len(&mut self, n: usize) -> Self {
+        let mut new = self.clone();
+        new.max_len = Some(n);
+        new
+    }
+}
+
+/// Whether `text` is a valid JSON document. Without the `json` feature there is no parser to check
+/// with, so only empty text is rejected.
+pub fn is_valid_json(text: &str) -> bool {
+    #[cfg(feature = "json")]
+    return codec::serde_json::from_str::<codec::serde_json::Value>(text).is_ok();
+    #[cfg(not(feature = "json"))]
+    return !text.is_empty();
+}
+
+/// Whether `lat` and `lon` are the coordinates of a point on the globe.
+pub fn is_valid_geo_point(lat: f64, lon: f64) -> bool {
+    (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)
 }
 
 pub fn type_check(value: &Value, value_type: &Type) -> bool {
@@ -371,24 +585,189 @@ pub fn type_check(value: &Value, value_type: &Type) -> bool {
             },
         ) => true,
         (
-            Value::Bytes(_),
+            Value::Bytes(v),
             Type {
                 primitive: PrimitiveType::Bytes,
+                max_len,
                 ..
             },
-        ) => true,
+        ) => max_len.is_none_or(|max_len| v.len() <= max_len),
         (
-            Value::String(_),
+            Value::String(v),
             Type {
                 primitive: PrimitiveType::String,
+                max_len,
+                ..
+            },
+        ) => max_len.is_none_or(|max_len| v.len() <= max_len),
+        (
+            Value::Timestamp(_),
+            Type {
+                primitive: PrimitiveType::Timestamp,
                 ..
             },
         ) => true,
+        (
+            Value::String(s),
+            Type {
+                primitive: PrimitiveType::Enum(variants),
+                ..
+            },
+        ) => variants.contains(s),
+        (
+            Value::Json(s),
+            Type {
+                primitive: PrimitiveType::Json,
+                ..
+            },
+        ) => is_valid_json(s),
+        (
+            Value::Bytes(b),
+            Type {
+                primitive: PrimitiveType::FixedBytes(len),
+                ..
+            },
+        ) => b.len() == *len,
+        (
+            Value::Float(_),
+            Type {
+                primitive: PrimitiveType::Float,
+                ..
+            },
+        ) => true,
+        (
+            Value::Record(values),
+            Type {
+                primitive: PrimitiveType::Record(fields),
+                ..
+            },
+        ) => {
+            values.len() == fields.len()
+                && values
+                    .iter()
+                    .zip(fields)
+                    .all(|(value, (_, field_type))| type_check(value, field_type))
+        }
+        (
+            Value::GeoPoint(lat, lon),
+            Type {
+                primitive: PrimitiveType::GeoPoint,
+                ..
+            },
+        ) => is_valid_geo_point(*lat, *lon),
         (Value::Null, Type { nullable: true, .. }) => true,
         _ => false,
     }
 }
 
+/// The order in which `DB::top_k` walks an index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Smallest values first.
+    Asc,
+    /// Greatest values first.
+    Desc,
+}
+
+/// Selects the segments that `DB::compact` should operate on.
+#[derive(Debug, Clone)]
+pub enum SegmentSelector {
+    /// The active segment. Compacting it also rotates it, so that new writes go to a fresh segment.
+    Active,
+    /// All segments, including the active one.
+    All,
+    /// The segments with the given numbers.
+    Ids(Vec<u16>),
+    /// Sealed segments whose metadata file has not been modified within the given duration.
+    OlderThan(Duration),
+}
+
+/// Describes the result of compacting a single segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub segment_num: u16,
+    /// Number of distinct records stored for the segment before compaction.
+    pub records_before: usize,
+    /// Number of records stored for the segment after compaction.
+    pub records_after: usize,
+    /// Total size of the segment's records before compaction, in bytes.
+    pub bytes_before: u64,
+    /// Total size of the segment's records after compaction, in bytes.
+    pub bytes_after: u64,
+}
+
+impl CompactionReport {
+    /// The number of bytes reclaimed by the compaction.
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+}
+
+/// A position in the log. Each write is stored at its own position, and positions are totally ordered
+/// so that a later write always has a greater position, also across segment rotations. Positions can
+/// be serialized with `to_bytes` or `to_string` and persisted as resume points.
+///
+/// Compaction rewrites the rows of a segment, so within a compacted segment the index no longer
+/// identifies a single write. Positions in different segments still compare correctly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogPosition {
+    segment_num: u16,
+    index: u64,
+}
+
+impl LogPosition {
+    pub fn segment_num(&self) -> u16 {
+        self.segment_num
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Serialize the position into 8 bytes. The serialized positions sort bytewise in the same order
+    /// as the positions themselves.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        LogKey::new(self.segment_num, self.index).0.to_be_bytes()
+    }
+
+    pub fn from_bytes(bytes: [u8; 8]) -> LogPosition {
+        LogPosition::from(LogKey(u64::from_be_bytes(bytes)))
+    }
+}
+
+impl From<LogKey> for LogPosition {
+    fn from(log_key: LogKey) -> Self {
+        LogPosition {
+            segment_num: log_key.segment_num(),
+            index: log_key.index(),
+        }
+    }
+}
+
+/// Formats the position as `<segment number>:<index>`.
+impl Display for LogPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.segment_num, self.index)
+    }
+}
+
+impl std::str::FromStr for LogPosition {
+    type Err = DBError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || DBError::ValidationError(format!("Invalid log position: {:?}", s));
+
+        let (segment_num, index) = s.split_once(':').ok_or_else(invalid)?;
+        let segment_num = segment_num.parse().map_err(|_| invalid())?;
+        let index: u64 = index.parse().map_err(|_| invalid())?;
+        if index >= 1 << 48 {
+            return Err(invalid());
+        }
+
+        Ok(LogPosition { segment_num, index })
+    }
+}
+
 pub fn get_secondary_memtable_index_by_field<Field: Eq>(
     sks: &Vec<Field>,
     field: &Field,
@@ -397,37 +776,44 @@ pub fn get_secondary_memtable_index_by_field<Field: Eq>(
 }
 
 pub fn is_file_same_as_path(file: &File, path: &PathBuf) -> DBResult<bool> {
-    // Get the metadata for the open file handle
-    let file_metadata = file.metadata()?;
-
-    // Get the metadata for the file at the specified path
-    let path_metadata = metadata(path)?;
-
-    // Platform-specific comparison
-    #[cfg(unix)]
-    {
-        Ok(
-            file_metadata.dev() == path_metadata.dev()
-                && file_metadata.ino() == path_metadata.ino(),
-        )
-    }
-
-    #[cfg(windows)]
-    {
-        Ok(file_metadata.file_index() == path_metadata.file_index()
-            && file_metadata.volume_serial_number() == path_metadata.volume_serial_number())
-    }
+    Ok(file.metadata()?.is_same_file(&metadata(path)?))
 }
 
-pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
-    #[cfg(unix)]
+/// Allocate disk space for `len` bytes of `file` from `offset` without changing the size of the
+/// file, see `ConfigBuilder::preallocate`. Does nothing on platforms and filesystems without support,
+/// and for files in memory.
+pub fn preallocate(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let Some(file) = file.as_disk() else {
+        return Ok(());
+    };
+
+    #[cfg(target_os = "linux")]
     {
-        std::os::unix::fs::symlink(original, link)
+        use std::os::fd::AsRawFd;
+        // SAFETY: fallocate only uses the file descriptor, which stays open for the call
+        let result = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if result != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                debug!("Filesystem does not support preallocation: {}", e);
+                return Ok(());
+            }
+            return Err(e);
+        }
+        Ok(())
     }
 
-    #[cfg(windows)]
+    #[cfg(not(target_os = "linux"))]
     {
-        std::os::windows::fs::symlink_file(original, link)
+        let _ = (file, offset, len);
+        Ok(())
     }
 }
 
@@ -441,7 +827,7 @@ pub fn set_active_segment(data_dir_path: &Path, segment_num: u16) -> DBResult<()
     let metadata_path = Path::new(&metadata_filename);
     let active_symlink = data_dir_path.join(ACTIVE_SYMLINK_FILENAME);
 
-    symlink(&metadata_path, &tmp_path)?;
+    fs::symlink(metadata_path, &tmp_path)?;
     fs::rename(&tmp_path, &active_symlink)?;
 
     Ok(())
@@ -452,7 +838,8 @@ pub fn set_active_segment(data_dir_path: &Path, segment_num: u16) -> DBResult<()
 /// See `ARCHITECTURE.md` for the file format.
 pub fn create_segment_metadata_file(
     data_dir_path: &Path,
-    data_file_uuid: &Uuid,
+    metadata_header: &MetadataHeader,
+    write_durability: &WriteDurability,
 ) -> DBResult<(u16, PathBuf)> {
     let current_greatest_num = greatest_segment_number(data_dir_path)?;
     let new_num = current_greatest_num + 1;
@@ -465,17 +852,15 @@ pub fn create_segment_metadata_file(
         .append(true)
         .open(&metadata_path)?;
 
-    let metadata_header = MetadataHeader {
-        version: 1,
-        uuid: *data_file_uuid,
-    };
-
     metadata_file.write_all(&metadata_header.serialize())?;
-    metadata_file.flush()?;
+    write_durability.persist(&mut metadata_file)?;
 
     let len = metadata_file.seek(io::SeekFrom::End(0))?;
     assert!(len >= METADATA_FILE_HEADER_SIZE as u64);
-    assert_eq!((len - METADATA_FILE_HEADER_SIZE as u64) % 16, 0);
+    assert_eq!(
+        (len - METADATA_FILE_HEADER_SIZE as u64) % METADATA_ROW_LENGTH as u64,
+        0
+    );
 
     Ok((new_num, metadata_path))
 }
@@ -514,6 +899,64 @@ pub fn greatest_segment_number(data_dir_path: &Path) -> DBResult<u16> {
     parse_segment_number(&segment_metadata_path)
 }
 
+/// Total size of the files in the data directory in bytes. Subdirectories, e.g. the instance
+/// registry, and the active symlink are not counted.
+pub fn data_dir_size(data_dir_path: &Path) -> DBResult<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(data_dir_path)? {
+        let metadata = match entry?.metadata() {
+            Ok(metadata) => metadata,
+            // Removed by compaction after the directory was listed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// List the numbers of all segments in the data directory in ascending order.
+pub fn list_segment_numbers(data_dir_path: &Path) -> DBResult<Vec<u16>> {
+    let mut segment_nums = vec![];
+    for entry in fs::read_dir(data_dir_path)? {
+        let path = entry?.path();
+        let is_metadata_file = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("metadata."));
+
+        if is_metadata_file {
+            segment_nums.push(parse_segment_number(&path)?);
+        }
+    }
+
+    segment_nums.sort_unstable();
+    Ok(segment_nums)
+}
+
+/// Remove the data file with the given UUID if no segment metadata file refers to it anymore.
+/// Consecutive segments may share a data file, so a data file can only be removed once all
+/// of its segments have been rewritten. Returns `true` if the file was removed.
+pub fn remove_data_file_if_unreferenced(data_dir_path: &Path, uuid: &Uuid) -> DBResult<bool> {
+    for segment_num in list_segment_numbers(data_dir_path)? {
+        let mut metadata_file =
+            READ_MODE.open(data_dir_path.join(metadata_filename(segment_num)))?;
+        if read_metadata_header(&mut metadata_file)?.uuid == *uuid {
+            return Ok(false);
+        }
+    }
+
+    let data_path = data_dir_path.join(uuid.to_string());
+    if !fs::exists(&data_path)? {
+        return Ok(false);
+    }
+
+    fs::remove_file(&data_path)?;
+    Ok(true)
+}
+
 /// Create a new segment data file and return its UUID.
 /// A data file contains the segment data, tightly packed without separators.
 /// An accompanying metadata file is required to interpret the data.
@@ -535,20 +978,36 @@ pub fn read_metadata_header(metadata_file: &mut fs::File) -> DBResult<MetadataHe
     let mut buf = [0u8; METADATA_FILE_HEADER_SIZE];
     metadata_file.read_exact(&mut buf)?;
 
-    let header = MetadataHeader::deserialize(&buf);
-    Ok(header)
+    MetadataHeader::deserialize(&buf)
 }
 
 pub fn validate_metadata_header(header: &MetadataHeader) -> DBResult<()> {
-    if header.version != 1 {
-        return Err(DBError::ValidationError(
-            "Unsupported metadata file version".to_owned(),
-        ));
+    if header.version > SEGMENT_FORMAT_VERSION {
+        return Err(DBError::ValidationError(format!(
+            "Segment format version {} is newer than the supported version {}",
+            header.version, SEGMENT_FORMAT_VERSION
+        )));
+    }
+    if header.version < MIN_SEGMENT_FORMAT_VERSION {
+        return Err(DBError::ValidationError(format!(
+            "Unsupported segment format version {}",
+            header.version
+        )));
     }
+    header.compression.check_available()?;
 
     Ok(())
 }
 
+/// Upgrade the serialized record at `start` in `buf`, read from a segment in `format_version`, to
+/// the current format. The version must have been checked with `validate_metadata_header`.
+pub fn upgrade_record(format_version: u8, buf: &mut Vec<u8>, start: usize) {
+    let first = (format_version - MIN_SEGMENT_FORMAT_VERSION) as usize;
+    for upgrade in &RECORD_UPGRADES[first..] {
+        upgrade(buf, start);
+    }
+}
+
 pub enum IsMetadatafileValidResult {
     Ok,
     ReplaceFile,
@@ -561,11 +1020,12 @@ pub fn is_metadata_file_valid(metadata_file: &mut fs::File) -> DBResult<IsMetada
     if size < METADATA_FILE_HEADER_SIZE {
         return Ok(IsMetadatafileValidResult::ReplaceFile);
     }
+    let header = read_metadata_header(metadata_file)?;
 
-    // The data section must be a multiple of 16 bytes.
+    // The data section must be a multiple of the row length.
     // Otherwise, the non-aligned part of the file is dropped.
     let data_section_len = size - METADATA_FILE_HEADER_SIZE;
-    let remainder = data_section_len % 16;
+    let remainder = data_section_len % metadata_row_length(header.version);
     if remainder != 0 {
         return Ok(IsMetadatafileValidResult::TruncateToSize(
             (size - remainder) as u64,
@@ -600,12 +1060,9 @@ pub fn ensure_active_metadata_is_valid(
                 active_target.display(),
                 current_len,
             );
-            let mut tmp_file = tempfile::NamedTempFile::new()?;
+            let mut tmp_file = fs::NamedTempFile::new_in(data_dir)?;
 
-            let header = MetadataHeader {
-                version: 1,
-                uuid: Uuid::new_v4(),
-            };
+            let header = MetadataHeader::new(Uuid::new_v4());
 
             tmp_file.write_all(&header.serialize())?;
             tmp_file.flush()?;
@@ -625,7 +1082,7 @@ pub fn ensure_active_metadata_is_valid(
                 new_size
             );
 
-            let mut tmp_file = tempfile::NamedTempFile::new()?;
+            let mut tmp_file = fs::NamedTempFile::new_in(data_dir)?;
 
             let mut buf = vec![0; new_size as usize];
             metadata_file.seek(SeekFrom::Start(0))?;
@@ -642,6 +1099,7 @@ pub fn ensure_active_metadata_is_valid(
     }
 }
 
+#[derive(Clone)]
 pub struct OwnedBounds<T> {
     start: Bound<T>,
     end: Bound<T>,
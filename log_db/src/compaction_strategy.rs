@@ -0,0 +1,132 @@
+use super::*;
+
+/// Decides when `DB::do_maintenance_tasks` rotates the active segment and which sealed segments it
+/// compacts, see `ConfigBuilder::compaction_strategy`.
+///
+/// Rotating a segment compacts it, and compacting a sealed segment drops the records superseded by
+/// newer segments. Both rewrite the segment, so a strategy trades the write amplification of
+/// compacting often against the space and read cost of keeping superseded records around.
+pub trait CompactionStrategy: Send + Sync {
+    /// Whether the active segment should be rotated and compacted. The default rotates the segment
+    /// once its metadata file reaches `segment_size` bytes, see `ConfigBuilder::segment_size`.
+    fn should_rotate(&self, active: &SegmentStats, segment_size: usize) -> bool {
+        active.metadata_bytes >= segment_size as u64
+    }
+
+    /// The sealed segments to compact, given the statistics of the sealed segments in segment order.
+    /// Maintenance runs often, so a strategy should not select the same segments on every run, see
+    /// `SegmentStats::compacted`. The default compacts no sealed segments.
+    fn select_sealed(&self, _sealed: &[SegmentStats]) -> Vec<u16> {
+        vec![]
+    }
+
+    /// Runs of consecutive sealed segments to merge into the last segment of each run, given the
+    /// statistics of the sealed segments in segment order, see `DB::merge_segments`. Merging bounds
+    /// the number of segments that reads and index rebuilds go through. The default merges nothing.
+    fn select_merges(&self, _sealed: &[SegmentStats]) -> Vec<Vec<u16>> {
+        vec![]
+    }
+
+    /// Whether the strategy needs `SegmentStats::tombstones`. Counting tombstones reads the whole
+    /// segment, so they are only counted for strategies that use them.
+    fn counts_tombstones(&self) -> bool {
+        false
+    }
+}
+
+/// Statistics of a segment that a `CompactionStrategy` bases its decisions on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentStats {
+    pub segment_num: u16,
+    /// Number of records in the segment, including tombstones.
+    pub records: u64,
+    /// Number of tombstones in the segment, if counted, see `CompactionStrategy::counts_tombstones`.
+    pub tombstones: Option<u64>,
+    /// Size of the metadata file of the segment, in bytes.
+    pub metadata_bytes: u64,
+    /// Time of the first write in the segment, or `None` if the segment is empty.
+    pub first_written: Option<SystemTime>,
+    /// Whether the segment is unchanged since maintenance by this handle last compacted it.
+    /// Compacting it again reclaims only the records superseded since.
+    pub compacted: bool,
+}
+
+/// The default strategy: the active segment is rotated once it reaches `ConfigBuilder::segment_size`,
+/// and sealed segments are only compacted on request with `DB::compact`.
+pub struct SizeCompaction;
+
+impl CompactionStrategy for SizeCompaction {}
+
+/// Rotates the active segment once it reaches `ConfigBuilder::segment_size` or once its first
+/// record is older than `max_age`, so that each segment covers a bounded span of time. This suits
+/// workloads that compact or drop old segments by age, e.g. with `SegmentSelector::OlderThan`.
+pub struct TimeCompaction {
+    pub max_age: Duration,
+}
+
+impl CompactionStrategy for TimeCompaction {
+    fn should_rotate(&self, active: &SegmentStats, segment_size: usize) -> bool {
+        let expired = active.first_written.is_some_and(|first_written| {
+            first_written.elapsed().unwrap_or(Duration::ZERO) >= self.max_age
+        });
+        expired || SizeCompaction.should_rotate(active, segment_size)
+    }
+}
+
+/// Rotates the active segment by size like `SizeCompaction`, and compacts the sealed segments once
+/// a new sealed segment consists of at least `ratio` tombstones. The tombstones supersede records
+/// in the earlier segments, so the segment and all segments before it are compacted. This suits
+/// delete-heavy workloads, where deleted records would otherwise linger in old segments.
+pub struct TombstoneCompaction {
+    pub ratio: f64,
+}
+
+impl CompactionStrategy for TombstoneCompaction {
+    fn select_sealed(&self, sealed: &[SegmentStats]) -> Vec<u16> {
+        let last_deleting = sealed.iter().rposition(|stats| {
+            !stats.compacted
+                && stats.records > 0
+                && stats.tombstones.unwrap_or(0) as f64 / stats.records as f64 >= self.ratio
+        });
+        match last_deleting {
+            Some(position) => sealed[..=position]
+                .iter()
+                .map(|stats| stats.segment_num)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn counts_tombstones(&self) -> bool {
+        true
+    }
+}
+
+/// Rotates the active segment by size like `SizeCompaction`, and merges sealed segments of similar
+/// size in tiers: once `fan_in` consecutive segments are in the same tier, they are merged into a
+/// segment of the next tier. A segment with `n` records is in tier `log(n)` with base `fan_in`, so
+/// the number of segments grows logarithmically with the number of records, while each record is
+/// rewritten about once per tier.
+pub struct TieredCompaction {
+    pub fan_in: usize,
+}
+
+impl CompactionStrategy for TieredCompaction {
+    fn select_merges(&self, sealed: &[SegmentStats]) -> Vec<Vec<u16>> {
+        let fan_in = self.fan_in.max(2);
+        let tier = |stats: &SegmentStats| stats.records.max(1).ilog(fan_in as u64);
+
+        let mut merges = vec![];
+        let mut run: Vec<&SegmentStats> = vec![];
+        for stats in sealed {
+            if run.last().is_some_and(|last| tier(last) != tier(stats)) {
+                run.clear();
+            }
+            run.push(stats);
+            if run.len() == fan_in {
+                merges.push(run.drain(..).map(|stats| stats.segment_num).collect());
+            }
+        }
+        merges
+    }
+}
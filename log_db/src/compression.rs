@@ -0,0 +1,182 @@
+use super::*;
+use log_db_codec::{encoded_len, B_BYTES, B_COMPRESSED, B_JSON, B_STRING};
+
+/// The compression of the records of a segment, stored in its metadata header.
+/// See `ConfigBuilder::compression`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4, which is fast to compress and decompress but compresses less.
+    Lz4,
+    /// Zstandard at the given level, which compresses better but is slower.
+    /// Requires the `zstd` feature. The level is not stored, segments read back have level 0.
+    Zstd(i32),
+}
+
+/// The first byte of each record in a compressed segment tells whether the rest is compressed.
+/// Records that do not get smaller are stored as they are.
+const FRAME_STORED: u8 = 0;
+const FRAME_COMPRESSED: u8 = 1;
+
+impl Compression {
+    /// The code of the compression in the metadata header.
+    pub fn code(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd(_) => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> DBResult<Compression> {
+        match code {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd(0)),
+            _ => Err(DBError::ValidationError(format!(
+                "Unknown segment compression {}",
+                code
+            ))),
+        }
+    }
+
+    /// Returns an error if the compression is not supported by this build.
+    pub fn check_available(&self) -> DBResult<()> {
+        if matches!(self, Compression::Zstd(_)) && !cfg!(feature = "zstd") {
+            return Err(DBError::ValidationError(
+                "Zstd compression requires the zstd feature".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The bytes of the serialized `record` as stored in a segment with this compression.
+    pub fn compress<'a>(&self, record: &'a [u8]) -> Cow<'a, [u8]> {
+        let compressed = match self {
+            Compression::None => return Cow::Borrowed(record),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(record),
+            Compression::Zstd(level) => compress_zstd(record, *level),
+        };
+
+        let mut frame = Vec::with_capacity(record.len().min(compressed.len()) + 1);
+        if compressed.len() < record.len() {
+            frame.push(FRAME_COMPRESSED);
+            frame.extend_from_slice(&compressed);
+        } else {
+            frame.push(FRAME_STORED);
+            frame.extend_from_slice(record);
+        }
+        Cow::Owned(frame)
+    }
+
+    /// Replace the record stored at `start` in `buf`, which extends to the end of the buffer, with
+    /// the serialized record. The inverse of `compress`.
+    pub fn decompress(&self, buf: &mut Vec<u8>, start: usize) -> DBResult<()> {
+        if *self == Compression::None {
+            return Ok(());
+        }
+
+        let damaged = |reason: &str| {
+            DBError::ConsistencyError(format!("Compressed record is damaged: {}", reason))
+        };
+        let record = match buf.get(start) {
+            Some(&FRAME_STORED) => buf[start + 1..].to_vec(),
+            Some(&FRAME_COMPRESSED) => {
+                let compressed = &buf[start + 1..];
+                match self {
+                    Compression::Lz4 => lz4_flex::decompress_size_prepended(compressed)
+                        .map_err(|e| damaged(&e.to_string()))?,
+                    _ => decompress_zstd(compressed).map_err(|e| damaged(&e.to_string()))?,
+                }
+            }
+            Some(frame) => return Err(damaged(&format!("unknown frame {}", frame))),
+            None => return Err(damaged("empty record")),
+        };
+
+        buf.truncate(start);
+        buf.extend_from_slice(&record);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn compress_zstd(record: &[u8], level: i32) -> Vec<u8> {
+    zstd::bulk::compress(record, level).expect("Compressing into a vector cannot fail")
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_zstd(compressed: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::stream::decode_all(compressed)
+}
+
+// Zstd segments and configurations are refused with `check_available` without the feature
+#[cfg(not(feature = "zstd"))]
+fn compress_zstd(_record: &[u8], _level: i32) -> Vec<u8> {
+    unreachable!("Zstd compression requires the zstd feature")
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_zstd(_compressed: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "zstd compression requires the zstd feature",
+    ))
+}
+
+/// Replace a string, JSON or bytes value serialized at `start` in `bytes`, which extends to the end
+/// of the buffer, with its compressed form `[tag | B_COMPRESSED][length][compressed]` if it is
+/// smaller. The compressed part is the value without its tag.
+pub fn compress_value(bytes: &mut Vec<u8>, start: usize) {
+    if !matches!(bytes[start], B_STRING | B_JSON | B_BYTES) {
+        return;
+    }
+    let compressed = lz4_flex::compress_prepend_size(&bytes[start + 1..]);
+    if 1 + 8 + compressed.len() >= bytes.len() - start {
+        return;
+    }
+
+    bytes[start] |= B_COMPRESSED;
+    bytes.truncate(start + 1);
+    bytes.extend((compressed.len() as u64).to_be_bytes());
+    bytes.extend(compressed);
+}
+
+/// Expand the values compressed with `compress_value` in the serialized record at `start` in `buf`,
+/// which extends to the end of the buffer. Records without compressed values are left as they are.
+/// Values that cannot be decoded are left for the decoding or validation of the record to report.
+pub fn expand_values(buf: &mut Vec<u8>, start: usize) -> DBResult<()> {
+    let mut pos = start + RECORD_HEADER_SIZE;
+    while pos < buf.len() && buf[pos] & B_COMPRESSED == 0 {
+        match encoded_len(&buf[pos..]) {
+            Ok(len) => pos += len,
+            Err(_) => return Ok(()),
+        }
+    }
+    if pos >= buf.len() {
+        return Ok(());
+    }
+
+    let mut expanded = Vec::with_capacity(buf.len() - pos);
+    let mut rest = &buf[pos..];
+    while !rest.is_empty() {
+        let Ok(len) = encoded_len(rest) else {
+            expanded.extend_from_slice(rest);
+            break;
+        };
+        if rest[0] & B_COMPRESSED != 0 {
+            let value = lz4_flex::decompress_size_prepended(&rest[1 + 8..len]).map_err(|e| {
+                DBError::ConsistencyError(format!("Compressed value is damaged: {}", e))
+            })?;
+            expanded.push(rest[0] & !B_COMPRESSED);
+            expanded.extend(value);
+        } else {
+            expanded.extend_from_slice(&rest[..len]);
+        }
+        rest = &rest[len..];
+    }
+
+    buf.truncate(pos);
+    buf.extend(expanded);
+    Ok(())
+}
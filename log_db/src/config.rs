@@ -1,10 +1,43 @@
 use super::*;
+use std::sync::Arc;
 
 pub struct ConfigBuilder<R: Recordable> {
     data_dir: Option<String>,
     segment_size: Option<usize>,
+    max_segment_records: Option<u64>,
+    max_segment_age: Option<Duration>,
     write_durability: Option<WriteDurability>,
+    group_commit_window: Option<Duration>,
     read_consistency: Option<ReadConsistency>,
+    manifest_verification: Option<ManifestVerification>,
+    merge_operator: Option<MergeOperator<R>>,
+    non_indexed_queries: Option<NonIndexedQueries>,
+    delete_mode: Option<DeleteMode>,
+    expiry_field: Option<R::Field>,
+    write_transforms: Vec<(R::Field, WriteTransform)>,
+    checks: Vec<(R::Field, FieldCheck)>,
+    collations: Vec<(R::Field, Collation)>,
+    computed_indexes: Vec<(String, ComputedKey)>,
+    scrub_rate: Option<u64>,
+    scrub_hook: Option<ScrubHook>,
+    max_disk_bytes: Option<u64>,
+    compact_on_open: Option<f64>,
+    retention: Option<RetentionPolicy>,
+    retention_hook: Option<RetentionHook>,
+    archive: Option<(Arc<dyn ArchiveStore>, Duration)>,
+    numeric_widening: bool,
+    read_only: bool,
+    in_memory: bool,
+    ephemeral: bool,
+    mmap_reads: bool,
+    io_uring: bool,
+    preallocate: bool,
+    compression: Option<Compression>,
+    compress_appends: bool,
+    compress_values_over: Option<usize>,
+    encryption: Option<Arc<dyn EncryptionProvider>>,
+    compaction_strategy: Option<Arc<dyn CompactionStrategy>>,
+    compaction_threads: Option<usize>,
     _marker: PhantomData<R>,
 }
 
@@ -13,12 +46,72 @@ impl<R: Recordable> ConfigBuilder<R> {
         ConfigBuilder {
             data_dir: None,
             segment_size: None,
+            max_segment_records: None,
+            max_segment_age: None,
             write_durability: None,
+            group_commit_window: None,
             read_consistency: None,
+            manifest_verification: None,
+            merge_operator: None,
+            non_indexed_queries: None,
+            delete_mode: None,
+            expiry_field: None,
+            write_transforms: vec![],
+            checks: vec![],
+            collations: vec![],
+            computed_indexes: vec![],
+            scrub_rate: None,
+            scrub_hook: None,
+            max_disk_bytes: None,
+            compact_on_open: None,
+            retention: None,
+            retention_hook: None,
+            archive: None,
+            numeric_widening: false,
+            read_only: false,
+            in_memory: false,
+            ephemeral: false,
+            mmap_reads: false,
+            io_uring: false,
+            preallocate: false,
+            compression: None,
+            compress_appends: false,
+            compress_values_over: None,
+            encryption: None,
+            compaction_strategy: None,
+            compaction_threads: None,
             _marker: PhantomData,
         }
     }
 
+    /// A builder for the collection of a database configured with `parent`, stored in `data_dir`.
+    /// The settings of the storage are inherited from the parent, while the settings that refer to
+    /// the fields of a record are left at their defaults.
+    pub(crate) fn from_parent<P: Recordable>(
+        parent: &Config<P>,
+        data_dir: &Path,
+    ) -> ConfigBuilder<R> {
+        let mut builder = ConfigBuilder::new();
+        builder.data_dir = Some(data_dir.to_string_lossy().into_owned());
+        builder.segment_size = Some(parent.segment_size);
+        builder.write_durability = Some(parent.write_durability.clone());
+        builder.group_commit_window = Some(parent.group_commit_window);
+        builder.read_consistency = Some(parent.read_consistency.clone());
+        builder.manifest_verification = Some(parent.manifest_verification.clone());
+        builder.non_indexed_queries = Some(parent.non_indexed_queries.clone());
+        builder.delete_mode = Some(parent.delete_mode.clone());
+        builder.scrub_rate = Some(parent.scrub_rate);
+        builder.scrub_hook = parent.scrub_hook;
+        builder.read_only = parent.read_only;
+        builder.compression = Some(parent.compression);
+        builder.compress_appends = parent.compress_appends;
+        builder.compress_values_over = parent.compress_values_over;
+        builder.encryption = parent.encryption.clone();
+        builder.compaction_strategy = Some(parent.compaction_strategy.clone());
+        builder.compaction_threads = Some(parent.compaction_threads);
+        builder
+    }
+
     /// The directory where the database will store its data.
     pub fn data_dir(&mut self, data_dir: &str) -> &mut Self {
         self.data_dir = Some(data_dir.to_string());
@@ -34,6 +127,23 @@ impl<R: Recordable> ConfigBuilder<R> {
         self
     }
 
+    /// Rotate the active segment once it has `max_records` records, in addition to the rotations
+    /// of the compaction strategy, e.g. by `segment_size`. Like other rotations, this happens in
+    /// `db.do_maintenance_tasks()`. Not set by default.
+    pub fn max_segment_records(&mut self, max_records: u64) -> &mut Self {
+        self.max_segment_records = Some(max_records);
+        self
+    }
+
+    /// Rotate the active segment once its first record was written at least `max_age` ago, in
+    /// addition to the rotations of the compaction strategy, so that each segment covers a bounded
+    /// span of time with any strategy. Like other rotations, this happens in
+    /// `db.do_maintenance_tasks()`. Not set by default.
+    pub fn max_segment_age(&mut self, max_age: Duration) -> &mut Self {
+        self.max_segment_age = Some(max_age);
+        self
+    }
+
     /// The write durability policy for the database.
     /// This determines how writes are persisted to disk.
     /// The default is WriteDurability::Flush.
@@ -42,6 +152,18 @@ impl<R: Recordable> ConfigBuilder<R> {
         self
     }
 
+    /// How long a `WriteDurability::FlushSync` or `FlushDataSync` write waits for other writers
+    /// before syncing.
+    /// Writes that are appended while a sync is waiting or in progress share the next sync instead
+    /// of each making their own, which raises the throughput of concurrent writers at the cost of
+    /// the latency of each write. Has no effect with `WriteDurability::Flush`.
+    /// The default is zero, which still shares syncs between writers that append while another
+    /// writer is syncing.
+    pub fn group_commit_window(&mut self, window: Duration) -> &mut Self {
+        self.group_commit_window = Some(window);
+        self
+    }
+
     /// The read consistency policy for the database.
     /// This determines how recent writes are visible when reading.
     /// See individual `ReadConsistency` enum values for more information.
@@ -51,24 +173,395 @@ impl<R: Recordable> ConfigBuilder<R> {
         self
     }
 
+    /// Whether the sealed segment files are checked against the manifest on initialization.
+    /// This detects segment files that have been modified outside of the database, e.g. by
+    /// accidental manual edits or partial restores. Reading all sealed segments can take a while
+    /// for large databases.
+    /// The default is ManifestVerification::Disabled.
+    pub fn verify_manifest(&mut self, manifest_verification: ManifestVerification) -> &mut Self {
+        self.manifest_verification = Some(manifest_verification);
+        self
+    }
+
+    /// The function that folds merge deltas written with `db.merge` into the previous version of a record.
+    /// It receives the previous version, or `None` if the record does not exist, and the delta,
+    /// and returns the new version, e.g. `|old, delta| Counter { count: old.map_or(0, |o| o.count) + delta.count, ..delta }`.
+    ///
+    /// The merge operator must not change the primary, secondary or composite keys of the record.
+    /// Deltas are folded when records are read and when segments are compacted, so the operator must
+    /// always return the same result for the same arguments.
+    pub fn merge_operator(&mut self, merge_operator: MergeOperator<R>) -> &mut Self {
+        self.merge_operator = Some(merge_operator);
+        self
+    }
+
+    /// How queries by fields without an index are handled, e.g. `find_by` by a field that is
+    /// neither the primary key nor a secondary key.
+    /// The default is NonIndexedQueries::Refuse.
+    pub fn non_indexed_queries(&mut self, non_indexed_queries: NonIndexedQueries) -> &mut Self {
+        self.non_indexed_queries = Some(non_indexed_queries);
+        self
+    }
+
+    /// How `delete` and `delete_by` remove records.
+    /// The default is DeleteMode::Hard.
+    pub fn delete_mode(&mut self, delete_mode: DeleteMode) -> &mut Self {
+        self.delete_mode = Some(delete_mode);
+        self
+    }
+
+    /// An Int field holding the time at which each record expires, in milliseconds since the Unix
+    /// epoch, e.g. for a cache or a session store. Records whose expiry time has passed are no
+    /// longer returned by reads, and compaction deletes them. Records with a null expiry time never
+    /// expire. See also `DB::upsert_with_ttl`.
+    ///
+    /// Queries that only read the indexes, `distinct` and `aggregate` on keys, may still see expired
+    /// records until they have been compacted.
+    pub fn expiry_field(&mut self, field: R::Field) -> &mut Self {
+        self.expiry_field = Some(field);
+        self
+    }
+
+    /// Add a transform that is applied to the value of `field` whenever a record is written,
+    /// e.g. `.write_transform(Field::Email, WriteTransform::Lowercase)`. The transforms of a field
+    /// are applied in the order they are added, so e.g. an email address can be normalized before
+    /// it is hashed. Since the transforms are applied by the engine, they are enforced for every
+    /// process that writes with the same configuration.
+    ///
+    /// Transforms are applied to the primary key as well, so a record must be looked up by
+    /// the transformed key.
+    pub fn write_transform(&mut self, field: R::Field, transform: WriteTransform) -> &mut Self {
+        self.write_transforms.push((field, transform));
+        self
+    }
+
+    /// Add a check that the value of `field` must pass whenever a record is written, e.g.
+    /// `.check(Field::Age, |age| matches!(age, Value::Int(0..=150)))`. A record with a value that
+    /// fails a check is not written, and the write returns `DBError::ValidationError` naming the
+    /// field. Checks see the value after the write transforms of the field, and are not run on null
+    /// values, deletions or merge deltas.
+    pub fn check(&mut self, field: R::Field, check: FieldCheck) -> &mut Self {
+        self.checks.push((field, check));
+        self
+    }
+
+    /// Allow `Int` fields of the stored schema to be changed into `Float` or `Decimal` fields
+    /// without rewriting the log. Integers written before the change are read as floats or decimals,
+    /// and are indexed as such. The stored schema records the change, so a data directory with
+    /// widened fields can only be opened with widening enabled until it is rewritten with
+    /// `DB::migrate`. Defaults to `false`.
+    pub fn numeric_widening(&mut self, enabled: bool) -> &mut Self {
+        self.numeric_widening = enabled;
+        self
+    }
+
+    /// Open an initialized data directory for reading only, e.g. on a read-only filesystem or a
+    /// replica. No files are created or written: the lock files are used for shared locks if they
+    /// exist, and all writes, compaction and maintenance return `DBError::ReadOnly`. Interrupted
+    /// appends are not recovered, so a data directory left by a crashed writer must be opened for
+    /// writing, or repaired with `DB::fsck`, first. Defaults to `false`.
+    pub fn read_only(&mut self, enabled: bool) -> &mut Self {
+        self.read_only = enabled;
+        self
+    }
+
+    /// Keep the database in memory instead of in a data directory, e.g. for tests and caches. The
+    /// segment files are kept in `Vec<u8>` buffers of this process, under a virtual data directory
+    /// that is not on disk, so the engine works as it does on disk without any disk I/O on all
+    /// platforms. The data is dropped with the handle, and other processes cannot open it. Reads
+    /// are not memory-mapped and io_uring is not used. Cannot be combined with `data_dir`.
+    pub fn in_memory(&mut self) -> &mut Self {
+        self.in_memory = true;
+        self
+    }
+
+    /// Keep the database in a new temporary directory, which is removed when the handle is dropped,
+    /// e.g. for tests. Unlike `in_memory`, the directory is in the system temporary directory on
+    /// disk. Cannot be combined with `data_dir`.
+    pub fn ephemeral(&mut self) -> &mut Self {
+        self.ephemeral = true;
+        self
+    }
+
+    /// Read records through memory maps of the segment files instead of opening and seeking them
+    /// on each read, which saves the system calls of point lookups spread across segments. The maps
+    /// are kept until their segment is compacted or merged, and remapped when the active segment
+    /// has grown past them. Falls back to regular reads on platforms without memory maps.
+    /// Defaults to `false`.
+    pub fn mmap_reads(&mut self, enabled: bool) -> &mut Self {
+        self.mmap_reads = enabled;
+        self
+    }
+
+    /// Submit the reads of a batch lookup and the writes of an append through io_uring, so that
+    /// the rows and records read from a segment take a single system call each, and a record and
+    /// its row are appended with one. Requires the `io-uring` feature on Linux; elsewhere, or if
+    /// the kernel does not allow io_uring, standard I/O is used with a warning. Defaults to `false`.
+    pub fn io_uring(&mut self, enabled: bool) -> &mut Self {
+        self.io_uring = enabled;
+        self
+    }
+
+    /// Allocate `segment_size` bytes of disk space for the data and metadata files of each new
+    /// active segment when it is created. Appends then fill space that was allocated at once, which
+    /// reduces fragmentation, and running out of disk space is reported by the rotation instead of
+    /// by a write. The sizes of the files do not change. Only supported on Linux; elsewhere and on
+    /// filesystems without support, nothing is allocated in advance. Defaults to `false`.
+    pub fn preallocate(&mut self, enabled: bool) -> &mut Self {
+        self.preallocate = enabled;
+        self
+    }
+
+    /// Set the collation of the index of the string secondary key `field`, e.g.
+    /// `.collation(Field::Name, Collation::AsciiCaseInsensitive)`. The collation determines the
+    /// order of `range_by`, `range_by_stream` and `top_k`, and which values `find_by` considers
+    /// equal. Indexes are built in memory when the database is opened, so the collation of an
+    /// index can be changed freely. Defaults to `Collation::Binary`.
+    pub fn collation(&mut self, field: R::Field, collation: Collation) -> &mut Self {
+        self.collations.push((field, collation));
+        self
+    }
+
+    /// Add an index over a value computed from the values of a record, e.g. a lowercased name or
+    /// the year of a timestamp. The function is given the values in schema order, and must return
+    /// an indexable value. The index is queried by its name with `DB::find_by_computed` and
+    /// `DB::range_by_computed`.
+    ///
+    /// Computed keys are not stored in the log but computed whenever a record is indexed, so the
+    /// function must be deterministic, and changing it takes effect when the indexes are rebuilt on
+    /// the next `initialize`.
+    pub fn computed_index(&mut self, name: &str, compute: ComputedKey) -> &mut Self {
+        self.computed_indexes.push((name.to_owned(), compute));
+        self
+    }
+
+    /// The maximum rate at which the scrubber reads segment files, in bytes per second, see
+    /// `DB::run_scrubber`. A low rate keeps the scrubber from competing with queries for I/O.
+    /// The default is 4 MiB per second.
+    pub fn scrub_rate(&mut self, bytes_per_second: u64) -> &mut Self {
+        self.scrub_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// A function that is called with each problem found by the scrubber, e.g. to raise an alert.
+    /// Problems are also logged as warnings.
+    pub fn scrub_hook(&mut self, scrub_hook: ScrubHook) -> &mut Self {
+        self.scrub_hook = Some(scrub_hook);
+        self
+    }
+
+    /// The maximum total size of the files in the data directory, in bytes. Once the size reaches
+    /// 90% of the quota, writes and `db.do_maintenance_tasks()` compact all segments to reclaim the
+    /// space of superseded and deleted records. Writes that would exceed the quota are rejected with
+    /// `DBError::QuotaExceeded`, so that a growing database cannot fill up the disk it shares with
+    /// other services. Deleting records is still allowed, but tombstones take space until compaction
+    /// can drop them, so a database that is full of live records needs a larger quota to accept
+    /// writes again.
+    ///
+    /// The quota is approximate: compaction temporarily needs space for the rewritten segment and
+    /// the manifest, so the quota should leave room for at least one segment on the disk. The
+    /// default is no quota.
+    pub fn max_disk_bytes(&mut self, max_disk_bytes: u64) -> &mut Self {
+        self.max_disk_bytes = Some(max_disk_bytes);
+        self
+    }
+
+    /// Compact the database when it is opened if superseded records and tombstones take up more
+    /// than `garbage_ratio`, between 0 and 1, of the record bytes in the log. The active segment is
+    /// compacted and rotated, and all sealed segments are merged into one, so that a database that
+    /// is mostly appended to can be shrunk at restart. The ratio is computed from the metadata rows
+    /// without reading the data files. The default is no compaction on open.
+    pub fn compact_on_open(&mut self, garbage_ratio: f64) -> &mut Self {
+        self.compact_on_open = Some(garbage_ratio);
+        self
+    }
+
+    /// Limits on the total size, the number of segments and the age of the sealed segments, which
+    /// `db.do_maintenance_tasks()` keeps to. While a limit is exceeded, the oldest segments are
+    /// dropped as long as all of their records are superseded, and the remaining segments over the
+    /// limits are merged, which drops their superseded records. Unlike `max_disk_bytes`, writes
+    /// are never rejected. If the live records alone exceed the limits, maintenance calls the
+    /// `retention_hook`, or returns `DBError::QuotaExceeded` if there is none. The default is no
+    /// retention policy.
+    pub fn retention(&mut self, retention: RetentionPolicy) -> &mut Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// A function that is called when maintenance cannot bring the data directory within the
+    /// `retention` policy, e.g. to raise an alert. Maintenance then succeeds instead of returning
+    /// `DBError::QuotaExceeded`.
+    pub fn retention_hook(&mut self, retention_hook: RetentionHook) -> &mut Self {
+        self.retention_hook = Some(retention_hook);
+        self
+    }
+
+    /// Move the data files of sealed segments that have not changed for `older_than` to `store`,
+    /// e.g. object storage, to keep the data directory small. `db.do_maintenance_tasks()` uploads
+    /// them and removes the local copies, keeping the metadata files. Reading an archived record
+    /// downloads its data file back, and maintenance removes the local copy again once it has not
+    /// been fetched for `older_than`. The keys of the archived records are kept locally, so opening
+    /// the database does not fetch the archived data files, unless computed indexes need whole records.
+    ///
+    /// The archive must stay available while the data directory is used: the archived data files
+    /// are listed in the data directory, and `DB::fsck`, backups and manifest verification rely on
+    /// the list instead of on the files. The default is no archive.
+    pub fn archive(&mut self, store: Arc<dyn ArchiveStore>, older_than: Duration) -> &mut Self {
+        self.archive = Some((store, older_than));
+        self
+    }
+
+    /// Compress the records of the segments written by compaction and migration, e.g.
+    /// `.compression(Compression::Lz4)`. The compression of each segment is stored in its metadata
+    /// header, so segments compressed differently or not at all are read regardless of this
+    /// setting, and changing it only affects the segments written after the change.
+    /// Records that do not get smaller are stored uncompressed. Defaults to `Compression::None`.
+    pub fn compression(&mut self, compression: Compression) -> &mut Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Compress the records appended to the active segment as well, with the compression set with
+    /// `compression`. Each record is compressed on its own, so small records gain little. Takes
+    /// effect from the next new active segment. Defaults to `false`.
+    pub fn compress_appends(&mut self, enabled: bool) -> &mut Self {
+        self.compress_appends = enabled;
+        self
+    }
+
+    /// Compress string, JSON and bytes values whose serialized length exceeds `threshold` bytes
+    /// with LZ4 when they are written, e.g. `.compress_values_over(4096)`. Unlike `compression`,
+    /// this applies to every write and leaves small values untouched, so large blobs shrink
+    /// without slowing down records of small values. Compressed values are marked in their tag and
+    /// are read regardless of this setting. By default values are not compressed.
+    pub fn compress_values_over(&mut self, threshold: usize) -> &mut Self {
+        self.compress_values_over = Some(threshold);
+        self
+    }
+
+    /// Encrypt the records in the data files with `provider`, e.g.
+    /// `.encryption(AesGcmProvider::new(1, key))` with the `aes-gcm` feature. New segments are
+    /// encrypted with the current key of the provider, whose id is stored in the metadata header of
+    /// the segment. To rotate the key, make a new key current while keeping the old one available,
+    /// and compact all segments with `DB::compact` to re-encrypt them with the new key.
+    ///
+    /// Metadata files hold only the positions, lengths and checksums of the encrypted records, and
+    /// are not encrypted. Encrypted segments cannot be read without the provider. By default
+    /// records are not encrypted.
+    pub fn encryption(&mut self, provider: impl EncryptionProvider + 'static) -> &mut Self {
+        self.encryption = Some(Arc::new(provider));
+        self
+    }
+
+    /// When `DB::do_maintenance_tasks` rotates the active segment and which sealed segments it
+    /// compacts, e.g. `.compaction_strategy(TombstoneCompaction { ratio: 0.5 })`. See
+    /// `CompactionStrategy` for the built-in strategies.
+    /// The default is `SizeCompaction`, which rotates the active segment at `segment_size`.
+    pub fn compaction_strategy(
+        &mut self,
+        strategy: impl CompactionStrategy + 'static,
+    ) -> &mut Self {
+        self.compaction_strategy = Some(Arc::new(strategy));
+        self
+    }
+
+    /// The number of threads that compaction and merges read, decode and encode records with,
+    /// while the rewritten data file is written in order on the calling thread. Small segments
+    /// are compacted on the calling thread alone. The default is one thread per available CPU.
+    pub fn compaction_threads(&mut self, threads: usize) -> &mut Self {
+        self.compaction_threads = Some(threads);
+        self
+    }
+
     pub fn initialize(&self) -> DBResult<DB<R>> {
-        let config = Config {
+        if (self.in_memory || self.ephemeral) && self.data_dir.is_some() {
+            return Err(DBError::ValidationError(
+                "An in-memory or ephemeral database cannot have a data directory".to_owned(),
+            ));
+        }
+        DB::initialize(self.build())
+    }
+
+    /// Open a full backup made with `DB::backup_incremental` in the data directory for reading, without
+    /// restoring it first. Nothing is written to the backup directory, and all writes return
+    /// `DBError::ReadOnly`. Incremental backups must be restored with `DB::restore` instead.
+    pub fn open_backup(&self) -> DBResult<DB<R>> {
+        DB::open_backup(self.build())
+    }
+
+    /// Open the database in the data directory for reading as it was at `position`, i.e. with the
+    /// writes before it applied and the ones from it on ignored. Positions come from write
+    /// receipts or `DB::end_position`. Nothing is written to the data directory, and all writes
+    /// return `DBError::ReadOnly`, but other handles can keep writing while it is open.
+    ///
+    /// Compaction drops the records that later writes supersede, so positions in compacted
+    /// segments refer to the compacted rows, and the state at them may include later writes. If a
+    /// segment that has been read is compacted or merged while the handle is open, its queries
+    /// return `DBError::ConsistencyError`.
+    pub fn open_at(&self, position: LogPosition) -> DBResult<DB<R>> {
+        DB::open_at(self.build(), position)
+    }
+
+    fn build(&self) -> Config<R> {
+        Config {
             fields: R::schema(),
             primary_key: R::primary_key(),
             secondary_keys: R::secondary_keys(),
+            composite_keys: R::composite_keys(),
             data_dir: self.data_dir.clone().unwrap_or("db_data".to_string()),
             segment_size: self.segment_size.unwrap_or(4 * 1024 * 1024), // 4MB
+            max_segment_records: self.max_segment_records,
+            max_segment_age: self.max_segment_age,
             write_durability: self
                 .write_durability
                 .clone()
                 .unwrap_or(WriteDurability::Flush),
+            group_commit_window: self.group_commit_window.unwrap_or(Duration::ZERO),
             read_consistency: self
                 .read_consistency
                 .clone()
                 .unwrap_or(ReadConsistency::Strong),
-        };
-
-        DB::initialize(config)
+            manifest_verification: self
+                .manifest_verification
+                .clone()
+                .unwrap_or(ManifestVerification::Disabled),
+            merge_operator: self.merge_operator,
+            non_indexed_queries: self
+                .non_indexed_queries
+                .clone()
+                .unwrap_or(NonIndexedQueries::Refuse),
+            delete_mode: self.delete_mode.clone().unwrap_or(DeleteMode::Hard),
+            expiry_field: self.expiry_field.clone(),
+            write_transforms: self.write_transforms.clone(),
+            checks: self.checks.clone(),
+            collations: self.collations.clone(),
+            computed_indexes: self.computed_indexes.clone(),
+            scrub_rate: self.scrub_rate.unwrap_or(4 * 1024 * 1024), // 4MiB/s
+            scrub_hook: self.scrub_hook,
+            max_disk_bytes: self.max_disk_bytes,
+            compact_on_open: self.compact_on_open,
+            retention: self.retention.clone(),
+            retention_hook: self.retention_hook,
+            archive: self.archive.clone(),
+            numeric_widening: self.numeric_widening,
+            read_only: self.read_only,
+            in_memory: self.in_memory,
+            ephemeral: self.ephemeral,
+            mmap_reads: self.mmap_reads,
+            io_uring: self.io_uring,
+            preallocate: self.preallocate,
+            compression: self.compression.unwrap_or_default(),
+            compress_appends: self.compress_appends,
+            compress_values_over: self.compress_values_over,
+            encryption: self.encryption.clone(),
+            compaction_strategy: self
+                .compaction_strategy
+                .clone()
+                .unwrap_or_else(|| Arc::new(SizeCompaction)),
+            compaction_threads: self
+                .compaction_threads
+                .unwrap_or_else(parallel::available_threads),
+        }
     }
 }
 
@@ -77,12 +570,104 @@ pub struct Config<R: Recordable> {
     pub fields: Vec<(R::Field, Type)>,
     pub primary_key: R::Field,
     pub secondary_keys: Vec<R::Field>,
+    pub composite_keys: Vec<Vec<R::Field>>,
     pub data_dir: String,
     pub segment_size: usize,
+    pub max_segment_records: Option<u64>,
+    pub max_segment_age: Option<Duration>,
     pub write_durability: WriteDurability,
+    pub group_commit_window: Duration,
     pub read_consistency: ReadConsistency,
+    pub manifest_verification: ManifestVerification,
+    pub merge_operator: Option<MergeOperator<R>>,
+    pub non_indexed_queries: NonIndexedQueries,
+    pub delete_mode: DeleteMode,
+    pub expiry_field: Option<R::Field>,
+    pub write_transforms: Vec<(R::Field, WriteTransform)>,
+    pub checks: Vec<(R::Field, FieldCheck)>,
+    pub collations: Vec<(R::Field, Collation)>,
+    pub computed_indexes: Vec<(String, ComputedKey)>,
+    pub scrub_rate: u64,
+    pub scrub_hook: Option<ScrubHook>,
+    pub max_disk_bytes: Option<u64>,
+    pub compact_on_open: Option<f64>,
+    pub retention: Option<RetentionPolicy>,
+    pub retention_hook: Option<RetentionHook>,
+    pub archive: Option<(Arc<dyn ArchiveStore>, Duration)>,
+    pub numeric_widening: bool,
+    pub read_only: bool,
+    pub in_memory: bool,
+    pub ephemeral: bool,
+    pub mmap_reads: bool,
+    pub io_uring: bool,
+    pub preallocate: bool,
+    pub compression: Compression,
+    pub compress_appends: bool,
+    pub compress_values_over: Option<usize>,
+    pub encryption: Option<Arc<dyn EncryptionProvider>>,
+    pub compaction_strategy: Arc<dyn CompactionStrategy>,
+    pub compaction_threads: usize,
+}
+
+impl<R: Recordable> Config<R> {
+    /// The header of a new segment of the data file `data_uuid`, whose records are compressed with
+    /// `compression` and encrypted with the current key of the encryption provider.
+    pub fn segment_header(&self, data_uuid: Uuid, compression: Compression) -> MetadataHeader {
+        MetadataHeader::new(data_uuid)
+            .with_compression(compression)
+            .with_key_id(self.encryption.as_ref().map(|e| e.current_key_id()))
+    }
+
+    /// Whether the active segment has reached `ConfigBuilder::max_segment_records` or
+    /// `ConfigBuilder::max_segment_age`.
+    pub(crate) fn exceeds_segment_limits(&self, active: &SegmentStats) -> bool {
+        let too_many = self
+            .max_segment_records
+            .is_some_and(|max_records| active.records >= max_records.max(1));
+        let too_old = self.max_segment_age.is_some_and(|max_age| {
+            active.first_written.is_some_and(|first_written| {
+                first_written.elapsed().unwrap_or(Duration::ZERO) >= max_age
+            })
+        });
+        too_many || too_old
+    }
+
+    /// Allocate `segment_size` bytes past the end of the files of a new active segment, if enabled
+    /// with `ConfigBuilder::preallocate`.
+    pub(crate) fn preallocate_segment(
+        &self,
+        metadata_file: &fs::File,
+        data_file: &fs::File,
+    ) -> io::Result<()> {
+        if !self.preallocate {
+            return Ok(());
+        }
+        for file in [metadata_file, data_file] {
+            preallocate(file, file.metadata()?.len(), self.segment_size as u64)?;
+        }
+        Ok(())
+    }
+
+    /// The compression of new active segments, see `ConfigBuilder::compress_appends`.
+    pub fn append_compression(&self) -> Compression {
+        if self.compress_appends {
+            self.compression
+        } else {
+            Compression::None
+        }
+    }
 }
 
+/// A check that a field value must pass to be written, see `ConfigBuilder::check`.
+pub type FieldCheck = fn(&Value) -> bool;
+
+/// Computes the key of a computed index from the values of a record, see
+/// `ConfigBuilder::computed_index`.
+pub type ComputedKey = fn(&[Value]) -> Value;
+
+/// Folds a merge delta into the previous version of a record, see `ConfigBuilder::merge_operator`.
+pub type MergeOperator<R> = fn(old: Option<R>, delta: R) -> R;
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ReadConsistency {
     /// Reads by client A are guaranteed to see writes by themselves and any writes by other clients B
@@ -93,14 +678,78 @@ pub enum ReadConsistency {
     Strong,
 }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ManifestVerification {
+    /// Segment files are not checked against the manifest.
+    Disabled,
+    /// Segment files that do not match the manifest are logged as warnings.
+    Warn,
+    /// Initialization fails with `DBError::ConsistencyError` if segment files do not match the manifest.
+    Refuse,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum NonIndexedQueries {
+    /// Queries by non-indexed fields fail with `DBError::ValidationError`.
+    Refuse,
+    /// Queries by non-indexed fields scan over all segments, like `scan_filter`. This makes ad-hoc
+    /// queries by rarely used fields possible without maintaining an index, but each such query
+    /// reads the whole log.
+    Scan,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DeleteMode {
+    /// Deleted records are replaced by tombstones and can no longer be read.
+    Hard,
+    /// Deleted records are flagged as deleted. They are hidden from queries, but can still be
+    /// read with `DB::include_deleted` and restored with `DB::undelete`. `DB::purge` deletes a
+    /// record for good.
+    Soft,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum WriteDurability {
     /// Changes are written to the OS write buffer but not immediately synced to disk.
     /// This is generally recommended. Most OSes will sync the write buffer to disk within a few seconds.
     Flush,
-    /// Changes are written to the OS write buffer and synced to disk immediately.
-    /// Offers the best durability guarantees but is a lot slower.
+    /// Changes are written to the OS write buffer and synced to disk before the write returns.
+    /// Offers the best durability guarantees but is a lot slower. Concurrent writers share syncs,
+    /// see `ConfigBuilder::group_commit_window`. Reads may see records before they are synced.
+    /// The data directory is also synced when segment files are created, renamed or removed.
     FlushSync,
+    /// Like `FlushSync`, but the files are synced with a data sync (`fdatasync` on Linux), which
+    /// skips the file metadata that is not needed to read the data back, e.g. the modification
+    /// time. Appended records are as durable as with `FlushSync`, at a lower latency on most
+    /// filesystems.
+    FlushDataSync,
+}
+
+impl WriteDurability {
+    /// Persist the writes made to `file` according to the durability policy.
+    pub(crate) fn persist(&self, file: &mut fs::File) -> io::Result<()> {
+        file.flush()?;
+        self.sync(file)
+    }
+
+    /// Sync the flushed writes made to `file` to disk according to the durability policy.
+    pub(crate) fn sync(&self, file: &fs::File) -> io::Result<()> {
+        match self {
+            WriteDurability::Flush => Ok(()),
+            WriteDurability::FlushSync => file.sync_all(),
+            WriteDurability::FlushDataSync => file.sync_data(),
+        }
+    }
+
+    /// Persist the creations, renames and removals of files in the directory `dir_path` according
+    /// to the durability policy. Syncing a file does not sync its directory entry, so without this
+    /// a power loss can lose whole files whose contents were synced.
+    pub(crate) fn persist_dir(&self, dir_path: &Path) -> io::Result<()> {
+        if *self != WriteDurability::Flush {
+            fs::sync_dir(dir_path)?;
+        }
+        Ok(())
+    }
 }
 
 impl Display for WriteDurability {
@@ -0,0 +1,95 @@
+use super::*;
+
+/// Encrypts the records of segments at rest, see `ConfigBuilder::encryption`.
+///
+/// Keys are identified by ids, and the id of the key of each segment is stored in its metadata
+/// header. A provider must be able to decrypt with every key that a segment on disk still uses:
+/// after the current key is changed, segments are re-encrypted with it as they are compacted.
+pub trait EncryptionProvider: Send + Sync {
+    /// The id of the key that new segments are encrypted with.
+    fn current_key_id(&self) -> u32;
+
+    /// Encrypt a stored record with the key `key_id`.
+    fn encrypt(&self, key_id: u32, plaintext: &[u8]) -> DBResult<Vec<u8>>;
+
+    /// Decrypt a record encrypted with `encrypt`. Returns an error if the key is unknown or the
+    /// ciphertext has been tampered with.
+    fn decrypt(&self, key_id: u32, ciphertext: &[u8]) -> DBResult<Vec<u8>>;
+}
+
+/// An `EncryptionProvider` using AES-256-GCM with a random nonce for each record, stored before
+/// the ciphertext.
+#[cfg(feature = "aes-gcm")]
+pub struct AesGcmProvider {
+    current_key_id: u32,
+    ciphers: HashMap<u32, aes_gcm::Aes256Gcm>,
+}
+
+#[cfg(feature = "aes-gcm")]
+impl AesGcmProvider {
+    const NONCE_SIZE: usize = 12;
+
+    /// A provider that encrypts new segments with `key`, identified by `key_id`.
+    pub fn new(key_id: u32, key: [u8; 32]) -> AesGcmProvider {
+        AesGcmProvider {
+            current_key_id: key_id,
+            ciphers: HashMap::new(),
+        }
+        .with_old_key(key_id, key)
+    }
+
+    /// Add a key that is no longer used for new segments, but may still be needed to read
+    /// segments that have not been compacted since the key was rotated.
+    pub fn with_old_key(mut self, key_id: u32, key: [u8; 32]) -> AesGcmProvider {
+        use aes_gcm::KeyInit;
+        self.ciphers
+            .entry(key_id)
+            .or_insert_with(|| aes_gcm::Aes256Gcm::new(&key.into()));
+        self
+    }
+
+    fn cipher(&self, key_id: u32) -> DBResult<&aes_gcm::Aes256Gcm> {
+        self.ciphers.get(&key_id).ok_or_else(|| {
+            DBError::ValidationError(format!("Encryption key {} is not available", key_id))
+        })
+    }
+}
+
+#[cfg(feature = "aes-gcm")]
+impl EncryptionProvider for AesGcmProvider {
+    fn current_key_id(&self) -> u32 {
+        self.current_key_id
+    }
+
+    fn encrypt(&self, key_id: u32, plaintext: &[u8]) -> DBResult<Vec<u8>> {
+        use aes_gcm::aead::{Aead, AeadCore, OsRng};
+        let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher(key_id)?
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| DBError::ValidationError("Failed to encrypt record".to_owned()))?;
+
+        let mut encrypted = Vec::with_capacity(Self::NONCE_SIZE + ciphertext.len());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend(ciphertext);
+        Ok(encrypted)
+    }
+
+    fn decrypt(&self, key_id: u32, ciphertext: &[u8]) -> DBResult<Vec<u8>> {
+        use aes_gcm::aead::Aead;
+        if ciphertext.len() < Self::NONCE_SIZE {
+            return Err(DBError::ConsistencyError(
+                "Encrypted record is truncated".to_owned(),
+            ));
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(Self::NONCE_SIZE);
+        self.cipher(key_id)?
+            .decrypt(nonce.into(), ciphertext)
+            .map_err(|_| {
+                DBError::ConsistencyError(
+                    "Encrypted record failed authentication, it is damaged or was encrypted with a different key"
+                        .to_owned(),
+                )
+            })
+    }
+}
@@ -1,4 +1,12 @@
 use super::*;
+use segment_map::{MappedSegment, SegmentSource};
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+
+/// The number of metadata rows that compaction reads and decodes at a time on one thread
+const COMPACTION_READ_ROWS: u64 = 4096;
+/// The number of records that compaction encodes at a time on one thread
+const COMPACTION_ENCODE_RECORDS: usize = 256;
 
 pub struct Engine<R: Recordable> {
     pub config: Config<R>,
@@ -6,19 +14,91 @@ pub struct Engine<R: Recordable> {
 
     data_dir_path: PathBuf,
     primary_key_index: usize,
+    /// Indexes of the secondary key fields in a record, in the order of `config.secondary_keys`
+    secondary_key_indexes: Vec<usize>,
+    /// Collations of the secondary indexes, in the order of `config.secondary_keys`
+    secondary_collations: Vec<Collation>,
+    /// Indexes of the fields of each composite key in a record, in the order of `config.composite_keys`
+    composite_key_indexes: Vec<Vec<usize>>,
+    /// Index of the expiry field in a record, see `ConfigBuilder::expiry_field`
+    pub expiry_index: Option<usize>,
+    /// How the values of enum and fixed-length bytes fields are stored in the log
+    log_encoding: LogEncoding,
     refresh_next_logkey: LogKey,
+    /// The version to assign to the next write, see `RecordMeta::version`
+    next_version: u64,
+    /// Data file UUID of each indexed segment at the time it was indexed. Compaction gives a segment
+    /// a new data file, so a different UUID on disk means that its log keys are no longer valid.
+    indexed_segment_uuids: BTreeMap<u16, Uuid>,
+    /// The active segment of a backup opened with `open_backup`, which has no active symlink, or
+    /// the last segment read by a handle opened with `open_at`. `None` for regular data directories.
+    fixed_active_segment_num: Option<u16>,
+    /// The position that a handle opened with `open_at` reads the log up to, excluding it
+    point_in_time: Option<LogKey>,
+    /// Handle to the manifest as of the last check for compacted segments. Compaction always
+    /// rewrites the manifest, so the check can be skipped while the handle is current.
+    manifest_file: Option<fs::File>,
+    /// Token checked between segment reads, set for the duration of `DB::with_cancellation`
+    pub cancellation: Option<Cancellation>,
+    /// Whether queries return soft-deleted records, set for the duration of `DB::include_deleted`
+    pub include_deleted: bool,
+    /// The segment scrubbed last by `scrub_next_segment`
+    scrub_cursor: Option<u16>,
+    /// Size of the data directory after the last compaction triggered by the disk quota
+    quota_compacted_size: Option<u64>,
+    /// Statistics of the sealed segments for the compaction strategy, with the data file UUID and
+    /// metadata length of each segment at the time. Sealed segments change only when compacted.
+    sealed_stats: BTreeMap<u16, (Uuid, u64, SegmentStats)>,
+    /// Data file UUID and metadata length of the segments after maintenance compacted them,
+    /// see `SegmentStats::compacted`
+    maintenance_compacted: BTreeMap<u16, (Uuid, u64)>,
+    /// The quiesce requested by this handle with `DB::quiesce`, lifted when dropped
+    pub quiesce_flag: Option<QuiesceFlag>,
+    /// Shares the syncs of `FlushSync` and `FlushDataSync` writes, `None` for read-only handles
+    group_commit: Option<GroupCommit>,
+    /// Records appended with `FlushSync` or `FlushDataSync` that must be synced before the write
+    /// returns, synced by `with_exclusive_lock` after releasing the lock
+    pending_sync: Option<PendingSync>,
 
     active_metadata_file: fs::File,
     active_data_file: fs::File,
+    /// Memory maps of the indexed segments that have been read from, see `ConfigBuilder::mmap_reads`.
+    /// Dropped when their segment is compacted or merged, so that the old files can be freed.
+    mapped_segments: RefCell<BTreeMap<u16, Arc<MappedSegment>>>,
+    /// Whether memory maps turned out to be unsupported on this platform
+    mmap_unavailable: Cell<bool>,
+    /// How records are read and appended, see `ConfigBuilder::io_uring`
+    io: Box<dyn IoBackend>,
 
     // TODO: these could be made private. Currently they are public for testing in lib.rs.
     pub primary_memtable: PrimaryMemtable,
     pub secondary_memtables: Vec<SecondaryMemtable>,
+    composite_memtables: Vec<SecondaryMemtable<Vec<IndexableValue>>>,
+    /// Memtables of the computed indexes, in the order of `config.computed_indexes`
+    computed_memtables: Vec<SecondaryMemtable>,
+    /// Log keys of the merge deltas written after the record that the primary memtable points to,
+    /// in log order. Deltas are only indexed by the primary key, since they cannot change the other keys.
+    merge_deltas: BTreeMap<IndexableValue, Vec<LogKey>>,
+    /// Log keys of the soft-deleted records by primary key. Soft-deleted records are not in the
+    /// other memtables, so queries skip them unless they are made with `include_deleted`.
+    deleted_memtable: PrimaryMemtable,
+    /// Primary keys whose newest version in the log is a tombstone, so that they keep shadowing
+    /// the records of mounted sources, see `DB::mount`.
+    tombstoned_keys: HashSet<IndexableValue>,
+    /// The wheels of the `DB::expirations` streams, kept up to date with the expiry times of the
+    /// indexed records
+    expiry_watches: Vec<ExpiryWatch>,
 }
 
 impl<R: Recordable> Engine<R> {
     pub fn initialize(config: Config<R>) -> DBResult<Engine<R>> {
+        if config.read_only {
+            return Self::open_read_only(config);
+        }
+
         info!("Initializing DB...");
+        // Checked before any segment is created with the compression
+        config.compression.check_available()?;
         // If data_dir does not exist or is empty, create it and any necessary files.
         // After creation, the directory should always be in a complete state without missing files.
 
@@ -44,7 +124,7 @@ impl<R: Recordable> Engine<R> {
             for entry in fs::read_dir(&data_dir_path)? {
                 let entry = entry?;
                 let path = entry.path();
-                if path.is_file()
+                if fs::metadata(&path).is_ok_and(|metadata| metadata.is_file())
                     && path.file_name().unwrap() != LOCK_FILENAME
                     && path.file_name().unwrap() != EXCL_LOCK_REQ_FILENAME
                 {
@@ -53,14 +133,300 @@ impl<R: Recordable> Engine<R> {
             }
 
             // Create the initial segment files
-            let (segment_uuid, _) = create_segment_data_file(&data_dir_path)?;
-            let (segment_num, _) = create_segment_metadata_file(&data_dir_path, &segment_uuid)?;
+            let (segment_uuid, data_path) = create_segment_data_file(&data_dir_path)?;
+            let (segment_num, metadata_path) = create_segment_metadata_file(
+                &data_dir_path,
+                &config.segment_header(segment_uuid, config.append_compression()),
+                &config.write_durability,
+            )?;
+            config.preallocate_segment(
+                &APPEND_MODE.open(metadata_path)?,
+                &APPEND_MODE.open(data_path)?,
+            )?;
             set_active_segment(&data_dir_path, segment_num)?;
+            Manifest::compute(&data_dir_path)?.write(&data_dir_path)?;
+            // The segment files must be on disk before the initialized file can be
+            config.write_durability.persist_dir(&data_dir_path)?;
 
             // Create the initialized file to indicate that the directory is in a complete state
             fs::File::create(data_dir_path.join(INITIALIZED_FILENAME))?;
+            config.write_durability.persist_dir(&data_dir_path)?;
+        }
+
+        // Data directories created before manifests were introduced get one based on their current state
+        if !fs::exists(data_dir_path.join(MANIFEST_FILENAME))? {
+            info!("No manifest found, creating one from the current segment files");
+            Manifest::compute(&data_dir_path)?.write(&data_dir_path)?;
+        }
+
+        StoredSchema::check(
+            &data_dir_path,
+            StoredSchema::from_config(&config),
+            false,
+            config.numeric_widening,
+        )?;
+
+        Self::verify_manifest(&config, &data_dir_path)?;
+
+        // Appends are written in the format of the active segment, so a segment in an older format
+        // is sealed and a new one is started. The sealed segment is upgraded when it is compacted.
+        let active_target = fs::read_link(data_dir_path.join(ACTIVE_SYMLINK_FILENAME))?;
+        let active_header =
+            read_metadata_header(&mut READ_MODE.open(data_dir_path.join(active_target))?)?;
+        validate_metadata_header(&active_header)?;
+        if active_header.version < SEGMENT_FORMAT_VERSION {
+            info!(
+                "Active segment is in format version {}, starting a new segment in version {}",
+                active_header.version, SEGMENT_FORMAT_VERSION
+            );
+            let (segment_uuid, data_path) = create_segment_data_file(&data_dir_path)?;
+            let (segment_num, metadata_path) = create_segment_metadata_file(
+                &data_dir_path,
+                &config.segment_header(segment_uuid, config.append_compression()),
+                &config.write_durability,
+            )?;
+            config.preallocate_segment(
+                &APPEND_MODE.open(metadata_path)?,
+                &APPEND_MODE.open(data_path)?,
+            )?;
+            set_active_segment(&data_dir_path, segment_num)?;
+            Manifest::compute(&data_dir_path)?.write(&data_dir_path)?;
+            config.write_durability.persist_dir(&data_dir_path)?;
+        }
+
+        // Complete a batch whose append was interrupted by a crash
+        JournalEntry::recover(&data_dir_path)?;
+        // An append interrupted outside of a batch leaves a partial row or unreferenced data behind
+        fsck::truncate_torn_append(&data_dir_path)?;
+
+        let mut engine = Self::open(config, lock_manager, data_dir_path, None, None)?;
+        engine.compact_on_open()?;
+        Ok(engine)
+    }
+
+    /// Compact the active segment and merge the sealed segments if the log has more garbage than
+    /// configured with `ConfigBuilder::compact_on_open`.
+    fn compact_on_open(&mut self) -> DBResult<()> {
+        let Some(max_garbage_ratio) = self.config.compact_on_open else {
+            return Ok(());
+        };
+        // Soft-deleted records are kept by compaction, so they do not count as garbage
+        let log_bytes = self.log_record_bytes()?;
+        let current_bytes = self.size_report(0)?.total_bytes;
+        let deleted = self
+            .deleted_memtable
+            .iter()
+            .map(|(_, log_key)| (0, log_key));
+        let live_bytes = current_bytes + self.tagged_record_lengths(deleted, 1)?[0];
+        if log_bytes == 0 {
+            return Ok(());
+        }
+        let garbage_ratio = log_bytes.saturating_sub(live_bytes) as f64 / log_bytes as f64;
+        if garbage_ratio <= max_garbage_ratio {
+            return Ok(());
+        }
+
+        info!(
+            "Superseded records take up {:.0}% of the log, compacting...",
+            garbage_ratio * 100.0
+        );
+        self.with_exclusive_lock(|engine| {
+            engine.compact_segments(SegmentSelector::Active)?;
+            let active_num = engine.active_segment_num()?;
+            let sealed: Vec<u16> = list_segment_numbers(&engine.data_dir_path)?
+                .into_iter()
+                .filter(|&segment_num| segment_num != active_num)
+                .collect();
+            if !sealed.is_empty() {
+                engine.merge_segments(&sealed)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The total length of the records in the log, including superseded versions and tombstones,
+    /// summed from the metadata rows.
+    fn log_record_bytes(&self) -> DBResult<u64> {
+        let mut bytes = 0;
+        for segment_num in list_segment_numbers(&self.data_dir_path)? {
+            let (header, _, mut metadata_file) = self.segment_file_state(segment_num)?;
+            metadata_file.seek(SeekFrom::Start(METADATA_FILE_HEADER_SIZE as u64))?;
+            let mut metadata_buf = vec![];
+            metadata_file.read_to_end(&mut metadata_buf)?;
+            for row in metadata_buf.chunks_exact(metadata_row_length(header.version)) {
+                let row = MetadataRow::deserialize(header.version, row)?;
+                if !row.is_unused() {
+                    bytes += row.length;
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Open an initialized data directory for reading without writing anything to it, see
+    /// `ConfigBuilder::read_only`. Other handles may keep writing while it is open, and its reads
+    /// see their writes like those of any other handle.
+    fn open_read_only(config: Config<R>) -> DBResult<Engine<R>> {
+        info!("Opening DB read-only...");
+        let data_dir_path = Path::new(&config.data_dir).to_path_buf();
+        if !fs::exists(data_dir_path.join(INITIALIZED_FILENAME))? {
+            return Err(DBError::ValidationError(format!(
+                "Data directory {} has not been initialized",
+                data_dir_path.display()
+            )));
+        }
+
+        let mut lock_manager = LockManager::existing(&data_dir_path)?;
+        lock_manager.lock_shared()?;
+        let result = StoredSchema::check(
+            &data_dir_path,
+            StoredSchema::from_config(&config),
+            true,
+            config.numeric_widening,
+        )
+        .and_then(|_| Self::verify_manifest(&config, &data_dir_path));
+        if let Err(e) = result {
+            lock_manager.unlock()?;
+            return Err(e);
+        }
+
+        Self::open(config, lock_manager, data_dir_path, None, None)
+    }
+
+    /// Check the segment files against the manifest as configured with `ConfigBuilder::verify_manifest`.
+    fn verify_manifest(config: &Config<R>, data_dir_path: &Path) -> DBResult<()> {
+        if config.manifest_verification == ManifestVerification::Disabled {
+            return Ok(());
+        }
+
+        info!("Verifying segment files against the manifest...");
+        let problems = match Manifest::read(data_dir_path)? {
+            Some(manifest) => manifest.verify(data_dir_path)?,
+            None => vec!["Manifest is missing".to_owned()],
+        };
+
+        if !problems.is_empty() {
+            if config.manifest_verification == ManifestVerification::Refuse {
+                return Err(DBError::ConsistencyError(format!(
+                    "Segment files do not match the manifest: {}",
+                    problems.join("; ")
+                )));
+            }
+            for problem in problems {
+                warn!("Segment files do not match the manifest: {}", problem);
+            }
+        }
+        Ok(())
+    }
+
+    /// Open a backup directory made with `backup_incremental` for reading. Backups have no lock files
+    /// or active symlink, so the active segment is taken from the manifest, and the engine is read-only.
+    /// Only a full backup can be opened, since an incremental one lacks the unchanged segments.
+    pub fn open_backup(config: Config<R>) -> DBResult<Engine<R>> {
+        info!("Opening backup...");
+        let data_dir_path = Path::new(&config.data_dir).to_path_buf();
+
+        let manifest = Manifest::read(&data_dir_path)?.ok_or_else(|| {
+            DBError::ConsistencyError(format!(
+                "Backup {} has no manifest, it may be incomplete",
+                data_dir_path.display()
+            ))
+        })?;
+
+        // Segments merged into later ones are not in the manifest, so the numbering may have gaps
+        let segment_nums = manifest
+            .segments
+            .iter()
+            .map(|segment| segment.segment_num)
+            .chain([manifest.active_segment_num]);
+        for segment_num in segment_nums {
+            if !fs::exists(data_dir_path.join(metadata_filename(segment_num)))? {
+                return Err(DBError::ConsistencyError(format!(
+                    "Segment {} is missing from the backup. Incremental backups must be restored with DB::restore before opening them",
+                    segment_num
+                )));
+            }
+        }
+
+        // Backups made before schema files were introduced have none, and are opened unchecked
+        StoredSchema::check(
+            &data_dir_path,
+            StoredSchema::from_config(&config),
+            true,
+            config.numeric_widening,
+        )?;
+
+        let mut lock_manager = LockManager::read_only();
+        lock_manager.lock_shared()?;
+
+        Self::open(
+            config,
+            lock_manager,
+            data_dir_path,
+            Some(manifest.active_segment_num),
+            None,
+        )
+    }
+
+    /// Open a data directory for reading the log up to `position`, see `DB::open_at`. The handle
+    /// takes shared locks like any other, so that it can read while other handles write, but
+    /// nothing is written to the data directory, and all writes return `DBError::ReadOnly`.
+    pub fn open_at(config: Config<R>, position: LogPosition) -> DBResult<Engine<R>> {
+        info!("Opening DB at {}...", position);
+        let data_dir_path = Path::new(&config.data_dir).to_path_buf();
+        if !fs::exists(data_dir_path.join(INITIALIZED_FILENAME))? {
+            return Err(DBError::ValidationError(format!(
+                "Data directory {} has not been initialized",
+                data_dir_path.display()
+            )));
+        }
+
+        let mut lock_manager = LockManager::shared_only(data_dir_path.clone())?;
+        lock_manager.lock_shared()?;
+        let result = Self::check_point_in_time(&config, &data_dir_path, position);
+        if let Err(e) = result {
+            lock_manager.unlock()?;
+            return Err(e);
+        }
+
+        Self::open(
+            config,
+            lock_manager,
+            data_dir_path,
+            Some(position.segment_num()),
+            Some(LogKey::new(position.segment_num(), position.index())),
+        )
+    }
+
+    fn check_point_in_time(
+        config: &Config<R>,
+        data_dir_path: &Path,
+        position: LogPosition,
+    ) -> DBResult<()> {
+        StoredSchema::check(
+            data_dir_path,
+            StoredSchema::from_config(config),
+            true,
+            config.numeric_widening,
+        )?;
+        if !list_segment_numbers(data_dir_path)?.contains(&position.segment_num()) {
+            return Err(DBError::ValidationError(format!(
+                "Segment {} does not exist, it may have been merged into a later one",
+                position.segment_num()
+            )));
         }
+        Ok(())
+    }
 
+    /// Build the engine for a data directory that is in a complete state and read the indexes from it.
+    /// The lock manager must be holding a lock, which is released once the engine is ready.
+    fn open(
+        config: Config<R>,
+        lock_manager: LockManager,
+        data_dir_path: PathBuf,
+        fixed_active_segment_num: Option<u16>,
+        point_in_time: Option<LogKey>,
+    ) -> DBResult<Engine<R>> {
         // Calculate the index of the primary value in a record
         let primary_key_index = config
             .fields
@@ -70,9 +436,17 @@ impl<R: Recordable> Engine<R> {
                 "Primary key not found in schema after initialize".to_owned(),
             ))?;
 
-        // Join primary key and secondary keys vec into a single vec
+        // Join primary key, secondary keys and the fields of composite keys into a single vec
         let mut all_keys = vec![&config.primary_key];
         all_keys.extend(&config.secondary_keys);
+        for composite_key in &config.composite_keys {
+            if composite_key.is_empty() {
+                return Err(DBError::ValidationError(
+                    "Composite key must have at least one field".to_owned(),
+                ));
+            }
+            all_keys.extend(composite_key);
+        }
 
         // If any of the keys is not in the schema or
         // is not an IndexableValue, return an error
@@ -82,40 +456,191 @@ impl<R: Recordable> Engine<R> {
             )?;
 
             match value_type.primitive {
-                PrimitiveType::Int | PrimitiveType::String => {}
+                PrimitiveType::Int
+                | PrimitiveType::Decimal
+                | PrimitiveType::Float
+                | PrimitiveType::String
+                | PrimitiveType::Timestamp
+                | PrimitiveType::Enum(_)
+                | PrimitiveType::GeoPoint => {}
                 _ => return Err(DBError::ValidationError("Key must be indexable".to_owned())),
             }
         }
+
+        let field_index = |key: &R::Field| {
+            config
+                .fields
+                .iter()
+                .position(|(field, _)| field == key)
+                .ok_or(DBError::ValidationError(
+                    "Key must be present in the field schema".to_owned(),
+                ))
+        };
+        let secondary_key_indexes = config
+            .secondary_keys
+            .iter()
+            .map(field_index)
+            .collect::<DBResult<Vec<usize>>>()?;
+        let composite_key_indexes = config
+            .composite_keys
+            .iter()
+            .map(|composite_key| composite_key.iter().map(field_index).collect())
+            .collect::<DBResult<Vec<Vec<usize>>>>()?;
+
+        let expiry_index = match &config.expiry_field {
+            Some(expiry_field) => {
+                let index = field_index(expiry_field)?;
+                if !matches!(config.fields[index].1.primitive, PrimitiveType::Int) {
+                    return Err(DBError::ValidationError(
+                        "Expiry field must be an Int field".to_owned(),
+                    ));
+                }
+                Some(index)
+            }
+            None => None,
+        };
+
+        for (field, field_type) in &config.fields {
+            if let PrimitiveType::Enum(variants) = &field_type.primitive {
+                let distinct: HashSet<&String> = variants.iter().collect();
+                if variants.is_empty() || distinct.len() != variants.len() {
+                    return Err(DBError::ValidationError(format!(
+                        "Enum field {:?} must have at least one variant and no duplicates",
+                        field
+                    )));
+                }
+            }
+            if field_type.max_len.is_some()
+                && !matches!(
+                    field_type.primitive,
+                    PrimitiveType::String | PrimitiveType::Bytes
+                )
+            {
+                return Err(DBError::ValidationError(format!(
+                    "Field {:?} cannot have a maximum length, only string and bytes fields can",
+                    field
+                )));
+            }
+        }
+        let log_encoding = LogEncoding::from_schema(&config.fields, config.numeric_widening)
+            .with_value_compression(config.compress_values_over);
+
+        let computed_names: HashSet<&String> = config
+            .computed_indexes
+            .iter()
+            .map(|(name, _)| name)
+            .collect();
+        if computed_names.len() != config.computed_indexes.len() {
+            return Err(DBError::ValidationError(
+                "Computed indexes must have distinct names".to_owned(),
+            ));
+        }
+
+        let mut secondary_collations = vec![Collation::Binary; config.secondary_keys.len()];
+        for (field, collation) in &config.collations {
+            let index = get_secondary_memtable_index_by_field(&config.secondary_keys, field)
+                .ok_or_else(|| {
+                    DBError::ValidationError(format!(
+                        "Field {:?} must be a secondary key to have a collation",
+                        field
+                    ))
+                })?;
+            if !matches!(
+                config.fields[secondary_key_indexes[index]].1.primitive,
+                PrimitiveType::String
+            ) {
+                return Err(DBError::ValidationError(format!(
+                    "Field {:?} must be a string field to have a collation",
+                    field
+                )));
+            }
+            secondary_collations[index] = collation.clone();
+        }
+
         let primary_memtable = PrimaryMemtable::new();
         let secondary_memtables = config
             .secondary_keys
             .iter()
             .map(|_| SecondaryMemtable::new())
             .collect();
+        let composite_memtables = config
+            .composite_keys
+            .iter()
+            .map(|_| SecondaryMemtable::new())
+            .collect();
+        let computed_memtables = config
+            .computed_indexes
+            .iter()
+            .map(|_| SecondaryMemtable::new())
+            .collect();
 
-        let active_symlink = Path::new(&config.data_dir).join(ACTIVE_SYMLINK_FILENAME);
-
-        let active_target = fs::read_link(&active_symlink)?;
-        let active_metadata_path = Path::new(&config.data_dir).join(active_target);
-        let mut active_metadata_file = APPEND_MODE.open(&active_metadata_path)?;
+        // Backups and read-only handles may be on a read-only filesystem, and never append
+        let open_mode = match fixed_active_segment_num.is_some() || config.read_only {
+            true => &READ_MODE,
+            false => &APPEND_MODE,
+        };
+        let active_metadata_path = match fixed_active_segment_num {
+            Some(segment_num) => data_dir_path.join(metadata_filename(segment_num)),
+            None => {
+                let active_symlink = data_dir_path.join(ACTIVE_SYMLINK_FILENAME);
+                data_dir_path.join(fs::read_link(&active_symlink)?)
+            }
+        };
+        let mut active_metadata_file = open_mode.open(&active_metadata_path)?;
 
         let active_metadata_header = read_metadata_header(&mut active_metadata_file)?;
         validate_metadata_header(&active_metadata_header)?;
 
-        let active_data_path =
-            Path::new(&config.data_dir).join(active_metadata_header.uuid.to_string());
-        let active_data_file = APPEND_MODE.open(&active_data_path)?;
+        let active_data_path = data_dir_path.join(active_metadata_header.uuid.to_string());
+        let active_data_file = open_mode.open(&active_data_path)?;
+
+        let group_commit = match fixed_active_segment_num.is_some() || config.read_only {
+            true => None,
+            false => Some(GroupCommit::new(
+                &data_dir_path,
+                config.group_commit_window,
+            )?),
+        };
 
+        let io = io_backend::io_backend(config.io_uring);
         let mut engine = Engine::<R> {
             config,
             lock_manager,
             data_dir_path,
             primary_key_index,
+            secondary_key_indexes,
+            secondary_collations,
+            composite_key_indexes,
+            expiry_index,
+            log_encoding,
             primary_memtable,
             secondary_memtables,
+            composite_memtables,
+            computed_memtables,
+            merge_deltas: BTreeMap::new(),
+            deleted_memtable: PrimaryMemtable::new(),
+            tombstoned_keys: HashSet::new(),
+            expiry_watches: vec![],
             active_metadata_file,
             active_data_file,
             refresh_next_logkey: LogKey::new(1, 0),
+            next_version: 1,
+            indexed_segment_uuids: BTreeMap::new(),
+            fixed_active_segment_num,
+            point_in_time,
+            cancellation: None,
+            include_deleted: false,
+            scrub_cursor: None,
+            quota_compacted_size: None,
+            sealed_stats: BTreeMap::new(),
+            maintenance_compacted: BTreeMap::new(),
+            quiesce_flag: None,
+            group_commit,
+            pending_sync: None,
+            manifest_file: None,
+            mapped_segments: RefCell::new(BTreeMap::new()),
+            mmap_unavailable: Cell::new(false),
+            io,
         };
 
         info!("Rebuilding memtable indexes...");
@@ -127,43 +652,87 @@ impl<R: Recordable> Engine<R> {
         Ok(engine)
     }
 
+    /// Whether this is a read-only handle, see `Engine::open_backup`, `Engine::open_at` and
+    /// `ConfigBuilder::read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.fixed_active_segment_num.is_some() || self.config.read_only
+    }
+
+    fn active_segment_num(&self) -> DBResult<u16> {
+        match self.fixed_active_segment_num {
+            Some(segment_num) => Ok(segment_num),
+            None => {
+                let active_target =
+                    fs::read_link(self.data_dir_path.join(ACTIVE_SYMLINK_FILENAME))?;
+                parse_segment_number(&active_target)
+            }
+        }
+    }
+
     pub fn refresh_indexes(&mut self) -> DBResult<()> {
-        let active_symlink_path = self.data_dir_path.join(ACTIVE_SYMLINK_FILENAME);
-        let active_target = fs::read_link(active_symlink_path)?;
-        let active_metadata_path = self.data_dir_path.join(active_target);
+        // Log keys into compacted segments must be fixed before reading on from refresh_next_logkey
+        self.resync_compacted_segments()?;
 
-        let to_segnum = parse_segment_number(&active_metadata_path)?;
+        let to_segnum = self.active_segment_num()?;
         let from_segnum = self.refresh_next_logkey.segment_num();
         let mut from_index = self.refresh_next_logkey.index();
+        let point_in_time = self.point_in_time.clone();
 
         for segnum in from_segnum..=to_segnum {
             let metadata_path = self.data_dir_path.join(metadata_filename(segnum));
+            // Segments merged into later ones leave gaps in the numbering
+            if segnum != to_segnum && !fs::exists(&metadata_path)? {
+                continue;
+            }
             let mut metadata_file = READ_MODE.open(&metadata_path)?;
 
             let metadata_len = metadata_file.seek(SeekFrom::End(0))?;
-            if (metadata_len - METADATA_FILE_HEADER_SIZE as u64) % METADATA_ROW_LENGTH as u64 != 0 {
+            let metadata_header = read_metadata_header(&mut metadata_file)?;
+            validate_metadata_header(&metadata_header)?;
+
+            let row_length = metadata_row_length(metadata_header.version) as u64;
+            if !(metadata_len - METADATA_FILE_HEADER_SIZE as u64).is_multiple_of(row_length) {
                 return Err(DBError::ConsistencyError(format!(
                     "Metadata file {} has invalid size: {}",
                     metadata_path.display(),
                     metadata_len
                 )));
             }
-
-            let metadata_header = read_metadata_header(&mut metadata_file)?;
-            validate_metadata_header(&metadata_header)?;
-
-            let data_path = self.data_dir_path.join(metadata_header.uuid.to_string());
-            let data_file = READ_MODE.open(data_path)?;
-
-            for ForwardLogReaderItem { record, index } in
-                ForwardLogReader::new_with_index(metadata_file, data_file, from_index)
-            {
+            self.indexed_segment_uuids
+                .insert(segnum, metadata_header.uuid);
+
+            let records: Box<dyn Iterator<Item = DBResult<(u64, Record)>>> =
+                match self.archived_keys(&metadata_header.uuid, metadata_len)? {
+                    Some(keys) => Box::new(
+                        keys.records
+                            .into_iter()
+                            .filter(move |(index, _)| *index >= from_index)
+                            .map(Ok),
+                    ),
+                    None => {
+                        let data_file = self.open_data_file(&metadata_header.uuid)?;
+                        Box::new(
+                            ForwardLogReader::new_with_index(metadata_file, data_file, from_index)
+                                .with_log_encoding(&self.log_encoding)
+                                .with_encryption(&self.config.encryption)
+                                .try_records()
+                                .map(|item| item.map(|item| (item.index, item.record))),
+                        )
+                    }
+                };
+
+            for item in records {
+                let (index, record) = item?;
                 let log_key = LogKey::new(segnum, index);
+                if point_in_time.as_ref().is_some_and(|end| log_key >= *end) {
+                    break;
+                }
+                self.next_version = self.next_version.max(record.version + 1);
 
                 if record.tombstone {
-                    self.remove_record_from_memtables(&record);
+                    self.remove_record_from_memtables(&record)?;
                 } else {
-                    self.insert_record_to_memtables(log_key, record);
+                    self.insert_record_to_memtables(log_key, record)?;
                 }
 
                 // Update from_index in case this is the last iteration: we need to know the next
@@ -183,131 +752,963 @@ impl<R: Recordable> Engine<R> {
         Ok(())
     }
 
-    fn insert_record_to_memtables(&mut self, log_key: LogKey, record: Record) {
-        for (sk_index, sk_field) in self.config.secondary_keys.iter().enumerate() {
-            let secondary_memtable = &mut self.secondary_memtables[sk_index];
-            let sk_field_index = self
-                .config
-                .fields
-                .iter()
-                .position(|(f, _)| sk_field == f)
-                .unwrap();
-            let sk = record.at(sk_field_index).as_indexable().unwrap();
+    pub fn end_position(&mut self) -> DBResult<LogPosition> {
+        self.refresh_indexes()?;
+        Ok(LogPosition::from(self.refresh_next_logkey.clone()))
+    }
 
-            secondary_memtable.set(sk, log_key.clone());
-        }
+    pub fn calibrate_durability(
+        &self,
+        writes: usize,
+        record_size: usize,
+    ) -> DBResult<DurabilityReport> {
+        calibration::calibrate_durability(&self.data_dir_path, writes, record_size)
+    }
 
-        // Doing this last because this moves log_key
-        let pk = record.at(self.primary_key_index).as_indexable().unwrap();
-        self.primary_memtable.set(pk, log_key);
+    pub fn backup_incremental(
+        &self,
+        backup_dir: &str,
+        prev_manifest: Option<&Manifest>,
+    ) -> DBResult<Manifest> {
+        backup::backup(&self.data_dir_path, Path::new(backup_dir), prev_manifest)
     }
 
-    fn remove_record_from_memtables(&mut self, record: &Record) {
-        let pk = record.at(self.primary_key_index).as_indexable().unwrap();
+    pub fn next_sequence(&self, name: &str) -> DBResult<u64> {
+        sequence::next_sequence(&self.data_dir_path, name)
+    }
 
-        if let Some(plk) = self.primary_memtable.remove(&pk) {
-            for (sk_index, sk_field) in self.config.secondary_keys.iter_mut().enumerate() {
-                let secondary_memtable = &mut self.secondary_memtables[sk_index];
-                let sk_field_index = self
-                    .config
-                    .fields
-                    .iter()
-                    .position(|(f, _)| sk_field == f)
-                    .unwrap();
-                let sk = record.at(sk_field_index).as_indexable().unwrap();
+    pub fn index_dump(&self) -> IndexDump {
+        fn sorted_positions(log_keys: &HashSet<LogKey>) -> Vec<LogPosition> {
+            let mut positions: Vec<LogPosition> =
+                log_keys.iter().cloned().map(LogPosition::from).collect();
+            positions.sort();
+            positions
+        }
 
-                secondary_memtable.remove(&sk, &plk);
-            }
+        let mut indexes = vec![DumpedIndex {
+            field: format!("{:?}", self.config.primary_key),
+            primary: true,
+            entries: self
+                .primary_memtable
+                .iter()
+                .map(|(key, log_key)| {
+                    (
+                        Value::from(key.clone()),
+                        vec![LogPosition::from(log_key.clone())],
+                    )
+                })
+                .collect(),
+        }];
+
+        for (sk_field, memtable) in self
+            .config
+            .secondary_keys
+            .iter()
+            .zip(&self.secondary_memtables)
+        {
+            indexes.push(DumpedIndex {
+                field: format!("{:?}", sk_field),
+                primary: false,
+                entries: memtable
+                    .iter()
+                    .map(|(key, log_keys)| (Value::from(key.clone()), sorted_positions(log_keys)))
+                    .collect(),
+            });
         }
-    }
 
-    pub fn batch_upsert_records(&mut self, records: impl Iterator<Item = Record>) -> DBResult<()> {
-        debug!("Opening file in append mode...");
+        for (ck_fields, memtable) in self
+            .config
+            .composite_keys
+            .iter()
+            .zip(&self.composite_memtables)
+        {
+            indexes.push(DumpedIndex {
+                field: format!("{:?}", ck_fields),
+                primary: false,
+                entries: memtable
+                    .iter()
+                    .map(|(key, log_keys)| {
+                        let values = key.iter().cloned().map(Value::from).collect();
+                        (Value::Record(values), sorted_positions(log_keys))
+                    })
+                    .collect(),
+            });
+        }
 
-        if !self.ensure_metadata_file_is_active()?
-            || !ensure_active_metadata_is_valid(
-                &self.data_dir_path,
-                &mut self.active_metadata_file,
-            )?
+        for ((name, _), memtable) in self
+            .config
+            .computed_indexes
+            .iter()
+            .zip(&self.computed_memtables)
         {
-            // The log file has been rotated, so we must try again
-            return self.batch_upsert_records(records);
+            indexes.push(DumpedIndex {
+                field: name.clone(),
+                primary: false,
+                entries: memtable
+                    .iter()
+                    .map(|(key, log_keys)| (Value::from(key.clone()), sorted_positions(log_keys)))
+                    .collect(),
+            });
         }
 
-        let active_symlink_path = self.data_dir_path.join(ACTIVE_SYMLINK_FILENAME);
-        let active_target = fs::read_link(active_symlink_path)?;
-        let segment_num = parse_segment_number(&active_target)?;
+        IndexDump { indexes }
+    }
 
-        debug!("Appending to log file");
+    /// Re-index the segments that have been compacted by another handle since they were indexed.
+    fn resync_compacted_segments(&mut self) -> DBResult<()> {
+        // This handle may have compacted segments itself, which keeps the manifest up to date
+        let indexed_segment_uuids = &self.indexed_segment_uuids;
+        self.mapped_segments
+            .get_mut()
+            .retain(|segment_num, mapped| {
+                indexed_segment_uuids.get(segment_num) == Some(&mapped.header.uuid)
+            });
+
+        let manifest_path = self.data_dir_path.join(MANIFEST_FILENAME);
+        if let Some(manifest_file) = &self.manifest_file {
+            if is_file_same_as_path(manifest_file, &manifest_path)? {
+                return Ok(());
+            }
+        }
+        let mut manifest_file = READ_MODE.open(&manifest_path)?;
+        // Compaction may have dropped the records with the greatest versions
+        let mut contents = String::new();
+        manifest_file.read_to_string(&mut contents)?;
+        match Manifest::deserialize(&contents) {
+            Ok(manifest) => self.next_version = self.next_version.max(manifest.next_version),
+            Err(e) => warn!("Ignoring the next version of an unreadable manifest: {}", e),
+        }
 
-        let mut serialized_data: Vec<u8> = vec![];
-        let mut serialized_metadata: Vec<u8> = vec![];
-        let mut pending_memtable_insertions: Vec<(LogKey, Record)> = vec![];
-        for record in records {
-            // Write the record to the log
-            let serialized = &record.serialize();
-            let record_offset = self.active_data_file.seek(SeekFrom::End(0))?;
-            let record_length = serialized.len() as u64;
-            assert!(record_length > 0);
+        let indexed: Vec<(u16, Uuid)> = self
+            .indexed_segment_uuids
+            .iter()
+            .map(|(&segment_num, &uuid)| (segment_num, uuid))
+            .collect();
 
-            serialized_data.extend(serialized);
+        for (segment_num, indexed_uuid) in indexed {
+            let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+            let metadata_uuid = match fs::exists(&metadata_path)? {
+                true => Some(read_metadata_header(&mut READ_MODE.open(&metadata_path)?)?.uuid),
+                false => None,
+            };
+            // Compaction drops the records that later ones supersede, including ones written
+            // after the point in time, so the state as of it can no longer be read
+            if self.point_in_time.is_some() && metadata_uuid != Some(indexed_uuid) {
+                return Err(DBError::ConsistencyError(format!(
+                    "Segment {} has been compacted or merged since the database was opened at {}, its history is gone",
+                    segment_num,
+                    LogPosition::from(self.point_in_time.clone().unwrap())
+                )));
+            }
+            let Some(metadata_uuid) = metadata_uuid else {
+                // Merged into a later segment, which is re-indexed in turn
+                debug!("Segment {} has been merged, dropping it", segment_num);
+                self.remap_memtables(|log_key| {
+                    (log_key.segment_num() != segment_num).then(|| log_key.clone())
+                });
+                self.indexed_segment_uuids.remove(&segment_num);
+                continue;
+            };
+            if metadata_uuid != indexed_uuid {
+                self.resync_segment(segment_num)?;
+            }
+        }
 
-            let metadata_pos = self.active_metadata_file.seek(SeekFrom::End(0))?;
-            let metadata_index =
-                (metadata_pos - METADATA_FILE_HEADER_SIZE as u64) / METADATA_ROW_LENGTH as u64;
+        self.manifest_file = Some(manifest_file);
+        Ok(())
+    }
 
-            // Write the record metadata to the metadata file
-            let mut metadata_buf = vec![];
-            metadata_buf.extend(record_offset.to_be_bytes().into_iter());
-            metadata_buf.extend(record_length.to_be_bytes().into_iter());
+    /// Replace the memtable entries of a segment with ones read from its current layout.
+    /// Versions of records in later segments take precedence over the ones in this segment.
+    fn resync_segment(&mut self, segment_num: u16) -> DBResult<()> {
+        debug!("Segment {} has been compacted, re-indexing it", segment_num);
+
+        self.remap_memtables(|log_key| {
+            (log_key.segment_num() != segment_num).then(|| log_key.clone())
+        });
+
+        let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+        let mut metadata_file = READ_MODE.open(&metadata_path)?;
+        let metadata_len = metadata_file.seek(SeekFrom::End(0))?;
+        let metadata_header = read_metadata_header(&mut metadata_file)?;
+        validate_metadata_header(&metadata_header)?;
+        let row_count = (metadata_len - METADATA_FILE_HEADER_SIZE as u64)
+            / metadata_row_length(metadata_header.version) as u64;
+
+        let data_file = self.open_data_file(&metadata_header.uuid)?;
+
+        for item in ForwardLogReader::new(metadata_file, data_file)
+            .with_log_encoding(&self.log_encoding)
+            .with_encryption(&self.config.encryption)
+            .try_records()
+        {
+            let ForwardLogReaderItem { record, index, .. } = item?;
+            self.next_version = self.next_version.max(record.version + 1);
+
+            let pk = key_at(&record, self.primary_key_index)?;
+            let newest = self
+                .primary_memtable
+                .get(&pk)
+                .or_else(|| self.deleted_memtable.get(&pk));
+            if newest.is_some_and(|log_key| log_key.segment_num() > segment_num) {
+                continue;
+            }
 
-            assert_eq!(metadata_buf.len(), 16);
+            if record.tombstone {
+                self.remove_record_from_memtables(&record)?;
+            } else {
+                self.insert_record_to_memtables(LogKey::new(segment_num, index), record)?;
+            }
+        }
 
-            serialized_metadata.extend(metadata_buf);
+        if self.refresh_next_logkey.segment_num() == segment_num {
+            self.refresh_next_logkey = LogKey::new(segment_num, row_count);
+        }
+        self.indexed_segment_uuids
+            .insert(segment_num, metadata_header.uuid);
 
-            let log_key = LogKey::new(segment_num, metadata_index);
+        Ok(())
+    }
 
-            pending_memtable_insertions.push((log_key, record));
+    /// Map the log keys in the memtables with `f`. Log keys for which `f` returns `None` are
+    /// removed from the memtables.
+    fn remap_memtables(&mut self, f: impl Fn(&LogKey) -> Option<LogKey>) {
+        let remap_log_key = &f;
+        self.primary_memtable.remap(remap_log_key);
+        self.deleted_memtable.remap(remap_log_key);
+        for secondary_memtable in self.secondary_memtables.iter_mut() {
+            secondary_memtable.remap(remap_log_key);
+        }
+        for composite_memtable in self.composite_memtables.iter_mut() {
+            composite_memtable.remap(remap_log_key);
+        }
+        for computed_memtable in self.computed_memtables.iter_mut() {
+            computed_memtable.remap(remap_log_key);
+        }
+        for deltas in self.merge_deltas.values_mut() {
+            *deltas = deltas.iter().filter_map(remap_log_key).collect();
         }
+        self.merge_deltas.retain(|_, deltas| !deltas.is_empty());
+    }
 
-        self.active_data_file.write_all(&serialized_data)?;
-        self.active_metadata_file.write_all(&serialized_metadata)?;
+    /// Apply the result of compacting `segment_nums` into the last of them to the memtables, so
+    /// that they stay valid without re-indexing the segment.
+    fn apply_index_remap(
+        &mut self,
+        segment_nums: &[u16],
+        new_data_uuid: Uuid,
+        index_remap: &HashMap<LogKey, u64>,
+    ) {
+        let target_num = *segment_nums.last().expect("No segments were compacted");
+        self.remap_memtables(|log_key| {
+            if segment_nums.contains(&log_key.segment_num()) {
+                index_remap
+                    .get(log_key)
+                    .map(|&index| LogKey::new(target_num, index))
+            } else {
+                Some(log_key.clone())
+            }
+        });
 
-        // Flush and sync data and metadata to disk
-        if self.config.write_durability == WriteDurability::Flush {
-            self.active_data_file.flush()?;
-            self.active_metadata_file.flush()?;
-        } else if self.config.write_durability == WriteDurability::FlushSync {
-            self.active_data_file.flush()?;
-            self.active_data_file.sync_all()?;
-            self.active_metadata_file.flush()?;
-            self.active_metadata_file.sync_all()?;
+        if self.refresh_next_logkey.segment_num() == target_num {
+            self.refresh_next_logkey = LogKey::new(target_num, index_remap.len() as u64);
+        }
+        for segment_num in segment_nums {
+            self.indexed_segment_uuids.remove(segment_num);
         }
+        self.indexed_segment_uuids.insert(target_num, new_data_uuid);
+    }
 
-        debug!("Records appended to log file");
+    /// Get the primary, secondary, composite and computed keys of a record, in the order of the
+    /// memtables.
+    fn record_keys(&self, record: &Record) -> DBResult<RecordKeys> {
+        let computed = self
+            .config
+            .computed_indexes
+            .iter()
+            .map(|(name, compute)| {
+                compute(&record.values).as_indexable().ok_or_else(|| {
+                    DBError::ValidationError(format!(
+                        "Computed index {} must compute an indexable value",
+                        name
+                    ))
+                })
+            })
+            .collect::<DBResult<Vec<IndexableValue>>>()?;
+        self.keys_with(|field_index| key_at(record, field_index), computed)
+    }
 
-        for (log_key, record) in pending_memtable_insertions {
-            self.insert_record_to_memtables(log_key, record);
-        }
+    /// Get the keys of a record whose value at a field index is given by `key_at`, and whose
+    /// computed keys are `computed`.
+    fn keys_with(
+        &self,
+        key_at: impl Fn(usize) -> DBResult<IndexableValue>,
+        computed: Vec<IndexableValue>,
+    ) -> DBResult<RecordKeys> {
+        let pk = key_at(self.primary_key_index)?;
+        let sks = self
+            .secondary_key_indexes
+            .iter()
+            .zip(&self.secondary_collations)
+            .map(|(&sk_field_index, collation)| {
+                key_at(sk_field_index).map(|key| collation.apply(key))
+            })
+            .collect::<DBResult<Vec<IndexableValue>>>()?;
+        let cks = self
+            .composite_key_indexes
+            .iter()
+            .map(|field_indexes| {
+                field_indexes
+                    .iter()
+                    .map(|&field_index| key_at(field_index))
+                    .collect()
+            })
+            .collect::<DBResult<Vec<Vec<IndexableValue>>>>()?;
+        Ok((pk, sks, cks, computed))
+    }
 
+    fn insert_record_to_memtables(&mut self, log_key: LogKey, record: Record) -> DBResult<()> {
+        // All keys are extracted before modifying the memtables, so that they stay in sync on error
+        let keys = self.record_keys(&record)?;
+        let expires_at = self.expires_at(&record);
+        self.insert_keys_to_memtables(log_key, keys, expires_at, record.delta, record.deleted);
         Ok(())
     }
 
-    pub fn batch_find_by_records<'a>(
+    /// Index a record by its keys and its expiry time, see `insert_record_to_memtables`.
+    fn insert_keys_to_memtables(
         &mut self,
-        field: &R::Field,
-        values: impl Iterator<Item = &'a Value>,
-    ) -> DBResult<Vec<(usize, Record)>> {
-        let field_type = self.get_field_type(field).ok_or(DBError::ValidationError(
-            "Field not found in schema".to_owned(),
-        ))?;
+        log_key: LogKey,
+        (pk, sks, cks, computed): RecordKeys,
+        expires_at: Option<i64>,
+        delta: bool,
+        deleted: bool,
+    ) {
+        self.tombstoned_keys.remove(&pk);
+
+        // A soft-deleted record is only indexed by its primary key, so that it can be restored
+        if deleted {
+            self.remove_keys_from_memtables(&pk);
+            self.deleted_memtable.set(pk, log_key);
+            return;
+        }
 
-        let indexables = values
-            .map(|value| {
-                if type_check(&value, &field_type) {
-                    value.as_indexable().ok_or(DBError::ValidationError(
-                        "Queried value must be indexable".to_owned(),
-                    ))
+        // A delta is folded into the record it follows. A delta without a preceding record
+        // is indexed like a full record and merged into nothing when read.
+        if delta {
+            if let Some(head) = self.primary_memtable.get(&pk) {
+                if *head < log_key {
+                    // Refreshing reads the writes of this process again, so the delta may be known
+                    let deltas = self.merge_deltas.entry(pk).or_default();
+                    if let Err(position) = deltas.binary_search(&log_key) {
+                        deltas.insert(position, log_key);
+                    }
+                    return;
+                }
+            }
+        }
+
+        // The record replaces the deltas that were written before it. Compacted segments are
+        // re-indexed after later segments, so deltas from later segments must be kept.
+        if let Some(deltas) = self.merge_deltas.get_mut(&pk) {
+            deltas.retain(|delta| *delta > log_key);
+            if deltas.is_empty() {
+                self.merge_deltas.remove(&pk);
+            }
+        }
+
+        // The entries of the superseded version would otherwise be left behind if its keys differ
+        if let Some(superseded) = self.primary_memtable.get(&pk).cloned() {
+            if superseded != log_key {
+                self.remove_secondary_entries(&superseded);
+            }
+        }
+        for (secondary_memtable, sk) in self.secondary_memtables.iter_mut().zip(sks) {
+            secondary_memtable.set(sk, log_key.clone());
+        }
+        for (composite_memtable, ck) in self.composite_memtables.iter_mut().zip(cks) {
+            composite_memtable.set(ck, log_key.clone());
+        }
+        for (computed_memtable, key) in self.computed_memtables.iter_mut().zip(computed) {
+            computed_memtable.set(key, log_key.clone());
+        }
+
+        self.expiry_watches.retain(|watch| !watch.is_unwatched());
+        for watch in &self.expiry_watches {
+            watch
+                .wheel()
+                .lock()
+                .unwrap()
+                .schedule(pk.clone(), expires_at);
+        }
+
+        // Doing this last because this moves log_key
+        self.deleted_memtable.remove(&pk);
+        self.primary_memtable.set(pk, log_key);
+    }
+
+    fn remove_record_from_memtables(&mut self, record: &Record) -> DBResult<()> {
+        let pk = key_at(record, self.primary_key_index)?;
+        self.remove_keys_from_memtables(&pk);
+        self.tombstoned_keys.insert(pk);
+        Ok(())
+    }
+
+    fn remove_keys_from_memtables(&mut self, pk: &IndexableValue) {
+        for watch in &self.expiry_watches {
+            watch.wheel().lock().unwrap().unschedule(pk);
+        }
+        self.merge_deltas.remove(pk);
+        self.deleted_memtable.remove(pk);
+        if let Some(plk) = self.primary_memtable.remove(pk) {
+            self.remove_secondary_entries(&plk);
+            // Compacting frees the space of the removed record, so it is worth trying again
+            self.quota_compacted_size = None;
+        }
+    }
+
+    /// Remove the entries of the record at `log_key` from the secondary, composite and computed
+    /// memtables.
+    fn remove_secondary_entries(&mut self, log_key: &LogKey) {
+        for secondary_memtable in self.secondary_memtables.iter_mut() {
+            secondary_memtable.remove_log_key(log_key);
+        }
+        for composite_memtable in self.composite_memtables.iter_mut() {
+            composite_memtable.remove_log_key(log_key);
+        }
+        for computed_memtable in self.computed_memtables.iter_mut() {
+            computed_memtable.remove_log_key(log_key);
+        }
+    }
+
+    pub fn batch_upsert_records(
+        &mut self,
+        records: impl Iterator<Item = Record>,
+    ) -> DBResult<Vec<WriteReceipt>> {
+        let batch = self.prepare_batch(records)?;
+        self.finish_append(batch.segment_num, &batch.data, &batch.metadata)?;
+        Ok(self.index_batch(batch))
+    }
+
+    /// Assign versions to a batch of records and serialize them for appending to the active
+    /// segment, without writing anything. Must be called while holding the exclusive lock.
+    fn prepare_batch(&mut self, records: impl Iterator<Item = Record>) -> DBResult<PreparedBatch> {
+        let data_dir_size = self.compact_for_quota()?;
+        let position = self.begin_append()?;
+        let timestamp = current_timestamp();
+
+        let mut serialized_data: Vec<u8> = vec![];
+        let mut serialized_metadata: Vec<u8> = vec![];
+        let mut pending_memtable_insertions: Vec<(LogKey, RecordKeys, Record)> = vec![];
+        let mut receipts = vec![];
+        for mut record in records {
+            self.apply_write_transforms(&mut record)?;
+            self.run_checks(&record)?;
+            // Computing the keys may fail, which must happen before anything is written
+            let keys = self.record_keys(&record)?;
+            record.version = self.next_version;
+            record.timestamp = timestamp;
+            self.next_version += 1;
+
+            // Write the record to the log. The batch is written at once after the loop,
+            // so the offsets and indexes are relative to the current end of the files.
+            let serialized = &self.log_encoding.serialize(&record);
+            let stored = position
+                .header
+                .encode_record(serialized, self.config.encryption.as_deref())?;
+            let record_offset = position.data_end + serialized_data.len() as u64;
+            let record_length = stored.len() as u64;
+            assert!(record_length > 0);
+
+            serialized_data.extend_from_slice(&stored);
+
+            let metadata_index =
+                position.first_metadata_index + pending_memtable_insertions.len() as u64;
+
+            // Write the record metadata to the metadata file
+            serialized_metadata.extend(MetadataRow::new(record_offset, &stored).serialize());
+
+            let log_key = LogKey::new(position.segment_num, metadata_index);
+
+            receipts.push(WriteReceipt {
+                position: LogPosition::from(log_key.clone()),
+                meta: record.meta(),
+            });
+            pending_memtable_insertions.push((log_key, keys, record));
+        }
+
+        let appended = serialized_data.len() + serialized_metadata.len();
+        self.check_quota(data_dir_size, appended as u64)?;
+
+        Ok(PreparedBatch {
+            segment_num: position.segment_num,
+            data: serialized_data,
+            metadata: serialized_metadata,
+            insertions: pending_memtable_insertions,
+            receipts,
+        })
+    }
+
+    /// Index the records of a batch once it has been appended.
+    fn index_batch(&mut self, batch: PreparedBatch) -> Vec<WriteReceipt> {
+        for (log_key, keys, record) in batch.insertions {
+            let expires_at = self.expires_at(&record);
+            self.insert_keys_to_memtables(log_key, keys, expires_at, record.delta, record.deleted);
+        }
+        batch.receipts
+    }
+
+    /// Write a batch of records of a transaction to the data file, and its rows to the journal
+    /// along with `commit_marker`, the path of the commit marker of the transaction relative to the
+    /// data directory. The rows are appended with `commit_staged` once the commit marker has been
+    /// written. Must be called while holding the exclusive lock.
+    pub fn stage_records(
+        &mut self,
+        records: Vec<Record>,
+        commit_marker: &Path,
+    ) -> DBResult<PreparedBatch> {
+        let batch = self.prepare_batch(records.into_iter())?;
+        self.write_journaled_data(
+            batch.segment_num,
+            &batch.data,
+            &batch.metadata,
+            Some(commit_marker),
+        )?;
+        Ok(batch)
+    }
+
+    /// Append and index the rows of a batch written with `stage_records`.
+    pub fn commit_staged(&mut self, batch: PreparedBatch) -> DBResult<Vec<WriteReceipt>> {
+        self.append_journaled_rows(batch.segment_num, &batch.metadata)?;
+        Ok(self.index_batch(batch))
+    }
+
+    /// Append a record in its serialized form without decoding it, see `DB::append_raw`. The
+    /// flag of the record must be `B_LIVE`, or `B_MERGE` if a merge operator is configured. Its
+    /// version and timestamp are overwritten. Records are decoded only to apply write transforms and checks.
+    pub fn append_raw_record(&mut self, mut bytes: Vec<u8>) -> DBResult<WriteReceipt> {
+        let values = validate_serialized(&bytes, &self.config.fields)?;
+        let delta = match bytes[0] {
+            B_LIVE => false,
+            B_MERGE if self.config.merge_operator.is_some() => true,
+            B_MERGE => {
+                return Err(DBError::ValidationError(
+                    "Merging requires a merge operator".to_owned(),
+                ))
+            }
+            flag => {
+                return Err(DBError::ValidationError(format!(
+                    "Serialized record has an invalid flag: {}",
+                    flag
+                )))
+            }
+        };
+        // Computed keys are computed from decoded values
+        if !self.config.write_transforms.is_empty()
+            || !self.config.checks.is_empty()
+            || !self.config.computed_indexes.is_empty()
+        {
+            let mut record = Record::deserialize(&bytes);
+            self.log_encoding.decode(&mut record);
+            return Ok(self
+                .batch_upsert_records(std::iter::once(record))?
+                .remove(0));
+        }
+
+        let keys = self.keys_with(
+            |field_index| {
+                let value = self
+                    .log_encoding
+                    .decode_ref(field_index, values[field_index]);
+                value.as_indexable().ok_or_else(|| {
+                    DBError::ValidationError(format!(
+                        "Record field {} is not indexable",
+                        field_index
+                    ))
+                })
+            },
+            vec![],
+        )?;
+
+        let expires_at = match self
+            .expiry_index
+            .map(|index| self.log_encoding.decode_ref(index, values[index]))
+        {
+            Some(ValueRef::Int(expires_at)) => Some(expires_at),
+            _ => None,
+        };
+
+        let data_dir_size = self.compact_for_quota()?;
+        let position = self.begin_append()?;
+        let meta = RecordMeta {
+            version: self.next_version,
+            timestamp: current_timestamp(),
+            deleted: false,
+        };
+        self.next_version += 1;
+        bytes[1..1 + 8].copy_from_slice(&meta.version.to_be_bytes());
+        bytes[1 + 8..RECORD_HEADER_SIZE]
+            .copy_from_slice(&timestamp_to_micros(meta.timestamp).to_be_bytes());
+
+        let stored = position
+            .header
+            .encode_record(&bytes, self.config.encryption.as_deref())?;
+        let metadata = MetadataRow::new(position.data_end, &stored).serialize();
+        self.check_quota(data_dir_size, (stored.len() + metadata.len()) as u64)?;
+        self.finish_append(position.segment_num, &stored, &metadata)?;
+
+        let log_key = LogKey::new(position.segment_num, position.first_metadata_index);
+        let receipt = WriteReceipt {
+            position: LogPosition::from(log_key.clone()),
+            meta,
+        };
+        self.insert_keys_to_memtables(log_key, keys, expires_at, delta, false);
+        Ok(receipt)
+    }
+
+    /// Compact all segments if the size of the data directory is approaching the quota set with
+    /// `ConfigBuilder::max_disk_bytes`. To avoid compacting on every write when most of the data is
+    /// live, the segments are compacted again only after a segment's worth of growth or after a
+    /// record has been deleted. Returns the size of the data directory, or `None` if there is no
+    /// quota.
+    fn compact_for_quota(&mut self) -> DBResult<Option<u64>> {
+        let Some(max_disk_bytes) = self.config.max_disk_bytes else {
+            return Ok(None);
+        };
+
+        let size = data_dir_size(&self.data_dir_path)?;
+        let grown = self
+            .quota_compacted_size
+            .is_none_or(|compacted| size > compacted + self.config.segment_size as u64);
+        if size < max_disk_bytes / 10 * 9 || !grown {
+            return Ok(Some(size));
+        }
+
+        info!(
+            "Data directory size {} is approaching the quota of {} bytes, compacting",
+            size, max_disk_bytes
+        );
+        self.compact_segments(SegmentSelector::All)?;
+
+        let size = data_dir_size(&self.data_dir_path)?;
+        self.quota_compacted_size = Some(size);
+        Ok(Some(size))
+    }
+
+    /// Return `DBError::QuotaExceeded` if writing `appended` bytes would make the data directory
+    /// larger than the quota. `data_dir_size` is the size returned by `compact_for_quota`.
+    fn check_quota(&self, data_dir_size: Option<u64>, appended: u64) -> DBResult<()> {
+        match (data_dir_size, self.config.max_disk_bytes) {
+            (Some(size), Some(max_disk_bytes)) if size + appended > max_disk_bytes => {
+                Err(DBError::QuotaExceeded(format!(
+                    "writing {} bytes to a data directory of {} bytes would exceed the quota of {} bytes",
+                    appended, size, max_disk_bytes
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Prepare the active segment for appending records, after seeing the writes of other
+    /// processes so that the appended records are assigned greater versions. Returns where in the
+    /// active segment the records will be written.
+    fn begin_append(&mut self) -> DBResult<AppendPosition> {
+        // A writer may have crashed in the middle of appending a batch. Its rows are recovered
+        // before the indexes are refreshed, so that they are indexed and their versions are seen.
+        JournalEntry::recover(&self.data_dir_path)?;
+        // Writes by other processes must be seen to assign the records a greater version
+        self.refresh_indexes()?;
+
+        debug!("Opening file in append mode...");
+
+        if !self.ensure_metadata_file_is_active()?
+            || !ensure_active_metadata_is_valid(
+                &self.data_dir_path,
+                &mut self.active_metadata_file,
+            )?
+        {
+            // The log file has been rotated, so we must try again
+            return self.begin_append();
+        }
+
+        let active_symlink_path = self.data_dir_path.join(ACTIVE_SYMLINK_FILENAME);
+        let active_target = fs::read_link(active_symlink_path)?;
+        let segment_num = parse_segment_number(&active_target)?;
+
+        let header = read_metadata_header(&mut self.active_metadata_file)?;
+        let data_end = self.active_data_file.seek(SeekFrom::End(0))?;
+        let metadata_end = self.active_metadata_file.seek(SeekFrom::End(0))?;
+        let first_metadata_index =
+            (metadata_end - METADATA_FILE_HEADER_SIZE as u64) / METADATA_ROW_LENGTH as u64;
+
+        Ok(AppendPosition {
+            segment_num,
+            data_end,
+            first_metadata_index,
+            header,
+        })
+    }
+
+    /// Write serialized records and their metadata rows to the end of the active segment `segment_num`.
+    fn finish_append(&mut self, segment_num: u16, data: &[u8], metadata: &[u8]) -> DBResult<()> {
+        debug!("Appending to log file");
+
+        // Batches are journaled so that a crash cannot leave only some of their rows appended
+        if metadata.len() > METADATA_ROW_LENGTH {
+            self.write_journaled_data(segment_num, data, metadata, None)?;
+            return self.append_journaled_rows(segment_num, metadata);
+        }
+
+        // A record and its row are appended with a single submission if the backend allows
+        self.io.append_batch(&[
+            (&self.active_data_file, data),
+            (&self.active_metadata_file, metadata),
+        ])?;
+        self.persist_active(segment_num)?;
+
+        debug!("Records appended to log file");
+        Ok(())
+    }
+
+    /// Append the records of a batch to the data file and write their rows to the journal.
+    fn write_journaled_data(
+        &mut self,
+        segment_num: u16,
+        data: &[u8],
+        metadata: &[u8],
+        commit_marker: Option<&Path>,
+    ) -> DBResult<()> {
+        self.io.append_batch(&[(&self.active_data_file, data)])?;
+        self.config
+            .write_durability
+            .persist(&mut self.active_data_file)?;
+        JournalEntry {
+            segment_num,
+            metadata_start: self.active_metadata_file.seek(SeekFrom::End(0))?,
+            rows: metadata.to_vec(),
+            commit_marker: commit_marker.map(Path::to_path_buf),
+        }
+        .write(&self.data_dir_path, &self.config.write_durability)
+    }
+
+    /// Append the journaled rows of a batch to the metadata file and remove the journal.
+    fn append_journaled_rows(&mut self, segment_num: u16, metadata: &[u8]) -> DBResult<()> {
+        self.io
+            .append_batch(&[(&self.active_metadata_file, metadata)])?;
+        self.persist_active(segment_num)?;
+        JournalEntry::clear(&self.data_dir_path)?;
+
+        debug!("Records appended to log file");
+        Ok(())
+    }
+
+    /// Flush the records appended to the active segment `segment_num`. With `FlushSync` or
+    /// `FlushDataSync`, the records are synced by `sync_pending` once the exclusive
+    /// lock has been released, so that other writers can append while this one syncs.
+    fn persist_active(&mut self, segment_num: u16) -> DBResult<()> {
+        WriteDurability::Flush.persist(&mut self.active_data_file)?;
+        WriteDurability::Flush.persist(&mut self.active_metadata_file)?;
+        if self.config.write_durability == WriteDurability::Flush {
+            return Ok(());
+        }
+
+        // A sync covers a single segment, so records appended to an earlier one are synced first
+        if self
+            .pending_sync
+            .as_ref()
+            .is_some_and(|pending| pending.segment_num != segment_num)
+        {
+            self.sync_pending()?;
+        }
+        self.pending_sync = Some(PendingSync {
+            segment_num,
+            metadata_end: self.active_metadata_file.metadata()?.len(),
+            data_file: self.active_data_file.try_clone()?,
+            metadata_file: self.active_metadata_file.try_clone()?,
+        });
+        Ok(())
+    }
+
+    /// Wait until the records appended with `FlushSync` or `FlushDataSync` are synced to disk.
+    fn sync_pending(&mut self) -> DBResult<()> {
+        let Some(pending) = self.pending_sync.take() else {
+            return Ok(());
+        };
+        let write_durability = &self.config.write_durability;
+        match &mut self.group_commit {
+            Some(group_commit) => group_commit.sync(&pending, write_durability),
+            None => {
+                write_durability.sync(&pending.data_file)?;
+                write_durability.sync(&pending.metadata_file)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Upsert a record only if the current version of the record with the same primary key
+    /// matches `expected_version`. An expected version of `None` means that the record must not exist.
+    pub fn upsert_record_if_version(
+        &mut self,
+        record: Record,
+        expected_version: Option<u64>,
+    ) -> DBResult<WriteReceipt> {
+        self.refresh_indexes()?;
+
+        let pk =
+            record
+                .at(self.primary_key_index)
+                .as_indexable()
+                .ok_or(DBError::ValidationError(
+                    "Primary key must be indexable".to_owned(),
+                ))?;
+
+        let current_version = self
+            .read_current_record(&pk)?
+            .map(|current| current.version);
+
+        if current_version != expected_version {
+            return Err(DBError::VersionConflict(format!(
+                "expected version {:?}, found {:?}",
+                expected_version, current_version
+            )));
+        }
+
+        let mut receipts = self.batch_upsert_records(std::iter::once(record))?;
+        Ok(receipts.remove(0))
+    }
+
+    /// Append a record unless there is a current record with the same primary key, in which case
+    /// `DBError::DuplicateKey` is returned. The check and the write must happen under the same
+    /// exclusive lock.
+    pub fn insert_record(&mut self, record: Record) -> DBResult<WriteReceipt> {
+        self.refresh_indexes()?;
+
+        let pk =
+            record
+                .at(self.primary_key_index)
+                .as_indexable()
+                .ok_or(DBError::ValidationError(
+                    "Primary key must be indexable".to_owned(),
+                ))?;
+
+        if self.read_current_record(&pk)?.is_some() {
+            return Err(DBError::DuplicateKey(format!(
+                "a record with the primary key {:?} already exists",
+                Value::from(pk)
+            )));
+        }
+
+        let mut receipts = self.batch_upsert_records(std::iter::once(record))?;
+        Ok(receipts.remove(0))
+    }
+
+    /// Read the current version of the record with the primary key `pk`, if there is one.
+    /// Expects the memtables to be up to date.
+    fn read_current_record(&mut self, pk: &IndexableValue) -> DBResult<Option<Record>> {
+        match self.primary_memtable.get(pk) {
+            Some(log_key) => Ok(self
+                .read_tagged_log_keys(std::iter::once((0, log_key)))?
+                .into_iter()
+                .next()
+                .map(|(_, current)| current)),
+            None => Ok(None),
+        }
+    }
+
+    /// Add `delta` to the integer `field` of the record with the primary key `pk` and write the
+    /// record back. Returns the new value, or `None` if there is no such record. A null counter
+    /// counts as zero. The read and the write must happen under the same exclusive lock.
+    pub fn increment_record(
+        &mut self,
+        pk: &Value,
+        field: &R::Field,
+        delta: i64,
+    ) -> DBResult<Option<i64>> {
+        self.refresh_indexes()?;
+
+        if *field == self.config.primary_key {
+            return Err(DBError::ValidationError(
+                "Primary key cannot be incremented".to_owned(),
+            ));
+        }
+        let field_index = self
+            .config
+            .fields
+            .iter()
+            .position(|(f, _)| f == field)
+            .ok_or(DBError::ValidationError(
+                "Field not found in schema".to_owned(),
+            ))?;
+        if !matches!(
+            self.config.fields[field_index].1.primitive,
+            PrimitiveType::Int
+        ) {
+            return Err(DBError::ValidationError(format!(
+                "Field {:?} is not an integer",
+                field
+            )));
+        }
+
+        let pk = value_to_indexable(pk, &self.config.fields[self.primary_key_index].1)?;
+        let mut record = match self.primary_memtable.get(&pk) {
+            Some(log_key) => match self
+                .read_tagged_log_keys(std::iter::once((0, log_key)))?
+                .into_iter()
+                .next()
+            {
+                Some((_, record)) => record,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+
+        let current = match record.values.get(field_index) {
+            Some(Value::Int(current)) => *current,
+            Some(Value::Null) => 0,
+            _ => return Err(record_schema_mismatch(&record)),
+        };
+        let new = current.checked_add(delta).ok_or_else(|| {
+            DBError::ValidationError(format!("Incrementing {} by {} overflows", current, delta))
+        })?;
+
+        record.values[field_index] = Value::Int(new);
+        self.batch_upsert_records(std::iter::once(Record::from(&record.values)))?;
+        Ok(Some(new))
+    }
+
+    pub fn batch_find_by_records<'a>(
+        &mut self,
+        field: &R::Field,
+        values: impl Iterator<Item = &'a Value>,
+    ) -> DBResult<Vec<(usize, Record)>> {
+        let mut arena = RecordArena::new();
+        self.batch_find_by_into(field, values, &mut arena)?;
+
+        Ok(arena
+            .records()
+            .map(|record| (record.tag(), record.to_record()))
+            .collect())
+    }
+
+    /// Like `batch_find_by_records`, but the records are read into `arena`, replacing its contents.
+    pub fn batch_find_by_into<'a>(
+        &mut self,
+        field: &R::Field,
+        values: impl Iterator<Item = &'a Value>,
+        arena: &mut RecordArena,
+    ) -> DBResult<()> {
+        arena.clear();
+        arena.set_log_encoding(&self.log_encoding);
+        let field_type = self.get_field_type(field).ok_or(DBError::ValidationError(
+            "Field not found in schema".to_owned(),
+        ))?;
+
+        let indexables = values
+            .map(|value| {
+                if type_check(&value, &field_type) {
+                    value.as_indexable().ok_or(DBError::ValidationError(
+                        "Queried value must be indexable".to_owned(),
+                    ))
                 } else {
                     Err(DBError::ValidationError(format!(
                         "Queried value {:?} does not match key type: {:?}",
@@ -317,6 +1718,16 @@ impl<R: Recordable> Engine<R> {
             })
             .collect::<DBResult<Vec<IndexableValue>>>()?;
 
+        if field != &self.config.primary_key
+            && !self.config.secondary_keys.contains(field)
+            && self.config.non_indexed_queries == NonIndexedQueries::Scan
+        {
+            for (tag, record) in self.find_by_scan(field, indexables)? {
+                arena.push(tag, &record);
+            }
+            return Ok(());
+        }
+
         // Otherwise, continue with querying secondary indexes.
         debug!(
             "Finding all records with fields {:?} = {:?}",
@@ -325,38 +1736,13 @@ impl<R: Recordable> Engine<R> {
 
         if self.config.read_consistency == ReadConsistency::Strong {
             self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
         }
 
         let log_key_batches = indexables
-            .into_iter()
-            .map(|query_key| {
-                if field == &self.config.primary_key {
-                    let opt = self.primary_memtable.get(&query_key);
-                    let log_keys = match opt {
-                        Some(log_key) => vec![log_key],
-                        None => vec![],
-                    };
-                    Ok(log_keys)
-                } else {
-                    let smemtable_index = match get_secondary_memtable_index_by_field(
-                        &self.config.secondary_keys,
-                        field,
-                    ) {
-                        Some(index) => index,
-                        None => {
-                            return Err(DBError::ValidationError(
-                                "Cannot find_by by non-indexed key".to_owned(),
-                            ))
-                        }
-                    };
-
-                    let log_keys = self.secondary_memtables[smemtable_index]
-                        .find_by(&query_key)
-                        .into_iter()
-                        .collect();
-                    Ok(log_keys)
-                }
-            })
+            .iter()
+            .map(|query_key| self.find_log_keys(field, query_key))
             .collect::<DBResult<Vec<Vec<&LogKey>>>>()?;
 
         debug!("Found log keys in memtable: {:?}", log_key_batches);
@@ -369,253 +1755,2528 @@ impl<R: Recordable> Engine<R> {
             tag += 1;
         }
 
-        let tagged_records = self.read_tagged_log_keys(tagged.into_iter())?;
+        self.read_tagged_log_keys_into(tagged.into_iter(), arena)?;
+
+        // Soft-deleted records are only indexed by their primary key
+        if field != &self.config.primary_key {
+            let field_index = self.field_index(field)?;
+            for record in self.visible_deleted_records()? {
+                let key = key_at(&record, field_index)?;
+                for (tag, query_key) in indexables.iter().enumerate() {
+                    if *query_key == key {
+                        arena.push(tag, &record);
+                    }
+                }
+            }
+        }
 
-        debug!("Read {} records", tagged_records.len());
+        debug!("Read {} records", arena.len());
 
-        Ok(tagged_records)
+        Ok(())
     }
 
-    /// Read records from segment files based on log keys.
-    /// The log keys are accompanied by an integer tag that can be used to identify and group them later.
-    fn read_tagged_log_keys<'a>(
-        &self,
-        log_keys: impl Iterator<Item = (usize, &'a LogKey)>,
-    ) -> DBResult<Vec<(usize, Record)>> {
-        let mut records = vec![];
-        let mut log_keys_map = BTreeMap::new();
+    pub fn find_by_any_records<'a>(
+        &mut self,
+        field: &R::Field,
+        values: impl Iterator<Item = &'a Value>,
+    ) -> DBResult<Vec<Record>> {
+        let primary_key_index = self.primary_key_index;
+        let mut seen = HashSet::new();
 
-        for (tag, log_key) in log_keys {
-            if !log_keys_map.contains_key(&log_key.segment_num()) {
-                log_keys_map.insert(log_key.segment_num(), vec![(tag, log_key.index())]);
-            } else {
-                log_keys_map
-                    .get_mut(&log_key.segment_num())
-                    .unwrap()
-                    .push((tag, log_key.index()));
-            }
+        Ok(self
+            .batch_find_by_records(field, values)?
+            .into_iter()
+            .map(|(_, record)| record)
+            .filter(|record| seen.insert(record.at(primary_key_index).as_indexable()))
+            .collect())
+    }
+
+    /// Get the distinct values of an indexed field from the memtables, without reading any records.
+    pub fn distinct_values(&mut self, field: &R::Field) -> DBResult<Vec<Value>> {
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
         }
 
-        for (segment_num, mut segment_indexes) in log_keys_map {
-            segment_indexes.sort_unstable();
+        if field == &self.config.primary_key {
+            return Ok(self
+                .primary_memtable
+                .iter()
+                .map(|(key, _)| Value::from(key.clone()))
+                .collect());
+        }
 
-            let metadata_path = &self.data_dir_path.join(metadata_filename(segment_num));
-            let mut metadata_file = READ_MODE.open(&metadata_path)?;
+        let smemtable_index =
+            get_secondary_memtable_index_by_field(&self.config.secondary_keys, field).ok_or(
+                DBError::ValidationError(
+                    "Cannot get distinct values of non-indexed key".to_owned(),
+                ),
+            )?;
 
-            let metadata_header = read_metadata_header(&mut metadata_file)?;
+        // Secondary memtables may still refer to superseded versions of a record, so a value
+        // is only present if it refers to a current version.
+        let primary_memtable = &self.primary_memtable;
+        Ok(self.secondary_memtables[smemtable_index]
+            .iter()
+            .filter(|(_, log_keys)| {
+                log_keys
+                    .iter()
+                    .any(|log_key| primary_memtable.contains_log_key(log_key))
+            })
+            .map(|(key, _)| Value::from(key.clone()))
+            .collect())
+    }
+
+    pub fn aggregate_field(&mut self, field: &R::Field, aggregate: Aggregate) -> DBResult<Value> {
+        let field_type = self.get_field_type(field).ok_or(DBError::ValidationError(
+            "Field not found in schema".to_owned(),
+        ))?;
+        let mut accumulator = Accumulator::new(aggregate, field_type)?;
+
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let is_extreme = aggregate == Aggregate::Min || aggregate == Aggregate::Max;
+
+        if field == &self.config.primary_key {
+            // Primary keys are never null, and each key has exactly one current record
+            let keys: Box<dyn Iterator<Item = _>> = match aggregate {
+                Aggregate::Max => Box::new(self.primary_memtable.iter().rev().take(1)),
+                Aggregate::Min => Box::new(self.primary_memtable.iter().take(1)),
+                _ => Box::new(self.primary_memtable.iter()),
+            };
+            for (key, _) in keys {
+                accumulator.add(&Value::from(key.clone()), 1)?;
+            }
+        } else if let Some(smemtable_index) =
+            get_secondary_memtable_index_by_field(&self.config.secondary_keys, field).filter(
+                |&index| {
+                    // The keys of a collated index are not the values of the field, so they are scanned
+                    matches!(self.secondary_collations[index], Collation::Binary)
+                },
+            )
+        {
+            // Secondary memtables may still refer to superseded versions of a record, see `distinct_values`
+            let primary_memtable = &self.primary_memtable;
+            let entries = self.secondary_memtables[smemtable_index].iter();
+            let entries: Box<dyn Iterator<Item = _>> = match aggregate {
+                Aggregate::Max => Box::new(entries.rev()),
+                _ => Box::new(entries),
+            };
+            for (key, log_keys) in entries {
+                let count = log_keys
+                    .iter()
+                    .filter(|log_key| primary_memtable.contains_log_key(log_key))
+                    .count();
+                if count == 0 || *key == IndexableValue::Null {
+                    continue;
+                }
+
+                accumulator.add(&Value::from(key.clone()), count as u64)?;
+
+                // The index is ordered, so the first value found is the extreme one
+                if is_extreme {
+                    break;
+                }
+            }
+        } else {
+            let field_index = self
+                .config
+                .fields
+                .iter()
+                .position(|(f, _)| f == field)
+                .ok_or(DBError::ValidationError(
+                    "Field not found in schema".to_owned(),
+                ))?;
+
+            let mut result = Ok(());
+            self.scan_filter_records(|record| {
+                if result.is_ok() {
+                    result = match record.values.get(field_index) {
+                        Some(value) => accumulator.add(value, 1),
+                        None => Err(record_schema_mismatch(record)),
+                    };
+                }
+                false
+            })?;
+            result?;
+        }
+
+        accumulator.finish()
+    }
+
+    /// Read the records of the next `batch_size` keys of the index of `field` in `bounds`, in key order.
+    /// Returns the records and the last key read, after which the next batch starts, or `None` if
+    /// the range has been exhausted.
+    pub fn range_by_batch(
+        &mut self,
+        field: &R::Field,
+        bounds: OwnedBounds<IndexableValue>,
+        batch_size: usize,
+    ) -> DBResult<(Vec<Record>, Option<IndexableValue>)> {
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let mut keys = vec![];
+        let mut log_keys: Vec<&LogKey> = vec![];
+        if field == &self.config.primary_key {
+            for (key, log_key) in self.primary_memtable.range_entries(bounds).take(batch_size) {
+                keys.push(key);
+                log_keys.push(log_key);
+            }
+        } else {
+            let index = get_secondary_memtable_index_by_field(&self.config.secondary_keys, field)
+                .ok_or_else(|| {
+                DBError::ValidationError("Cannot range_by by non-indexed key".to_owned())
+            })?;
+
+            let entries = self.secondary_memtables[index].range_entries(bounds);
+            for (key, key_log_keys) in entries.take(batch_size) {
+                let mut key_log_keys: Vec<&LogKey> = key_log_keys.iter().collect();
+                key_log_keys.sort_unstable();
+                keys.push(key);
+                log_keys.extend(key_log_keys);
+            }
+        }
+        // A partial batch is the end of the range
+        let next_start = match keys.last() {
+            Some(&key) if keys.len() == batch_size => Some(key.clone()),
+            _ => None,
+        };
+
+        let mut tagged_records = self.read_tagged_log_keys(log_keys.iter().cloned().enumerate())?;
+        tagged_records.sort_unstable_by_key(|(tag, _)| *tag);
+
+        // Secondary memtables may still refer to superseded versions of a record
+        let mut records = vec![];
+        for (tag, record) in tagged_records {
+            let pk = key_at(&record, self.primary_key_index)?;
+            if self.primary_memtable.get(&pk) == Some(log_keys[tag]) {
+                records.push(record);
+            }
+        }
+        Ok((records, next_start))
+    }
+
+    /// Read the `k` records with the smallest or greatest non-null values of the indexed `field`.
+    /// The index is walked from the requested end, and records are read in batches only until
+    /// `k` current records have been found.
+    pub fn top_k_records(
+        &mut self,
+        field: &R::Field,
+        k: usize,
+        direction: Direction,
+    ) -> DBResult<Vec<Record>> {
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let entries: Box<dyn Iterator<Item = (&IndexableValue, Vec<&LogKey>)>> = if field
+            == &self.config.primary_key
+        {
+            let entries = self
+                .primary_memtable
+                .iter()
+                .map(|(key, log_key)| (key, vec![log_key]));
+            match direction {
+                Direction::Asc => Box::new(entries),
+                Direction::Desc => Box::new(entries.rev()),
+            }
+        } else {
+            let index = get_secondary_memtable_index_by_field(&self.config.secondary_keys, field)
+                .ok_or_else(|| {
+                DBError::ValidationError("Cannot top_k by non-indexed key".to_owned())
+            })?;
+            let entries = self.secondary_memtables[index]
+                .iter()
+                .map(|(key, log_keys)| {
+                    let mut log_keys: Vec<&LogKey> = log_keys.iter().collect();
+                    log_keys.sort_unstable();
+                    (key, log_keys)
+                });
+            match direction {
+                Direction::Asc => Box::new(entries),
+                Direction::Desc => Box::new(entries.rev()),
+            }
+        };
+        let mut log_keys = entries
+            .filter(|(key, _)| **key != IndexableValue::Null)
+            .flat_map(|(_, log_keys)| log_keys);
+
+        let mut records = vec![];
+        while records.len() < k {
+            // Secondary indexes may still refer to superseded versions, so a batch may come up short
+            let batch: Vec<&LogKey> = log_keys.by_ref().take(k - records.len()).collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut tagged_records =
+                self.read_tagged_log_keys(batch.iter().cloned().enumerate())?;
+            tagged_records.sort_unstable_by_key(|(tag, _)| *tag);
+            for (tag, record) in tagged_records {
+                let pk = key_at(&record, self.primary_key_index)?;
+                if self.primary_memtable.get(&pk) == Some(batch[tag]) {
+                    records.push(record);
+                }
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Find the current records whose composite key `fields` starts with the values in `prefix` and
+    /// whose next field, if any, is in `range`.
+    pub fn range_by_composite_records<B: RangeBounds<Value>>(
+        &mut self,
+        fields: &[R::Field],
+        prefix: &[Value],
+        range: B,
+    ) -> DBResult<Vec<Record>> {
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let log_keys = self.composite_log_keys(fields, prefix, range)?;
+        let tagged_records = self.read_tagged_log_keys(log_keys.iter().cloned().enumerate())?;
+
+        // Composite memtables may still refer to superseded versions of a record
+        let mut records = vec![];
+        for (tag, record) in tagged_records {
+            let pk = key_at(&record, self.primary_key_index)?;
+            if self.primary_memtable.get(&pk) == Some(log_keys[tag]) {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Compute the stored size of each current record from the metadata rows, without reading
+    /// the data files, and summarize them into a report with the `top_n` largest records.
+    pub fn size_report(&mut self, top_n: usize) -> DBResult<SizeReport> {
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        // The log keys of the current version and the merge deltas of each record
+        let keys: Vec<&IndexableValue> = self.primary_memtable.iter().map(|(pk, _)| pk).collect();
+        let log_keys = self
+            .primary_memtable
+            .iter()
+            .enumerate()
+            .flat_map(|(tag, (pk, log_key))| {
+                let deltas = self.merge_deltas.get(pk).into_iter().flatten();
+                std::iter::once(log_key)
+                    .chain(deltas)
+                    .map(move |log_key| (tag, log_key))
+            });
+        let sizes = self.tagged_record_lengths(log_keys, keys.len())?;
+
+        let sizes = keys
+            .into_iter()
+            .map(|pk| Value::from(pk.clone()))
+            .zip(sizes)
+            .collect();
+        Ok(SizeReport::new(sizes, top_n))
+    }
+
+    /// Sum the lengths of the records at `log_keys` by their tags, which are less than `tags`,
+    /// from the metadata rows.
+    fn tagged_record_lengths<'a>(
+        &self,
+        log_keys: impl Iterator<Item = (usize, &'a LogKey)>,
+        tags: usize,
+    ) -> DBResult<Vec<u64>> {
+        let mut log_keys_map: BTreeMap<u16, Vec<(u64, usize)>> = BTreeMap::new();
+        for (tag, log_key) in log_keys {
+            log_keys_map
+                .entry(log_key.segment_num())
+                .or_default()
+                .push((log_key.index(), tag));
+        }
+
+        let mut sizes = vec![0; tags];
+        for (segment_num, mut segment_indexes) in log_keys_map {
+            segment_indexes.sort_unstable();
+
+            let metadata_path = &self.data_dir_path.join(metadata_filename(segment_num));
+            let mut metadata_file = READ_MODE.open(metadata_path)?;
+            let format_version = read_metadata_header(&mut metadata_file)?.version;
+            let row_length = metadata_row_length(format_version);
+            for (segment_index, tag) in segment_indexes {
+                metadata_file.seek(SeekFrom::Start(
+                    (METADATA_FILE_HEADER_SIZE + segment_index as usize * row_length) as u64,
+                ))?;
+                let mut metadata_buf = [0; METADATA_ROW_LENGTH];
+                metadata_file.read_exact(&mut metadata_buf[..row_length])?;
+                sizes[tag] += MetadataRow::deserialize(format_version, &metadata_buf)?.length;
+            }
+        }
+        Ok(sizes)
+    }
+
+    /// Read records from segment files based on log keys.
+    /// The log keys are accompanied by an integer tag that can be used to identify and group them later.
+    fn read_tagged_log_keys<'a>(
+        &self,
+        log_keys: impl Iterator<Item = (usize, &'a LogKey)>,
+    ) -> DBResult<Vec<(usize, Record)>> {
+        let mut arena = RecordArena::new();
+        self.read_tagged_log_keys_into(log_keys, &mut arena)?;
+
+        Ok(arena
+            .records()
+            .map(|record| (record.tag(), record.to_record()))
+            .collect())
+    }
+
+    /// Like `read_tagged_log_keys`, but the records are appended to `arena` without being
+    /// deserialized.
+    fn read_tagged_log_keys_into<'a>(
+        &self,
+        log_keys: impl Iterator<Item = (usize, &'a LogKey)>,
+        arena: &mut RecordArena,
+    ) -> DBResult<()> {
+        arena.set_log_encoding(&self.log_encoding);
+        let now = unix_millis(SystemTime::now());
+        let mut log_keys_map = BTreeMap::new();
+
+        for (tag, log_key) in log_keys {
+            if !log_keys_map.contains_key(&log_key.segment_num()) {
+                log_keys_map.insert(log_key.segment_num(), vec![(tag, log_key.index())]);
+            } else {
+                log_keys_map
+                    .get_mut(&log_key.segment_num())
+                    .unwrap()
+                    .push((tag, log_key.index()));
+            }
+        }
+
+        // Records of segments that are not mapped are read here first
+        let mut scratch = vec![];
+        for (segment_num, mut segment_indexes) in log_keys_map {
+            self.check_cancelled()?;
+            segment_indexes.sort_unstable();
+
+            let max_index = segment_indexes.iter().map(|(_, index)| *index).max();
+            let max_index = max_index.unwrap_or(0);
+            let source = match self.mapped_segment(segment_num, max_index)? {
+                Some(mapped) => SegmentSource::Mapped(mapped),
+                None => {
+                    SegmentSource::open(&self.data_dir_path, segment_num, self.archive_store())?
+                }
+            };
+
+            let indexes: Vec<u64> = segment_indexes.iter().map(|(_, index)| *index).collect();
+            let rows = source.read_rows(&indexes, self.io.as_ref())?;
+            let (segment_indexes, rows): (Vec<_>, Vec<_>) = segment_indexes
+                .into_iter()
+                .zip(rows)
+                // A row zeroed by compaction means that a newer version of the record exists,
+                // which the memtables will point to after the next refresh.
+                .filter(|(_, row)| row.length != 0)
+                .unzip();
+            let records = source.read_records(&rows, self.io.as_ref(), &mut scratch)?;
+
+            for ((tag, segment_index), record) in segment_indexes.into_iter().zip(records) {
+                let start = arena.next_start();
+                let buf = arena.buf_mut();
+                buf.extend_from_slice(record);
+                source
+                    .header()
+                    .decode_record(buf, start, self.config.encryption.as_deref())?;
+
+                let log_key = LogKey::new(segment_num, segment_index);
+                self.fold_merge_deltas_in(&log_key, arena, start)?;
+                if self.is_expired_in(&arena.pending(start), now) {
+                    arena.rollback(start);
+                    continue;
+                }
+                arena.commit(tag, start);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The memory map of a segment that covers its row `max_index`, or `None` if reads are not
+    /// memory-mapped or the segment cannot be mapped, in which case its files are read instead.
+    /// Maps are reused until the segment is compacted or has grown past them.
+    fn mapped_segment(
+        &self,
+        segment_num: u16,
+        max_index: u64,
+    ) -> DBResult<Option<Arc<MappedSegment>>> {
+        // The files of an in-memory database are read from memory already
+        if !self.config.mmap_reads
+            || self.mmap_unavailable.get()
+            || fs::is_in_memory(&self.data_dir_path)
+        {
+            return Ok(None);
+        }
+        let Some(indexed_uuid) = self.indexed_segment_uuids.get(&segment_num) else {
+            return Ok(None);
+        };
+        let is_current = |mapped: &MappedSegment| {
+            mapped.header.uuid == *indexed_uuid && mapped.rows() > max_index
+        };
+
+        let mut mapped_segments = self.mapped_segments.borrow_mut();
+        if let Some(mapped) = mapped_segments.get(&segment_num) {
+            if is_current(mapped) {
+                return Ok(Some(mapped.clone()));
+            }
+        }
+        mapped_segments.remove(&segment_num);
+
+        match MappedSegment::map(&self.data_dir_path, segment_num, self.archive_store()) {
+            Ok(mapped) if is_current(&mapped) => {
+                let mapped = Arc::new(mapped);
+                mapped_segments.insert(segment_num, mapped.clone());
+                Ok(Some(mapped))
+            }
+            // Compacted since it was indexed, which the files report in turn
+            Ok(_) => Ok(None),
+            Err(DBError::IOError(e)) if e.kind() == io::ErrorKind::Unsupported => {
+                warn!("Memory maps are not supported, reading segment files instead");
+                self.mmap_unavailable.set(true);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// If the record at `log_key` is the current version of its key, fold the merge deltas written
+    /// after it into it. Other records are returned as they are.
+    fn fold_merge_deltas(&self, log_key: &LogKey, record: Record) -> DBResult<Record> {
+        if self.merge_deltas.is_empty() && !record.delta {
+            return Ok(record);
+        }
+
+        let pk = key_at(&record, self.primary_key_index)?;
+        if self.primary_memtable.get(&pk) != Some(log_key) {
+            return Ok(record);
+        }
+
+        let delta_log_keys = self.merge_deltas.get(&pk).map_or(&[][..], Vec::as_slice);
+        let deltas = self.read_tagged_log_keys(delta_log_keys.iter().map(|key| (0, key)))?;
+
+        let mut folded = if record.delta {
+            self.merge(None, record)?
+        } else {
+            record
+        };
+        for (_, delta) in deltas {
+            folded = self.merge(Some(folded), delta)?;
+        }
+        Ok(folded)
+    }
+
+    /// Like `fold_merge_deltas`, for the record appended to `arena` at `start`. Records without
+    /// merge deltas are left in place, so the fold only costs a deserialization when it applies.
+    fn fold_merge_deltas_in(
+        &self,
+        log_key: &LogKey,
+        arena: &mut RecordArena,
+        start: usize,
+    ) -> DBResult<()> {
+        let pending = arena.pending(start);
+        if self.merge_deltas.is_empty() && !pending.is_delta() {
+            return Ok(());
+        }
+
+        let pk = pending
+            .get(self.primary_key_index)
+            .and_then(|value| value.as_indexable())
+            .ok_or(DBError::ValidationError(
+                "Primary key must be indexable".to_owned(),
+            ))?;
+        if !pending.is_delta() && !self.merge_deltas.contains_key(&pk) {
+            return Ok(());
+        }
+
+        let record = pending.to_record();
+        let folded = self.fold_merge_deltas(log_key, record)?;
+        arena.replace_pending(start, &folded);
+        Ok(())
+    }
+
+    /// Apply the merge operator to a delta and the previous version of its record.
+    /// The result has the version and timestamp of the delta.
+    fn merge(&self, old: Option<Record>, delta: Record) -> DBResult<Record> {
+        let merge_operator = self.config.merge_operator.ok_or_else(|| {
+            DBError::ConsistencyError(format!(
+                "Record version {} is a merge delta, but no merge operator is configured",
+                delta.version
+            ))
+        })?;
+
+        let keys = self.record_keys(old.as_ref().unwrap_or(&delta))?;
+        let (version, timestamp) = (delta.version, delta.timestamp);
+
+        let old = old.map(|old| R::from_record(old.values));
+        let merged = merge_operator(old, R::from_record(delta.values)).into_record();
+        let mut merged = Record::from(&merged);
+        merged.validate(&self.config.fields)?;
+        if self.record_keys(&merged)? != keys {
+            return Err(DBError::ConsistencyError(format!(
+                "Merge operator changed the keys of record version {}",
+                version
+            )));
+        }
+
+        merged.version = version;
+        merged.timestamp = timestamp;
+        Ok(merged)
+    }
+
+    /// Find the records whose non-indexed `field` has one of the `values` with a full scan.
+    /// The records are tagged with the index of the matching value, like in `batch_find_by_records`.
+    fn find_by_scan(
+        &mut self,
+        field: &R::Field,
+        values: Vec<IndexableValue>,
+    ) -> DBResult<Vec<(usize, Record)>> {
+        debug!(
+            "Scanning for records with non-indexed field {:?} = {:?}",
+            field, values
+        );
+
+        let field_index = self.field_index(field)?;
+
+        let mut tags: HashMap<IndexableValue, Vec<usize>> = HashMap::new();
+        for (tag, value) in values.into_iter().enumerate() {
+            tags.entry(value).or_default().push(tag);
+        }
+
+        let value_tags = |record: &Record| {
+            record
+                .values
+                .get(field_index)
+                .and_then(Value::as_indexable)
+                .and_then(|value| tags.get(&value))
+        };
+        let records = self.scan_filter_records(|record| value_tags(record).is_some())?;
+
+        let mut tagged = vec![];
+        for record in records {
+            for &tag in value_tags(&record).into_iter().flatten() {
+                tagged.push((tag, record.clone()));
+            }
+        }
+        Ok(tagged)
+    }
+
+    /// Find the current records whose fields equal the values of all `conditions`, sorted by
+    /// primary key. The log keys of the conditions on indexed fields are intersected, so that only
+    /// records matching all of them are read. Conditions on other fields are checked on the records
+    /// read. Without any indexed condition, the log is scanned if `NonIndexedQueries::Scan` is set.
+    pub fn find_by_all_records(
+        &mut self,
+        conditions: &[(R::Field, Value)],
+    ) -> DBResult<Vec<Record>> {
+        let conditions = conditions
+            .iter()
+            .map(|(field, value)| {
+                let field_index = self.field_index(field)?;
+                let field_type = &self.config.fields[field_index].1;
+                if !type_check(value, field_type) {
+                    return Err(DBError::ValidationError(format!(
+                        "Queried value {:?} does not match key type: {:?}",
+                        value, field_type
+                    )));
+                }
+                Ok((field, field_index, value))
+            })
+            .collect::<DBResult<Vec<_>>>()?;
+        if conditions.is_empty() {
+            return Err(DBError::ValidationError(
+                "Querying by all of no conditions is not supported".to_owned(),
+            ));
+        }
+        let matches_all = |record: &Record| {
+            conditions
+                .iter()
+                .all(|(_, field_index, value)| record.values.get(*field_index) == Some(*value))
+        };
+
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let field_values: Vec<(&R::Field, &Value)> = conditions
+            .iter()
+            .map(|(field, _, value)| (*field, *value))
+            .collect();
+        let mut records = match self.intersect_log_keys(&field_values)? {
+            Some(log_keys) => {
+                let mut records = vec![];
+                for (tag, record) in
+                    self.read_tagged_log_keys(log_keys.iter().cloned().enumerate())?
+                {
+                    // Secondary memtables may still refer to superseded versions of a record
+                    let pk = key_at(&record, self.primary_key_index)?;
+                    if self.primary_memtable.get(&pk) == Some(log_keys[tag]) && matches_all(&record)
+                    {
+                        records.push(record);
+                    }
+                }
+                records.extend(
+                    self.visible_deleted_records()?
+                        .into_iter()
+                        .filter(|record| matches_all(record)),
+                );
+                records
+            }
+            None if self.config.non_indexed_queries == NonIndexedQueries::Scan => {
+                self.scan_filter_records(|record| matches_all(record))?
+            }
+            None => {
+                return Err(DBError::ValidationError(
+                    "Cannot find_by_all without a condition on an indexed key".to_owned(),
+                ))
+            }
+        };
+
+        records.sort_by_cached_key(|record| record.at(self.primary_key_index).as_indexable());
+        Ok(records)
+    }
+
+    /// Intersect the log keys of the records matching the conditions on indexed fields, starting
+    /// from the smallest set. Returns `None` if none of the fields is indexed.
+    fn intersect_log_keys(
+        &self,
+        conditions: &[(&R::Field, &Value)],
+    ) -> DBResult<Option<Vec<&LogKey>>> {
+        let mut primary: Option<Option<&LogKey>> = None;
+        let mut sets: Vec<&HashSet<LogKey>> = vec![];
+        for &(field, value) in conditions {
+            let field_type = self.get_field_type(field).ok_or(DBError::ValidationError(
+                "Field not found in schema".to_owned(),
+            ))?;
+            if field == &self.config.primary_key {
+                let log_key = self
+                    .primary_memtable
+                    .get(&value_to_indexable(value, field_type)?);
+                primary = Some(match primary {
+                    None => log_key,
+                    Some(previous) => previous.filter(|&previous| Some(previous) == log_key),
+                });
+            } else if let Some(index) =
+                get_secondary_memtable_index_by_field(&self.config.secondary_keys, field)
+            {
+                let key =
+                    self.secondary_collations[index].apply(value_to_indexable(value, field_type)?);
+                sets.push(self.secondary_memtables[index].find_by(&key));
+            }
+        }
+
+        sets.sort_by_key(|set| set.len());
+        let candidates: Vec<&LogKey> = match primary {
+            Some(log_key) => log_key.into_iter().collect(),
+            None => match sets.first() {
+                Some(smallest) => smallest.iter().collect(),
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(
+            candidates
+                .into_iter()
+                .filter(|log_key| sets.iter().all(|set| set.contains(*log_key)))
+                .collect(),
+        ))
+    }
+
+    /// Look up the log keys of the records whose indexed `field` equals `key`. Soft-deleted records
+    /// are included when looking up by primary key with `include_deleted`.
+    fn find_log_keys(&self, field: &R::Field, key: &IndexableValue) -> DBResult<Vec<&LogKey>> {
+        if field == &self.config.primary_key {
+            let log_key = self.primary_memtable.get(key).or_else(|| {
+                self.deleted_memtable
+                    .get(key)
+                    .filter(|_| self.include_deleted)
+            });
+            return Ok(log_key.into_iter().collect());
+        }
+
+        let smemtable_index =
+            get_secondary_memtable_index_by_field(&self.config.secondary_keys, field).ok_or(
+                DBError::ValidationError("Cannot find_by by non-indexed key".to_owned()),
+            )?;
+        let key = self.secondary_collations[smemtable_index].apply(key.clone());
+        Ok(self.secondary_memtables[smemtable_index]
+            .find_by(&key)
+            .iter()
+            .collect())
+    }
+
+    /// Look up the log keys of the records whose indexed `field` is within `bounds`.
+    fn range_log_keys(
+        &self,
+        field: &R::Field,
+        bounds: &OwnedBounds<IndexableValue>,
+    ) -> DBResult<Vec<&LogKey>> {
+        if field == &self.config.primary_key {
+            return Ok(self.primary_memtable.range(bounds.clone()));
+        }
+
+        let index = get_secondary_memtable_index_by_field(&self.config.secondary_keys, field)
+            .ok_or_else(|| {
+                DBError::ValidationError("Cannot range_by by non-indexed key".to_owned())
+            })?;
+        Ok(self.secondary_memtables[index].range(bounds.clone()))
+    }
+
+    /// Look up the log keys of the records whose composite key `fields` starts with `prefix`, and
+    /// whose field following the prefix is in `range`, in the order of the composite key.
+    fn composite_log_keys<B: RangeBounds<Value>>(
+        &self,
+        fields: &[R::Field],
+        prefix: &[Value],
+        range: B,
+    ) -> DBResult<Vec<&LogKey>> {
+        let ck_index = self
+            .config
+            .composite_keys
+            .iter()
+            .position(|composite_key| composite_key.as_slice() == fields)
+            .ok_or_else(|| {
+                DBError::ValidationError(format!("No composite key {:?} in schema", fields))
+            })?;
+        if prefix.len() > fields.len() {
+            return Err(DBError::ValidationError(format!(
+                "Composite key {:?} has fewer fields than the queried prefix",
+                fields
+            )));
+        }
+
+        let field_types: Vec<&Type> = self.composite_key_indexes[ck_index]
+            .iter()
+            .map(|&field_index| &self.config.fields[field_index].1)
+            .collect();
+        let prefix: Vec<IndexableValue> = prefix
+            .iter()
+            .zip(&field_types)
+            .map(|(value, field_type)| value_to_indexable(value, field_type))
+            .collect::<DBResult<_>>()?;
+        // The range applies to the field after the prefix. A full prefix leaves nothing to range over.
+        let next_bounds = match field_types.get(prefix.len()) {
+            Some(field_type) => OwnedBounds::new(
+                bound_to_indexable(range.start_bound(), field_type)?,
+                bound_to_indexable(range.end_bound(), field_type)?,
+            ),
+            None => OwnedBounds::new(Bound::Unbounded, Bound::Unbounded),
+        };
+
+        Ok(self.composite_memtables[ck_index]
+            .range_entries((Bound::Included(prefix.clone()), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter(|(key, _)| {
+                key.get(prefix.len())
+                    .is_none_or(|next| next_bounds.contains(next))
+            })
+            .flat_map(|(_, log_keys)| log_keys.iter())
+            .collect())
+    }
+
+    /// Compute the plan of a query from the memtables, see `DB::explain`.
+    pub fn explain_query(&mut self, query: &Query<R::Field>) -> DBResult<QueryPlan> {
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let (field, value) = match query {
+            Query::Get(value) => (&self.config.primary_key, value),
+            Query::FindBy(field, value) => (field, value),
+            Query::FindByAll(conditions) => {
+                let field_values: Vec<(&R::Field, &Value)> = conditions
+                    .iter()
+                    .map(|(field, value)| (field, value))
+                    .collect();
+                let indexed_fields: Vec<&R::Field> = conditions
+                    .iter()
+                    .map(|(field, _)| field)
+                    .filter(|field| {
+                        *field == &self.config.primary_key
+                            || self.config.secondary_keys.contains(field)
+                    })
+                    .collect();
+                return match self.intersect_log_keys(&field_values)? {
+                    Some(mut log_keys) => {
+                        let (index, field) = match indexed_fields.as_slice() {
+                            [field] if *field == &self.config.primary_key => {
+                                (QueryIndex::Primary, format!("{:?}", field))
+                            }
+                            [field] => (QueryIndex::Secondary, format!("{:?}", field)),
+                            fields => (QueryIndex::Intersection, format!("{:?}", fields)),
+                        };
+                        // Soft-deleted records are only indexed by their primary key
+                        if self.include_deleted {
+                            log_keys
+                                .extend(self.deleted_memtable.iter().map(|(_, log_key)| log_key));
+                        }
+                        Ok(plan_from_log_keys(index, field, log_keys))
+                    }
+                    None if self.config.non_indexed_queries == NonIndexedQueries::Scan => {
+                        let fields: Vec<&R::Field> =
+                            conditions.iter().map(|(field, _)| field).collect();
+                        self.full_scan_plan(format!("{:?}", fields))
+                    }
+                    None => Err(DBError::ValidationError(
+                        "Cannot find_by_all without a condition on an indexed key".to_owned(),
+                    )),
+                };
+            }
+            Query::RangeBy(field, start, end) => {
+                let bounds = self.range_bounds(field, (start.as_ref(), end.as_ref()))?;
+                let log_keys = self.range_log_keys(field, &bounds)?;
+                return Ok(self.indexed_plan(field, log_keys));
+            }
+            Query::Composite(fields, prefix, start, end) => {
+                let log_keys =
+                    self.composite_log_keys(fields, prefix, (start.as_ref(), end.as_ref()))?;
+                return Ok(plan_from_log_keys(
+                    QueryIndex::Composite,
+                    format!("{:?}", fields),
+                    log_keys,
+                ));
+            }
+        };
+
+        let field_type = self.get_field_type(field).ok_or(DBError::ValidationError(
+            "Field not found in schema".to_owned(),
+        ))?;
+        let key = value_to_indexable(value, field_type)?;
+        if field != &self.config.primary_key
+            && !self.config.secondary_keys.contains(field)
+            && self.config.non_indexed_queries == NonIndexedQueries::Scan
+        {
+            return self.full_scan_plan(format!("{:?}", field));
+        }
+
+        let log_keys = self.find_log_keys(field, &key)?;
+        Ok(self.indexed_plan(field, log_keys))
+    }
+
+    /// The plan of a query that scans every row of every segment.
+    fn full_scan_plan(&self, field: String) -> DBResult<QueryPlan> {
+        let mut plan = QueryPlan {
+            index: QueryIndex::FullScan,
+            field,
+            estimated_log_keys: 0,
+            segments: list_segment_numbers(&self.data_dir_path)?,
+        };
+        for &segment_num in &plan.segments {
+            let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+            let mut metadata_file = READ_MODE.open(metadata_path)?;
+            let metadata_len = metadata_file.seek(SeekFrom::End(0))?;
+            let format_version = read_metadata_header(&mut metadata_file)?.version;
+            plan.estimated_log_keys += metadata_len
+                .saturating_sub(METADATA_FILE_HEADER_SIZE as u64)
+                / metadata_row_length(format_version) as u64;
+        }
+        Ok(plan)
+    }
+
+    /// The plan of a query by the primary key or a secondary key of `field` that reads `log_keys`.
+    /// Queries by a secondary key with `include_deleted` also read all soft-deleted records.
+    fn indexed_plan<'a>(&'a self, field: &R::Field, mut log_keys: Vec<&'a LogKey>) -> QueryPlan {
+        let index = if field == &self.config.primary_key {
+            QueryIndex::Primary
+        } else {
+            if self.include_deleted {
+                log_keys.extend(self.deleted_memtable.iter().map(|(_, log_key)| log_key));
+            }
+            QueryIndex::Secondary
+        };
+        plan_from_log_keys(index, format!("{:?}", field), log_keys)
+    }
+
+    /// Convert a range of values of `field` into a range of index keys.
+    pub fn range_bounds<B: RangeBounds<Value>>(
+        &self,
+        field: &R::Field,
+        range: B,
+    ) -> DBResult<OwnedBounds<IndexableValue>> {
+        let field_type = self.get_field_type(field).ok_or(DBError::ValidationError(
+            "Field not found in schema".to_owned(),
+        ))?;
+
+        let mut start_indexable = bound_to_indexable(range.start_bound(), field_type)?;
+        let end_indexable = bound_to_indexable(range.end_bound(), field_type)?;
+
+        // Null sorts before all other values, but like `top_k` and aggregates, ranges skip nulls
+        // unless they are asked for with a null bound
+        let end_is_null = matches!(
+            end_indexable,
+            Bound::Included(IndexableValue::Null) | Bound::Excluded(IndexableValue::Null)
+        );
+        if start_indexable == Bound::Unbounded && !end_is_null {
+            start_indexable = Bound::Excluded(IndexableValue::Null);
+        }
+
+        if field != &self.config.primary_key {
+            if let Some(index) =
+                get_secondary_memtable_index_by_field(&self.config.secondary_keys, field)
+            {
+                let collation = &self.secondary_collations[index];
+                let start_key = start_indexable.map(|key| collation.apply(key));
+                let end_key = end_indexable.map(|key| collation.apply(key));
+                return Ok(OwnedBounds::new(start_key, end_key));
+            }
+        }
+        Ok(OwnedBounds::new(start_indexable, end_indexable))
+    }
+
+    /// Count the current records, or the distinct values of an indexed `field`, from the sizes of
+    /// the memtables. With `include_deleted`, soft-deleted records are counted as records.
+    pub fn estimate_cardinality(&mut self, field: &R::Field) -> DBResult<u64> {
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        if field == &self.config.primary_key {
+            let mut count = self.primary_memtable.len();
+            if self.include_deleted {
+                count += self.deleted_memtable.len();
+            }
+            return Ok(count as u64);
+        }
+
+        let smemtable_index =
+            get_secondary_memtable_index_by_field(&self.config.secondary_keys, field).ok_or(
+                DBError::ValidationError(
+                    "Cannot estimate the cardinality of a non-indexed key".to_owned(),
+                ),
+            )?;
+        Ok(self.secondary_memtables[smemtable_index].key_count() as u64)
+    }
+
+    /// Get the primary keys of the current records in `range` in index order, from the primary
+    /// memtable only. With `include_deleted`, the keys of soft-deleted records are included.
+    pub fn primary_keys<B: RangeBounds<Value>>(&mut self, range: B) -> DBResult<Vec<Value>> {
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let bounds = self.range_bounds(&self.config.primary_key, range)?;
+        let bounds = (bounds.start_bound(), bounds.end_bound());
+        let mut keys: Vec<&IndexableValue> = self
+            .primary_memtable
+            .range_entries(bounds)
+            .map(|(key, _)| key)
+            .collect();
+        if self.include_deleted {
+            keys.extend(
+                self.deleted_memtable
+                    .range_entries(bounds)
+                    .map(|(key, _)| key),
+            );
+            keys.sort_unstable();
+        }
+
+        Ok(keys.into_iter().cloned().map(Value::from).collect())
+    }
+
+    pub fn range_by_records<B: RangeBounds<Value>>(
+        &mut self,
+        field: &R::Field,
+        range: B,
+    ) -> DBResult<Vec<Record>> {
+        Ok(self
+            .batch_range_by_records(field, std::slice::from_ref(&range))?
+            .into_iter()
+            .map(|(_, rec)| rec)
+            .collect())
+    }
+
+    /// Get the records whose key in the computed index `name` is within `range`, see
+    /// `DB::range_by_computed`.
+    pub fn range_by_computed_records<B: RangeBounds<Value>>(
+        &mut self,
+        name: &str,
+        range: B,
+    ) -> DBResult<Vec<Record>> {
+        let index = self
+            .config
+            .computed_indexes
+            .iter()
+            .position(|(index_name, _)| index_name == name)
+            .ok_or_else(|| {
+                DBError::ValidationError(format!("Computed index {} not found", name))
+            })?;
+        let to_indexable = |bound: Bound<&Value>| match bound {
+            Bound::Included(value) => value.as_indexable().map(Bound::Included),
+            Bound::Excluded(value) => value.as_indexable().map(Bound::Excluded),
+            Bound::Unbounded => Some(Bound::Unbounded),
+        };
+        let (Some(mut start), Some(end)) = (
+            to_indexable(range.start_bound()),
+            to_indexable(range.end_bound()),
+        ) else {
+            return Err(DBError::ValidationError(
+                "Queried value must be indexable".to_owned(),
+            ));
+        };
+        // Like in `range_bounds`, nulls are skipped unless they are asked for with a null bound
+        let end_is_null = matches!(
+            end,
+            Bound::Included(IndexableValue::Null) | Bound::Excluded(IndexableValue::Null)
+        );
+        if start == Bound::Unbounded && !end_is_null {
+            start = Bound::Excluded(IndexableValue::Null);
+        }
+        let bounds = OwnedBounds::new(start, end);
+
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let log_keys = self.computed_memtables[index].range(bounds.clone());
+        let mut records: Vec<Record> = self
+            .read_tagged_log_keys(log_keys.into_iter().map(|log_key| (0, log_key)))?
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect();
+
+        // Soft-deleted records are only indexed by their primary key
+        let compute = self.config.computed_indexes[index].1;
+        for record in self.visible_deleted_records()? {
+            let key = compute(&record.values).as_indexable();
+            if key.is_some_and(|key| bounds.contains(&key)) {
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Get the records whose field is in any of `ranges`, tagged with the index of the range, and
+    /// grouped by range in the order of `ranges`. The records of all ranges are read in a single
+    /// pass over the segments. A record in several ranges is returned once for each of them.
+    pub fn batch_range_by_records<B: RangeBounds<Value>>(
+        &mut self,
+        field: &R::Field,
+        ranges: &[B],
+    ) -> DBResult<Vec<(usize, Record)>> {
+        let indexable_bounds = ranges
+            .iter()
+            .map(|range| self.range_bounds(field, (range.start_bound(), range.end_bound())))
+            .collect::<DBResult<Vec<_>>>()?;
+
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let mut log_key_batches = vec![];
+        for (tag, bounds) in indexable_bounds.iter().enumerate() {
+            let log_keys = self.range_log_keys(field, bounds)?;
+            log_key_batches.extend(log_keys.into_iter().map(|log_key| (tag, log_key)));
+        }
+
+        let mut records = self.read_tagged_log_keys(log_key_batches.into_iter())?;
+
+        let deleted_records = self.visible_deleted_records()?;
+        if deleted_records.is_empty() {
+            records.sort_by_key(|(tag, _)| *tag);
+        } else {
+            let field_index = self.field_index(field)?;
+            for record in deleted_records {
+                let key = key_at(&record, field_index)?;
+                for (tag, bounds) in indexable_bounds.iter().enumerate() {
+                    if bounds.contains(&key) {
+                        records.push((tag, record.clone()));
+                    }
+                }
+            }
+            records
+                .sort_by_cached_key(|(tag, record)| (*tag, record.at(field_index).as_indexable()));
+        }
+
+        Ok(records)
+    }
+
+    /// Get the records whose geo point `field` is within the bounding box from `min` to `max`,
+    /// see `DB::find_in_bbox`.
+    pub fn find_in_bbox_records(
+        &mut self,
+        field: &R::Field,
+        min: (f64, f64),
+        max: (f64, f64),
+    ) -> DBResult<Vec<Record>> {
+        let geo_boxes = GeoBox::cover(min, max)?;
+
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let log_keys = self.bbox_log_keys(field, &geo_boxes)?;
+        let mut records: Vec<Record> = self
+            .read_tagged_log_keys(log_keys.into_iter().map(|log_key| (0, log_key)))?
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect();
+
+        let field_index = self.field_index(field)?;
+        for record in self.visible_deleted_records()? {
+            let key = key_at(&record, field_index)?;
+            if geo_boxes.iter().any(|geo_box| geo_box.contains(&key)) {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Look up the log keys of the records whose geo point `field` is in one of `geo_boxes`, in
+    /// z-order. The secondary index of the field is scanned from the z-order key of the south-west
+    /// corner of each box to that of its north-east corner, skipping ahead whenever a key outside
+    /// of the box is met.
+    fn bbox_log_keys(&self, field: &R::Field, geo_boxes: &[GeoBox]) -> DBResult<Vec<&LogKey>> {
+        let is_geo_point = self
+            .get_field_type(field)
+            .is_some_and(|field_type| matches!(field_type.primitive, PrimitiveType::GeoPoint));
+        let index = get_secondary_memtable_index_by_field(&self.config.secondary_keys, field)
+            .filter(|_| is_geo_point)
+            .ok_or_else(|| {
+                DBError::ValidationError(
+                    "Cannot find_in_bbox by a field that is not a geo point secondary key"
+                        .to_owned(),
+                )
+            })?;
+
+        let mut log_keys = vec![];
+        for geo_box in geo_boxes {
+            let end = IndexableValue::GeoPoint(geo_box.max_z, u64::MAX, u64::MAX);
+            let mut start = geo_box.min_z;
+            'seek: loop {
+                let start_key = IndexableValue::GeoPoint(start, 0, 0);
+                for (key, entry_log_keys) in self.secondary_memtables[index]
+                    .range_entries((Bound::Included(start_key), Bound::Included(end.clone())))
+                {
+                    let IndexableValue::GeoPoint(z, _, _) = key else {
+                        continue;
+                    };
+                    if !geo_box.contains_z(*z) {
+                        start = geo_box.next_z(*z);
+                        continue 'seek;
+                    }
+                    if geo_box.contains(key) {
+                        log_keys.extend(entry_log_keys.iter());
+                    }
+                }
+                break;
+            }
+        }
+        Ok(log_keys)
+    }
+
+    /// Scan all segments and return the current version of each record that matches `predicate`.
+    /// Superseded versions and deleted records are skipped before the predicate is applied.
+    pub fn scan_filter_records(
+        &mut self,
+        mut predicate: impl FnMut(&Record) -> bool,
+    ) -> DBResult<Vec<Record>> {
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let now = unix_millis(SystemTime::now());
+        let mut records = vec![];
+        self.scan_log(|engine, log_key, record| {
+            if record.tombstone {
+                return Ok(());
+            }
+
+            let pk = key_at(&record, engine.primary_key_index)?;
+            if !engine.is_visible(&pk, &log_key, record.deleted) {
+                return Ok(());
+            }
+
+            let record = engine.fold_merge_deltas(&log_key, record)?;
+            if engine.is_expired(&record, now) {
+                return Ok(());
+            }
+            if predicate(&record) {
+                records.push(record);
+            }
+            Ok(())
+        })?;
+
+        debug!("Scan matched {} records", records.len());
+
+        Ok(records)
+    }
+
+    /// Read all surviving versions of the record with the primary key `pk`, including merge deltas
+    /// and tombstones, in log order.
+    pub fn history_records(&mut self, pk: &Value) -> DBResult<Vec<(LogKey, Record)>> {
+        let pk = value_to_indexable(pk, &self.config.fields[self.primary_key_index].1)?;
+
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let mut versions = vec![];
+        self.scan_log(|engine, log_key, record| {
+            if key_at(&record, engine.primary_key_index)? == pk {
+                versions.push((log_key, record));
+            }
+            Ok(())
+        })?;
+
+        Ok(versions)
+    }
+
+    /// Like `scan_filter_records`, but the records are read into `arena`, replacing its contents.
+    /// Records that do not match the predicate are overwritten by the next one read, so the arena
+    /// only grows by the size of the matches.
+    pub fn scan_filter_into(
+        &mut self,
+        mut predicate: impl FnMut(&ArenaRecord) -> bool,
+        arena: &mut RecordArena,
+    ) -> DBResult<()> {
+        arena.clear();
+        arena.set_log_encoding(&self.log_encoding);
+        if self.config.read_consistency == ReadConsistency::Strong {
+            self.refresh_indexes()?;
+        } else {
+            self.resync_compacted_segments()?;
+        }
+
+        let now = unix_millis(SystemTime::now());
+        for segment_num in list_segment_numbers(&self.data_dir_path)? {
+            self.check_cancelled()?;
+            let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+            let mut metadata_file = READ_MODE.open(&metadata_path)?;
+
+            let metadata_header = read_metadata_header(&mut metadata_file)?;
+            validate_metadata_header(&metadata_header)?;
+
+            let data_file = self.open_data_file(&metadata_header.uuid)?;
+
+            let mut reader = ForwardLogReader::new(metadata_file, data_file)
+                .with_encryption(&self.config.encryption);
+            loop {
+                let start = arena.next_start();
+                let Some(row) = reader.read_raw(arena.buf_mut())? else {
+                    break;
+                };
+                let log_key = LogKey::new(segment_num, row.index);
+
+                let pending = arena.pending(start);
+                let is_current = !pending.is_tombstone()
+                    && pending
+                        .get(self.primary_key_index)
+                        .and_then(|value| value.as_indexable())
+                        .is_some_and(|pk| self.is_visible(&pk, &log_key, pending.meta().deleted));
+                if !is_current {
+                    arena.rollback(start);
+                    continue;
+                }
+
+                self.fold_merge_deltas_in(&log_key, arena, start)?;
+                if self.is_expired_in(&arena.pending(start), now) {
+                    arena.rollback(start);
+                    continue;
+                }
+                arena.commit(0, start);
+                if !predicate(&arena.get(arena.len() - 1).unwrap()) {
+                    arena.pop();
+                }
+            }
+        }
+
+        debug!("Scan matched {} records", arena.len());
+
+        Ok(())
+    }
+
+    /// Whether the version of the record with the primary key `pk` at `log_key` is the one seen by
+    /// queries: the newest version, unless it is soft-deleted and deleted records are not included.
+    fn is_visible(&self, pk: &IndexableValue, log_key: &LogKey, deleted: bool) -> bool {
+        if deleted {
+            self.include_deleted && self.deleted_memtable.get(pk) == Some(log_key)
+        } else {
+            self.primary_memtable.get(pk) == Some(log_key)
+        }
+    }
+
+    /// Start keeping a wheel of the expiry times of the current records up to date, for an
+    /// `Expirations` stream, see `DB::expirations`. Records that have already expired are not
+    /// scheduled.
+    #[cfg(feature = "tokio")]
+    pub fn watch_expirations(
+        &mut self,
+    ) -> DBResult<Arc<std::sync::Mutex<expirations::ExpiryWheel>>> {
+        let expiry_index = self.expiry_index.ok_or(DBError::ValidationError(
+            "Watching expirations requires an expiry field".to_owned(),
+        ))?;
+
+        // Records indexed while the current ones are scanned are scheduled as they are indexed
+        let watch = ExpiryWatch::new();
+        let wheel = watch.wheel().clone();
+        self.expiry_watches.push(watch);
+
+        let records =
+            self.scan_filter_records(|record| matches!(record.at(expiry_index), Value::Int(_)))?;
+        let mut wheel_guard = wheel.lock().unwrap();
+        for record in &records {
+            let pk = key_at(record, self.primary_key_index)?;
+            wheel_guard.schedule(pk, self.expires_at(record));
+        }
+        drop(wheel_guard);
+        Ok(wheel)
+    }
+
+    /// Whether the record with the primary key `pk` has been deleted, with a tombstone or softly.
+    pub fn is_deleted(&self, pk: &IndexableValue) -> bool {
+        self.tombstoned_keys.contains(pk) || self.deleted_memtable.get(pk).is_some()
+    }
+
+    /// Whether the expiry time of `record` has passed at `now`, in milliseconds since the Unix epoch.
+    /// Only full records expire: a delta is folded into a record before its expiry time is checked.
+    fn is_expired(&self, record: &Record, now: i64) -> bool {
+        match self.expiry_index.map(|index| record.at(index)) {
+            Some(Value::Int(expires_at)) => {
+                !record.tombstone && !record.delta && *expires_at <= now
+            }
+            _ => false,
+        }
+    }
+
+    /// The expiry time of `record` in milliseconds since the Unix epoch, if it has one.
+    fn expires_at(&self, record: &Record) -> Option<i64> {
+        match self.expiry_index.map(|index| record.at(index)) {
+            Some(Value::Int(expires_at)) => Some(*expires_at),
+            _ => None,
+        }
+    }
+
+    /// Like `is_expired`, for a record in an arena.
+    fn is_expired_in(&self, record: &ArenaRecord, now: i64) -> bool {
+        match self.expiry_index.and_then(|index| record.get(index)) {
+            Some(ValueRef::Int(expires_at)) => {
+                !record.is_tombstone() && !record.is_delta() && expires_at <= now
+            }
+            _ => false,
+        }
+    }
+
+    /// Read all soft-deleted records if queries include them, see `DB::include_deleted`.
+    /// Deleted records are not in the secondary indexes, so queries by other fields than the
+    /// primary key must read all of them.
+    fn visible_deleted_records(&self) -> DBResult<Vec<Record>> {
+        if !self.include_deleted {
+            return Ok(vec![]);
+        }
+
+        let log_keys = self
+            .deleted_memtable
+            .iter()
+            .map(|(_, log_key)| (0, log_key));
+        Ok(self
+            .read_tagged_log_keys(log_keys)?
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect())
+    }
+
+    /// Call `f` with every row of every segment in log order, including superseded versions.
+    fn scan_log(&self, mut f: impl FnMut(&Self, LogKey, Record) -> DBResult<()>) -> DBResult<()> {
+        for segment_num in list_segment_numbers(&self.data_dir_path)? {
+            self.check_cancelled()?;
+            let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+            let mut metadata_file = READ_MODE.open(&metadata_path)?;
+
+            let metadata_header = read_metadata_header(&mut metadata_file)?;
+            validate_metadata_header(&metadata_header)?;
+
+            let data_file = self.open_data_file(&metadata_header.uuid)?;
+
+            for item in ForwardLogReader::new(metadata_file, data_file)
+                .with_log_encoding(&self.log_encoding)
+                .with_encryption(&self.config.encryption)
+                .try_records()
+            {
+                let ForwardLogReaderItem { record, index, .. } = item?;
+                f(self, LogKey::new(segment_num, index), record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ensures that the `self.metadata_file` and `self.data_file` handles are still pointing to the correct files.
+    /// If the segment has been rotated, the handle will be closed and reopened.
+    /// Returns `false` if the file has been rotated and the handle has been reopened, `true` otherwise.
+    fn ensure_metadata_file_is_active(&mut self) -> DBResult<bool> {
+        let active_target = fs::read_link(&self.data_dir_path.join(ACTIVE_SYMLINK_FILENAME))?;
+        let active_metadata_path = &self.data_dir_path.join(active_target);
+
+        let correct = is_file_same_as_path(&self.active_metadata_file, &active_metadata_path)?;
+        if !correct {
+            debug!("Metadata file has been rotated. Reopening...");
+            let mut metadata_file = APPEND_MODE.open(active_metadata_path)?;
+
+            let metadata_header = read_metadata_header(&mut metadata_file)?;
+
+            validate_metadata_header(&metadata_header)?;
+
+            let data_file_path = &self.data_dir_path.join(metadata_header.uuid.to_string());
+
+            self.active_metadata_file = metadata_file;
+            self.active_data_file = APPEND_MODE.open(&data_file_path)?;
+
+            return Ok(false);
+        } else {
+            return Ok(true);
+        }
+    }
+
+    pub fn delete_by_field(&mut self, field: &R::Field, value: &Value) -> DBResult<Vec<Record>> {
+        Ok(self
+            .batch_delete_by_field(field, std::iter::once(value))?
+            .into_iter()
+            .map(|(_, rec)| rec)
+            .collect())
+    }
+
+    /// Delete the records whose field has any of the given values. Returns the deleted records
+    /// tagged with the index of the value they matched. A record matched by duplicate values is
+    /// deleted and returned once, tagged with the first of them.
+    pub fn batch_delete_by_field<'a>(
+        &mut self,
+        field: &R::Field,
+        values: impl Iterator<Item = &'a Value>,
+    ) -> DBResult<Vec<(usize, Record)>> {
+        // Writes by other processes must be seen to assign the tombstones a greater version
+        self.refresh_indexes()?;
+
+        let mut seen_pks = HashSet::new();
+        let tagged_recs: Vec<(usize, Record)> = self
+            .batch_find_by_records(field, values)?
+            .into_iter()
+            .filter(|(_, rec)| {
+                let pk = rec.at(self.primary_key_index).as_indexable();
+                seen_pks.insert(pk)
+            })
+            .collect();
+        let recs = tagged_recs.iter().map(|(_, rec)| rec.clone()).collect();
+
+        match self.config.delete_mode {
+            DeleteMode::Hard => {
+                self.write_tombstones(recs)?;
+            }
+            DeleteMode::Soft => {
+                let deleted = recs.into_iter().map(|mut rec: Record| {
+                    rec.deleted = true;
+                    rec
+                });
+                self.batch_upsert_records(deleted)?;
+
+                debug!("Records soft-deleted");
+            }
+        }
+        Ok(tagged_recs)
+    }
+
+    /// Restore the soft-deleted record with the primary key `pk` by writing it again as a new
+    /// version. Returns `None` if there is no soft-deleted record with the key.
+    pub fn undelete_record(&mut self, pk: &Value) -> DBResult<Option<Record>> {
+        self.refresh_indexes()?;
+
+        let pk = value_to_indexable(pk, &self.config.fields[self.primary_key_index].1)?;
+        let Some(log_key) = self.deleted_memtable.get(&pk) else {
+            return Ok(None);
+        };
+
+        let mut record = match self
+            .read_tagged_log_keys(std::iter::once((0, log_key)))?
+            .pop()
+        {
+            Some((_, record)) => record,
+            None => return Ok(None),
+        };
+        record.deleted = false;
+        self.batch_upsert_records(std::iter::once(record.clone()))?;
+
+        Ok(Some(record))
+    }
+
+    /// Delete the record with the primary key `pk` with a tombstone, whether it is soft-deleted or not.
+    pub fn purge_record(&mut self, pk: &Value) -> DBResult<Option<Record>> {
+        self.refresh_indexes()?;
+
+        let include_deleted = std::mem::replace(&mut self.include_deleted, true);
+        let primary_key = self.config.primary_key.clone();
+        let found = self.batch_find_by_records(&primary_key, std::iter::once(pk));
+        self.include_deleted = include_deleted;
+
+        let recs = found?.into_iter().map(|(_, rec)| rec).collect();
+        Ok(self.write_tombstones(recs)?.pop())
+    }
+
+    fn write_tombstones(&mut self, recs: Vec<Record>) -> DBResult<Vec<Record>> {
+        if recs.is_empty() {
+            return Ok(recs);
+        }
+        let position = self.begin_append()?;
+        let timestamp = current_timestamp();
+        let recs: Vec<Record> = recs
+            .into_iter()
+            .map(|mut rec| {
+                rec.tombstone = true;
+                rec.timestamp = timestamp;
+                rec.version = self.next_version;
+                self.next_version += 1;
+                rec
+            })
+            .collect();
+
+        // The tombstones are appended at once, like a batch of upserts
+        let mut data: Vec<u8> = vec![];
+        let mut metadata: Vec<u8> = vec![];
+        for record in &recs {
+            let serialized = self.log_encoding.serialize(record);
+            let stored = position
+                .header
+                .encode_record(&serialized, self.config.encryption.as_deref())?;
+            let offset = position.data_end + data.len() as u64;
+            metadata.extend(MetadataRow::new(offset, &stored).serialize());
+            data.extend_from_slice(&stored);
+        }
+        self.finish_append(position.segment_num, &data, &metadata)?;
+
+        for record in &recs {
+            self.remove_record_from_memtables(record)?;
+        }
+
+        debug!("Records deleted");
+
+        Ok(recs)
+    }
+
+    pub fn do_maintenance_tasks(&mut self) -> DBResult<()> {
+        ensure_active_metadata_is_valid(&self.data_dir_path, &mut self.active_metadata_file)?;
+
+        let strategy = self.config.compaction_strategy.clone();
+        let active_num = self.active_segment_num()?;
+        let active_stats = self.segment_stats(active_num)?;
+        if strategy.should_rotate(&active_stats, self.config.segment_size) {
+            debug!("Compaction strategy rotates the active segment");
+            self.rotate_and_compact()?;
+        } else if self.config.exceeds_segment_limits(&active_stats) {
+            debug!("Active segment reached its maximum record count or age, rotating");
+            self.rotate_and_compact()?;
+        }
+
+        let active_num = self.active_segment_num()?;
+        let mut sealed_stats = vec![];
+        for segment_num in list_segment_numbers(&self.data_dir_path)? {
+            if segment_num != active_num {
+                sealed_stats.push(self.segment_stats(segment_num)?);
+            }
+        }
+        let selected: Vec<u16> = strategy
+            .select_sealed(&sealed_stats)
+            .into_iter()
+            .filter(|&segment_num| sealed_stats.iter().any(|s| s.segment_num == segment_num))
+            .collect();
+        if !selected.is_empty() {
+            debug!(
+                "Compaction strategy compacts sealed segments {:?}",
+                selected
+            );
+            self.compact_segments(SegmentSelector::Ids(selected.clone()))?;
+            for segment_num in selected {
+                self.mark_maintenance_compacted(segment_num)?;
+            }
+        }
+
+        // Compaction keeps the segments, so the merges selected from the same statistics still apply
+        for merge in strategy.select_merges(&sealed_stats) {
+            debug!("Compaction strategy merges sealed segments {:?}", merge);
+            let report = self.merge_segments(&merge)?;
+            self.mark_maintenance_compacted(report.segment_num)?;
+        }
+
+        self.compact_for_quota()?;
+        self.enforce_retention()?;
+        self.archive_cold_segments()?;
+
+        Ok(())
+    }
+
+    fn mark_maintenance_compacted(&mut self, segment_num: u16) -> DBResult<()> {
+        let (header, metadata_bytes, _) = self.segment_file_state(segment_num)?;
+        self.maintenance_compacted
+            .insert(segment_num, (header.uuid, metadata_bytes));
+        Ok(())
+    }
+
+    /// Drop and merge the oldest segments while the data directory exceeds the retention policy,
+    /// see `ConfigBuilder::retention`.
+    fn enforce_retention(&mut self) -> DBResult<()> {
+        let Some(policy) = self.config.retention.clone() else {
+            return Ok(());
+        };
+        if self.retention_exceeded(&policy)?.is_empty() {
+            return Ok(());
+        }
+
+        // Segments without current records are found from the memtables, which must be up to date
+        self.refresh_indexes()?;
+        let active_num = self.active_segment_num()?;
+        let mut live_segments = BTreeSet::new();
+        let log_keys = self
+            .primary_memtable
+            .iter()
+            .chain(self.deleted_memtable.iter());
+        live_segments.extend(log_keys.map(|(_, log_key)| log_key.segment_num()));
+        let deltas = self.merge_deltas.values().flatten();
+        live_segments.extend(deltas.map(|log_key| log_key.segment_num()));
+
+        // The tombstones of the oldest segment shadow nothing, so it can be dropped once none of
+        // its records are current
+        let mut dropped_segments = vec![];
+        loop {
+            let oldest = list_segment_numbers(&self.data_dir_path)?[0];
+            if oldest == active_num
+                || live_segments.contains(&oldest)
+                || self.retention_exceeded(&policy)?.is_empty()
+            {
+                break;
+            }
+            debug!("Retention policy drops superseded segment {}", oldest);
+            self.drop_oldest_segment(oldest)?;
+            dropped_segments.push(oldest);
+        }
+
+        // The rest of the segments over the limits are merged to drop their superseded records.
+        // A segment that maintenance has merged before has nothing more to give until it changes.
+        let sealed: Vec<u16> = list_segment_numbers(&self.data_dir_path)?
+            .into_iter()
+            .filter(|&segment_num| segment_num != active_num)
+            .collect();
+        let merged = self.retention_merge_count(&policy, &sealed)?;
+        let already_merged = merged == 1 && self.segment_stats(sealed[0])?.compacted;
+        if merged > 0 && !already_merged {
+            debug!(
+                "Retention policy merges sealed segments {:?}",
+                &sealed[..merged]
+            );
+            let report = self.merge_segments(&sealed[..merged])?;
+            self.mark_maintenance_compacted(report.segment_num)?;
+        }
+
+        let exceeded = self.retention_exceeded(&policy)?;
+        if exceeded.is_empty() {
+            return Ok(());
+        }
+        let report = RetentionReport {
+            total_bytes: data_dir_size(&self.data_dir_path)?,
+            segments: list_segment_numbers(&self.data_dir_path)?.len(),
+            dropped_segments,
+        };
+        match self.config.retention_hook {
+            Some(retention_hook) => {
+                warn!(
+                    "Live records exceed the retention policy: {}",
+                    exceeded.join(", ")
+                );
+                retention_hook(&report);
+                Ok(())
+            }
+            None => Err(DBError::QuotaExceeded(format!(
+                "live records exceed the retention policy: {}",
+                exceeded.join(", ")
+            ))),
+        }
+    }
+
+    /// The limits of the retention policy that the data directory exceeds.
+    fn retention_exceeded(&self, policy: &RetentionPolicy) -> DBResult<Vec<String>> {
+        let segment_nums = list_segment_numbers(&self.data_dir_path)?;
+        let oldest_sealed_modified = match segment_nums.first() {
+            Some(&oldest) if oldest != self.active_segment_num()? => {
+                let metadata_path = self.data_dir_path.join(metadata_filename(oldest));
+                Some(fs::metadata(metadata_path)?.modified()?)
+            }
+            _ => None,
+        };
+        Ok(policy.exceeded(
+            data_dir_size(&self.data_dir_path)?,
+            segment_nums.len(),
+            oldest_sealed_modified,
+        ))
+    }
+
+    /// The number of the oldest `sealed` segments to merge to keep to the retention policy: all of
+    /// them if the data directory is too large, enough of them to leave `max_segments` segments, and
+    /// the ones older than `max_age`.
+    fn retention_merge_count(&self, policy: &RetentionPolicy, sealed: &[u16]) -> DBResult<usize> {
+        let mut count = 0;
+        if let Some(max_total_bytes) = policy.max_total_bytes {
+            if data_dir_size(&self.data_dir_path)? > max_total_bytes {
+                count = sealed.len();
+            }
+        }
+        if let Some(max_segments) = policy.max_segments {
+            // The active segment counts towards the limit, and the merged segments become one
+            let excess = (sealed.len() + 1).saturating_sub(max_segments.max(2));
+            if excess > 0 {
+                count = count.max(excess + 1);
+            }
+        }
+        if let Some(max_age) = policy.max_age {
+            let threshold = SystemTime::now()
+                .checked_sub(max_age)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            for (i, &segment_num) in sealed.iter().enumerate() {
+                let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+                if fs::metadata(metadata_path)?.modified()? < threshold {
+                    count = count.max(i + 1);
+                }
+            }
+        }
+        Ok(count.min(sealed.len()))
+    }
+
+    /// Remove the oldest segment, none of whose records are current.
+    fn drop_oldest_segment(&mut self, segment_num: u16) -> DBResult<()> {
+        let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+        let uuid = read_metadata_header(&mut READ_MODE.open(&metadata_path)?)?.uuid;
+        fs::remove_file(&metadata_path)?;
+        self.config
+            .write_durability
+            .persist_dir(&self.data_dir_path)?;
+        // The data file may be shared with the next segment
+        remove_data_file_if_unreferenced(&self.data_dir_path, &uuid)?;
+
+        self.indexed_segment_uuids.remove(&segment_num);
+        self.sealed_stats.remove(&segment_num);
+        self.maintenance_compacted.remove(&segment_num);
+        self.mapped_segments.borrow_mut().remove(&segment_num);
+        self.update_manifest(None)
+    }
+
+    /// Upload the data files of the sealed segments that have not changed for the time configured
+    /// with `ConfigBuilder::archive` to the archive store and remove their local copies. Archived data
+    /// files that no segment refers to anymore are removed from the store.
+    fn archive_cold_segments(&mut self) -> DBResult<()> {
+        let Some((store, older_than)) = self.config.archive.clone() else {
+            return Ok(());
+        };
+        let threshold = SystemTime::now()
+            .checked_sub(older_than)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let active_num = self.active_segment_num()?;
+
+        let mut data_file_segments: BTreeMap<Uuid, Vec<u16>> = BTreeMap::new();
+        for segment_num in list_segment_numbers(&self.data_dir_path)? {
+            let (header, _, _) = self.segment_file_state(segment_num)?;
+            data_file_segments
+                .entry(header.uuid)
+                .or_default()
+                .push(segment_num);
+        }
+
+        let mut archived = ArchivedFiles::read(&self.data_dir_path)?;
+        let mut cold_data_paths = vec![];
+        for (uuid, segment_nums) in &data_file_segments {
+            // Data files shared by consecutive segments are left in place, like the active one
+            let [segment_num] = segment_nums[..] else {
+                continue;
+            };
+            if segment_num == active_num {
+                continue;
+            }
+
+            // A fetched data file is removed again once it has not been fetched for as long
+            let data_path = self.data_dir_path.join(uuid.to_string());
+            let data_modified = match fs::metadata(&data_path) {
+                Ok(metadata) => metadata.modified()?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+            if data_modified >= threshold || fs::metadata(metadata_path)?.modified()? >= threshold {
+                continue;
+            }
+
+            // Keys are also written for data files archived before they were introduced
+            if !ArchivedKeys::exists(&self.data_dir_path, uuid)? {
+                self.write_archived_keys(segment_num, uuid)?;
+            }
+            if !archived.files.contains_key(uuid) {
+                debug!("Archiving data file {} of segment {}", uuid, segment_num);
+                let segment = ManifestSegment::compute(&self.data_dir_path, segment_num)?;
+                store.upload(&uuid.to_string(), &data_path)?;
+                archived.files.insert(
+                    *uuid,
+                    ArchivedFile {
+                        data_len: segment.data_len,
+                        data_checksum: segment.data_checksum,
+                    },
+                );
+            }
+            cold_data_paths.push(data_path);
+        }
+
+        let unreferenced: Vec<Uuid> = archived
+            .files
+            .keys()
+            .filter(|uuid| !data_file_segments.contains_key(uuid))
+            .copied()
+            .collect();
+        for uuid in &unreferenced {
+            archived.files.remove(uuid);
+        }
+        if cold_data_paths.is_empty() && unreferenced.is_empty() {
+            return Ok(());
+        }
+
+        // The local copies are removed only once the list of archived data files is on disk, and
+        // the objects of unreferenced data files only once they are no longer listed
+        archived.write(&self.data_dir_path)?;
+        for data_path in &cold_data_paths {
+            fs::remove_file(data_path)?;
+        }
+        self.config
+            .write_durability
+            .persist_dir(&self.data_dir_path)?;
+        for uuid in unreferenced {
+            debug!("Removing unreferenced archived data file {}", uuid);
+            ArchivedKeys::remove(&self.data_dir_path, &uuid)?;
+            store.remove(&uuid.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// The fields that the memtables are built from, or `None` if computed indexes need whole records.
+    fn indexed_fields(&self) -> Option<BTreeSet<usize>> {
+        if !self.config.computed_indexes.is_empty() {
+            return None;
+        }
+        let mut fields = BTreeSet::from([self.primary_key_index]);
+        fields.extend(&self.secondary_key_indexes);
+        fields.extend(self.composite_key_indexes.iter().flatten());
+        fields.extend(self.expiry_index);
+        Some(fields)
+    }
+
+    /// Write the keys of the segment `segment_num`, whose data file is about to be archived, so that
+    /// it can be indexed without fetching the data file, see `ArchivedKeys`.
+    fn write_archived_keys(&self, segment_num: u16, uuid: &Uuid) -> DBResult<()> {
+        let Some(fields) = self.indexed_fields() else {
+            return Ok(());
+        };
+        let (_, metadata_len, metadata_file) = self.segment_file_state(segment_num)?;
+        let data_file = self.open_data_file(uuid)?;
+        let records = ForwardLogReader::new(metadata_file, data_file)
+            .with_log_encoding(&self.log_encoding)
+            .with_encryption(&self.config.encryption)
+            .try_records()
+            .map(|item| item.map(|item| (item.index, item.record)))
+            .collect::<DBResult<Vec<(u64, Record)>>>()?;
+        ArchivedKeys::new(metadata_len, fields, records.into_iter())
+            .write(&self.data_dir_path, uuid)
+    }
+
+    /// The keys of the segment with the data file `uuid` and a metadata file of `metadata_len`
+    /// bytes, if the data file has been archived and has no local copy, and its keys were written
+    /// for the indexes of this handle.
+    fn archived_keys(&self, uuid: &Uuid, metadata_len: u64) -> DBResult<Option<ArchivedKeys>> {
+        let Some(fields) = self.indexed_fields() else {
+            return Ok(None);
+        };
+        if self.archive_store().is_none()
+            || !ArchivedFiles::is_archived_only(&self.data_dir_path, uuid)?
+        {
+            return Ok(None);
+        }
+        Ok(ArchivedKeys::read(&self.data_dir_path, uuid)?
+            .filter(|keys| keys.metadata_len == metadata_len && keys.fields.is_superset(&fields)))
+    }
+
+    /// Open a data file for reading, fetching it from the archive store if it has been archived.
+    fn open_data_file(&self, uuid: &Uuid) -> DBResult<fs::File> {
+        open_data_file(&self.data_dir_path, self.archive_store(), uuid)
+    }
+
+    fn archive_store(&self) -> Option<&dyn ArchiveStore> {
+        self.config
+            .archive
+            .as_ref()
+            .map(|(store, _)| store.as_ref())
+    }
+
+    /// Statistics of a segment for the compaction strategy. Tombstones are counted only if the
+    /// strategy uses them, and the statistics of sealed segments are cached until they change.
+    fn segment_stats(&mut self, segment_num: u16) -> DBResult<SegmentStats> {
+        let (header, metadata_bytes, metadata_file) = self.segment_file_state(segment_num)?;
+        let uuid = header.uuid;
+        let compacted =
+            self.maintenance_compacted.get(&segment_num) == Some(&(uuid, metadata_bytes));
+        if let Some((cached_uuid, cached_bytes, stats)) = self.sealed_stats.get(&segment_num) {
+            if (*cached_uuid, *cached_bytes) == (uuid, metadata_bytes) {
+                return Ok(SegmentStats {
+                    compacted,
+                    ..stats.clone()
+                });
+            }
+        }
 
-            let data_path = &self.data_dir_path.join(metadata_header.uuid.to_string());
-            let mut data_file = READ_MODE.open(&data_path)?;
+        let counts_tombstones = self.config.compaction_strategy.counts_tombstones();
+        let data_file = self.open_data_file(&uuid)?;
+        let mut records = 0;
+        let mut tombstones = 0;
+        let mut first_written = None;
+        for item in ForwardLogReader::new(metadata_file, data_file)
+            .with_log_encoding(&self.log_encoding)
+            .with_encryption(&self.config.encryption)
+            .try_records()
+        {
+            let record = item?.record;
+            records += 1;
+            first_written.get_or_insert(record.timestamp);
+            tombstones += record.tombstone as u64;
+            if !counts_tombstones {
+                break;
+            }
+        }
+        if !counts_tombstones {
+            records = (metadata_bytes - METADATA_FILE_HEADER_SIZE as u64)
+                / metadata_row_length(header.version) as u64;
+        }
 
-            let header_size = METADATA_FILE_HEADER_SIZE as i64;
-            let row_length = METADATA_ROW_LENGTH as i64;
-            let mut current_metadata_offset = header_size;
-            for (tag, segment_index) in segment_indexes {
-                let new_metadata_offset = header_size + segment_index as i64 * row_length;
-                metadata_file.seek_relative(new_metadata_offset - current_metadata_offset)?;
+        let stats = SegmentStats {
+            segment_num,
+            records,
+            tombstones: counts_tombstones.then_some(tombstones),
+            metadata_bytes,
+            first_written,
+            compacted,
+        };
+        if segment_num != self.active_segment_num()? {
+            self.sealed_stats
+                .insert(segment_num, (uuid, metadata_bytes, stats.clone()));
+        }
+        Ok(stats)
+    }
 
-                let mut metadata_buf = [0; METADATA_ROW_LENGTH];
-                metadata_file.read_exact(&mut metadata_buf)?;
+    /// The metadata header and metadata length of a segment, whose data file UUID and length
+    /// change whenever the segment does, and its metadata file.
+    fn segment_file_state(&self, segment_num: u16) -> DBResult<(MetadataHeader, u64, fs::File)> {
+        let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+        let mut metadata_file = READ_MODE.open(&metadata_path)?;
+        let metadata_bytes = metadata_file.seek(SeekFrom::End(0))?;
+        let header = read_metadata_header(&mut metadata_file)?;
+        validate_metadata_header(&header)?;
+        Ok((header, metadata_bytes, metadata_file))
+    }
 
-                let data_offset = u64::from_be_bytes(metadata_buf[0..8].try_into().unwrap());
-                let data_length = u64::from_be_bytes(metadata_buf[8..16].try_into().unwrap());
-                assert!(data_length > 0);
+    /// Scrub the sealed segment after the one scrubbed last, wrapping around to the first one, at
+    /// the rate configured with `ConfigBuilder::scrub_rate`. Returns `None` if there are no sealed
+    /// segments.
+    ///
+    /// The segment is read without holding the lock, so that writes are not blocked for the
+    /// duration of the scrub. Compaction may replace the segment files meanwhile, so a segment with
+    /// problems is scrubbed again while holding the shared lock, and only the problems found then
+    /// are reported.
+    pub fn scrub_next_segment(&mut self) -> DBResult<Option<ScrubReport>> {
+        let active_num = self.active_segment_num()?;
+        let sealed_nums: Vec<u16> = list_segment_numbers(&self.data_dir_path)?
+            .into_iter()
+            .filter(|&segment_num| segment_num != active_num)
+            .collect();
+        let next_num = sealed_nums
+            .iter()
+            .find(|&&segment_num| self.scrub_cursor.is_none_or(|last| segment_num > last))
+            .or(sealed_nums.first());
+        let Some(&segment_num) = next_num else {
+            return Ok(None);
+        };
 
-                data_file.seek(SeekFrom::Start(data_offset))?;
+        let mut limiter = RateLimiter::new(Some(self.config.scrub_rate));
+        let mut report = self.scrub_segment(segment_num, &mut limiter)?;
+        if !report.problems.is_empty() {
+            debug!("Scrubbing segment {} again under lock", segment_num);
+            report = self.with_shared_lock(|engine| {
+                engine.scrub_segment(segment_num, &mut RateLimiter::new(None))
+            })?;
+        }
 
-                let mut data_buf = vec![0; data_length as usize];
-                data_file.read_exact(&mut data_buf)?;
+        for problem in &report.problems {
+            warn!("Scrubber found a problem: {}", problem.description);
+            if let Some(scrub_hook) = self.config.scrub_hook {
+                scrub_hook(problem);
+            }
+        }
 
-                let record = Record::deserialize(&data_buf);
-                records.push((tag, record));
+        self.scrub_cursor = Some(segment_num);
+        Ok(Some(report))
+    }
 
-                current_metadata_offset = new_metadata_offset + row_length;
-            }
+    /// Verify the metadata rows and records of a segment, including the active one, see
+    /// `DB::verify_segment`.
+    pub fn verify_segment(&self, segment_num: u16) -> DBResult<ScrubReport> {
+        if !list_segment_numbers(&self.data_dir_path)?.contains(&segment_num) {
+            return Err(DBError::ValidationError(format!(
+                "Segment {} does not exist",
+                segment_num
+            )));
         }
+        self.scrub_segment(segment_num, &mut RateLimiter::new(None))
+    }
 
-        Ok(records)
+    pub fn verify_all_segments(&self) -> DBResult<Vec<ScrubReport>> {
+        list_segment_numbers(&self.data_dir_path)?
+            .into_iter()
+            .map(|segment_num| self.verify_segment(segment_num))
+            .collect()
     }
 
-    pub fn range_by_records<B: RangeBounds<Value>>(
+    /// Scrub a segment against its current manifest entry. A segment that cannot be read is
+    /// reported as a problem.
+    fn scrub_segment(&self, segment_num: u16, limiter: &mut RateLimiter) -> DBResult<ScrubReport> {
+        let manifest = Manifest::read(&self.data_dir_path)?;
+        let expected = manifest.as_ref().and_then(|manifest| {
+            manifest
+                .segments
+                .iter()
+                .find(|segment| segment.segment_num == segment_num)
+        });
+
+        let result = scrub_segment(
+            &self.data_dir_path,
+            segment_num,
+            expected,
+            &self.config.fields,
+            self.config.encryption.as_deref(),
+            limiter,
+            || self.check_cancelled(),
+        );
+        match result {
+            Err(DBError::Cancelled) => Err(DBError::Cancelled),
+            Err(e) => Ok(ScrubReport {
+                segment_num,
+                records_checked: 0,
+                bytes_read: 0,
+                problems: vec![ScrubProblem {
+                    segment_num,
+                    row: None,
+                    description: format!("Segment {} could not be read: {}", segment_num, e),
+                }],
+            }),
+            report => report,
+        }
+    }
+
+    pub fn compact_segments(
         &mut self,
-        field: &R::Field,
-        range: B,
-    ) -> DBResult<Vec<Record>> {
-        fn range_bound_to_indexable(
-            bound: Bound<&Value>,
-            field_type: &Type,
-        ) -> DBResult<Bound<IndexableValue>> {
-            fn convert(value: &Value, field_type: &Type) -> DBResult<IndexableValue> {
-                if !type_check(&value, field_type) {
+        selector: SegmentSelector,
+    ) -> DBResult<Vec<CompactionReport>> {
+        // Deciding which records are superseded requires up to date indexes
+        self.refresh_indexes()?;
+
+        let active_target = fs::read_link(self.data_dir_path.join(ACTIVE_SYMLINK_FILENAME))?;
+        let active_num = parse_segment_number(&active_target)?;
+        let segment_nums = list_segment_numbers(&self.data_dir_path)?;
+
+        let selected = match selector {
+            SegmentSelector::Active => vec![active_num],
+            SegmentSelector::All => segment_nums.clone(),
+            SegmentSelector::Ids(ids) => {
+                if let Some(id) = ids.iter().find(|id| !segment_nums.contains(id)) {
                     return Err(DBError::ValidationError(format!(
-                        "Queried value does not match type: {:?}",
-                        field_type
+                        "Segment {} does not exist",
+                        id
                     )));
                 }
-                value.as_indexable().ok_or(DBError::ValidationError(
-                    "Queried value must be indexable".to_owned(),
-                ))
+                ids
             }
+            SegmentSelector::OlderThan(age) => {
+                let threshold = SystemTime::now()
+                    .checked_sub(age)
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+
+                let mut selected = vec![];
+                for &segment_num in &segment_nums {
+                    let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+                    if segment_num != active_num
+                        && fs::metadata(&metadata_path)?.modified()? < threshold
+                    {
+                        selected.push(segment_num);
+                    }
+                }
+                selected
+            }
+        };
+
+        let first_segment_num = segment_nums.first().copied().unwrap_or(active_num);
 
-            match bound {
-                Bound::Included(value) => convert(value, field_type).map(Bound::Included),
-                Bound::Excluded(value) => convert(value, field_type).map(Bound::Excluded),
-                Bound::Unbounded => Ok(Bound::Unbounded),
+        let mut reports = vec![];
+        for &segment_num in &segment_nums {
+            if segment_num != active_num && selected.contains(&segment_num) {
+                reports.push(self.compact_sealed_segments(&[segment_num], first_segment_num)?);
             }
         }
 
-        let field_type = self.get_field_type(field).ok_or(DBError::ValidationError(
-            "Field not found in schema".to_owned(),
-        ))?;
+        // The active segment is handled last, since rotating it changes the active segment number
+        if selected.contains(&active_num) {
+            ensure_active_metadata_is_valid(&self.data_dir_path, &mut self.active_metadata_file)?;
 
-        let start_indexable = range_bound_to_indexable(range.start_bound(), field_type)?;
-        let end_indexable = range_bound_to_indexable(range.end_bound(), field_type)?;
+            let metadata_size = self.active_metadata_file.seek(SeekFrom::End(0))?;
+            if metadata_size > METADATA_FILE_HEADER_SIZE as u64 {
+                reports.push(self.rotate_and_compact()?);
+            }
+        }
 
-        let indexable_bounds = OwnedBounds::new(start_indexable, end_indexable);
+        Ok(reports)
+    }
 
-        if self.config.read_consistency == ReadConsistency::Strong {
-            self.refresh_indexes()?;
+    /// Compact segments that are no longer written to into the last of them. Besides deduplicating
+    /// the segments, records superseded by newer segments are dropped, and tombstones are dropped
+    /// if there are no older segments whose records they could be shadowing. Expects the segments
+    /// to be consecutive and the memtables to be up to date.
+    fn compact_sealed_segments(
+        &mut self,
+        segment_nums: &[u16],
+        first_segment_num: u16,
+    ) -> DBResult<CompactionReport> {
+        debug!("Compacting sealed segments {:?}", segment_nums);
+
+        let mut old_data_uuids = vec![];
+        for segment_num in segment_nums {
+            let metadata_path = self.data_dir_path.join(metadata_filename(*segment_num));
+            old_data_uuids.push(read_metadata_header(&mut READ_MODE.open(&metadata_path)?)?.uuid);
         }
 
-        let log_keys = if field == &self.config.primary_key {
-            self.primary_memtable.range(indexable_bounds)
-        } else {
-            let index = get_secondary_memtable_index_by_field(&self.config.secondary_keys, field)
-                .ok_or_else(|| {
-                DBError::ValidationError("Cannot range_by by non-indexed key".to_owned())
+        // A record must be kept if it is the newest version of its key or a merge delta on top of it,
+        // or if it is a tombstone that may be shadowing a live version in an older segment.
+        let primary_memtable = &self.primary_memtable;
+        let deleted_memtable = &self.deleted_memtable;
+        let merge_deltas = &self.merge_deltas;
+        let compacted = |log_key: &LogKey| segment_nums.contains(&log_key.segment_num());
+        let (new_data_uuid, index_remap, report, expired) =
+            self.rewrite_segments(segment_nums, |pk, record| match primary_memtable.get(pk) {
+                Some(log_key) => {
+                    compacted(log_key)
+                        || merge_deltas
+                            .get(pk)
+                            .is_some_and(|deltas| deltas.iter().any(compacted))
+                }
+                None => match deleted_memtable.get(pk) {
+                    Some(log_key) => compacted(log_key),
+                    None => record.tombstone && segment_nums[0] != first_segment_num,
+                },
             })?;
+        self.apply_index_remap(segment_nums, new_data_uuid, &index_remap);
+        for record in &expired {
+            self.remove_record_from_memtables(record)?;
+        }
 
-            self.secondary_memtables[index].range(indexable_bounds)
-        };
-
-        let log_key_batches = log_keys.into_iter().map(|log_key| (0, log_key));
+        // The merged segments are removed oldest first, so that a crash in between cannot leave a
+        // record in place without the tombstones and newer versions that were shadowing it
+        let (target_num, merged_nums) = segment_nums.split_last().expect("No segments to compact");
+        for segment_num in merged_nums {
+            fs::remove_file(self.data_dir_path.join(metadata_filename(*segment_num)))?;
+            self.config
+                .write_durability
+                .persist_dir(&self.data_dir_path)?;
+        }
+        for old_data_uuid in &old_data_uuids {
+            remove_data_file_if_unreferenced(&self.data_dir_path, old_data_uuid)?;
+        }
+        self.update_manifest(Some(*target_num))?;
 
-        let tagged_records = self.read_tagged_log_keys(log_key_batches);
+        debug!(
+            "Sealed segments {:?} compacted into segment {}, reduced data size: {} -> {}",
+            segment_nums, target_num, report.bytes_before, report.bytes_after
+        );
 
-        Ok(tagged_records?.into_iter().map(|(_, rec)| rec).collect())
+        Ok(report)
     }
 
-    /// Ensures that the `self.metadata_file` and `self.data_file` handles are still pointing to the correct files.
-    /// If the segment has been rotated, the handle will be closed and reopened.
-    /// Returns `false` if the file has been rotated and the handle has been reopened, `true` otherwise.
-    fn ensure_metadata_file_is_active(&mut self) -> DBResult<bool> {
-        let active_target = fs::read_link(&self.data_dir_path.join(ACTIVE_SYMLINK_FILENAME))?;
-        let active_metadata_path = &self.data_dir_path.join(active_target);
-
-        let correct = is_file_same_as_path(&self.active_metadata_file, &active_metadata_path)?;
-        if !correct {
-            debug!("Metadata file has been rotated. Reopening...");
-            let metadata_file = APPEND_MODE.open(&active_metadata_path)?;
+    /// Merge consecutive sealed segments into the last of them, see `DB::merge_segments`.
+    pub fn merge_segments(&mut self, segment_nums: &[u16]) -> DBResult<CompactionReport> {
+        // Deciding which records are superseded requires up to date indexes
+        self.refresh_indexes()?;
+
+        let active_num = self.active_segment_num()?;
+        let all_nums = list_segment_numbers(&self.data_dir_path)?;
+        let mut segment_nums = segment_nums.to_vec();
+        segment_nums.sort_unstable();
+        segment_nums.dedup();
+
+        let Some(first_position) = segment_nums
+            .first()
+            .and_then(|first| all_nums.iter().position(|num| num == first))
+        else {
+            return Err(DBError::ValidationError(format!(
+                "Segments {:?} do not exist",
+                segment_nums
+            )));
+        };
+        let consecutive = all_nums.get(first_position..first_position + segment_nums.len());
+        if consecutive != Some(&segment_nums[..]) {
+            return Err(DBError::ValidationError(format!(
+                "Segments {:?} are not consecutive existing segments",
+                segment_nums
+            )));
+        }
+        if segment_nums.contains(&active_num) {
+            return Err(DBError::ValidationError(
+                "The active segment cannot be merged".to_owned(),
+            ));
+        }
 
-            let metadata_header = read_metadata_header(&mut self.active_metadata_file)?;
+        self.compact_sealed_segments(&segment_nums, all_nums[0])
+    }
 
-            validate_metadata_header(&metadata_header)?;
+    fn rotate_and_compact(&mut self) -> DBResult<CompactionReport> {
+        debug!("Starting rotation and compaction...");
 
-            let data_file_path = &self.data_dir_path.join(metadata_header.uuid.to_string());
+        // The memtables are remapped to the compacted layout, so they must cover the whole segment
+        self.refresh_indexes()?;
 
-            self.active_metadata_file = metadata_file;
-            self.active_data_file = APPEND_MODE.open(&data_file_path)?;
+        let active_target = fs::read_link(&self.data_dir_path.join(ACTIVE_SYMLINK_FILENAME))?;
+        let active_num = parse_segment_number(&active_target)?;
+        let old_data_uuid = read_metadata_header(&mut self.active_metadata_file)?.uuid;
 
-            return Ok(false);
-        } else {
-            return Ok(true);
+        let (new_data_uuid, index_remap, report, expired) =
+            self.rewrite_segments(&[active_num], |_, _| true)?;
+        self.apply_index_remap(&[active_num], new_data_uuid, &index_remap);
+        for record in &expired {
+            self.remove_record_from_memtables(record)?;
         }
-    }
-
-    pub fn delete_by_field(&mut self, field: &R::Field, value: &Value) -> DBResult<Vec<Record>> {
-        let recs: Vec<Record> = self
-            .batch_find_by_records(field, std::iter::once(value))?
-            .into_iter()
-            .map(|(_, mut rec)| {
-                rec.tombstone = true;
-                rec
-            })
-            .collect();
 
-        for record in &recs {
-            let record_serialized = record.serialize();
-
-            let offset = self.active_data_file.seek(SeekFrom::End(0))?;
-            let length = record_serialized.len() as u64;
+        debug!("Compaction complete, creating new segment");
 
-            self.active_data_file.write_all(&record_serialized)?;
+        let new_data_path = &self.data_dir_path.join(new_data_uuid.to_string());
+        let new_segment_num = active_num + 1;
+        let new_metadata_path = self.data_dir_path.join(metadata_filename(new_segment_num));
+        let mut new_metadata_file = APPEND_MODE.clone().create(true).open(&new_metadata_path)?;
 
-            // Flush and sync data to disk
-            if self.config.write_durability == WriteDurability::Flush {
-                self.active_data_file.flush()?;
-            }
-            if self.config.write_durability == WriteDurability::FlushSync {
-                self.active_data_file.flush()?;
-                self.active_data_file.sync_all()?;
-            }
+        let new_metadata_header = self
+            .config
+            .segment_header(new_data_uuid, self.config.append_compression());
 
-            let mut metadata_entry = vec![];
-            metadata_entry.extend(offset.to_be_bytes().into_iter());
-            metadata_entry.extend(length.to_be_bytes().into_iter());
+        new_metadata_file.write_all(&new_metadata_header.serialize())?;
+        let write_durability = &self.config.write_durability;
+        write_durability.persist(&mut new_metadata_file)?;
+        // Running out of space fails the rotation, leaving the compacted segment active
+        let preallocated = self
+            .config
+            .preallocate_segment(&new_metadata_file, &APPEND_MODE.open(new_data_path)?);
+        if let Err(e) = preallocated {
+            fs::remove_file(&new_metadata_path)?;
+            return Err(e.into());
+        }
 
-            self.active_metadata_file.write_all(&metadata_entry)?;
+        set_active_segment(&self.data_dir_path, new_segment_num)?;
+        write_durability.persist_dir(&self.data_dir_path)?;
 
-            // Flush and sync metadata to disk
-            if self.config.write_durability == WriteDurability::Flush {
-                self.active_metadata_file.flush()?;
-            }
-            if self.config.write_durability == WriteDurability::FlushSync {
-                self.active_metadata_file.flush()?;
-                self.active_metadata_file.sync_all()?;
-            }
+        self.active_metadata_file = APPEND_MODE.open(&new_metadata_path)?;
+        self.active_data_file = APPEND_MODE.open(new_data_path)?;
 
-            self.remove_record_from_memtables(&record);
-        }
+        // The previous data file may still be shared with the preceding segment
+        remove_data_file_if_unreferenced(&self.data_dir_path, &old_data_uuid)?;
+        self.update_manifest(Some(active_num))?;
 
-        debug!("Records deleted");
+        debug!(
+            "Active log file {} rotated and compacted, new segment: {}",
+            active_num, new_segment_num
+        );
 
-        Ok(recs)
+        Ok(report)
     }
 
-    pub fn do_maintenance_tasks(&mut self) -> DBResult<()> {
-        ensure_active_metadata_is_valid(&self.data_dir_path, &mut self.active_metadata_file)?;
+    /// Record the current state of a sealed segment, if given, as well as the active segment number,
+    /// the next version and the removal of segments, in the manifest.
+    fn update_manifest(&self, sealed_segment_num: Option<u16>) -> DBResult<()> {
+        let mut manifest = match Manifest::read(&self.data_dir_path) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => Manifest::compute(&self.data_dir_path)?,
+            Err(e) => {
+                warn!("Replacing unreadable manifest: {}", e);
+                Manifest::compute(&self.data_dir_path)?
+            }
+        };
 
-        let metadata_size = self.active_metadata_file.seek(SeekFrom::End(0))?;
-        if metadata_size >= self.config.segment_size as u64 {
-            self.rotate_and_compact()?;
+        manifest.active_segment_num = greatest_segment_number(&self.data_dir_path)?;
+        manifest.next_version = manifest.next_version.max(self.next_version);
+        // Segments merged into others or dropped no longer exist
+        let segment_nums = list_segment_numbers(&self.data_dir_path)?;
+        manifest
+            .segments
+            .retain(|segment| segment_nums.contains(&segment.segment_num));
+        if let Some(sealed_segment_num) = sealed_segment_num {
+            manifest.set_segment(ManifestSegment::compute(
+                &self.data_dir_path,
+                sealed_segment_num,
+            )?);
         }
-
-        Ok(())
+        manifest.write(&self.data_dir_path)
     }
 
-    fn rotate_and_compact(&mut self) -> DBResult<()> {
-        debug!("Active log size exceeds threshold, starting rotation and compaction...");
+    /// Rewrite consecutive segments into a new data file that contains only the latest record of
+    /// each primary key, further filtered by `keep`. The metadata file of the last segment is
+    /// replaced with one that has a row for each kept record, in primary key order. The metadata
+    /// files of the other segments are left for the caller to remove.
+    ///
+    /// Expired records are replaced by tombstones, so that they keep shadowing older versions of
+    /// their keys in earlier segments.
+    ///
+    /// Returns the UUID of the new data file, a map from the old log key of each kept record to
+    /// its new row index, a report of the compaction, and the kept records that expired, which the
+    /// caller must remove from the memtables.
+    fn rewrite_segments(
+        &self,
+        segment_nums: &[u16],
+        keep: impl Fn(&IndexableValue, &Record) -> bool,
+    ) -> DBResult<RewrittenSegment> {
+        let target_num = *segment_nums.last().expect("No segments to rewrite");
+        let metadata_path = self.data_dir_path.join(metadata_filename(target_num));
 
-        let original_data_len = self.active_data_file.seek(SeekFrom::End(0))?;
+        debug!("Reading segment data into a BTreeMap");
+        // The segments are read and decoded in ranges of rows on parallel threads, and collected
+        // in log order
+        let mut row_ranges = vec![];
+        for &segment_num in segment_nums {
+            let (header, metadata_len, _) = self.segment_file_state(segment_num)?;
+            // An archived data file is fetched first, so that the threads find it
+            self.open_data_file(&header.uuid)?;
+            let rows = (metadata_len - METADATA_FILE_HEADER_SIZE as u64)
+                / metadata_row_length(header.version) as u64;
+            for start in (0..rows).step_by(COMPACTION_READ_ROWS as usize) {
+                let end = (start + COMPACTION_READ_ROWS).min(rows);
+                row_ranges.push((segment_num, header.uuid, start, end));
+            }
+        }
 
-        let active_target = fs::read_link(&self.data_dir_path.join(ACTIVE_SYMLINK_FILENAME))?;
-        let active_num = parse_segment_number(&active_target)?;
+        let data_dir_path = &self.data_dir_path;
+        let (log_encoding, encryption) = (&self.log_encoding, &self.config.encryption);
+        let primary_key_index = self.primary_key_index;
+        let read_range = |&(segment_num, uuid, start, end): &(u16, Uuid, u64, u64)| {
+            let metadata_file =
+                READ_MODE.open(data_dir_path.join(metadata_filename(segment_num)))?;
+            let data_file = READ_MODE.open(data_dir_path.join(uuid.to_string()))?;
+            let items = ForwardLogReader::new_with_index(metadata_file, data_file, start)
+                .until_index(end)
+                .with_log_encoding(log_encoding)
+                .with_encryption(encryption)
+                .try_records()
+                .map(|item| {
+                    let item = item?;
+                    let pk = key_at(&item.record, primary_key_index)?;
+                    let log_key = LogKey::new(segment_num, item.index);
+                    Ok((log_key, pk, item))
+                })
+                .collect::<DBResult<Vec<_>>>()?;
+            Ok((uuid, items))
+        };
 
-        debug!("Reading segment data into a BTreeMap");
-        let mut pk_to_item_map: BTreeMap<&IndexableValue, &Record> = BTreeMap::new();
-        let forward_read_items: Vec<(IndexableValue, Record)> = ForwardLogReader::new(
-            self.active_metadata_file.try_clone()?,
-            self.active_data_file.try_clone()?,
-        )
-        .map(|item| {
-            (
-                item.record
-                    .at(self.primary_key_index)
-                    .as_indexable()
-                    .expect("Primary key was not indexable"),
-                item.record,
-            )
-        })
-        .collect();
+        let mut distinct_entries = HashSet::new();
+        let mut forward_read_items: Vec<(LogKey, IndexableValue, Record)> = vec![];
+        parallel::map_ordered(
+            &row_ranges,
+            self.config.compaction_threads,
+            1,
+            read_range,
+            |(uuid, items)| {
+                for (log_key, pk, item) in items {
+                    distinct_entries.insert((uuid, item.offset, item.length));
+                    forward_read_items.push((log_key, pk, item.record));
+                }
+                Ok(())
+            },
+        )?;
+
+        // Each key keeps its newest record, into which the merge deltas written after it are folded.
+        // Deltas whose previous version is in an older segment cannot be folded and are kept in order.
+        let mut pk_to_rows: BTreeMap<IndexableValue, Vec<(LogKey, Record)>> = BTreeMap::new();
+        for (log_key, pk, record) in forward_read_items {
+            let chain_head = self.primary_memtable.get(&pk) == Some(&log_key);
+            let rows = pk_to_rows.entry(pk).or_default();
+            if !record.delta {
+                *rows = vec![(log_key, record)];
+                continue;
+            }
 
-        for (pk, record) in forward_read_items.iter() {
-            pk_to_item_map.insert(pk, record);
+            match rows.pop() {
+                // The memtables point to the full record, so the folded record takes its row
+                Some((head_index, last)) if !last.delta && !last.tombstone && !last.deleted => {
+                    rows.push((head_index, self.merge(Some(last), record)?));
+                }
+                Some((_, last)) if last.tombstone || last.deleted => {
+                    rows.push((log_key, self.merge(None, record)?));
+                }
+                Some(last) => {
+                    rows.push(last);
+                    rows.push((log_key, record));
+                }
+                None if chain_head => rows.push((log_key, self.merge(None, record)?)),
+                None => rows.push((log_key, record)),
+            }
+        }
+        // A record with deltas in later segments is only known to have expired once they are folded
+        let now = unix_millis(SystemTime::now());
+        let mut expired_pks = HashSet::new();
+        for (pk, rows) in pk_to_rows.iter_mut() {
+            if let [(_, record)] = rows.as_mut_slice() {
+                if self.is_expired(record, now) && !self.merge_deltas.contains_key(pk) {
+                    record.tombstone = true;
+                    expired_pks.insert(pk.clone());
+                }
+            }
         }
 
+        pk_to_rows.retain(|pk, rows| match rows.last() {
+            Some((_, record)) => keep(pk, record),
+            None => false,
+        });
+        let expired = expired_pks
+            .iter()
+            .filter_map(|pk| pk_to_rows.get(pk))
+            .map(|rows| rows[0].1.clone())
+            .collect();
+
         debug!(
-            "Read {} records, out of which {} were unique",
-            forward_read_items.len(),
-            pk_to_item_map.len()
+            "Read {} records, out of which {} are kept",
+            distinct_entries.len(),
+            pk_to_rows.len()
         );
 
         // Create a new log data file and write it
@@ -623,16 +4284,35 @@ impl<R: Recordable> Engine<R> {
         let (new_data_uuid, new_data_path) = create_segment_data_file(&self.data_dir_path)?;
         let mut new_data_file = APPEND_MODE.open(&new_data_path)?;
 
-        let mut pk_to_data_map = BTreeMap::new();
+        let mut data_rows = vec![];
         let mut offset = 0u64;
-        for (pk, record) in pk_to_item_map.into_iter() {
-            let serialized = record.serialize();
-            let len = serialized.len() as u64;
-            new_data_file.write_all(&serialized)?;
-
-            pk_to_data_map.insert(pk, (offset, len));
-            offset += len;
-        }
+        let new_header = self
+            .config
+            .segment_header(new_data_uuid, self.config.compression);
+        // The records are encoded on parallel threads and written in order on this one
+        let records: Vec<&Record> = pk_to_rows
+            .values()
+            .flatten()
+            .map(|(_, record)| record)
+            .collect();
+        let encryption = self.config.encryption.as_deref();
+        parallel::map_ordered(
+            &records,
+            self.config.compaction_threads,
+            COMPACTION_ENCODE_RECORDS,
+            |record| {
+                let serialized = log_encoding.serialize(record);
+                Ok(new_header
+                    .encode_record(&serialized, encryption)?
+                    .into_owned())
+            },
+            |stored| {
+                new_data_file.write_all(&stored)?;
+                data_rows.push(MetadataRow::new(offset, &stored));
+                offset += stored.len() as u64;
+                Ok(())
+            },
+        )?;
 
         // Sync the data file to disk.
         // This is fine to do without consulting WriteDurability because this is a one-off
@@ -641,68 +4321,167 @@ impl<R: Recordable> Engine<R> {
         new_data_file.sync_all()?;
 
         let final_data_len = new_data_file.seek(io::SeekFrom::End(0))?;
-        debug!(
-            "Wrote compacted data, reduced data size: {} -> {}",
-            original_data_len, final_data_len
-        );
 
         // Create a new log metadata file and write it
         debug!("Opening temp metadata file and writing pointers to compacted data file");
-        let temp_metadata_file = tempfile::NamedTempFile::new()?;
-        let temp_metadata_path = temp_metadata_file.as_ref();
-        let mut temp_metadata_file = WRITE_MODE.open(temp_metadata_path)?;
+        let mut temp_metadata_file = fs::NamedTempFile::new_in(&self.data_dir_path)?;
 
-        let metadata_header = MetadataHeader {
-            version: 1,
-            uuid: new_data_uuid,
-        };
-
-        temp_metadata_file.write_all(&metadata_header.serialize())?;
-
-        for (pk, _) in forward_read_items.iter() {
-            let (offset, len) = pk_to_data_map.get(&pk).unwrap();
-
-            let mut metadata_buf = vec![];
-            metadata_buf.extend(offset.to_be_bytes().into_iter());
-            metadata_buf.extend(len.to_be_bytes().into_iter());
+        temp_metadata_file.write_all(&new_header.serialize())?;
 
-            temp_metadata_file.write_all(&metadata_buf)?;
+        for row in &data_rows {
+            temp_metadata_file.write_all(&row.serialize())?;
         }
 
         // Sync the metadata file to disk, see comment above about sync.
         temp_metadata_file.flush()?;
-        temp_metadata_file.sync_all()?;
+        temp_metadata_file.as_file().sync_all()?;
 
-        debug!("Moving temporary files to their final locations");
-        let new_data_path = &self.data_dir_path.join(new_data_uuid.to_string());
-        let active_metadata_path = &self.data_dir_path.join(metadata_filename(active_num)); // overwrite active
+        debug!("Moving temporary metadata file to its final location");
+        fs::rename(temp_metadata_file.path(), &metadata_path)?;
+        self.config
+            .write_durability
+            .persist_dir(&self.data_dir_path)?;
+
+        let index_remap = pk_to_rows
+            .values()
+            .flatten()
+            .enumerate()
+            .map(|(new_index, (old_log_key, _))| (old_log_key.clone(), new_index as u64))
+            .collect();
 
-        fs::rename(&temp_metadata_path, &active_metadata_path)?;
+        let report = CompactionReport {
+            segment_num: target_num,
+            records_before: distinct_entries.len(),
+            records_after: data_rows.len(),
+            bytes_before: distinct_entries.iter().map(|(_, _, length)| length).sum(),
+            bytes_after: final_data_len,
+        };
 
-        debug!("Compaction complete, creating new segment");
+        Ok((new_data_uuid, index_remap, report, expired))
+    }
 
-        let new_segment_num = active_num + 1;
-        let new_metadata_path = self.data_dir_path.join(metadata_filename(new_segment_num));
-        let mut new_metadata_file = APPEND_MODE.clone().create(true).open(&new_metadata_path)?;
+    /// Rewrite every segment by mapping each of its records with `migrate`, and serialize the mapped
+    /// records with `encoding`, see `DB::migrate`. Unlike compaction, every row is kept, so the log
+    /// keeps its history and the row indexes stay the same.
+    ///
+    /// All segments are written to new data files and temporary metadata files first, and only once
+    /// every record has been mapped are the metadata files moved into place. If a record cannot be
+    /// mapped, the new files are removed and the log is left untouched.
+    pub fn migrate_segments(
+        &mut self,
+        migrate: impl Fn(Record) -> DBResult<Record>,
+        encoding: &LogEncoding,
+        schema: &StoredSchema,
+    ) -> DBResult<()> {
+        ensure_active_metadata_is_valid(&self.data_dir_path, &mut self.active_metadata_file)?;
 
-        let new_metadata_header = MetadataHeader {
-            version: 1,
-            uuid: new_data_uuid,
+        let segment_nums = list_segment_numbers(&self.data_dir_path)?;
+        let mut new_data_paths = vec![];
+        let written = segment_nums
+            .iter()
+            .map(|&segment_num| {
+                self.check_cancelled()?;
+                let (new_data_uuid, new_data_path) = create_segment_data_file(&self.data_dir_path)?;
+                new_data_paths.push(new_data_path.clone());
+                self.migrate_segment(
+                    segment_num,
+                    new_data_uuid,
+                    &new_data_path,
+                    &migrate,
+                    encoding,
+                )
+            })
+            .collect::<DBResult<Vec<_>>>();
+
+        let written = match written {
+            Ok(written) => written,
+            Err(e) => {
+                for path in &new_data_paths {
+                    fs::remove_file(path).ok();
+                }
+                return Err(e);
+            }
         };
 
-        new_metadata_file.write_all(&new_metadata_header.serialize())?;
+        debug!("Moving migrated metadata files to their final locations");
+        for (segment_num, old_data_uuid, temp_metadata_file) in written {
+            let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+            temp_metadata_file
+                .persist(&metadata_path)
+                .map_err(|e| e.error)?;
+            // Adjacent segments may share the old data file, so it goes with the last one
+            remove_data_file_if_unreferenced(&self.data_dir_path, &old_data_uuid)?;
+        }
+        self.config
+            .write_durability
+            .persist_dir(&self.data_dir_path)?;
 
-        set_active_segment(&self.data_dir_path, new_segment_num)?;
+        schema.write(&self.data_dir_path)?;
+        Manifest::compute(&self.data_dir_path)?.write(&self.data_dir_path)
+    }
 
-        self.active_metadata_file = APPEND_MODE.open(&new_metadata_path)?;
-        self.active_data_file = APPEND_MODE.open(&new_data_path)?;
+    /// Write the mapped records of a segment to a new data file and a temporary metadata file.
+    /// Returns the segment number, the UUID of its old data file and the temporary metadata file.
+    fn migrate_segment(
+        &self,
+        segment_num: u16,
+        new_data_uuid: Uuid,
+        new_data_path: &Path,
+        migrate: &impl Fn(Record) -> DBResult<Record>,
+        encoding: &LogEncoding,
+    ) -> DBResult<(u16, Uuid, fs::NamedTempFile)> {
+        debug!("Migrating segment {}", segment_num);
+
+        let metadata_path = self.data_dir_path.join(metadata_filename(segment_num));
+        let mut metadata_file = READ_MODE.open(&metadata_path)?;
+        let metadata_header = read_metadata_header(&mut metadata_file)?;
+        validate_metadata_header(&metadata_header)?;
+        let old_data_uuid = metadata_header.uuid;
+        let data_file = self.open_data_file(&old_data_uuid)?;
+
+        let mut new_data_file = APPEND_MODE.open(new_data_path)?;
+        let mut temp_metadata_file = fs::NamedTempFile::new_in(&self.data_dir_path)?;
+        let new_header = self
+            .config
+            .segment_header(new_data_uuid, self.config.compression);
+        temp_metadata_file.write_all(&new_header.serialize())?;
 
-        debug!(
-            "Active log file {} rotated and compacted, new segment: {}",
-            active_num, new_segment_num
-        );
+        let mut offset = 0u64;
+        for item in ForwardLogReader::new(metadata_file, data_file)
+            .with_log_encoding(&self.log_encoding)
+            .with_encryption(&self.config.encryption)
+            .try_records()
+        {
+            let record = migrate(item?.record)?;
+            let stored = new_header
+                .encode_record(
+                    &encoding.serialize(&record),
+                    self.config.encryption.as_deref(),
+                )?
+                .into_owned();
+            new_data_file.write_all(&stored)?;
+            temp_metadata_file.write_all(&MetadataRow::new(offset, &stored).serialize())?;
+            offset += stored.len() as u64;
+        }
 
-        Ok(())
+        // Like compaction, this is a one-off operation, so the files are synced regardless of
+        // WriteDurability.
+        new_data_file.flush()?;
+        new_data_file.sync_all()?;
+        temp_metadata_file.flush()?;
+        temp_metadata_file.as_file().sync_all()?;
+
+        Ok((segment_num, old_data_uuid, temp_metadata_file))
+    }
+
+    fn field_index(&self, field: &R::Field) -> DBResult<usize> {
+        self.config
+            .fields
+            .iter()
+            .position(|(f, _)| f == field)
+            .ok_or(DBError::ValidationError(
+                "Field not found in schema".to_owned(),
+            ))
     }
 
     #[inline]
@@ -714,14 +4493,116 @@ impl<R: Recordable> Engine<R> {
             .map(|(_, t)| t)
     }
 
+    /// Apply the configured write transforms to a record and check that the result is still valid.
+    fn apply_write_transforms(&self, record: &mut Record) -> DBResult<()> {
+        if self.config.write_transforms.is_empty() || record.tombstone || record.deleted {
+            return Ok(());
+        }
+
+        for (field, transform) in &self.config.write_transforms {
+            let field_index = self
+                .config
+                .fields
+                .iter()
+                .position(|(f, _)| f == field)
+                .ok_or(DBError::ValidationError(
+                    "Field not found in schema".to_owned(),
+                ))?;
+            let value = std::mem::replace(&mut record.values[field_index], Value::Null);
+            record.values[field_index] = transform.apply(value)?;
+        }
+
+        record.validate(&self.config.fields)
+    }
+
+    /// Run the configured checks on the values of a record that is about to be written.
+    fn run_checks(&self, record: &Record) -> DBResult<()> {
+        if record.tombstone || record.deleted || record.delta {
+            return Ok(());
+        }
+
+        for (field, check) in &self.config.checks {
+            let value = &record.values[self.field_index(field)?];
+            if *value != Value::Null && !check(value) {
+                return Err(DBError::ValidationError(format!(
+                    "Value {:?} of field {:?} failed its check",
+                    value, field
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_cancelled(&self) -> DBResult<()> {
+        match &self.cancellation {
+            Some(cancellation) => cancellation.check(),
+            None => Ok(()),
+        }
+    }
+
     #[inline]
     pub fn with_exclusive_lock<T>(
         &mut self,
         f: impl FnOnce(&mut Self) -> DBResult<T>,
     ) -> DBResult<T> {
-        self.lock_manager.lock_exclusive()?;
+        self.lock_exclusive()?;
         let result = f(self);
+        self.unlock_exclusive()?;
+        result
+    }
+
+    /// Acquire the exclusive lock, to be released with `unlock_exclusive`. Used by transactions,
+    /// which hold the locks of several databases at once.
+    pub fn lock_exclusive(&mut self) -> DBResult<()> {
+        self.lock_exclusive_unless_quiesced()
+    }
+
+    pub fn unlock_exclusive(&mut self) -> DBResult<()> {
         self.lock_manager.unlock()?;
+        // Records written with FlushSync are synced outside the lock, see `persist_active`
+        self.sync_pending()
+    }
+
+    pub fn data_dir_path(&self) -> &Path {
+        &self.data_dir_path
+    }
+
+    /// Acquire the exclusive lock, waiting for as long as another handle has quiesced the data
+    /// directory. The quiesce is checked while holding the lock, so once `quiesce` has acquired the
+    /// lock after raising its flag, no other handle can start writing.
+    fn lock_exclusive_unless_quiesced(&mut self) -> DBResult<()> {
+        loop {
+            self.lock_manager.lock_exclusive()?;
+            if self.quiesce_flag.is_some() {
+                return Ok(());
+            }
+            match is_quiesced(&self.data_dir_path) {
+                Ok(false) => return Ok(()),
+                Ok(true) => self.lock_manager.unlock()?,
+                Err(e) => {
+                    self.lock_manager.unlock()?;
+                    return Err(e);
+                }
+            }
+
+            debug!("Data directory is quiesced, waiting before writing");
+            self.check_cancelled()?;
+            thread::sleep(QUIESCE_POLL_INTERVAL);
+        }
+    }
+
+    /// Quiesce the data directory, see `DB::quiesce`.
+    pub fn quiesce(&mut self) -> DBResult<()> {
+        if self.quiesce_flag.is_some() {
+            return Ok(());
+        }
+        self.quiesce_flag = Some(QuiesceFlag::raise(&self.data_dir_path)?);
+
+        // Writes in progress hold the exclusive lock until they finish
+        let result = self.with_exclusive_lock(|_| Ok(()));
+        if result.is_err() {
+            self.quiesce_flag = None;
+        }
         result
     }
 
@@ -733,3 +4614,84 @@ impl<R: Recordable> Engine<R> {
         result
     }
 }
+
+/// The active segment and the positions in its files where appended records are written
+struct AppendPosition {
+    segment_num: u16,
+    data_end: u64,
+    first_metadata_index: u64,
+    /// The header of the active segment, whose compression and key the records must be written with
+    header: MetadataHeader,
+}
+
+/// A batch of records serialized for appending to the active segment `segment_num`, see
+/// `Engine::prepare_batch`
+pub struct PreparedBatch {
+    segment_num: u16,
+    data: Vec<u8>,
+    metadata: Vec<u8>,
+    insertions: Vec<(LogKey, RecordKeys, Record)>,
+    receipts: Vec<WriteReceipt>,
+}
+
+/// The new data file UUID, row index remap, report and expired records of a rewritten segment
+type RewrittenSegment = (Uuid, HashMap<LogKey, u64>, CompactionReport, Vec<Record>);
+
+/// The primary key, secondary keys, composite keys and computed keys of a record
+type RecordKeys = (
+    IndexableValue,
+    Vec<IndexableValue>,
+    Vec<Vec<IndexableValue>>,
+    Vec<IndexableValue>,
+);
+
+/// Get the key at `field_index` of a record read from the log. The record may have been written by
+/// a process with a different schema, so a missing or non-indexable key is an error instead of a panic.
+fn key_at(record: &Record, field_index: usize) -> DBResult<IndexableValue> {
+    record
+        .values
+        .get(field_index)
+        .and_then(Value::as_indexable)
+        .ok_or_else(|| record_schema_mismatch(record))
+}
+
+/// A plan that reads `log_keys` using `index`.
+fn plan_from_log_keys(index: QueryIndex, field: String, log_keys: Vec<&LogKey>) -> QueryPlan {
+    let segments: BTreeSet<u16> = log_keys
+        .iter()
+        .map(|log_key| log_key.segment_num())
+        .collect();
+    QueryPlan {
+        index,
+        field,
+        estimated_log_keys: log_keys.len() as u64,
+        segments: segments.into_iter().collect(),
+    }
+}
+
+fn value_to_indexable(value: &Value, field_type: &Type) -> DBResult<IndexableValue> {
+    if !type_check(value, field_type) {
+        return Err(DBError::ValidationError(format!(
+            "Queried value does not match type: {:?}",
+            field_type
+        )));
+    }
+    value.as_indexable().ok_or(DBError::ValidationError(
+        "Queried value must be indexable".to_owned(),
+    ))
+}
+
+fn bound_to_indexable(bound: Bound<&Value>, field_type: &Type) -> DBResult<Bound<IndexableValue>> {
+    match bound {
+        Bound::Included(value) => value_to_indexable(value, field_type).map(Bound::Included),
+        Bound::Excluded(value) => value_to_indexable(value, field_type).map(Bound::Excluded),
+        Bound::Unbounded => Ok(Bound::Unbounded),
+    }
+}
+
+fn record_schema_mismatch(record: &Record) -> DBError {
+    DBError::ConsistencyError(format!(
+        "Record version {} does not match the schema: {:?}",
+        record.version, record.values
+    ))
+}
@@ -0,0 +1,172 @@
+use super::*;
+#[cfg(feature = "tokio")]
+use std::future::Future;
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+#[cfg(feature = "tokio")]
+use std::task::{Context, Poll};
+
+/// The primary keys of the records with an expiry time, bucketed by the millisecond they expire
+/// at, see `DB::expirations`. The engine schedules a key whenever it indexes a version of the
+/// record, and unschedules it when the record is deleted, so each key is in at most one bucket.
+pub struct ExpiryWheel {
+    buckets: BTreeMap<i64, HashSet<IndexableValue>>,
+    expires_at: HashMap<IndexableValue, i64>,
+    /// The waker of the stream waiting for the next expiry, woken when an earlier one is scheduled
+    waker: Option<Waker>,
+    /// Set when the database handle is dropped, after which no more keys are scheduled
+    closed: bool,
+}
+
+impl ExpiryWheel {
+    fn new() -> ExpiryWheel {
+        ExpiryWheel {
+            buckets: BTreeMap::new(),
+            expires_at: HashMap::new(),
+            waker: None,
+            closed: false,
+        }
+    }
+
+    /// Schedule `key` to expire at `expires_at`, replacing its previous expiry time. A key without
+    /// an expiry time is unscheduled.
+    pub fn schedule(&mut self, key: IndexableValue, expires_at: Option<i64>) {
+        self.unschedule(&key);
+        let Some(expires_at) = expires_at else {
+            return;
+        };
+
+        let is_next = self
+            .next_expiry()
+            .is_none_or(|next_expiry| expires_at < next_expiry);
+        self.buckets
+            .entry(expires_at)
+            .or_default()
+            .insert(key.clone());
+        self.expires_at.insert(key, expires_at);
+        if is_next {
+            self.wake();
+        }
+    }
+
+    pub fn unschedule(&mut self, key: &IndexableValue) {
+        let Some(expires_at) = self.expires_at.remove(key) else {
+            return;
+        };
+        if let Some(bucket) = self.buckets.get_mut(&expires_at) {
+            bucket.remove(key);
+            if bucket.is_empty() {
+                self.buckets.remove(&expires_at);
+            }
+        }
+    }
+
+    /// The earliest expiry time scheduled, in milliseconds since the Unix epoch.
+    pub fn next_expiry(&self) -> Option<i64> {
+        self.buckets.keys().next().copied()
+    }
+
+    /// Remove and return a key whose expiry time is at or before `now`.
+    pub fn pop_expired(&mut self, now: i64) -> Option<IndexableValue> {
+        let mut bucket = self.buckets.first_entry()?;
+        if *bucket.key() > now {
+            return None;
+        }
+        let key = bucket.get().iter().next().cloned()?;
+        bucket.get_mut().remove(&key);
+        if bucket.get().is_empty() {
+            bucket.remove();
+        }
+        self.expires_at.remove(&key);
+        Some(key)
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The engine's handle to the wheel of an `Expirations` stream. Dropping it, along with the
+/// database handle, ends the stream.
+pub struct ExpiryWatch(Arc<Mutex<ExpiryWheel>>);
+
+impl ExpiryWatch {
+    pub fn new() -> ExpiryWatch {
+        ExpiryWatch(Arc::new(Mutex::new(ExpiryWheel::new())))
+    }
+
+    pub fn wheel(&self) -> &Arc<Mutex<ExpiryWheel>> {
+        &self.0
+    }
+
+    /// Whether the stream of the wheel has been dropped, so that it no longer needs updates.
+    pub fn is_unwatched(&self) -> bool {
+        Arc::strong_count(&self.0) == 1
+    }
+}
+
+impl Drop for ExpiryWatch {
+    fn drop(&mut self) {
+        let mut wheel = self.0.lock().unwrap();
+        wheel.closed = true;
+        wheel.wake();
+    }
+}
+
+/// A stream of the primary keys of records as they expire, returned by `DB::expirations`.
+///
+/// The stream waits for the next expiry time with a Tokio timer, so it must be polled within a
+/// Tokio runtime with the time driver enabled. It ends when the database handle is dropped.
+#[cfg(feature = "tokio")]
+pub struct Expirations {
+    wheel: Arc<Mutex<ExpiryWheel>>,
+    /// Created on the first poll, so that the stream can be created outside of a runtime
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+#[cfg(feature = "tokio")]
+impl Expirations {
+    pub(crate) fn new(wheel: Arc<Mutex<ExpiryWheel>>) -> Expirations {
+        Expirations { wheel, sleep: None }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl futures_core::Stream for Expirations {
+    type Item = Value;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Value>> {
+        let this = self.get_mut();
+        loop {
+            let now = unix_millis(SystemTime::now());
+            let next_expiry = {
+                let mut wheel = this.wheel.lock().unwrap();
+                if let Some(key) = wheel.pop_expired(now) {
+                    return Poll::Ready(Some(Value::from(key)));
+                }
+                if wheel.closed {
+                    return Poll::Ready(None);
+                }
+                wheel.waker = Some(cx.waker().clone());
+                match wheel.next_expiry() {
+                    Some(next_expiry) => next_expiry,
+                    None => return Poll::Pending,
+                }
+            };
+
+            let deadline = tokio::time::Instant::now()
+                + Duration::from_millis((next_expiry - now).max(0) as u64);
+            let sleep = this
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+            sleep.as_mut().reset(deadline);
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use super::*;
+
+/// A query to be explained with `DB::explain`, mirroring the query methods of `DB`.
+#[derive(Debug, Clone)]
+pub enum Query<Field> {
+    /// `DB::get` by the primary key.
+    Get(Value),
+    /// `DB::find_by` a field and a value.
+    FindBy(Field, Value),
+    /// `DB::range_by` a field, with the start and end bounds of the range.
+    RangeBy(Field, Bound<Value>, Bound<Value>),
+    /// `DB::range_by_composite` the fields of a composite key, a prefix of values and the start and
+    /// end bounds of the range of the field following the prefix. Use unbounded bounds for
+    /// `DB::find_by_composite`.
+    Composite(Vec<Field>, Vec<Value>, Bound<Value>, Bound<Value>),
+    /// `DB::find_by_all` the pairs of fields and values.
+    FindByAll(Vec<(Field, Value)>),
+}
+
+/// How a query finds its records, see `QueryPlan`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryIndex {
+    /// The primary memtable.
+    Primary,
+    /// The secondary memtable of the queried field.
+    Secondary,
+    /// The memtable of a composite key.
+    Composite,
+    /// The intersection of the log keys found in the memtables of several indexed fields.
+    Intersection,
+    /// No index: every record of every segment is read, see `NonIndexedQueries::Scan`.
+    FullScan,
+}
+
+/// The plan of a query, as returned by `DB::explain`. Plans are computed from the in-memory
+/// indexes without reading any records, so they are cheap to compute for any query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryPlan {
+    pub index: QueryIndex,
+    /// The queried field, or the list of fields of a composite key or an intersection, formatted
+    /// with `Debug`.
+    pub field: String,
+    /// Number of records the query would read from the log. For an indexed query this is the
+    /// number of log keys found in the index, which may include records that turn out to be
+    /// expired, and soft-deleted records with `DB::include_deleted`. For a full scan it is the
+    /// number of rows in the log, including superseded versions.
+    pub estimated_log_keys: u64,
+    /// The segments whose files the query would read, in ascending order.
+    pub segments: Vec<u16>,
+}
+
+impl QueryPlan {
+    /// Whether the query reads the whole log instead of using an index.
+    pub fn is_full_scan(&self) -> bool {
+        self.index == QueryIndex::FullScan
+    }
+}
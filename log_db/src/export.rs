@@ -0,0 +1,228 @@
+use super::*;
+
+/// Text formats that `DB::export` writes records in and `DB::import` reads them from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line, keyed by the field names of the schema.
+    JsonLines,
+    /// Comma-separated values as in RFC 4180, with a header row of the field names of the schema.
+    Csv,
+}
+
+/// Number of records read from the log at a time during an export.
+pub(crate) const EXPORT_BATCH_SIZE: usize = 1000;
+
+impl<R: Recordable> Engine<R> {
+    /// Write the current records to `writer` in `format`, see `DB::export`. The caller must hold a
+    /// lock that prevents writes, so that the export is a consistent snapshot.
+    pub fn export(&mut self, writer: &mut impl Write, format: Format) -> DBResult<u64> {
+        let names: Vec<String> = self
+            .config
+            .fields
+            .iter()
+            .map(|(field, _)| format!("{:?}", field))
+            .collect();
+        if format == Format::Csv {
+            let header: Vec<String> = names.iter().map(|name| csv_field(name)).collect();
+            writeln!(writer, "{}", header.join(","))?;
+        }
+
+        let primary_key = self.config.primary_key.clone();
+        let mut exported = 0;
+        let mut start = Bound::Unbounded;
+        loop {
+            let bounds = OwnedBounds::new(start, Bound::Unbounded);
+            let (records, next_start) =
+                self.range_by_batch(&primary_key, bounds, EXPORT_BATCH_SIZE)?;
+
+            for record in records {
+                let fields = record.values.iter().zip(&self.config.fields);
+                let line = match format {
+                    Format::JsonLines => {
+                        let mut line = String::new();
+                        let named = names.iter().zip(fields.map(|(value, (_, t))| (value, t)));
+                        write_json_object(&mut line, named);
+                        line
+                    }
+                    Format::Csv => fields
+                        .map(|(value, (_, field_type))| csv_value(value, field_type))
+                        .collect::<Vec<String>>()
+                        .join(","),
+                };
+                writeln!(writer, "{}", line)?;
+                exported += 1;
+            }
+
+            match next_start {
+                Some(key) => start = Bound::Excluded(key),
+                None => break,
+            }
+        }
+
+        writer.flush()?;
+        Ok(exported)
+    }
+}
+
+/// Decimals are written as strings to keep them exact, timestamps as integers of microseconds since
+/// the Unix epoch, bytes as base64 strings, JSON values as the documents themselves, floats that are
+/// not finite as `null`, geo points as `[latitude, longitude]` and nested records as objects.
+fn write_json_value(out: &mut String, value: &Value, value_type: &Type) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Int(i) | Value::Timestamp(i) => out.push_str(&i.to_string()),
+        Value::Decimal(d) => write_json_string(out, &d.to_string()),
+        Value::String(s) => write_json_string(out, s),
+        Value::Bytes(b) => write_json_string(out, &base64(b)),
+        Value::Json(s) => out.push_str(s),
+        Value::Float(f) => write_json_float(out, *f),
+        Value::GeoPoint(lat, lon) => {
+            out.push('[');
+            write_json_float(out, *lat);
+            out.push(',');
+            write_json_float(out, *lon);
+            out.push(']');
+        }
+        Value::Record(values) => match &value_type.primitive {
+            PrimitiveType::Record(fields) => {
+                let named = fields
+                    .iter()
+                    .zip(values)
+                    .map(|((name, field_type), value)| (name, (value, field_type)));
+                write_json_object(out, named);
+            }
+            // Records only match record types, but the values are written without names if not
+            _ => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_json_value(out, value, &Type::bytes());
+                }
+                out.push(']');
+            }
+        },
+    }
+}
+
+fn write_json_object<'a>(
+    out: &mut String,
+    fields: impl Iterator<Item = (&'a String, (&'a Value, &'a Type))>,
+) {
+    out.push('{');
+    for (i, (name, (value, value_type))) in fields.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_json_string(out, name);
+        out.push(':');
+        write_json_value(out, value, value_type);
+    }
+    out.push('}');
+}
+
+fn write_json_float(out: &mut String, f: f64) {
+    if f.is_finite() {
+        out.push_str(&f.to_string());
+    } else {
+        out.push_str("null");
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Values are written as their JSON counterparts would be, except that strings, decimals and JSON
+/// documents are written as they are, floats as they print, geo points as `latitude,longitude`,
+/// and nulls as empty fields.
+fn csv_value(value: &Value, value_type: &Type) -> String {
+    let text = match value {
+        Value::Null => return String::new(),
+        Value::Int(i) | Value::Timestamp(i) => i.to_string(),
+        Value::Decimal(d) => d.to_string(),
+        Value::String(s) | Value::Json(s) => s.clone(),
+        Value::Bytes(b) => base64(b),
+        Value::Float(f) => f.to_string(),
+        Value::GeoPoint(lat, lon) => format!("{},{}", lat, lon),
+        Value::Record(_) => {
+            let mut json = String::new();
+            write_json_value(&mut json, value, value_type);
+            json
+        }
+    };
+    csv_field(&text)
+}
+
+/// Quote a field if it contains a separator, quote or line break, doubling the quotes inside it.
+/// Empty text is quoted too, to tell it apart from a null.
+fn csv_field(text: &str) -> String {
+    if text.is_empty() || text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_owned()
+    }
+}
+
+/// Standard base64 with padding, as most analytics tools decode binary columns from.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_encodings() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field(""), "\"\"");
+        assert_eq!(csv_field("a,\"b\"\n"), "\"a,\"\"b\"\"\n\"");
+
+        let nested = Type::record(&[("name", Type::string()), ("point", Type::geo_point())]);
+        let value = Value::Record(vec![
+            Value::String("tab\there \"q\"".to_owned()),
+            Value::GeoPoint(60.5, f64::NAN),
+        ]);
+        let mut json = String::new();
+        write_json_value(&mut json, &value, &nested);
+        assert_eq!(json, r#"{"name":"tab\there \"q\"","point":[60.5,null]}"#);
+        assert_eq!(
+            csv_value(&value, &nested),
+            r#""{""name"":""tab\there \""q\"""",""point"":[60.5,null]}""#
+        );
+    }
+}
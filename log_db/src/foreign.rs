@@ -0,0 +1,25 @@
+use super::*;
+
+/// A read-only source of records that can be mounted next to a database with `DB::mount`, e.g. a CSV
+/// snapshot or the data directory of an older database. Mounted sources are queried by
+/// `DB::find_by` alongside the database itself, which is useful while migrating data between stores.
+///
+/// The records of a source are of the same type as the records of the database it is mounted to.
+pub trait ForeignSource<R: Recordable> {
+    /// Find the records whose `field` has the value `value`, like `DB::find_by`.
+    fn find_by(&mut self, field: &R::Field, value: &Value) -> DBResult<Vec<R>>;
+}
+
+/// Another log_db database can be mounted as a source, e.g. the data directory of the previous
+/// version of a table. Its secondary indexes are used for the queries.
+impl<R: Recordable> ForeignSource<R> for DB<R> {
+    fn find_by(&mut self, field: &R::Field, value: &Value) -> DBResult<Vec<R>> {
+        DB::find_by(self, field, value)
+    }
+}
+
+/// A mounted source and the name it was mounted with.
+pub struct Mount<R: Recordable> {
+    pub name: String,
+    pub source: Box<dyn ForeignSource<R>>,
+}
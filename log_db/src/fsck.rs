@@ -0,0 +1,458 @@
+use super::*;
+
+/// Whether `DB::fsck` only reports the problems it finds or also repairs the ones it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckMode {
+    /// Report problems without changing anything. Takes a shared lock, so writes wait for the check.
+    Check,
+    /// Recover an interrupted batch, truncate torn trailing entries, remove orphaned data files and
+    /// rebuild the active symlink. Takes the exclusive lock.
+    Repair,
+}
+
+/// The kind of a problem found by `DB::fsck`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckProblemKind {
+    /// The active symlink is missing or does not point to a metadata file.
+    BrokenActiveSymlink,
+    /// The journal of a batch whose append was interrupted by a crash has not been recovered.
+    PendingJournal,
+    /// The metadata file of a segment is shorter than its header or ends in a partial row.
+    MetadataSize,
+    /// The data file of a segment is missing.
+    MissingDataFile,
+    /// The data file of the active segment has bytes after the last record that a metadata row
+    /// points to.
+    DanglingDataBytes,
+    /// A metadata row or a record does not match its checksum, or the record is missing from the
+    /// data file.
+    ChecksumFailure,
+    /// A data file that no metadata file refers to.
+    OrphanedDataFile,
+}
+
+/// A problem found by `DB::fsck`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsckProblem {
+    pub kind: FsckProblemKind,
+    /// The segment that the problem is in, or `None` if it is not in a single segment.
+    pub segment_num: Option<u16>,
+    pub description: String,
+    /// Whether the problem was repaired, which only happens in `FsckMode::Repair`.
+    pub repaired: bool,
+}
+
+/// The result of `DB::fsck`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsckReport {
+    pub segments_checked: u64,
+    /// Number of records whose checksums were checked.
+    pub records_checked: u64,
+    pub problems: Vec<FsckProblem>,
+}
+
+impl FsckReport {
+    /// Whether every problem found was repaired, i.e. the data directory is consistent.
+    pub fn is_clean(&self) -> bool {
+        self.problems.iter().all(|problem| problem.repaired)
+    }
+
+    fn push(
+        &mut self,
+        kind: FsckProblemKind,
+        segment_num: Option<u16>,
+        description: String,
+        repaired: bool,
+    ) {
+        self.problems.push(FsckProblem {
+            kind,
+            segment_num,
+            description,
+            repaired,
+        });
+    }
+}
+
+/// Check the files of the data directory in `data_dir_path` for consistency, see `DB::fsck`.
+pub fn fsck(data_dir_path: &Path, mode: FsckMode) -> DBResult<FsckReport> {
+    if !fs::exists(data_dir_path.join(INITIALIZED_FILENAME))? {
+        return Err(DBError::ValidationError(format!(
+            "{} is not an initialized data directory",
+            data_dir_path.display()
+        )));
+    }
+
+    let mut lock_manager = LockManager::new(data_dir_path.to_path_buf())?;
+    match mode {
+        FsckMode::Check => lock_manager.lock_shared()?,
+        FsckMode::Repair => lock_manager.lock_exclusive()?,
+    }
+    let result = check_data_dir(data_dir_path, mode == FsckMode::Repair);
+    lock_manager.unlock()?;
+    result
+}
+
+fn check_data_dir(data_dir_path: &Path, repair: bool) -> DBResult<FsckReport> {
+    let mut report = FsckReport {
+        segments_checked: 0,
+        records_checked: 0,
+        problems: vec![],
+    };
+    let segment_nums = list_segment_numbers(data_dir_path)?;
+
+    // The active segment is the newest one, since segments are only ever created with a greater number
+    let active_symlink = data_dir_path.join(ACTIVE_SYMLINK_FILENAME);
+    let symlink_problem = match fs::read_link(&active_symlink) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Some("is missing".to_owned()),
+        Err(e) => return Err(e.into()),
+        Ok(target) => match parse_segment_number(&target) {
+            Ok(_) if fs::exists(data_dir_path.join(&target))? => None,
+            Ok(_) => Some(format!(
+                "points to {}, which does not exist",
+                target.display()
+            )),
+            Err(_) => Some(format!(
+                "points to {}, which is not a metadata file",
+                target.display()
+            )),
+        },
+    };
+    if let Some(symlink_problem) = symlink_problem {
+        let newest = segment_nums.last().copied();
+        let repaired = repair && newest.is_some();
+        if let (true, Some(segment_num)) = (repaired, newest) {
+            set_active_segment(data_dir_path, segment_num)?;
+        }
+        report.push(
+            FsckProblemKind::BrokenActiveSymlink,
+            None,
+            format!("Active symlink {}", symlink_problem),
+            repaired,
+        );
+    }
+
+    // The rows of the journal point at data that is already written, so it is recovered before
+    // the data files are checked for bytes that no row points at
+    if fs::exists(data_dir_path.join(JOURNAL_FILENAME))? {
+        let repaired = repair && fs::exists(&active_symlink)?;
+        if repaired {
+            JournalEntry::recover(data_dir_path)?;
+        }
+        report.push(
+            FsckProblemKind::PendingJournal,
+            None,
+            "Journal of an interrupted batch append has not been recovered".to_owned(),
+            repaired,
+        );
+    }
+
+    // The greatest end of the records pointed to by each data file, or `None` if the metadata of
+    // one of its segments is damaged and the end is not known
+    let mut data_ends: BTreeMap<Uuid, Option<u64>> = BTreeMap::new();
+    let mut active_data_uuid = None;
+    let mut all_headers_read = true;
+    for &segment_num in &segment_nums {
+        report.segments_checked += 1;
+        let is_active = Some(&segment_num) == segment_nums.last();
+        match check_segment(data_dir_path, segment_num, is_active, repair, &mut report)? {
+            Some((uuid, data_end)) => {
+                let end = data_ends.entry(uuid).or_insert(Some(0));
+                *end = end.zip(data_end).map(|(a, b)| a.max(b));
+                active_data_uuid = Some(uuid);
+            }
+            None => {
+                all_headers_read = false;
+                active_data_uuid = None;
+            }
+        }
+    }
+
+    // Only the data file of the active segment is appended to. The data files of sealed segments
+    // may end in the records of a later segment that shared the file until it was compacted.
+    if let Some(uuid) = active_data_uuid {
+        let data_path = data_dir_path.join(uuid.to_string());
+        if let (Some(Some(data_end)), true) = (data_ends.get(&uuid), fs::exists(&data_path)?) {
+            let data_len = fs::metadata(&data_path)?.len();
+            if data_len > *data_end {
+                if repair {
+                    truncate_in_place(&data_path, *data_end)?;
+                }
+                report.push(
+                    FsckProblemKind::DanglingDataBytes,
+                    segment_nums.last().copied(),
+                    format!(
+                        "Data file {} has {} bytes after its last record, left by an interrupted append",
+                        uuid,
+                        data_len - data_end
+                    ),
+                    repair,
+                );
+            }
+        }
+    }
+
+    // A data file may belong to a segment whose metadata header could not be read, so orphans are
+    // only removed when every header was read
+    for entry in fs::read_dir(data_dir_path)? {
+        let entry = entry?;
+        let Some(uuid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| Uuid::parse_str(name).ok())
+        else {
+            continue;
+        };
+        if !entry.file_type()?.is_file() || data_ends.contains_key(&uuid) {
+            continue;
+        }
+        let repaired = repair && all_headers_read;
+        if repaired {
+            fs::remove_file(entry.path())?;
+        }
+        report.push(
+            FsckProblemKind::OrphanedDataFile,
+            None,
+            format!("Data file {} is not referred to by any segment", uuid),
+            repaired,
+        );
+    }
+
+    // Truncated sealed segments no longer match their manifest entries
+    if report.problems.iter().any(|problem| problem.repaired) && fs::exists(&active_symlink)? {
+        Manifest::compute(data_dir_path)?.write(data_dir_path)?;
+    }
+    Ok(report)
+}
+
+/// Check the metadata file of a segment, its rows and the checksums of its records. Returns the
+/// UUID of the data file of the segment and the greatest end of its records, which is `None` if a
+/// row is damaged. Returns `None` if the metadata header could not be read.
+fn check_segment(
+    data_dir_path: &Path,
+    segment_num: u16,
+    is_active: bool,
+    repair: bool,
+    report: &mut FsckReport,
+) -> DBResult<Option<(Uuid, Option<u64>)>> {
+    let metadata_path = data_dir_path.join(metadata_filename(segment_num));
+    let mut metadata_file = READ_MODE.open(&metadata_path)?;
+    let metadata_header = match is_metadata_file_valid(&mut metadata_file) {
+        Ok(IsMetadatafileValidResult::ReplaceFile) => {
+            report.push(
+                FsckProblemKind::MetadataSize,
+                Some(segment_num),
+                format!(
+                    "Metadata file of segment {} is shorter than its header",
+                    segment_num
+                ),
+                false,
+            );
+            return Ok(None);
+        }
+        Ok(IsMetadatafileValidResult::TruncateToSize(size)) => {
+            let len = metadata_file.seek(SeekFrom::End(0))?;
+            if repair && is_active {
+                truncate_in_place(&metadata_path, size)?;
+            } else if repair {
+                replace_with_prefix(data_dir_path, &metadata_path, size)?;
+                metadata_file = READ_MODE.open(&metadata_path)?;
+            }
+            report.push(
+                FsckProblemKind::MetadataSize,
+                Some(segment_num),
+                format!(
+                    "Metadata file of segment {} ends in a partial row of {} bytes",
+                    segment_num,
+                    len - size
+                ),
+                repair,
+            );
+            read_metadata_header(&mut metadata_file)?
+        }
+        Ok(IsMetadatafileValidResult::Ok) => read_metadata_header(&mut metadata_file)?,
+        Err(e) => {
+            report.push(
+                FsckProblemKind::MetadataSize,
+                Some(segment_num),
+                format!(
+                    "Metadata header of segment {} cannot be read: {}",
+                    segment_num, e
+                ),
+                false,
+            );
+            return Ok(None);
+        }
+    };
+
+    let data = match fs::read(data_dir_path.join(metadata_header.uuid.to_string())) {
+        Ok(data) => data,
+        // The records of an archived data file are checked against its checksum when it is fetched
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound
+                && ArchivedFiles::read(data_dir_path)?
+                    .files
+                    .contains_key(&metadata_header.uuid) =>
+        {
+            return Ok(Some((metadata_header.uuid, None)));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            report.push(
+                FsckProblemKind::MissingDataFile,
+                Some(segment_num),
+                format!(
+                    "Data file {} of segment {} is missing",
+                    metadata_header.uuid, segment_num
+                ),
+                false,
+            );
+            return Ok(Some((metadata_header.uuid, None)));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    metadata_file.seek(SeekFrom::Start(0))?;
+    let mut metadata_buf = vec![];
+    metadata_file.read_to_end(&mut metadata_buf)?;
+    let row_length = metadata_row_length(metadata_header.version);
+
+    let mut data_end = Some(0);
+    for (index, row) in metadata_buf[METADATA_FILE_HEADER_SIZE..]
+        .chunks_exact(row_length)
+        .enumerate()
+    {
+        let row = match MetadataRow::deserialize(metadata_header.version, row) {
+            Ok(row) => row,
+            Err(e) => {
+                report.push(
+                    FsckProblemKind::ChecksumFailure,
+                    Some(segment_num),
+                    format!(
+                        "Metadata row {} of segment {} is damaged: {}",
+                        index, segment_num, e
+                    ),
+                    false,
+                );
+                data_end = None;
+                continue;
+            }
+        };
+        if row.is_unused() {
+            continue;
+        }
+        report.records_checked += 1;
+        let end = row.offset.saturating_add(row.length);
+        data_end = data_end.map(|data_end: u64| data_end.max(end));
+
+        let record = usize::try_from(row.offset)
+            .ok()
+            .zip(usize::try_from(row.length).ok())
+            .and_then(|(offset, length)| data.get(offset..offset.checked_add(length)?));
+        let description = match record {
+            Some(record) if row.matches(record) => continue,
+            Some(_) => format!(
+                "Record {} of segment {} is damaged: checksum mismatch",
+                index, segment_num
+            ),
+            None => format!(
+                "Record {} of segment {} is missing from the data file",
+                index, segment_num
+            ),
+        };
+        report.push(
+            FsckProblemKind::ChecksumFailure,
+            Some(segment_num),
+            description,
+            false,
+        );
+    }
+
+    Ok(Some((metadata_header.uuid, data_end)))
+}
+
+/// Truncate the torn trailing entries that an append interrupted by a crash leaves in the active
+/// segment: a partial metadata row, and data bytes after the last record that a row points to.
+/// Must be called while holding the exclusive lock, after the journal has been recovered, since
+/// the rows of the journal point at data that has already been written.
+pub fn truncate_torn_append(data_dir_path: &Path) -> DBResult<()> {
+    let active_target = fs::read_link(data_dir_path.join(ACTIVE_SYMLINK_FILENAME))?;
+    let active_num = parse_segment_number(&active_target)?;
+    let active_path = data_dir_path.join(&active_target);
+    let mut metadata_file = READ_MODE.open(&active_path)?;
+    if let IsMetadatafileValidResult::TruncateToSize(size) =
+        is_metadata_file_valid(&mut metadata_file)?
+    {
+        warn!(
+            "Metadata file {} ends in a partial row, truncating it to {} bytes",
+            active_target.display(),
+            size
+        );
+        truncate_in_place(&active_path, size)?;
+    }
+
+    // The active segment shares its data file with the sealed segments right before it, whose
+    // records may end after the last record of the active segment
+    let uuid = read_metadata_header(&mut metadata_file)?.uuid;
+    let mut data_end = 0;
+    for segment_num in list_segment_numbers(data_dir_path)?.into_iter().rev() {
+        if segment_num > active_num {
+            continue;
+        }
+        let mut metadata_file =
+            READ_MODE.open(data_dir_path.join(metadata_filename(segment_num)))?;
+        let metadata_header = read_metadata_header(&mut metadata_file)?;
+        if metadata_header.uuid != uuid {
+            break;
+        }
+        // The end of the records is not known if a row is damaged, so nothing is truncated
+        match records_end(&mut metadata_file, metadata_header.version)? {
+            Some(end) => data_end = data_end.max(end),
+            None => return Ok(()),
+        }
+    }
+
+    let data_path = data_dir_path.join(uuid.to_string());
+    let data_len = fs::metadata(&data_path)?.len();
+    if data_len > data_end {
+        warn!(
+            "Data file {} has {} bytes after its last record, truncating them",
+            uuid,
+            data_len - data_end
+        );
+        truncate_in_place(&data_path, data_end)?;
+    }
+    Ok(())
+}
+
+/// The greatest end of the records that the rows of a metadata file point to, or `None` if a row
+/// is damaged. The seek head must be after the header.
+fn records_end(metadata_file: &mut fs::File, format_version: u8) -> DBResult<Option<u64>> {
+    let mut rows = vec![];
+    metadata_file.read_to_end(&mut rows)?;
+    let mut end = 0;
+    for row in rows.chunks_exact(metadata_row_length(format_version)) {
+        match MetadataRow::deserialize(format_version, row) {
+            Ok(row) => end = end.max(row.offset.saturating_add(row.length)),
+            Err(_) => return Ok(None),
+        }
+    }
+    Ok(Some(end))
+}
+
+/// Truncate a file of the active segment in place. Other handles keep the files of the active
+/// segment open, so they must not be replaced, and backups copy them rather than link them.
+fn truncate_in_place(path: &Path, len: u64) -> DBResult<()> {
+    let file = fs::OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Replace the file at `path` with its first `len` bytes. Sealed files may be hard-linked into
+/// backups, so they are replaced with a new file rather than truncated in place.
+fn replace_with_prefix(data_dir_path: &Path, path: &Path, len: u64) -> DBResult<()> {
+    let mut tmp_file = fs::NamedTempFile::new_in(data_dir_path)?;
+    io::copy(&mut READ_MODE.open(path)?.take(len), &mut tmp_file)?;
+    tmp_file.as_file().sync_all()?;
+    tmp_file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
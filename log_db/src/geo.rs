@@ -0,0 +1,151 @@
+use super::*;
+use log_db_codec::{float_order_key, geo_point_key};
+
+/// The bits of the latitude in a `geo_point_key`. The bits of the longitude are the rest.
+const LAT_BITS: u64 = 0xaaaa_aaaa_aaaa_aaaa;
+const LON_BITS: u64 = 0x5555_5555_5555_5555;
+
+/// A bounding box that does not cross the antimeridian, as the range of z-order keys between its
+/// corners and the ranges of the order keys of its coordinates.
+///
+/// The keys of the points in the box are between the keys of its corners, but not all keys between
+/// them are in the box. Scans of the index skip the keys outside of it with `next_z`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeoBox {
+    pub min_z: u64,
+    pub max_z: u64,
+    lat: RangeInclusive<u64>,
+    lon: RangeInclusive<u64>,
+}
+
+impl GeoBox {
+    /// The boxes that cover the bounding box from the south-west corner `min` to the north-east
+    /// corner `max`, given as (latitude, longitude). A box whose minimum longitude is greater than
+    /// its maximum crosses the antimeridian, and is covered by a box on each side of it.
+    pub fn cover(min: (f64, f64), max: (f64, f64)) -> DBResult<Vec<GeoBox>> {
+        let (min_lat, min_lon) = min;
+        let (max_lat, max_lon) = max;
+        if !is_valid_geo_point(min_lat, min_lon) || !is_valid_geo_point(max_lat, max_lon) {
+            return Err(DBError::ValidationError(format!(
+                "Invalid bounding box corners {:?} and {:?}",
+                min, max
+            )));
+        }
+        if min_lat > max_lat {
+            return Err(DBError::ValidationError(format!(
+                "Bounding box minimum latitude {} is greater than its maximum {}",
+                min_lat, max_lat
+            )));
+        }
+
+        if min_lon <= max_lon {
+            Ok(vec![GeoBox::new(min, max)])
+        } else {
+            Ok(vec![
+                GeoBox::new(min, (max_lat, 180.0)),
+                GeoBox::new((min_lat, -180.0), max),
+            ])
+        }
+    }
+
+    fn new((min_lat, min_lon): (f64, f64), (max_lat, max_lon): (f64, f64)) -> GeoBox {
+        GeoBox {
+            min_z: geo_point_key(min_lat, min_lon),
+            max_z: geo_point_key(max_lat, max_lon),
+            lat: float_order_key(min_lat)..=float_order_key(max_lat),
+            lon: float_order_key(min_lon)..=float_order_key(max_lon),
+        }
+    }
+
+    /// Whether the point of an index key is in the box.
+    pub fn contains(&self, key: &IndexableValue) -> bool {
+        match key {
+            IndexableValue::GeoPoint(_, lat, lon) => {
+                self.lat.contains(lat) && self.lon.contains(lon)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the z-order key `z` is in the box, after the coordinates of the box are quantized
+    /// like those of the key.
+    pub fn contains_z(&self, z: u64) -> bool {
+        let within = |bits: u64| (self.min_z & bits..=self.max_z & bits).contains(&(z & bits));
+        within(LAT_BITS) && within(LON_BITS)
+    }
+
+    /// The smallest z-order key in the box that is greater than `z`, which is a key between the
+    /// corners of the box but outside of it. This is the BIGMIN of Tropf and Herzog.
+    pub fn next_z(&self, z: u64) -> u64 {
+        let (mut min_z, mut max_z) = (self.min_z, self.max_z);
+        let mut next = max_z;
+
+        for bit in (0..64).rev() {
+            let mask = 1 << bit;
+            match (z & mask != 0, min_z & mask != 0, max_z & mask != 0) {
+                (false, false, true) => {
+                    next = load_1000(min_z, bit);
+                    max_z = load_0111(max_z, bit);
+                }
+                (false, true, true) => return min_z,
+                (true, false, false) => return next,
+                (true, false, true) => min_z = load_1000(min_z, bit),
+                _ => {}
+            }
+        }
+        next
+    }
+}
+
+/// The lower bits of the same coordinate as `bit` in a z-order key.
+fn lower_bits_of_coordinate(bit: u32) -> u64 {
+    let bits = if bit.is_multiple_of(2) {
+        LON_BITS
+    } else {
+        LAT_BITS
+    };
+    bits & ((1 << bit) - 1)
+}
+
+/// Set `bit` of `z` and clear the lower bits of its coordinate.
+fn load_1000(z: u64, bit: u32) -> u64 {
+    (z & !lower_bits_of_coordinate(bit)) | 1 << bit
+}
+
+/// Clear `bit` of `z` and set the lower bits of its coordinate.
+fn load_0111(z: u64, bit: u32) -> u64 {
+    (z & !(1 << bit)) | lower_bits_of_coordinate(bit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The z-order key of quantized coordinates.
+    fn interleave(lat: u32, lon: u32) -> u64 {
+        (0..32).fold(0, |z, bit| {
+            z | ((lat as u64 >> bit) & 1) << (2 * bit + 1) | ((lon as u64 >> bit) & 1) << (2 * bit)
+        })
+    }
+
+    #[test]
+    fn test_next_z() {
+        let geo_box = GeoBox {
+            min_z: interleave(3, 5),
+            max_z: interleave(10, 12),
+            lat: 0..=0,
+            lon: 0..=0,
+        };
+
+        for z in geo_box.min_z..=geo_box.max_z {
+            assert_eq!(
+                geo_box.contains_z(z),
+                (3..=10).any(|lat| (5..=12).any(|lon| interleave(lat, lon) == z))
+            );
+            if !geo_box.contains_z(z) {
+                let expected = (z + 1..=geo_box.max_z).find(|&next| geo_box.contains_z(next));
+                assert_eq!(Some(geo_box.next_z(z)), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use super::*;
+
+/// Shares the syncs of `WriteDurability::FlushSync` and `FlushDataSync` writes between the handles of a data
+/// directory, in this process or others.
+///
+/// Writers append and flush their records under the exclusive lock, and sync them after releasing
+/// it, so that other writers can append in the meantime. Syncs are made one at a time under the
+/// sync lock. A sync covers every record appended to the active segment before it started, so the
+/// syncing writer records in the synced file how far the metadata file was synced, and writers
+/// waiting for the sync lock return without syncing if their records are covered.
+pub struct GroupCommit {
+    sync_lock_file: fs::File,
+    synced_file: fs::File,
+    /// How long the syncing writer waits for other writers before syncing
+    window: Duration,
+}
+
+/// The end of the records that a writer must see synced before returning, see `Engine::persist_active`.
+pub struct PendingSync {
+    pub segment_num: u16,
+    pub metadata_end: u64,
+    pub data_file: fs::File,
+    pub metadata_file: fs::File,
+}
+
+const SYNCED_LEN: usize = 2 + 8;
+
+impl GroupCommit {
+    pub fn new(data_dir_path: &Path, window: Duration) -> DBResult<GroupCommit> {
+        let sync_lock_file = fs::File::create(data_dir_path.join(SYNC_LOCK_FILENAME))?;
+        let synced_file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(data_dir_path.join(SYNCED_FILENAME))?;
+        Ok(GroupCommit {
+            sync_lock_file,
+            synced_file,
+            window,
+        })
+    }
+
+    /// Wait until the records of `pending` are synced, syncing them along with those of the other
+    /// writers if no other writer has.
+    pub fn sync(
+        &mut self,
+        pending: &PendingSync,
+        write_durability: &WriteDurability,
+    ) -> DBResult<()> {
+        self.sync_lock_file.lock_exclusive()?;
+        let result = self.sync_locked(pending, write_durability);
+        self.sync_lock_file.unlock()?;
+        result
+    }
+
+    fn sync_locked(
+        &mut self,
+        pending: &PendingSync,
+        write_durability: &WriteDurability,
+    ) -> DBResult<()> {
+        if let Some((segment_num, metadata_end)) = self.read_synced()? {
+            if segment_num == pending.segment_num && metadata_end >= pending.metadata_end {
+                debug!("Records already synced by another writer");
+                return Ok(());
+            }
+        }
+
+        if !self.window.is_zero() {
+            thread::sleep(self.window);
+        }
+        // Rows are appended after their records, so syncing the data file after reading the
+        // length of the metadata file covers the records of every row up to it
+        let metadata_end = pending.metadata_file.metadata()?.len();
+        write_durability.sync(&pending.data_file)?;
+        write_durability.sync(&pending.metadata_file)?;
+        self.write_synced(pending.segment_num, metadata_end)
+    }
+
+    fn read_synced(&mut self) -> DBResult<Option<(u16, u64)>> {
+        let mut buf = [0; SYNCED_LEN];
+        self.synced_file.seek(SeekFrom::Start(0))?;
+        match self.synced_file.read_exact(&mut buf) {
+            Ok(()) => Ok(Some((
+                u16::from_be_bytes(buf[0..2].try_into().unwrap()),
+                u64::from_be_bytes(buf[2..].try_into().unwrap()),
+            ))),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record that the active segment has been synced up to `metadata_end`. The file is only read
+    /// while holding the sync lock and needs no sync itself: after a crash, nothing is pending.
+    fn write_synced(&mut self, segment_num: u16, metadata_end: u64) -> DBResult<()> {
+        let mut buf = [0; SYNCED_LEN];
+        buf[0..2].copy_from_slice(&segment_num.to_be_bytes());
+        buf[2..].copy_from_slice(&metadata_end.to_be_bytes());
+        self.synced_file.seek(SeekFrom::Start(0))?;
+        self.synced_file.write_all(&buf)?;
+        Ok(())
+    }
+}
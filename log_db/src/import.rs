@@ -0,0 +1,690 @@
+use super::*;
+use crate::schema::describe_type;
+use std::io::BufRead;
+
+/// The result of `DB::import`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Number of records written.
+    pub imported: u64,
+    /// The lines that could not be imported, in the order they were read.
+    pub problems: Vec<ImportProblem>,
+}
+
+/// A line of the input that was skipped by `DB::import`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportProblem {
+    /// The line number, starting from 1. A CSV record that spans lines is reported at its first line.
+    pub line: u64,
+    pub description: String,
+}
+
+/// Number of records written at a time during an import.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+impl<R: Recordable> DB<R> {
+    /// Read records from `reader` in `format` and upsert them. The input is in the format that
+    /// `DB::export` writes: JSON Lines with one object per record keyed by field names, or CSV with
+    /// a header row of field names. Fields missing from the input are null. Returns the number of
+    /// imported records and the lines that were skipped.
+    ///
+    /// Each line is parsed and validated against the schema on its own, and a line that fails is
+    /// reported as an `ImportProblem` and skipped instead of failing the import. The valid records
+    /// are written in batches with the atomic batch write of `DB::batch_upsert`, so a crash in the
+    /// middle of an import leaves a prefix of the batches in the database. A batch that fails
+    /// validation in the engine, e.g. a `FieldCheck`, is retried one record at a time to find the
+    /// failing lines. An unreadable input or a CSV header that does not match the schema fails the
+    /// import as a whole.
+    pub fn import(&mut self, reader: impl Read, format: Format) -> DBResult<ImportReport> {
+        let fields = self.engine.config.fields.clone();
+        let names: Vec<String> = fields
+            .iter()
+            .map(|(field, _)| format!("{:?}", field))
+            .collect();
+        let mut reader = io::BufReader::new(reader);
+        let mut report = ImportReport {
+            imported: 0,
+            problems: vec![],
+        };
+
+        let mut line_num = 0;
+        let mut columns = None;
+        if format == Format::Csv {
+            let Some((_, header)) = read_csv_record(&mut reader, &mut line_num)? else {
+                return Ok(report);
+            };
+            columns = Some(csv_columns(&header, &names)?);
+        }
+
+        let mut batch: Vec<(u64, Record)> = vec![];
+        loop {
+            let (line, parsed) = match &columns {
+                None => {
+                    let mut line = vec![];
+                    if reader.read_until(b'\n', &mut line)? == 0 {
+                        break;
+                    }
+                    line_num += 1;
+                    let text = String::from_utf8(line).map_err(|_| {
+                        DBError::ValidationError("Line is not valid UTF-8".to_owned())
+                    });
+                    if text.as_deref().is_ok_and(|text| text.trim().is_empty()) {
+                        continue;
+                    }
+                    (
+                        line_num,
+                        text.and_then(|text| parse_json_line(&text, &names, &fields)),
+                    )
+                }
+                Some(columns) => {
+                    let Some((line, csv_fields)) = read_csv_record(&mut reader, &mut line_num)?
+                    else {
+                        break;
+                    };
+                    (line, parse_csv_record(&csv_fields, columns, &fields))
+                }
+            };
+
+            match parsed.and_then(|values| {
+                let record = Record::from(&values);
+                record.validate(&fields).map(|_| record)
+            }) {
+                Ok(record) => batch.push((line, record)),
+                Err(e) => report.problems.push(ImportProblem {
+                    line,
+                    description: e.to_string(),
+                }),
+            }
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                self.import_batch(std::mem::take(&mut batch), &mut report)?;
+            }
+        }
+        self.import_batch(batch, &mut report)?;
+
+        report.problems.sort_by_key(|problem| problem.line);
+        Ok(report)
+    }
+
+    fn import_batch(
+        &mut self,
+        batch: Vec<(u64, Record)>,
+        report: &mut ImportReport,
+    ) -> DBResult<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let records = batch.iter().map(|(_, record)| record.clone());
+        match self
+            .engine
+            .with_exclusive_lock(|engine| engine.batch_upsert_records(records))
+        {
+            Ok(receipts) => {
+                report.imported += receipts.len() as u64;
+                Ok(())
+            }
+            // Nothing of the batch was written, so each record can be retried on its own
+            Err(DBError::ValidationError(_)) => {
+                for (line, record) in batch {
+                    match self.upsert_record(record) {
+                        Ok(_) => report.imported += 1,
+                        Err(DBError::ValidationError(description)) => {
+                            report.problems.push(ImportProblem { line, description })
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The schema field of each CSV column.
+fn csv_columns(header: &[String], names: &[String]) -> DBResult<Vec<usize>> {
+    header
+        .iter()
+        .map(|column| {
+            let column = column.strip_prefix(QUOTED).unwrap_or(column);
+            names.iter().position(|name| name == column).ok_or_else(|| {
+                DBError::ValidationError(format!("Unknown field in CSV header: {}", column))
+            })
+        })
+        .collect()
+}
+
+fn parse_json_line(
+    text: &str,
+    names: &[String],
+    fields: &[(impl Eq, Type)],
+) -> DBResult<Vec<Value>> {
+    let mut parser = JsonParser::new(text);
+    let json = parser.parse_document()?;
+    let JsonValue::Object(members, _) = json else {
+        return Err(DBError::ValidationError(
+            "Line is not a JSON object".to_owned(),
+        ));
+    };
+    let types: Vec<(&str, &Type)> = names
+        .iter()
+        .map(String::as_str)
+        .zip(fields.iter().map(|(_, field_type)| field_type))
+        .collect();
+    object_values(text, members, &types)
+}
+
+/// The values of the fields of `types` in a JSON object, in the order of `types`.
+fn object_values(
+    text: &str,
+    members: Vec<(String, JsonValue)>,
+    types: &[(&str, &Type)],
+) -> DBResult<Vec<Value>> {
+    let mut values = vec![Value::Null; types.len()];
+    for (name, json) in members {
+        let index = types
+            .iter()
+            .position(|(field_name, _)| *field_name == name)
+            .ok_or_else(|| DBError::ValidationError(format!("Unknown field: {}", name)))?;
+        values[index] = json_to_value(text, json, types[index].1)
+            .map_err(|e| DBError::ValidationError(format!("Field {}: {}", name, e)))?;
+    }
+    Ok(values)
+}
+
+/// The inverse of the JSON encoding of `DB::export`.
+fn json_to_value(text: &str, json: JsonValue, value_type: &Type) -> Result<Value, String> {
+    let mismatch = |json: &JsonValue| {
+        format!(
+            "expected {}, got {}",
+            describe_type(value_type),
+            json.kind()
+        )
+    };
+    Ok(match (&value_type.primitive, json) {
+        (_, JsonValue::Null) => Value::Null,
+        (PrimitiveType::Json, json) => Value::Json(text[json.span()].to_owned()),
+        (PrimitiveType::Int, JsonValue::Number(number, _)) => Value::Int(
+            number
+                .parse()
+                .map_err(|_| format!("invalid integer {}", number))?,
+        ),
+        (PrimitiveType::Timestamp, JsonValue::Number(number, _)) => Value::Timestamp(
+            number
+                .parse()
+                .map_err(|_| format!("invalid timestamp {}", number))?,
+        ),
+        (PrimitiveType::Decimal, JsonValue::String(number, _) | JsonValue::Number(number, _)) => {
+            Value::Decimal(parse_decimal(&number)?)
+        }
+        (PrimitiveType::Float, JsonValue::Number(number, _)) => Value::Float(
+            number
+                .parse()
+                .map_err(|_| format!("invalid float {}", number))?,
+        ),
+        (PrimitiveType::String | PrimitiveType::Enum(_), JsonValue::String(s, _)) => {
+            Value::String(s)
+        }
+        (PrimitiveType::Bytes | PrimitiveType::FixedBytes(_), JsonValue::String(s, _)) => {
+            Value::Bytes(base64_decode(&s)?)
+        }
+        (PrimitiveType::GeoPoint, JsonValue::Array(items, span)) => match &items[..] {
+            [JsonValue::Number(lat, _), JsonValue::Number(lon, _)] => Value::GeoPoint(
+                lat.parse()
+                    .map_err(|_| format!("invalid latitude {}", lat))?,
+                lon.parse()
+                    .map_err(|_| format!("invalid longitude {}", lon))?,
+            ),
+            _ => return Err(mismatch(&JsonValue::Array(items, span))),
+        },
+        (PrimitiveType::Record(nested), JsonValue::Object(members, _)) => {
+            let types: Vec<(&str, &Type)> = nested
+                .iter()
+                .map(|(name, field_type)| (name.as_str(), field_type))
+                .collect();
+            Value::Record(object_values(text, members, &types).map_err(|e| e.to_string())?)
+        }
+        (_, json) => return Err(mismatch(&json)),
+    })
+}
+
+fn parse_decimal(number: &str) -> Result<Decimal, String> {
+    Decimal::from_str_exact(number)
+        .or_else(|_| Decimal::from_scientific(number))
+        .map_err(|_| format!("invalid decimal {}", number))
+}
+
+/// The inverse of the CSV encoding of `DB::export`. An empty unquoted field is null.
+fn parse_csv_record(
+    csv_fields: &[String],
+    columns: &[usize],
+    fields: &[(impl Eq, Type)],
+) -> DBResult<Vec<Value>> {
+    if csv_fields.len() != columns.len() {
+        return Err(DBError::ValidationError(format!(
+            "Record has {} fields, the header has {}",
+            csv_fields.len(),
+            columns.len()
+        )));
+    }
+    let mut values = vec![Value::Null; fields.len()];
+    for (csv_field, &index) in csv_fields.iter().zip(columns) {
+        let field_type = &fields[index].1;
+        values[index] = csv_to_value(csv_field, field_type)
+            .map_err(|e| DBError::ValidationError(format!("Column {}: {}", index + 1, e)))?;
+    }
+    Ok(values)
+}
+
+/// CSV fields are read with a marker for quoting, see `read_csv_record`.
+fn csv_to_value(csv_field: &str, value_type: &Type) -> Result<Value, String> {
+    let text = match csv_field.strip_prefix(QUOTED) {
+        Some(text) => text,
+        None if csv_field.is_empty() => return Ok(Value::Null),
+        None => csv_field,
+    };
+    let invalid = |what: &str| format!("invalid {} {}", what, text);
+    Ok(match &value_type.primitive {
+        PrimitiveType::Int => Value::Int(text.parse().map_err(|_| invalid("integer"))?),
+        PrimitiveType::Timestamp => {
+            Value::Timestamp(text.parse().map_err(|_| invalid("timestamp"))?)
+        }
+        PrimitiveType::Decimal => Value::Decimal(parse_decimal(text)?),
+        PrimitiveType::Float => Value::Float(text.parse().map_err(|_| invalid("float"))?),
+        PrimitiveType::String | PrimitiveType::Enum(_) => Value::String(text.to_owned()),
+        PrimitiveType::Json => Value::Json(text.to_owned()),
+        PrimitiveType::Bytes | PrimitiveType::FixedBytes(_) => Value::Bytes(base64_decode(text)?),
+        PrimitiveType::GeoPoint => {
+            let (lat, lon) = text.split_once(',').ok_or_else(|| invalid("geo point"))?;
+            Value::GeoPoint(
+                lat.trim().parse().map_err(|_| invalid("geo point"))?,
+                lon.trim().parse().map_err(|_| invalid("geo point"))?,
+            )
+        }
+        PrimitiveType::Record(_) => {
+            let json = JsonParser::new(text)
+                .parse_document()
+                .map_err(|e| e.to_string())?;
+            json_to_value(text, json, value_type)?
+        }
+    })
+}
+
+/// Prefixed to quoted CSV fields, so that an empty quoted field can be told apart from a null. It
+/// cannot occur in the input, which is valid UTF-8.
+const QUOTED: &str = "\u{0}\"";
+
+/// Read a CSV record as in RFC 4180, which may span lines if a quoted field contains line breaks.
+/// Returns the number of its first line and its fields, with `QUOTED` prefixed to quoted fields,
+/// or `None` at the end of the input.
+fn read_csv_record(
+    reader: &mut impl BufRead,
+    line_num: &mut u64,
+) -> DBResult<Option<(u64, Vec<String>)>> {
+    let mut text = String::new();
+    let first_line = *line_num + 1;
+    loop {
+        let mut line = vec![];
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            if text.is_empty() {
+                return Ok(None);
+            }
+            return Err(DBError::ValidationError(format!(
+                "Unterminated quoted field in the record on line {}",
+                first_line
+            )));
+        }
+        *line_num += 1;
+        let line = String::from_utf8(line).map_err(|_| {
+            DBError::ValidationError(format!("Line {} is not valid UTF-8", line_num))
+        })?;
+        text.push_str(&line);
+        // A record ends at a line break outside quotes
+        if text.matches('"').count().is_multiple_of(2) {
+            break;
+        }
+    }
+
+    let record = text.strip_suffix('\n').unwrap_or(&text);
+    let record = record.strip_suffix('\r').unwrap_or(record);
+    if record.is_empty() {
+        return read_csv_record(reader, line_num);
+    }
+
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut chars = record.chars().peekable();
+    let mut in_quotes = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if in_quotes => in_quotes = false,
+            '"' if field.is_empty() => {
+                in_quotes = true;
+                field.push_str(QUOTED);
+            }
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    Ok(Some((first_line, fields)))
+}
+
+fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let digit = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let trimmed = text.trim_end_matches('=');
+    if !text.len().is_multiple_of(4) || text.len() - trimmed.len() > 2 {
+        return Err(format!("invalid base64 {}", text));
+    }
+    let mut bytes = Vec::with_capacity(trimmed.len() * 3 / 4);
+    for chunk in trimmed.as_bytes().chunks(4) {
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let d = digit(c).ok_or_else(|| format!("invalid base64 {}", text))?;
+            n |= (d as u32) << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+/// A parsed JSON value. Numbers are kept as their text, so that integers and decimals are read
+/// exactly. Values carry the byte range of their text, for storing them as JSON values. No field
+/// type reads booleans, so only their text is kept.
+enum JsonValue {
+    Null,
+    Bool(Range<usize>),
+    Number(String, Range<usize>),
+    String(String, Range<usize>),
+    Array(Vec<JsonValue>, Range<usize>),
+    Object(Vec<(String, JsonValue)>, Range<usize>),
+}
+
+impl JsonValue {
+    fn kind(&self) -> &'static str {
+        match self {
+            JsonValue::Null => "null",
+            JsonValue::Bool(..) => "a boolean",
+            JsonValue::Number(..) => "a number",
+            JsonValue::String(..) => "a string",
+            JsonValue::Array(..) => "an array",
+            JsonValue::Object(..) => "an object",
+        }
+    }
+
+    fn span(&self) -> Range<usize> {
+        match self {
+            JsonValue::Null => 0..0,
+            JsonValue::Bool(span)
+            | JsonValue::Number(_, span)
+            | JsonValue::String(_, span)
+            | JsonValue::Array(_, span)
+            | JsonValue::Object(_, span) => span.clone(),
+        }
+    }
+}
+
+/// A recursive descent parser of JSON as in RFC 8259.
+struct JsonParser<'a> {
+    text: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+/// Deeper documents are rejected rather than risking the stack.
+const MAX_JSON_DEPTH: usize = 128;
+
+impl<'a> JsonParser<'a> {
+    fn new(text: &'a str) -> Self {
+        JsonParser {
+            text,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    fn error(&self, message: &str) -> DBError {
+        DBError::ValidationError(format!(
+            "Invalid JSON at column {}: {}",
+            self.pos + 1,
+            message
+        ))
+    }
+
+    fn parse_document(&mut self) -> DBResult<JsonValue> {
+        let value = self.parse_value()?;
+        self.skip_whitespace();
+        if self.pos < self.text.len() {
+            return Err(self.error("unexpected trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> DBResult<()> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected '{}'", c as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> DBResult<JsonValue> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let literal = |parser: &mut Self, word: &str| {
+            if parser.text[parser.pos..].starts_with(word) {
+                parser.pos += word.len();
+                Ok(())
+            } else {
+                Err(parser.error("unexpected literal"))
+            }
+        };
+        match self.peek() {
+            Some(b'n') => literal(self, "null").map(|_| JsonValue::Null),
+            Some(b't') => literal(self, "true").map(|_| JsonValue::Bool(start..self.pos)),
+            Some(b'f') => literal(self, "false").map(|_| JsonValue::Bool(start..self.pos)),
+            Some(b'"') => {
+                let s = self.parse_string()?;
+                Ok(JsonValue::String(s, start..self.pos))
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                while matches!(
+                    self.peek(),
+                    Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+                ) {
+                    self.pos += 1;
+                }
+                let number = &self.text[start..self.pos];
+                if number.parse::<f64>().is_err() {
+                    return Err(self.error("invalid number"));
+                }
+                Ok(JsonValue::Number(number.to_owned(), start..self.pos))
+            }
+            Some(open @ (b'[' | b'{')) => {
+                self.depth += 1;
+                if self.depth > MAX_JSON_DEPTH {
+                    return Err(self.error("document is nested too deeply"));
+                }
+                self.pos += 1;
+                let close = if open == b'[' { b']' } else { b'}' };
+                let mut items = vec![];
+                let mut members = vec![];
+                self.skip_whitespace();
+                if self.peek() == Some(close) {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        if open == b'[' {
+                            items.push(self.parse_value()?);
+                        } else {
+                            self.skip_whitespace();
+                            if self.peek() != Some(b'"') {
+                                return Err(self.error("expected a member name"));
+                            }
+                            let name = self.parse_string()?;
+                            self.expect(b':')?;
+                            members.push((name, self.parse_value()?));
+                        }
+                        self.skip_whitespace();
+                        match self.peek() {
+                            Some(b',') => self.pos += 1,
+                            Some(c) if c == close => {
+                                self.pos += 1;
+                                break;
+                            }
+                            _ => return Err(self.error("expected ',' or a closing bracket")),
+                        }
+                    }
+                }
+                self.depth -= 1;
+                Ok(if open == b'[' {
+                    JsonValue::Array(items, start..self.pos)
+                } else {
+                    JsonValue::Object(members, start..self.pos)
+                })
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    /// Parse a string starting at its opening quote.
+    fn parse_string(&mut self) -> DBResult<String> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let rest = &self.text[self.pos..];
+            let Some(c) = rest.chars().next() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = self
+                        .peek()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    match escape {
+                        b'"' => s.push('"'),
+                        b'\\' => s.push('\\'),
+                        b'/' => s.push('/'),
+                        b'b' => s.push('\u{8}'),
+                        b'f' => s.push('\u{c}'),
+                        b'n' => s.push('\n'),
+                        b'r' => s.push('\r'),
+                        b't' => s.push('\t'),
+                        b'u' => s.push(self.parse_unicode_escape()?),
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                c if (c as u32) < 0x20 => return Err(self.error("control character in string")),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> DBResult<u32> {
+        let hex = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        let code =
+            u32::from_str_radix(hex, 16).map_err(|_| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    /// Parse the digits of a `\u` escape, combining surrogate pairs.
+    fn parse_unicode_escape(&mut self) -> DBResult<char> {
+        let high = self.parse_hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !self.text[self.pos..].starts_with("\\u") {
+                return Err(self.error("unpaired surrogate"));
+            }
+            self.pos += 2;
+            let low = self.parse_hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_parsing() {
+        assert_eq!(base64_decode("Zm9vYmFy").unwrap(), b"foobar");
+        assert_eq!(base64_decode("Zg==").unwrap(), b"f");
+        assert!(base64_decode("Zg=").is_err());
+        assert!(base64_decode("Z!==").is_err());
+
+        let mut reader = io::Cursor::new("a,\"b,\"\"c\"\"\nd\",\n\n\"\",x\r\n");
+        let mut line_num = 0;
+        assert_eq!(
+            read_csv_record(&mut reader, &mut line_num).unwrap(),
+            Some((
+                1,
+                vec![
+                    "a".to_owned(),
+                    format!("{}b,\"c\"\nd", QUOTED),
+                    String::new()
+                ]
+            ))
+        );
+        assert_eq!(
+            read_csv_record(&mut reader, &mut line_num).unwrap(),
+            Some((4, vec![QUOTED.to_owned(), "x".to_owned()]))
+        );
+        assert_eq!(read_csv_record(&mut reader, &mut line_num).unwrap(), None);
+
+        let text = r#" {"a": [1, -2.5e3, "é😀\n"], "b": {"c": null}} "#;
+        let JsonValue::Object(members, _) = JsonParser::new(text).parse_document().unwrap() else {
+            panic!("Expected an object");
+        };
+        let JsonValue::Array(items, span) = &members[0].1 else {
+            panic!("Expected an array");
+        };
+        assert_eq!(&text[span.clone()], r#"[1, -2.5e3, "é😀\n"]"#);
+        assert!(matches!(&items[1], JsonValue::Number(n, _) if n == "-2.5e3"));
+        assert!(matches!(&items[2], JsonValue::String(s, _) if s == "é😀\n"));
+        assert!(matches!(&members[1].1, JsonValue::Object(..)));
+
+        for invalid in ["{", r#"{"a" 1}"#, "[1,]", r#""\x""#, "{} x", "01x", "tru"] {
+            assert!(
+                JsonParser::new(invalid).parse_document().is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use super::*;
+use std::io::BufRead;
+
+const INDEX_DUMP_VERSION: u8 = 1;
+
+/// A snapshot of the in-memory indexes, written by `DB::dump_indexes` and read back by `IndexDump::load`.
+///
+/// The dump is a text file where each index starts with a `primary <field>` or `secondary <field>` line,
+/// followed by one indented line per key: `<key> -> <position> ...`, where the positions are the log
+/// positions of the records that the index points to. Keys are written as `null`, `int:<i64>`,
+/// `decimal:<decimal>`, `string:<quoted string>`, `timestamp:<micros>` or `float:<f64>`. Keys of composite
+/// indexes are written as `record:(<key> <key> ...)`, with one key per indexed field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexDump {
+    pub indexes: Vec<DumpedIndex>,
+}
+
+/// The contents of a single index in an `IndexDump`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpedIndex {
+    /// The indexed field, or the list of fields of a composite index, formatted with `Debug`, or
+    /// the name of a computed index.
+    pub field: String,
+    pub primary: bool,
+    /// The keys of the index in order, with the positions of the records each key points to.
+    /// The keys of a composite index are `Value::Record`s of one value per indexed field.
+    pub entries: Vec<(Value, Vec<LogPosition>)>,
+}
+
+impl IndexDump {
+    pub fn write(&self, writer: &mut impl Write) -> DBResult<()> {
+        writeln!(writer, "log_db index dump {}", INDEX_DUMP_VERSION)?;
+
+        for index in &self.indexes {
+            let kind = if index.primary {
+                "primary"
+            } else {
+                "secondary"
+            };
+            writeln!(writer, "{} {}", kind, index.field)?;
+
+            for (key, positions) in &index.entries {
+                let positions: Vec<String> = positions.iter().map(|p| p.to_string()).collect();
+                writeln!(writer, "  {} -> {}", format_key(key)?, positions.join(" "))?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(reader: impl Read) -> DBResult<IndexDump> {
+        let mut lines = io::BufReader::new(reader).lines().enumerate();
+
+        let header = match lines.next() {
+            Some((_, line)) => line?,
+            None => return Err(invalid_dump(1, "empty dump")),
+        };
+        if header != format!("log_db index dump {}", INDEX_DUMP_VERSION) {
+            return Err(invalid_dump(1, "unsupported header"));
+        }
+
+        let mut indexes: Vec<DumpedIndex> = vec![];
+        for (line_index, line) in lines {
+            let line = line?;
+            let line_num = line_index + 1;
+
+            if let Some(entry) = line.strip_prefix("  ") {
+                let index = indexes
+                    .last_mut()
+                    .ok_or_else(|| invalid_dump(line_num, "entry outside of an index"))?;
+
+                let (key, rest) =
+                    parse_key(entry).ok_or_else(|| invalid_dump(line_num, "malformed key"))?;
+                let positions = rest
+                    .strip_prefix(" ->")
+                    .ok_or_else(|| invalid_dump(line_num, "missing positions"))?
+                    .split_whitespace()
+                    .map(|position| position.parse())
+                    .collect::<DBResult<Vec<LogPosition>>>()?;
+
+                index.entries.push((key, positions));
+            } else if let Some(field) = line.strip_prefix("primary ") {
+                indexes.push(DumpedIndex {
+                    field: field.to_owned(),
+                    primary: true,
+                    entries: vec![],
+                });
+            } else if let Some(field) = line.strip_prefix("secondary ") {
+                indexes.push(DumpedIndex {
+                    field: field.to_owned(),
+                    primary: false,
+                    entries: vec![],
+                });
+            } else if !line.is_empty() {
+                return Err(invalid_dump(line_num, "unknown line"));
+            }
+        }
+
+        Ok(IndexDump { indexes })
+    }
+}
+
+fn invalid_dump(line_num: usize, reason: &str) -> DBError {
+    DBError::ValidationError(format!(
+        "Invalid index dump on line {}: {}",
+        line_num, reason
+    ))
+}
+
+fn format_key(key: &Value) -> DBResult<String> {
+    match key {
+        Value::Null => Ok("null".to_owned()),
+        Value::Int(i) => Ok(format!("int:{}", i)),
+        Value::Decimal(d) => Ok(format!("decimal:{}", d)),
+        Value::String(s) => Ok(format!("string:{:?}", s)),
+        Value::Timestamp(t) => Ok(format!("timestamp:{}", t)),
+        Value::Float(f) => Ok(format!("float:{:?}", f)),
+        Value::GeoPoint(lat, lon) => Ok(format!("geo_point:{:?},{:?}", lat, lon)),
+        Value::Record(values) => {
+            let values = values
+                .iter()
+                .map(format_key)
+                .collect::<DBResult<Vec<String>>>()?;
+            Ok(format!("record:({})", values.join(" ")))
+        }
+        Value::Bytes(_) | Value::Json(_) => Err(DBError::ValidationError(format!(
+            "Index keys cannot be {:?}",
+            key
+        ))),
+    }
+}
+
+/// Parse a key written by `format_key` from the beginning of `s`.
+/// Returns the key and the rest of the string.
+fn parse_key(s: &str) -> Option<(Value, &str)> {
+    if let Some(rest) = s.strip_prefix("null") {
+        return Some((Value::Null, rest));
+    }
+    if let Some(rest) = s.strip_prefix("string:\"") {
+        return parse_quoted(rest).map(|(string, rest)| (Value::String(string), rest));
+    }
+    if let Some(mut rest) = s.strip_prefix("record:(") {
+        let mut values = vec![];
+        loop {
+            rest = rest.trim_start();
+            if let Some(rest) = rest.strip_prefix(')') {
+                return Some((Value::Record(values), rest));
+            }
+            let (value, after) = parse_key(rest)?;
+            values.push(value);
+            rest = after;
+        }
+    }
+
+    let end = s.find([' ', ')']).unwrap_or(s.len());
+    let (token, rest) = s.split_at(end);
+    let value = if let Some(int) = token.strip_prefix("int:") {
+        Value::Int(int.parse().ok()?)
+    } else if let Some(decimal) = token.strip_prefix("decimal:") {
+        Value::Decimal(decimal.parse().ok()?)
+    } else if let Some(float) = token.strip_prefix("float:") {
+        Value::Float(float.parse().ok()?)
+    } else if let Some(timestamp) = token.strip_prefix("timestamp:") {
+        Value::Timestamp(timestamp.parse().ok()?)
+    } else if let Some(point) = token.strip_prefix("geo_point:") {
+        let (lat, lon) = point.split_once(',')?;
+        Value::GeoPoint(lat.parse().ok()?, lon.parse().ok()?)
+    } else {
+        return None;
+    };
+    Some((value, rest))
+}
+
+/// Parse the rest of a string quoted by its `Debug` implementation, after the opening quote.
+fn parse_quoted(s: &str) -> Option<(String, &str)> {
+    let mut string = String::new();
+    let mut chars = s.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &s[i + 1..])),
+            '\\' => {
+                let unescaped = match chars.next()?.1 {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    '0' => '\0',
+                    'u' => {
+                        if chars.next()?.1 != '{' {
+                            return None;
+                        }
+                        let mut hex = String::new();
+                        loop {
+                            match chars.next()?.1 {
+                                '}' => break,
+                                digit => hex.push(digit),
+                            }
+                        }
+                        char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                    }
+                    other => other,
+                };
+                string.push(unescaped);
+            }
+            _ => string.push(c),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_dump_write_load() {
+        let position = |segment_num, index| {
+            format!("{}:{}", segment_num, index)
+                .parse::<LogPosition>()
+                .unwrap()
+        };
+
+        let dump = IndexDump {
+            indexes: vec![
+                DumpedIndex {
+                    field: "Id".to_owned(),
+                    primary: true,
+                    entries: vec![
+                        (Value::Int(-1), vec![position(1, 0)]),
+                        (Value::Int(2), vec![position(2, 5)]),
+                    ],
+                },
+                DumpedIndex {
+                    field: "At".to_owned(),
+                    primary: false,
+                    entries: vec![
+                        (Value::Timestamp(-1_500), vec![position(2, 0)]),
+                        (Value::Float(-0.25), vec![position(2, 1)]),
+                        (Value::Float(f64::NAN), vec![position(2, 2)]),
+                    ],
+                },
+                DumpedIndex {
+                    field: "Name".to_owned(),
+                    primary: false,
+                    entries: vec![
+                        (Value::Null, vec![position(1, 0)]),
+                        (Value::Decimal(Decimal::new(314, 2)), vec![position(1, 1)]),
+                        (
+                            Value::String("tab\t\"quote\" -> ä\u{1}".to_owned()),
+                            vec![position(1, 2), position(2, 5)],
+                        ),
+                    ],
+                },
+                DumpedIndex {
+                    field: "[Name, Id]".to_owned(),
+                    primary: false,
+                    entries: vec![(
+                        Value::Record(vec![Value::String("a) b".to_owned()), Value::Int(3)]),
+                        vec![position(1, 3)],
+                    )],
+                },
+            ],
+        };
+
+        let mut buf = vec![];
+        dump.write(&mut buf).unwrap();
+        assert_eq!(IndexDump::load(&buf[..]).unwrap(), dump);
+
+        assert!(IndexDump::load("not a dump".as_bytes()).is_err());
+    }
+}
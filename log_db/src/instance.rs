@@ -0,0 +1,150 @@
+use super::*;
+use std::time::UNIX_EPOCH;
+
+/// An open database handle, as listed by `DB::who`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceInfo {
+    /// The process that opened the handle. A process may have several handles open.
+    pub pid: u32,
+    /// When the handle was opened.
+    pub opened: SystemTime,
+    /// A summary of the configuration of the handle, e.g. its durability and consistency settings.
+    pub config: String,
+}
+
+/// The registration of an open handle in the instance registry of a data directory. Each handle
+/// has its own file in the `instances` directory, which it holds a shared lock on for as long as it
+/// is open. The file is removed when the handle is dropped. The file of a process that crashed is
+/// left behind, but since its lock was released, `who` recognizes and removes it.
+pub struct Registration {
+    path: PathBuf,
+    _file: fs::File,
+}
+
+impl Registration {
+    pub fn register(data_dir_path: &Path, config: String) -> DBResult<Registration> {
+        let instances_path = data_dir_path.join(INSTANCES_DIRNAME);
+        fs::create_dir_all(&instances_path)?;
+
+        let info = InstanceInfo {
+            pid: std::process::id(),
+            opened: SystemTime::now(),
+            config,
+        };
+
+        // The file is locked before it is moved into place, so it is never seen unlocked
+        let mut tmp_file = fs::NamedTempFile::new_in(&instances_path)?;
+        tmp_file.write_all(info.serialize().as_bytes())?;
+        tmp_file.flush()?;
+        tmp_file.as_file().lock_shared()?;
+
+        let path = instances_path.join(format!("{}-{}", info.pid, Uuid::new_v4()));
+        let file = tmp_file.persist(&path).map_err(|e| e.error)?;
+
+        Ok(Registration { path, _file: file })
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove instance registration {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl InstanceInfo {
+    fn serialize(&self) -> String {
+        let opened = self
+            .opened
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        format!(
+            "pid {}\nopened {}\nconfig {}\n",
+            self.pid, opened, self.config
+        )
+    }
+
+    fn deserialize(contents: &str) -> Option<InstanceInfo> {
+        let mut lines = contents.lines();
+        let pid = lines.next()?.strip_prefix("pid ")?.parse().ok()?;
+        let opened: u64 = lines.next()?.strip_prefix("opened ")?.parse().ok()?;
+        let config = lines.next()?.strip_prefix("config ")?.to_owned();
+
+        Some(InstanceInfo {
+            pid,
+            opened: UNIX_EPOCH + Duration::from_micros(opened),
+            config,
+        })
+    }
+}
+
+/// List the open handles registered in the data directory, oldest first. Registrations left
+/// behind by processes that exited without closing their handles are removed.
+pub fn who(data_dir_path: &Path) -> DBResult<Vec<InstanceInfo>> {
+    let instances_path = data_dir_path.join(INSTANCES_DIRNAME);
+    if !fs::exists(&instances_path)? {
+        return Ok(vec![]);
+    }
+
+    let mut instances = vec![];
+    for entry in fs::read_dir(&instances_path)? {
+        let path = entry?.path();
+        // Registrations that are still being written are temporary files starting with a dot
+        let is_registration = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| !name.starts_with('.'));
+        if !is_registration {
+            continue;
+        }
+
+        let mut file = match READ_MODE.open(&path) {
+            Ok(file) => file,
+            // The handle was closed after the directory was listed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        match file.try_lock_exclusive() {
+            Ok(()) => {
+                debug!("Removing stale instance registration {}", path.display());
+                fs::remove_file(&path).ok();
+                continue;
+            }
+            Err(e) if e.kind() == lock_contended_error().kind() => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        match InstanceInfo::deserialize(&contents) {
+            Some(info) => instances.push(info),
+            None => warn!("Invalid instance registration {}", path.display()),
+        }
+    }
+
+    instances.sort_by_key(|info| (info.opened, info.pid));
+    Ok(instances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_info_serialize_deserialize() {
+        let info = InstanceInfo {
+            pid: 42,
+            opened: UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456),
+            config: "write_durability=Flush read_consistency=Strong".to_owned(),
+        };
+        assert_eq!(InstanceInfo::deserialize(&info.serialize()), Some(info));
+        assert_eq!(InstanceInfo::deserialize("pid x\n"), None);
+    }
+}
@@ -0,0 +1,262 @@
+use super::*;
+
+/// The file operations of record reads and appends, which a backend may submit in batches to save
+/// system calls, see `ConfigBuilder::io_uring`.
+pub trait IoBackend: Send {
+    /// Fill each buffer of `reads` with the bytes at its offset in `file`.
+    fn read_at_batch(&self, file: &fs::File, reads: &mut [(u64, &mut [u8])]) -> io::Result<()>;
+
+    /// Append the bytes of each of `writes` to the end of its file, which is opened in append mode.
+    /// The writes are made in order, so that e.g. a record is written before the row pointing at it.
+    fn append_batch(&self, writes: &[(&fs::File, &[u8])]) -> io::Result<()>;
+}
+
+/// The backend selected with `ConfigBuilder::io_uring`. Standard I/O is used if io_uring is not
+/// enabled, or not available on this platform or kernel.
+pub fn io_backend(io_uring: bool) -> Box<dyn IoBackend> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if io_uring {
+        match uring::UringIo::new() {
+            Ok(backend) => return Box::new(backend),
+            Err(e) => warn!(
+                "Could not set up io_uring, using standard I/O instead: {}",
+                e
+            ),
+        }
+    }
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+    if io_uring {
+        warn!("io_uring requires the io-uring feature on Linux, using standard I/O instead");
+    }
+    Box::new(StdIo)
+}
+
+/// A system call for each read and append.
+pub struct StdIo;
+
+impl IoBackend for StdIo {
+    fn read_at_batch(&self, file: &fs::File, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        for (offset, buf) in reads.iter_mut() {
+            file.read_exact_at(buf, *offset)?;
+        }
+        Ok(())
+    }
+
+    fn append_batch(&self, writes: &[(&fs::File, &[u8])]) -> io::Result<()> {
+        for (mut file, bytes) in writes.iter().copied() {
+            file.write_all(bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring {
+    use super::*;
+    use io_uring::{opcode, squeue, types, IoUring};
+    use std::cell::RefCell;
+    use std::os::fd::AsRawFd;
+
+    /// The most operations submitted at once. Larger batches are submitted in chunks.
+    const RING_ENTRIES: u32 = 64;
+
+    /// Submits the reads of a batch at once, and the appends of a batch as a chain of linked
+    /// writes, so that they take a single system call.
+    pub struct UringIo {
+        ring: RefCell<IoUring>,
+    }
+
+    impl UringIo {
+        pub fn new() -> io::Result<UringIo> {
+            Ok(UringIo {
+                ring: RefCell::new(IoUring::new(RING_ENTRIES)?),
+            })
+        }
+
+        /// Submit `entries`, whose `user_data` are their indexes, and wait for all of them to
+        /// complete. Returns their results in order.
+        ///
+        /// # Safety
+        ///
+        /// The buffers of the entries must be valid until this returns.
+        unsafe fn submit(&self, entries: &[squeue::Entry]) -> io::Result<Vec<i32>> {
+            let mut ring = self.ring.borrow_mut();
+            ring.submission()
+                .push_multiple(entries)
+                .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+
+            // The entries are consumed by the first call that succeeds, so the later calls only
+            // wait. The calls that fail, fail before consuming any entries.
+            let mut results = vec![0; entries.len()];
+            let mut completed = 0;
+            while completed < entries.len() {
+                match ring.submit_and_wait(entries.len() - completed) {
+                    Ok(_) => {}
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::Interrupted
+                                | io::ErrorKind::WouldBlock
+                                | io::ErrorKind::ResourceBusy
+                        ) => {}
+                    Err(e) => return Err(e),
+                }
+                for entry in ring.completion() {
+                    results[entry.user_data() as usize] = entry.result();
+                    completed += 1;
+                }
+            }
+            Ok(results)
+        }
+    }
+
+    impl IoBackend for UringIo {
+        fn read_at_batch(&self, file: &fs::File, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+            // Files in memory have no file descriptor
+            let Some(disk_file) = file.as_disk() else {
+                return StdIo.read_at_batch(file, reads);
+            };
+            let fd = types::Fd(disk_file.as_raw_fd());
+            for chunk in reads.chunks_mut(RING_ENTRIES as usize) {
+                let entries: Vec<squeue::Entry> = chunk
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(i, (offset, buf))| {
+                        opcode::Read::new(
+                            fd,
+                            buf.as_mut_ptr(),
+                            buf.len().min(u32::MAX as usize) as u32,
+                        )
+                        .offset(*offset)
+                        .build()
+                        .user_data(i as u64)
+                    })
+                    .collect();
+                // SAFETY: The buffers are borrowed from `chunk` for the whole call
+                let results = unsafe { self.submit(&entries)? };
+
+                for ((offset, buf), result) in chunk.iter_mut().zip(results) {
+                    if result < 0 {
+                        return Err(io::Error::from_raw_os_error(-result));
+                    }
+                    // A short read is finished with standard I/O, which reports the end of file
+                    let read = result as usize;
+                    if read < buf.len() {
+                        file.read_exact_at(&mut buf[read..], *offset + read as u64)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn append_batch(&self, writes: &[(&fs::File, &[u8])]) -> io::Result<()> {
+            let Some(disk_files) = writes
+                .iter()
+                .map(|(file, _)| file.as_disk())
+                .collect::<Option<Vec<_>>>()
+            else {
+                return StdIo.append_batch(writes);
+            };
+            for (chunk, disk_files) in writes
+                .chunks(RING_ENTRIES as usize)
+                .zip(disk_files.chunks(RING_ENTRIES as usize))
+            {
+                let entries: Vec<squeue::Entry> = chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, bytes))| {
+                        // The offset -1 writes at the file position, which is the end of the file
+                        // in append mode
+                        let entry = opcode::Write::new(
+                            types::Fd(disk_files[i].as_raw_fd()),
+                            bytes.as_ptr(),
+                            bytes.len().min(u32::MAX as usize) as u32,
+                        )
+                        .offset(u64::MAX)
+                        .build()
+                        .user_data(i as u64);
+                        if i + 1 < chunk.len() {
+                            entry.flags(squeue::Flags::IO_LINK)
+                        } else {
+                            entry
+                        }
+                    })
+                    .collect();
+                // SAFETY: The buffers are borrowed from `writes` for the whole call
+                let results = unsafe { self.submit(&entries)? };
+
+                // A short write cancels the writes linked after it, so the rest of the chain is
+                // written with standard I/O in order
+                let mut cancelled = false;
+                for ((mut file, bytes), result) in chunk.iter().copied().zip(results) {
+                    if cancelled {
+                        file.write_all(bytes)?;
+                        continue;
+                    }
+                    if result < 0 {
+                        return Err(io::Error::from_raw_os_error(-result));
+                    }
+                    let written = result as usize;
+                    if written < bytes.len() {
+                        file.write_all(&bytes[written..])?;
+                        cancelled = true;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_backend(backend: &dyn IoBackend) {
+        let dir = tempfile::tempdir().unwrap();
+        let open = |name: &str| APPEND_MODE.clone().create(true).open(dir.path().join(name));
+        let (data_file, metadata_file) = (open("data").unwrap(), open("metadata").unwrap());
+
+        let records: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; i as usize + 1]).collect();
+        let mut writes: Vec<(&fs::File, &[u8])> = vec![];
+        for record in &records {
+            writes.push((&data_file, record));
+            writes.push((&metadata_file, &record[..1]));
+        }
+        backend.append_batch(&writes).unwrap();
+        assert_eq!(metadata_file.metadata().unwrap().len(), 100);
+
+        let mut offset = 0;
+        let mut expected = vec![];
+        for record in &records {
+            expected.push((offset, record.clone()));
+            offset += record.len() as u64;
+        }
+        let mut bufs: Vec<Vec<u8>> = records.iter().map(|record| vec![0; record.len()]).collect();
+        let mut reads: Vec<(u64, &mut [u8])> = expected
+            .iter()
+            .zip(bufs.iter_mut())
+            .map(|((offset, _), buf)| (*offset, buf.as_mut_slice()))
+            .collect();
+        backend.read_at_batch(&data_file, &mut reads).unwrap();
+        assert_eq!(bufs, records);
+
+        // Reads past the end of the file are short
+        let mut buf = [0; 8];
+        let err = backend
+            .read_at_batch(&data_file, &mut [(offset - 4, &mut buf)])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_std_io() {
+        check_backend(&StdIo);
+    }
+
+    #[test]
+    fn test_configured_io() {
+        // io_uring if the feature is enabled and the kernel allows it
+        check_backend(io_backend(true).as_ref());
+    }
+}
@@ -0,0 +1,252 @@
+use super::*;
+
+/// The metadata rows of a batch of records being appended to the active segment, written to the
+/// journal file before the rows are appended to the metadata file.
+///
+/// A batch becomes visible when its rows are appended, so a crash in the middle of appending them
+/// could leave only some of the records of the batch in the log. The journal holds all of the rows
+/// until they have been appended, and `recover` completes an interrupted append the next time the
+/// exclusive lock is taken. Records without rows are never read, so the data file needs no journal.
+/// The journal is serialized as `[segment number][metadata start][rows length][rows][checksum]`,
+/// with `[commit marker length][commit marker]` before the checksum if there is a commit marker.
+pub struct JournalEntry {
+    pub segment_num: u16,
+    /// The length of the metadata file before the rows are appended
+    pub metadata_start: u64,
+    pub rows: Vec<u8>,
+    /// The path of the commit marker of the transaction the rows belong to, relative to the data
+    /// directory. The rows are recovered only if the marker exists, see `Transaction::commit`.
+    pub commit_marker: Option<PathBuf>,
+}
+
+const JOURNAL_HEADER_SIZE: usize = 2 + 8 + 8;
+
+impl JournalEntry {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(JOURNAL_HEADER_SIZE + self.rows.len() + 4);
+        bytes.extend(self.segment_num.to_be_bytes());
+        bytes.extend(self.metadata_start.to_be_bytes());
+        bytes.extend((self.rows.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.rows);
+        if let Some(commit_marker) = &self.commit_marker {
+            let commit_marker = commit_marker.to_string_lossy();
+            bytes.extend((commit_marker.len() as u16).to_be_bytes());
+            bytes.extend(commit_marker.as_bytes());
+        }
+        bytes.extend(crc32fast::hash(&bytes).to_be_bytes());
+        bytes
+    }
+
+    /// Returns `None` if the journal was not completely written.
+    fn deserialize(bytes: &[u8]) -> Option<JournalEntry> {
+        if bytes.len() < JOURNAL_HEADER_SIZE + 4 {
+            return None;
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32fast::hash(content).to_be_bytes() != checksum {
+            return None;
+        }
+        let rows_len = u64::from_be_bytes(content[10..18].try_into().unwrap()) as usize;
+        let rows_end = JOURNAL_HEADER_SIZE.checked_add(rows_len)?;
+        if content.len() < rows_end {
+            return None;
+        }
+        let commit_marker = match &content[rows_end..] {
+            [] => None,
+            [len_0, len_1, commit_marker @ ..]
+                if commit_marker.len() == u16::from_be_bytes([*len_0, *len_1]) as usize =>
+            {
+                Some(PathBuf::from(
+                    String::from_utf8(commit_marker.to_vec()).ok()?,
+                ))
+            }
+            _ => return None,
+        };
+        Some(JournalEntry {
+            segment_num: u16::from_be_bytes(content[0..2].try_into().unwrap()),
+            metadata_start: u64::from_be_bytes(content[2..10].try_into().unwrap()),
+            rows: content[JOURNAL_HEADER_SIZE..rows_end].to_vec(),
+            commit_marker,
+        })
+    }
+
+    /// Write the entry to the journal of the data directory, persisted according to `write_durability`.
+    pub fn write(&self, data_dir_path: &Path, write_durability: &WriteDurability) -> DBResult<()> {
+        let mut file = fs::File::create(data_dir_path.join(JOURNAL_FILENAME))?;
+        file.write_all(&self.serialize())?;
+        write_durability.persist(&mut file)?;
+        Ok(())
+    }
+
+    /// Remove the journal once its rows have been appended.
+    pub fn clear(data_dir_path: &Path) -> DBResult<()> {
+        match fs::remove_file(data_dir_path.join(JOURNAL_FILENAME)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Complete the append of a batch that was interrupted by a crash. Must be called while holding
+    /// the exclusive lock, before anything else is appended to the active segment.
+    ///
+    /// A journal left behind means that its rows were appended partially or completely, since the
+    /// journal is removed before the lock is released. A journal that was not completely written
+    /// means that none of its rows were appended. Rows already in the metadata file are left as
+    /// they are, so recovering twice or from a journal whose removal was lost is harmless.
+    /// A journal of a transaction whose commit marker does not exist is discarded, since the
+    /// transaction was not committed and none of its rows were appended.
+    pub fn recover(data_dir_path: &Path) -> DBResult<()> {
+        let bytes = match fs::read(data_dir_path.join(JOURNAL_FILENAME)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let active_target = fs::read_link(data_dir_path.join(ACTIVE_SYMLINK_FILENAME))?;
+        let active_segment_num = parse_segment_number(&active_target)?;
+        let entry = JournalEntry::deserialize(&bytes);
+        let committed = match entry
+            .as_ref()
+            .and_then(|entry| entry.commit_marker.as_ref())
+        {
+            Some(commit_marker) => fs::exists(data_dir_path.join(commit_marker))?,
+            None => true,
+        };
+        match entry {
+            Some(_) if !committed => {
+                debug!("Journal belongs to a transaction that was not committed")
+            }
+            Some(entry) if entry.segment_num == active_segment_num => {
+                let mut metadata_file = fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(data_dir_path.join(active_target))?;
+                entry.apply(&mut metadata_file)?;
+            }
+            Some(entry) => debug!(
+                "Journal of segment {} is stale, the active segment is {}",
+                entry.segment_num, active_segment_num
+            ),
+            None => debug!("Journal was not completely written, nothing was appended"),
+        }
+
+        JournalEntry::clear(data_dir_path)
+    }
+
+    fn apply(&self, metadata_file: &mut fs::File) -> DBResult<()> {
+        let metadata_len = metadata_file.seek(SeekFrom::End(0))?;
+        let rows_end = self.metadata_start + self.rows.len() as u64;
+        if metadata_len < self.metadata_start {
+            return Err(DBError::ConsistencyError(format!(
+                "Journal starts at {} bytes, but the active metadata file has only {} bytes",
+                self.metadata_start, metadata_len
+            )));
+        }
+
+        if metadata_len >= rows_end {
+            let mut appended = vec![0; self.rows.len()];
+            metadata_file.seek(SeekFrom::Start(self.metadata_start))?;
+            metadata_file.read_exact(&mut appended)?;
+            if appended == self.rows {
+                debug!("Journaled rows were already appended");
+                return Ok(());
+            }
+        }
+
+        warn!(
+            "Completing an interrupted append of {} metadata rows from the journal",
+            self.rows.len() / METADATA_ROW_LENGTH
+        );
+        metadata_file.set_len(self.metadata_start)?;
+        metadata_file.seek(SeekFrom::Start(self.metadata_start))?;
+        metadata_file.write_all(&self.rows)?;
+        metadata_file.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let metadata_path = dir.path().join(metadata_filename(1));
+        set_active_segment(dir.path(), 1).unwrap();
+
+        let header = vec![1; METADATA_FILE_HEADER_SIZE];
+        let rows: Vec<u8> = (0..3 * METADATA_ROW_LENGTH as u8).collect();
+        let entry = JournalEntry {
+            segment_num: 1,
+            metadata_start: header.len() as u64,
+            rows: rows.clone(),
+            commit_marker: None,
+        };
+        let complete = [header.clone(), rows.clone()].concat();
+
+        // An append interrupted after the first row is completed
+        fs::write(
+            &metadata_path,
+            &complete[..header.len() + METADATA_ROW_LENGTH],
+        )
+        .unwrap();
+        entry.write(dir.path(), &WriteDurability::Flush).unwrap();
+        JournalEntry::recover(dir.path()).unwrap();
+        assert_eq!(fs::read(&metadata_path).unwrap(), complete);
+        assert!(!fs::exists(dir.path().join(JOURNAL_FILENAME)).unwrap());
+
+        // A completed append is left as it is
+        entry.write(dir.path(), &WriteDurability::Flush).unwrap();
+        JournalEntry::recover(dir.path()).unwrap();
+        assert_eq!(fs::read(&metadata_path).unwrap(), complete);
+
+        // A journal that was not completely written is discarded
+        fs::write(&metadata_path, &header).unwrap();
+        let serialized = entry.serialize();
+        fs::write(
+            dir.path().join(JOURNAL_FILENAME),
+            &serialized[..serialized.len() - 1],
+        )
+        .unwrap();
+        JournalEntry::recover(dir.path()).unwrap();
+        assert_eq!(fs::read(&metadata_path).unwrap(), header);
+        assert!(!fs::exists(dir.path().join(JOURNAL_FILENAME)).unwrap());
+    }
+
+    #[test]
+    fn test_transaction_journal_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let metadata_path = dir.path().join(metadata_filename(1));
+        set_active_segment(dir.path(), 1).unwrap();
+
+        let header = vec![1; METADATA_FILE_HEADER_SIZE];
+        let rows: Vec<u8> = (0..2 * METADATA_ROW_LENGTH as u8).collect();
+        let commit_marker = Path::new(TRANSACTIONS_DIRNAME).join("marker");
+        let entry = JournalEntry {
+            segment_num: 1,
+            metadata_start: header.len() as u64,
+            rows: rows.clone(),
+            commit_marker: Some(commit_marker.clone()),
+        };
+        let serialized = entry.serialize();
+        let deserialized = JournalEntry::deserialize(&serialized).unwrap();
+        assert_eq!(deserialized.rows, rows);
+        assert_eq!(deserialized.commit_marker, Some(commit_marker.clone()));
+
+        // The rows of a transaction without a commit marker are discarded
+        fs::write(&metadata_path, &header).unwrap();
+        entry.write(dir.path(), &WriteDurability::Flush).unwrap();
+        JournalEntry::recover(dir.path()).unwrap();
+        assert_eq!(fs::read(&metadata_path).unwrap(), header);
+        assert!(!fs::exists(dir.path().join(JOURNAL_FILENAME)).unwrap());
+
+        // The rows of a committed transaction are appended
+        fs::create_dir(dir.path().join(TRANSACTIONS_DIRNAME)).unwrap();
+        fs::write(dir.path().join(&commit_marker), []).unwrap();
+        entry.write(dir.path(), &WriteDurability::Flush).unwrap();
+        JournalEntry::recover(dir.path()).unwrap();
+        assert_eq!(fs::read(&metadata_path).unwrap(), [header, rows].concat());
+    }
+}
@@ -1,48 +1,161 @@
 #[macro_use]
 extern crate log;
 
-use fs2::{lock_contended_error, FileExt};
+use fs2::lock_contended_error;
 use once_cell::sync::Lazy;
-use rust_decimal::Decimal;
+use std::any::Any;
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt::Debug;
 use std::fmt::Display;
-use std::fs::{self, metadata, File};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::marker::PhantomData;
 use std::ops::*;
 use std::path::{Path, PathBuf};
 use std::thread;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 use uuid::Uuid;
-
+use vfs::{self as fs, metadata, File};
+
+mod aggregate;
+mod archive;
+mod arena;
+mod backup;
+mod calibration;
+mod cancellation;
 #[macro_use]
 mod common;
+mod compaction_strategy;
+mod compression;
 mod config;
+mod encryption;
 mod engine;
+#[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+mod expirations;
+mod explain;
+mod export;
+mod foreign;
+mod fsck;
+mod geo;
+mod group_commit;
+mod import;
+mod index_dump;
+mod instance;
+mod io_backend;
+mod journal;
 mod lock;
+mod log_encoding;
 mod log_reader_forward;
+mod manifest;
 mod memtable_primary;
 mod memtable_secondary;
+mod migration;
+mod parallel;
+#[cfg(feature = "parquet")]
+mod parquet_export;
+mod projection;
+mod quiesce;
+mod range_stream;
 mod record;
-
-pub use common::{DBError, DBResult, Type, Value};
-pub use config::{ReadConsistency, WriteDurability};
-pub use record::Recordable;
-
+mod retention;
+mod schema;
+mod scrub;
+mod segment_map;
+mod sequence;
+mod size_report;
+#[cfg(feature = "sqlite")]
+mod sqlite_export;
+mod transaction;
+mod transform;
+mod vfs;
+
+pub use aggregate::Aggregate;
+pub use archive::{ArchiveStore, DirectoryArchive};
+pub use arena::{ArenaRecord, RecordArena, ValueRefs};
+pub use calibration::{DurabilityMeasurement, DurabilityReport};
+pub use cancellation::Cancellation;
+pub use common::{
+    CompactionReport, DBError, DBResult, Direction, LogPosition, SegmentSelector, Type, Value,
+    ValueRef, SEGMENT_FORMAT_VERSION,
+};
+pub use compaction_strategy::{
+    CompactionStrategy, SegmentStats, SizeCompaction, TieredCompaction, TimeCompaction,
+    TombstoneCompaction,
+};
+pub use compression::Compression;
+pub use config::{
+    ComputedKey, DeleteMode, FieldCheck, ManifestVerification, MergeOperator, NonIndexedQueries,
+    ReadConsistency, WriteDurability,
+};
+#[cfg(feature = "aes-gcm")]
+pub use encryption::AesGcmProvider;
+pub use encryption::EncryptionProvider;
+#[cfg(feature = "tokio")]
+pub use expirations::Expirations;
+pub use explain::{Query, QueryIndex, QueryPlan};
+pub use export::Format;
+pub use foreign::ForeignSource;
+pub use fsck::{FsckMode, FsckProblem, FsckProblemKind, FsckReport};
+pub use import::{ImportProblem, ImportReport};
+pub use index_dump::{DumpedIndex, IndexDump};
+pub use instance::InstanceInfo;
+pub use log_db_codec as codec;
+pub use log_db_derive::Recordable;
+pub use manifest::{Manifest, ManifestSegment};
+pub use migration::MigrationPlan;
+pub use projection::Projection;
+pub use range_stream::RangeStream;
+pub use record::{FieldValue, RecordMeta, RecordVersion, Recordable, VersionKind, WriteReceipt};
+pub use retention::{RetentionHook, RetentionPolicy, RetentionReport};
+pub use rust_decimal::Decimal;
+pub use schema::{CompatReport, SchemaChange, SchemaMismatch, StoredSchema};
+pub use scrub::{ScrubHook, ScrubProblem, ScrubReport};
+pub use size_report::{SizeBucket, SizeReport};
+#[cfg(feature = "sqlite")]
+pub use sqlite_export::SQLITE_TABLE_NAME;
+pub use transaction::Transaction;
+pub use transform::{Collation, WriteTransform};
+
+use aggregate::*;
+use archive::{open_data_file, ArchivedFile, ArchivedFiles, ArchivedKeys};
 use common::*;
+use compression::{compress_value, expand_values};
 use config::*;
 use engine::*;
+use expirations::ExpiryWatch;
+use foreign::Mount;
+use geo::GeoBox;
+use group_commit::{GroupCommit, PendingSync};
+use instance::Registration;
+use io_backend::IoBackend;
+use journal::JournalEntry;
 use lock::*;
+use log_encoding::LogEncoding;
 use log_reader_forward::*;
 use memtable_primary::PrimaryMemtable;
 use memtable_secondary::SecondaryMemtable;
+use quiesce::*;
 use record::*;
+use scrub::*;
 
 pub struct DB<R: Recordable> {
     engine: Engine<R>,
+    /// Read-only sources mounted with `mount`, in the order they were mounted
+    mounts: Vec<Mount<R>>,
+    /// Registration of this handle for `DB::who`, removed when the handle is dropped.
+    /// Read-only handles are not registered.
+    _registration: Option<Registration>,
+    /// Collections opened with `collection`, each a `DB` of its own record type, by name
+    collections: HashMap<String, Box<dyn Any>>,
+    /// The data directory of an in-memory or ephemeral database, removed once the fields above have closed
+    /// their files
+    _temp_dir: Option<fs::TempDir>,
 }
 
 impl<R: Recordable> DB<R> {
@@ -51,30 +164,221 @@ impl<R: Recordable> DB<R> {
         ConfigBuilder::new()
     }
 
-    fn initialize(config: Config<R>) -> DBResult<DB<R>> {
+    fn initialize(mut config: Config<R>) -> DBResult<DB<R>> {
+        let temp_dir = match (config.in_memory, config.ephemeral) {
+            (true, _) => Some(fs::TempDir::in_memory()?),
+            (false, true) => Some(fs::TempDir::new()?),
+            (false, false) => None,
+        };
+        if let Some(temp_dir) = &temp_dir {
+            config.data_dir = temp_dir.path().to_string_lossy().into_owned();
+        }
+
+        let read_only = config.read_only;
         let engine = Engine::initialize(config)?;
-        Ok(DB { engine })
+        // Read-only handles write nothing, so they are not registered either
+        if read_only {
+            return Ok(DB {
+                engine,
+                mounts: vec![],
+                _registration: None,
+                collections: HashMap::new(),
+                _temp_dir: temp_dir,
+            });
+        }
+        let config_summary = format!(
+            "segment_size={} write_durability={:?} read_consistency={:?}",
+            engine.config.segment_size,
+            engine.config.write_durability,
+            engine.config.read_consistency
+        );
+        let registration =
+            Registration::register(Path::new(&engine.config.data_dir), config_summary)?;
+        Ok(DB {
+            engine,
+            mounts: vec![],
+            _registration: Some(registration),
+            collections: HashMap::new(),
+            _temp_dir: temp_dir,
+        })
+    }
+
+    fn open_backup(config: Config<R>) -> DBResult<DB<R>> {
+        let engine = Engine::open_backup(config)?;
+        Ok(DB {
+            engine,
+            mounts: vec![],
+            _registration: None,
+            collections: HashMap::new(),
+            _temp_dir: None,
+        })
+    }
+
+    fn open_at(config: Config<R>, position: LogPosition) -> DBResult<DB<R>> {
+        let engine = Engine::open_at(config, position)?;
+        Ok(DB {
+            engine,
+            mounts: vec![],
+            _registration: None,
+            collections: HashMap::new(),
+            _temp_dir: None,
+        })
     }
 
     /// Insert a record into the database. If the primary key value already exists,
     /// the existing record will be replaced by the supplied one.
-    pub fn upsert(&mut self, recordable: R) -> DBResult<()> {
+    /// Returns the position and metadata assigned to the stored record.
+    pub fn upsert(&mut self, recordable: R) -> DBResult<WriteReceipt> {
+        self.upsert_record(Record::from(&recordable.into_record()))
+    }
+
+    /// Insert a record whose primary key does not exist yet. If there is a current record with the
+    /// same primary key, `DBError::DuplicateKey` is returned and nothing is written. The check is
+    /// made under the exclusive lock, so of several processes inserting the same key, only one
+    /// succeeds. Soft-deleted and expired records do not count as existing.
+    /// Returns the position and metadata assigned to the stored record.
+    pub fn insert(&mut self, recordable: R) -> DBResult<WriteReceipt> {
         let record = Record::from(&recordable.into_record());
+        debug!("Inserting record: {:?}", record);
+
+        record.validate(&self.engine.config.fields)?;
+        debug!("Record is valid");
+
+        self.engine
+            .with_exclusive_lock(move |engine| engine.insert_record(record))
+    }
+
+    /// Insert or update a record that expires after `ttl`. The expiry field of the record is
+    /// set to the current time plus `ttl`, see `ConfigBuilder::expiry_field`.
+    pub fn upsert_with_ttl(&mut self, recordable: R, ttl: Duration) -> DBResult<WriteReceipt> {
+        let expiry_index = self.engine.expiry_index.ok_or(DBError::ValidationError(
+            "Upserting with a TTL requires an expiry field".to_owned(),
+        ))?;
+
+        let mut record = Record::from(&recordable.into_record());
+        if let Some(value) = record.values.get_mut(expiry_index) {
+            *value = Value::Int(unix_millis(SystemTime::now() + ttl));
+        }
+        self.upsert_record(record)
+    }
+
+    /// Get a stream of the primary keys of records as they expire, so that async applications can
+    /// schedule cleanup work without scanning for expired records, see `ConfigBuilder::expiry_field`.
+    /// Each key is yielded once its expiry time has passed, and only if it has not been deleted or
+    /// rewritten with another expiry time by then. Records that have already expired are not yielded.
+    ///
+    /// The stream keeps a wheel of the expiry times of the current records, which this handle keeps
+    /// up to date as it indexes records: writes by other handles are seen once this handle refreshes
+    /// its indexes, e.g. on its next read. The stream ends when this handle is dropped.
+    ///
+    /// Requires the `tokio` feature.
+    #[cfg(feature = "tokio")]
+    pub fn expirations(&mut self) -> DBResult<Expirations> {
+        let wheel = self
+            .engine
+            .with_shared_lock(|engine| engine.watch_expirations())?;
+        Ok(Expirations::new(wheel))
+    }
+
+    fn upsert_record(&mut self, record: Record) -> DBResult<WriteReceipt> {
         debug!("Upserting record: {:?}", record);
 
         record.validate(&self.engine.config.fields)?;
         debug!("Record is valid");
 
-        self.engine.with_exclusive_lock(move |engine| {
+        let mut receipts = self.engine.with_exclusive_lock(move |engine| {
             engine.batch_upsert_records(std::iter::once(record))
         })?;
 
-        Ok(())
+        Ok(receipts.remove(0))
+    }
+
+    /// Insert or update a record whose values were encoded with `codec::encode_values`, e.g. on a
+    /// device using the codec crate in a `no_std` environment. The values are validated against the
+    /// schema like those of `upsert`, and invalid encodings are rejected with `DBError::ValidationError`.
+    pub fn append_preencoded(&mut self, bytes: &[u8]) -> DBResult<WriteReceipt> {
+        let values = codec::decode_values(bytes)
+            .map_err(|e| DBError::ValidationError(format!("Invalid encoded record: {}", e)))?;
+        self.upsert_record(Record::from(&values))
+    }
+
+    /// Insert or update a record that is already in the format it is stored in the log, e.g. as
+    /// produced by `codec::encode_record` or copied from another log_db log, for ingest pipelines
+    /// that hold their records in that format. The values are validated against the schema like
+    /// those of `upsert`, but the record is appended as is, without decoding the values into a
+    /// `Vec<Value>` and serializing them again. A record flagged as a merge delta is appended like
+    /// with `merge`. The version and timestamp in the bytes are replaced with newly assigned ones.
+    /// If write transforms or checks are configured, the record is decoded to apply them.
+    pub fn append_raw(&mut self, bytes: &[u8]) -> DBResult<WriteReceipt> {
+        let bytes = bytes.to_vec();
+        self.engine
+            .with_exclusive_lock(move |engine| engine.append_raw_record(bytes))
+    }
+
+    /// Append a merge delta for the record with the same primary key. The delta is folded into the
+    /// current version of the record with the merge operator of the configuration when the record is read,
+    /// e.g. to add to a counter or append to a list without reading the record first.
+    /// Compaction folds the deltas into a full record. Returns an error if no merge operator is configured.
+    pub fn merge(&mut self, delta: R) -> DBResult<WriteReceipt> {
+        if self.engine.config.merge_operator.is_none() {
+            return Err(DBError::ValidationError(
+                "Merging requires a merge operator".to_owned(),
+            ));
+        }
+
+        let mut record = Record::from(&delta.into_record());
+        record.delta = true;
+        debug!("Merging delta: {:?}", record);
+
+        record.validate(&self.engine.config.fields)?;
+
+        let mut receipts = self.engine.with_exclusive_lock(move |engine| {
+            engine.batch_upsert_records(std::iter::once(record))
+        })?;
+
+        Ok(receipts.remove(0))
+    }
+
+    /// Insert or update a record, but only if the record currently stored with the same primary key
+    /// has the version `expected_version`, as returned by `get_with_meta`. If `expected_version` is `None`,
+    /// the record must not exist. Otherwise, `DBError::VersionConflict` is returned and nothing is written.
+    ///
+    /// This allows optimistic concurrency control: read a record, modify it and write it back only
+    /// if nobody else has written it in the meantime.
+    pub fn upsert_if_version(
+        &mut self,
+        recordable: R,
+        expected_version: Option<u64>,
+    ) -> DBResult<WriteReceipt> {
+        let record = Record::from(&recordable.into_record());
+        debug!(
+            "Upserting record with expected version {:?}: {:?}",
+            expected_version, record
+        );
+
+        record.validate(&self.engine.config.fields)?;
+        debug!("Record is valid");
+
+        self.engine.with_exclusive_lock(move |engine| {
+            engine.upsert_record_if_version(record, expected_version)
+        })
+    }
+
+    /// Atomically add `delta` to the integer field `field` of the record with the primary key `pk`,
+    /// e.g. `db.increment(&Value::Int(1), &Field::Counter, 1)`. The record is read and written under
+    /// the exclusive lock, so increments from several processes are never lost. A null value counts as zero.
+    /// Returns the new value of the field, or `None` if there is no record with the primary key.
+    pub fn increment(&mut self, pk: &Value, field: &R::Field, delta: i64) -> DBResult<Option<i64>> {
+        debug!("Incrementing {:?} of {:?} by {}", field, pk, delta);
+        self.engine
+            .with_exclusive_lock(|engine| engine.increment_record(pk, field, delta))
     }
 
     /// Insert a batch of records into the database. If the primary key value for a record already exists,
     /// the existing record will be replaced by the supplied one. Records are inserted in the order they are given.
-    pub fn batch_upsert(&mut self, recordables: Vec<R>) -> DBResult<()> {
+    /// Returns the position and metadata assigned to each record, in the same order.
+    /// The batch is atomic: after a crash, either all or none of the records are in the database.
+    pub fn batch_upsert(&mut self, recordables: Vec<R>) -> DBResult<Vec<WriteReceipt>> {
         let records = recordables
             .into_iter()
             .map(|r| Record::from(&r.into_record()))
@@ -87,9 +391,7 @@ impl<R: Recordable> DB<R> {
         debug!("Records are valid");
 
         self.engine
-            .with_exclusive_lock(move |engine| engine.batch_upsert_records(records.into_iter()))?;
-
-        Ok(())
+            .with_exclusive_lock(move |engine| engine.batch_upsert_records(records.into_iter()))
     }
 
     /// Get a record by its primary index value.
@@ -111,19 +413,135 @@ impl<R: Recordable> DB<R> {
             .map(|(_, rec)| R::from_record(rec.values)))
     }
 
+    /// Get all versions of a record that are still stored in the log, oldest first, e.g. for auditing
+    /// changes to a record. This includes deletions and merge deltas. Older versions are dropped
+    /// when the segments they are in are compacted, so the history may be incomplete. This does a full scan over all segments, like `scan_filter`.
+    pub fn history(&mut self, pk: &Value) -> DBResult<Vec<RecordVersion<R>>> {
+        let versions = self
+            .engine
+            .with_shared_lock(|engine| engine.history_records(pk))?;
+
+        Ok(versions
+            .into_iter()
+            .map(|(log_key, record)| RecordVersion {
+                position: LogPosition::from(log_key),
+                meta: record.meta(),
+                kind: if record.tombstone {
+                    VersionKind::Delete
+                } else if record.deleted {
+                    VersionKind::SoftDelete
+                } else if record.delta {
+                    VersionKind::Merge
+                } else {
+                    VersionKind::Write
+                },
+                record: R::from_record(record.values),
+            })
+            .collect())
+    }
+
+    /// Get a record and its engine-managed metadata by its primary index value.
+    /// The metadata contains e.g. the record version, which can be passed to `upsert_if_version`.
+    pub fn get_with_meta(&mut self, value: &Value) -> DBResult<Option<(R, RecordMeta)>> {
+        let recs = self.engine.with_shared_lock(|engine| {
+            engine.batch_find_by_records(
+                // TODO: This clone is only here to appease the borrow checker
+                &engine.config.primary_key.clone(),
+                std::iter::once(value),
+            )
+        })?;
+
+        assert!(recs.len() <= 1);
+
+        Ok(recs.into_iter().next().map(|(_, rec)| {
+            let meta = rec.meta();
+            (R::from_record(rec.values), meta)
+        }))
+    }
+
     /// Get a collection of records based on a field value.
     /// Indexes will be used if they are applicable.
+    /// Records without a value for a nullable field can be found with `Value::Null`, e.g.
+    /// `db.find_by(&Field::Email, &Value::Null)`.
+    ///
+    /// The sources mounted with `mount` are queried too. A record found in the database, or deleted
+    /// from it, shadows the records with the same primary key in the sources, and sources mounted
+    /// earlier shadow the ones mounted later. The records of the database are returned first,
+    /// followed by the records of each source in mount order.
     pub fn find_by(&mut self, field: &R::Field, value: &Value) -> DBResult<Vec<R>> {
+        let mut mounted = vec![];
+        for mount in self.mounts.iter_mut() {
+            let records = mount.source.find_by(field, value)?;
+            mounted.extend(
+                records
+                    .into_iter()
+                    .map(|recordable| Record::from(&recordable.into_record())),
+            );
+        }
+
+        let primary_key_index = self
+            .engine
+            .config
+            .fields
+            .iter()
+            .position(|(field, _)| field == &self.engine.config.primary_key)
+            .expect("Primary key is validated to be in the schema");
+
         let recs = self.engine.with_shared_lock(|engine| {
-            engine.batch_find_by_records(field, std::iter::once(value))
+            let mut recs: Vec<Record> = engine
+                .batch_find_by_records(field, std::iter::once(value))?
+                .into_iter()
+                .map(|(_, rec)| rec)
+                .collect();
+            if mounted.is_empty() {
+                return Ok(recs);
+            }
+
+            // Records of the sources are converted to values to find their primary keys
+            let primary_key_of = |record: &Record| {
+                record
+                    .values
+                    .get(primary_key_index)
+                    .and_then(Value::as_indexable)
+                    .ok_or_else(|| {
+                        DBError::ValidationError(format!(
+                            "Mounted source returned a record without a primary key: {:?}",
+                            record.values
+                        ))
+                    })
+            };
+            let mut seen_keys = recs
+                .iter()
+                .map(primary_key_of)
+                .collect::<DBResult<HashSet<_>>>()?;
+            for record in mounted {
+                let key = primary_key_of(&record)?;
+                if !engine.is_deleted(&key) && seen_keys.insert(key) {
+                    recs.push(record);
+                }
+            }
+            Ok(recs)
         })?;
 
         Ok(recs
             .into_iter()
-            .map(|(_, rec)| R::from_record(rec.values))
+            .map(|rec| R::from_record(rec.values))
             .collect())
     }
 
+    /// Like `batch_find_by`, but the records are read into `arena` instead of being returned, see
+    /// `RecordArena`. The records, tagged like the results of `batch_find_by`, can then be read
+    /// with `arena.records()` until the arena is reused for the next query.
+    pub fn batch_find_by_in(
+        &mut self,
+        arena: &mut RecordArena,
+        field: &R::Field,
+        values: &[Value],
+    ) -> DBResult<()> {
+        self.engine
+            .with_shared_lock(|engine| engine.batch_find_by_into(field, values.iter(), arena))
+    }
+
     /// Get a collection of records based on a sequence of field values.
     /// Indexes will be used if they are applicable.
     /// Returns a vector of pairs where the first value is an index into the given sequence of values,
@@ -143,6 +561,75 @@ impl<R: Recordable> DB<R> {
             .collect())
     }
 
+    /// Get the records whose field has any of the given values, i.e. an `IN` query.
+    /// All values are looked up while holding the lock once. Each record is returned once,
+    /// even if the values contain duplicates.
+    pub fn find_by_any(&mut self, field: &R::Field, values: &[Value]) -> DBResult<Vec<R>> {
+        let recs = self
+            .engine
+            .with_shared_lock(|engine| engine.find_by_any_records(field, values.iter()))?;
+
+        Ok(recs
+            .into_iter()
+            .map(|rec| R::from_record(rec.values))
+            .collect())
+    }
+
+    /// Get the distinct values of an indexed field among the current records, in index order.
+    /// The values are read from the in-memory indexes only, so this is cheap even for large databases.
+    /// If any current record has no value for the field, `Value::Null` is the first value.
+    pub fn distinct(&mut self, field: &R::Field) -> DBResult<Vec<Value>> {
+        self.engine
+            .with_shared_lock(|engine| engine.distinct_values(field))
+    }
+
+    /// Compute an aggregate over the values of a field among the current records, e.g.
+    /// `db.aggregate(&Field::Id, Aggregate::Max)`. Aggregates over indexed fields are computed from the
+    /// in-memory indexes, and `Min` and `Max` only look at the ends of the index. Aggregates over
+    /// other fields require a full scan over all segments, like `scan_filter`.
+    pub fn aggregate(&mut self, field: &R::Field, aggregate: Aggregate) -> DBResult<Value> {
+        self.engine
+            .with_shared_lock(|engine| engine.aggregate_field(field, aggregate))
+    }
+
+    /// Estimate the number of current records from the size of the in-memory primary index, so that
+    /// e.g. monitoring can track the size of the database without reading any records. The
+    /// estimate includes expired records until they are compacted away, and with
+    /// `include_deleted`, soft-deleted records.
+    pub fn estimate_count(&mut self) -> DBResult<u64> {
+        let primary_key = self.engine.config.primary_key.clone();
+        self.estimate_cardinality(&primary_key)
+    }
+
+    /// Estimate the number of distinct values of an indexed field among the current records, e.g.
+    /// `db.estimate_cardinality(&Field::Name)`, from the size of its in-memory index. Null counts as
+    /// a value. The cardinality of the primary key is the number of records, see `estimate_count`.
+    /// Like `estimate_count`, values of expired records are included until they are compacted
+    /// away, while values of soft-deleted records are not.
+    pub fn estimate_cardinality(&mut self, field: &R::Field) -> DBResult<u64> {
+        self.engine
+            .with_shared_lock(|engine| engine.estimate_cardinality(field))
+    }
+
+    /// Get the primary keys of all current records in index order. The keys are read from the
+    /// in-memory primary index only, without reading any records, so this is cheap e.g. for
+    /// checking which keys exist. Expired records are included until they are compacted away.
+    /// See also `keys_range`.
+    pub fn keys(&mut self) -> DBResult<Vec<Value>> {
+        self.keys_range(..)
+    }
+
+    /// Get the primary keys of the current records in `range` in index order, e.g.
+    /// `db.keys_range(&Value::Int(3)..&Value::Int(7))`. Like `keys`, no records are read.
+    pub fn keys_range<B: RangeBounds<Value>>(&mut self, range: B) -> DBResult<Vec<Value>> {
+        self.engine
+            .with_shared_lock(|engine| engine.primary_keys(range))
+    }
+
+    /// Get the records whose indexed field is in `range`, e.g.
+    /// `db.range_by(&Field::Id, &Value::Int(3)..&Value::Int(7))`.
+    /// Records with a null value are skipped, unless the range has a `Value::Null` bound, e.g.
+    /// `..=&Value::Null` to get only them.
     pub fn range_by<B: RangeBounds<Value>>(
         &mut self,
         field: &R::Field,
@@ -158,6 +645,234 @@ impl<R: Recordable> DB<R> {
             .collect())
     }
 
+    /// Get the records whose geo point `field` is within the bounding box from the south-west
+    /// corner `min` to the north-east corner `max`, both given as (latitude, longitude), e.g.
+    /// `db.find_in_bbox(&Field::Location, (60.0, 24.0), (61.0, 25.5))`. The field must be a
+    /// secondary key of type `Type::geo_point`. Points on the edges are in the box. A box whose
+    /// minimum longitude is greater than its maximum crosses the antimeridian.
+    pub fn find_in_bbox(
+        &mut self,
+        field: &R::Field,
+        min: (f64, f64),
+        max: (f64, f64),
+    ) -> DBResult<Vec<R>> {
+        let recs = self
+            .engine
+            .with_shared_lock(|engine| engine.find_in_bbox_records(field, min, max))?;
+
+        Ok(recs
+            .into_iter()
+            .map(|rec| R::from_record(rec.values))
+            .collect())
+    }
+
+    /// Get the records whose key in the computed index `name` equals `value`, see
+    /// `ConfigBuilder::computed_index`.
+    pub fn find_by_computed(&mut self, name: &str, value: &Value) -> DBResult<Vec<R>> {
+        self.range_by_computed(name, (Bound::Included(value), Bound::Included(value)))
+    }
+
+    /// Get the records whose key in the computed index `name` is within `range`. Like with
+    /// `range_by`, records with a null key are skipped unless the range has a `Value::Null` bound.
+    pub fn range_by_computed<B: RangeBounds<Value>>(
+        &mut self,
+        name: &str,
+        range: B,
+    ) -> DBResult<Vec<R>> {
+        let recs = self
+            .engine
+            .with_shared_lock(|engine| engine.range_by_computed_records(name, range))?;
+
+        Ok(recs
+            .into_iter()
+            .map(|rec| R::from_record(rec.values))
+            .collect())
+    }
+
+    /// Get the records whose field is in any of the given ranges, e.g.
+    /// `db.batch_range_by(&Field::Id, &[&Value::Int(0)..&Value::Int(10), &Value::Int(90)..&Value::Int(100)])`.
+    /// All ranges are answered with a single pass over the segments while holding the lock once.
+    /// Returns a vector of pairs where the first value is an index into the given ranges, and the
+    /// second value is the record. The records are grouped by range in the order of the ranges,
+    /// and a record in overlapping ranges is returned once for each of them.
+    pub fn batch_range_by<B: RangeBounds<Value>>(
+        &mut self,
+        field: &R::Field,
+        ranges: &[B],
+    ) -> DBResult<Vec<(usize, R)>> {
+        let recs = self
+            .engine
+            .with_shared_lock(|engine| engine.batch_range_by_records(field, ranges))?;
+
+        Ok(recs
+            .into_iter()
+            .map(|(tag, rec)| (tag, R::from_record(rec.values)))
+            .collect())
+    }
+
+    /// Get the `k` records with the greatest (`Direction::Desc`) or smallest (`Direction::Asc`) values
+    /// of an indexed field, e.g. `db.top_k(&Field::Score, 10, Direction::Desc)`. Records with a null
+    /// value are skipped. The index is walked from the requested end, so records are only read
+    /// from disk until `k` of them have been found.
+    pub fn top_k(&mut self, field: &R::Field, k: usize, direction: Direction) -> DBResult<Vec<R>> {
+        let recs = self
+            .engine
+            .with_shared_lock(|engine| engine.top_k_records(field, k, direction))?;
+
+        Ok(recs
+            .into_iter()
+            .map(|rec| R::from_record(rec.values))
+            .collect())
+    }
+
+    /// Get the record with the smallest value of an indexed field, e.g. the oldest entry by a
+    /// timestamp field with `db.first_by(&Field::CreatedAt)`. Records with a null value are skipped.
+    /// Only the record at the start of the index is read, like `top_k` with `k` of 1.
+    pub fn first_by(&mut self, field: &R::Field) -> DBResult<Option<R>> {
+        Ok(self.top_k(field, 1, Direction::Asc)?.pop())
+    }
+
+    /// Get the record with the greatest value of an indexed field, e.g. the latest entry by a
+    /// timestamp field with `db.last_by(&Field::CreatedAt)`. See `first_by`.
+    pub fn last_by(&mut self, field: &R::Field) -> DBResult<Option<R>> {
+        Ok(self.top_k(field, 1, Direction::Desc)?.pop())
+    }
+
+    /// Like `range_by`, but returns an iterator that reads the records in batches as it is consumed,
+    /// in the order of the index. Use this for ranges too large to hold in memory at once.
+    /// The shared lock is held only while a batch is read, see `RangeStream`.
+    pub fn range_by_stream<B: RangeBounds<Value>>(
+        &mut self,
+        field: &R::Field,
+        range: B,
+    ) -> DBResult<RangeStream<'_, R>> {
+        let config = &self.engine.config;
+        if field != &config.primary_key && !config.secondary_keys.contains(field) {
+            return Err(DBError::ValidationError(
+                "Cannot range_by by non-indexed key".to_owned(),
+            ));
+        }
+
+        let bounds = self.engine.range_bounds(field, range)?;
+        Ok(RangeStream::new(self, field.clone(), bounds))
+    }
+
+    /// Find all records whose fields equal all of the given values, e.g.
+    /// `db.find_by_all(&[(Field::Name, name), (Field::City, city)])`, sorted by primary key. When
+    /// several of the fields are indexed, the entries of their indexes are intersected, starting
+    /// from the most selective one, so that only the records matching all of them are read.
+    /// Fields that are not indexed are compared on the records read. A query without any indexed
+    /// field is refused, or scans the whole log with `NonIndexedQueries::Scan`.
+    pub fn find_by_all(&mut self, conditions: &[(R::Field, Value)]) -> DBResult<Vec<R>> {
+        let recs = self
+            .engine
+            .with_shared_lock(|engine| engine.find_by_all_records(conditions))?;
+
+        Ok(recs
+            .into_iter()
+            .map(|rec| R::from_record(rec.values))
+            .collect())
+    }
+
+    /// Find all records by the leading fields of a composite key, e.g.
+    /// `db.find_by_composite(&[Field::Name, Field::Id], &[Value::String("Bob".to_owned())])`.
+    /// `fields` must be one of the composite keys of the schema, and `prefix` may contain values for
+    /// any number of its leading fields.
+    pub fn find_by_composite(&mut self, fields: &[R::Field], prefix: &[Value]) -> DBResult<Vec<R>> {
+        self.range_by_composite(fields, prefix, ..)
+    }
+
+    /// Like `find_by_composite`, but the field following `prefix` in the composite key must also be
+    /// in `range`. The records are returned in the order of the composite key.
+    pub fn range_by_composite<B: RangeBounds<Value>>(
+        &mut self,
+        fields: &[R::Field],
+        prefix: &[Value],
+        range: B,
+    ) -> DBResult<Vec<R>> {
+        let recs = self
+            .engine
+            .with_shared_lock(|engine| engine.range_by_composite_records(fields, prefix, range))?;
+
+        Ok(recs
+            .into_iter()
+            .map(|rec| R::from_record(rec.values))
+            .collect())
+    }
+
+    /// Describe how `query` would be executed, without running it, e.g.
+    /// `db.explain(&Query::FindBy(Field::Name, Value::String("Bob".to_owned())))`. The plan tells
+    /// which index the query would use, or whether it would scan the whole log, and how many
+    /// records it would read from which segments. Useful for finding out why a query is slow.
+    /// Returns the same errors as the query, e.g. for a query by a non-indexed field.
+    pub fn explain(&mut self, query: &Query<R::Field>) -> DBResult<QueryPlan> {
+        self.engine
+            .with_shared_lock(|engine| engine.explain_query(query))
+    }
+
+    /// Get the records whose newest version was written after `timestamp`, with their metadata,
+    /// in no particular order. The greatest timestamp returned can be passed as `timestamp` on the
+    /// next call to fetch the records modified in the meantime, e.g. for incremental exports.
+    /// Deleted records are not returned, and since timestamps follow the system clock, writes by
+    /// processes with skewed clocks may be missed or returned twice.
+    /// This does a full scan over all segments, like `scan_filter`.
+    pub fn modified_since(&mut self, timestamp: SystemTime) -> DBResult<Vec<(R, RecordMeta)>> {
+        let recs = self.engine.with_shared_lock(|engine| {
+            engine.scan_filter_records(|record| record.timestamp > timestamp)
+        })?;
+
+        Ok(recs
+            .into_iter()
+            .map(|rec| {
+                let meta = rec.meta();
+                (R::from_record(rec.values), meta)
+            })
+            .collect())
+    }
+
+    /// Run the queries in `f` so that they can be interrupted with `cancellation`, e.g.
+    /// `db.with_cancellation(&Cancellation::with_timeout(timeout), |db| db.scan_filter(predicate))`.
+    /// Once the token is cancelled or its deadline passes, the running query returns `DBError::Cancelled`
+    /// at the next segment read. Cancellation only interrupts reads, never a write in progress.
+    pub fn with_cancellation<T>(
+        &mut self,
+        cancellation: &Cancellation,
+        f: impl FnOnce(&mut Self) -> DBResult<T>,
+    ) -> DBResult<T> {
+        let previous = self.engine.cancellation.replace(cancellation.clone());
+        let result = f(self);
+        self.engine.cancellation = previous;
+        result
+    }
+
+    /// Get all records for which `predicate` returns true. The predicate receives the record values
+    /// in schema order. This does a full scan over all segments, so it can be used to query by
+    /// non-indexed fields, but it is a lot slower than the index-based queries.
+    /// Only the newest version of each record is considered, and deleted records are skipped.
+    pub fn scan_filter(&mut self, mut predicate: impl FnMut(&[Value]) -> bool) -> DBResult<Vec<R>> {
+        let recs = self.engine.with_shared_lock(|engine| {
+            engine.scan_filter_records(|record| predicate(&record.values))
+        })?;
+
+        Ok(recs
+            .into_iter()
+            .map(|rec| R::from_record(rec.values))
+            .collect())
+    }
+
+    /// Like `scan_filter`, but the matching records are read into `arena` instead of being
+    /// returned, see `RecordArena`. The records can then be read with `arena.records()` until the
+    /// arena is reused for the next query. The predicate sees the values of each record as
+    /// `ValueRef`s borrowing from the arena, so records that do not match are never copied out.
+    pub fn scan_filter_in(
+        &mut self,
+        arena: &mut RecordArena,
+        mut predicate: impl FnMut(&ArenaRecord) -> bool,
+    ) -> DBResult<()> {
+        self.engine
+            .with_shared_lock(|engine| engine.scan_filter_into(&mut predicate, arena))
+    }
+
     /// Delete records by a field value.
     /// E.g. `db.delete_by(Field::Name, "John")`, assuming `Field` is the DB field type and `Field::Name` is secondary indexed.
     /// Returns a vector of deleted records. If no records were deleted, the vector will be empty.
@@ -175,7 +890,24 @@ impl<R: Recordable> DB<R> {
             .collect())
     }
 
+    /// Delete the records whose field has any of the given values, writing all tombstones while
+    /// holding the lock once. Returns the number of records deleted for each value, in the order of
+    /// `values`. A record matched by duplicate values is counted for the first of them.
+    /// Like `batch_upsert`, the deletes are atomic.
+    pub fn batch_delete_by(&mut self, field: &R::Field, values: &[Value]) -> DBResult<Vec<usize>> {
+        let recs = self
+            .engine
+            .with_exclusive_lock(|engine| engine.batch_delete_by_field(field, values.iter()))?;
+
+        let mut counts = vec![0; values.len()];
+        for (tag, _) in recs {
+            counts[tag] += 1;
+        }
+        Ok(counts)
+    }
+
     /// Delete record by primary key.
+    /// With `DeleteMode::Soft`, the record is only flagged as deleted, see `undelete` and `purge`.
     pub fn delete(&mut self, pk: &Value) -> DBResult<Option<R>> {
         let recs = self.engine.with_exclusive_lock(|engine| {
             engine
@@ -191,8 +923,43 @@ impl<R: Recordable> DB<R> {
             .map(|rec| R::from_record(rec.values)))
     }
 
+    /// Restore a record that was deleted with `DeleteMode::Soft`. The record is written again as
+    /// a new version. Returns the restored record, or `None` if there is no soft-deleted record
+    /// with the primary key.
+    pub fn undelete(&mut self, pk: &Value) -> DBResult<Option<R>> {
+        let rec = self
+            .engine
+            .with_exclusive_lock(|engine| engine.undelete_record(pk))?;
+
+        Ok(rec.map(|rec| R::from_record(rec.values)))
+    }
+
+    /// Delete a record for good with a tombstone, whether it is soft-deleted or not.
+    /// Returns the purged record, if any.
+    pub fn purge(&mut self, pk: &Value) -> DBResult<Option<R>> {
+        let rec = self
+            .engine
+            .with_exclusive_lock(|engine| engine.purge_record(pk))?;
+
+        Ok(rec.map(|rec| R::from_record(rec.values)))
+    }
+
+    /// Run the queries in `f` so that they also return records deleted with `DeleteMode::Soft`,
+    /// e.g. `db.include_deleted(|db| db.get(&pk))`. Soft-deleted records have `RecordMeta::deleted`
+    /// set. This applies to `get`, the `find_by` variants, `range_by`, `keys` and the scans. Soft-deleted
+    /// records are not in the secondary indexes, so queries by other fields than the primary key
+    /// read all of them.
+    pub fn include_deleted<T>(&mut self, f: impl FnOnce(&mut Self) -> DBResult<T>) -> DBResult<T> {
+        let previous = std::mem::replace(&mut self.engine.include_deleted, true);
+        let result = f(self);
+        self.engine.include_deleted = previous;
+        result
+    }
+
     /// Check if there are any pending tasks and do them. Tasks include:
-    /// - Rotating the active log file if it has reached capacity and compacting it.
+    /// - Rotating the active log file and compacting it when the compaction strategy says so,
+    ///   by default once it has reached capacity. See `ConfigBuilder::compaction_strategy`.
+    /// - Compacting and merging the sealed segments that the compaction strategy selects.
     ///
     /// This function should be called periodically to ensure that the database remains in an optimal state.
     /// Note that this function is synchronous and may block for a relatively long time.
@@ -203,11 +970,435 @@ impl<R: Recordable> DB<R> {
             .with_exclusive_lock(|engine| engine.do_maintenance_tasks())
     }
 
+    /// Verify the sealed segment after the one verified last by this handle, wrapping around to
+    /// the first one. The checksums of the segment files are checked against the manifest, and each
+    /// record is checked to decode into values that match the schema. The files are read at the
+    /// rate configured with `ConfigBuilder::scrub_rate`, without blocking writes.
+    /// Returns `None` if there are no sealed segments. See also `run_scrubber`.
+    pub fn scrub_next_segment(&mut self) -> DBResult<Option<ScrubReport>> {
+        self.engine.scrub_next_segment()
+    }
+
+    /// Verify segment `segment_num` under a shared lock: each metadata row and each record is
+    /// checked against its checksum, each record is decoded and validated against the schema, and
+    /// a sealed segment is checked against the manifest. Unlike the scrubber, this also verifies
+    /// the active segment, and reads at full speed. Damaged rows and records are reported with
+    /// their row numbers in the returned report. Segments written before metadata rows had
+    /// checksums are only checked by decoding their records.
+    pub fn verify_segment(&mut self, segment_num: u16) -> DBResult<ScrubReport> {
+        self.engine
+            .with_shared_lock(|engine| engine.verify_segment(segment_num))
+    }
+
+    /// Verify every segment with `verify_segment` under a single shared lock, returning a report
+    /// for each segment in order.
+    pub fn verify_all(&mut self) -> DBResult<Vec<ScrubReport>> {
+        self.engine
+            .with_shared_lock(|engine| engine.verify_all_segments())
+    }
+
+    /// Continuously verify the sealed segments with `scrub_next_segment` until `cancellation` is
+    /// cancelled, so that silent corruption, e.g. bit rot, is found before a query reads the
+    /// corrupted records. Problems are reported to the hook configured with
+    /// `ConfigBuilder::scrub_hook` as they are found, and the problems found during the whole run
+    /// are returned once it is cancelled.
+    ///
+    /// The scrubber blocks the calling thread, so it is meant to be run in a thread of its own,
+    /// with a handle of its own, e.g.
+    /// `thread::spawn(move || DB::<R>::configure().data_dir(dir).initialize()?.run_scrubber(&token))`.
+    pub fn run_scrubber(&mut self, cancellation: &Cancellation) -> DBResult<Vec<ScrubProblem>> {
+        let mut problems = vec![];
+        let result: DBResult<()> = self.with_cancellation(cancellation, |db| loop {
+            match db.engine.scrub_next_segment()? {
+                Some(report) => problems.extend(report.problems),
+                None => {
+                    cancellation.check()?;
+                    thread::sleep(SCRUB_IDLE_INTERVAL);
+                }
+            }
+        });
+
+        match result {
+            Ok(()) | Err(DBError::Cancelled) => Ok(problems),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Ask all other handles of the data directory, in this and other processes, to stop writing
+    /// until `resume` is called or this handle is dropped, e.g. so that a backup, an upgrade or a
+    /// move of the data directory can proceed without racing live writers. Writes, compactions and
+    /// maintenance tasks of other handles wait for the quiesce to be lifted before they start, and
+    /// can be given up with `with_cancellation`. Reads are not affected, and this handle can still
+    /// write. Returns once the writes that were already in progress have finished.
+    ///
+    /// Returns `DBError::LockRequestError` if another handle has already quiesced the data directory.
+    pub fn quiesce(&mut self) -> DBResult<()> {
+        self.engine.quiesce()
+    }
+
+    /// Lift the quiesce requested with `quiesce`, letting other handles write again. Does nothing if
+    /// this handle has not quiesced the data directory.
+    pub fn resume(&mut self) {
+        self.engine.quiesce_flag = None;
+    }
+
+    /// The data directory of the database, which is a temporary directory for ephemeral databases
+    /// and a virtual directory that is not on disk for in-memory databases.
+    pub fn data_dir(&self) -> &Path {
+        Path::new(&self.engine.config.data_dir)
+    }
+
+    /// Get the total size of the files in the data directory in bytes, as counted against the
+    /// quota set with `ConfigBuilder::max_disk_bytes`.
+    pub fn disk_usage(&mut self) -> DBResult<u64> {
+        data_dir_size(Path::new(&self.engine.config.data_dir))
+    }
+
+    /// Compact the selected segments regardless of their size and return a report for each compacted segment.
+    /// Sealed segments are rewritten to contain only records that have not been superseded by newer writes.
+    /// If the active segment is selected and it is not empty, it is compacted and rotated.
+    ///
+    /// This is useful for reclaiming space during a maintenance window. Like `do_maintenance_tasks`,
+    /// the database will be exclusively locked for the duration of the compaction.
+    pub fn compact(&mut self, selector: SegmentSelector) -> DBResult<Vec<CompactionReport>> {
+        self.engine
+            .with_exclusive_lock(|engine| engine.compact_segments(selector))
+    }
+
+    /// Merge consecutive sealed segments into one and return a report of the merge. The records
+    /// are compacted as with `compact`, and the merged segment takes the place of the last of the
+    /// segments, whose number it keeps, while the other segments are removed. Tombstones are dropped
+    /// if the first segment of the database is merged, since they no longer shadow anything.
+    ///
+    /// Merging bounds the number of segments, each of which adds to the cost of scans and index
+    /// rebuilds. See `TieredCompaction` for merging as part of `do_maintenance_tasks`.
+    pub fn merge_segments(&mut self, segment_nums: &[u16]) -> DBResult<CompactionReport> {
+        self.engine
+            .with_exclusive_lock(|engine| engine.merge_segments(segment_nums))
+    }
+
+    /// Rewrite every segment to the schema of `N` as described by `plan`: fields can be dropped,
+    /// renamed, reordered and backfilled with defaults. Every record, including superseded versions
+    /// and tombstones, is mapped and validated against the new schema. The segments are written to
+    /// new files that replace the old ones only once all records have been mapped, like in
+    /// compaction, so on error the log is left as it was.
+    ///
+    /// This handle is consumed, since its schema no longer matches the log. Reopen the data
+    /// directory as `DB<N>` afterwards. Other handles of the data directory must be closed before
+    /// migrating.
+    pub fn migrate<N: Recordable>(mut self, plan: MigrationPlan<R, N>) -> DBResult<()> {
+        let sources = plan.resolve()?;
+        let new_schema = N::schema();
+        let encoding = LogEncoding::from_schema(&new_schema, false)
+            .with_value_compression(self.engine.config.compress_values_over);
+        let stored_schema =
+            StoredSchema::from_schema(&new_schema, &N::primary_key(), &N::secondary_keys());
+        self.engine.with_exclusive_lock(|engine| {
+            engine.migrate_segments(
+                |record| migration::migrate_record(record, &sources, &new_schema),
+                &encoding,
+                &stored_schema,
+            )
+        })
+    }
+
+    /// Get the position at the end of the log, i.e. the position that the next write will be stored at.
+    /// All writes made so far, also by other processes, have a smaller position.
+    pub fn end_position(&mut self) -> DBResult<LogPosition> {
+        self.engine.with_shared_lock(|engine| engine.end_position())
+    }
+
+    /// Measure the write throughput and latency of each `WriteDurability` mode on the disk of the
+    /// data directory, by doing `writes` writes of `record_size` bytes with each mode.
+    /// Use the report to choose a durability mode based on the actual hardware.
+    ///
+    /// The measurement writes to temporary files in the data directory, which are removed afterwards.
+    /// Records of the database are not touched, and the database is not locked.
+    pub fn calibrate_durability(
+        &mut self,
+        writes: usize,
+        record_size: usize,
+    ) -> DBResult<DurabilityReport> {
+        self.engine.calibrate_durability(writes, record_size)
+    }
+
+    /// Return the next value of the named sequence, starting from 1, e.g. `db.next_sequence("orders")`.
+    /// The sequences are stored in a ledger in the data directory and advanced under the exclusive lock,
+    /// so the values are strictly increasing across all processes and never handed out twice, even after a crash.
+    pub fn next_sequence(&mut self, name: &str) -> DBResult<u64> {
+        self.engine
+            .with_exclusive_lock(|engine| engine.next_sequence(name))
+    }
+
+    /// Report the distribution of stored record sizes and the `top_n` largest records by primary key,
+    /// e.g. to find unexpectedly large values. The sizes are read from the segment metadata, so
+    /// the report is cheap to compute even for large records.
+    pub fn size_report(&mut self, top_n: usize) -> DBResult<SizeReport> {
+        self.engine
+            .with_shared_lock(|engine| engine.size_report(top_n))
+    }
+
+    /// Export the current records into a new SQLite database file at `path`, for ad-hoc SQL queries
+    /// over a snapshot of the data. The records are written into a table named `records` with one
+    /// column per field, named after the field's `Debug` representation. The primary key, secondary keys
+    /// and composite keys are indexed. Decimals are stored as text to keep them exact.
+    /// Returns the number of exported records.
+    ///
+    /// Requires the `sqlite` feature. Writes are blocked for the duration of the export.
+    #[cfg(feature = "sqlite")]
+    pub fn export_sqlite(&mut self, path: &str) -> DBResult<u64> {
+        self.engine
+            .with_shared_lock(|engine| engine.export_sqlite(Path::new(path)))
+    }
+
+    /// Write the current records into a new Parquet file at `path`, in primary key order, with a
+    /// column per field of the schema. Returns the number of exported records.
+    ///
+    /// Fields map to the Arrow types that analytics tools read natively: integers to `Int64`,
+    /// floats to `Float64`, timestamps to UTC `Timestamp` in microseconds, bytes to `Binary` or
+    /// `FixedSizeBinary`, and strings, enums and JSON to `Utf8`. Decimals are written as `Utf8` to
+    /// keep them exact, since a Parquet decimal column has a single scale. Geo points become structs
+    /// of `lat` and `lon`, and nested records structs of their fields. Nullable fields are nullable
+    /// columns. The file is written in row groups of a batch of records at a time, compressed with
+    /// Snappy, and moved into place once complete.
+    ///
+    /// Requires the `parquet` feature. Writes are blocked for the duration of the export.
+    #[cfg(feature = "parquet")]
+    pub fn export_parquet(&mut self, path: &str) -> DBResult<u64> {
+        self.engine
+            .with_shared_lock(|engine| engine.export_parquet(Path::new(path)))
+    }
+
+    /// Write the current records to `writer` in `format`, in primary key order, for loading into
+    /// tools that do not read SQLite. Returns the number of exported records.
+    ///
+    /// Fields are named after the schema: JSON Lines objects are keyed by the field names, and CSV
+    /// starts with a header row of them. Values are written according to their type, see
+    /// `Format`. Decimals are written as text to keep them exact, timestamps as integers of
+    /// microseconds since the Unix epoch and bytes as base64. The records are streamed in batches,
+    /// so the export does not hold all of them in memory. Writes are blocked for the duration of the
+    /// export.
+    pub fn export(&mut self, writer: &mut impl Write, format: Format) -> DBResult<u64> {
+        self.engine
+            .with_shared_lock(|engine| engine.export(writer, format))
+    }
+
+    /// Write the contents of the in-memory indexes to `writer` in a human-readable format, see
+    /// `IndexDump`. The indexes are written as they are, without refreshing them first.
+    /// The dump can be read back with `IndexDump::load` for offline analysis.
+    pub fn dump_indexes(&mut self, writer: &mut impl Write) -> DBResult<()> {
+        self.engine.index_dump().write(writer)
+    }
+
+    /// Back up the segments that have been created or changed since the backup described by
+    /// `prev_manifest` into `backup_dir`, which must not exist or be empty. With `None`, all segments are
+    /// backed up. Returns the manifest of the new backup, to be passed on to the next incremental backup;
+    /// it can also be read from an existing backup with `Manifest::read`.
+    ///
+    /// Sealed segments do not change until they are compacted or merged, so a chain of incremental
+    /// backups only copies each sealed segment once. The active segment is copied every time. The
+    /// chain starts from a full backup, made with `backup_to` or with `None`, and can be restored
+    /// with `DB::restore`. Like `backup_to`, the backup is moved into place only once complete and
+    /// links the files of sealed segments where possible. The database is locked for writes for the
+    /// duration of the backup.
+    pub fn backup_incremental(
+        &mut self,
+        backup_dir: &str,
+        prev_manifest: Option<&Manifest>,
+    ) -> DBResult<Manifest> {
+        self.engine
+            .with_shared_lock(|engine| engine.backup_incremental(backup_dir, prev_manifest))
+    }
+
+    /// Make a consistent point-in-time backup of the database at `backup_dir`, which must not
+    /// exist or be empty. The backup can be opened for reading with `ConfigBuilder::open_backup`,
+    /// or restored with `DB::restore`. Returns the manifest of the backup, which can be passed on
+    /// to `backup_incremental` to continue a chain of incremental backups from it.
+    ///
+    /// The backup is made in a temporary directory next to `backup_dir` and moved into place when
+    /// complete, so `backup_dir` never holds a partial backup. The files of sealed segments are
+    /// hard-linked when the backup is on the same filesystem, which makes the backup fast and
+    /// small until the segments are compacted, and copied otherwise. The database is locked for
+    /// writes for the duration of the backup.
+    pub fn backup_to(&mut self, backup_dir: &str) -> DBResult<Manifest> {
+        self.engine
+            .with_shared_lock(|engine| engine.backup_incremental(backup_dir, None))
+    }
+
+    /// List the handles that are currently open in the data directory `data_dir` by any process,
+    /// oldest first, e.g. to find out which processes may be holding the locks. Registrations left
+    /// behind by processes that crashed are cleaned up.
+    pub fn who(data_dir: &str) -> DBResult<Vec<InstanceInfo>> {
+        instance::who(Path::new(data_dir))
+    }
+
+    /// Check whether a handle has quiesced the data directory `data_dir` with `quiesce`. A quiesce
+    /// left behind by a process that crashed is cleaned up.
+    pub fn is_quiesced(data_dir: &str) -> DBResult<bool> {
+        quiesce::is_quiesced(Path::new(data_dir))
+    }
+
+    /// Compare the schema of the data directory `data_dir` with a proposed schema before committing
+    /// to it, e.g. `DB::<Item>::check_compat(dir, &StoredSchema::from_schema(&schema, &pk, &[]))`.
+    /// The report lists each change and whether it breaks opening the directory without a
+    /// migration. The directory is only read, without locking it. A directory without a schema
+    /// file is compared as if it had been written with the schema of `R`.
+    pub fn check_compat(data_dir: &str, schema: &StoredSchema) -> DBResult<CompatReport> {
+        let data_dir_path = Path::new(data_dir);
+        if !fs::exists(data_dir_path.join(INITIALIZED_FILENAME))? {
+            return Err(DBError::ValidationError(format!(
+                "{} is not an initialized data directory",
+                data_dir
+            )));
+        }
+
+        let stored = match StoredSchema::read(data_dir_path)? {
+            Some(stored) => stored,
+            None => {
+                StoredSchema::from_schema(&R::schema(), &R::primary_key(), &R::secondary_keys())
+            }
+        };
+        Ok(CompatReport {
+            changes: stored.changes_to(schema),
+            stored,
+            proposed: schema.clone(),
+        })
+    }
+
+    /// Check the files of the data directory `data_dir` for consistency: the active symlink, an
+    /// unrecovered journal, metadata files that end in a partial row, bytes after the last record
+    /// of the active data file, data files without a segment, and the checksums of all metadata
+    /// rows and records.
+    ///
+    /// With `FsckMode::Repair`, the torn trailing entries left by an interrupted append are
+    /// truncated, the journal is recovered, orphaned data files are removed and a broken active
+    /// symlink is pointed at the newest segment. Damaged records are only reported, since the data
+    /// to repair them is gone. The check takes a shared lock and the repair the exclusive lock, so
+    /// both can be run while other handles are open.
+    pub fn fsck(data_dir: &str, mode: FsckMode) -> DBResult<FsckReport> {
+        fsck::fsck(Path::new(data_dir), mode)
+    }
+
+    /// Restore a chain of backups made with `backup_to` and `backup_incremental` into `data_dir`, which
+    /// must not exist or be empty. `backup_dirs` lists the backups in the order they were made,
+    /// starting from a full backup; a single backup is restored with `&[backup_dir]`.
+    ///
+    /// The manifest of the newest backup is validated and each segment is verified against it
+    /// before it is restored. The restored directory is built next to `data_dir` and moved into
+    /// place only once the segments and the checksums of their records have been verified again,
+    /// so `data_dir` cannot be opened for writes before the restore is complete and intact.
+    pub fn restore(backup_dirs: &[&str], data_dir: &str) -> DBResult<()> {
+        let backup_dir_paths: Vec<PathBuf> = backup_dirs.iter().map(PathBuf::from).collect();
+        backup::restore_chain(&backup_dir_paths, Path::new(data_dir))
+    }
+
+    /// Get the collection `name` of this database, e.g. `db.collection::<User>("users")`. A collection
+    /// is a database of its own, with its own schema, segments and indexes, stored in a subdirectory
+    /// of the data directory. It is opened when it is first asked for, and kept open until this handle
+    /// is dropped. The settings of the storage, e.g. the segment size and write durability, are
+    /// inherited from this database.
+    ///
+    /// Names may contain ASCII letters, digits, `_` and `-`. A collection opened again by the same
+    /// handle must be asked for with the same record type. Collections are not included in backups
+    /// of this database.
+    pub fn collection<N: Recordable + 'static>(&mut self, name: &str) -> DBResult<&mut DB<N>> {
+        let is_valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !is_valid_name {
+            return Err(DBError::ValidationError(format!(
+                "Invalid collection name: {:?}",
+                name
+            )));
+        }
+        // Collections of a handle opened with `read_only` are opened read-only in turn
+        if self.engine.is_read_only() && !self.engine.config.read_only {
+            return Err(DBError::ReadOnly(
+                "Collections cannot be opened from a read-only database".to_owned(),
+            ));
+        }
+
+        let collection = match self.collections.entry(name.to_owned()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let collections_path =
+                    Path::new(&self.engine.config.data_dir).join(COLLECTIONS_DIRNAME);
+                if !self.engine.config.read_only {
+                    fs::create_dir_all(&collections_path)?;
+                }
+                let collection = ConfigBuilder::<N>::from_parent(
+                    &self.engine.config,
+                    &collections_path.join(name),
+                )
+                .initialize()?;
+                entry.insert(Box::new(collection))
+            }
+        };
+
+        collection.downcast_mut::<DB<N>>().ok_or_else(|| {
+            DBError::ValidationError(format!(
+                "Collection {} is open with a different record type",
+                name
+            ))
+        })
+    }
+
+    /// Start a transaction of upserts to this database and its collections, which are written
+    /// atomically when the transaction is committed, e.g. so that an order and the event announcing
+    /// it cannot be observed separately after a crash. Records are staged with `Transaction::upsert`
+    /// and `Transaction::upsert_in`, and nothing is written before `Transaction::commit`.
+    pub fn transaction(&mut self) -> Transaction<'_, R> {
+        Transaction::new(self)
+    }
+
+    /// List the names of the collections in the data directory, including ones not opened by this
+    /// handle, in alphabetical order.
+    pub fn collection_names(&self) -> DBResult<Vec<String>> {
+        let collections_path = Path::new(&self.engine.config.data_dir).join(COLLECTIONS_DIRNAME);
+        if !fs::exists(&collections_path)? {
+            return Ok(vec![]);
+        }
+
+        let mut names = vec![];
+        for entry in fs::read_dir(&collections_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Mount a read-only source of records under `name`, so that it is included in `find_by` queries.
+    /// Returns an error if a source with the same name is already mounted.
+    pub fn mount(&mut self, name: &str, source: Box<dyn ForeignSource<R>>) -> DBResult<()> {
+        if self.mounts.iter().any(|mount| mount.name == name) {
+            return Err(DBError::ValidationError(format!(
+                "A source named {} is already mounted",
+                name
+            )));
+        }
+
+        self.mounts.push(Mount {
+            name: name.to_owned(),
+            source,
+        });
+        Ok(())
+    }
+
+    /// Unmount the source mounted under `name` and return it, if any.
+    pub fn unmount(&mut self, name: &str) -> Option<Box<dyn ForeignSource<R>>> {
+        let index = self.mounts.iter().position(|mount| mount.name == name)?;
+        Some(self.mounts.remove(index).source)
+    }
+
     /// Refresh the in-memory indexes from the log files.
     /// This needs to only be called if the read consistency is set to `ReadConsistency::Eventual`.
     pub fn refresh_indexes(&mut self) -> DBResult<()> {
         self.engine
-            .with_exclusive_lock(|engine| engine.refresh_indexes())
+            .with_shared_lock(|engine| engine.refresh_indexes())
     }
 }
 
@@ -331,7 +1522,7 @@ mod tests {
         // Note negation here
         assert!(!fs::exists(data_dir.join(metadata_filename(3))).unwrap());
 
-        // Check that the compacted metadata file has the same size
+        // Check that the compacted metadata file has a single row left
         let mut segment1_metadata_file_compacted =
             READ_MODE.open(data_dir.join(metadata_filename(1))).unwrap();
         let segment1_metadata_size_compacted = segment1_metadata_file_compacted
@@ -339,8 +1530,9 @@ mod tests {
             .unwrap();
         assert_eq!(
             segment1_metadata_size_compacted,
-            segment1_metadata_size_original
+            (METADATA_FILE_HEADER_SIZE + METADATA_ROW_LENGTH) as u64
         );
+        assert!(segment1_metadata_size_compacted < segment1_metadata_size_original);
 
         // Check that the compacted data file is smaller
         let segment1_header_compacted =
@@ -403,7 +1595,10 @@ mod tests {
         file.flush().unwrap();
 
         let len = file.seek(SeekFrom::End(0)).expect("Failed to seek");
-        assert_ne!(len, METADATA_FILE_HEADER_SIZE as u64 + n_recs * 16);
+        assert_ne!(
+            len,
+            METADATA_FILE_HEADER_SIZE as u64 + n_recs * METADATA_ROW_LENGTH as u64
+        );
 
         // Try to refresh indexes, reading the file from beginning to end: should lead to error
         db.refresh_indexes()
@@ -422,7 +1617,32 @@ mod tests {
             .open(&segment_metadata_path)
             .expect("Failed to open file");
         let len = file.seek(SeekFrom::End(0)).expect("Failed to seek");
-        assert_eq!(len, METADATA_FILE_HEADER_SIZE as u64 + n_recs * 16);
+        assert_eq!(
+            len,
+            METADATA_FILE_HEADER_SIZE as u64 + n_recs * METADATA_ROW_LENGTH as u64
+        );
+    }
+
+    #[test]
+    fn test_schema_mismatch_is_an_error() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+
+        let mut db = DB::<TestInst1>::configure()
+            .data_dir(data_dir)
+            .initialize()
+            .expect("Failed to create DB");
+        db.upsert(TestInst1 { id: 1 })
+            .expect("Failed to insert record");
+
+        let result = DB::<TestInst2>::configure().data_dir(data_dir).initialize();
+        assert!(matches!(result, Err(DBError::SchemaMismatch(_))));
+
+        // Without the schema file, the records have no name to index, which must not panic the reader
+        fs::remove_file(temp_dir.path().join(SCHEMA_FILENAME)).unwrap();
+        let result = DB::<TestInst2>::configure().data_dir(data_dir).initialize();
+        assert!(matches!(result, Err(DBError::ConsistencyError(_))));
     }
 
     #[test]
@@ -466,4 +1686,45 @@ mod tests {
             &expected_set,
         );
     }
+
+    #[test]
+    fn test_interrupted_batch_is_recovered_before_versions_are_assigned() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data_dir = temp_dir.path();
+        let configure = || {
+            DB::<TestInst1>::configure()
+                .data_dir(data_dir.to_str().unwrap())
+                .initialize()
+                .expect("Failed to create DB")
+        };
+        let mut writer = configure();
+        let mut other = configure();
+
+        let receipts = writer
+            .batch_upsert(vec![TestInst1 { id: 1 }, TestInst1 { id: 2 }])
+            .unwrap();
+
+        // Simulate a crash after the batch was journaled, but before its last row was appended
+        let metadata_path = data_dir.join(metadata_filename(1));
+        let metadata = fs::read(&metadata_path).unwrap();
+        let metadata_start = (metadata.len() - METADATA_ROW_LENGTH) as u64;
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&metadata_path)
+            .unwrap()
+            .set_len(metadata_start)
+            .unwrap();
+        JournalEntry {
+            segment_num: 1,
+            metadata_start,
+            rows: metadata[metadata_start as usize..].to_vec(),
+            commit_marker: None,
+        }
+        .write(data_dir, &WriteDurability::Flush)
+        .unwrap();
+
+        let receipt = other.upsert(TestInst1 { id: 3 }).unwrap();
+        assert!(receipt.meta.version > receipts[1].meta.version);
+        assert!(other.get(&Value::Int(2)).unwrap().is_some());
+    }
 }
@@ -1,12 +1,19 @@
 use super::*;
 
 pub struct LockManager {
-    lock_file: fs::File,
-    excl_lock_file: fs::File,
+    /// The lock files, or `None` for a read-only data directory that nobody writes to, such as a backup
+    files: Option<LockFiles>,
+    /// Whether exclusive locks may be taken, false for read-only handles
+    writable: bool,
 
     state: LockState,
 }
 
+struct LockFiles {
+    lock_file: fs::File,
+    excl_lock_file: fs::File,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum LockState {
     NotLocked,
@@ -20,16 +27,60 @@ impl LockManager {
         let excl_lock_file = fs::File::create(data_dir_path.join(EXCL_LOCK_REQ_FILENAME))?;
 
         Ok(LockManager {
-            lock_file,
-            excl_lock_file,
+            files: Some(LockFiles {
+                lock_file,
+                excl_lock_file,
+            }),
+            writable: true,
+            state: LockState::NotLocked,
+        })
+    }
+
+    /// Create a lock manager for a read-only handle to a data directory that others write to.
+    /// Shared locks are taken as usual, and exclusive locks are refused with `DBError::ReadOnly`.
+    pub fn shared_only(data_dir_path: PathBuf) -> DBResult<LockManager> {
+        let mut lock_manager = LockManager::new(data_dir_path)?;
+        lock_manager.writable = false;
+        Ok(lock_manager)
+    }
+
+    /// Create a lock manager for a read-only handle that must not create files, see
+    /// `ConfigBuilder::read_only`. The lock files are opened for shared locks if they exist, which
+    /// they do once any handle has opened the data directory for writing.
+    pub fn existing(data_dir_path: &Path) -> DBResult<LockManager> {
+        let open = |filename| match READ_MODE.open(data_dir_path.join(filename)) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        let files = match (open(LOCK_FILENAME)?, open(EXCL_LOCK_REQ_FILENAME)?) {
+            (Some(lock_file), Some(excl_lock_file)) => Some(LockFiles {
+                lock_file,
+                excl_lock_file,
+            }),
+            _ => None,
+        };
+        Ok(LockManager {
+            files,
+            writable: false,
             state: LockState::NotLocked,
         })
     }
 
-    fn is_exclusive_lock_requested(&self) -> DBResult<bool> {
+    /// Create a lock manager for a data directory that nobody writes to. No lock files are created,
+    /// shared locks always succeed and exclusive locks are refused with `DBError::ReadOnly`.
+    pub fn read_only() -> LockManager {
+        LockManager {
+            files: None,
+            writable: false,
+            state: LockState::NotLocked,
+        }
+    }
+
+    fn is_exclusive_lock_requested(files: &LockFiles) -> DBResult<bool> {
         // Attempt to acquire a shared lock on the lock request file
         // If the file is already locked, return false
-        match self.excl_lock_file.try_lock_shared() {
+        match files.excl_lock_file.try_lock_shared() {
             Err(e) => {
                 if e.kind() == lock_contended_error().kind() {
                     return Ok(true);
@@ -38,7 +89,7 @@ impl LockManager {
             }
 
             Ok(_) => {
-                self.excl_lock_file.unlock()?;
+                files.excl_lock_file.unlock()?;
                 return Ok(false);
             }
         }
@@ -55,9 +106,17 @@ impl LockManager {
             ));
         }
 
+        let files = match &self.files {
+            Some(files) => files,
+            None => {
+                self.state = LockState::Shared;
+                return Ok(());
+            }
+        };
+
         let mut timeout = 5;
         loop {
-            if self.is_exclusive_lock_requested()? {
+            if Self::is_exclusive_lock_requested(files)? {
                 debug!(
                     "Exclusive lock requested, waiting for {}ms before requesting a shared lock again",
                     timeout
@@ -71,7 +130,7 @@ impl LockManager {
                     ));
                 }
             } else {
-                self.lock_file.lock_shared()?;
+                files.lock_file.lock_shared()?;
                 self.state = LockState::Shared;
                 return Ok(());
             }
@@ -89,16 +148,25 @@ impl LockManager {
             ));
         }
 
+        let files = match &self.files {
+            Some(files) if self.writable => files,
+            _ => {
+                return Err(DBError::ReadOnly(
+                    "Cannot modify a read-only database".to_owned(),
+                ))
+            }
+        };
+
         // Create a lock on the exclusive lock request file to signal to readers that they should wait
         // This will block until the lock is acquired
-        self.excl_lock_file.lock_exclusive()?;
+        files.excl_lock_file.lock_exclusive()?;
 
         // Acquire an exclusive lock on the actual lock files
-        self.lock_file.lock_exclusive()?;
+        files.lock_file.lock_exclusive()?;
         self.state = LockState::Exclusive;
 
         // Unlock the request file
-        self.excl_lock_file.unlock()?;
+        files.excl_lock_file.unlock()?;
 
         Ok(())
     }
@@ -110,7 +178,9 @@ impl LockManager {
             ));
         }
 
-        self.lock_file.unlock()?;
+        if let Some(files) = &self.files {
+            files.lock_file.unlock()?;
+        }
         self.state = LockState::NotLocked;
         Ok(())
     }
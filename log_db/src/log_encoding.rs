@@ -0,0 +1,146 @@
+use super::*;
+use log_db_codec::{serialize_short_bytes_into, SHORT_BYTES_MAX_LEN};
+use std::sync::Arc;
+
+/// How the values of the fields of a schema are stored in the log, where it differs from how they
+/// are held in memory:
+///
+/// - Values of enum fields (`Type::enum_of`) are strings in memory, but stored as the `Int` index
+///   of their variant.
+/// - Values of fixed-length bytes fields (`Type::bytes_fixed`) are stored with a one-byte length
+///   prefix instead of the usual eight bytes.
+/// - With numeric widening, values of float and decimal fields may be stored as the integers they
+///   were written as before the field was widened, see `ConfigBuilder::numeric_widening`.
+/// - With value compression, long string, JSON and bytes values are stored compressed, see
+///   `ConfigBuilder::compress_values_over`. They are expanded with `expand_values` when read.
+///
+/// Records are encoded with `serialize` and decoded with `decode` or `decode_ref` where they cross
+/// the log. Cloning is cheap, so that arenas and readers can hold their own copy.
+#[derive(Debug, Clone, Default)]
+pub struct LogEncoding {
+    /// The encoding of each field in schema order, or `None` for fields stored as they are.
+    /// Empty if no field of the schema needs an encoding.
+    fields: Arc<Vec<Option<FieldEncoding>>>,
+    /// The length over which values are compressed, see `ConfigBuilder::compress_values_over`
+    compress_values_over: Option<usize>,
+}
+
+#[derive(Debug)]
+enum FieldEncoding {
+    /// The variants of an enum field.
+    Variants(Vec<String>),
+    /// Bytes short enough for a one-byte length prefix.
+    ShortBytes,
+    /// A float field that may hold integers.
+    WidenedToFloat,
+    /// A decimal field that may hold integers.
+    WidenedToDecimal,
+}
+
+impl LogEncoding {
+    /// The encoding of `schema`. With `widening`, integers stored in float and decimal fields are
+    /// decoded as floats and decimals.
+    pub fn from_schema<Field>(schema: &[(Field, Type)], widening: bool) -> LogEncoding {
+        let fields: Vec<Option<FieldEncoding>> = schema
+            .iter()
+            .map(|(_, t)| match &t.primitive {
+                PrimitiveType::Enum(variants) => Some(FieldEncoding::Variants(variants.clone())),
+                PrimitiveType::FixedBytes(len) if *len <= SHORT_BYTES_MAX_LEN => {
+                    Some(FieldEncoding::ShortBytes)
+                }
+                PrimitiveType::Float if widening => Some(FieldEncoding::WidenedToFloat),
+                PrimitiveType::Decimal if widening => Some(FieldEncoding::WidenedToDecimal),
+                _ => None,
+            })
+            .collect();
+        if fields.iter().all(Option::is_none) {
+            return LogEncoding::default();
+        }
+
+        LogEncoding {
+            fields: Arc::new(fields),
+            compress_values_over: None,
+        }
+    }
+
+    /// Compress the values whose serialized length exceeds `threshold` bytes.
+    pub fn with_value_compression(mut self, threshold: Option<usize>) -> LogEncoding {
+        self.compress_values_over = threshold;
+        self
+    }
+
+    /// Serialize a record with its values in their stored form.
+    pub fn serialize(&self, record: &Record) -> Vec<u8> {
+        if self.fields.is_empty() && self.compress_values_over.is_none() {
+            return record.serialize();
+        }
+
+        record.serialize_with(|i, value, bytes| {
+            let start = bytes.len();
+            self.serialize_value(i, value, bytes);
+            if let Some(threshold) = self.compress_values_over {
+                if bytes.len() - start > threshold {
+                    compress_value(bytes, start);
+                }
+            }
+        })
+    }
+
+    fn serialize_value(&self, i: usize, value: &Value, bytes: &mut Vec<u8>) {
+        match (self.fields.get(i), value) {
+            (Some(Some(FieldEncoding::Variants(variants))), Value::String(s)) => {
+                match variants.iter().position(|v| v == s) {
+                    Some(tag) => Value::Int(tag as i64).serialize_into(bytes),
+                    None => value.serialize_into(bytes),
+                }
+            }
+            (Some(Some(FieldEncoding::ShortBytes)), Value::Bytes(b)) => {
+                serialize_short_bytes_into(b, bytes)
+            }
+            _ => value.serialize_into(bytes),
+        }
+    }
+
+    /// Replace the indexes stored in the enum fields of a deserialized record with their variants,
+    /// and widen integers stored in widened fields. Bytes need no decoding, since their encoding is
+    /// self-describing.
+    pub fn decode(&self, record: &mut Record) {
+        for (value, encoding) in record.values.iter_mut().zip(self.fields.iter()) {
+            let Value::Int(int) = *value else {
+                continue;
+            };
+            match encoding {
+                Some(FieldEncoding::Variants(variants)) => {
+                    if let Some(variant) =
+                        usize::try_from(int).ok().and_then(|tag| variants.get(tag))
+                    {
+                        *value = Value::String(variant.clone());
+                    }
+                }
+                Some(FieldEncoding::WidenedToFloat) => *value = Value::Float(int as f64),
+                Some(FieldEncoding::WidenedToDecimal) => {
+                    *value = Value::Decimal(Decimal::from(int))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The variant of the value of the field at `index` if it is an enum index, the widened value
+    /// if it is an integer in a widened field, otherwise the value as it is. Indexes out of range
+    /// are left for validation to report.
+    pub fn decode_ref<'a>(&'a self, index: usize, value: ValueRef<'a>) -> ValueRef<'a> {
+        let (Some(Some(encoding)), ValueRef::Int(int)) = (self.fields.get(index), value) else {
+            return value;
+        };
+        match encoding {
+            FieldEncoding::Variants(variants) => usize::try_from(int)
+                .ok()
+                .and_then(|tag| variants.get(tag))
+                .map_or(value, |variant| ValueRef::String(variant)),
+            FieldEncoding::WidenedToFloat => ValueRef::Float(int as f64),
+            FieldEncoding::WidenedToDecimal => ValueRef::Decimal(Decimal::from(int)),
+            FieldEncoding::ShortBytes => value,
+        }
+    }
+}
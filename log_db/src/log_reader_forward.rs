@@ -1,55 +1,132 @@
 use super::*;
+use std::sync::Arc;
 
 pub struct ForwardLogReader {
     metadata_reader: io::BufReader<fs::File>,
     data_reader: io::BufReader<fs::File>,
+    log_encoding: LogEncoding,
+    /// The header of the segment, whose records are decoded with `MetadataHeader::decode_record`
+    /// as they are read.
+    header: MetadataHeader,
+    encryption: Option<Arc<dyn EncryptionProvider>>,
+    /// The index of the row to stop reading at, see `ForwardLogReader::until_index`
+    end_index: Option<u64>,
 }
 
 pub struct ForwardLogReaderItem {
     pub record: Record,
     pub index: u64,
+    /// Offset of the record in the data file.
+    pub offset: u64,
+    /// Length of the record in the data file in bytes, which is less than the length of the
+    /// serialized record if the segment is compressed.
+    pub length: u64,
+}
+
+/// The position of a record read with `ForwardLogReader::read_raw`.
+pub struct ForwardLogReaderRow {
+    pub index: u64,
+    pub offset: u64,
+    pub length: u64,
 }
 
 impl ForwardLogReader {
+    /// Read the segment from the start. The metadata header must have been checked with
+    /// `validate_metadata_header`.
     pub fn new(metadata_file: fs::File, data_file: fs::File) -> ForwardLogReader {
-        let mut ret = ForwardLogReader {
-            metadata_reader: io::BufReader::new(metadata_file),
-            data_reader: io::BufReader::new(data_file),
-        };
-
-        ret.metadata_reader
-            .seek(io::SeekFrom::Start(METADATA_FILE_HEADER_SIZE as u64))
-            .expect("Seek failed");
-
-        ret
+        ForwardLogReader::new_with_index(metadata_file, data_file, 0)
     }
 
     pub fn new_with_index(
-        metadata_file: fs::File,
+        mut metadata_file: fs::File,
         data_file: fs::File,
         index: u64,
     ) -> ForwardLogReader {
+        let header =
+            read_metadata_header(&mut metadata_file).expect("Failed to read metadata header");
+        let format_version = header.version;
         let mut ret = ForwardLogReader {
             metadata_reader: io::BufReader::new(metadata_file),
             data_reader: io::BufReader::new(data_file),
+            log_encoding: LogEncoding::default(),
+            header,
+            encryption: None,
+            end_index: None,
         };
 
         ret.metadata_reader
             .seek(io::SeekFrom::Start(
-                METADATA_FILE_HEADER_SIZE as u64 + METADATA_ROW_LENGTH as u64 * index,
+                METADATA_FILE_HEADER_SIZE as u64
+                    + metadata_row_length(format_version) as u64 * index,
             ))
             .expect("Seek failed");
 
         ret
     }
 
+    /// Decode the values of the records read as an iterator from their stored form.
+    pub fn with_log_encoding(mut self, log_encoding: &LogEncoding) -> ForwardLogReader {
+        self.log_encoding = log_encoding.clone();
+        self
+    }
+
+    /// Decrypt the records read with `encryption`, which is required if the segment is encrypted.
+    pub fn with_encryption(
+        mut self,
+        encryption: &Option<Arc<dyn EncryptionProvider>>,
+    ) -> ForwardLogReader {
+        self.encryption = encryption.clone();
+        self
+    }
+
+    /// Stop reading at the row at `index`, so that the reader covers the rows from its starting
+    /// index up to `index`, e.g. to read the parts of a segment in parallel.
+    pub fn until_index(mut self, index: u64) -> ForwardLogReader {
+        self.end_index = Some(index);
+        self
+    }
+
+    /// The records as an iterator of results. Unlike iterating the reader, which panics, this
+    /// returns the errors of records that cannot be decoded, e.g. because they are encrypted with a
+    /// key that is not available.
+    pub fn try_records(mut self) -> impl Iterator<Item = DBResult<ForwardLogReaderItem>> {
+        std::iter::from_fn(move || self.read_record().map_err(into_db_error).transpose())
+    }
+
     fn read_record(&mut self) -> Result<Option<ForwardLogReaderItem>, io::Error> {
+        let mut result_buf = vec![];
+        let Some(row) = self.read_raw(&mut result_buf)? else {
+            return Ok(None);
+        };
+
+        let mut record = Record::deserialize(&result_buf);
+        self.log_encoding.decode(&mut record);
+        Ok(Some(ForwardLogReaderItem {
+            record,
+            index: row.index,
+            offset: row.offset,
+            length: row.length,
+        }))
+    }
+
+    /// Append the serialized bytes of the next record to `buf` without deserializing them.
+    pub fn read_raw(
+        &mut self,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<ForwardLogReaderRow>, io::Error> {
         loop {
             let pos = self.metadata_reader.stream_position()?;
-            let index = (pos - METADATA_FILE_HEADER_SIZE as u64) / METADATA_ROW_LENGTH as u64;
+            let row_length = metadata_row_length(self.header.version);
+            let index = (pos - METADATA_FILE_HEADER_SIZE as u64) / row_length as u64;
+            if self.end_index.is_some_and(|end_index| index >= end_index) {
+                return Ok(None);
+            }
 
-            let mut metadata_entry_buf = vec![0; 16]; // 2x u64
-            if let Err(e) = self.metadata_reader.read_exact(&mut metadata_entry_buf) {
+            let mut metadata_entry_buf = [0; METADATA_ROW_LENGTH];
+            if let Err(e) = self
+                .metadata_reader
+                .read_exact(&mut metadata_entry_buf[..row_length])
+            {
                 if e.kind() == io::ErrorKind::UnexpectedEof {
                     return Ok(None);
                 } else {
@@ -57,29 +134,44 @@ impl ForwardLogReader {
                 }
             }
 
-            // First u64 is the offset of the record in the data file, second is the length of the record
-            let entry_offset = u64::from_be_bytes(metadata_entry_buf[0..8].try_into().unwrap());
-            let entry_length = u64::from_be_bytes(metadata_entry_buf[8..16].try_into().unwrap());
-
-            if entry_offset == 0 && entry_length == 0 {
+            let row = MetadataRow::deserialize(self.header.version, &metadata_entry_buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if row.is_unused() {
                 // This is an unused entry in the metadata file, skip
                 continue;
             }
+            let (entry_offset, entry_length) = (row.offset, row.length);
 
             // Use .seek_relative instead of .seek to avoid dropping the BufReader internal buffer when
             // the seek distance is small
             let seek_distance = entry_offset as i64 - self.data_reader.stream_position()? as i64;
             self.data_reader.seek_relative(seek_distance)?;
 
-            let mut result_buf = vec![0; entry_length as usize];
-            self.data_reader.read_exact(&mut result_buf)?;
-
-            let record = Record::deserialize(&result_buf);
-            return Ok(Some(ForwardLogReaderItem { record, index }));
+            let start = buf.len();
+            buf.resize(start + entry_length as usize, 0);
+            self.data_reader.read_exact(&mut buf[start..])?;
+            self.header
+                .decode_record(buf, start, self.encryption.as_deref())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            return Ok(Some(ForwardLogReaderRow {
+                index,
+                offset: entry_offset,
+                length: entry_length,
+            }));
         }
     }
 }
 
+/// Unwrap the errors of decoding a record, which `read_raw` returns wrapped in an `io::Error`.
+fn into_db_error(e: io::Error) -> DBError {
+    if e.get_ref().is_some_and(|inner| inner.is::<DBError>()) {
+        *e.into_inner().unwrap().downcast::<DBError>().unwrap()
+    } else {
+        DBError::IOError(e)
+    }
+}
+
 impl Iterator for ForwardLogReader {
     type Item = ForwardLogReaderItem;
 
@@ -101,7 +193,7 @@ mod tests {
         let _ = env_logger::builder().is_test(true).try_init();
         let metadata_path = Path::new(TEST_RESOURCES_DIR).join("test_metadata_1");
         let data_path = Path::new(TEST_RESOURCES_DIR).join("test_data_1");
-        let metadata_file = fs::OpenOptions::new()
+        let mut metadata_file = fs::OpenOptions::new()
             .read(true)
             .open(&metadata_path)
             .expect("Failed to open metadata file");
@@ -110,6 +202,11 @@ mod tests {
             .open(&data_path)
             .expect("Failed to open data file");
 
+        // The fixture is in segment format 1, written before records had versions and timestamps
+        let header = read_metadata_header(&mut metadata_file).unwrap();
+        assert_eq!(header.version, 1);
+        validate_metadata_header(&header).unwrap();
+
         let mut forward_log_reader = ForwardLogReader::new(metadata_file, data_file);
 
         // There are two records in the log with "schema" with one field: Bytes
@@ -121,6 +218,8 @@ mod tests {
             [Value::Bytes(bytes)] => bytes.len() == 256,
             _ => false,
         });
+        assert_eq!(first_record.record.version, 0);
+        assert_eq!(first_record.record.timestamp, SystemTime::UNIX_EPOCH);
 
         assert!(forward_log_reader.next().is_none());
     }
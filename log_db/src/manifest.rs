@@ -0,0 +1,377 @@
+use super::*;
+
+const MANIFEST_VERSION: u8 = 2;
+
+/// The manifest records the expected contents of sealed segments, i.e. segments that are no longer
+/// written to. It is rewritten whenever a segment is sealed or compacted, so segment files that do not
+/// match the manifest have been modified outside of the engine. It also records the next record
+/// version, since compaction may drop the records with the greatest versions.
+///
+/// The manifest is a small text file, see `Manifest::serialize` for the format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub active_segment_num: u16,
+    /// A version greater than that of any record written before the manifest was last updated,
+    /// including records that have since been dropped by compaction
+    pub next_version: u64,
+    pub segments: Vec<ManifestSegment>,
+}
+
+/// A manifest entry describing a sealed segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestSegment {
+    pub segment_num: u16,
+    pub metadata_len: u64,
+    pub metadata_checksum: u32,
+    pub data_uuid: Uuid,
+    /// Length of the data file prefix that the segment refers to. Consecutive segments may share
+    /// a data file, in which case the rest of the file belongs to the next segment.
+    pub data_len: u64,
+    pub data_checksum: u32,
+}
+
+impl ManifestSegment {
+    /// Compute the manifest entry of a segment from its current files.
+    pub fn compute(data_dir_path: &Path, segment_num: u16) -> DBResult<ManifestSegment> {
+        let mut metadata_file =
+            READ_MODE.open(data_dir_path.join(metadata_filename(segment_num)))?;
+        let metadata_header = read_metadata_header(&mut metadata_file)?;
+
+        metadata_file.seek(SeekFrom::Start(0))?;
+        let mut metadata_buf = vec![];
+        metadata_file.read_to_end(&mut metadata_buf)?;
+
+        // The segment's data ends where its last record ends
+        let mut data_len = 0;
+        for row in metadata_buf[METADATA_FILE_HEADER_SIZE..]
+            .chunks_exact(metadata_row_length(metadata_header.version))
+        {
+            let row = MetadataRow::deserialize(metadata_header.version, row)?;
+            data_len = data_len.max(row.offset + row.length);
+        }
+
+        let data_path = data_dir_path.join(metadata_header.uuid.to_string());
+        let (read_len, data_checksum) = match READ_MODE.open(data_path) {
+            Ok(data_file) => checksum_prefix(data_file, data_len)?,
+            // An archived data file without a local copy is described by its archival
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                match ArchivedFiles::read(data_dir_path)?
+                    .files
+                    .get(&metadata_header.uuid)
+                {
+                    Some(archived) => (archived.data_len, archived.data_checksum),
+                    None => return Err(e.into()),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if read_len != data_len {
+            return Err(DBError::ConsistencyError(format!(
+                "Data file of segment {} is truncated: {} bytes, expected at least {}",
+                segment_num, read_len, data_len
+            )));
+        }
+
+        Ok(ManifestSegment {
+            segment_num,
+            metadata_len: metadata_buf.len() as u64,
+            metadata_checksum: crc32fast::hash(&metadata_buf),
+            data_uuid: metadata_header.uuid,
+            data_len,
+            data_checksum,
+        })
+    }
+}
+
+impl Manifest {
+    /// Compute a manifest from the current segment files in the data directory. The next version
+    /// cannot be computed from the files, so it is kept from the current manifest if it is readable.
+    pub fn compute(data_dir_path: &Path) -> DBResult<Manifest> {
+        let active_segment_num = greatest_segment_number(data_dir_path)?;
+
+        let mut segments = vec![];
+        for segment_num in list_segment_numbers(data_dir_path)? {
+            if segment_num != active_segment_num {
+                segments.push(ManifestSegment::compute(data_dir_path, segment_num)?);
+            }
+        }
+
+        let next_version = match Manifest::read(data_dir_path) {
+            Ok(Some(manifest)) => manifest.next_version,
+            Ok(None) | Err(_) => 1,
+        };
+
+        Ok(Manifest {
+            active_segment_num,
+            next_version,
+            segments,
+        })
+    }
+
+    /// Read the manifest from the data directory. Returns `None` if there is no manifest.
+    pub fn read(data_dir_path: &Path) -> DBResult<Option<Manifest>> {
+        let manifest_path = data_dir_path.join(MANIFEST_FILENAME);
+        if !fs::exists(&manifest_path)? {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&manifest_path)?;
+        Manifest::deserialize(&contents).map(Some)
+    }
+
+    /// Atomically replace the manifest in the data directory with this one.
+    pub fn write(&self, data_dir_path: &Path) -> DBResult<()> {
+        let mut tmp_file = fs::NamedTempFile::new_in(data_dir_path)?;
+        tmp_file.write_all(self.serialize().as_bytes())?;
+        tmp_file.flush()?;
+        tmp_file.as_file().sync_all()?;
+
+        fs::rename(tmp_file.path(), data_dir_path.join(MANIFEST_FILENAME))?;
+        Ok(())
+    }
+
+    /// Check that the manifest describes a possible set of segments: sealed segments in increasing
+    /// order, each listed once and before the active segment. Manifests read from disk are intact
+    /// thanks to their checksum, but may still have been written by hand or by a broken tool.
+    pub fn validate(&self) -> DBResult<()> {
+        let mut prev_segment_num = None;
+        for segment in &self.segments {
+            if prev_segment_num.is_some_and(|prev| prev >= segment.segment_num) {
+                return Err(DBError::ConsistencyError(format!(
+                    "Invalid manifest: segment {} is out of order or listed twice",
+                    segment.segment_num
+                )));
+            }
+            if segment.segment_num >= self.active_segment_num {
+                return Err(DBError::ConsistencyError(format!(
+                    "Invalid manifest: sealed segment {} is not before the active segment {}",
+                    segment.segment_num, self.active_segment_num
+                )));
+            }
+            prev_segment_num = Some(segment.segment_num);
+        }
+        Ok(())
+    }
+
+    /// Add the entry of a segment to the manifest, replacing any previous entry of the same segment.
+    pub fn set_segment(&mut self, entry: ManifestSegment) {
+        self.segments
+            .retain(|segment| segment.segment_num != entry.segment_num);
+        self.segments.push(entry);
+        self.segments.sort_by_key(|segment| segment.segment_num);
+    }
+
+    /// Check that the segment files in the data directory match the manifest.
+    /// Returns a description of each problem found, or an empty vector if everything matches.
+    pub fn verify(&self, data_dir_path: &Path) -> DBResult<Vec<String>> {
+        let mut problems = vec![];
+
+        let active_segment_num = greatest_segment_number(data_dir_path)?;
+        if active_segment_num != self.active_segment_num {
+            problems.push(format!(
+                "Active segment is {}, manifest expects {}",
+                active_segment_num, self.active_segment_num
+            ));
+        }
+
+        let segment_nums = list_segment_numbers(data_dir_path)?;
+        for &segment_num in &segment_nums {
+            let listed = self
+                .segments
+                .iter()
+                .any(|segment| segment.segment_num == segment_num);
+            if segment_num != self.active_segment_num && !listed {
+                problems.push(format!("Segment {} is not in the manifest", segment_num));
+            }
+        }
+
+        for expected in &self.segments {
+            if !segment_nums.contains(&expected.segment_num) {
+                problems.push(format!("Segment {} is missing", expected.segment_num));
+                continue;
+            }
+
+            let actual = match ManifestSegment::compute(data_dir_path, expected.segment_num) {
+                Ok(actual) => actual,
+                Err(e) => {
+                    problems.push(format!(
+                        "Segment {} could not be read: {}",
+                        expected.segment_num, e
+                    ));
+                    continue;
+                }
+            };
+
+            if actual.metadata_len != expected.metadata_len
+                || actual.metadata_checksum != expected.metadata_checksum
+            {
+                problems.push(format!(
+                    "Metadata file of segment {} has been modified",
+                    expected.segment_num
+                ));
+            }
+            if actual.data_uuid != expected.data_uuid
+                || actual.data_len != expected.data_len
+                || actual.data_checksum != expected.data_checksum
+            {
+                problems.push(format!(
+                    "Data file of segment {} has been modified",
+                    expected.segment_num
+                ));
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// Serialize the manifest into lines of space-separated fields:
+    ///
+    /// ```text
+    /// manifest <version>
+    /// active <segment number>
+    /// next_version <record version>
+    /// segment <number> <metadata len> <metadata crc32> <data uuid> <data len> <data crc32>
+    /// checksum <crc32 of all preceding lines>
+    /// ```
+    ///
+    /// There is one `segment` line per sealed segment.
+    pub fn serialize(&self) -> String {
+        let mut contents = format!(
+            "manifest {}\nactive {}\nnext_version {}\n",
+            MANIFEST_VERSION, self.active_segment_num, self.next_version
+        );
+        for segment in &self.segments {
+            contents.push_str(&format!(
+                "segment {} {} {:08x} {} {} {:08x}\n",
+                segment.segment_num,
+                segment.metadata_len,
+                segment.metadata_checksum,
+                segment.data_uuid,
+                segment.data_len,
+                segment.data_checksum
+            ));
+        }
+
+        let checksum = crc32fast::hash(contents.as_bytes());
+        contents.push_str(&format!("checksum {:08x}\n", checksum));
+        contents
+    }
+
+    pub fn deserialize(contents: &str) -> DBResult<Manifest> {
+        fn invalid(reason: &str) -> DBError {
+            DBError::ConsistencyError(format!("Invalid manifest: {}", reason))
+        }
+
+        let checksum_start = contents
+            .rfind("checksum ")
+            .ok_or_else(|| invalid("missing checksum"))?;
+        let (body, checksum_line) = contents.split_at(checksum_start);
+        let checksum = u32::from_str_radix(checksum_line["checksum ".len()..].trim(), 16)
+            .map_err(|_| invalid("malformed checksum"))?;
+        if checksum != crc32fast::hash(body.as_bytes()) {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let mut active_segment_num = None;
+        // Manifests of version 1 do not record the next version
+        let mut next_version = 1;
+        let mut segments = vec![];
+        for line in body.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                ["manifest", version] => {
+                    if !matches!(version.parse(), Ok(1..=MANIFEST_VERSION)) {
+                        return Err(invalid("unsupported version"));
+                    }
+                }
+                ["active", num] => {
+                    active_segment_num = Some(
+                        num.parse()
+                            .map_err(|_| invalid("malformed active segment"))?,
+                    );
+                }
+                ["next_version", version] => {
+                    next_version = version
+                        .parse()
+                        .map_err(|_| invalid("malformed next version"))?;
+                }
+                ["segment", num, metadata_len, metadata_checksum, data_uuid, data_len, data_checksum] =>
+                {
+                    let malformed = |_| invalid("malformed segment entry");
+                    segments.push(ManifestSegment {
+                        segment_num: num.parse().map_err(malformed)?,
+                        metadata_len: metadata_len.parse().map_err(malformed)?,
+                        metadata_checksum: u32::from_str_radix(metadata_checksum, 16)
+                            .map_err(malformed)?,
+                        data_uuid: Uuid::parse_str(data_uuid)
+                            .map_err(|_| invalid("malformed data file uuid"))?,
+                        data_len: data_len.parse().map_err(malformed)?,
+                        data_checksum: u32::from_str_radix(data_checksum, 16).map_err(malformed)?,
+                    });
+                }
+                _ => return Err(invalid("unknown entry")),
+            }
+        }
+
+        Ok(Manifest {
+            active_segment_num: active_segment_num
+                .ok_or_else(|| invalid("missing active segment"))?,
+            next_version,
+            segments,
+        })
+    }
+}
+
+/// Compute the CRC32 checksum of at most `len` first bytes of `file`.
+/// Returns the number of bytes read and the checksum.
+pub fn checksum_prefix(file: fs::File, len: u64) -> io::Result<(u64, u32)> {
+    let mut reader = io::BufReader::new(file).take(len);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = [0u8; 64 * 1024];
+    let mut read_len = 0;
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        read_len += n as u64;
+    }
+
+    Ok((read_len, hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_serialize_deserialize() {
+        let manifest = Manifest {
+            active_segment_num: 3,
+            next_version: 42,
+            segments: vec![ManifestSegment {
+                segment_num: 2,
+                metadata_len: 56,
+                metadata_checksum: 0xdeadbeef,
+                data_uuid: Uuid::new_v4(),
+                data_len: 120,
+                data_checksum: 0x1234,
+            }],
+        };
+
+        let serialized = manifest.serialize();
+        assert_eq!(Manifest::deserialize(&serialized).unwrap(), manifest);
+
+        // Any modification is caught by the checksum
+        let tampered = serialized.replace("active 3", "active 4");
+        assert!(Manifest::deserialize(&tampered).is_err());
+
+        // Manifests written before the next version was recorded start versions from 1
+        let mut contents = "manifest 1\nactive 3\n".to_owned();
+        let checksum = crc32fast::hash(contents.as_bytes());
+        contents.push_str(&format!("checksum {:08x}\n", checksum));
+        assert_eq!(Manifest::deserialize(&contents).unwrap().next_version, 1);
+    }
+}
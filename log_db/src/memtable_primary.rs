@@ -1,5 +1,5 @@
 use super::*;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 
 pub struct PrimaryMemtable {
     /// Map of records indexed by key. Used as a shared heap of records
@@ -10,25 +10,64 @@ pub struct PrimaryMemtable {
     /// Note: it must be invariant that all memtables (primary and secondary)
     /// contain the same keys.
     records: BTreeMap<IndexableValue, LogKey>,
+    /// The log keys in `records`, so that it can be checked whether a log key found in a secondary
+    /// memtable refers to the current version of its record without knowing its primary key.
+    log_keys: HashSet<LogKey>,
 }
 
 impl PrimaryMemtable {
     pub fn new() -> PrimaryMemtable {
         PrimaryMemtable {
             records: BTreeMap::new(),
+            log_keys: HashSet::new(),
         }
     }
 
     pub fn set(&mut self, key: IndexableValue, value: LogKey) {
-        self.records.insert(key, value);
+        if let Some(previous) = self.records.insert(key, value.clone()) {
+            self.log_keys.remove(&previous);
+        }
+        self.log_keys.insert(value);
     }
 
     pub fn get(&self, key: &IndexableValue) -> Option<&LogKey> {
         self.records.get(key)
     }
 
+    /// Whether `log_key` is the current log key of some primary key.
+    pub fn contains_log_key(&self, log_key: &LogKey) -> bool {
+        self.log_keys.contains(log_key)
+    }
+
     pub fn remove(&mut self, key: &IndexableValue) -> Option<LogKey> {
-        self.records.remove(key)
+        let removed = self.records.remove(key)?;
+        self.log_keys.remove(&removed);
+        Some(removed)
+    }
+
+    /// Replace each log key with `f(log_key)`, removing the entries for which `f` returns `None`.
+    pub fn remap(&mut self, f: impl Fn(&LogKey) -> Option<LogKey>) {
+        self.records = std::mem::take(&mut self.records)
+            .into_iter()
+            .filter_map(|(key, log_key)| f(&log_key).map(|log_key| (key, log_key)))
+            .collect();
+        self.log_keys = self.records.values().cloned().collect();
+    }
+
+    /// Number of keys in the memtable.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&IndexableValue, &LogKey)> {
+        self.records.iter()
+    }
+
+    pub fn range_entries<B: RangeBounds<IndexableValue>>(
+        &self,
+        range: B,
+    ) -> impl Iterator<Item = (&IndexableValue, &LogKey)> {
+        self.records.range(range)
     }
 
     pub fn range<B: RangeBounds<IndexableValue>>(&self, range: B) -> Vec<&LogKey> {
@@ -1,25 +1,32 @@
 use once_cell::sync::Lazy;
 
 use super::*;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-pub struct SecondaryMemtable {
+/// A secondary index. The key is an `IndexableValue` for indexes over a single field, and
+/// a `Vec<IndexableValue>` for composite indexes over several fields.
+pub struct SecondaryMemtable<K = IndexableValue> {
     /// Map of records indexed by key. The value is the set of primary key values of records
     /// that have the secondary key value. The actual `Record` objects are stored in the
     /// primary memtable, which acts as the shared heap.
-    records: BTreeMap<IndexableValue, LogKeySet>,
+    records: BTreeMap<K, LogKeySet>,
+    /// The key of each log key in `records`, so that the entry of a record can be removed by its
+    /// log key alone, e.g. when the record is superseded by a new version with a different key.
+    keys: HashMap<LogKey, K>,
 }
 
 static EMPTY_SET: Lazy<HashSet<LogKey>> = Lazy::new(|| HashSet::new());
 
-impl SecondaryMemtable {
-    pub fn new() -> SecondaryMemtable {
+impl<K: Ord + Clone> SecondaryMemtable<K> {
+    pub fn new() -> SecondaryMemtable<K> {
         SecondaryMemtable {
             records: BTreeMap::new(),
+            keys: HashMap::new(),
         }
     }
 
-    pub fn set(&mut self, key: IndexableValue, value: LogKey) {
+    pub fn set(&mut self, key: K, value: LogKey) {
+        self.keys.insert(value.clone(), key.clone());
         match self.records.get_mut(&key) {
             Some(set) => {
                 set.insert(value);
@@ -30,7 +37,7 @@ impl SecondaryMemtable {
         };
     }
 
-    pub fn find_by(&self, key: &IndexableValue) -> &HashSet<LogKey> {
+    pub fn find_by(&self, key: &K) -> &HashSet<LogKey> {
         match self.records.get(key) {
             Some(set) => set.log_keys(),
             None => &EMPTY_SET,
@@ -39,24 +46,67 @@ impl SecondaryMemtable {
 
     // Remove a single log key associated with the given key. Returns `true`
     // if the log key existed and was removed, `false` otherwise.
-    pub fn remove(&mut self, key: &IndexableValue, log_key: &LogKey) -> bool {
+    pub fn remove(&mut self, key: &K, log_key: &LogKey) -> bool {
         let set = match self.records.get_mut(key) {
             Some(set) => set,
             None => return false,
         };
-        if set.len() == 1 && set.contains(log_key) {
+        let removed = if set.len() == 1 && set.contains(log_key) {
             self.records.remove(key);
             true
         } else {
-            return match set.remove(log_key) {
+            match set.remove(log_key) {
                 Ok(_) => true,
                 Err(LogKeySetError::NotFoundError) => false,
                 Err(e) => panic!("{:?}", e),
-            };
+            }
+        };
+        if removed {
+            self.keys.remove(log_key);
+        }
+        removed
+    }
+
+    /// Remove a log key under whichever key it is associated with. Returns `true` if the log key
+    /// existed and was removed, `false` otherwise.
+    pub fn remove_log_key(&mut self, log_key: &LogKey) -> bool {
+        match self.keys.get(log_key).cloned() {
+            Some(key) => self.remove(&key, log_key),
+            None => false,
         }
     }
 
-    pub fn range<B: RangeBounds<IndexableValue>>(&self, range: B) -> Vec<&LogKey> {
+    /// Replace each log key with `f(log_key)`, removing the log keys for which `f` returns `None`.
+    pub fn remap(&mut self, f: impl Fn(&LogKey) -> Option<LogKey>) {
+        self.keys.clear();
+        for (key, set) in std::mem::take(&mut self.records) {
+            for log_key in set.log_keys() {
+                if let Some(log_key) = f(log_key) {
+                    self.set(key.clone(), log_key);
+                }
+            }
+        }
+    }
+
+    /// Number of distinct keys in the memtable.
+    pub fn key_count(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&K, &HashSet<LogKey>)> {
+        self.records.iter().map(|(key, set)| (key, set.log_keys()))
+    }
+
+    pub fn range_entries<B: RangeBounds<K>>(
+        &self,
+        range: B,
+    ) -> impl Iterator<Item = (&K, &HashSet<LogKey>)> {
+        self.records
+            .range(range)
+            .map(|(key, set)| (key, set.log_keys()))
+    }
+
+    pub fn range<B: RangeBounds<K>>(&self, range: B) -> Vec<&LogKey> {
         let mut keys = Vec::new();
         for (_, set) in self.records.range(range) {
             keys.extend(set.log_keys().iter());
@@ -0,0 +1,106 @@
+use super::*;
+
+/// How to build the records of the schema of `New` from the records of the schema of `Old`, see
+/// `DB::migrate`.
+///
+/// Each field of the new schema is filled from the old field given with `copy`, or with the value
+/// given with `default`. Fields that are not mentioned are copied from the old field with the same
+/// name, as formatted with `Debug`, or filled with `Null` if there is no such field and the new
+/// field is nullable. Old fields that no new field is filled from are dropped.
+pub struct MigrationPlan<Old: Recordable, New: Recordable> {
+    sources: Vec<(New::Field, FieldSource<Old::Field>)>,
+}
+
+pub(crate) enum FieldSource<Field> {
+    Copy(Field),
+    Default(Value),
+}
+
+impl<Old: Recordable, New: Recordable> MigrationPlan<Old, New> {
+    pub fn new() -> MigrationPlan<Old, New> {
+        MigrationPlan { sources: vec![] }
+    }
+
+    /// Fill `field` with the value of `from` in the old record. This renames and reorders fields.
+    pub fn copy(mut self, field: New::Field, from: Old::Field) -> MigrationPlan<Old, New> {
+        self.sources.push((field, FieldSource::Copy(from)));
+        self
+    }
+
+    /// Fill `field` with `value` in every record, e.g. to backfill a new field.
+    pub fn default(mut self, field: New::Field, value: Value) -> MigrationPlan<Old, New> {
+        self.sources.push((field, FieldSource::Default(value)));
+        self
+    }
+
+    /// Resolve the source of each field of the new schema, in schema order.
+    pub(crate) fn resolve(&self) -> DBResult<Vec<FieldSource<usize>>> {
+        let old_schema = Old::schema();
+        let old_index = |field: &Old::Field| {
+            old_schema
+                .iter()
+                .position(|(f, _)| f == field)
+                .ok_or_else(|| {
+                    DBError::ValidationError(format!("Field {:?} not found in schema", field))
+                })
+        };
+
+        New::schema()
+            .iter()
+            .map(|(field, field_type)| {
+                let source = self.sources.iter().rev().find(|(f, _)| f == field);
+                match source {
+                    Some((_, FieldSource::Copy(from))) => Ok(FieldSource::Copy(old_index(from)?)),
+                    Some((_, FieldSource::Default(value))) => {
+                        if !type_check(value, field_type) {
+                            return Err(DBError::ValidationError(format!(
+                                "Default value {:?} of field {:?} does not match its type {:?}",
+                                value, field, field_type.primitive
+                            )));
+                        }
+                        Ok(FieldSource::Default(value.clone()))
+                    }
+                    None => {
+                        let name = format!("{:?}", field);
+                        match old_schema
+                            .iter()
+                            .position(|(f, _)| format!("{:?}", f) == name)
+                        {
+                            Some(index) => Ok(FieldSource::Copy(index)),
+                            None if field_type.nullable => Ok(FieldSource::Default(Value::Null)),
+                            None => Err(DBError::ValidationError(format!(
+                                "Field {:?} has no source and is not nullable",
+                                field
+                            ))),
+                        }
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+impl<Old: Recordable, New: Recordable> Default for MigrationPlan<Old, New> {
+    fn default() -> Self {
+        MigrationPlan::new()
+    }
+}
+
+/// Build the record of the new schema from `record` with the resolved `sources`. The flags,
+/// version and timestamp of the record are kept.
+pub(crate) fn migrate_record<Field: Eq>(
+    mut record: Record,
+    sources: &[FieldSource<usize>],
+    new_schema: &Vec<(Field, Type)>,
+) -> DBResult<Record> {
+    let values = sources
+        .iter()
+        .map(|source| match source {
+            FieldSource::Copy(index) => record.values[*index].clone(),
+            FieldSource::Default(value) => value.clone(),
+        })
+        .collect();
+    record.values = values;
+    record.validate(new_schema)?;
+    Ok(record)
+}
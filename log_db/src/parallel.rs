@@ -0,0 +1,134 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc;
+
+/// Map `items` with `map` on up to `threads` scoped threads, taking `batch_size` items at a time,
+/// and pass the results to `write` in the order of the items as soon as they are available, see
+/// `ConfigBuilder::compaction_threads`. `write` runs on the calling thread, so that e.g. a file can
+/// be appended to in order while the following items are still being mapped.
+///
+/// At most `2 * threads` batches are mapped ahead of the one being written. The first error, of
+/// either `map` or `write`, stops the mapping and is returned.
+pub fn map_ordered<T: Sync, U: Send>(
+    items: &[T],
+    threads: usize,
+    batch_size: usize,
+    map: impl Fn(&T) -> DBResult<U> + Sync,
+    mut write: impl FnMut(U) -> DBResult<()>,
+) -> DBResult<()> {
+    let batch_size = batch_size.max(1);
+    let batches = items.len().div_ceil(batch_size);
+    let threads = threads.min(batches);
+    if threads <= 1 {
+        for item in items {
+            write(map(item)?)?;
+        }
+        return Ok(());
+    }
+
+    let next_batch = AtomicUsize::new(0);
+    // Written batches free up a slot each, so that fast threads do not map the whole input ahead
+    let (slot_sender, slot_receiver) = mpsc::sync_channel::<()>(2 * threads);
+    let slot_receiver = std::sync::Mutex::new(slot_receiver);
+    for _ in 0..2 * threads {
+        slot_sender.send(()).expect("Slot receiver is alive");
+    }
+
+    thread::scope(|scope| {
+        // Dropped along with the receiver when the writer stops, so that waiting threads stop too
+        let slot_sender = slot_sender;
+        let (sender, receiver) = mpsc::channel::<(usize, DBResult<Vec<U>>)>();
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let (next_batch, slot_receiver, map) = (&next_batch, &slot_receiver, &map);
+            scope.spawn(move || loop {
+                if slot_receiver.lock().unwrap().recv().is_err() {
+                    return;
+                }
+                let batch = next_batch.fetch_add(1, AtomicOrdering::Relaxed);
+                if batch >= batches {
+                    return;
+                }
+                let batch_items =
+                    &items[batch * batch_size..((batch + 1) * batch_size).min(items.len())];
+                let mapped = batch_items.iter().map(map).collect();
+                if sender.send((batch, mapped)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        for next in 0..batches {
+            let mapped = loop {
+                if let Some(mapped) = pending.remove(&next) {
+                    break mapped;
+                }
+                let (batch, mapped) = receiver.recv().expect("Mapping threads are alive");
+                pending.insert(batch, mapped);
+            };
+            for result in mapped? {
+                write(result)?;
+            }
+            // The threads may have stopped after mapping the last batches
+            let _ = slot_sender.try_send(());
+        }
+        Ok(())
+    })
+}
+
+/// The number of threads to use by default, one per available CPU.
+pub fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_ordered() {
+        let items: Vec<u64> = (0..200).collect();
+        for threads in [1, 2, 8] {
+            let mut written = vec![];
+            map_ordered(
+                &items,
+                threads,
+                7,
+                |item| {
+                    // Later items finish first
+                    thread::sleep(Duration::from_micros(200 - item));
+                    Ok(item * 2)
+                },
+                |doubled| {
+                    written.push(doubled);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(
+                written,
+                items.iter().map(|item| item * 2).collect::<Vec<_>>()
+            );
+        }
+
+        // The first error stops both the mapping and the writing
+        let mut written = 0;
+        let result = map_ordered(
+            &items,
+            4,
+            10,
+            |&item| match item {
+                100 => Err(DBError::ValidationError("item 100".to_owned())),
+                _ => Ok(item),
+            },
+            |_| {
+                written += 1;
+                Ok(())
+            },
+        );
+        assert!(matches!(result, Err(DBError::ValidationError(_))));
+        assert_eq!(written, 100);
+    }
+}
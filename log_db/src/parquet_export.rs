@@ -0,0 +1,273 @@
+use super::*;
+use crate::export::EXPORT_BATCH_SIZE;
+use arrow_array::{
+    ArrayRef, BinaryArray, FixedSizeBinaryArray, Float64Array, Int64Array, RecordBatch,
+    StringArray, StructArray, TimestampMicrosecondArray,
+};
+use arrow_schema::{DataType, Field as ArrowField, Fields, Schema, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression as ParquetCompression;
+use parquet::file::properties::WriterProperties;
+use std::sync::Arc;
+
+impl<R: Recordable> Engine<R> {
+    /// Write the current records into a new Parquet file at `path`, see `DB::export_parquet`.
+    /// The caller must hold a lock that prevents writes, so that the export is a consistent snapshot.
+    pub fn export_parquet(&mut self, path: &Path) -> DBResult<u64> {
+        if fs::exists(path)? {
+            return Err(DBError::ValidationError(format!(
+                "Export target {} already exists",
+                path.display()
+            )));
+        }
+
+        let fields: Vec<ArrowField> = self
+            .config
+            .fields
+            .iter()
+            .map(|(field, field_type)| arrow_field(&format!("{:?}", field), field_type))
+            .collect();
+        let schema = Arc::new(Schema::new(fields));
+        let properties = WriterProperties::builder()
+            .set_compression(ParquetCompression::SNAPPY)
+            .build();
+
+        // Written to a temporary file first, so that a failed export leaves no partial file behind
+        let parent_path = match path.parent() {
+            Some(parent_path) if !parent_path.as_os_str().is_empty() => parent_path,
+            _ => Path::new("."),
+        };
+        let tmp_file = tempfile::NamedTempFile::new_in(parent_path)?;
+        let mut writer = ArrowWriter::try_new(
+            tmp_file.as_file().try_clone()?,
+            schema.clone(),
+            Some(properties),
+        )?;
+
+        let primary_key = self.config.primary_key.clone();
+        let mut exported = 0;
+        let mut start = Bound::Unbounded;
+        loop {
+            let bounds = OwnedBounds::new(start, Bound::Unbounded);
+            let (records, next_start) =
+                self.range_by_batch(&primary_key, bounds, EXPORT_BATCH_SIZE)?;
+
+            if !records.is_empty() {
+                let columns = self
+                    .config
+                    .fields
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, field_type))| {
+                        let values: Vec<&Value> =
+                            records.iter().map(|record| &record.values[i]).collect();
+                        build_array(&values, field_type)
+                    })
+                    .collect::<DBResult<Vec<ArrayRef>>>()?;
+                writer
+                    .write(&RecordBatch::try_new(schema.clone(), columns).map_err(arrow_error)?)?;
+                exported += records.len() as u64;
+            }
+
+            match next_start {
+                Some(key) => start = Bound::Excluded(key),
+                None => break,
+            }
+        }
+
+        writer.close()?;
+        tmp_file.as_file().sync_all()?;
+        tmp_file.persist_noclobber(path).map_err(|e| e.error)?;
+        Ok(exported)
+    }
+}
+
+/// Decimals are exported as strings, since their scale varies from value to value while a Parquet
+/// decimal column has a single scale. Timestamps are exported as UTC timestamps in microseconds,
+/// enums and JSON as strings, geo points as structs of `lat` and `lon`, and nested records as
+/// structs of their fields.
+fn arrow_field(name: &str, field_type: &Type) -> ArrowField {
+    let data_type = match &field_type.primitive {
+        PrimitiveType::Int => DataType::Int64,
+        PrimitiveType::Float => DataType::Float64,
+        PrimitiveType::Decimal
+        | PrimitiveType::String
+        | PrimitiveType::Enum(_)
+        | PrimitiveType::Json => DataType::Utf8,
+        PrimitiveType::Bytes => DataType::Binary,
+        PrimitiveType::FixedBytes(len) => DataType::FixedSizeBinary(*len as i32),
+        PrimitiveType::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+        PrimitiveType::GeoPoint => DataType::Struct(geo_point_fields()),
+        PrimitiveType::Record(fields) => DataType::Struct(
+            fields
+                .iter()
+                .map(|(name, field_type)| arrow_field(name, field_type))
+                .collect(),
+        ),
+    };
+    ArrowField::new(name, data_type, field_type.nullable)
+}
+
+fn geo_point_fields() -> Fields {
+    Fields::from(vec![
+        ArrowField::new("lat", DataType::Float64, false),
+        ArrowField::new("lon", DataType::Float64, false),
+    ])
+}
+
+/// Build the column of a field from its values, which have been validated against `field_type`.
+fn build_array(values: &[&Value], field_type: &Type) -> DBResult<ArrayRef> {
+    let array: ArrayRef = match &field_type.primitive {
+        PrimitiveType::Int => Arc::new(Int64Array::from_iter(values.iter().map(
+            |value| match value {
+                Value::Int(i) => Some(*i),
+                _ => None,
+            },
+        ))),
+        PrimitiveType::Timestamp => Arc::new(
+            TimestampMicrosecondArray::from_iter(values.iter().map(|value| match value {
+                Value::Timestamp(t) => Some(*t),
+                _ => None,
+            }))
+            .with_timezone("UTC"),
+        ),
+        PrimitiveType::Float => {
+            Arc::new(Float64Array::from_iter(values.iter().map(
+                |value| match value {
+                    Value::Float(f) => Some(*f),
+                    _ => None,
+                },
+            )))
+        }
+        PrimitiveType::Decimal
+        | PrimitiveType::String
+        | PrimitiveType::Enum(_)
+        | PrimitiveType::Json => {
+            Arc::new(StringArray::from_iter(values.iter().map(
+                |value| match value {
+                    Value::Decimal(d) => Some(d.to_string()),
+                    Value::String(s) | Value::Json(s) => Some(s.clone()),
+                    _ => None,
+                },
+            )))
+        }
+        PrimitiveType::Bytes => {
+            Arc::new(BinaryArray::from_iter(values.iter().map(
+                |value| match value {
+                    Value::Bytes(b) => Some(b.as_slice()),
+                    _ => None,
+                },
+            )))
+        }
+        PrimitiveType::FixedBytes(len) => Arc::new(
+            FixedSizeBinaryArray::try_from_sparse_iter_with_size(
+                values.iter().map(|value| match value {
+                    Value::Bytes(b) => Some(b.as_slice()),
+                    _ => None,
+                }),
+                *len as i32,
+            )
+            .map_err(arrow_error)?,
+        ),
+        PrimitiveType::GeoPoint => {
+            let coordinate = |pick: fn(f64, f64) -> f64| -> ArrayRef {
+                Arc::new(Float64Array::from_iter_values(values.iter().map(
+                    |value| match value {
+                        Value::GeoPoint(lat, lon) => pick(*lat, *lon),
+                        _ => 0.0,
+                    },
+                )))
+            };
+            let children = vec![coordinate(|lat, _| lat), coordinate(|_, lon| lon)];
+            Arc::new(
+                StructArray::try_new(geo_point_fields(), children, struct_nulls(values))
+                    .map_err(arrow_error)?,
+            )
+        }
+        PrimitiveType::Record(fields) => {
+            let arrow_fields: Fields = fields
+                .iter()
+                .map(|(name, field_type)| arrow_field(name, field_type))
+                .collect();
+            // The fields of a null record are null, or their defaults if not nullable, masked by
+            // the null of the record
+            let children = fields
+                .iter()
+                .enumerate()
+                .map(|(i, (_, field_type))| {
+                    let child_values: Vec<&Value> = values
+                        .iter()
+                        .map(|value| match value {
+                            Value::Record(nested) => &nested[i],
+                            _ => &Value::Null,
+                        })
+                        .collect();
+                    build_array(&child_values, field_type)
+                })
+                .collect::<DBResult<Vec<ArrayRef>>>()?;
+            Arc::new(
+                StructArray::try_new(arrow_fields, children, struct_nulls(values))
+                    .map_err(arrow_error)?,
+            )
+        }
+    };
+    Ok(array)
+}
+
+fn struct_nulls(values: &[&Value]) -> Option<arrow_buffer::NullBuffer> {
+    let valid: Vec<bool> = values
+        .iter()
+        .map(|value| !matches!(value, Value::Null))
+        .collect();
+    if valid.iter().all(|&valid| valid) {
+        None
+    } else {
+        Some(arrow_buffer::NullBuffer::from(valid))
+    }
+}
+
+fn arrow_error(e: arrow_schema::ArrowError) -> DBError {
+    DBError::ParquetError(e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+
+    #[test]
+    fn test_nested_columns() {
+        let location =
+            Type::record(&[("name", Type::string()), ("point", Type::geo_point())]).nullable();
+        let present = Value::Record(vec![
+            Value::String("home".to_owned()),
+            Value::GeoPoint(60.2, 24.9),
+        ]);
+        let array = build_array(&[&present, &Value::Null], &location).unwrap();
+        assert_eq!(
+            array.data_type(),
+            arrow_field("location", &location).data_type()
+        );
+
+        let locations = array.as_any().downcast_ref::<StructArray>().unwrap();
+        assert!(locations.is_valid(0));
+        assert!(locations.is_null(1));
+        let names = locations
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(names.value(0), "home");
+        let points = locations
+            .column(1)
+            .as_any()
+            .downcast_ref::<StructArray>()
+            .unwrap();
+        let lons = points
+            .column(1)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(lons.value(0), 24.9);
+    }
+}
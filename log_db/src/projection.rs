@@ -0,0 +1,66 @@
+use super::*;
+
+/// A selection of values from records by dotted paths, e.g. `Address.city` for the `city` field
+/// of the nested record in the `Address` field. The first part of a path is the name of a field
+/// of the schema as formatted with `Debug`, like in `IndexDump`, and the following parts are the
+/// names of nested fields, see `Type::record`.
+#[derive(Debug, Clone)]
+pub struct Projection {
+    /// The index of the field at each level of each path
+    paths: Vec<Vec<usize>>,
+}
+
+impl Projection {
+    /// Resolve `paths` against the schema of `R`. Returns an error for paths that do not exist.
+    pub fn new<R: Recordable>(paths: &[&str]) -> DBResult<Projection> {
+        let schema = R::schema();
+        let paths = paths
+            .iter()
+            .map(|path| {
+                let mut parts = path.split('.');
+                let name = parts.next().unwrap_or_default();
+                let not_found = || DBError::ValidationError(format!("No such field: {}", path));
+
+                let index = schema
+                    .iter()
+                    .position(|(field, _)| format!("{:?}", field) == name)
+                    .ok_or_else(not_found)?;
+                let mut indexes = vec![index];
+                let mut field_type = &schema[index].1;
+                for part in parts {
+                    let PrimitiveType::Record(fields) = &field_type.primitive else {
+                        return Err(not_found());
+                    };
+                    let index = fields
+                        .iter()
+                        .position(|(name, _)| name == part)
+                        .ok_or_else(not_found)?;
+                    indexes.push(index);
+                    field_type = &fields[index].1;
+                }
+                Ok(indexes)
+            })
+            .collect::<DBResult<_>>()?;
+        Ok(Projection { paths })
+    }
+
+    /// The values at the paths in the values of a record, e.g. from `Recordable::into_record`.
+    /// A path through a null record yields `Null`.
+    pub fn apply(&self, values: &[Value]) -> Vec<Value> {
+        self.paths
+            .iter()
+            .map(|path| {
+                let mut values = values;
+                let mut value = &Value::Null;
+                for &index in path {
+                    value = values.get(index).unwrap_or(&Value::Null);
+                    match value {
+                        Value::Record(nested) => values = nested,
+                        _ => values = &[],
+                    }
+                }
+                value.clone()
+            })
+            .collect()
+    }
+}
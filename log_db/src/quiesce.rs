@@ -0,0 +1,74 @@
+use super::*;
+
+/// How long a writer waits before checking again whether the data directory is still quiesced.
+pub const QUIESCE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A request for the other handles of a data directory to stop writing, see `DB::quiesce`. The
+/// request is a flag file that its owner holds an exclusive lock on, and it is lifted by removing
+/// the file when the flag is dropped. The flag of a process that crashed is left behind, but since
+/// its lock was released, `is_quiesced` recognizes and removes it.
+pub struct QuiesceFlag {
+    path: PathBuf,
+    _file: fs::File,
+}
+
+impl QuiesceFlag {
+    /// Raise the flag, or return `DBError::LockRequestError` if another handle has raised it.
+    pub fn raise(data_dir_path: &Path) -> DBResult<QuiesceFlag> {
+        if is_quiesced(data_dir_path)? {
+            return Err(DBError::LockRequestError(
+                "The data directory is already quiesced by another handle".to_owned(),
+            ));
+        }
+
+        // The file is locked before it is moved into place, so it is never seen unlocked
+        let tmp_file = fs::NamedTempFile::new_in(data_dir_path)?;
+        tmp_file.as_file().lock_exclusive()?;
+
+        let path = data_dir_path.join(QUIESCE_FILENAME);
+        let file = tmp_file.persist_noclobber(&path).map_err(|e| {
+            if e.error.kind() == io::ErrorKind::AlreadyExists {
+                DBError::LockRequestError(
+                    "The data directory is already quiesced by another handle".to_owned(),
+                )
+            } else {
+                e.error.into()
+            }
+        })?;
+
+        Ok(QuiesceFlag { path, _file: file })
+    }
+}
+
+impl Drop for QuiesceFlag {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove quiesce flag {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Check whether a handle has quiesced the data directory. A flag left behind by a process that
+/// exited without lifting it is removed.
+pub fn is_quiesced(data_dir_path: &Path) -> DBResult<bool> {
+    let path = data_dir_path.join(QUIESCE_FILENAME);
+    let file = match READ_MODE.open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+
+    match file.try_lock_shared() {
+        Ok(()) => {
+            debug!("Removing stale quiesce flag {}", path.display());
+            fs::remove_file(&path).ok();
+            Ok(false)
+        }
+        Err(e) if e.kind() == lock_contended_error().kind() => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
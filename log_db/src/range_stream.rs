@@ -0,0 +1,78 @@
+use super::*;
+use std::collections::VecDeque;
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// An iterator over the records in a range of an index, returned by `DB::range_by_stream`.
+/// The records are read from the log in batches of index keys as the iterator is consumed, so the
+/// whole range is never held in memory at once. The shared lock is only held while a batch is read.
+///
+/// Each batch reflects the database at the time it is read, so writes made while iterating may or
+/// may not be seen, but each key of the index is visited at most once and in order.
+pub struct RangeStream<'a, R: Recordable> {
+    db: &'a mut DB<R>,
+    field: R::Field,
+    start: Bound<IndexableValue>,
+    end: Bound<IndexableValue>,
+    batch_size: usize,
+    buffer: VecDeque<Record>,
+    exhausted: bool,
+}
+
+impl<'a, R: Recordable> RangeStream<'a, R> {
+    pub(crate) fn new(
+        db: &'a mut DB<R>,
+        field: R::Field,
+        bounds: OwnedBounds<IndexableValue>,
+    ) -> RangeStream<'a, R> {
+        RangeStream {
+            db,
+            field,
+            start: bounds.start_bound().cloned(),
+            end: bounds.end_bound().cloned(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            buffer: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    /// Set the number of index keys read per batch. Defaults to 1000.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    fn read_batch(&mut self) -> DBResult<()> {
+        let bounds = OwnedBounds::new(self.start.clone(), self.end.clone());
+        let (field, batch_size) = (&self.field, self.batch_size);
+        let (records, next_start) = self
+            .db
+            .engine
+            .with_shared_lock(|engine| engine.range_by_batch(field, bounds, batch_size))?;
+
+        self.buffer.extend(records);
+        match next_start {
+            Some(key) => self.start = Bound::Excluded(key),
+            None => self.exhausted = true,
+        }
+        Ok(())
+    }
+}
+
+impl<R: Recordable> Iterator for RangeStream<'_, R> {
+    type Item = DBResult<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // A batch may be empty if all of its records were superseded
+        while self.buffer.is_empty() && !self.exhausted {
+            if let Err(e) = self.read_batch() {
+                self.exhausted = true;
+                return Some(Err(e));
+            }
+        }
+
+        self.buffer
+            .pop_front()
+            .map(|record| Ok(R::from_record(record.values)))
+    }
+}
@@ -4,20 +4,89 @@ use super::*;
 pub struct Record {
     pub values: Vec<Value>,
     pub tombstone: bool,
+    /// The record is a merge delta, to be folded into the previous version of the record
+    /// with the configured merge operator.
+    pub delta: bool,
+    /// The record was soft-deleted, see `DeleteMode::Soft`.
+    pub deleted: bool,
+    /// Write sequence number assigned by the engine. See `RecordMeta::version`.
+    pub version: u64,
+    /// Time of the write, assigned by the engine. Stored with microsecond precision.
+    pub timestamp: SystemTime,
+}
+
+/// Engine-managed information about a stored record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordMeta {
+    /// The version of the record. Every write is assigned a version that is greater than that of
+    /// any earlier write in the database, so the version of a key increases each time it is written.
+    /// Versions are not contiguous per key. They are preserved by compaction.
+    pub version: u64,
+    /// The wall clock time of the write that stored the record. All records of a batch write share
+    /// the same timestamp. Unlike versions, timestamps follow the system clock and are not
+    /// guaranteed to increase.
+    pub timestamp: SystemTime,
+    /// Whether the record is soft-deleted. Soft-deleted records are only returned by queries made
+    /// with `DB::include_deleted`.
+    pub deleted: bool,
+}
+
+/// Describes where and when a record was stored by a write.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteReceipt {
+    pub position: LogPosition,
+    pub meta: RecordMeta,
+}
+
+/// A surviving version of a record in the log, as returned by `DB::history`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordVersion<R> {
+    pub position: LogPosition,
+    pub meta: RecordMeta,
+    pub kind: VersionKind,
+    /// The values written. For a deletion, the values of the record that was deleted.
+    /// For a merge, the delta that was merged.
+    pub record: R,
+}
+
+/// How a version in the history of a record was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionKind {
+    /// A full version, written e.g. with `DB::upsert`.
+    Write,
+    /// A delta written with `DB::merge`.
+    Merge,
+    /// A deletion.
+    Delete,
+    /// A soft deletion, see `DeleteMode::Soft`.
+    SoftDelete,
 }
 
 impl Record {
     pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with(|_, value, bytes| value.serialize_into(bytes))
+    }
+
+    /// Like `serialize`, with each value appended by `serialize_value`, which is given the index
+    /// of its field.
+    pub fn serialize_with(&self, serialize_value: impl Fn(usize, &Value, &mut Vec<u8>)) -> Vec<u8> {
         let mut bytes = Vec::new();
 
         if self.tombstone {
             bytes.extend(&[B_TOMBSTONE]);
+        } else if self.delta {
+            bytes.extend(&[B_MERGE]);
+        } else if self.deleted {
+            bytes.extend(&[B_DELETED]);
         } else {
             bytes.extend(&[B_LIVE]);
         }
 
-        for value in &self.values {
-            bytes.extend(value.serialize());
+        bytes.extend(self.version.to_be_bytes());
+        bytes.extend(timestamp_to_micros(self.timestamp).to_be_bytes());
+
+        for (i, value) in self.values.iter().enumerate() {
+            serialize_value(i, value, &mut bytes);
         }
         bytes
     }
@@ -28,20 +97,45 @@ impl Record {
         let mut values = Vec::new();
 
         let tombstone = bytes[0] == B_TOMBSTONE;
+        let delta = bytes[0] == B_MERGE;
+        let deleted = bytes[0] == B_DELETED;
+        let version = u64::from_be_bytes(bytes[1..1 + 8].try_into().unwrap());
+        let timestamp = micros_to_timestamp(u64::from_be_bytes(
+            bytes[1 + 8..1 + 8 + 8].try_into().unwrap(),
+        ));
 
-        let mut start = 1;
+        let mut start = 1 + 8 + 8;
         while start < bytes.len() {
             let (rv, consumed) = Value::deserialize(&bytes[start..]);
             values.push(rv);
             start += consumed;
         }
-        Record { values, tombstone }
+        Record {
+            values,
+            tombstone,
+            delta,
+            deleted,
+            version,
+            timestamp,
+        }
     }
 
     pub fn from(values: &[Value]) -> Record {
         Record {
             values: values.to_vec(),
             tombstone: false,
+            delta: false,
+            deleted: false,
+            version: 0,
+            timestamp: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    pub fn meta(&self) -> RecordMeta {
+        RecordMeta {
+            version: self.version,
+            timestamp: self.timestamp,
+            deleted: self.deleted,
         }
     }
 
@@ -61,21 +155,44 @@ impl Record {
 
         // Validate that record fields match schema types
         for (i, (_, field)) in schema.iter().enumerate() {
+            check_max_len(i, value_len(&self.values[i]), field)?;
             match (&self.values[i], field) {
+                (Value::Null, Type { nullable: true, .. }) => {}
                 (
-                    Value::Null,
+                    Value::Int(_),
                     Type {
-                        nullable: true,
-                        primitive: _,
+                        primitive: PrimitiveType::Int,
+                        ..
                     },
                 ) => {}
                 (
-                    Value::Int(_),
+                    Value::Decimal(_),
                     Type {
-                        primitive: PrimitiveType::Int,
+                        primitive: PrimitiveType::Decimal,
                         ..
                     },
                 ) => {}
+                (
+                    Value::Float(_),
+                    Type {
+                        primitive: PrimitiveType::Float,
+                        ..
+                    },
+                ) => {}
+                (
+                    Value::GeoPoint(lat, lon),
+                    Type {
+                        primitive: PrimitiveType::GeoPoint,
+                        ..
+                    },
+                ) if is_valid_geo_point(*lat, *lon) => {}
+                (
+                    value @ Value::Record(_),
+                    field @ Type {
+                        primitive: PrimitiveType::Record(_),
+                        ..
+                    },
+                ) if type_check(value, field) => {}
                 (
                     Value::String(_),
                     Type {
@@ -90,6 +207,60 @@ impl Record {
                         ..
                     },
                 ) => {}
+                (
+                    Value::Timestamp(_),
+                    Type {
+                        primitive: PrimitiveType::Timestamp,
+                        ..
+                    },
+                ) => {}
+                (
+                    Value::Json(s),
+                    Type {
+                        primitive: PrimitiveType::Json,
+                        ..
+                    },
+                ) if is_valid_json(s) => {}
+                (
+                    Value::Bytes(b),
+                    Type {
+                        primitive: PrimitiveType::FixedBytes(len),
+                        ..
+                    },
+                ) if b.len() != *len => {
+                    return Err(DBError::ValidationError(format!(
+                        "Record field {} has {} bytes, expected {}",
+                        &i,
+                        b.len(),
+                        len
+                    )));
+                }
+                (
+                    Value::Bytes(_),
+                    Type {
+                        primitive: PrimitiveType::FixedBytes(_),
+                        ..
+                    },
+                ) => {}
+                (
+                    Value::String(s),
+                    Type {
+                        primitive: PrimitiveType::Enum(variants),
+                        ..
+                    },
+                ) if variants.contains(s) => {}
+                (
+                    Value::String(s),
+                    Type {
+                        primitive: PrimitiveType::Enum(variants),
+                        ..
+                    },
+                ) => {
+                    return Err(DBError::ValidationError(format!(
+                        "Record field {} has value {:?}, expected one of {:?}",
+                        &i, s, variants
+                    )));
+                }
                 _ => {
                     return Err(DBError::ValidationError(format!(
                         "Record field {} has incorrect type: {:?}, expected {:?}",
@@ -102,7 +273,155 @@ impl Record {
     }
 }
 
+/// The length in bytes of a string or bytes value, which is limited by `Type::max_len`.
+fn value_len(value: &Value) -> Option<usize> {
+    match value {
+        Value::String(s) => Some(s.len()),
+        Value::Bytes(b) => Some(b.len()),
+        _ => None,
+    }
+}
+
+fn check_max_len(i: usize, len: Option<usize>, field: &Type) -> DBResult<()> {
+    match (len, field.max_len) {
+        (Some(len), Some(max_len)) if len > max_len => Err(DBError::ValidationError(format!(
+            "Record field {} has {} bytes, at most {} allowed",
+            i, len, max_len
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Validate the values of a record serialized with `Record::serialize` against the schema like
+/// `Record::validate` does, without decoding them into `Value`s. Returns the values borrowed from
+/// `bytes`. The flag, version and timestamp of the record are not checked. Values of enum fields
+/// may be stored either as the index of their variant or as the variant itself.
+pub fn validate_serialized<'a, Field: Eq>(
+    bytes: &'a [u8],
+    schema: &[(Field, Type)],
+) -> DBResult<Vec<ValueRef<'a>>> {
+    let mut rest = bytes.get(RECORD_HEADER_SIZE..).ok_or_else(|| {
+        DBError::ValidationError("Serialized record is shorter than its header".to_owned())
+    })?;
+
+    let mut values = Vec::with_capacity(schema.len());
+    while !rest.is_empty() {
+        let (value, consumed) = ValueRef::try_deserialize(rest).map_err(|e| {
+            DBError::ValidationError(format!(
+                "Record field {} is not a valid value: {}",
+                values.len(),
+                e
+            ))
+        })?;
+        values.push(value);
+        rest = &rest[consumed..];
+    }
+
+    if values.len() != schema.len() {
+        return Err(DBError::ValidationError(format!(
+            "Record has an incorrect number of fields: {}, expected {}",
+            values.len(),
+            schema.len()
+        )));
+    }
+
+    for (i, ((_, field), value)) in schema.iter().zip(&values).enumerate() {
+        let len = match value {
+            ValueRef::String(s) => Some(s.len()),
+            ValueRef::Bytes(b) => Some(b.len()),
+            _ => None,
+        };
+        check_max_len(i, len, field)?;
+        let valid = match value {
+            ValueRef::Null => field.nullable,
+            ValueRef::Int(i) => match &field.primitive {
+                PrimitiveType::Int => true,
+                PrimitiveType::Enum(variants) => {
+                    usize::try_from(*i).is_ok_and(|i| i < variants.len())
+                }
+                _ => false,
+            },
+            ValueRef::String(s) => match &field.primitive {
+                PrimitiveType::String => true,
+                PrimitiveType::Enum(variants) => variants.iter().any(|v| v == s),
+                _ => false,
+            },
+            ValueRef::Bytes(b) => match field.primitive {
+                PrimitiveType::Bytes => true,
+                PrimitiveType::FixedBytes(len) => b.len() == len,
+                _ => false,
+            },
+            ValueRef::Timestamp(_) => matches!(field.primitive, PrimitiveType::Timestamp),
+            ValueRef::Json(s) => matches!(field.primitive, PrimitiveType::Json) && is_valid_json(s),
+            ValueRef::Decimal(_) => matches!(field.primitive, PrimitiveType::Decimal),
+            ValueRef::Float(_) => matches!(field.primitive, PrimitiveType::Float),
+            ValueRef::Record(_) => {
+                matches!(field.primitive, PrimitiveType::Record(_))
+                    && type_check(&value.to_value(), field)
+            }
+            ValueRef::GeoPoint(lat, lon) => {
+                matches!(field.primitive, PrimitiveType::GeoPoint) && is_valid_geo_point(*lat, *lon)
+            }
+        };
+        if !valid {
+            return Err(DBError::ValidationError(format!(
+                "Record field {} has incorrect type: {:?}, expected {:?}",
+                i, value, field.primitive
+            )));
+        }
+    }
+    Ok(values)
+}
+
+/// The current time, truncated to the precision that record timestamps are stored with.
+pub fn current_timestamp() -> SystemTime {
+    micros_to_timestamp(timestamp_to_micros(SystemTime::now()))
+}
+
+/// Milliseconds since the Unix epoch, the unit of expiry times, see `ConfigBuilder::expiry_field`.
+pub fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+pub(crate) fn timestamp_to_micros(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+pub(crate) fn micros_to_timestamp(micros: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_micros(micros)
+}
+
 /// A trait that describes how to convert a data structure into a database record and vice versa.
+///
+/// The trait can be derived for structs with named fields whose types implement `FieldValue`.
+/// The derive generates the field enum, named after the struct with a `Field` suffix unless named
+/// with `#[log_db(field_enum = Name)]`, with a variant for each field in camel case:
+///
+/// ```
+/// use log_db::{Recordable, Type};
+///
+/// #[derive(Recordable)]
+/// struct User {
+///     #[log_db(primary_key)]
+///     id: i64,
+///     #[log_db(secondary_key)]
+///     email: String,
+///     #[log_db(field_type = Type::enum_of(&["admin", "member"]))]
+///     role: String,
+///     nickname: Option<String>,
+/// }
+///
+/// assert_eq!(User::primary_key(), UserField::Id);
+/// assert_eq!(User::secondary_keys(), vec![UserField::Email]);
+/// ```
+///
+/// The type of a field is `FieldValue::field_type` of its Rust type, or the type given with
+/// `field_type`, which must have the same representation as a `Value`.
 pub trait Recordable {
     /// The field type of the data structure implementing the `Recordable` trait.
     type Field: Eq + Clone + Debug;
@@ -114,6 +433,12 @@ pub trait Recordable {
     fn secondary_keys() -> Vec<Self::Field> {
         Vec::new()
     }
+    /// Define the composite secondary keys of the instance implementing the `Recordable` trait.
+    /// A composite key indexes several fields together, e.g. `vec![vec![Field::Name, Field::Id]]`,
+    /// so that records can be queried efficiently by the leading fields of the key.
+    fn composite_keys() -> Vec<Vec<Self::Field>> {
+        Vec::new()
+    }
 
     /// Convert the data structure implementing the `Recordable` trait into a vector of database values.
     fn into_record(self) -> Vec<Value>;
@@ -121,6 +446,133 @@ pub trait Recordable {
     fn from_record(record: Vec<Value>) -> Self;
 }
 
+/// A Rust type of a field of a struct that derives `Recordable`, with the type of the field and
+/// conversions to and from its values. `Option`s of these types are nullable.
+pub trait FieldValue: Sized {
+    fn field_type() -> Type;
+    fn into_value(self) -> Value;
+    /// Convert a value read from the database, or `None` if it is not a value of this type.
+    fn from_value(value: Value) -> Option<Self>;
+}
+
+impl FieldValue for i64 {
+    fn field_type() -> Type {
+        Type::int()
+    }
+    fn into_value(self) -> Value {
+        Value::Int(self)
+    }
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+}
+
+impl FieldValue for f64 {
+    fn field_type() -> Type {
+        Type::float()
+    }
+    fn into_value(self) -> Value {
+        Value::Float(self)
+    }
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+}
+
+/// A geo point as (latitude, longitude).
+impl FieldValue for (f64, f64) {
+    fn field_type() -> Type {
+        Type::geo_point()
+    }
+    fn into_value(self) -> Value {
+        Value::GeoPoint(self.0, self.1)
+    }
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::GeoPoint(lat, lon) => Some((lat, lon)),
+            _ => None,
+        }
+    }
+}
+
+impl FieldValue for Decimal {
+    fn field_type() -> Type {
+        Type::decimal()
+    }
+    fn into_value(self) -> Value {
+        Value::Decimal(self)
+    }
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Decimal(d) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+impl FieldValue for String {
+    fn field_type() -> Type {
+        Type::string()
+    }
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl FieldValue for Vec<u8> {
+    fn field_type() -> Type {
+        Type::bytes()
+    }
+    fn into_value(self) -> Value {
+        Value::Bytes(self)
+    }
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl FieldValue for SystemTime {
+    fn field_type() -> Type {
+        Type::timestamp()
+    }
+    fn into_value(self) -> Value {
+        Value::from_system_time(self)
+    }
+    fn from_value(value: Value) -> Option<Self> {
+        value.as_system_time()
+    }
+}
+
+impl<T: FieldValue> FieldValue for Option<T> {
+    fn field_type() -> Type {
+        T::field_type().nullable()
+    }
+    fn into_value(self) -> Value {
+        self.map_or(Value::Null, T::into_value)
+    }
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -134,6 +586,10 @@ mod tests {
                 Value::Bytes(vec![0, 1, 2, 3]),
             ],
             tombstone: true,
+            delta: false,
+            deleted: false,
+            version: 7,
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456),
         };
 
         let serialized = record.serialize();
@@ -142,5 +598,7 @@ mod tests {
 
         assert_eq!(serialized.len(), reserialized.len());
         assert_eq!(record.values, deserialized.values);
+        assert_eq!(record.version, deserialized.version);
+        assert_eq!(record.timestamp, deserialized.timestamp);
     }
 }
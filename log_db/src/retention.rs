@@ -0,0 +1,57 @@
+use super::*;
+
+/// Limits on the data directory that `db.do_maintenance_tasks()` keeps to by dropping and merging
+/// the oldest segments, see `ConfigBuilder::retention`. Only superseded records are ever removed,
+/// so the limits cannot be met if the live records alone exceed them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// The maximum total size of the files in the data directory, in bytes.
+    pub max_total_bytes: Option<u64>,
+    /// The maximum number of segments, including the active segment.
+    pub max_segments: Option<usize>,
+    /// The maximum age of a sealed segment, measured from its last modification.
+    pub max_age: Option<Duration>,
+}
+
+/// Called when the live records exceed the retention policy, see `ConfigBuilder::retention_hook`.
+pub type RetentionHook = fn(&RetentionReport);
+
+/// The state of the data directory after maintenance could not bring it within the retention
+/// policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionReport {
+    /// Total size of the files in the data directory, in bytes.
+    pub total_bytes: u64,
+    /// Number of segments, including the active segment.
+    pub segments: usize,
+    /// The segments dropped by the maintenance run, since all of their records were superseded.
+    pub dropped_segments: Vec<u16>,
+}
+
+impl RetentionPolicy {
+    /// The limits that the data directory exceeds, e.g. `["60000 bytes > 50000"]`, given its size and
+    /// segments, and the time that the oldest sealed segment was last modified, if there is one.
+    pub(crate) fn exceeded(
+        &self,
+        total_bytes: u64,
+        segments: usize,
+        oldest_sealed_modified: Option<SystemTime>,
+    ) -> Vec<String> {
+        let mut exceeded = vec![];
+        if let Some(max_total_bytes) = self.max_total_bytes.filter(|max| total_bytes > *max) {
+            exceeded.push(format!("{} bytes > {}", total_bytes, max_total_bytes));
+        }
+        if let Some(max_segments) = self.max_segments.filter(|max| segments > *max) {
+            exceeded.push(format!("{} segments > {}", segments, max_segments));
+        }
+        if let Some(max_age) = self.max_age {
+            let age = oldest_sealed_modified
+                .map(|modified| modified.elapsed().unwrap_or(Duration::ZERO))
+                .unwrap_or(Duration::ZERO);
+            if age > max_age {
+                exceeded.push(format!("segment age {:?} > {:?}", age, max_age));
+            }
+        }
+        exceeded
+    }
+}
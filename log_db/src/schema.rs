@@ -0,0 +1,520 @@
+use super::*;
+
+const SCHEMA_VERSION: u8 = 1;
+
+/// The schema that a data directory was written with, stored in the schema file so that opening
+/// the directory with a different schema is detected instead of misinterpreting the log.
+///
+/// Fields are named by their `Debug` formatting and types are described with `describe_type`, so
+/// two schemas are the same if their descriptions are. See `StoredSchema::serialize` for the format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredSchema {
+    /// The name and type description of each field, in schema order.
+    pub fields: Vec<(String, String)>,
+    pub primary_key: String,
+    pub secondary_keys: Vec<String>,
+}
+
+/// The stored schema of a data directory and the schema it was opened with, see
+/// `DBError::SchemaMismatch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaMismatch {
+    pub stored: StoredSchema,
+    pub configured: StoredSchema,
+}
+
+impl Display for SchemaMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (stored, configured) = (&self.stored, &self.configured);
+        if stored.fields.len() != configured.fields.len() {
+            return write!(
+                f,
+                "the data directory has {} fields, the schema has {}",
+                stored.fields.len(),
+                configured.fields.len()
+            );
+        }
+        let differing = stored.fields.iter().zip(&configured.fields).enumerate();
+        for (index, ((stored_name, stored_type), (name, field_type))) in differing {
+            if stored_name == name
+                && stored_type.split_once('<').map(|(t, _)| t) == Some(field_type)
+            {
+                return write!(
+                    f,
+                    "field {} {} has values stored as {}, which are only read with numeric widening",
+                    index, name, stored_type
+                );
+            }
+            if stored_name != name || stored_type != field_type {
+                return write!(
+                    f,
+                    "field {} is {} {} in the data directory, {} {} in the schema",
+                    index, stored_name, stored_type, name, field_type
+                );
+            }
+        }
+        write!(
+            f,
+            "the primary key is {} in the data directory, {} in the schema",
+            stored.primary_key, configured.primary_key
+        )
+    }
+}
+
+/// A difference between the schema of a data directory and a proposed schema, see
+/// `DB::check_compat`. Fields are matched by name, so a renamed field is a removed field and an
+/// added field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaChange {
+    FieldAdded {
+        name: String,
+        field_type: String,
+    },
+    FieldRemoved {
+        name: String,
+    },
+    /// The field is at a different position in the proposed schema.
+    FieldMoved {
+        name: String,
+        from: usize,
+        to: usize,
+    },
+    TypeChanged {
+        name: String,
+        from: String,
+        to: String,
+    },
+    /// An int field becomes a float or decimal field, which can be read without a migration with
+    /// `ConfigBuilder::numeric_widening`.
+    FieldWidened {
+        name: String,
+        from: String,
+        to: String,
+    },
+    PrimaryKeyChanged {
+        from: String,
+        to: String,
+    },
+    SecondaryKeyAdded {
+        name: String,
+    },
+    SecondaryKeyRemoved {
+        name: String,
+    },
+}
+
+impl SchemaChange {
+    /// Whether the data directory cannot be opened with the proposed schema before it is migrated
+    /// with `DB::migrate`.
+    pub fn is_breaking(&self) -> bool {
+        !matches!(
+            self,
+            SchemaChange::FieldWidened { .. }
+                | SchemaChange::SecondaryKeyAdded { .. }
+                | SchemaChange::SecondaryKeyRemoved { .. }
+        )
+    }
+}
+
+/// The changes from the schema of a data directory to a proposed schema, see `DB::check_compat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatReport {
+    pub stored: StoredSchema,
+    pub proposed: StoredSchema,
+    pub changes: Vec<SchemaChange>,
+}
+
+impl CompatReport {
+    /// Whether the data directory can be opened with the proposed schema without a migration.
+    pub fn is_compatible(&self) -> bool {
+        !self.changes.iter().any(SchemaChange::is_breaking)
+    }
+
+    pub fn breaking_changes(&self) -> impl Iterator<Item = &SchemaChange> {
+        self.changes.iter().filter(|change| change.is_breaking())
+    }
+}
+
+impl StoredSchema {
+    /// Describe the schema configured in `config`.
+    pub fn from_config<R: Recordable>(config: &Config<R>) -> StoredSchema {
+        StoredSchema::from_schema(&config.fields, &config.primary_key, &config.secondary_keys)
+    }
+
+    pub fn from_schema<Field: Debug>(
+        fields: &[(Field, Type)],
+        primary_key: &Field,
+        secondary_keys: &[Field],
+    ) -> StoredSchema {
+        StoredSchema {
+            fields: fields
+                .iter()
+                .map(|(field, field_type)| (format!("{:?}", field), describe_type(field_type)))
+                .collect(),
+            primary_key: format!("{:?}", primary_key),
+            secondary_keys: secondary_keys
+                .iter()
+                .map(|field| format!("{:?}", field))
+                .collect(),
+        }
+    }
+
+    /// Whether a log written with this schema can be read with `other`. Only the fields and the
+    /// primary key determine how the log is read; secondary keys are indexed in memory and may
+    /// change freely.
+    ///
+    /// A field may also be widened in `other`, in which case the values of this schema are among
+    /// its older values.
+    pub fn is_compatible_with(&self, other: &StoredSchema) -> bool {
+        let compatible_field = |((name, field_type), (other_name, other_type)): (
+            &(String, String),
+            &(String, String),
+        )| {
+            name == other_name
+                && (field_type == other_type
+                    || other_type.split_once('<').map(|(_, older)| older) == Some(field_type))
+        };
+        self.fields.len() == other.fields.len()
+            && self.fields.iter().zip(&other.fields).all(compatible_field)
+            && self.primary_key == other.primary_key
+    }
+
+    /// The `configured` schema with the fields that widen a field of this schema described as
+    /// widened, e.g. `float<int` for an int field that has become a float field. Fields widened
+    /// in this schema stay widened, since their old values are still in the log.
+    fn widened_to(&self, mut configured: StoredSchema) -> StoredSchema {
+        for (index, (name, field_type)) in configured.fields.iter_mut().enumerate() {
+            let Some((stored_name, stored_type)) = self.fields.get(index) else {
+                break;
+            };
+            if stored_name != name {
+                continue;
+            }
+
+            match stored_type.split_once('<') {
+                Some((widened_type, _)) if widened_type == field_type => {
+                    *field_type = stored_type.clone();
+                }
+                None if widens(stored_type, field_type) => {
+                    *field_type = format!("{}<{}", field_type, stored_type);
+                }
+                None => {}
+                Some(_) => {}
+            }
+        }
+        configured
+    }
+
+    /// The changes from this schema to `proposed`, fields first in the order of this schema.
+    pub fn changes_to(&self, proposed: &StoredSchema) -> Vec<SchemaChange> {
+        let mut changes = vec![];
+        for (index, (name, stored_type)) in self.fields.iter().enumerate() {
+            let Some(proposed_index) = proposed.fields.iter().position(|(n, _)| n == name) else {
+                changes.push(SchemaChange::FieldRemoved { name: name.clone() });
+                continue;
+            };
+            if proposed_index != index {
+                changes.push(SchemaChange::FieldMoved {
+                    name: name.clone(),
+                    from: index,
+                    to: proposed_index,
+                });
+            }
+
+            let proposed_type = &proposed.fields[proposed_index].1;
+            // A field that was widened before is still widened, its older values are in the log
+            let (stored_type, older_type) = match stored_type.split_once('<') {
+                Some((widened_type, older_type)) => (widened_type, Some(older_type)),
+                None => (stored_type.as_str(), None),
+            };
+            if (stored_type == proposed_type && older_type.is_some())
+                || widens(stored_type, proposed_type)
+            {
+                changes.push(SchemaChange::FieldWidened {
+                    name: name.clone(),
+                    from: older_type.unwrap_or(stored_type).to_owned(),
+                    to: proposed_type.clone(),
+                });
+            } else if stored_type != proposed_type {
+                changes.push(SchemaChange::TypeChanged {
+                    name: name.clone(),
+                    from: self.fields[index].1.clone(),
+                    to: proposed_type.clone(),
+                });
+            }
+        }
+        for (name, field_type) in &proposed.fields {
+            if !self.fields.iter().any(|(n, _)| n == name) {
+                changes.push(SchemaChange::FieldAdded {
+                    name: name.clone(),
+                    field_type: field_type.clone(),
+                });
+            }
+        }
+
+        if self.primary_key != proposed.primary_key {
+            changes.push(SchemaChange::PrimaryKeyChanged {
+                from: self.primary_key.clone(),
+                to: proposed.primary_key.clone(),
+            });
+        }
+        for name in &proposed.secondary_keys {
+            if !self.secondary_keys.contains(name) {
+                changes.push(SchemaChange::SecondaryKeyAdded { name: name.clone() });
+            }
+        }
+        for name in &self.secondary_keys {
+            if !proposed.secondary_keys.contains(name) {
+                changes.push(SchemaChange::SecondaryKeyRemoved { name: name.clone() });
+            }
+        }
+        changes
+    }
+
+    /// Read the stored schema from the data directory. Returns `None` if there is no schema file.
+    pub fn read(data_dir_path: &Path) -> DBResult<Option<StoredSchema>> {
+        let schema_path = data_dir_path.join(SCHEMA_FILENAME);
+        if !fs::exists(&schema_path)? {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&schema_path)?;
+        StoredSchema::deserialize(&contents).map(Some)
+    }
+
+    /// Atomically replace the schema file in the data directory with this schema.
+    pub fn write(&self, data_dir_path: &Path) -> DBResult<()> {
+        let mut tmp_file = fs::NamedTempFile::new_in(data_dir_path)?;
+        tmp_file.write_all(self.serialize().as_bytes())?;
+        tmp_file.flush()?;
+        tmp_file.as_file().sync_all()?;
+
+        fs::rename(tmp_file.path(), data_dir_path.join(SCHEMA_FILENAME))?;
+        Ok(())
+    }
+
+    /// Check the stored schema of the data directory against `configured`, or store `configured`
+    /// if the directory has no schema file yet. A changed set of secondary keys is stored unless
+    /// `read_only` is set. With `widening`, int fields may have been changed into float or decimal
+    /// fields, see `ConfigBuilder::numeric_widening`.
+    pub fn check(
+        data_dir_path: &Path,
+        configured: StoredSchema,
+        read_only: bool,
+        widening: bool,
+    ) -> DBResult<()> {
+        let stored = match StoredSchema::read(data_dir_path)? {
+            Some(stored) => stored,
+            None if read_only => return Ok(()),
+            None => {
+                info!("No schema file found, storing the configured schema");
+                return configured.write(data_dir_path);
+            }
+        };
+        let configured = match widening {
+            true => stored.widened_to(configured),
+            false => configured,
+        };
+
+        if !stored.is_compatible_with(&configured) {
+            return Err(DBError::SchemaMismatch(Box::new(SchemaMismatch {
+                stored,
+                configured,
+            })));
+        }
+        if stored != configured && !read_only {
+            debug!("Secondary keys have changed, storing the configured schema");
+            configured.write(data_dir_path)?;
+        }
+        Ok(())
+    }
+
+    /// Serialize the schema into lines of space-separated entries:
+    ///
+    /// ```text
+    /// schema <version>
+    /// field <name> <type>
+    /// primary <name>
+    /// secondary <name>
+    /// checksum <crc32 of all preceding lines>
+    /// ```
+    ///
+    /// There is one `field` line per field in schema order, and one `secondary` line per secondary
+    /// key. Names are quoted like strings formatted with `Debug`, so they may contain spaces. The
+    /// type of a widened field is followed by `<` and the type of its older values, e.g. `float<int`.
+    pub fn serialize(&self) -> String {
+        let mut contents = format!("schema {}\n", SCHEMA_VERSION);
+        for (name, field_type) in &self.fields {
+            contents.push_str(&format!("field {:?} {}\n", name, field_type));
+        }
+        contents.push_str(&format!("primary {:?}\n", self.primary_key));
+        for name in &self.secondary_keys {
+            contents.push_str(&format!("secondary {:?}\n", name));
+        }
+
+        let checksum = crc32fast::hash(contents.as_bytes());
+        contents.push_str(&format!("checksum {:08x}\n", checksum));
+        contents
+    }
+
+    pub fn deserialize(contents: &str) -> DBResult<StoredSchema> {
+        fn invalid(reason: &str) -> DBError {
+            DBError::ConsistencyError(format!("Invalid schema file: {}", reason))
+        }
+
+        let checksum_start = contents
+            .rfind("checksum ")
+            .ok_or_else(|| invalid("missing checksum"))?;
+        let (body, checksum_line) = contents.split_at(checksum_start);
+        let checksum = u32::from_str_radix(checksum_line["checksum ".len()..].trim(), 16)
+            .map_err(|_| invalid("malformed checksum"))?;
+        if checksum != crc32fast::hash(body.as_bytes()) {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let mut fields = vec![];
+        let mut primary_key = None;
+        let mut secondary_keys = vec![];
+        for line in body.lines() {
+            let (entry, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match entry {
+                "schema" => {
+                    if rest != SCHEMA_VERSION.to_string() {
+                        return Err(invalid("unsupported version"));
+                    }
+                }
+                "field" => {
+                    let (name, field_type) =
+                        parse_quoted(rest).ok_or_else(|| invalid("malformed field entry"))?;
+                    fields.push((name, field_type.trim_start().to_owned()));
+                }
+                "primary" => {
+                    let (name, _) =
+                        parse_quoted(rest).ok_or_else(|| invalid("malformed primary key"))?;
+                    primary_key = Some(name);
+                }
+                "secondary" => {
+                    let (name, _) =
+                        parse_quoted(rest).ok_or_else(|| invalid("malformed secondary key"))?;
+                    secondary_keys.push(name);
+                }
+                _ => return Err(invalid("unknown entry")),
+            }
+        }
+
+        Ok(StoredSchema {
+            fields,
+            primary_key: primary_key.ok_or_else(|| invalid("missing primary key"))?,
+            secondary_keys,
+        })
+    }
+}
+
+/// Whether values of the `stored_type` can be read as values of `field_type` with numeric widening:
+/// an int field that has become a float or decimal field of the same nullability.
+fn widens(stored_type: &str, field_type: &str) -> bool {
+    let nullable = if field_type.ends_with('?') { "?" } else { "" };
+    matches!(field_type.trim_end_matches('?'), "float" | "decimal")
+        && stored_type == format!("int{}", nullable)
+}
+
+/// Describe a type for the schema file, e.g. `string?` for a nullable string or
+/// `record("lat": float, "lon": float)` for a nested record.
+pub fn describe_type(field_type: &Type) -> String {
+    let primitive = match &field_type.primitive {
+        PrimitiveType::Int => "int".to_owned(),
+        PrimitiveType::Decimal => "decimal".to_owned(),
+        PrimitiveType::String => "string".to_owned(),
+        PrimitiveType::Bytes => "bytes".to_owned(),
+        PrimitiveType::Timestamp => "timestamp".to_owned(),
+        PrimitiveType::Enum(variants) => format!(
+            "enum({})",
+            variants
+                .iter()
+                .map(|variant| format!("{:?}", variant))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        PrimitiveType::Json => "json".to_owned(),
+        PrimitiveType::FixedBytes(len) => format!("bytes({})", len),
+        PrimitiveType::Float => "float".to_owned(),
+        PrimitiveType::GeoPoint => "geo_point".to_owned(),
+        PrimitiveType::Record(fields) => format!(
+            "record({})",
+            fields
+                .iter()
+                .map(|(name, field_type)| format!("{:?}: {}", name, describe_type(field_type)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    if field_type.nullable {
+        primitive + "?"
+    } else {
+        primitive
+    }
+}
+
+/// Parse a string quoted with `Debug` at the start of `s`, returning it unescaped and the rest of `s`.
+fn parse_quoted(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut unquoted = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((unquoted, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => unquoted.push('\n'),
+                'r' => unquoted.push('\r'),
+                't' => unquoted.push('\t'),
+                '0' => unquoted.push('\0'),
+                'u' => {
+                    let (_, '{') = chars.next()? else {
+                        return None;
+                    };
+                    let mut hex = String::new();
+                    loop {
+                        match chars.next()?.1 {
+                            '}' => break,
+                            digit => hex.push(digit),
+                        }
+                    }
+                    unquoted.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                escaped => unquoted.push(escaped),
+            },
+            c => unquoted.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_serialize_deserialize() {
+        let schema = StoredSchema {
+            fields: vec![
+                ("Id".to_owned(), "int".to_owned()),
+                (
+                    "Status \"quoted\"\t".to_owned(),
+                    describe_type(&Type::enum_of(&["a b", "ä"]).nullable()),
+                ),
+            ],
+            primary_key: "Id".to_owned(),
+            secondary_keys: vec!["Status \"quoted\"\t".to_owned()],
+        };
+        assert_eq!(schema.fields[1].1, "enum(\"a b\", \"ä\")?");
+
+        let serialized = schema.serialize();
+        assert_eq!(StoredSchema::deserialize(&serialized).unwrap(), schema);
+
+        let tampered = serialized.replace("int", "string");
+        assert!(matches!(
+            StoredSchema::deserialize(&tampered),
+            Err(DBError::ConsistencyError(_))
+        ));
+    }
+}
@@ -0,0 +1,247 @@
+use super::*;
+use std::time::Instant;
+
+/// Size of the reads of the scrubber. The scrubber sleeps between reads to keep to its rate.
+const SCRUB_READ_SIZE: usize = 64 * 1024;
+
+/// How long the scrubber waits before checking again when there are no sealed segments.
+pub const SCRUB_IDLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Called with each problem found by the scrubber, see `ConfigBuilder::scrub_hook`.
+pub type ScrubHook = fn(&ScrubProblem);
+
+/// A problem found in a segment by the scrubber or by `DB::verify_segment`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrubProblem {
+    pub segment_num: u16,
+    /// The metadata row that is damaged or whose record is damaged or could not be decoded, or
+    /// `None` if the problem is with the segment files as a whole, e.g. a manifest mismatch.
+    pub row: Option<u64>,
+    pub description: String,
+}
+
+/// The result of scrubbing a single segment, see `DB::scrub_next_segment` and `DB::verify_segment`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub segment_num: u16,
+    /// Number of records that were decoded and validated against the schema.
+    pub records_checked: u64,
+    /// Number of bytes read from the metadata and data files.
+    pub bytes_read: u64,
+    pub problems: Vec<ScrubProblem>,
+}
+
+/// Limits reads to an average rate by sleeping after each read.
+pub struct RateLimiter {
+    bytes_per_second: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    /// Create a limiter for `bytes_per_second`, or one that never sleeps if `None`.
+    pub fn new(bytes_per_second: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            bytes_per_second,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Account for a read of `bytes` bytes, sleeping until the average rate since the limiter was
+    /// created is back within the limit.
+    pub fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let Some(bytes_per_second) = self.bytes_per_second else {
+            return;
+        };
+        let due = Duration::from_secs_f64(self.bytes as f64 / bytes_per_second.max(1) as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
+
+/// Check the files of a segment against its manifest entry, if it has one, check the metadata rows
+/// and records against their checksums, and check that each record decodes into values that match
+/// the schema. Problems are returned in the report, while errors reading the files are returned as
+/// errors. `check_cancelled` is called between reads.
+///
+/// The whole data of the segment is read into memory, since its records are not necessarily
+/// stored in the order of their metadata rows.
+pub fn scrub_segment<Field: Eq>(
+    data_dir_path: &Path,
+    segment_num: u16,
+    expected: Option<&ManifestSegment>,
+    schema: &[(Field, Type)],
+    encryption: Option<&dyn EncryptionProvider>,
+    limiter: &mut RateLimiter,
+    check_cancelled: impl Fn() -> DBResult<()>,
+) -> DBResult<ScrubReport> {
+    let mut report = ScrubReport {
+        segment_num,
+        records_checked: 0,
+        bytes_read: 0,
+        problems: vec![],
+    };
+    let problem = |row: Option<u64>, description: String| ScrubProblem {
+        segment_num,
+        row,
+        description,
+    };
+
+    let mut metadata_file = READ_MODE.open(data_dir_path.join(metadata_filename(segment_num)))?;
+    let metadata_header = read_metadata_header(&mut metadata_file)?;
+    metadata_file.seek(SeekFrom::Start(0))?;
+    let mut metadata_buf = vec![];
+    metadata_file.read_to_end(&mut metadata_buf)?;
+    limiter.consume(metadata_buf.len() as u64);
+    report.bytes_read += metadata_buf.len() as u64;
+
+    // Damaged rows are reported and skipped, since their offsets and lengths cannot be trusted
+    let mut rows = vec![];
+    for (index, row) in metadata_buf[METADATA_FILE_HEADER_SIZE..]
+        .chunks_exact(metadata_row_length(metadata_header.version))
+        .enumerate()
+    {
+        match MetadataRow::deserialize(metadata_header.version, row) {
+            Ok(row) => rows.push((index, row)),
+            Err(e) => report.problems.push(problem(
+                Some(index as u64),
+                format!(
+                    "Metadata row {} of segment {} is damaged: {}",
+                    index, segment_num, e
+                ),
+            )),
+        }
+    }
+    let data_len = rows
+        .iter()
+        .map(|(_, row)| row.offset.saturating_add(row.length))
+        .max()
+        .unwrap_or(0);
+
+    // The records of an archived data file are checked against its checksum when it is fetched
+    if ArchivedFiles::is_archived_only(data_dir_path, &metadata_header.uuid)? {
+        return Ok(report);
+    }
+    let mut data_file = READ_MODE.open(data_dir_path.join(metadata_header.uuid.to_string()))?;
+    let mut data = vec![];
+    let mut hasher = crc32fast::Hasher::new();
+    let mut chunk = vec![0; SCRUB_READ_SIZE];
+    while (data.len() as u64) < data_len {
+        check_cancelled()?;
+        let want = (data_len - data.len() as u64).min(SCRUB_READ_SIZE as u64) as usize;
+        let read = data_file.read(&mut chunk[..want])?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&chunk[..read]);
+        data.extend_from_slice(&chunk[..read]);
+        limiter.consume(read as u64);
+    }
+    report.bytes_read += data.len() as u64;
+
+    if (data.len() as u64) < data_len {
+        report.problems.push(problem(
+            None,
+            format!(
+                "Data file of segment {} is truncated: {} bytes, expected at least {}",
+                segment_num,
+                data.len(),
+                data_len
+            ),
+        ));
+    }
+
+    if let Some(expected) = expected {
+        if metadata_buf.len() as u64 != expected.metadata_len
+            || crc32fast::hash(&metadata_buf) != expected.metadata_checksum
+        {
+            report.problems.push(problem(
+                None,
+                format!("Metadata file of segment {} has been modified", segment_num),
+            ));
+        }
+        if metadata_header.uuid != expected.data_uuid
+            || data_len != expected.data_len
+            || hasher.finalize() != expected.data_checksum
+        {
+            report.problems.push(problem(
+                None,
+                format!("Data file of segment {} has been modified", segment_num),
+            ));
+        }
+    }
+
+    if let Err(e) = validate_metadata_header(&metadata_header) {
+        report.problems.push(problem(
+            None,
+            format!("Segment {} cannot be read: {}", segment_num, e),
+        ));
+        return Ok(report);
+    }
+
+    let mut decoded = vec![];
+    for (row, metadata_row) in rows {
+        if metadata_row.is_unused() {
+            continue;
+        }
+        let Some(bytes) = usize::try_from(metadata_row.offset)
+            .ok()
+            .zip(usize::try_from(metadata_row.length).ok())
+            .and_then(|(offset, length)| data.get(offset..offset.checked_add(length)?))
+        else {
+            // Covered by the truncation problem
+            continue;
+        };
+        report.records_checked += 1;
+        if !metadata_row.matches(bytes) {
+            report.problems.push(problem(
+                Some(row as u64),
+                format!(
+                    "Record {} of segment {} is damaged: checksum mismatch",
+                    row, segment_num
+                ),
+            ));
+            continue;
+        }
+        decoded.clear();
+        decoded.extend_from_slice(bytes);
+        if let Err(e) = metadata_header.decode_record(&mut decoded, 0, encryption) {
+            report.problems.push(problem(
+                Some(row as u64),
+                format!(
+                    "Record {} of segment {} is damaged: {}",
+                    row, segment_num, e
+                ),
+            ));
+            continue;
+        }
+        let bytes = &decoded[..];
+
+        let flag = bytes.first().copied();
+        let result = match flag {
+            Some(B_LIVE | B_MERGE | B_DELETED | B_TOMBSTONE) => {
+                validate_serialized(bytes, schema).map(|_| ())
+            }
+            Some(flag) => Err(DBError::ValidationError(format!(
+                "Record has an invalid flag: {}",
+                flag
+            ))),
+            None => Err(DBError::ValidationError("Record is empty".to_owned())),
+        };
+        if let Err(e) = result {
+            report.problems.push(problem(
+                Some(row as u64),
+                format!(
+                    "Record {} of segment {} is invalid: {}",
+                    row, segment_num, e
+                ),
+            ));
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,185 @@
+use super::*;
+use memmap2::Mmap;
+use std::sync::Arc;
+
+/// The metadata and data files of a segment mapped into memory for reads, see
+/// `ConfigBuilder::mmap_reads`.
+pub struct MappedSegment {
+    pub header: MetadataHeader,
+    metadata: Mmap,
+    data: Mmap,
+}
+
+/// The file on disk of a segment, files in memory cannot be mapped.
+fn disk_file(file: &File) -> io::Result<&std::fs::File> {
+    file.as_disk().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Files in memory cannot be memory-mapped",
+        )
+    })
+}
+
+impl MappedSegment {
+    /// Map the files of a segment as they are now. The metadata file is mapped first, so that the
+    /// records of all of its mapped rows are in the mapped part of the data file, since records are
+    /// written before their rows. An archived data file is fetched from `archive` first.
+    pub fn map(
+        data_dir_path: &Path,
+        segment_num: u16,
+        archive: Option<&dyn ArchiveStore>,
+    ) -> DBResult<MappedSegment> {
+        let mut metadata_file =
+            READ_MODE.open(data_dir_path.join(metadata_filename(segment_num)))?;
+        let header = read_metadata_header(&mut metadata_file)?;
+        validate_metadata_header(&header)?;
+        let data_file = open_data_file(data_dir_path, archive, &header.uuid)?;
+
+        // SAFETY: Sealed segment files are never modified in place, compaction and merges rename
+        // new files over them, which leaves the maps pointing at the old files. The files of the
+        // active segment are only appended to, or truncated to drop a torn append, whose partial
+        // rows and records are never read.
+        let metadata = unsafe { Mmap::map(disk_file(&metadata_file)?)? };
+        let data = unsafe { Mmap::map(disk_file(&data_file)?)? };
+        Ok(MappedSegment {
+            header,
+            metadata,
+            data,
+        })
+    }
+
+    /// Number of whole metadata rows in the mapped part of the metadata file.
+    pub fn rows(&self) -> u64 {
+        let rows_len = self
+            .metadata
+            .len()
+            .saturating_sub(METADATA_FILE_HEADER_SIZE);
+        (rows_len / metadata_row_length(self.header.version)) as u64
+    }
+}
+
+/// Where `Engine::read_tagged_log_keys_into` reads the rows and records of a segment from.
+pub enum SegmentSource {
+    Mapped(Arc<MappedSegment>),
+    Files {
+        header: MetadataHeader,
+        metadata_file: fs::File,
+        data_file: fs::File,
+    },
+}
+
+impl SegmentSource {
+    /// Open the files of a segment for reading at offsets, fetching an archived data file from
+    /// `archive` first.
+    pub fn open(
+        data_dir_path: &Path,
+        segment_num: u16,
+        archive: Option<&dyn ArchiveStore>,
+    ) -> DBResult<SegmentSource> {
+        let mut metadata_file =
+            READ_MODE.open(data_dir_path.join(metadata_filename(segment_num)))?;
+        let header = read_metadata_header(&mut metadata_file)?;
+        validate_metadata_header(&header)?;
+        let data_file = open_data_file(data_dir_path, archive, &header.uuid)?;
+        Ok(SegmentSource::Files {
+            header,
+            metadata_file,
+            data_file,
+        })
+    }
+
+    pub fn header(&self) -> &MetadataHeader {
+        match self {
+            SegmentSource::Mapped(mapped) => &mapped.header,
+            SegmentSource::Files { header, .. } => header,
+        }
+    }
+
+    /// Read the metadata rows at `indexes`, with a single batch of reads if the segment is not mapped.
+    pub fn read_rows(&self, indexes: &[u64], io: &dyn IoBackend) -> DBResult<Vec<MetadataRow>> {
+        let version = self.header().version;
+        let row_length = metadata_row_length(version);
+        let row_offset = |index: u64| METADATA_FILE_HEADER_SIZE + index as usize * row_length;
+        match self {
+            SegmentSource::Mapped(mapped) => indexes
+                .iter()
+                .map(|&index| {
+                    let row_offset = row_offset(index);
+                    let row = mapped
+                        .metadata
+                        .get(row_offset..row_offset + row_length)
+                        .ok_or_else(|| {
+                            DBError::ConsistencyError(format!(
+                                "Metadata row {} is past the end of the mapped metadata file",
+                                index
+                            ))
+                        })?;
+                    MetadataRow::deserialize(version, row)
+                })
+                .collect(),
+            SegmentSource::Files { metadata_file, .. } => {
+                let mut buf = vec![0; indexes.len() * row_length];
+                let mut reads: Vec<(u64, &mut [u8])> = indexes
+                    .iter()
+                    .zip(buf.chunks_mut(row_length))
+                    .map(|(&index, row)| (row_offset(index) as u64, row))
+                    .collect();
+                io.read_at_batch(metadata_file, &mut reads)?;
+                buf.chunks(row_length)
+                    .map(|row| MetadataRow::deserialize(version, row))
+                    .collect()
+            }
+        }
+    }
+
+    /// The stored records that `rows` point at. If the segment is not mapped, they are read into
+    /// `scratch` with a single batch of reads.
+    pub fn read_records<'a>(
+        &'a self,
+        rows: &[MetadataRow],
+        io: &dyn IoBackend,
+        scratch: &'a mut Vec<u8>,
+    ) -> DBResult<Vec<&'a [u8]>> {
+        match self {
+            SegmentSource::Mapped(mapped) => rows
+                .iter()
+                .map(|row| {
+                    usize::try_from(row.offset)
+                        .ok()
+                        .and_then(|offset| {
+                            mapped
+                                .data
+                                .get(offset..offset.checked_add(row.length as usize)?)
+                        })
+                        .ok_or_else(|| {
+                            DBError::ConsistencyError(format!(
+                                "Record at {} is past the end of the mapped data file",
+                                row.offset
+                            ))
+                        })
+                })
+                .collect(),
+            SegmentSource::Files { data_file, .. } => {
+                scratch.clear();
+                scratch.resize(rows.iter().map(|row| row.length as usize).sum(), 0);
+                let mut reads: Vec<(u64, &mut [u8])> = Vec::with_capacity(rows.len());
+                let mut rest = scratch.as_mut_slice();
+                for row in rows {
+                    let (record, tail) = rest.split_at_mut(row.length as usize);
+                    reads.push((row.offset, record));
+                    rest = tail;
+                }
+                io.read_at_batch(data_file, &mut reads)?;
+
+                let mut records = Vec::with_capacity(rows.len());
+                let mut rest = scratch.as_slice();
+                for row in rows {
+                    let (record, tail) = rest.split_at(row.length as usize);
+                    records.push(record);
+                    rest = tail;
+                }
+                Ok(records)
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use super::*;
+
+const SEQUENCES_VERSION: u8 = 1;
+
+/// The ledger of named sequences in the data directory. Each sequence stores the last value it has
+/// handed out, so values are never reused, also across crashes: a value is only returned after the
+/// ledger containing it has been synced to disk.
+///
+/// The ledger is a small text file in the same format as the manifest:
+///
+/// ```text
+/// sequences <version>
+/// sequence <name> <last value>
+/// checksum <crc32 of all preceding lines>
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Sequences {
+    pub values: BTreeMap<String, u64>,
+}
+
+impl Sequences {
+    /// Read the ledger from the data directory. A missing ledger has no sequences.
+    pub fn read(data_dir_path: &Path) -> DBResult<Sequences> {
+        let sequences_path = data_dir_path.join(SEQUENCES_FILENAME);
+        if !fs::exists(&sequences_path)? {
+            return Ok(Sequences::default());
+        }
+
+        let contents = fs::read_to_string(&sequences_path)?;
+        Sequences::deserialize(&contents)
+    }
+
+    /// Atomically replace the ledger in the data directory with this one.
+    pub fn write(&self, data_dir_path: &Path) -> DBResult<()> {
+        let mut tmp_file = fs::NamedTempFile::new_in(data_dir_path)?;
+        tmp_file.write_all(self.serialize().as_bytes())?;
+        tmp_file.flush()?;
+        tmp_file.as_file().sync_all()?;
+
+        fs::rename(tmp_file.path(), data_dir_path.join(SEQUENCES_FILENAME))?;
+        Ok(())
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut contents = format!("sequences {}\n", SEQUENCES_VERSION);
+        for (name, value) in &self.values {
+            contents.push_str(&format!("sequence {} {}\n", name, value));
+        }
+
+        let checksum = crc32fast::hash(contents.as_bytes());
+        contents.push_str(&format!("checksum {:08x}\n", checksum));
+        contents
+    }
+
+    pub fn deserialize(contents: &str) -> DBResult<Sequences> {
+        fn invalid(reason: &str) -> DBError {
+            DBError::ConsistencyError(format!("Invalid sequence ledger: {}", reason))
+        }
+
+        let checksum_start = contents
+            .rfind("checksum ")
+            .ok_or_else(|| invalid("missing checksum"))?;
+        let (body, checksum_line) = contents.split_at(checksum_start);
+        let checksum = u32::from_str_radix(checksum_line["checksum ".len()..].trim(), 16)
+            .map_err(|_| invalid("malformed checksum"))?;
+        if checksum != crc32fast::hash(body.as_bytes()) {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let mut values = BTreeMap::new();
+        for line in body.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                ["sequences", version] => {
+                    if version != SEQUENCES_VERSION.to_string() {
+                        return Err(invalid("unsupported version"));
+                    }
+                }
+                ["sequence", name, value] => {
+                    let value = value
+                        .parse()
+                        .map_err(|_| invalid("malformed sequence value"))?;
+                    values.insert(name.to_owned(), value);
+                }
+                _ => return Err(invalid("unknown entry")),
+            }
+        }
+
+        Ok(Sequences { values })
+    }
+}
+
+/// Advance the sequence `name` in the ledger of the data directory and return its new value.
+/// The first value of a sequence is 1. The caller must hold the exclusive lock.
+pub fn next_sequence(data_dir_path: &Path, name: &str) -> DBResult<u64> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(DBError::ValidationError(format!(
+            "Invalid sequence name {:?}: must be non-empty and contain no whitespace",
+            name
+        )));
+    }
+
+    let mut sequences = Sequences::read(data_dir_path)?;
+    let value = sequences.values.entry(name.to_owned()).or_insert(0);
+    *value = value
+        .checked_add(1)
+        .ok_or_else(|| DBError::ValidationError(format!("Sequence {} is exhausted", name)))?;
+    let value = *value;
+
+    sequences.write(data_dir_path)?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequences_serialize_deserialize() {
+        let sequences = Sequences {
+            values: BTreeMap::from([("orders".to_owned(), 12), ("batches".to_owned(), 1)]),
+        };
+
+        let serialized = sequences.serialize();
+        assert_eq!(Sequences::deserialize(&serialized).unwrap(), sequences);
+
+        let tampered = serialized.replace("orders 12", "orders 11");
+        assert!(Sequences::deserialize(&tampered).is_err());
+    }
+}
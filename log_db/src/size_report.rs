@@ -0,0 +1,119 @@
+use super::*;
+
+/// The distribution of the stored sizes of the current records, as returned by `DB::size_report`.
+/// The stored size of a record is the length of its current version in the data file, plus the
+/// lengths of its merge deltas, if any. Superseded versions that have not been compacted yet are not counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeReport {
+    pub records: u64,
+    pub total_bytes: u64,
+    /// Number of records in each power-of-two size range, in increasing order of size.
+    /// Empty ranges between the smallest and the largest record are included.
+    pub histogram: Vec<SizeBucket>,
+    /// The primary keys of the largest records with their stored sizes, largest first.
+    pub largest: Vec<(Value, u64)>,
+}
+
+/// The records whose stored size is in `min_size..=max_size`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeBucket {
+    pub min_size: u64,
+    pub max_size: u64,
+    pub records: u64,
+    pub bytes: u64,
+}
+
+impl SizeReport {
+    /// Build a report from the primary key and stored size of each record.
+    pub fn new(sizes: Vec<(Value, u64)>, top_n: usize) -> SizeReport {
+        let bucket_of = |size: u64| size.checked_ilog2().map_or(0, |log| log as usize + 1);
+
+        let mut histogram: Vec<SizeBucket> = vec![];
+        if let (Some(min), Some(max)) = (
+            sizes.iter().map(|(_, size)| *size).min(),
+            sizes.iter().map(|(_, size)| *size).max(),
+        ) {
+            histogram = (bucket_of(min)..=bucket_of(max))
+                .map(|bucket| SizeBucket {
+                    min_size: if bucket == 0 { 0 } else { 1 << (bucket - 1) },
+                    max_size: if bucket == 0 { 0 } else { (1 << bucket) - 1 },
+                    records: 0,
+                    bytes: 0,
+                })
+                .collect();
+
+            let first_bucket = bucket_of(min);
+            for (_, size) in &sizes {
+                let bucket = &mut histogram[bucket_of(*size) - first_bucket];
+                bucket.records += 1;
+                bucket.bytes += size;
+            }
+        }
+
+        let records = sizes.len() as u64;
+        let total_bytes = sizes.iter().map(|(_, size)| size).sum();
+
+        let mut largest = sizes;
+        // Stable sort keeps the keys of equally sized records in key order
+        largest.sort_by(|(_, a), (_, b)| b.cmp(a));
+        largest.truncate(top_n);
+
+        SizeReport {
+            records,
+            total_bytes,
+            histogram,
+            largest,
+        }
+    }
+}
+
+impl Display for SizeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} records, {} bytes", self.records, self.total_bytes)?;
+        for bucket in &self.histogram {
+            writeln!(
+                f,
+                "{}..={} bytes: {} records, {} bytes",
+                bucket.min_size, bucket.max_size, bucket.records, bucket.bytes
+            )?;
+        }
+        for (key, size) in &self.largest {
+            writeln!(f, "{:?}: {} bytes", key, size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_report_histogram() {
+        let sizes = vec![
+            (Value::Int(1), 20),
+            (Value::Int(2), 16),
+            (Value::Int(3), 100),
+            (Value::Int(4), 31),
+        ];
+        let report = SizeReport::new(sizes, 2);
+
+        assert_eq!(report.records, 4);
+        assert_eq!(report.total_bytes, 167);
+        let ranges: Vec<(u64, u64, u64, u64)> = report
+            .histogram
+            .iter()
+            .map(|b| (b.min_size, b.max_size, b.records, b.bytes))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![(16, 31, 3, 67), (32, 63, 0, 0), (64, 127, 1, 100)]
+        );
+        assert_eq!(
+            report.largest,
+            vec![(Value::Int(3), 100), (Value::Int(4), 31)]
+        );
+
+        assert!(SizeReport::new(vec![], 5).histogram.is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+use super::*;
+use crate::export::EXPORT_BATCH_SIZE;
+use rusqlite::types::Value as SqlValue;
+
+/// Name of the table the records are exported into.
+pub const SQLITE_TABLE_NAME: &str = "records";
+
+impl<R: Recordable> Engine<R> {
+    /// Write the current records into a new SQLite database at `path`, see `DB::export_sqlite`.
+    /// The caller must hold a lock that prevents writes, so that the export is a consistent snapshot.
+    pub fn export_sqlite(&mut self, path: &Path) -> DBResult<u64> {
+        if fs::exists(path)? {
+            return Err(DBError::ValidationError(format!(
+                "Export target {} already exists",
+                path.display()
+            )));
+        }
+
+        let column = |field: &R::Field| quote_identifier(&format!("{:?}", field));
+        let columns: Vec<String> = self
+            .config
+            .fields
+            .iter()
+            .map(|(field, field_type)| {
+                let mut column = format!("{} {}", column(field), sql_type(field_type));
+                if !field_type.nullable {
+                    column.push_str(" NOT NULL");
+                }
+                if *field == self.config.primary_key {
+                    column.push_str(" PRIMARY KEY");
+                }
+                column
+            })
+            .collect();
+
+        let mut connection = rusqlite::Connection::open(path)?;
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            &format!(
+                "CREATE TABLE {} ({})",
+                SQLITE_TABLE_NAME,
+                columns.join(", ")
+            ),
+            (),
+        )?;
+
+        // The indexes of the schema are useful for ad-hoc queries as well
+        let indexes = self
+            .config
+            .secondary_keys
+            .iter()
+            .map(std::slice::from_ref)
+            .chain(self.config.composite_keys.iter().map(Vec::as_slice));
+        for (i, fields) in indexes.enumerate() {
+            let index_columns: Vec<String> = fields.iter().map(column).collect();
+            transaction.execute(
+                &format!(
+                    "CREATE INDEX {}_{} ON {} ({})",
+                    SQLITE_TABLE_NAME,
+                    i,
+                    SQLITE_TABLE_NAME,
+                    index_columns.join(", ")
+                ),
+                (),
+            )?;
+        }
+
+        let placeholders = vec!["?"; self.config.fields.len()].join(", ");
+        let mut exported = 0;
+        {
+            let mut insert = transaction.prepare(&format!(
+                "INSERT INTO {} VALUES ({})",
+                SQLITE_TABLE_NAME, placeholders
+            ))?;
+
+            let primary_key = self.config.primary_key.clone();
+            let mut start = Bound::Unbounded;
+            loop {
+                let bounds = OwnedBounds::new(start, Bound::Unbounded);
+                let (records, next_start) =
+                    self.range_by_batch(&primary_key, bounds, EXPORT_BATCH_SIZE)?;
+
+                for record in records {
+                    let values = record.values.into_iter().map(to_sql_value);
+                    insert.execute(rusqlite::params_from_iter(values))?;
+                    exported += 1;
+                }
+
+                match next_start {
+                    Some(key) => start = Bound::Excluded(key),
+                    None => break,
+                }
+            }
+        }
+
+        transaction.commit()?;
+        Ok(exported)
+    }
+}
+
+/// Decimals are exported as text, since SQLite would store them as lossy floating point numbers.
+/// Timestamps are exported as integers of microseconds since the Unix epoch, enums as the text of
+/// their variants, geo points as text of their latitude and longitude separated by a comma, JSON as text that SQLite's JSON functions accept, and nested records as blobs of
+/// their values encoded with `codec::encode_values`.
+fn sql_type(field_type: &Type) -> &'static str {
+    match field_type.primitive {
+        PrimitiveType::Int => "INTEGER",
+        PrimitiveType::Decimal => "TEXT",
+        PrimitiveType::Float => "REAL",
+        PrimitiveType::Record(_) => "BLOB",
+        PrimitiveType::String => "TEXT",
+        PrimitiveType::Bytes | PrimitiveType::FixedBytes(_) => "BLOB",
+        PrimitiveType::Timestamp => "INTEGER",
+        PrimitiveType::Enum(_) => "TEXT",
+        PrimitiveType::Json => "TEXT",
+        PrimitiveType::GeoPoint => "TEXT",
+    }
+}
+
+fn to_sql_value(value: Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Int(i) => SqlValue::Integer(i),
+        Value::Decimal(d) => SqlValue::Text(d.to_string()),
+        Value::String(s) => SqlValue::Text(s),
+        Value::Bytes(b) => SqlValue::Blob(b),
+        Value::Timestamp(t) => SqlValue::Integer(t),
+        Value::Json(s) => SqlValue::Text(s),
+        Value::Float(f) => SqlValue::Real(f),
+        Value::Record(values) => SqlValue::Blob(log_db_codec::encode_values(&values)),
+        Value::GeoPoint(lat, lon) => SqlValue::Text(format!("{},{}", lat, lon)),
+    }
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
@@ -0,0 +1,236 @@
+use super::*;
+
+/// Upserts to a database and its collections, staged in memory and committed atomically with
+/// `commit`, returned by `DB::transaction`. Dropping the transaction discards the staged upserts.
+pub struct Transaction<'a, R: Recordable> {
+    db: &'a mut DB<R>,
+    /// The staged records in the order they were staged, each with the name of its collection, or
+    /// `None` for the database itself
+    writes: Vec<(Option<String>, Record)>,
+    /// The engine of each collection with staged records. Collections are stored with their record
+    /// type erased, which is only known when a record is staged.
+    collection_engines: HashMap<String, CollectionEngine>,
+}
+
+/// Get the engine of a collection of a known record type from the collections of a `DB`
+type CollectionEngine = fn(&mut dyn Any) -> &mut dyn Participant;
+
+fn collection_engine<N: Recordable + 'static>(collection: &mut dyn Any) -> &mut dyn Participant {
+    &mut collection
+        .downcast_mut::<DB<N>>()
+        .expect("Collection type was checked when the record was staged")
+        .engine
+}
+
+/// The engine of a database taking part in a transaction, with its record type erased.
+trait Participant {
+    fn data_dir_path(&self) -> &Path;
+    fn lock_exclusive(&mut self) -> DBResult<()>;
+    fn unlock_exclusive(&mut self) -> DBResult<()>;
+    fn stage_records(
+        &mut self,
+        records: Vec<Record>,
+        commit_marker: &Path,
+    ) -> DBResult<PreparedBatch>;
+    fn commit_staged(&mut self, batch: PreparedBatch) -> DBResult<Vec<WriteReceipt>>;
+}
+
+impl<R: Recordable> Participant for Engine<R> {
+    fn data_dir_path(&self) -> &Path {
+        Engine::data_dir_path(self)
+    }
+
+    fn lock_exclusive(&mut self) -> DBResult<()> {
+        Engine::lock_exclusive(self)
+    }
+
+    fn unlock_exclusive(&mut self) -> DBResult<()> {
+        Engine::unlock_exclusive(self)
+    }
+
+    fn stage_records(
+        &mut self,
+        records: Vec<Record>,
+        commit_marker: &Path,
+    ) -> DBResult<PreparedBatch> {
+        Engine::stage_records(self, records, commit_marker)
+    }
+
+    fn commit_staged(&mut self, batch: PreparedBatch) -> DBResult<Vec<WriteReceipt>> {
+        Engine::commit_staged(self, batch)
+    }
+}
+
+/// The records of a transaction to be written to one database
+struct ParticipantWrites<'a> {
+    engine: &'a mut dyn Participant,
+    /// The path of the commit marker relative to the data directory of the database
+    commit_marker: PathBuf,
+    /// The positions of the records in the order they were staged
+    positions: Vec<usize>,
+    records: Vec<Record>,
+}
+
+impl<'a, R: Recordable> Transaction<'a, R> {
+    pub(crate) fn new(db: &'a mut DB<R>) -> Transaction<'a, R> {
+        Transaction {
+            db,
+            writes: vec![],
+            collection_engines: HashMap::new(),
+        }
+    }
+
+    /// Stage an upsert of a record to the database.
+    pub fn upsert(&mut self, recordable: R) -> DBResult<()> {
+        let record = Record::from(&recordable.into_record());
+        record.validate(&self.db.engine.config.fields)?;
+        self.writes.push((None, record));
+        Ok(())
+    }
+
+    /// Stage an upsert of a record to the collection `name`, opening the collection if it is not
+    /// open yet, see `DB::collection`.
+    pub fn upsert_in<N: Recordable + 'static>(
+        &mut self,
+        name: &str,
+        recordable: N,
+    ) -> DBResult<()> {
+        let collection = self.db.collection::<N>(name)?;
+        let record = Record::from(&recordable.into_record());
+        record.validate(&collection.engine.config.fields)?;
+        self.collection_engines
+            .insert(name.to_owned(), collection_engine::<N>);
+        self.writes.push((Some(name.to_owned()), record));
+        Ok(())
+    }
+
+    /// Commit the staged upserts. Returns the position and metadata assigned to each record, in
+    /// the order they were staged.
+    ///
+    /// The commit is atomic: after a crash, either all or none of the records are in the database
+    /// and its collections. The exclusive locks of all databases written to are held for the
+    /// duration of the commit. The records of each database are written to its log and its journal
+    /// first, then an empty commit marker is created in the `transactions` directory of this
+    /// database, and only then are the rows of the records appended. A journal naming a commit
+    /// marker that does not exist is discarded on recovery.
+    pub fn commit(self) -> DBResult<Vec<WriteReceipt>> {
+        let Transaction {
+            db,
+            writes,
+            collection_engines,
+        } = self;
+        debug!("Committing a transaction of {} records", writes.len());
+        if writes.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let transactions_path = db.engine.data_dir_path().join(TRANSACTIONS_DIRNAME);
+        let write_durability = db.engine.config.write_durability.clone();
+        let marker_name = Uuid::new_v4().to_string();
+
+        let mut writes_by_db: BTreeMap<Option<String>, (Vec<usize>, Vec<Record>)> = BTreeMap::new();
+        let records_len = writes.len();
+        for (position, (name, record)) in writes.into_iter().enumerate() {
+            let (positions, records) = writes_by_db.entry(name).or_default();
+            positions.push(position);
+            records.push(record);
+        }
+
+        let DB {
+            engine,
+            collections,
+            ..
+        } = db;
+        let mut participants = vec![];
+        if let Some((positions, records)) = writes_by_db.remove(&None) {
+            participants.push(ParticipantWrites {
+                engine: engine as &mut dyn Participant,
+                commit_marker: Path::new(TRANSACTIONS_DIRNAME).join(&marker_name),
+                positions,
+                records,
+            });
+        }
+        for (name, collection) in collections.iter_mut() {
+            if let Some((positions, records)) = writes_by_db.remove(&Some(name.clone())) {
+                participants.push(ParticipantWrites {
+                    engine: collection_engines[name](collection.as_mut()),
+                    commit_marker: Path::new("..")
+                        .join("..")
+                        .join(TRANSACTIONS_DIRNAME)
+                        .join(&marker_name),
+                    positions,
+                    records,
+                });
+            }
+        }
+        // Locks are always taken in the same order, so that transactions cannot deadlock
+        participants.sort_by(|a, b| a.engine.data_dir_path().cmp(b.engine.data_dir_path()));
+
+        let mut locked = 0;
+        let mut result = Ok(vec![]);
+        for participant in participants.iter_mut() {
+            if let Err(e) = participant.engine.lock_exclusive() {
+                result = Err(e);
+                break;
+            }
+            locked += 1;
+        }
+        if result.is_ok() {
+            result = commit_locked(
+                &mut participants,
+                &transactions_path.join(&marker_name),
+                &write_durability,
+            );
+        }
+        for participant in participants[..locked].iter_mut() {
+            let unlocked = participant.engine.unlock_exclusive();
+            if result.is_ok() {
+                unlocked?;
+            }
+        }
+
+        let mut receipts: Vec<Option<WriteReceipt>> = vec![None; records_len];
+        for (positions, participant_receipts) in result? {
+            for (position, receipt) in positions.into_iter().zip(participant_receipts) {
+                receipts[position] = Some(receipt);
+            }
+        }
+        Ok(receipts.into_iter().flatten().collect())
+    }
+}
+
+/// Write the records of a transaction while holding the exclusive locks of all participants.
+/// Returns the positions and receipts of the records of each participant.
+fn commit_locked(
+    participants: &mut [ParticipantWrites],
+    marker_path: &Path,
+    write_durability: &WriteDurability,
+) -> DBResult<Vec<(Vec<usize>, Vec<WriteReceipt>)>> {
+    let mut batches = vec![];
+    for participant in participants.iter_mut() {
+        let records = std::mem::take(&mut participant.records);
+        batches.push(
+            participant
+                .engine
+                .stage_records(records, &participant.commit_marker)?,
+        );
+    }
+
+    // The transaction is committed once its marker exists
+    let transactions_path = marker_path.parent().unwrap();
+    fs::create_dir_all(transactions_path)?;
+    write_durability.persist(&mut fs::File::create(marker_path)?)?;
+    write_durability.persist_dir(transactions_path)?;
+
+    let mut receipts = vec![];
+    for (participant, batch) in participants.iter_mut().zip(batches) {
+        receipts.push((
+            std::mem::take(&mut participant.positions),
+            participant.engine.commit_staged(batch)?,
+        ));
+    }
+
+    // A journal is removed once its rows have been appended, so no journal names the marker anymore
+    fs::remove_file(marker_path)?;
+    Ok(receipts)
+}
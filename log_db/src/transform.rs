@@ -0,0 +1,129 @@
+use super::*;
+use sha2::{Digest, Sha256};
+
+/// A transformation applied to the value of a field before a record is written, see
+/// `ConfigBuilder::write_transform`. Transforms leave null values as they are.
+#[derive(Debug, Clone)]
+pub enum WriteTransform {
+    /// Trim leading and trailing whitespace of a string and collapse runs of inner whitespace into
+    /// single spaces.
+    NormalizeWhitespace,
+    /// Convert a string to lowercase.
+    Lowercase,
+    /// Truncate a string to at most this many characters, or bytes to at most this many bytes.
+    Truncate(usize),
+    /// Replace a string with the lowercase hex SHA-256 digest of its UTF-8 bytes, or bytes with
+    /// their 32-byte SHA-256 digest. Useful for pseudonymizing e.g. email addresses while keeping
+    /// them comparable.
+    Sha256,
+    /// Any other transformation. The function must return a value of the type of the field.
+    Custom(fn(Value) -> Value),
+}
+
+impl WriteTransform {
+    pub fn apply(&self, value: Value) -> DBResult<Value> {
+        let value = match (self, value) {
+            (_, Value::Null) => Value::Null,
+            (WriteTransform::NormalizeWhitespace, Value::String(s)) => {
+                Value::String(s.split_whitespace().collect::<Vec<&str>>().join(" "))
+            }
+            (WriteTransform::Lowercase, Value::String(s)) => Value::String(s.to_lowercase()),
+            (WriteTransform::Truncate(max_len), Value::String(s)) => {
+                Value::String(s.chars().take(*max_len).collect())
+            }
+            (WriteTransform::Truncate(max_len), Value::Bytes(mut b)) => {
+                b.truncate(*max_len);
+                Value::Bytes(b)
+            }
+            (WriteTransform::Sha256, Value::String(s)) => {
+                let digest = Sha256::digest(s.as_bytes());
+                Value::String(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+            }
+            (WriteTransform::Sha256, Value::Bytes(b)) => Value::Bytes(Sha256::digest(&b).to_vec()),
+            (WriteTransform::Custom(f), value) => f(value),
+            (transform, value) => {
+                return Err(DBError::ValidationError(format!(
+                    "Write transform {:?} cannot be applied to {:?}",
+                    transform, value
+                )))
+            }
+        };
+        Ok(value)
+    }
+}
+
+/// The order and equality of the string keys of a secondary index, see
+/// `ConfigBuilder::collation`. Records are stored as they were written; only their keys in the
+/// index are collated, so values read from the index, e.g. with `DB::distinct_values`, are in
+/// their collated form.
+#[derive(Debug, Clone, Default)]
+pub enum Collation {
+    /// Compare strings by their UTF-8 bytes, which orders them by code point.
+    #[default]
+    Binary,
+    /// Compare strings ignoring the case of ASCII letters, so that e.g. `"apple"` sorts before
+    /// `"Banana"` and `find_by` with `"APPLE"` finds `"Apple"`. Other characters are compared
+    /// by their bytes.
+    AsciiCaseInsensitive,
+    /// Compare strings by the sort keys returned by the function, e.g. the sort keys of a
+    /// locale-aware collator. Strings with the same sort key are equal in the index. The
+    /// function must return the same key for the same string in every process.
+    SortKey(fn(&str) -> String),
+}
+
+impl Collation {
+    /// Get the key of `key` in an index with this collation. Keys other than strings are kept as
+    /// they are.
+    pub fn apply(&self, key: IndexableValue) -> IndexableValue {
+        match (self, key) {
+            (Collation::Binary, key) => key,
+            (Collation::AsciiCaseInsensitive, IndexableValue::String(s)) => {
+                IndexableValue::String(s.to_ascii_lowercase())
+            }
+            (Collation::SortKey(sort_key), IndexableValue::String(s)) => {
+                IndexableValue::String(sort_key(&s))
+            }
+            (_, key) => key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_transforms() {
+        let string = |s: &str| Value::String(s.to_owned());
+
+        assert_eq!(
+            WriteTransform::NormalizeWhitespace
+                .apply(string("  Foo \t bar\n"))
+                .unwrap(),
+            string("Foo bar")
+        );
+        assert_eq!(
+            WriteTransform::Lowercase.apply(string("ÄbC")).unwrap(),
+            string("äbc")
+        );
+        assert_eq!(
+            WriteTransform::Truncate(2).apply(string("äöü")).unwrap(),
+            string("äö")
+        );
+        assert_eq!(
+            WriteTransform::Truncate(2)
+                .apply(Value::Bytes(vec![1, 2, 3]))
+                .unwrap(),
+            Value::Bytes(vec![1, 2])
+        );
+        assert_eq!(
+            WriteTransform::Sha256.apply(string("abc")).unwrap(),
+            string("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            WriteTransform::Lowercase.apply(Value::Null).unwrap(),
+            Value::Null
+        );
+        assert!(WriteTransform::Lowercase.apply(Value::Int(1)).is_err());
+    }
+}
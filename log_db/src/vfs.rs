@@ -0,0 +1,1329 @@
+use super::*;
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+
+/// The root of the files kept in memory by this process, in `Vec<u8>` buffers, see
+/// `ConfigBuilder::in_memory`. The engine works with files through this module, which mirrors the
+/// parts of `std::fs` that it uses and passes all other paths on to `std::fs`.
+///
+/// Files in memory behave like files on a local filesystem as far as the engine relies on it:
+/// renames replace their target atomically, removed files stay readable through open handles,
+/// hard links share their contents and locks are advisory and held per open file, like `flock`.
+/// Files in memory cannot be memory-mapped, and syncing them does nothing.
+const MEMORY_ROOT: &str = "/log_db-memory";
+
+/// The number of symlinks followed to resolve a path before giving up
+const MAX_SYMLINKS: usize = 40;
+
+static MEMORY: Lazy<Mutex<MemoryFs>> = Lazy::new(|| Mutex::new(MemoryFs::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, AtomicOrdering::Relaxed)
+}
+
+fn memory() -> MutexGuard<'static, MemoryFs> {
+    MEMORY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Whether the files of `path` are kept in memory rather than on disk.
+pub fn is_in_memory(path: &Path) -> bool {
+    memory_path(path).is_some()
+}
+
+/// The normalized form of `path` if it is kept in memory. Paths are normalized lexically, i.e.
+/// `..` removes the preceding component, since there are no symlinked directories in memory.
+fn memory_path(path: &Path) -> Option<PathBuf> {
+    if !path.starts_with(MEMORY_ROOT) {
+        return None;
+    }
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized.starts_with(MEMORY_ROOT).then_some(normalized)
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} already exists", path.display()),
+    )
+}
+
+fn crosses_devices(from: &Path, to: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::CrossesDevices,
+        format!(
+            "{} and {} are not both in memory or both on disk",
+            from.display(),
+            to.display()
+        ),
+    )
+}
+
+/// The contents of the files and directories kept in memory, by their normalized paths.
+struct MemoryFs {
+    nodes: BTreeMap<PathBuf, Node>,
+}
+
+enum Node {
+    Dir { modified: SystemTime },
+    File(Arc<Inode>),
+    Symlink(PathBuf),
+}
+
+/// The contents of a file in memory, shared by its hard links and open handles
+struct Inode {
+    id: u64,
+    contents: RwLock<Vec<u8>>,
+    modified: Mutex<SystemTime>,
+    locks: Mutex<Locks>,
+    unlocked: Condvar,
+}
+
+/// The handles holding a lock on a file in memory, by the id of their `OpenFile`
+#[derive(Default)]
+struct Locks {
+    exclusive: Option<u64>,
+    shared: HashSet<u64>,
+}
+
+impl MemoryFs {
+    fn new() -> MemoryFs {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            PathBuf::from(MEMORY_ROOT),
+            Node::Dir {
+                modified: SystemTime::now(),
+            },
+        );
+        MemoryFs { nodes }
+    }
+
+    /// Follow the symlinks of `path`. The resolved path may not exist.
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut path = path.to_path_buf();
+        for _ in 0..MAX_SYMLINKS {
+            match self.nodes.get(&path) {
+                Some(Node::Symlink(target)) => {
+                    let parent_path = path.parent().unwrap_or(Path::new(MEMORY_ROOT));
+                    path = memory_path(&parent_path.join(target))
+                        .ok_or_else(|| not_found(&parent_path.join(target)))?;
+                }
+                _ => return Ok(path),
+            }
+        }
+        Err(io::Error::other(format!(
+            "Too many levels of symlinks in {}",
+            path.display()
+        )))
+    }
+
+    fn get(&self, path: &Path) -> io::Result<&Node> {
+        self.nodes.get(path).ok_or_else(|| not_found(path))
+    }
+
+    fn inode(&self, path: &Path) -> io::Result<Arc<Inode>> {
+        match self.get(&self.resolve(path)?)? {
+            Node::File(inode) => Ok(inode.clone()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("{} is not a file", path.display()),
+            )),
+        }
+    }
+
+    /// Check that a node can be created at `path`.
+    fn check_new(&self, path: &Path) -> io::Result<()> {
+        if self.nodes.contains_key(path) {
+            return Err(already_exists(path));
+        }
+        match path.parent().map(|parent_path| self.nodes.get(parent_path)) {
+            Some(Some(Node::Dir { .. })) => Ok(()),
+            _ => Err(not_found(path.parent().unwrap_or(path))),
+        }
+    }
+
+    /// The paths of the nodes under `dir_path`, not including itself, in order.
+    fn descendants(&self, dir_path: &Path) -> Vec<PathBuf> {
+        self.nodes
+            .range(dir_path.to_path_buf()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(dir_path))
+            .filter(|path| *path != dir_path)
+            .cloned()
+            .collect()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = match self.get(path)? {
+            Node::Dir { modified } => MemoryMetadata {
+                file_type: FileType::Dir,
+                len: 0,
+                modified: *modified,
+                inode_id: None,
+            },
+            Node::File(inode) => MemoryMetadata {
+                file_type: FileType::File,
+                len: inode.len(),
+                modified: *inode.modified.lock().unwrap(),
+                inode_id: Some(inode.id),
+            },
+            Node::Symlink(target) => MemoryMetadata {
+                file_type: FileType::Symlink,
+                len: target.as_os_str().len() as u64,
+                modified: SystemTime::UNIX_EPOCH,
+                inode_id: None,
+            },
+        };
+        Ok(Metadata(MetadataInner::Memory(metadata)))
+    }
+}
+
+impl Inode {
+    fn new() -> Inode {
+        Inode {
+            id: next_id(),
+            contents: RwLock::new(vec![]),
+            modified: Mutex::new(SystemTime::now()),
+            locks: Mutex::new(Locks::default()),
+            unlocked: Condvar::new(),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.contents.read().unwrap().len() as u64
+    }
+
+    fn touch(&self) {
+        *self.modified.lock().unwrap() = SystemTime::now();
+    }
+
+    /// Take a lock for the handle `owner`, waiting for other handles to release theirs if `wait`.
+    /// A handle that already holds a lock has it converted, like with `flock`.
+    fn lock(&self, owner: u64, exclusive: bool, wait: bool) -> io::Result<()> {
+        let mut locks = self.locks.lock().unwrap();
+        loop {
+            let held_by_others = locks.exclusive.is_some_and(|holder| holder != owner)
+                || (exclusive && locks.shared.iter().any(|&holder| holder != owner));
+            if !held_by_others {
+                break;
+            }
+            if !wait {
+                return Err(fs2::lock_contended_error());
+            }
+            locks = self.unlocked.wait(locks).unwrap();
+        }
+
+        locks.shared.remove(&owner);
+        locks.exclusive = locks.exclusive.filter(|&holder| holder != owner);
+        match exclusive {
+            true => locks.exclusive = Some(owner),
+            false => {
+                locks.shared.insert(owner);
+            }
+        }
+        Ok(())
+    }
+
+    fn unlock(&self, owner: u64) {
+        let mut locks = self.locks.lock().unwrap();
+        locks.shared.remove(&owner);
+        if locks.exclusive == Some(owner) {
+            locks.exclusive = None;
+        }
+        self.unlocked.notify_all();
+    }
+}
+
+/// An open file in memory, shared by the handles cloned from it. Like an open file description,
+/// it has the position and the locks of its handles.
+struct OpenFile {
+    id: u64,
+    inode: Arc<Inode>,
+    position: Mutex<u64>,
+    read: bool,
+    write: bool,
+    append: bool,
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        self.inode.unlock(self.id);
+    }
+}
+
+/// An open file on disk or in memory, see `std::fs::File`.
+#[derive(Debug)]
+pub struct File(FileInner);
+
+enum FileInner {
+    Disk(std::fs::File),
+    Memory(Arc<OpenFile>),
+}
+
+impl Debug for FileInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileInner::Disk(file) => file.fmt(f),
+            FileInner::Memory(file) => write!(f, "File {{ memory: {} }}", file.inode.id),
+        }
+    }
+}
+
+impl File {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<File> {
+        OpenOptions::new().read(true).open(path)
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+    }
+
+    /// The file on disk, or `None` for a file in memory
+    pub fn as_disk(&self) -> Option<&std::fs::File> {
+        match &self.0 {
+            FileInner::Disk(file) => Some(file),
+            FileInner::Memory(_) => None,
+        }
+    }
+
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        match &self.0 {
+            FileInner::Disk(file) => Ok(Metadata(MetadataInner::Disk(file.metadata()?))),
+            FileInner::Memory(file) => Ok(Metadata(MetadataInner::Memory(MemoryMetadata {
+                file_type: FileType::File,
+                len: file.inode.len(),
+                modified: *file.inode.modified.lock().unwrap(),
+                inode_id: Some(file.inode.id),
+            }))),
+        }
+    }
+
+    pub fn set_len(&self, len: u64) -> io::Result<()> {
+        match &self.0 {
+            FileInner::Disk(file) => file.set_len(len),
+            FileInner::Memory(file) => {
+                file.check_writable()?;
+                file.inode.contents.write().unwrap().resize(len as usize, 0);
+                file.inode.touch();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        match &self.0 {
+            FileInner::Disk(file) => file.sync_all(),
+            FileInner::Memory(_) => Ok(()),
+        }
+    }
+
+    pub fn sync_data(&self) -> io::Result<()> {
+        match &self.0 {
+            FileInner::Disk(file) => file.sync_data(),
+            FileInner::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Create a new handle to the same open file, which shares its position and its locks.
+    pub fn try_clone(&self) -> io::Result<File> {
+        match &self.0 {
+            FileInner::Disk(file) => Ok(File(FileInner::Disk(file.try_clone()?))),
+            FileInner::Memory(file) => Ok(File(FileInner::Memory(file.clone()))),
+        }
+    }
+
+    /// Read exactly `buf.len()` bytes at `offset`. The position of the file is not used, though
+    /// it may be moved on Windows.
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let file = match &self.0 {
+            FileInner::Disk(file) => file,
+            FileInner::Memory(file) => {
+                file.check_readable()?;
+                let contents = file.inode.contents.read().unwrap();
+                let end = offset.checked_add(buf.len() as u64);
+                if end.is_none_or(|end| end > contents.len() as u64) {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                buf.copy_from_slice(&contents[offset as usize..offset as usize + buf.len()]);
+                return Ok(());
+            }
+        };
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+        }
+
+        #[cfg(windows)]
+        {
+            let (mut buf, mut offset) = (buf, offset);
+            while !buf.is_empty() {
+                match std::os::windows::fs::FileExt::seek_read(file, buf, offset) {
+                    Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(read) => {
+                        buf = &mut std::mem::take(&mut buf)[read..];
+                        offset += read as u64;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    pub fn lock_shared(&self) -> io::Result<()> {
+        match &self.0 {
+            FileInner::Disk(file) => fs2::FileExt::lock_shared(file),
+            FileInner::Memory(file) => file.inode.lock(file.id, false, true),
+        }
+    }
+
+    pub fn lock_exclusive(&self) -> io::Result<()> {
+        match &self.0 {
+            FileInner::Disk(file) => fs2::FileExt::lock_exclusive(file),
+            FileInner::Memory(file) => file.inode.lock(file.id, true, true),
+        }
+    }
+
+    /// Take a shared lock without waiting. Fails with `fs2::lock_contended_error` if another
+    /// handle holds an exclusive lock.
+    pub fn try_lock_shared(&self) -> io::Result<()> {
+        match &self.0 {
+            FileInner::Disk(file) => fs2::FileExt::try_lock_shared(file),
+            FileInner::Memory(file) => file.inode.lock(file.id, false, false),
+        }
+    }
+
+    /// Take an exclusive lock without waiting. Fails with `fs2::lock_contended_error` if another
+    /// handle holds a lock.
+    pub fn try_lock_exclusive(&self) -> io::Result<()> {
+        match &self.0 {
+            FileInner::Disk(file) => fs2::FileExt::try_lock_exclusive(file),
+            FileInner::Memory(file) => file.inode.lock(file.id, true, false),
+        }
+    }
+
+    pub fn unlock(&self) -> io::Result<()> {
+        match &self.0 {
+            FileInner::Disk(file) => fs2::FileExt::unlock(file),
+            FileInner::Memory(file) => {
+                file.inode.unlock(file.id);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl OpenFile {
+    fn check_readable(&self) -> io::Result<()> {
+        match self.read {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "File is not open for reading",
+            )),
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        match self.write || self.append {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "File is not open for writing",
+            )),
+        }
+    }
+}
+
+impl Read for &File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &self.0 {
+            FileInner::Disk(file) => {
+                let mut file = file;
+                file.read(buf)
+            }
+            FileInner::Memory(file) => {
+                file.check_readable()?;
+                let mut position = file.position.lock().unwrap();
+                let contents = file.inode.contents.read().unwrap();
+                let start = (*position).min(contents.len() as u64) as usize;
+                let len = buf.len().min(contents.len() - start);
+                buf[..len].copy_from_slice(&contents[start..start + len]);
+                *position += len as u64;
+                Ok(len)
+            }
+        }
+    }
+}
+
+impl Write for &File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.0 {
+            FileInner::Disk(file) => {
+                let mut file = file;
+                file.write(buf)
+            }
+            FileInner::Memory(file) => {
+                file.check_writable()?;
+                let mut position = file.position.lock().unwrap();
+                let mut contents = file.inode.contents.write().unwrap();
+                if file.append {
+                    *position = contents.len() as u64;
+                }
+                let start = *position as usize;
+                let end = start + buf.len();
+                if contents.len() < end {
+                    contents.resize(end, 0);
+                }
+                contents[start..end].copy_from_slice(buf);
+                *position = end as u64;
+                file.inode.touch();
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.0 {
+            FileInner::Disk(file) => {
+                let mut file = file;
+                file.flush()
+            }
+            FileInner::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Seek for &File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match &self.0 {
+            FileInner::Disk(file) => {
+                let mut file = file;
+                file.seek(pos)
+            }
+            FileInner::Memory(file) => {
+                let mut position = file.position.lock().unwrap();
+                let new_position = match pos {
+                    SeekFrom::Start(offset) => Some(offset),
+                    SeekFrom::End(offset) => file.inode.len().checked_add_signed(offset),
+                    SeekFrom::Current(offset) => position.checked_add_signed(offset),
+                };
+                *position = new_position.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position")
+                })?;
+                Ok(*position)
+            }
+        }
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        (&*self).seek(pos)
+    }
+}
+
+/// Options for opening a file on disk or in memory, see `std::fs::OpenOptions`.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut OpenOptions {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut OpenOptions {
+        self.write = write;
+        self
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut OpenOptions {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.create = create;
+        self
+    }
+
+    pub fn create_new(&mut self, create_new: bool) -> &mut OpenOptions {
+        self.create_new = create_new;
+        self
+    }
+
+    pub fn open(&self, path: impl AsRef<Path>) -> io::Result<File> {
+        let path = path.as_ref();
+        let Some(path) = memory_path(path) else {
+            return Ok(File(FileInner::Disk(
+                std::fs::OpenOptions::new()
+                    .read(self.read)
+                    .write(self.write)
+                    .append(self.append)
+                    .truncate(self.truncate)
+                    .create(self.create)
+                    .create_new(self.create_new)
+                    .open(path)?,
+            )));
+        };
+
+        let mut memory = memory();
+        let resolved_path = memory.resolve(&path)?;
+        let inode = match memory.nodes.get(&resolved_path) {
+            Some(_) if self.create_new => return Err(already_exists(&path)),
+            Some(_) => memory.inode(&resolved_path)?,
+            None if self.create || self.create_new => {
+                memory.check_new(&resolved_path)?;
+                let inode = Arc::new(Inode::new());
+                memory
+                    .nodes
+                    .insert(resolved_path, Node::File(inode.clone()));
+                inode
+            }
+            None => return Err(not_found(&path)),
+        };
+        if self.truncate && self.write {
+            inode.contents.write().unwrap().clear();
+            inode.touch();
+        }
+
+        Ok(File(FileInner::Memory(Arc::new(OpenFile {
+            id: next_id(),
+            inode,
+            position: Mutex::new(0),
+            read: self.read,
+            write: self.write,
+            append: self.append,
+        }))))
+    }
+}
+
+/// The type of a file on disk or in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    /// Other files on disk, e.g. sockets
+    Other,
+}
+
+impl FileType {
+    fn from_disk(file_type: std::fs::FileType) -> FileType {
+        if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_dir() {
+            FileType::Dir
+        } else if file_type.is_file() {
+            FileType::File
+        } else {
+            FileType::Other
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        *self == FileType::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        *self == FileType::Dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        *self == FileType::Symlink
+    }
+}
+
+/// Metadata of a file on disk or in memory, see `std::fs::Metadata`.
+#[derive(Debug)]
+pub struct Metadata(MetadataInner);
+
+#[derive(Debug)]
+enum MetadataInner {
+    Disk(std::fs::Metadata),
+    Memory(MemoryMetadata),
+}
+
+#[derive(Debug)]
+struct MemoryMetadata {
+    file_type: FileType,
+    len: u64,
+    modified: SystemTime,
+    /// The contents of a file, which its hard links share
+    inode_id: Option<u64>,
+}
+
+impl Metadata {
+    pub fn file_type(&self) -> FileType {
+        match &self.0 {
+            MetadataInner::Disk(metadata) => FileType::from_disk(metadata.file_type()),
+            MetadataInner::Memory(metadata) => metadata.file_type,
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type().is_file()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type().is_dir()
+    }
+
+    pub fn len(&self) -> u64 {
+        match &self.0 {
+            MetadataInner::Disk(metadata) => metadata.len(),
+            MetadataInner::Memory(metadata) => metadata.len,
+        }
+    }
+
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        match &self.0 {
+            MetadataInner::Disk(metadata) => metadata.modified(),
+            MetadataInner::Memory(metadata) => Ok(metadata.modified),
+        }
+    }
+
+    /// Whether both metadata describe the same file, e.g. a file and a hard link to it.
+    pub fn is_same_file(&self, other: &Metadata) -> bool {
+        match (&self.0, &other.0) {
+            (MetadataInner::Disk(a), MetadataInner::Disk(b)) => {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::MetadataExt;
+                    a.dev() == b.dev() && a.ino() == b.ino()
+                }
+
+                #[cfg(windows)]
+                {
+                    use std::os::windows::fs::MetadataExt;
+                    a.file_index() == b.file_index()
+                        && a.volume_serial_number() == b.volume_serial_number()
+                }
+            }
+            (MetadataInner::Memory(a), MetadataInner::Memory(b)) => {
+                a.inode_id.is_some() && a.inode_id == b.inode_id
+            }
+            _ => false,
+        }
+    }
+}
+
+/// An entry of a directory on disk or in memory, see `std::fs::DirEntry`.
+pub struct DirEntry(DirEntryInner);
+
+enum DirEntryInner {
+    Disk(std::fs::DirEntry),
+    Memory(PathBuf),
+}
+
+impl DirEntry {
+    pub fn path(&self) -> PathBuf {
+        match &self.0 {
+            DirEntryInner::Disk(entry) => entry.path(),
+            DirEntryInner::Memory(path) => path.clone(),
+        }
+    }
+
+    pub fn file_name(&self) -> OsString {
+        match &self.0 {
+            DirEntryInner::Disk(entry) => entry.file_name(),
+            DirEntryInner::Memory(path) => path.file_name().unwrap_or_default().to_owned(),
+        }
+    }
+
+    /// The metadata of the entry itself. Symlinks are not followed.
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        match &self.0 {
+            DirEntryInner::Disk(entry) => Ok(Metadata(MetadataInner::Disk(entry.metadata()?))),
+            DirEntryInner::Memory(path) => memory().metadata(path),
+        }
+    }
+
+    pub fn file_type(&self) -> io::Result<FileType> {
+        match &self.0 {
+            DirEntryInner::Disk(entry) => Ok(FileType::from_disk(entry.file_type()?)),
+            DirEntryInner::Memory(_) => Ok(self.metadata()?.file_type()),
+        }
+    }
+}
+
+/// The entries of a directory on disk or in memory, see `std::fs::ReadDir`. The entries of a
+/// directory in memory are listed when the iterator is created.
+pub struct ReadDir(ReadDirInner);
+
+enum ReadDirInner {
+    Disk(std::fs::ReadDir),
+    Memory(std::vec::IntoIter<PathBuf>),
+}
+
+impl Iterator for ReadDir {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<io::Result<DirEntry>> {
+        match &mut self.0 {
+            ReadDirInner::Disk(entries) => Some(
+                entries
+                    .next()?
+                    .map(|entry| DirEntry(DirEntryInner::Disk(entry))),
+            ),
+            ReadDirInner::Memory(paths) => Some(Ok(DirEntry(DirEntryInner::Memory(paths.next()?)))),
+        }
+    }
+}
+
+pub fn exists(path: impl AsRef<Path>) -> io::Result<bool> {
+    let path = path.as_ref();
+    match memory_path(path) {
+        Some(path) => {
+            let memory = memory();
+            Ok(memory.nodes.contains_key(&memory.resolve(&path)?))
+        }
+        None => std::fs::exists(path),
+    }
+}
+
+/// The metadata of the file at `path`, following symlinks.
+pub fn metadata(path: impl AsRef<Path>) -> io::Result<Metadata> {
+    let path = path.as_ref();
+    match memory_path(path) {
+        Some(path) => {
+            let memory = memory();
+            memory.metadata(&memory.resolve(&path)?)
+        }
+        None => Ok(Metadata(MetadataInner::Disk(std::fs::metadata(path)?))),
+    }
+}
+
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    let path = path.as_ref();
+    match memory_path(path) {
+        Some(path) => Ok(memory().inode(&path)?.contents.read().unwrap().clone()),
+        None => std::fs::read(path),
+    }
+}
+
+pub fn read_to_string(path: impl AsRef<Path>) -> io::Result<String> {
+    String::from_utf8(read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    File::create(path)?.write_all(contents.as_ref())
+}
+
+pub fn copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<u64> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    if !is_in_memory(from) && !is_in_memory(to) {
+        return std::fs::copy(from, to);
+    }
+    let contents = read(from)?;
+    write(to, &contents)?;
+    Ok(contents.len() as u64)
+}
+
+pub fn read_link(path: impl AsRef<Path>) -> io::Result<PathBuf> {
+    let path = path.as_ref();
+    let Some(path) = memory_path(path) else {
+        return std::fs::read_link(path);
+    };
+    match memory().get(&path)? {
+        Node::Symlink(target) => Ok(target.clone()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a symlink", path.display()),
+        )),
+    }
+}
+
+/// Create a symlink at `link` that points to `original`, which is a file.
+pub fn symlink(original: impl AsRef<Path>, link: impl AsRef<Path>) -> io::Result<()> {
+    let (original, link) = (original.as_ref(), link.as_ref());
+    let Some(link) = memory_path(link) else {
+        #[cfg(unix)]
+        {
+            return std::os::unix::fs::symlink(original, link);
+        }
+
+        #[cfg(windows)]
+        {
+            return std::os::windows::fs::symlink_file(original, link);
+        }
+    };
+    let mut memory = memory();
+    memory.check_new(&link)?;
+    memory
+        .nodes
+        .insert(link, Node::Symlink(original.to_path_buf()));
+    Ok(())
+}
+
+pub fn hard_link(original: impl AsRef<Path>, link: impl AsRef<Path>) -> io::Result<()> {
+    let (original, link) = (original.as_ref(), link.as_ref());
+    let (Some(original), Some(link)) = (memory_path(original), memory_path(link)) else {
+        if is_in_memory(original) || is_in_memory(link) {
+            return Err(crosses_devices(original, link));
+        }
+        return std::fs::hard_link(original, link);
+    };
+    let mut memory = memory();
+    let inode = memory.inode(&original)?;
+    memory.check_new(&link)?;
+    memory.nodes.insert(link, Node::File(inode));
+    Ok(())
+}
+
+/// Rename a file or a directory, replacing the file at `to` if there is one.
+pub fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let (Some(from), Some(to)) = (memory_path(from), memory_path(to)) else {
+        if is_in_memory(from) || is_in_memory(to) {
+            return Err(crosses_devices(from, to));
+        }
+        return std::fs::rename(from, to);
+    };
+    if from == to {
+        return Ok(());
+    }
+
+    let mut memory = memory();
+    let is_dir = matches!(memory.get(&from)?, Node::Dir { .. });
+    match memory.nodes.get(&to) {
+        Some(Node::Dir { .. }) if !memory.descendants(&to).is_empty() => {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("{} is not empty", to.display()),
+            ))
+        }
+        Some(Node::Dir { .. }) if !is_dir => {
+            return Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("{} is a directory", to.display()),
+            ))
+        }
+        Some(_) => {
+            memory.nodes.remove(&to);
+        }
+        None => memory.check_new(&to)?,
+    }
+    if is_dir && to.starts_with(&from) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot move {} into itself", from.display()),
+        ));
+    }
+
+    for path in [vec![from.clone()], memory.descendants(&from)].concat() {
+        let node = memory.nodes.remove(&path).unwrap();
+        let new_path = to.join(path.strip_prefix(&from).unwrap());
+        memory.nodes.insert(new_path, node);
+    }
+    Ok(())
+}
+
+/// Remove a file or a symlink.
+pub fn remove_file(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    let Some(path) = memory_path(path) else {
+        return std::fs::remove_file(path);
+    };
+    let mut memory = memory();
+    match memory.get(&path)? {
+        Node::Dir { .. } => Err(io::Error::new(
+            io::ErrorKind::IsADirectory,
+            format!("{} is a directory", path.display()),
+        )),
+        _ => {
+            memory.nodes.remove(&path);
+            Ok(())
+        }
+    }
+}
+
+pub fn create_dir(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    let Some(path) = memory_path(path) else {
+        return std::fs::create_dir(path);
+    };
+    let mut memory = memory();
+    memory.check_new(&path)?;
+    memory.nodes.insert(
+        path,
+        Node::Dir {
+            modified: SystemTime::now(),
+        },
+    );
+    Ok(())
+}
+
+pub fn create_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    let Some(path) = memory_path(path) else {
+        return std::fs::create_dir_all(path);
+    };
+    let mut memory = memory();
+    for ancestor in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+        match memory.nodes.get(ancestor) {
+            Some(Node::Dir { .. }) => {}
+            Some(_) => return Err(already_exists(ancestor)),
+            None if ancestor.starts_with(MEMORY_ROOT) => {
+                memory.nodes.insert(
+                    ancestor.to_path_buf(),
+                    Node::Dir {
+                        modified: SystemTime::now(),
+                    },
+                );
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Remove a directory with its contents.
+pub fn remove_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    let Some(path) = memory_path(path) else {
+        return std::fs::remove_dir_all(path);
+    };
+    let mut memory = memory();
+    if !matches!(memory.get(&path)?, Node::Dir { .. }) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            format!("{} is not a directory", path.display()),
+        ));
+    }
+    for descendant in memory.descendants(&path) {
+        memory.nodes.remove(&descendant);
+    }
+    memory.nodes.remove(&path);
+    Ok(())
+}
+
+pub fn read_dir(path: impl AsRef<Path>) -> io::Result<ReadDir> {
+    let path = path.as_ref();
+    let Some(path) = memory_path(path) else {
+        return Ok(ReadDir(ReadDirInner::Disk(std::fs::read_dir(path)?)));
+    };
+    let memory = memory();
+    let path = memory.resolve(&path)?;
+    if !matches!(memory.get(&path)?, Node::Dir { .. }) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            format!("{} is not a directory", path.display()),
+        ));
+    }
+    let entries: Vec<PathBuf> = memory
+        .descendants(&path)
+        .into_iter()
+        .filter(|descendant| descendant.parent() == Some(path.as_path()))
+        .collect();
+    Ok(ReadDir(ReadDirInner::Memory(entries.into_iter())))
+}
+
+/// Sync the entries of a directory to disk, e.g. after creating or renaming files in it.
+pub fn sync_dir(dir_path: &Path) -> io::Result<()> {
+    match is_in_memory(dir_path) {
+        true => Ok(()),
+        false => std::fs::File::open(dir_path)?.sync_all(),
+    }
+}
+
+/// A file that is removed when dropped unless it is persisted, created next to where it is
+/// persisted so that the rename stays on the same filesystem, see `tempfile::NamedTempFile`.
+pub struct NamedTempFile {
+    file: File,
+    path: PathBuf,
+    persisted: bool,
+}
+
+/// The error of persisting a `NamedTempFile`, which is left in place.
+#[derive(Debug)]
+pub struct PersistError {
+    pub error: io::Error,
+    pub file: NamedTempFile,
+}
+
+impl From<PersistError> for io::Error {
+    fn from(e: PersistError) -> io::Error {
+        e.error
+    }
+}
+
+impl Debug for NamedTempFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NamedTempFile({})", self.path.display())
+    }
+}
+
+impl NamedTempFile {
+    pub fn new_in(dir_path: impl AsRef<Path>) -> io::Result<NamedTempFile> {
+        let dir_path = dir_path.as_ref();
+        loop {
+            let path = dir_path.join(format!(".tmp{}", Uuid::new_v4().simple()));
+            match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => {
+                    return Ok(NamedTempFile {
+                        file,
+                        path,
+                        persisted: false,
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn as_file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn as_file_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Rename the file to `path`, replacing any file there.
+    pub fn persist(self, path: impl AsRef<Path>) -> Result<File, PersistError> {
+        let path = path.as_ref();
+        self.persist_with(|tmp_path| rename(tmp_path, path))
+    }
+
+    /// Move the file to `path`, failing with `AlreadyExists` if there is a file there already.
+    pub fn persist_noclobber(self, path: impl AsRef<Path>) -> Result<File, PersistError> {
+        let path = path.as_ref();
+        self.persist_with(|tmp_path| {
+            hard_link(tmp_path, path)?;
+            remove_file(tmp_path)
+        })
+    }
+
+    fn persist_with(
+        mut self,
+        move_file: impl FnOnce(&Path) -> io::Result<()>,
+    ) -> Result<File, PersistError> {
+        let file = match self.file.try_clone() {
+            Ok(file) => file,
+            Err(error) => return Err(PersistError { error, file: self }),
+        };
+        match move_file(&self.path) {
+            Ok(()) => {
+                self.persisted = true;
+                Ok(file)
+            }
+            Err(error) => Err(PersistError { error, file: self }),
+        }
+    }
+}
+
+impl Drop for NamedTempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = remove_file(&self.path);
+        }
+    }
+}
+
+impl Read for NamedTempFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for NamedTempFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for NamedTempFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// A directory that is removed with its contents when dropped, in the system temporary directory
+/// or in memory, see `ConfigBuilder::ephemeral` and `ConfigBuilder::in_memory`.
+pub struct TempDir(TempDirInner);
+
+enum TempDirInner {
+    Disk(tempfile::TempDir),
+    Memory(PathBuf),
+}
+
+impl TempDir {
+    /// Create a new directory in the system temporary directory.
+    pub fn new() -> io::Result<TempDir> {
+        let dir = tempfile::Builder::new().prefix("log_db-").tempdir()?;
+        Ok(TempDir(TempDirInner::Disk(dir)))
+    }
+
+    /// Create a new directory in memory.
+    pub fn in_memory() -> io::Result<TempDir> {
+        let path = Path::new(MEMORY_ROOT).join(format!("log_db-{}", Uuid::new_v4().simple()));
+        create_dir(&path)?;
+        Ok(TempDir(TempDirInner::Memory(path)))
+    }
+
+    pub fn path(&self) -> &Path {
+        match &self.0 {
+            TempDirInner::Disk(dir) => dir.path(),
+            TempDirInner::Memory(path) => path,
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let TempDirInner::Memory(path) = &self.0 {
+            let _ = remove_dir_all(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_files() {
+        let dir = TempDir::in_memory().unwrap();
+        let path = dir.path().join("file");
+        assert!(is_in_memory(&path));
+        assert!(!std::fs::exists(dir.path()).unwrap());
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"hello").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(b" world").unwrap();
+        assert_eq!(read(&path).unwrap(), b"hello world");
+        let mut buf = [0; 5];
+        file.read_exact_at(&mut buf, 6).unwrap();
+        assert_eq!(&buf, b"world");
+
+        // Renaming over a file replaces it, and the old file stays readable through its handle
+        write(dir.path().join("other"), b"other").unwrap();
+        let other = File::open(dir.path().join("other")).unwrap();
+        rename(&path, dir.path().join("other")).unwrap();
+        assert!(!exists(&path).unwrap());
+        assert_eq!(read(dir.path().join("other")).unwrap(), b"hello world");
+        assert_eq!(io::read_to_string(&other).unwrap(), "other");
+
+        // Symlinks are resolved relative to their directory, and paths are normalized
+        symlink("other", dir.path().join("link")).unwrap();
+        assert_eq!(
+            read_link(dir.path().join("link")).unwrap(),
+            Path::new("other")
+        );
+        let linked = File::open(dir.path().join("sub/../link")).unwrap();
+        assert!(is_file_same_as_path(&linked, &dir.path().join("other")).unwrap());
+
+        hard_link(dir.path().join("other"), dir.path().join("hard")).unwrap();
+        remove_file(dir.path().join("other")).unwrap();
+        assert_eq!(read(dir.path().join("hard")).unwrap(), b"hello world");
+
+        let names: Vec<OsString> = read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["hard", "link"]);
+
+        let dir_path = dir.path().to_path_buf();
+        drop(dir);
+        assert!(!exists(&dir_path).unwrap());
+    }
+
+    #[test]
+    fn test_memory_locks() {
+        let dir = TempDir::in_memory().unwrap();
+        let path = dir.path().join("lock");
+        let a = File::create(&path).unwrap();
+        let b = File::create(&path).unwrap();
+
+        a.lock_shared().unwrap();
+        b.try_lock_shared().unwrap();
+        assert!(a.try_lock_exclusive().is_err());
+        b.unlock().unwrap();
+        a.try_lock_exclusive().unwrap();
+        assert!(b.try_lock_shared().is_err());
+
+        // Locks are released when the last handle to an open file is dropped
+        let a_clone = a.try_clone().unwrap();
+        drop(a);
+        assert!(b.try_lock_shared().is_err());
+        drop(a_clone);
+        b.try_lock_exclusive().unwrap();
+    }
+}
@@ -8,9 +8,11 @@ use env_logger;
 use log_db::*;
 use serial_test::serial;
 use std::fs::{self};
+use std::ops::Bound;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tempfile::tempdir;
 
 pub fn tmp_dir() -> String {
@@ -90,6 +92,33 @@ impl Recordable for Inst {
     }
 }
 
+/// `Inst` with a composite index on its name and id
+struct InstComposite(Inst);
+
+impl Recordable for InstComposite {
+    type Field = Field;
+    fn schema() -> Vec<(Self::Field, Type)> {
+        Inst::schema()
+    }
+    fn primary_key() -> Self::Field {
+        Inst::primary_key()
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        Inst::secondary_keys()
+    }
+    fn composite_keys() -> Vec<Vec<Self::Field>> {
+        vec![vec![Field::Name, Field::Id]]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        self.0.into_record()
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        InstComposite(Inst::from_record(record))
+    }
+}
+
 #[test]
 fn test_initialize_only() {
     let data_dir = tmp_dir();
@@ -567,6 +596,280 @@ fn test_delete_by() {
     );
 }
 
+#[test]
+#[serial]
+fn test_batch_delete_by() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for (id, name) in [(0, "John"), (1, "John"), (2, "Bob"), (3, "Alice")] {
+        db.upsert(Inst {
+            id,
+            name: Some(name.to_string()),
+            data: vec![],
+        })
+        .unwrap();
+    }
+
+    let name = |name: &str| Value::String(name.to_string());
+    let counts = db
+        .batch_delete_by(
+            &Field::Name,
+            &[name("John"), name("Nobody"), name("Bob"), name("John")],
+        )
+        .unwrap();
+    assert_eq!(counts, vec![2, 0, 1, 0]);
+
+    for id in 0..3 {
+        assert!(db.get(&Value::Int(id)).unwrap().is_none());
+    }
+    assert!(db.get(&Value::Int(3)).unwrap().is_some());
+
+    // The tombstones are seen by other handles
+    let mut other_handle = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(
+        other_handle
+            .find_by(&Field::Name, &name("John"))
+            .unwrap()
+            .len(),
+        0
+    );
+    assert_eq!(
+        other_handle
+            .batch_delete_by(&Field::Id, &[Value::Int(3), Value::Int(3)])
+            .unwrap(),
+        vec![1, 0]
+    );
+    assert!(db.get(&Value::Int(3)).unwrap().is_none());
+}
+
+#[test]
+#[serial]
+fn test_batch_range_by() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(100)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..10 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name{}", id % 3)),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+
+    let tagged_ids = |results: Vec<(usize, Inst)>| {
+        let mut tagged_ids: Vec<(usize, i64)> = results
+            .into_iter()
+            .map(|(tag, inst)| (tag, inst.id))
+            .collect();
+        // Within a range, the records are in log order
+        tagged_ids.sort();
+        tagged_ids
+    };
+
+    let results = db
+        .batch_range_by(
+            &Field::Id,
+            &[
+                &Value::Int(8)..&Value::Int(20),
+                &Value::Int(-5)..&Value::Int(2),
+                &Value::Int(1)..&Value::Int(3),
+                &Value::Int(20)..&Value::Int(30),
+            ],
+        )
+        .unwrap();
+    let tags: Vec<usize> = results.iter().map(|(tag, _)| *tag).collect();
+    assert!(tags.is_sorted());
+    assert_eq!(
+        tagged_ids(results),
+        vec![(0, 8), (0, 9), (1, 0), (1, 1), (2, 1), (2, 2)]
+    );
+
+    let name = |name: &str| Value::String(name.to_string());
+    let results = db
+        .batch_range_by(
+            &Field::Name,
+            &[(Bound::Excluded(name("name1")), Bound::Unbounded)],
+        )
+        .unwrap();
+    assert_eq!(tagged_ids(results), vec![(0, 2), (0, 5), (0, 8)]);
+
+    assert!(db
+        .batch_range_by(&Field::Data, &[&Value::Bytes(vec![])..])
+        .is_err());
+}
+
+#[test]
+#[serial]
+fn test_keys() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .delete_mode(DeleteMode::Soft)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    assert!(db.keys().unwrap().is_empty());
+    for id in [5, 1, 3, 2, 4] {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("inst {}", id)),
+            data: vec![],
+        })
+        .unwrap();
+    }
+    db.do_maintenance_tasks().unwrap();
+    db.delete(&Value::Int(3)).unwrap();
+
+    let ints = |ids: &[i64]| ids.iter().map(|&id| Value::Int(id)).collect::<Vec<_>>();
+    assert_eq!(db.keys().unwrap(), ints(&[1, 2, 4, 5]));
+    assert_eq!(
+        db.keys_range(&Value::Int(2)..&Value::Int(5)).unwrap(),
+        ints(&[2, 4])
+    );
+    assert_eq!(
+        db.include_deleted(|db| db.keys_range(&Value::Int(2)..))
+            .unwrap(),
+        ints(&[2, 3, 4, 5])
+    );
+}
+
+#[test]
+fn test_estimate_count() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .delete_mode(DeleteMode::Soft)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    assert_eq!(db.estimate_count().unwrap(), 0);
+    for (id, name) in [
+        (1, Some("Bob")),
+        (2, Some("Alice")),
+        (3, Some("Bob")),
+        (4, None),
+    ] {
+        db.upsert(Inst {
+            id,
+            name: name.map(str::to_owned),
+            data: vec![],
+        })
+        .unwrap();
+    }
+    assert_eq!(db.estimate_count().unwrap(), 4);
+    assert_eq!(db.estimate_cardinality(&Field::Id).unwrap(), 4);
+    assert_eq!(db.estimate_cardinality(&Field::Name).unwrap(), 3);
+
+    // Superseded versions and deleted records are not counted
+    db.upsert(Inst {
+        id: 2,
+        name: Some("Bob".to_string()),
+        data: vec![],
+    })
+    .unwrap();
+    db.do_maintenance_tasks().unwrap();
+    db.delete(&Value::Int(4)).unwrap();
+    assert_eq!(db.estimate_count().unwrap(), 3);
+    assert_eq!(db.estimate_cardinality(&Field::Name).unwrap(), 1);
+    assert_eq!(db.include_deleted(|db| db.estimate_count()).unwrap(), 4);
+
+    assert!(matches!(
+        db.estimate_cardinality(&Field::Data),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_find_by_null() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for (id, name) in [(0, None), (1, Some("John")), (2, None), (3, Some("Bob"))] {
+        db.upsert(Inst {
+            id,
+            name: name.map(str::to_string),
+            data: vec![],
+        })
+        .unwrap();
+    }
+
+    let ids = |insts: Vec<Inst>| {
+        let mut ids: Vec<i64> = insts.into_iter().map(|inst| inst.id).collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(
+        ids(db.find_by(&Field::Name, &Value::Null).unwrap()),
+        vec![0, 2]
+    );
+
+    // Filling in a value removes the record from the null entry of the index
+    db.upsert(Inst {
+        id: 2,
+        name: Some("Alice".to_string()),
+        data: vec![],
+    })
+    .unwrap();
+    db.upsert(Inst {
+        id: 1,
+        name: None,
+        data: vec![],
+    })
+    .unwrap();
+    assert_eq!(
+        ids(db.find_by(&Field::Name, &Value::Null).unwrap()),
+        vec![0, 1]
+    );
+    assert_eq!(
+        ids(db
+            .find_by(&Field::Name, &Value::String("John".to_string()))
+            .unwrap()),
+        Vec::<i64>::new()
+    );
+    let mut other_handle = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(
+        ids(other_handle.find_by(&Field::Name, &Value::Null).unwrap()),
+        vec![0, 1]
+    );
+
+    // Ranges skip nulls unless asked for them
+    assert_eq!(
+        ids(db
+            .range_by(&Field::Name, ..&Value::String("C".to_string()))
+            .unwrap()),
+        vec![2, 3]
+    );
+    assert_eq!(
+        ids(db.range_by(&Field::Name, ..=&Value::Null).unwrap()),
+        vec![0, 1]
+    );
+
+    // Non-nullable fields cannot be queried for null
+    assert!(db.find_by(&Field::Id, &Value::Null).is_err());
+}
+
 #[test]
 fn test_range_by_id() {
     let data_dir = tmp_dir();
@@ -647,3 +950,7312 @@ fn test_batch_find_by() {
         vec![2, 3, 4]
     );
 }
+
+#[test]
+fn test_find_by_any() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for (id, name) in [(0, "Alice"), (1, "Bob"), (2, "Carol"), (3, "Bob")] {
+        db.upsert(Inst {
+            id,
+            name: Some(name.to_string()),
+            data: vec![],
+        })
+        .unwrap();
+    }
+
+    let names = vec![
+        Value::String("Bob".to_string()),
+        Value::String("Carol".to_string()),
+        Value::String("Bob".to_string()),
+        Value::String("Dave".to_string()),
+    ];
+    let mut ids: Vec<i64> = db
+        .find_by_any(&Field::Name, &names)
+        .unwrap()
+        .into_iter()
+        .map(|inst| inst.id)
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![1, 2, 3]);
+
+    let ids: Vec<i64> = db
+        .find_by_any(&Field::Id, &[Value::Int(3), Value::Int(0), Value::Int(7)])
+        .unwrap()
+        .into_iter()
+        .map(|inst| inst.id)
+        .collect();
+    assert_eq!(ids, vec![3, 0]);
+
+    assert!(db.find_by_any(&Field::Name, &[]).unwrap().is_empty());
+}
+
+#[test]
+fn test_compaction_by_other_handle() {
+    let data_dir = tmp_dir();
+    let mut db1 = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for name in ["Alice", "Bob"] {
+        for id in 0..10 {
+            db1.upsert(Inst {
+                id,
+                name: Some(name.to_string()),
+                data: vec![],
+            })
+            .unwrap();
+        }
+    }
+
+    let mut db2 = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .read_consistency(ReadConsistency::Eventual)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(db2.get(&Value::Int(7)).unwrap().unwrap().id, 7);
+
+    // Compaction changes the row indexes of the segment that db2 has indexed
+    db1.compact(SegmentSelector::Active).unwrap();
+
+    for id in 0..10 {
+        let inst = db2.get(&Value::Int(id)).unwrap().unwrap();
+        assert_eq!(inst.id, id);
+        assert_eq!(inst.name, Some("Bob".to_string()));
+    }
+
+    db1.upsert(Inst {
+        id: 3,
+        name: Some("Carol".to_string()),
+        data: vec![],
+    })
+    .unwrap();
+    db1.delete(&Value::Int(4)).unwrap();
+    db1.compact(SegmentSelector::Active).unwrap();
+    db1.compact(SegmentSelector::Ids(vec![1])).unwrap();
+
+    db2.refresh_indexes().unwrap();
+    assert_eq!(
+        db2.get(&Value::Int(3)).unwrap().unwrap().name,
+        Some("Carol".to_string())
+    );
+    assert!(db2.get(&Value::Int(4)).unwrap().is_none());
+    assert_eq!(
+        db2.find_by(&Field::Name, &Value::String("Bob".to_string()))
+            .unwrap()
+            .len(),
+        8
+    );
+    assert!(db2
+        .find_by(&Field::Name, &Value::String("Alice".to_string()))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_compact_sealed_segments() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..10 {
+        db.upsert(Inst {
+            id,
+            name: Some("Alice".to_string()),
+            data: vec![0; 100],
+        })
+        .unwrap();
+    }
+
+    // Seal the first segment
+    let reports = db.compact(SegmentSelector::Active).unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].segment_num, 1);
+    assert_eq!(reports[0].records_after, 10);
+    assert!(data_dir_path.join("metadata.2").exists());
+
+    // Supersede half of the records in the new active segment, then seal it too
+    for id in 0..5 {
+        db.upsert(Inst {
+            id,
+            name: Some("Bob".to_string()),
+            data: vec![],
+        })
+        .unwrap();
+    }
+    db.delete(&Value::Int(9)).unwrap();
+    db.compact(SegmentSelector::Active).unwrap();
+
+    // Compacting the first segment drops the superseded and deleted records
+    let reports = db.compact(SegmentSelector::Ids(vec![1])).unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].segment_num, 1);
+    assert_eq!(reports[0].records_before, 10);
+    assert_eq!(reports[0].records_after, 4);
+    assert!(reports[0].reclaimed_bytes() > 0);
+
+    // Reads reflect the newest versions
+    for id in 0..5 {
+        let inst = db.get(&Value::Int(id)).unwrap().unwrap();
+        assert_eq!(inst.name, Some("Bob".to_string()));
+    }
+    for id in 5..9 {
+        let inst = db.get(&Value::Int(id)).unwrap().unwrap();
+        assert_eq!(inst.name, Some("Alice".to_string()));
+    }
+    assert!(db.get(&Value::Int(9)).unwrap().is_none());
+    assert_eq!(
+        db.find_by(&Field::Name, &Value::String("Alice".to_string()))
+            .unwrap()
+            .len(),
+        4
+    );
+
+    // A fresh instance rebuilds the same indexes from the compacted segments
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(db.range_by(&Field::Id, ..).unwrap().len(), 9);
+}
+
+#[test]
+fn test_compact_selectors() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    // Nothing to compact in an empty active segment
+    assert!(db.compact(SegmentSelector::All).unwrap().is_empty());
+
+    db.upsert(Inst {
+        id: 0,
+        name: None,
+        data: vec![],
+    })
+    .unwrap();
+
+    assert!(db.compact(SegmentSelector::Ids(vec![5])).is_err());
+
+    // The active segment is never selected by age
+    assert!(db
+        .compact(SegmentSelector::OlderThan(Duration::ZERO))
+        .unwrap()
+        .is_empty());
+
+    let reports = db.compact(SegmentSelector::All).unwrap();
+    assert_eq!(reports.len(), 1);
+
+    // Segment 1 is now sealed and selected by age
+    let reports = db
+        .compact(SegmentSelector::OlderThan(Duration::ZERO))
+        .unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].segment_num, 1);
+    assert_eq!(reports[0].records_after, 1);
+
+    assert!(db.get(&Value::Int(0)).unwrap().is_some());
+}
+
+#[test]
+fn test_scan_filter() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..10 {
+        db.upsert(Inst {
+            id,
+            name: None,
+            data: vec![id as u8 % 2],
+        })
+        .unwrap();
+    }
+
+    // Rotate so that the scan spans multiple segments
+    db.compact(SegmentSelector::Active).unwrap();
+
+    // Update a record so that it no longer matches, and delete another
+    db.upsert(Inst {
+        id: 0,
+        name: None,
+        data: vec![1],
+    })
+    .unwrap();
+    db.delete(&Value::Int(2)).unwrap();
+
+    let mut received = db
+        .scan_filter(|values| values[2] == Value::Bytes(vec![0]))
+        .unwrap();
+    received.sort_by_key(|inst| inst.id);
+
+    let received_ids: Vec<i64> = received.iter().map(|inst| inst.id).collect();
+    assert_eq!(received_ids, vec![4, 6, 8]);
+}
+
+#[test]
+fn test_record_versions() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let inst = |name: &str| Inst {
+        id: 0,
+        name: Some(name.to_string()),
+        data: vec![],
+    };
+
+    // Inserting requires that the record does not exist yet
+    db.upsert_if_version(inst("Alice"), None).unwrap();
+    assert!(matches!(
+        db.upsert_if_version(inst("Alice"), None),
+        Err(DBError::VersionConflict(_))
+    ));
+
+    let (_, meta1) = db.get_with_meta(&Value::Int(0)).unwrap().unwrap();
+
+    // Versions increase with every write, also across compaction and handles
+    db.upsert(inst("Bob")).unwrap();
+    db.compact(SegmentSelector::Active).unwrap();
+
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let (found, meta2) = db.get_with_meta(&Value::Int(0)).unwrap().unwrap();
+    assert_eq!(found.name, Some("Bob".to_string()));
+    assert!(meta2.version > meta1.version);
+
+    // A stale version is rejected and nothing is written
+    assert!(matches!(
+        db.upsert_if_version(inst("Carol"), Some(meta1.version)),
+        Err(DBError::VersionConflict(_))
+    ));
+    db.upsert_if_version(inst("Carol"), Some(meta2.version))
+        .unwrap();
+
+    let (found, meta3) = db.get_with_meta(&Value::Int(0)).unwrap().unwrap();
+    assert_eq!(found.name, Some("Carol".to_string()));
+    assert!(meta3.version > meta2.version);
+
+    // A deleted and reinserted record does not reuse old versions
+    db.delete(&Value::Int(0)).unwrap();
+    db.upsert_if_version(inst("Dave"), None).unwrap();
+    let (_, meta4) = db.get_with_meta(&Value::Int(0)).unwrap().unwrap();
+    assert!(meta4.version > meta3.version);
+}
+
+#[test]
+fn test_versions_survive_compaction_of_deleted_records() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let inst = |name: &str| Inst {
+        id: 1,
+        name: Some(name.to_string()),
+        data: vec![],
+    };
+
+    let receipt1 = db.upsert(inst("Alice")).unwrap();
+    db.delete(&Value::Int(1)).unwrap();
+    // The first compaction leaves only the tombstone, the second drops it
+    db.compact(SegmentSelector::All).unwrap();
+    db.compact(SegmentSelector::All).unwrap();
+    drop(db);
+
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert!(db.get(&Value::Int(1)).unwrap().is_none());
+
+    let receipt2 = db.upsert(inst("Bob")).unwrap();
+    assert!(receipt2.meta.version > receipt1.meta.version);
+    assert!(matches!(
+        db.upsert_if_version(inst("Carol"), Some(receipt1.meta.version)),
+        Err(DBError::VersionConflict(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_insert() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let inst = |id: i64, name: &str| Inst {
+        id,
+        name: Some(name.to_string()),
+        data: vec![],
+    };
+
+    db.insert(inst(0, "Alice")).unwrap();
+    assert!(matches!(
+        db.insert(inst(0, "Bob")),
+        Err(DBError::DuplicateKey(_))
+    ));
+    assert_eq!(
+        db.get(&Value::Int(0)).unwrap().unwrap().name,
+        Some("Alice".to_string())
+    );
+
+    // A deleted key can be inserted again
+    db.delete(&Value::Int(0)).unwrap();
+    db.insert(inst(0, "Carol")).unwrap();
+
+    // Of concurrent inserts of the same key by different handles, exactly one succeeds
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let data_dir = data_dir.clone();
+            thread::spawn(move || {
+                let mut db = DB::<Inst>::configure()
+                    .data_dir(&data_dir)
+                    .initialize()
+                    .expect("Failed to initialize DB instance");
+                db.insert(inst(1, &format!("thread {}", i)))
+            })
+        })
+        .collect();
+    let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+    assert!(results
+        .iter()
+        .filter_map(|result| result.as_ref().err())
+        .all(|e| matches!(e, DBError::DuplicateKey(_))));
+}
+
+#[test]
+fn test_manifest_verification() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..10 {
+        db.upsert(Inst {
+            id,
+            name: None,
+            data: vec![1, 2, 3],
+        })
+        .unwrap();
+    }
+    db.compact(SegmentSelector::Active).unwrap();
+    drop(db);
+
+    let open = |verification: ManifestVerification| {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .verify_manifest(verification)
+            .initialize()
+    };
+
+    // Untouched files pass verification
+    open(ManifestVerification::Refuse).expect("Verification should succeed");
+
+    // Modify a padding byte of the sealed segment's metadata header, which is not otherwise validated
+    let metadata_path = data_dir_path.join("metadata.1");
+    let mut metadata = fs::read(&metadata_path).unwrap();
+    metadata[7] = 0xFF;
+    fs::write(&metadata_path, metadata).unwrap();
+
+    assert!(matches!(
+        open(ManifestVerification::Refuse),
+        Err(DBError::ConsistencyError(_))
+    ));
+
+    // Warnings don't prevent using the database
+    let mut db = open(ManifestVerification::Warn).expect("Verification should only warn");
+    assert!(db.get(&Value::Int(0)).unwrap().is_some());
+    drop(db);
+
+    open(ManifestVerification::Disabled).expect("Verification should be skipped");
+}
+
+#[test]
+#[serial]
+fn test_disk_quota() {
+    let data_dir = tmp_dir();
+    let max_disk_bytes = 40_000;
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .max_disk_bytes(max_disk_bytes)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let inst = |id: i64| Inst {
+        id,
+        name: None,
+        data: vec![0; 1000],
+    };
+
+    // Superseded versions are compacted away when the quota is approached
+    for i in 0..200 {
+        db.upsert(inst(i % 5)).unwrap();
+        assert!(db.disk_usage().unwrap() <= max_disk_bytes);
+    }
+
+    // Live records fill the quota until writes are rejected
+    let mut id = 5;
+    let error = loop {
+        match db.upsert(inst(id)) {
+            Ok(_) => id += 1,
+            Err(e) => break e,
+        }
+        assert!(id < 100, "The quota was not enforced");
+    };
+    assert!(matches!(error, DBError::QuotaExceeded(_)));
+    assert!(db.get(&Value::Int(id)).unwrap().is_none());
+    assert!(matches!(
+        db.append_raw(&codec::encode_record(&inst(id).into_record())),
+        Err(DBError::QuotaExceeded(_))
+    ));
+
+    // Deleting records is still allowed
+    assert_eq!(
+        db.batch_delete_by(&Field::Id, &[Value::Int(0), Value::Int(1), Value::Int(2)])
+            .unwrap(),
+        vec![1, 1, 1]
+    );
+    drop(db);
+
+    // Raising the quota allows writes again
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .max_disk_bytes(2 * max_disk_bytes)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    db.upsert(inst(id)).unwrap();
+    assert!(db.get(&Value::Int(id)).unwrap().is_some());
+}
+
+static SCRUB_HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+#[test]
+#[serial]
+fn test_scrubber() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let open = |data_dir: &str| {
+        DB::<Inst>::configure()
+            .data_dir(data_dir)
+            .scrub_hook(|_| {
+                SCRUB_HOOK_CALLS.fetch_add(1, AtomicOrdering::SeqCst);
+            })
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let mut db = open(&data_dir);
+
+    // Nothing to scrub before a segment has been sealed
+    assert_eq!(db.scrub_next_segment().unwrap(), None);
+
+    for id in 0..10 {
+        db.upsert(Inst {
+            id,
+            name: None,
+            data: vec![1, 2, 3],
+        })
+        .unwrap();
+    }
+    db.compact(SegmentSelector::Active).unwrap();
+    db.upsert(Inst {
+        id: 10,
+        name: None,
+        data: vec![],
+    })
+    .unwrap();
+    db.compact(SegmentSelector::Active).unwrap();
+
+    // The sealed segments are scrubbed in turn, wrapping around
+    let scrubbed: Vec<(u16, u64)> = (0..3)
+        .map(|_| {
+            let report = db.scrub_next_segment().unwrap().unwrap();
+            assert!(report.problems.is_empty());
+            (report.segment_num, report.records_checked)
+        })
+        .collect();
+    assert_eq!(scrubbed, vec![(1, 10), (2, 1), (1, 10)]);
+
+    // Corrupt the flag of the first record of the first segment
+    let manifest = Manifest::read(data_dir_path).unwrap().unwrap();
+    let metadata = fs::read(data_dir_path.join("metadata.1")).unwrap();
+    let offset = u64::from_be_bytes(metadata[24..32].try_into().unwrap()) as usize;
+    let data_path = data_dir_path.join(manifest.segments[0].data_uuid.to_string());
+    let mut data = fs::read(&data_path).unwrap();
+    data[offset] = 0x7F;
+    fs::write(&data_path, data).unwrap();
+
+    let hook_calls = SCRUB_HOOK_CALLS.load(AtomicOrdering::SeqCst);
+    let report = db.scrub_next_segment().unwrap().unwrap();
+    assert_eq!(report.segment_num, 2);
+    assert!(report.problems.is_empty());
+
+    let cancellation = Cancellation::with_timeout(Duration::from_millis(300));
+    let scrubber_dir = data_dir.clone();
+    let scrubber = thread::spawn(move || open(&scrubber_dir).run_scrubber(&cancellation));
+    let problems = scrubber.join().unwrap().unwrap();
+
+    assert!(problems.iter().all(|problem| problem.segment_num == 1));
+    assert!(problems.iter().any(|problem| problem.row == Some(0)));
+    assert!(problems.iter().any(|problem| problem.row.is_none()));
+    assert!(SCRUB_HOOK_CALLS.load(AtomicOrdering::SeqCst) >= hook_calls + problems.len());
+}
+
+#[test]
+fn test_log_position() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let start = db.end_position().unwrap();
+    assert_eq!(start.segment_num(), 1);
+    assert_eq!(start.index(), 0);
+
+    for id in 0..3 {
+        db.upsert(Inst {
+            id,
+            name: None,
+            data: vec![],
+        })
+        .unwrap();
+    }
+    let before_rotation = db.end_position().unwrap();
+    assert_eq!(before_rotation.index(), 3);
+    assert!(before_rotation > start);
+
+    // Positions keep increasing across rotations
+    db.compact(SegmentSelector::Active).unwrap();
+    let after_rotation = db.end_position().unwrap();
+    assert_eq!(after_rotation.segment_num(), 2);
+    assert_eq!(after_rotation.index(), 0);
+    assert!(after_rotation > before_rotation);
+
+    // Serialized positions round trip and sort like the positions
+    assert_eq!(
+        LogPosition::from_bytes(before_rotation.to_bytes()),
+        before_rotation
+    );
+    assert!(before_rotation.to_bytes() < after_rotation.to_bytes());
+    assert_eq!(before_rotation.to_string(), "1:3");
+    assert_eq!("1:3".parse::<LogPosition>().unwrap(), before_rotation);
+    assert!("1".parse::<LogPosition>().is_err());
+    assert!("1:x".parse::<LogPosition>().is_err());
+}
+
+#[test]
+fn test_write_receipts() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let first = db
+        .upsert(Inst {
+            id: 0,
+            name: None,
+            data: vec![],
+        })
+        .unwrap();
+    assert_eq!(first.position.to_string(), "1:0");
+
+    let receipts = db
+        .batch_upsert(
+            (1..4)
+                .map(|id| Inst {
+                    id,
+                    name: Some(format!("name{}", id)),
+                    data: vec![id as u8; id as usize],
+                })
+                .collect(),
+        )
+        .unwrap();
+    assert_eq!(receipts.len(), 3);
+    for (i, receipt) in receipts.iter().enumerate() {
+        assert_eq!(receipt.position.segment_num(), 1);
+        assert_eq!(receipt.position.index(), i as u64 + 1);
+        assert!(receipt.meta.version > first.meta.version);
+        assert_eq!(receipt.meta.timestamp, receipts[0].meta.timestamp);
+    }
+    assert!(receipts[0].meta.timestamp >= first.meta.timestamp);
+    assert_eq!(db.end_position().unwrap().index(), 4);
+
+    // Each record of the batch is stored separately and carries the returned metadata
+    for (id, receipt) in (1..4).zip(&receipts) {
+        let (inst, meta) = db.get_with_meta(&Value::Int(id)).unwrap().unwrap();
+        assert_eq!(inst.name, Some(format!("name{}", id)));
+        assert_eq!(inst.data, vec![id as u8; id as usize]);
+        assert_eq!(meta, receipt.meta);
+    }
+}
+
+#[test]
+fn test_dump_indexes() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for (id, name) in [(2, "Bob"), (1, "Alice"), (3, "Bob")] {
+        db.upsert(Inst {
+            id,
+            name: Some(name.to_string()),
+            data: vec![],
+        })
+        .unwrap();
+    }
+
+    let mut buf = vec![];
+    db.dump_indexes(&mut buf).unwrap();
+    let dump = IndexDump::load(&buf[..]).unwrap();
+
+    assert_eq!(dump.indexes.len(), 2);
+    assert_eq!(dump.indexes[0].field, "Id");
+    assert!(dump.indexes[0].primary);
+    assert_eq!(
+        dump.indexes[0]
+            .entries
+            .iter()
+            .map(|(key, positions)| (key.clone(), positions[0].to_string()))
+            .collect::<Vec<_>>(),
+        vec![
+            (Value::Int(1), "1:1".to_string()),
+            (Value::Int(2), "1:0".to_string()),
+            (Value::Int(3), "1:2".to_string()),
+        ]
+    );
+
+    assert_eq!(dump.indexes[1].field, "Name");
+    assert!(!dump.indexes[1].primary);
+    let (key, positions) = &dump.indexes[1].entries[1];
+    assert_eq!(key, &Value::String("Bob".to_string()));
+    assert_eq!(positions.len(), 2);
+}
+
+#[test]
+fn test_composite_keys() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<InstComposite>::configure()
+        .data_dir(&data_dir)
+        .segment_size(4)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for (id, name) in [
+        (1, "Bob"),
+        (2, "Alice"),
+        (3, "Bob"),
+        (4, "Bob"),
+        (5, "Carol"),
+    ] {
+        db.upsert(InstComposite(Inst {
+            id,
+            name: Some(name.to_string()),
+            data: vec![],
+        }))
+        .unwrap();
+    }
+    // Renamed records must not be found by their old composite key
+    db.upsert(InstComposite(Inst {
+        id: 4,
+        name: Some("Dave".to_string()),
+        data: vec![],
+    }))
+    .unwrap();
+
+    let composite = [Field::Name, Field::Id];
+    let bob = Value::String("Bob".to_string());
+    let ids = |insts: Vec<InstComposite>| insts.iter().map(|inst| inst.0.id).collect::<Vec<_>>();
+
+    let result = db
+        .find_by_composite(&composite, std::slice::from_ref(&bob))
+        .unwrap();
+    assert_eq!(ids(result), vec![1, 3]);
+
+    let result = db
+        .find_by_composite(&composite, &[bob.clone(), Value::Int(3)])
+        .unwrap();
+    assert_eq!(ids(result), vec![3]);
+
+    let result = db
+        .range_by_composite(&composite, std::slice::from_ref(&bob), Value::Int(2)..)
+        .unwrap();
+    assert_eq!(ids(result), vec![3]);
+
+    let result = db
+        .range_by_composite(
+            &composite,
+            &[],
+            Value::String("Alice".to_string())..Value::String("Carol".to_string()),
+        )
+        .unwrap();
+    assert_eq!(ids(result), vec![2, 1, 3]);
+
+    let result = db.find_by_composite(&composite, &[]).unwrap();
+    assert_eq!(ids(result), vec![2, 1, 3, 5, 4]);
+
+    // Only declared composite keys can be queried, with correctly typed values
+    assert!(db
+        .find_by_composite(&[Field::Id, Field::Name], &[Value::Int(1)])
+        .is_err());
+    assert!(db.find_by_composite(&composite, &[Value::Int(1)]).is_err());
+    assert!(db
+        .find_by_composite(&composite, &[bob, Value::Int(1), Value::Int(1)])
+        .is_err());
+
+    let mut buf = vec![];
+    db.dump_indexes(&mut buf).unwrap();
+    let dump = IndexDump::load(&buf[..]).unwrap();
+    assert_eq!(dump.indexes[2].field, "[Name, Id]");
+    assert_eq!(
+        dump.indexes[2].entries[0].0,
+        Value::Record(vec![Value::String("Alice".to_string()), Value::Int(2)])
+    );
+}
+
+#[test]
+fn test_explain() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<InstComposite>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for (id, name) in [(1, "Bob"), (2, "Alice"), (3, "Bob")] {
+        db.upsert(InstComposite(Inst {
+            id,
+            name: Some(name.to_string()),
+            data: vec![],
+        }))
+        .unwrap();
+    }
+    db.compact(SegmentSelector::Active).unwrap();
+    db.upsert(InstComposite(Inst {
+        id: 4,
+        name: Some("Bob".to_string()),
+        data: vec![],
+    }))
+    .unwrap();
+    let segments = |plan: &QueryPlan| plan.segments.len();
+    let bob = Value::String("Bob".to_string());
+
+    let plan = db.explain(&Query::Get(Value::Int(2))).unwrap();
+    assert_eq!(plan.index, QueryIndex::Primary);
+    assert_eq!(plan.field, "Id");
+    assert_eq!(plan.estimated_log_keys, 1);
+    assert_eq!(segments(&plan), 1);
+    assert!(!plan.is_full_scan());
+
+    let plan = db.explain(&Query::Get(Value::Int(9))).unwrap();
+    assert_eq!(plan.estimated_log_keys, 0);
+    assert!(plan.segments.is_empty());
+
+    let plan = db
+        .explain(&Query::FindBy(Field::Name, bob.clone()))
+        .unwrap();
+    assert_eq!(plan.index, QueryIndex::Secondary);
+    assert_eq!(plan.field, "Name");
+    assert_eq!(plan.estimated_log_keys, 3);
+    assert_eq!(segments(&plan), 2);
+
+    let plan = db
+        .explain(&Query::RangeBy(
+            Field::Id,
+            Bound::Included(Value::Int(2)),
+            Bound::Unbounded,
+        ))
+        .unwrap();
+    assert_eq!(plan.index, QueryIndex::Primary);
+    assert_eq!(plan.estimated_log_keys, 3);
+
+    let plan = db
+        .explain(&Query::Composite(
+            vec![Field::Name, Field::Id],
+            vec![bob],
+            Bound::Excluded(Value::Int(1)),
+            Bound::Unbounded,
+        ))
+        .unwrap();
+    assert_eq!(plan.index, QueryIndex::Composite);
+    assert_eq!(plan.field, "[Name, Id]");
+    assert_eq!(plan.estimated_log_keys, 2);
+
+    // Queries that would fail cannot be explained either
+    assert!(matches!(
+        db.explain(&Query::FindBy(Field::Data, Value::Bytes(vec![]))),
+        Err(DBError::ValidationError(_))
+    ));
+
+    // Queries by non-indexed fields scan every row of every segment
+    let data_dir = tmp_dir();
+    let mut db = DB::<Counter>::configure()
+        .data_dir(&data_dir)
+        .non_indexed_queries(NonIndexedQueries::Scan)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for count in 0..3 {
+        db.upsert(Counter {
+            id: 0,
+            name: "counter".to_string(),
+            count,
+            log: vec![],
+        })
+        .unwrap();
+    }
+    let plan = db
+        .explain(&Query::FindBy(CounterField::Count, Value::Int(2)))
+        .unwrap();
+    assert!(plan.is_full_scan());
+    assert_eq!(plan.field, "Count");
+    assert_eq!(plan.estimated_log_keys, 3);
+    assert_eq!(segments(&plan), 1);
+}
+
+#[test]
+fn test_distinct() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for (id, name) in [
+        (0, Some("Bob")),
+        (1, Some("Alice")),
+        (2, Some("Bob")),
+        (3, None),
+    ] {
+        db.upsert(Inst {
+            id,
+            name: name.map(|name| name.to_string()),
+            data: vec![],
+        })
+        .unwrap();
+    }
+
+    assert_eq!(
+        db.distinct(&Field::Name).unwrap(),
+        vec![
+            Value::Null,
+            Value::String("Alice".to_string()),
+            Value::String("Bob".to_string()),
+        ]
+    );
+
+    // Values that are no longer used by any record disappear
+    db.upsert(Inst {
+        id: 1,
+        name: Some("Carol".to_string()),
+        data: vec![],
+    })
+    .unwrap();
+    db.delete(&Value::Int(3)).unwrap();
+    assert_eq!(
+        db.distinct(&Field::Name).unwrap(),
+        vec![
+            Value::String("Bob".to_string()),
+            Value::String("Carol".to_string()),
+        ]
+    );
+
+    assert_eq!(
+        db.distinct(&Field::Id).unwrap(),
+        vec![Value::Int(0), Value::Int(1), Value::Int(2)]
+    );
+    assert!(db.distinct(&Field::Data).is_err());
+}
+
+#[test]
+fn test_aggregate() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    assert_eq!(
+        db.aggregate(&Field::Id, Aggregate::Max).unwrap(),
+        Value::Null
+    );
+    assert_eq!(
+        db.aggregate(&Field::Id, Aggregate::Count).unwrap(),
+        Value::Int(0)
+    );
+
+    for (id, name) in [
+        (4, Some("Bob")),
+        (1, Some("Alice")),
+        (7, None),
+        (2, Some("Bob")),
+    ] {
+        db.upsert(Inst {
+            id,
+            name: name.map(|name| name.to_string()),
+            data: vec![],
+        })
+        .unwrap();
+    }
+    // Superseded values are not aggregated
+    db.upsert(Inst {
+        id: 1,
+        name: Some("Carol".to_string()),
+        data: vec![],
+    })
+    .unwrap();
+
+    // Primary key
+    assert_eq!(
+        db.aggregate(&Field::Id, Aggregate::Min).unwrap(),
+        Value::Int(1)
+    );
+    assert_eq!(
+        db.aggregate(&Field::Id, Aggregate::Max).unwrap(),
+        Value::Int(7)
+    );
+    assert_eq!(
+        db.aggregate(&Field::Id, Aggregate::Sum).unwrap(),
+        Value::Int(14)
+    );
+    assert_eq!(
+        db.aggregate(&Field::Id, Aggregate::Avg).unwrap(),
+        Value::Decimal(rust_decimal::Decimal::new(35, 1))
+    );
+    assert_eq!(
+        db.aggregate(&Field::Id, Aggregate::Count).unwrap(),
+        Value::Int(4)
+    );
+
+    // Secondary key, nulls are ignored
+    assert_eq!(
+        db.aggregate(&Field::Name, Aggregate::Min).unwrap(),
+        Value::String("Bob".to_string())
+    );
+    assert_eq!(
+        db.aggregate(&Field::Name, Aggregate::Max).unwrap(),
+        Value::String("Carol".to_string())
+    );
+    assert_eq!(
+        db.aggregate(&Field::Name, Aggregate::Count).unwrap(),
+        Value::Int(3)
+    );
+    assert!(db.aggregate(&Field::Name, Aggregate::Sum).is_err());
+
+    // Non-indexed field
+    assert_eq!(
+        db.aggregate(&Field::Data, Aggregate::Count).unwrap(),
+        Value::Int(4)
+    );
+    assert!(db.aggregate(&Field::Data, Aggregate::Max).is_err());
+}
+
+#[test]
+fn test_calibrate_durability() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let files_before = fs::read_dir(&data_dir).unwrap().count();
+
+    let report = db.calibrate_durability(20, 128).unwrap();
+    assert_eq!(report.record_size, 128);
+    assert_eq!(
+        report
+            .measurements
+            .iter()
+            .map(|m| m.write_durability.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            WriteDurability::Flush,
+            WriteDurability::FlushSync,
+            WriteDurability::FlushDataSync
+        ]
+    );
+    for measurement in &report.measurements {
+        assert_eq!(measurement.writes, 20);
+        assert!(measurement.p50_latency <= measurement.p99_latency);
+        assert!(measurement.p99_latency <= measurement.max_latency);
+        assert!(measurement.writes_per_second() > 0.0);
+    }
+
+    // The scratch files are removed and the database is untouched
+    assert_eq!(fs::read_dir(&data_dir).unwrap().count(), files_before);
+    assert_eq!(db.end_position().unwrap().to_string(), "1:0");
+    assert!(db.calibrate_durability(0, 128).is_err());
+}
+
+/// A source that holds its records in memory, as e.g. a source parsed from a CSV file would
+struct VecSource(Vec<(i64, &'static str)>);
+
+impl ForeignSource<Inst> for VecSource {
+    fn find_by(&mut self, field: &Field, value: &Value) -> DBResult<Vec<Inst>> {
+        Ok(self
+            .0
+            .iter()
+            .filter(|(id, name)| match field {
+                Field::Id => value == &Value::Int(*id),
+                Field::Name => value == &Value::String(name.to_string()),
+                Field::Data => false,
+            })
+            .map(|(id, name)| Inst {
+                id: *id,
+                name: Some(name.to_string()),
+                data: vec![],
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn test_foreign_sources() {
+    let old_data_dir = tmp_dir();
+    let mut old_db = DB::<Inst>::configure()
+        .data_dir(&old_data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for (id, name) in [(1, "Bob"), (2, "Bob"), (3, "Alice")] {
+        old_db
+            .upsert(Inst {
+                id,
+                name: Some(name.to_string()),
+                data: vec![1],
+            })
+            .unwrap();
+    }
+
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    // Record 2 has already been migrated to the new store
+    db.upsert(Inst {
+        id: 2,
+        name: Some("Bob".to_string()),
+        data: vec![2],
+    })
+    .unwrap();
+
+    db.mount("old", Box::new(old_db)).unwrap();
+    db.mount("csv", Box::new(VecSource(vec![(1, "Bob"), (4, "Bob")])))
+        .unwrap();
+    assert!(db.mount("old", Box::new(VecSource(vec![]))).is_err());
+
+    let bob = Value::String("Bob".to_string());
+    let result = db.find_by(&Field::Name, &bob).unwrap();
+    assert_eq!(
+        result
+            .iter()
+            .map(|inst| (inst.id, inst.data.clone()))
+            .collect::<Vec<_>>(),
+        vec![(2, vec![2]), (1, vec![1]), (4, vec![])]
+    );
+
+    // A record deleted from the database is not brought back by the sources
+    db.upsert(Inst {
+        id: 1,
+        name: Some("Bob".to_string()),
+        data: vec![2],
+    })
+    .unwrap();
+    db.delete(&Value::Int(1)).unwrap();
+    let result = db.find_by(&Field::Name, &bob).unwrap();
+    assert_eq!(
+        result.iter().map(|inst| inst.id).collect::<Vec<_>>(),
+        vec![2, 4]
+    );
+
+    // Deletes are still applied after the log is read again
+    drop(db);
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    db.mount("csv", Box::new(VecSource(vec![(1, "Bob"), (4, "Bob")])))
+        .unwrap();
+    assert_eq!(db.find_by(&Field::Id, &Value::Int(1)).unwrap().len(), 0);
+    assert_eq!(db.find_by(&Field::Id, &Value::Int(4)).unwrap().len(), 1);
+
+    let mut csv = db.unmount("csv").unwrap();
+    assert_eq!(csv.find_by(&Field::Name, &bob).unwrap().len(), 2);
+    assert!(db.unmount("csv").is_none());
+
+    // Without the sources, only the database itself is queried
+    assert_eq!(db.find_by(&Field::Name, &bob).unwrap().len(), 1);
+}
+
+#[test]
+fn test_incremental_backup() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let write_batch = |db: &mut DB<Inst>, ids: std::ops::Range<i64>| {
+        for id in ids {
+            db.upsert(Inst {
+                id,
+                name: Some(format!("name {}", id)),
+                data: vec![],
+            })
+            .unwrap();
+            db.do_maintenance_tasks().unwrap();
+        }
+    };
+
+    let backup_dirs = [tmp_dir(), tmp_dir(), tmp_dir()];
+    let metadata_files = |dir: &str| {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("metadata."))
+            .collect();
+        names.sort();
+        names
+    };
+
+    write_batch(&mut db, 0..30);
+    let full = db.backup_incremental(&backup_dirs[0], None).unwrap();
+    assert!(full.segments.len() > 1);
+    assert_eq!(
+        metadata_files(&backup_dirs[0]).len(),
+        full.segments.len() + 1
+    );
+
+    // The second backup only contains the segments sealed after the first one and the active segment
+    write_batch(&mut db, 30..60);
+    let incremental = db.backup_incremental(&backup_dirs[1], Some(&full)).unwrap();
+    let new_segments = incremental.segments.len() - full.segments.len();
+    assert!(new_segments > 0);
+    assert_eq!(metadata_files(&backup_dirs[1]).len(), new_segments + 1);
+
+    // Nothing has changed, so only the active segment is copied
+    let unchanged = db
+        .backup_incremental(&backup_dirs[2], Some(&incremental))
+        .unwrap();
+    assert_eq!(metadata_files(&backup_dirs[2]).len(), 1);
+    assert_eq!(
+        Manifest::read(Path::new(&backup_dirs[2])).unwrap(),
+        Some(unchanged)
+    );
+    assert!(db.backup_incremental(&backup_dirs[2], None).is_err());
+
+    let restored_dir = tmp_dir();
+    let chain: Vec<&str> = backup_dirs.iter().map(String::as_str).collect();
+    DB::<Inst>::restore(&chain, &restored_dir).unwrap();
+    let mut restored = DB::<Inst>::configure()
+        .data_dir(&restored_dir)
+        .verify_manifest(ManifestVerification::Refuse)
+        .initialize()
+        .expect("Failed to open restored DB");
+    for id in 0..60 {
+        let inst = restored.get(&Value::Int(id)).unwrap().unwrap();
+        assert_eq!(inst.name, Some(format!("name {}", id)));
+    }
+
+    // A broken chain cannot be restored
+    assert!(DB::<Inst>::restore(&chain[1..], &tmp_dir()).is_err());
+    assert!(DB::<Inst>::restore(&chain, &restored_dir).is_err());
+}
+
+#[test]
+fn test_backup_chain_with_merges() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let write_batch = |db: &mut DB<Inst>, ids: std::ops::Range<i64>, name: &str| {
+        for id in ids {
+            db.upsert(Inst {
+                id,
+                name: Some(name.to_string()),
+                data: vec![],
+            })
+            .unwrap();
+            db.do_maintenance_tasks().unwrap();
+        }
+    };
+
+    let backups_dir = tmp_dir();
+    let backup_dirs: Vec<String> = (0..3)
+        .map(|i| format!("{}/backup-{}", backups_dir, i))
+        .collect();
+
+    write_batch(&mut db, 0..30, "first");
+    let full = db.backup_to(&backup_dirs[0]).unwrap();
+
+    // Merging changes the merged segment, so it is backed up again, unlike the untouched segments
+    let merged: Vec<u16> = full.segments[..3]
+        .iter()
+        .map(|segment| segment.segment_num)
+        .collect();
+    db.merge_segments(&merged).unwrap();
+    write_batch(&mut db, 20..40, "second");
+    let incremental = db.backup_incremental(&backup_dirs[1], Some(&full)).unwrap();
+    let changed = incremental
+        .segments
+        .iter()
+        .filter(|segment| !full.segments.contains(segment))
+        .count();
+    let metadata_files = fs::read_dir(&backup_dirs[1])
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with("metadata.")
+        })
+        .count();
+    assert_eq!(metadata_files, changed + 1);
+    assert!(incremental
+        .segments
+        .iter()
+        .all(|segment| !merged[..2].contains(&segment.segment_num)));
+
+    write_batch(&mut db, 40..45, "third");
+    db.backup_incremental(&backup_dirs[2], Some(&incremental))
+        .unwrap();
+
+    // Each prefix of the chain restores the state of its newest backup
+    let expected_name = |id: i64, backups: usize| match (backups, id) {
+        (_, 0..20) => Some("first"),
+        (1, 20..30) => Some("first"),
+        (1, _) => None,
+        (_, 20..40) => Some("second"),
+        (2, _) => None,
+        (_, 40..45) => Some("third"),
+        _ => None,
+    };
+    for backups in 1..=3 {
+        let restored_dir = tmp_dir();
+        let chain: Vec<&str> = backup_dirs[..backups].iter().map(String::as_str).collect();
+        DB::<Inst>::restore(&chain, &restored_dir).unwrap();
+        let mut restored = DB::<Inst>::configure()
+            .data_dir(&restored_dir)
+            .verify_manifest(ManifestVerification::Refuse)
+            .initialize()
+            .expect("Failed to open restored DB");
+        for id in 0..45 {
+            let name = restored
+                .get(&Value::Int(id))
+                .unwrap()
+                .and_then(|inst| inst.name);
+            assert_eq!(
+                name.as_deref(),
+                expected_name(id, backups),
+                "id {} with {} backups",
+                id,
+                backups
+            );
+        }
+    }
+}
+
+#[test]
+fn test_restore_verification() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 0..25 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name {}", id)),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    db.upsert(Inst {
+        id: 25,
+        name: None,
+        data: vec![],
+    })
+    .unwrap();
+
+    let backup_dir = tmp_dir();
+    let manifest = db.backup_incremental(&backup_dir, None).unwrap();
+    let restore_parent = tmp_dir();
+    let restored_dir = format!("{}/restored", restore_parent);
+    let leftovers = || fs::read_dir(&restore_parent).unwrap().count();
+
+    // The active segment is not in the manifest, so only the record checksums catch damage to it
+    let active_metadata = fs::read(format!(
+        "{}/metadata.{}",
+        backup_dir, manifest.active_segment_num
+    ))
+    .unwrap();
+    let uuid = uuid::Uuid::from_slice(&active_metadata[8..24]).unwrap();
+    let last_row = &active_metadata[active_metadata.len() - 24..];
+    let last_offset = u64::from_be_bytes(last_row[0..8].try_into().unwrap()) as usize;
+    let active_data = format!("{}/{}", backup_dir, uuid);
+    let original = fs::read(&active_data).unwrap();
+    let mut damaged = original.clone();
+    damaged[last_offset] ^= 0xff;
+    fs::write(&active_data, &damaged).unwrap();
+    assert!(matches!(
+        DB::<Inst>::restore(&[&backup_dir], &restored_dir),
+        Err(DBError::ConsistencyError(_))
+    ));
+    assert!(!fs::exists(&restored_dir).unwrap());
+    assert_eq!(leftovers(), 0);
+    fs::write(&active_data, &original).unwrap();
+
+    // A manifest that lists the active segment as sealed is rejected before anything is copied
+    let mut invalid = manifest.clone();
+    let mut extra = invalid.segments.last().unwrap().clone();
+    extra.segment_num = invalid.active_segment_num;
+    invalid.segments.push(extra);
+    invalid.write(Path::new(&backup_dir)).unwrap();
+    assert!(matches!(
+        DB::<Inst>::restore(&[&backup_dir], &restored_dir),
+        Err(DBError::ConsistencyError(_))
+    ));
+    assert_eq!(leftovers(), 0);
+    manifest.write(Path::new(&backup_dir)).unwrap();
+
+    DB::<Inst>::restore(&[&backup_dir], &restored_dir).unwrap();
+    assert_eq!(leftovers(), 1);
+    let mut restored = DB::<Inst>::configure()
+        .data_dir(&restored_dir)
+        .verify_manifest(ManifestVerification::Refuse)
+        .initialize()
+        .expect("Failed to open restored DB");
+    for id in 0..25 {
+        assert!(restored.get(&Value::Int(id)).unwrap().is_some());
+    }
+}
+
+#[test]
+fn test_open_backup() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..30 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name {}", id % 3)),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+
+    let backup_dir = tmp_dir();
+    let manifest = db.backup_incremental(&backup_dir, None).unwrap();
+    let backup_files = fs::read_dir(&backup_dir).unwrap().count();
+
+    let mut backup = DB::<Inst>::configure()
+        .data_dir(&backup_dir)
+        .open_backup()
+        .expect("Failed to open backup");
+    assert_eq!(backup.get(&Value::Int(29)).unwrap().unwrap().id, 29);
+    assert_eq!(
+        backup
+            .find_by(&Field::Name, &Value::String("name 1".to_string()))
+            .unwrap()
+            .len(),
+        10
+    );
+    assert_eq!(
+        backup.end_position().unwrap().segment_num(),
+        manifest.active_segment_num
+    );
+
+    let result = backup.upsert(Inst {
+        id: 100,
+        name: None,
+        data: vec![],
+    });
+    assert!(matches!(result, Err(DBError::ReadOnly(_))));
+    assert!(matches!(
+        backup.do_maintenance_tasks(),
+        Err(DBError::ReadOnly(_))
+    ));
+    backup.refresh_indexes().unwrap();
+
+    // Opening the backup does not create lock files or other scaffolding
+    assert_eq!(fs::read_dir(&backup_dir).unwrap().count(), backup_files);
+
+    // An incremental backup lacks the unchanged segments
+    let incremental_dir = tmp_dir();
+    db.backup_incremental(&incremental_dir, Some(&manifest))
+        .unwrap();
+    let result = DB::<Inst>::configure()
+        .data_dir(&incremental_dir)
+        .open_backup();
+    assert!(matches!(result, Err(DBError::ConsistencyError(_))));
+}
+
+#[test]
+#[serial]
+fn test_open_at() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let upsert = |db: &mut DB<Inst>, id: i64, name: &str| {
+        db.upsert(Inst {
+            id,
+            name: Some(name.to_string()),
+            data: vec![],
+        })
+        .unwrap()
+    };
+    for id in 0..10 {
+        upsert(&mut db, id, "first");
+        db.do_maintenance_tasks().unwrap();
+    }
+    let receipt = upsert(&mut db, 10, "first");
+    let before_updates = db.end_position().unwrap();
+
+    for id in 0..5 {
+        upsert(&mut db, id, "second");
+    }
+    db.delete(&Value::Int(9)).unwrap();
+    assert!(receipt.position.segment_num() > 1);
+
+    let open_at = |position: LogPosition| {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .open_at(position)
+            .expect("Failed to open DB at position")
+    };
+    let mut past = open_at(before_updates);
+    assert_eq!(
+        past.get(&Value::Int(2)).unwrap().unwrap().name.unwrap(),
+        "first"
+    );
+    assert_eq!(past.get(&Value::Int(9)).unwrap().unwrap().id, 9);
+    assert_eq!(
+        past.find_by(&Field::Name, &Value::String("first".to_string()))
+            .unwrap()
+            .len(),
+        11
+    );
+    assert_eq!(past.end_position().unwrap(), before_updates);
+
+    // The position of a write is excluded, so the state is as it was right before it
+    let mut before_receipt = open_at(receipt.position);
+    assert!(before_receipt.get(&Value::Int(10)).unwrap().is_none());
+    assert!(before_receipt.get(&Value::Int(9)).unwrap().is_some());
+
+    let result = past.upsert(Inst {
+        id: 100,
+        name: None,
+        data: vec![],
+    });
+    assert!(matches!(result, Err(DBError::ReadOnly(_))));
+    assert!(matches!(
+        past.do_maintenance_tasks(),
+        Err(DBError::ReadOnly(_))
+    ));
+
+    // Other handles keep writing while the past view is open, without it seeing the writes
+    upsert(&mut db, 11, "third");
+    past.refresh_indexes().unwrap();
+    assert!(past.get(&Value::Int(11)).unwrap().is_none());
+
+    // Compaction drops the history that the past view is based on
+    db.compact(SegmentSelector::All).unwrap();
+    assert!(matches!(
+        past.get(&Value::Int(2)),
+        Err(DBError::ConsistencyError(_))
+    ));
+
+    let result = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .open_at(LogPosition::from_bytes([0xff, 0xff, 0, 0, 0, 0, 0, 0]));
+    assert!(matches!(result, Err(DBError::ValidationError(_))));
+}
+
+#[test]
+#[serial]
+fn test_fsck() {
+    use std::io::Write;
+
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 0..20 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name {}", id)),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    let active_segment_num = db.end_position().unwrap().segment_num();
+    drop(db);
+
+    let report = DB::<Inst>::fsck(&data_dir, FsckMode::Check).unwrap();
+    assert!(report.problems.is_empty());
+    assert!(report.segments_checked > 1);
+    assert_eq!(report.records_checked, 20);
+
+    // Simulate an append torn by a crash, a rotation that crashed before creating the metadata
+    // file and a lost active symlink
+    let active_metadata_path = format!("{}/metadata.{}", data_dir, active_segment_num);
+    let active_metadata = fs::read(&active_metadata_path).unwrap();
+    let uuid = uuid::Uuid::from_slice(&active_metadata[8..24]).unwrap();
+    let append = |path: &str, bytes: &[u8]| {
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    };
+    append(&active_metadata_path, &[1; 10]);
+    append(&format!("{}/{}", data_dir, uuid), &[2; 7]);
+    let orphan_path = format!("{}/{}", data_dir, uuid::Uuid::new_v4());
+    fs::write(&orphan_path, b"orphan").unwrap();
+    fs::remove_file(format!("{}/active", data_dir)).unwrap();
+
+    let kinds = |report: &FsckReport| {
+        let mut kinds: Vec<FsckProblemKind> =
+            report.problems.iter().map(|problem| problem.kind).collect();
+        kinds.sort_by_key(|kind| format!("{:?}", kind));
+        kinds
+    };
+    let expected = vec![
+        FsckProblemKind::BrokenActiveSymlink,
+        FsckProblemKind::DanglingDataBytes,
+        FsckProblemKind::MetadataSize,
+        FsckProblemKind::OrphanedDataFile,
+    ];
+    let report = DB::<Inst>::fsck(&data_dir, FsckMode::Check).unwrap();
+    assert_eq!(kinds(&report), expected);
+    assert!(!report.is_clean());
+    assert!(fs::exists(&orphan_path).unwrap());
+    assert_eq!(
+        fs::read(&active_metadata_path).unwrap().len(),
+        active_metadata.len() + 10
+    );
+
+    let report = DB::<Inst>::fsck(&data_dir, FsckMode::Repair).unwrap();
+    assert_eq!(kinds(&report), expected);
+    assert!(report.is_clean());
+    assert!(!fs::exists(&orphan_path).unwrap());
+    assert_eq!(fs::read(&active_metadata_path).unwrap(), active_metadata);
+    assert!(DB::<Inst>::fsck(&data_dir, FsckMode::Check)
+        .unwrap()
+        .problems
+        .is_empty());
+
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .verify_manifest(ManifestVerification::Refuse)
+        .initialize()
+        .expect("Failed to open repaired DB");
+    assert_eq!(db.end_position().unwrap().segment_num(), active_segment_num);
+    assert_eq!(db.keys().unwrap().len(), 20);
+    drop(db);
+
+    // Damaged records cannot be repaired, only reported
+    let first_metadata = fs::read(format!("{}/metadata.1", data_dir)).unwrap();
+    let first_data = format!(
+        "{}/{}",
+        data_dir,
+        uuid::Uuid::from_slice(&first_metadata[8..24]).unwrap()
+    );
+    let mut data = fs::read(&first_data).unwrap();
+    data[0] ^= 0xff;
+    fs::write(&first_data, &data).unwrap();
+    let report = DB::<Inst>::fsck(&data_dir, FsckMode::Repair).unwrap();
+    assert_eq!(kinds(&report), vec![FsckProblemKind::ChecksumFailure]);
+    assert_eq!(report.problems[0].segment_num, Some(1));
+    assert!(!report.is_clean());
+}
+
+#[test]
+#[serial]
+fn test_torn_append_truncated_on_initialize() {
+    use std::io::Write;
+
+    let data_dir = tmp_dir();
+    let open = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .segment_size(200)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let mut db = open();
+    for id in 0..12 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name {}", id)),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    drop(db);
+
+    // A crash in the middle of an append leaves part of a record and part of its row behind
+    let active_metadata_path = format!("{}/active", data_dir);
+    let active_metadata = fs::read(&active_metadata_path).unwrap();
+    let data_path = format!(
+        "{}/{}",
+        data_dir,
+        uuid::Uuid::from_slice(&active_metadata[8..24]).unwrap()
+    );
+    let data = fs::read(&data_path).unwrap();
+    let append = |path: &str, bytes: &[u8]| {
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    };
+    append(&data_path, &[2; 15]);
+    append(&active_metadata_path, &[1; 11]);
+
+    let mut db = open();
+    assert_eq!(fs::read(&active_metadata_path).unwrap(), active_metadata);
+    assert_eq!(fs::read(&data_path).unwrap(), data);
+    assert_eq!(db.keys().unwrap().len(), 12);
+    db.upsert(Inst {
+        id: 12,
+        name: None,
+        data: vec![],
+    })
+    .unwrap();
+    assert_eq!(db.get(&Value::Int(12)).unwrap().unwrap().id, 12);
+    drop(db);
+
+    assert!(DB::<Inst>::fsck(&data_dir, FsckMode::Check)
+        .unwrap()
+        .problems
+        .is_empty());
+}
+
+#[test]
+fn test_backup_to() {
+    use std::os::unix::fs::MetadataExt;
+
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let upsert = |db: &mut DB<Inst>, id: i64, name: &str| {
+        db.upsert(Inst {
+            id,
+            name: Some(name.to_string()),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    };
+
+    for id in 0..30 {
+        upsert(&mut db, id, "before");
+    }
+
+    let backup_dir = format!("{}/snapshot", tmp_dir());
+    let manifest = db.backup_to(&backup_dir).unwrap();
+    assert!(manifest.segments.len() > 1);
+    assert_eq!(
+        Manifest::read(Path::new(&backup_dir)).unwrap(),
+        Some(manifest.clone())
+    );
+
+    // The metadata files of sealed segments are shared with the data directory
+    let first_sealed = format!("metadata.{}", manifest.segments[0].segment_num);
+    let inode = |dir: &str| {
+        fs::metadata(Path::new(dir).join(&first_sealed))
+            .unwrap()
+            .ino()
+    };
+    assert_eq!(inode(&data_dir), inode(&backup_dir));
+
+    // Writes and compactions after the backup do not change it
+    for id in 0..40 {
+        upsert(&mut db, id, "after");
+    }
+    db.compact(SegmentSelector::All).unwrap();
+
+    let mut backup = DB::<Inst>::configure()
+        .data_dir(&backup_dir)
+        .open_backup()
+        .expect("Failed to open backup");
+    for id in 0..30 {
+        let inst = backup.get(&Value::Int(id)).unwrap().unwrap();
+        assert_eq!(inst.name, Some("before".to_string()));
+    }
+    assert!(backup.get(&Value::Int(35)).unwrap().is_none());
+
+    // The backup must go to a new or empty directory
+    assert!(matches!(
+        db.backup_to(&backup_dir),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+enum CounterField {
+    Id,
+    Name,
+    Count,
+    Log,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Counter {
+    pub id: i64,
+    pub name: String,
+    pub count: i64,
+    pub log: Vec<u8>,
+}
+
+impl Recordable for Counter {
+    type Field = CounterField;
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (CounterField::Id, Type::int()),
+            (CounterField::Name, Type::string()),
+            (CounterField::Count, Type::int()),
+            (CounterField::Log, Type::bytes()),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        CounterField::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![CounterField::Name]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![
+            Value::Int(self.id),
+            Value::String(self.name),
+            Value::Int(self.count),
+            Value::Bytes(self.log),
+        ]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::Int(id), Value::String(name), Value::Int(count), Value::Bytes(log)] => {
+                Counter {
+                    id: *id,
+                    name: name.clone(),
+                    count: *count,
+                    log: log.clone(),
+                }
+            }
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+fn add_to_counter(old: Option<Counter>, delta: Counter) -> Counter {
+    match old {
+        Some(old) => Counter {
+            count: old.count + delta.count,
+            log: [old.log, delta.log].concat(),
+            ..old
+        },
+        None => delta,
+    }
+}
+
+#[test]
+fn test_merge_operator() {
+    let data_dir = tmp_dir();
+    let open = || {
+        DB::<Counter>::configure()
+            .data_dir(&data_dir)
+            .segment_size(200)
+            .merge_operator(add_to_counter)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let mut db = open();
+    let mut other = open();
+
+    let delta = |id, count, entry| Counter {
+        id,
+        name: format!("counter {}", id),
+        count,
+        log: vec![entry],
+    };
+
+    // A delta without a previous version is merged into nothing
+    db.merge(delta(1, 5, 1)).unwrap();
+    assert_eq!(db.get(&Value::Int(1)).unwrap(), Some(delta(1, 5, 1)));
+
+    db.upsert(delta(2, 100, 0)).unwrap();
+    let mut last_receipt = None;
+    for i in 1..=20u8 {
+        db.merge(delta(1, 1, i + 1)).unwrap();
+        last_receipt = Some(db.merge(delta(2, i as i64, i)).unwrap());
+        db.do_maintenance_tasks().unwrap();
+    }
+
+    let expected_1 = Counter {
+        id: 1,
+        name: "counter 1".to_string(),
+        count: 25,
+        log: (1..=21).collect(),
+    };
+    let expected_2 = Counter {
+        id: 2,
+        name: "counter 2".to_string(),
+        count: 310,
+        log: (0..=20).collect(),
+    };
+
+    // Deltas are folded by every read path, also across segments and by other handles
+    for db in [&mut db, &mut other] {
+        assert_eq!(db.get(&Value::Int(1)).unwrap().as_ref(), Some(&expected_1));
+        let found = db
+            .find_by(&CounterField::Name, &Value::String("counter 2".to_string()))
+            .unwrap();
+        assert_eq!(found, vec![expected_2.clone()]);
+        let scanned = db
+            .scan_filter(|values| values[2] == Value::Int(25))
+            .unwrap();
+        assert_eq!(scanned, vec![expected_1.clone()]);
+        let mut arena = RecordArena::new();
+        db.scan_filter_in(&mut arena, |record| {
+            record.get(2) == Some(ValueRef::Int(25))
+        })
+        .unwrap();
+        let scanned: Vec<Counter> = arena
+            .records()
+            .map(|record| Counter::from_record(record.to_values()))
+            .collect();
+        assert_eq!(scanned, vec![expected_1.clone()]);
+    }
+
+    // The folded record has the version of the newest delta
+    let (_, meta) = db.get_with_meta(&Value::Int(2)).unwrap().unwrap();
+    assert_eq!(meta, last_receipt.unwrap().meta);
+
+    // Compaction folds the deltas into full records
+    db.compact(SegmentSelector::All).unwrap();
+    assert_eq!(db.get(&Value::Int(1)).unwrap(), Some(expected_1.clone()));
+    assert_eq!(other.get(&Value::Int(2)).unwrap(), Some(expected_2.clone()));
+    db.merge(delta(2, 1, 21)).unwrap();
+    assert_eq!(other.get(&Value::Int(2)).unwrap().unwrap().count, 311);
+
+    // After a delete, the next delta starts from nothing
+    db.delete(&Value::Int(1)).unwrap();
+    db.merge(delta(1, 7, 0)).unwrap();
+    assert_eq!(other.get(&Value::Int(1)).unwrap(), Some(delta(1, 7, 0)));
+
+    // Deltas cannot be written or read without a merge operator
+    let mut no_operator = DB::<Counter>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert!(no_operator.merge(delta(3, 1, 0)).is_err());
+    assert!(matches!(
+        no_operator.get(&Value::Int(1)),
+        Err(DBError::ConsistencyError(_))
+    ));
+}
+
+#[test]
+fn test_merge_operator_must_keep_keys() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Counter>::configure()
+        .data_dir(&data_dir)
+        .merge_operator(|_, delta| Counter {
+            name: "renamed".to_string(),
+            ..delta
+        })
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    db.merge(Counter {
+        id: 1,
+        name: "counter".to_string(),
+        count: 1,
+        log: vec![],
+    })
+    .unwrap();
+    assert!(matches!(
+        db.get(&Value::Int(1)),
+        Err(DBError::ConsistencyError(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_increment() {
+    let data_dir = tmp_dir();
+    let open = |data_dir: &str| {
+        DB::<Counter>::configure()
+            .data_dir(data_dir)
+            .segment_size(500)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+
+    let mut db = open(&data_dir);
+    db.upsert(Counter {
+        id: 1,
+        name: "counter".to_string(),
+        count: 0,
+        log: vec![],
+    })
+    .unwrap();
+
+    // Increments from several processes are not lost
+    let mut threads = vec![];
+    for _ in 0..10 {
+        let data_dir = data_dir.clone();
+        threads.push(thread::spawn(move || {
+            let mut db = open(&data_dir);
+            for _ in 0..10 {
+                db.increment(&Value::Int(1), &CounterField::Count, 2)
+                    .unwrap()
+                    .expect("Record not found");
+            }
+        }));
+    }
+    for thread in threads {
+        thread.join().expect("Failed to join thread");
+    }
+
+    assert_eq!(db.get(&Value::Int(1)).unwrap().unwrap().count, 200);
+    assert_eq!(
+        db.increment(&Value::Int(1), &CounterField::Count, -50)
+            .unwrap(),
+        Some(150)
+    );
+
+    assert_eq!(
+        db.increment(&Value::Int(2), &CounterField::Count, 1)
+            .unwrap(),
+        None
+    );
+    assert!(db
+        .increment(&Value::Int(1), &CounterField::Name, 1)
+        .is_err());
+    assert!(db.increment(&Value::Int(1), &CounterField::Id, 1).is_err());
+    assert!(db
+        .increment(&Value::Int(1), &CounterField::Count, i64::MAX)
+        .is_err());
+}
+
+#[test]
+#[serial]
+fn test_size_report() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Counter>::configure()
+        .data_dir(&data_dir)
+        .segment_size(10_000)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let counter = |id, log_len| Counter {
+        id,
+        name: "counter".to_string(),
+        count: 0,
+        log: vec![0; log_len],
+    };
+
+    assert_eq!(db.size_report(3).unwrap().records, 0);
+
+    for id in 0..20 {
+        db.upsert(counter(id, 10)).unwrap();
+    }
+    db.upsert(counter(7, 5000)).unwrap();
+    db.upsert(counter(3, 1000)).unwrap();
+
+    let report = db.size_report(2).unwrap();
+    assert_eq!(report.records, 20);
+    let largest: Vec<&Value> = report.largest.iter().map(|(key, _)| key).collect();
+    assert_eq!(largest, vec![&Value::Int(7), &Value::Int(3)]);
+    assert!(report.largest[0].1 > 5000);
+
+    // The histogram covers every record once
+    let bucketed: u64 = report.histogram.iter().map(|bucket| bucket.records).sum();
+    let bucketed_bytes: u64 = report.histogram.iter().map(|bucket| bucket.bytes).sum();
+    assert_eq!(bucketed, 20);
+    assert_eq!(bucketed_bytes, report.total_bytes);
+    assert_eq!(report.histogram.last().unwrap().records, 1);
+
+    // Superseded versions are not counted
+    db.upsert(counter(7, 10)).unwrap();
+    let report = db.size_report(1).unwrap();
+    assert_eq!(report.largest[0].0, Value::Int(3));
+    assert_eq!(report.histogram[0].records, 19);
+    assert_eq!(
+        report.total_bytes,
+        report.histogram[0].bytes + report.largest[0].1
+    );
+}
+
+#[test]
+#[serial]
+fn test_next_sequence() {
+    let data_dir = tmp_dir();
+    let open = |data_dir: &str| {
+        DB::<InstSingleId>::configure()
+            .data_dir(data_dir)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+
+    let mut db = open(&data_dir);
+    assert_eq!(db.next_sequence("orders").unwrap(), 1);
+    assert_eq!(db.next_sequence("orders").unwrap(), 2);
+    assert_eq!(db.next_sequence("batches").unwrap(), 1);
+    assert!(db.next_sequence("").is_err());
+    assert!(db.next_sequence("two words").is_err());
+
+    // Values handed out to several processes are unique
+    let mut threads = vec![];
+    for _ in 0..10 {
+        let data_dir = data_dir.clone();
+        threads.push(thread::spawn(move || {
+            let mut db = open(&data_dir);
+            (0..10)
+                .map(|_| db.next_sequence("orders").unwrap())
+                .collect::<Vec<u64>>()
+        }));
+    }
+    let mut values: Vec<u64> = threads
+        .into_iter()
+        .flat_map(|thread| thread.join().expect("Failed to join thread"))
+        .collect();
+    values.sort();
+    assert_eq!(values, (3..103).collect::<Vec<u64>>());
+
+    // The ledger survives reopening and is included in backups
+    assert_eq!(open(&data_dir).next_sequence("orders").unwrap(), 103);
+    let backup_dir = tmp_dir();
+    db.backup_incremental(&backup_dir, None).unwrap();
+    let restored_dir = tmp_dir();
+    DB::<InstSingleId>::restore(&[&backup_dir], &restored_dir).unwrap();
+    assert_eq!(open(&restored_dir).next_sequence("orders").unwrap(), 104);
+}
+
+#[test]
+#[serial]
+fn test_range_by_stream() {
+    let data_dir = tmp_dir();
+    let open = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .segment_size(400)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let mut db = open();
+    let mut other = open();
+
+    for id in 0..50 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name {}", id % 5)),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    // Move some records to another name, leaving superseded entries in the secondary index
+    for id in 0..10 {
+        db.upsert(Inst {
+            id,
+            name: Some("name 9".to_string()),
+            data: vec![],
+        })
+        .unwrap();
+    }
+
+    let ids: Vec<i64> = db
+        .range_by_stream(&Field::Id, &Value::Int(5)..&Value::Int(45))
+        .unwrap()
+        .batch_size(7)
+        .map(|inst| inst.unwrap().id)
+        .collect();
+    assert_eq!(ids, (5..45).collect::<Vec<i64>>());
+
+    // Secondary index ranges are in key order and skip superseded versions
+    let names: Vec<(String, i64)> = db
+        .range_by_stream(
+            &Field::Name,
+            &Value::String("name 1".to_string())..=&Value::String("name 9".to_string()),
+        )
+        .unwrap()
+        .batch_size(1)
+        .map(|inst| {
+            let inst = inst.unwrap();
+            (inst.name.unwrap(), inst.id)
+        })
+        .collect();
+    let mut expected: Vec<(String, i64)> = (10..50)
+        .filter(|id| id % 5 != 0)
+        .map(|id| (format!("name {}", id % 5), id))
+        .chain((0..10).map(|id| ("name 9".to_string(), id)))
+        .collect();
+    expected.sort();
+    assert_eq!(names, expected);
+
+    // Writes made while streaming are seen by the batches read after them
+    let mut stream = db
+        .range_by_stream(&Field::Id, &Value::Int(40)..)
+        .unwrap()
+        .batch_size(5);
+    assert_eq!(stream.next().unwrap().unwrap().id, 40);
+    other
+        .upsert(Inst {
+            id: 100,
+            name: None,
+            data: vec![],
+        })
+        .unwrap();
+    let rest: Vec<i64> = stream.map(|inst| inst.unwrap().id).collect();
+    assert_eq!(rest, vec![41, 42, 43, 44, 45, 46, 47, 48, 49, 100]);
+
+    assert!(db.range_by_stream(&Field::Data, ..).is_err());
+}
+
+#[test]
+#[serial]
+fn test_cancellation() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(400)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..30 {
+        db.upsert(Inst {
+            id,
+            name: None,
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+
+    let token = Cancellation::new();
+    let all = db
+        .with_cancellation(&token, |db| db.scan_filter(|_| true))
+        .unwrap();
+    assert_eq!(all.len(), 30);
+
+    // A scan is interrupted at the next segment after the token is cancelled
+    let mut scanned = 0;
+    let result = db.with_cancellation(&token, |db| {
+        db.scan_filter(|_| {
+            scanned += 1;
+            token.cancel();
+            true
+        })
+    });
+    assert!(matches!(result, Err(DBError::Cancelled)));
+    assert!(scanned < 30);
+
+    let result = db.with_cancellation(&token, |db| db.range_by(&Field::Id, ..));
+    assert!(matches!(result, Err(DBError::Cancelled)));
+
+    let expired = Cancellation::with_timeout(Duration::ZERO);
+    let result = db.with_cancellation(&expired, |db| db.get(&Value::Int(1)));
+    assert!(matches!(result, Err(DBError::Cancelled)));
+
+    // The token only applies within `with_cancellation`
+    assert_eq!(db.range_by(&Field::Id, ..).unwrap().len(), 30);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+#[serial]
+fn test_export_sqlite() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(400)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..20 {
+        db.upsert(Inst {
+            id,
+            name: if id % 2 == 0 {
+                Some(format!("name \"{}\"", id))
+            } else {
+                None
+            },
+            data: vec![id as u8],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    db.delete(&Value::Int(3)).unwrap();
+
+    let export_path = Path::new(&tmp_dir()).join("export.sqlite");
+    let export_path = export_path.to_str().unwrap();
+    assert_eq!(db.export_sqlite(export_path).unwrap(), 19);
+    assert!(db.export_sqlite(export_path).is_err());
+
+    let connection = rusqlite::Connection::open(export_path).unwrap();
+    let count: i64 = connection
+        .query_row(
+            "SELECT COUNT(*) FROM records WHERE Name IS NULL",
+            (),
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(count, 9);
+    let (name, data): (String, Vec<u8>) = connection
+        .query_row("SELECT Name, Data FROM records WHERE Id = 4", (), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .unwrap();
+    assert_eq!(name, "name \"4\"");
+    assert_eq!(data, vec![4]);
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+#[serial]
+fn test_export_parquet() {
+    use arrow_array::{Array, BinaryArray, Int64Array, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(400)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 0..2500 {
+        db.upsert(Inst {
+            id,
+            name: if id % 2 == 0 {
+                Some(format!("name {}", id))
+            } else {
+                None
+            },
+            data: vec![id as u8],
+        })
+        .unwrap();
+    }
+    db.delete(&Value::Int(3)).unwrap();
+
+    let export_path = Path::new(&tmp_dir()).join("export.parquet");
+    let export_path = export_path.to_str().unwrap();
+    assert_eq!(db.export_parquet(export_path).unwrap(), 2499);
+    assert!(db.export_parquet(export_path).is_err());
+
+    let file = fs::File::open(export_path).unwrap();
+    let builder = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+    let schema = builder.schema().clone();
+    let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+    assert_eq!(names, vec!["Id", "Name", "Data"]);
+    assert!(!schema.field(0).is_nullable());
+    assert!(schema.field(1).is_nullable());
+
+    let batches: Vec<_> = builder
+        .build()
+        .unwrap()
+        .map(|batch| batch.unwrap())
+        .collect();
+    assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2499);
+    let first = &batches[0];
+    let ids = first
+        .column(0)
+        .as_any()
+        .downcast_ref::<Int64Array>()
+        .unwrap();
+    let names = first
+        .column(1)
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    let data = first
+        .column(2)
+        .as_any()
+        .downcast_ref::<BinaryArray>()
+        .unwrap();
+    assert_eq!(ids.value(3), 4);
+    assert_eq!(names.value(3), "name 4");
+    assert!(names.is_null(1));
+    assert_eq!(data.value(3), &[4]);
+}
+
+#[test]
+#[serial]
+fn test_export() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(400)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..1500 {
+        db.upsert(Inst {
+            id,
+            name: if id % 2 == 0 {
+                Some(format!("name \"{}\", {}", id, id))
+            } else {
+                None
+            },
+            data: vec![id as u8; 2],
+        })
+        .unwrap();
+    }
+    db.do_maintenance_tasks().unwrap();
+    db.delete(&Value::Int(3)).unwrap();
+
+    let mut jsonl = vec![];
+    assert_eq!(db.export(&mut jsonl, Format::JsonLines).unwrap(), 1499);
+    let jsonl = String::from_utf8(jsonl).unwrap();
+    let lines: Vec<&str> = jsonl.lines().collect();
+    assert_eq!(lines.len(), 1499);
+    assert_eq!(lines[0], r#"{"Id":0,"Name":"name \"0\", 0","Data":"AAA="}"#);
+    assert_eq!(lines[1], r#"{"Id":1,"Name":null,"Data":"AQE="}"#);
+    assert!(lines[2].starts_with(r#"{"Id":2,"#));
+
+    let mut csv = vec![];
+    assert_eq!(db.export(&mut csv, Format::Csv).unwrap(), 1499);
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 1500);
+    assert_eq!(lines[0], "Id,Name,Data");
+    assert_eq!(lines[1], r#"0,"name ""0"", 0",AAA="#);
+    assert_eq!(lines[2], "1,,AQE=");
+    assert_eq!(lines[1500 - 1].split(',').next(), Some("1499"));
+}
+
+#[test]
+#[serial]
+fn test_import() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(400)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 0..1200 {
+        db.upsert(Inst {
+            id,
+            name: match id % 3 {
+                0 => Some(format!("line\nbreak, \"{}\"", id)),
+                1 => Some(String::new()),
+                _ => None,
+            },
+            data: vec![id as u8; id as usize % 4],
+        })
+        .unwrap();
+    }
+
+    // What is exported imports back as it was
+    for format in [Format::JsonLines, Format::Csv] {
+        let mut exported = vec![];
+        db.export(&mut exported, format).unwrap();
+        let mut imported = DB::<Inst>::configure()
+            .data_dir(&tmp_dir())
+            .initialize()
+            .expect("Failed to initialize DB instance");
+        let report = imported.import(exported.as_slice(), format).unwrap();
+        assert_eq!(report.imported, 1200);
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        for id in 0..1200 {
+            let expected = db.get(&Value::Int(id)).unwrap().unwrap();
+            let actual = imported.get(&Value::Int(id)).unwrap().unwrap();
+            assert_eq!((actual.name, actual.data), (expected.name, expected.data));
+        }
+    }
+
+    // Lines that cannot be imported are reported and skipped
+    let mut checked = DB::<Inst>::configure()
+        .data_dir(&tmp_dir())
+        .check(
+            Field::Data,
+            |data| matches!(data, Value::Bytes(b) if b.len() <= 4),
+        )
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    let jsonl = concat!(
+        "{\"Id\":1,\"Name\":\"one\",\"Data\":\"AQ==\"}\n",
+        "{\"Id\":\"2\",\"Data\":\"\"}\n",
+        "\n",
+        "{\"Id\":3,\"Data\":\"AAAAAAAA\"}\n",
+        "{\"Id\":4,\"Data\":\"\",\"Extra\":1}\n",
+        "{\"Id\":5,\n",
+        "{\"Name\":\"no id\",\"Data\":\"\"}\n",
+        "{\"Id\":7,\"Data\":\"\"}",
+    );
+    let report = checked.import(jsonl.as_bytes(), Format::JsonLines).unwrap();
+    assert_eq!(report.imported, 2);
+    let lines: Vec<u64> = report.problems.iter().map(|problem| problem.line).collect();
+    assert_eq!(lines, vec![2, 4, 5, 6, 7]);
+    assert!(report.problems[0].description.contains("Id"));
+    assert_eq!(
+        checked.get(&Value::Int(1)).unwrap().unwrap().name,
+        Some("one".to_owned())
+    );
+    assert!(checked.get(&Value::Int(7)).unwrap().is_some());
+    assert!(checked.get(&Value::Int(3)).unwrap().is_none());
+
+    let csv = "Data,Id\nAQ==,10\n\"\nx\",11\n,12,13\n";
+    let report = checked.import(csv.as_bytes(), Format::Csv).unwrap();
+    assert_eq!(report.imported, 1);
+    let lines: Vec<u64> = report.problems.iter().map(|problem| problem.line).collect();
+    assert_eq!(lines, vec![3, 5]);
+    assert!(matches!(
+        checked.import("Id,Unknown\n".as_bytes(), Format::Csv),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_non_indexed_queries_scan() {
+    let data_dir = tmp_dir();
+    let open = |non_indexed_queries| {
+        DB::<Counter>::configure()
+            .data_dir(&data_dir)
+            .segment_size(400)
+            .non_indexed_queries(non_indexed_queries)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let mut db = open(NonIndexedQueries::Scan);
+
+    for id in 0..30 {
+        db.upsert(Counter {
+            id,
+            name: format!("counter {}", id),
+            count: id % 3,
+            log: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    // Only the newest version of a record is matched
+    db.upsert(Counter {
+        id: 0,
+        name: "counter 0".to_string(),
+        count: 5,
+        log: vec![],
+    })
+    .unwrap();
+
+    let mut ids: Vec<i64> = db
+        .find_by(&CounterField::Count, &Value::Int(0))
+        .unwrap()
+        .into_iter()
+        .map(|counter| counter.id)
+        .collect();
+    ids.sort();
+    assert_eq!(ids, (3..30).step_by(3).collect::<Vec<i64>>());
+
+    let found = db
+        .find_by_any(&CounterField::Count, &[Value::Int(5), Value::Int(7)])
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, 0);
+    assert!(db
+        .find_by(&CounterField::Count, &Value::String("0".to_string()))
+        .is_err());
+
+    let deleted = db.delete_by(&CounterField::Count, &Value::Int(2)).unwrap();
+    assert_eq!(deleted.len(), 10);
+    assert!(db
+        .find_by(&CounterField::Count, &Value::Int(2))
+        .unwrap()
+        .is_empty());
+
+    // Refused by default
+    let mut refusing = open(NonIndexedQueries::Refuse);
+    assert!(refusing
+        .find_by(&CounterField::Count, &Value::Int(0))
+        .is_err());
+}
+
+#[test]
+#[serial]
+fn test_write_transforms() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .write_transform(Field::Name, WriteTransform::NormalizeWhitespace)
+        .write_transform(Field::Name, WriteTransform::Lowercase)
+        .write_transform(Field::Data, WriteTransform::Truncate(4))
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    db.upsert(Inst {
+        id: 1,
+        name: Some("  Foo   BAR ".to_string()),
+        data: vec![1, 2, 3, 4, 5, 6],
+    })
+    .unwrap();
+    db.batch_upsert(vec![Inst {
+        id: 2,
+        name: None,
+        data: vec![1],
+    }])
+    .unwrap();
+
+    let inst = db.get(&Value::Int(1)).unwrap().unwrap();
+    assert_eq!(inst.name, Some("foo bar".to_string()));
+    assert_eq!(inst.data, vec![1, 2, 3, 4]);
+    assert_eq!(db.get(&Value::Int(2)).unwrap().unwrap().name, None);
+
+    // The indexes contain the transformed values
+    let found = db
+        .find_by(&Field::Name, &Value::String("foo bar".to_string()))
+        .unwrap();
+    assert_eq!(found.len(), 1);
+
+    // Transforms are applied in order, and hashing pseudonymizes the value
+    let mut hashing = DB::<Inst>::configure()
+        .data_dir(&tmp_dir())
+        .write_transform(Field::Name, WriteTransform::Lowercase)
+        .write_transform(Field::Name, WriteTransform::Sha256)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    hashing
+        .upsert(Inst {
+            id: 1,
+            name: Some("ABC".to_string()),
+            data: vec![],
+        })
+        .unwrap();
+    assert_eq!(
+        hashing.get(&Value::Int(1)).unwrap().unwrap().name,
+        Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string())
+    );
+
+    // A transform that produces an invalid value is rejected
+    let mut invalid = DB::<Inst>::configure()
+        .data_dir(&tmp_dir())
+        .write_transform(Field::Name, WriteTransform::Custom(|_| Value::Int(0)))
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert!(invalid
+        .upsert(Inst {
+            id: 1,
+            name: Some("foo".to_string()),
+            data: vec![],
+        })
+        .is_err());
+    assert!(invalid.get(&Value::Int(1)).unwrap().is_none());
+}
+
+#[test]
+#[serial]
+fn test_who() {
+    let data_dir = tmp_dir();
+    assert!(DB::<Inst>::who(&data_dir).unwrap().is_empty());
+
+    let open = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let db = open();
+    let other = open();
+
+    let instances = DB::<Inst>::who(&data_dir).unwrap();
+    assert_eq!(instances.len(), 2);
+    assert!(instances
+        .iter()
+        .all(|instance| instance.pid == std::process::id()));
+    assert!(instances[0].opened <= instances[1].opened);
+    assert!(instances[0].config.contains("read_consistency=Strong"));
+
+    // Closed handles are unregistered
+    drop(other);
+    assert_eq!(DB::<Inst>::who(&data_dir).unwrap().len(), 1);
+
+    // The registration of a process that crashed is not locked and gets cleaned up
+    let stale_path = Path::new(&data_dir).join("instances").join("1-stale");
+    fs::write(&stale_path, "pid 1\nopened 0\nconfig crashed\n").unwrap();
+    assert_eq!(DB::<Inst>::who(&data_dir).unwrap().len(), 1);
+    assert!(!stale_path.exists());
+
+    drop(db);
+    assert!(DB::<Inst>::who(&data_dir).unwrap().is_empty());
+}
+
+#[test]
+#[serial]
+fn test_quiesce() {
+    let data_dir = tmp_dir();
+    let open = |data_dir: &str| {
+        DB::<Inst>::configure()
+            .data_dir(data_dir)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let inst = |id: i64| Inst {
+        id,
+        name: None,
+        data: vec![],
+    };
+    let mut db = open(&data_dir);
+    let mut other = open(&data_dir);
+    assert!(!DB::<Inst>::is_quiesced(&data_dir).unwrap());
+
+    db.quiesce().unwrap();
+    assert!(DB::<Inst>::is_quiesced(&data_dir).unwrap());
+    assert!(matches!(other.quiesce(), Err(DBError::LockRequestError(_))));
+
+    // The quiescing handle can still write, and other handles can still read
+    db.upsert(inst(1)).unwrap();
+    assert!(other.get(&Value::Int(1)).unwrap().is_some());
+
+    // Writes of other handles wait until the quiesce is lifted
+    let writer_dir = data_dir.clone();
+    let writer = thread::spawn(move || {
+        let mut writer_db = open(&writer_dir);
+        writer_db.upsert(inst(2)).unwrap();
+    });
+    thread::sleep(Duration::from_millis(200));
+    assert!(!writer.is_finished());
+    assert!(db.get(&Value::Int(2)).unwrap().is_none());
+
+    db.resume();
+    writer.join().unwrap();
+    assert!(db.get(&Value::Int(2)).unwrap().is_some());
+    assert!(!DB::<Inst>::is_quiesced(&data_dir).unwrap());
+
+    // Waiting writes can be given up
+    other.quiesce().unwrap();
+    let cancellation = Cancellation::new();
+    cancellation.cancel();
+    assert!(matches!(
+        db.with_cancellation(&cancellation, |db| db.upsert(inst(3))),
+        Err(DBError::Cancelled)
+    ));
+
+    // The quiesce is lifted when the handle is dropped
+    drop(other);
+    db.upsert(inst(3)).unwrap();
+
+    // The quiesce of a process that crashed is not locked and gets cleaned up
+    let stale_path = Path::new(&data_dir).join("quiesce");
+    fs::write(&stale_path, "").unwrap();
+    assert!(!DB::<Inst>::is_quiesced(&data_dir).unwrap());
+    assert!(!stale_path.exists());
+    db.upsert(inst(4)).unwrap();
+}
+
+#[test]
+#[serial]
+fn test_top_k() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(400)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..20 {
+        db.upsert(Inst {
+            id,
+            name: (id != 5).then(|| format!("name {:02}", id)),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+
+    let ids = |insts: Vec<Inst>| insts.into_iter().map(|inst| inst.id).collect::<Vec<i64>>();
+
+    assert_eq!(
+        ids(db.top_k(&Field::Id, 3, Direction::Desc).unwrap()),
+        vec![19, 18, 17]
+    );
+    assert_eq!(
+        ids(db.top_k(&Field::Name, 2, Direction::Asc).unwrap()),
+        vec![0, 1]
+    );
+
+    // Superseded index entries and null values are skipped
+    db.upsert(Inst {
+        id: 19,
+        name: Some("a".to_string()),
+        data: vec![],
+    })
+    .unwrap();
+    assert_eq!(
+        ids(db.top_k(&Field::Name, 3, Direction::Desc).unwrap()),
+        vec![18, 17, 16]
+    );
+    assert_eq!(
+        ids(db.top_k(&Field::Name, 7, Direction::Asc).unwrap()),
+        vec![19, 0, 1, 2, 3, 4, 6]
+    );
+    assert_eq!(
+        db.top_k(&Field::Name, 100, Direction::Asc).unwrap().len(),
+        19
+    );
+    assert!(db.top_k(&Field::Id, 0, Direction::Asc).unwrap().is_empty());
+    assert!(db.top_k(&Field::Data, 1, Direction::Asc).is_err());
+}
+
+#[test]
+fn test_first_last_by() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    assert!(db.first_by(&Field::Id).unwrap().is_none());
+    for (id, name) in [(3, Some("b")), (1, None), (7, Some("c")), (5, Some("a"))] {
+        db.upsert(Inst {
+            id,
+            name: name.map(str::to_owned),
+            data: vec![],
+        })
+        .unwrap();
+    }
+    let id = |inst: Option<Inst>| inst.map(|inst| inst.id);
+
+    assert_eq!(id(db.first_by(&Field::Id).unwrap()), Some(1));
+    assert_eq!(id(db.last_by(&Field::Id).unwrap()), Some(7));
+    // Nulls are skipped
+    assert_eq!(id(db.first_by(&Field::Name).unwrap()), Some(5));
+    assert_eq!(id(db.last_by(&Field::Name).unwrap()), Some(7));
+
+    // Deleted and renamed records are not returned
+    db.delete(&Value::Int(7)).unwrap();
+    db.upsert(Inst {
+        id: 5,
+        name: Some("d".to_string()),
+        data: vec![],
+    })
+    .unwrap();
+    assert_eq!(id(db.last_by(&Field::Id).unwrap()), Some(5));
+    assert_eq!(id(db.first_by(&Field::Name).unwrap()), Some(3));
+    assert_eq!(id(db.last_by(&Field::Name).unwrap()), Some(5));
+
+    assert!(matches!(
+        db.first_by(&Field::Data),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_history() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Counter>::configure()
+        .data_dir(&data_dir)
+        .segment_size(150)
+        .merge_operator(add_to_counter)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let counter = |id, count| Counter {
+        id,
+        name: format!("counter {}", id),
+        count,
+        log: vec![],
+    };
+
+    db.upsert(counter(1, 1)).unwrap();
+    db.upsert(counter(2, 1)).unwrap();
+    db.upsert(counter(1, 2)).unwrap();
+    db.do_maintenance_tasks().unwrap();
+    db.merge(counter(1, 10)).unwrap();
+    db.delete(&Value::Int(1)).unwrap();
+    db.do_maintenance_tasks().unwrap();
+    let last_receipt = db.upsert(counter(1, 3)).unwrap();
+
+    let history = db.history(&Value::Int(1)).unwrap();
+    let kinds: Vec<VersionKind> = history.iter().map(|version| version.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            VersionKind::Write,
+            VersionKind::Write,
+            VersionKind::Merge,
+            VersionKind::Delete,
+            VersionKind::Write
+        ]
+    );
+    let counts: Vec<i64> = history.iter().map(|version| version.record.count).collect();
+    assert_eq!(counts, vec![1, 2, 10, 12, 3]);
+    assert!(history
+        .windows(2)
+        .all(|pair| pair[0].position < pair[1].position
+            && pair[0].meta.version < pair[1].meta.version));
+    assert_eq!(history.last().unwrap().position, last_receipt.position);
+    assert_eq!(history.last().unwrap().meta, last_receipt.meta);
+
+    assert_eq!(db.history(&Value::Int(2)).unwrap().len(), 1);
+    assert!(db.history(&Value::Int(3)).unwrap().is_empty());
+    assert!(db.history(&Value::String("1".to_string())).is_err());
+
+    // Compaction drops the superseded versions
+    db.compact(SegmentSelector::All).unwrap();
+    let history = db.history(&Value::Int(1)).unwrap();
+    assert_eq!(history.last().unwrap().record, counter(1, 3));
+    assert!(history.len() < 5);
+}
+
+#[test]
+#[serial]
+fn test_modified_since() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(400)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let inst = |id| Inst {
+        id,
+        name: None,
+        data: vec![],
+    };
+
+    let receipts = db.batch_upsert((0..10).map(inst).collect()).unwrap();
+    let watermark = receipts[0].meta.timestamp;
+    assert_eq!(db.modified_since(watermark).unwrap().len(), 0);
+    assert_eq!(db.modified_since(SystemTime::UNIX_EPOCH).unwrap().len(), 10);
+
+    thread::sleep(Duration::from_millis(2));
+    for id in [3, 7, 10] {
+        db.upsert(inst(id)).unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    db.delete(&Value::Int(5)).unwrap();
+
+    let modified = db.modified_since(watermark).unwrap();
+    let mut ids: Vec<i64> = modified.iter().map(|(inst, _)| inst.id).collect();
+    ids.sort();
+    assert_eq!(ids, vec![3, 7, 10]);
+
+    // The newest timestamp is the next watermark
+    let watermark = modified
+        .iter()
+        .map(|(_, meta)| meta.timestamp)
+        .max()
+        .unwrap();
+    assert!(db.modified_since(watermark).unwrap().is_empty());
+}
+
+#[test]
+#[serial]
+fn test_arena_reads() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(400)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..20 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name {}", id % 3)),
+            data: vec![id as u8 % 2],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    db.upsert(Inst {
+        id: 3,
+        name: Some("name 1".to_string()),
+        data: vec![1],
+    })
+    .unwrap();
+    db.delete(&Value::Int(6)).unwrap();
+
+    let names: Vec<Value> = ["name 2", "name 0", "nobody"]
+        .iter()
+        .map(|name| Value::String(name.to_string()))
+        .collect();
+    let mut expected: Vec<(usize, i64)> = db
+        .batch_find_by(&Field::Name, &names)
+        .unwrap()
+        .into_iter()
+        .map(|(tag, inst)| (tag, inst.id))
+        .collect();
+    expected.sort();
+
+    // The arena is reused across queries
+    let mut arena = RecordArena::new();
+    for _ in 0..2 {
+        db.batch_find_by_in(&mut arena, &Field::Name, &names)
+            .unwrap();
+        let mut received: Vec<(usize, i64)> = arena
+            .records()
+            .map(|record| (record.tag(), Inst::from_record(record.to_values()).id))
+            .collect();
+        received.sort();
+        assert_eq!(received, expected);
+    }
+
+    let mut expected: Vec<i64> = db
+        .scan_filter(|values| values[2] == Value::Bytes(vec![0]))
+        .unwrap()
+        .iter()
+        .map(|inst| inst.id)
+        .collect();
+    expected.sort();
+
+    db.scan_filter_in(&mut arena, |record| {
+        record.get(2) == Some(ValueRef::Bytes(&[0]))
+    })
+    .unwrap();
+    let mut received: Vec<i64> = arena
+        .records()
+        .map(|record| Inst::from_record(record.to_values()).id)
+        .collect();
+    received.sort();
+    assert_eq!(received, expected);
+    assert_eq!(expected.len(), 9);
+
+    let first = arena.get(0).unwrap();
+    assert_eq!(first.get(0), Some(ValueRef::Int(received[0])));
+    assert_eq!(
+        first.meta(),
+        db.get_with_meta(&Value::Int(received[0]))
+            .unwrap()
+            .unwrap()
+            .1
+    );
+}
+
+#[test]
+#[serial]
+fn test_soft_delete() {
+    let data_dir = tmp_dir();
+    let open = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .segment_size(400)
+            .delete_mode(DeleteMode::Soft)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let mut db = open();
+
+    for id in 0..6 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name {}", id % 2)),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+
+    let ids = |insts: Vec<Inst>| {
+        let mut ids: Vec<i64> = insts.iter().map(|inst| inst.id).collect();
+        ids.sort();
+        ids
+    };
+    let name_1 = Value::String("name 1".to_string());
+
+    // Soft-deleted records are hidden from queries
+    assert_eq!(db.delete(&Value::Int(1)).unwrap().unwrap().id, 1);
+    assert!(db.get(&Value::Int(1)).unwrap().is_none());
+    assert_eq!(ids(db.find_by(&Field::Name, &name_1).unwrap()), vec![3, 5]);
+    assert_eq!(ids(db.scan_filter(|_| true).unwrap()), vec![0, 2, 3, 4, 5]);
+
+    // Unless they are included
+    let (inst, meta) = db
+        .include_deleted(|db| db.get_with_meta(&Value::Int(1)))
+        .unwrap()
+        .unwrap();
+    assert_eq!(inst.id, 1);
+    assert!(meta.deleted);
+    let found = db
+        .include_deleted(|db| db.find_by(&Field::Name, &name_1))
+        .unwrap();
+    assert_eq!(ids(found), vec![1, 3, 5]);
+    let ranged = db
+        .include_deleted(|db| db.range_by(&Field::Id, &Value::Int(1)..&Value::Int(3)))
+        .unwrap();
+    assert_eq!(
+        ranged.iter().map(|inst| inst.id).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    let scanned = db.include_deleted(|db| db.scan_filter(|_| true)).unwrap();
+    assert_eq!(ids(scanned), vec![0, 1, 2, 3, 4, 5]);
+
+    // Deleted records survive compaction and are seen by other handles
+    db.delete(&Value::Int(2)).unwrap();
+    db.compact(SegmentSelector::All).unwrap();
+    let mut other = open();
+    assert!(other.get(&Value::Int(2)).unwrap().is_none());
+    assert!(other
+        .include_deleted(|db| db.get(&Value::Int(2)))
+        .unwrap()
+        .is_some());
+
+    // Undelete restores the record as a new version
+    assert_eq!(other.undelete(&Value::Int(1)).unwrap().unwrap().id, 1);
+    assert!(other.undelete(&Value::Int(1)).unwrap().is_none());
+    let (_, meta) = db.get_with_meta(&Value::Int(1)).unwrap().unwrap();
+    assert!(!meta.deleted);
+    assert_eq!(
+        ids(db.find_by(&Field::Name, &name_1).unwrap()),
+        vec![1, 3, 5]
+    );
+
+    // Purge deletes both soft-deleted and live records for good
+    assert_eq!(db.purge(&Value::Int(2)).unwrap().unwrap().id, 2);
+    assert_eq!(db.purge(&Value::Int(3)).unwrap().unwrap().id, 3);
+    assert!(db.purge(&Value::Int(3)).unwrap().is_none());
+    assert!(db.undelete(&Value::Int(2)).unwrap().is_none());
+    let scanned = db.include_deleted(|db| db.scan_filter(|_| true)).unwrap();
+    assert_eq!(ids(scanned), vec![0, 1, 4, 5]);
+
+    // The first version was dropped by the compaction
+    let kinds: Vec<VersionKind> = db
+        .history(&Value::Int(1))
+        .unwrap()
+        .iter()
+        .map(|version| version.kind)
+        .collect();
+    assert_eq!(kinds, vec![VersionKind::SoftDelete, VersionKind::Write]);
+}
+
+#[test]
+#[serial]
+fn test_append_preencoded() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let values = vec![
+        Value::Int(1),
+        Value::String("device".to_string()),
+        Value::Bytes(vec![1, 2, 3]),
+    ];
+    let receipt = db
+        .append_preencoded(&codec::encode_values(&values))
+        .unwrap();
+    let (inst, meta) = db.get_with_meta(&Value::Int(1)).unwrap().unwrap();
+    assert_eq!(inst.into_record(), values);
+    assert_eq!(meta, receipt.meta);
+
+    // Invalid encodings and values that do not match the schema are rejected
+    let encoded = codec::encode_values(&values);
+    assert!(matches!(
+        db.append_preencoded(&encoded[..encoded.len() - 1]),
+        Err(DBError::ValidationError(_))
+    ));
+    assert!(matches!(
+        db.append_preencoded(&codec::encode_values(&values[..2])),
+        Err(DBError::ValidationError(_))
+    ));
+    assert!(matches!(
+        db.append_preencoded(&codec::encode_values(&[
+            Value::String("1".to_string()),
+            Value::Null,
+            Value::Bytes(vec![]),
+        ])),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_append_raw() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let values = |name: &str| {
+        vec![
+            Value::Int(1),
+            Value::String(name.to_string()),
+            Value::Bytes(vec![1, 2, 3]),
+        ]
+    };
+    let first = db
+        .append_raw(&codec::encode_record(&values("raw")))
+        .unwrap();
+    let (inst, meta) = db.get_with_meta(&Value::Int(1)).unwrap().unwrap();
+    assert_eq!(inst.into_record(), values("raw"));
+    assert_eq!(meta, first.meta);
+
+    // Raw records are indexed and versioned like upserted ones
+    let second = db
+        .append_raw(&codec::encode_record(&values("renamed")))
+        .unwrap();
+    assert!(second.meta.version > first.meta.version);
+    let mut other_handle = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    let found = other_handle
+        .find_by(&Field::Name, &Value::String("renamed".to_string()))
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(
+        other_handle
+            .get_with_meta(&Value::Int(1))
+            .unwrap()
+            .unwrap()
+            .1,
+        second.meta
+    );
+
+    // Invalid records are rejected without writing anything
+    let encoded = codec::encode_record(&values("raw"));
+    let mut tombstone = encoded.clone();
+    tombstone[0] = codec::B_TOMBSTONE;
+    let mut delta = encoded.clone();
+    delta[0] = codec::B_MERGE;
+    let invalid = [
+        encoded[..encoded.len() - 1].to_vec(),
+        encoded[..codec::RECORD_HEADER_SIZE - 1].to_vec(),
+        codec::encode_record(&values("raw")[..2]),
+        codec::encode_record(&[Value::Null, Value::Null, Value::Bytes(vec![])]),
+        tombstone,
+        delta,
+    ];
+    for bytes in invalid {
+        assert!(matches!(
+            db.append_raw(&bytes),
+            Err(DBError::ValidationError(_))
+        ));
+    }
+    assert_eq!(
+        db.get_with_meta(&Value::Int(1)).unwrap().unwrap().1,
+        second.meta
+    );
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+enum SessionField {
+    Token,
+    User,
+    ExpiresAt,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Session {
+    pub token: String,
+    pub user: String,
+    pub expires_at: Option<i64>,
+}
+
+impl Recordable for Session {
+    type Field = SessionField;
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (SessionField::Token, Type::string()),
+            (SessionField::User, Type::string()),
+            (SessionField::ExpiresAt, Type::int().nullable()),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        SessionField::Token
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![SessionField::User]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![
+            Value::String(self.token),
+            Value::String(self.user),
+            self.expires_at.map_or(Value::Null, Value::Int),
+        ]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::String(token), Value::String(user), expires_at] => Session {
+                token: token.clone(),
+                user: user.clone(),
+                expires_at: match expires_at {
+                    Value::Int(expires_at) => Some(*expires_at),
+                    _ => None,
+                },
+            },
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_expiry() {
+    let data_dir = tmp_dir();
+    let open = || {
+        DB::<Session>::configure()
+            .data_dir(&data_dir)
+            .segment_size(400)
+            .expiry_field(SessionField::ExpiresAt)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let mut db = open();
+
+    let session = |token: &str| Session {
+        token: token.to_string(),
+        user: "alice".to_string(),
+        expires_at: None,
+    };
+    let token = |token: &str| Value::String(token.to_string());
+    let tokens = |sessions: Vec<Session>| {
+        let mut tokens: Vec<String> = sessions.into_iter().map(|s| s.token).collect();
+        tokens.sort();
+        tokens
+    };
+
+    // An older version that must stay shadowed once the newer one has expired
+    db.upsert(Session {
+        user: "bob".to_string(),
+        ..session("a")
+    })
+    .unwrap();
+    db.compact(SegmentSelector::Active).unwrap();
+
+    db.upsert_with_ttl(session("a"), Duration::from_millis(50))
+        .unwrap();
+    db.upsert_with_ttl(session("b"), Duration::from_secs(3600))
+        .unwrap();
+    db.upsert(session("c")).unwrap();
+    assert_eq!(
+        tokens(
+            db.find_by(&SessionField::User, &Value::String("alice".to_string()))
+                .unwrap()
+        ),
+        vec!["a", "b", "c"]
+    );
+
+    thread::sleep(Duration::from_millis(60));
+
+    // Expired records are filtered out by reads
+    assert!(db.get(&token("a")).unwrap().is_none());
+    assert!(db.get(&token("b")).unwrap().is_some());
+    assert_eq!(
+        tokens(
+            db.find_by(&SessionField::User, &Value::String("alice".to_string()))
+                .unwrap()
+        ),
+        vec!["b", "c"]
+    );
+    assert_eq!(tokens(db.scan_filter(|_| true).unwrap()), vec!["b", "c"]);
+
+    // Compaction replaces expired records with tombstones
+    db.compact(SegmentSelector::All).unwrap();
+    let history = db.history(&token("a")).unwrap();
+    assert_eq!(history.last().unwrap().kind, VersionKind::Delete);
+    assert!(open().get(&token("a")).unwrap().is_none());
+    assert_eq!(
+        db.aggregate(&SessionField::Token, Aggregate::Count)
+            .unwrap(),
+        Value::Int(2)
+    );
+
+    // Rewriting an expired record makes it live again
+    db.upsert(session("a")).unwrap();
+    assert!(open().get(&token("a")).unwrap().is_some());
+
+    assert!(DB::<Session>::configure()
+        .data_dir(&data_dir)
+        .expiry_field(SessionField::User)
+        .initialize()
+        .is_err());
+    let mut no_expiry = DB::<Inst>::configure()
+        .data_dir(&tmp_dir())
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert!(no_expiry
+        .upsert_with_ttl(
+            Inst {
+                id: 1,
+                name: None,
+                data: vec![]
+            },
+            Duration::from_secs(1)
+        )
+        .is_err());
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+#[serial]
+fn test_expirations() {
+    use tokio_stream::StreamExt;
+
+    let data_dir = tmp_dir();
+    let mut db = DB::<Session>::configure()
+        .data_dir(&data_dir)
+        .expiry_field(SessionField::ExpiresAt)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    let session = |token: &str| Session {
+        token: token.to_string(),
+        user: "alice".to_string(),
+        expires_at: None,
+    };
+    let token = |token: &str| Value::String(token.to_string());
+
+    // Records that expired before the stream was created are not yielded
+    db.upsert_with_ttl(session("expired"), Duration::from_millis(1))
+        .unwrap();
+    thread::sleep(Duration::from_millis(5));
+    db.upsert_with_ttl(session("a"), Duration::from_millis(300))
+        .unwrap();
+
+    let mut expirations = db.expirations().unwrap();
+    db.upsert_with_ttl(session("b"), Duration::from_millis(100))
+        .unwrap();
+    db.upsert_with_ttl(session("deleted"), Duration::from_millis(50))
+        .unwrap();
+    db.delete(&token("deleted")).unwrap();
+    db.upsert_with_ttl(session("rewritten"), Duration::from_millis(50))
+        .unwrap();
+    db.upsert(session("rewritten")).unwrap();
+    db.upsert_with_ttl(session("late"), Duration::from_secs(3600))
+        .unwrap();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        assert_eq!(expirations.next().await, Some(token("b")));
+        assert_eq!(expirations.next().await, Some(token("a")));
+        assert!(db.get(&token("a")).unwrap().is_none());
+
+        // A key scheduled before the next one is yielded first
+        db.upsert_with_ttl(session("c"), Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(expirations.next().await, Some(token("c")));
+
+        // Dropping the handle ends the stream
+        drop(db);
+        assert_eq!(expirations.next().await, None);
+    });
+
+    let mut no_expiry = DB::<Inst>::configure()
+        .data_dir(&tmp_dir())
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert!(matches!(
+        no_expiry.expirations(),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+enum PersonField {
+    Id,
+    City,
+    Age,
+    Note,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Person {
+    pub id: i64,
+    pub city: String,
+    pub age: i64,
+    pub note: String,
+}
+
+impl Recordable for Person {
+    type Field = PersonField;
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (PersonField::Id, Type::int()),
+            (PersonField::City, Type::string()),
+            (PersonField::Age, Type::int()),
+            (PersonField::Note, Type::string()),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        PersonField::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![PersonField::City, PersonField::Age]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![
+            Value::Int(self.id),
+            Value::String(self.city),
+            Value::Int(self.age),
+            Value::String(self.note),
+        ]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::Int(id), Value::String(city), Value::Int(age), Value::String(note)] => Person {
+                id: *id,
+                city: city.clone(),
+                age: *age,
+                note: note.clone(),
+            },
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_find_by_all() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Person>::configure()
+        .data_dir(&data_dir)
+        .segment_size(300)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let cities = ["Helsinki", "Tampere", "Turku"];
+    for id in 0..60 {
+        db.upsert(Person {
+            id,
+            city: cities[id as usize % 3].to_string(),
+            age: 20 + id % 4,
+            note: if id % 5 == 0 { "vip" } else { "" }.to_string(),
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    let ids = |persons: Vec<Person>| persons.iter().map(|p| p.id).collect::<Vec<_>>();
+    let city = |name: &str| (PersonField::City, Value::String(name.to_string()));
+    let age = |age: i64| (PersonField::Age, Value::Int(age));
+
+    // Helsinki is id % 3 == 0 and age 21 is id % 4 == 1, so ids are 9 modulo 12
+    assert_eq!(
+        ids(db.find_by_all(&[city("Helsinki"), age(21)]).unwrap()),
+        vec![9, 21, 33, 45, 57]
+    );
+    let plan = db
+        .explain(&Query::FindByAll(vec![city("Helsinki"), age(21)]))
+        .unwrap();
+    assert_eq!(plan.index, QueryIndex::Intersection);
+    assert_eq!(plan.field, "[City, Age]");
+    assert_eq!(plan.estimated_log_keys, 5);
+
+    // Non-indexed fields are compared on the records read
+    assert_eq!(
+        ids(db
+            .find_by_all(&[
+                city("Helsinki"),
+                age(21),
+                (PersonField::Note, Value::String("vip".to_string()))
+            ])
+            .unwrap()),
+        vec![45]
+    );
+
+    // The primary key narrows the query down to a single record
+    assert_eq!(
+        ids(db
+            .find_by_all(&[age(21), (PersonField::Id, Value::Int(33))])
+            .unwrap()),
+        vec![33]
+    );
+    assert!(db
+        .find_by_all(&[age(20), (PersonField::Id, Value::Int(33))])
+        .unwrap()
+        .is_empty());
+
+    // Updated records are only found by their current values
+    db.upsert(Person {
+        id: 9,
+        city: "Turku".to_string(),
+        age: 21,
+        note: String::new(),
+    })
+    .unwrap();
+    assert_eq!(
+        ids(db.find_by_all(&[city("Helsinki"), age(21)]).unwrap()),
+        vec![21, 33, 45, 57]
+    );
+
+    assert!(matches!(
+        db.find_by_all(&[(PersonField::Note, Value::String("vip".to_string()))]),
+        Err(DBError::ValidationError(_))
+    ));
+    assert!(matches!(
+        db.find_by_all(&[]),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Event {
+    id: i64,
+    at: SystemTime,
+    name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum EventField {
+    Id,
+    At,
+    Name,
+}
+
+impl Recordable for Event {
+    type Field = EventField;
+
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (EventField::Id, Type::int()),
+            (EventField::At, Type::timestamp()),
+            (EventField::Name, Type::string()),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        EventField::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![EventField::At]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![
+            Value::Int(self.id),
+            Value::from_system_time(self.at),
+            Value::String(self.name),
+        ]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::Int(id), at @ Value::Timestamp(_), Value::String(name)] => Event {
+                id: *id,
+                at: at.as_system_time().unwrap(),
+                name: name.clone(),
+            },
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_timestamp_values() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Event>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let at = |minutes: u64| epoch + Duration::from_secs(minutes * 60);
+    for id in 0..10 {
+        db.upsert(Event {
+            id,
+            at: at(10 - id as u64),
+            name: format!("event {}", id),
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+
+    let found = db
+        .find_by(&EventField::At, &Value::from_system_time(at(3)))
+        .unwrap();
+    assert_eq!(found.iter().map(|e| e.id).collect::<Vec<_>>(), vec![7]);
+    assert_eq!(found[0].at, at(3));
+
+    let range = db
+        .range_by(
+            &EventField::At,
+            Value::from_system_time(at(2))..=Value::from_system_time(at(4)),
+        )
+        .unwrap();
+    let mut ids = range.iter().map(|e| e.id).collect::<Vec<_>>();
+    ids.sort();
+    assert_eq!(ids, vec![6, 7, 8]);
+
+    let latest = db.last_by(&EventField::At).unwrap().unwrap();
+    assert_eq!(latest.id, 0);
+    assert_eq!(db.first_by(&EventField::At).unwrap().unwrap().id, 9);
+
+    // Timestamps before the epoch are negative and sort before later ones
+    db.upsert(Event {
+        id: 100,
+        at: SystemTime::UNIX_EPOCH - Duration::from_secs(1),
+        name: "before".to_owned(),
+    })
+    .unwrap();
+    assert_eq!(db.first_by(&EventField::At).unwrap().unwrap().id, 100);
+
+    let plan = db
+        .explain(&Query::RangeBy(
+            EventField::At,
+            Bound::Unbounded,
+            Bound::Excluded(Value::from_system_time(epoch)),
+        ))
+        .unwrap();
+    assert!(!plan.is_full_scan());
+    assert_eq!(plan.estimated_log_keys, 1);
+
+    assert!(matches!(
+        db.find_by(&EventField::At, &Value::Int(0)),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Account {
+    id: i64,
+    balance: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AccountField {
+    Id,
+    Balance,
+}
+
+impl Recordable for Account {
+    type Field = AccountField;
+
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (AccountField::Id, Type::int()),
+            (AccountField::Balance, Type::decimal()),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        AccountField::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![AccountField::Balance]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![Value::Int(self.id), Value::Decimal(self.balance)]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::Int(id), Value::Decimal(balance)] => Account {
+                id: *id,
+                balance: *balance,
+            },
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_decimal_values() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Account>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let balances = ["0.1", "0.2", "-12.75", "1.50", "1000000000000.01", "0.30"];
+    for (id, balance) in balances.iter().enumerate() {
+        db.upsert(Account {
+            id: id as i64,
+            balance: balance.parse().unwrap(),
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+
+    // Decimals of different scales are equal if their numeric values are
+    let found = db
+        .find_by(
+            &AccountField::Balance,
+            &Value::Decimal("1.5".parse().unwrap()),
+        )
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, 3);
+    assert_eq!(found[0].balance.to_string(), "1.50");
+
+    let range = db
+        .range_by(
+            &AccountField::Balance,
+            Value::Decimal("0.2".parse().unwrap())..Value::Decimal("1.5".parse().unwrap()),
+        )
+        .unwrap();
+    let mut ids = range.iter().map(|a| a.id).collect::<Vec<_>>();
+    ids.sort();
+    assert_eq!(ids, vec![1, 5]);
+
+    assert_eq!(db.first_by(&AccountField::Balance).unwrap().unwrap().id, 2);
+    assert_eq!(db.last_by(&AccountField::Balance).unwrap().unwrap().id, 4);
+
+    // Sums are exact, unlike with floating point numbers
+    let sum = db
+        .aggregate(&AccountField::Balance, Aggregate::Sum)
+        .unwrap();
+    assert_eq!(
+        sum,
+        Value::Decimal("1000000000000.01".parse::<Decimal>().unwrap() - Decimal::new(1065, 2))
+    );
+
+    drop(db);
+    let mut db = DB::<Account>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    db.compact(SegmentSelector::All).unwrap();
+    assert_eq!(
+        db.get(&Value::Int(0)).unwrap().unwrap().balance,
+        Decimal::new(1, 1)
+    );
+    assert!(db
+        .scrub_next_segment()
+        .unwrap()
+        .is_none_or(|r| r.problems.is_empty()));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Task {
+    id: i64,
+    status: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TaskField {
+    Id,
+    Status,
+}
+
+impl Recordable for Task {
+    type Field = TaskField;
+
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (TaskField::Id, Type::int()),
+            (TaskField::Status, Type::enum_of(&["active", "archived"])),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        TaskField::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![TaskField::Status]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![Value::Int(self.id), Value::String(self.status)]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::Int(id), Value::String(status)] => Task {
+                id: *id,
+                status: status.clone(),
+            },
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_enum_values() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Task>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..10 {
+        let status = if id % 3 == 0 { "archived" } else { "active" };
+        db.upsert(Task {
+            id,
+            status: status.to_owned(),
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+
+    assert!(matches!(
+        db.upsert(Task {
+            id: 100,
+            status: "removed".to_owned()
+        }),
+        Err(DBError::ValidationError(_))
+    ));
+    assert!(matches!(
+        db.find_by(&TaskField::Status, &Value::String("removed".to_owned())),
+        Err(DBError::ValidationError(_))
+    ));
+
+    let archived = |db: &mut DB<Task>| {
+        let mut ids = db
+            .find_by(&TaskField::Status, &Value::String("archived".to_owned()))
+            .unwrap()
+            .iter()
+            .map(|task| task.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    };
+    assert_eq!(archived(&mut db), vec![0, 3, 6, 9]);
+    assert_eq!(
+        db.get(&Value::Int(1)).unwrap().unwrap().status,
+        "active".to_owned()
+    );
+
+    let mut arena = RecordArena::new();
+    db.batch_find_by_in(&mut arena, &TaskField::Id, &[Value::Int(3)])
+        .unwrap();
+    assert_eq!(
+        arena.get(0).unwrap().get(1),
+        Some(ValueRef::String("archived"))
+    );
+
+    // The variants are stored as their indexes, not as strings. Only the schema file names them.
+    for entry in fs::read_dir(&data_dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.file_name().unwrap() == "schema" {
+            continue;
+        }
+        let contents = fs::read(path).unwrap_or_default();
+        assert!(!contents.windows(8).any(|w| w == b"archived"));
+    }
+
+    db.upsert(Task {
+        id: 0,
+        status: "active".to_owned(),
+    })
+    .unwrap();
+    db.compact(SegmentSelector::All).unwrap();
+    drop(db);
+
+    let mut db = DB::<Task>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(archived(&mut db), vec![3, 6, 9]);
+    assert_eq!(db.get(&Value::Int(0)).unwrap().unwrap().status, "active");
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Document {
+    id: i64,
+    kind: String,
+    payload: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DocumentField {
+    Id,
+    Kind,
+    Payload,
+}
+
+impl Recordable for Document {
+    type Field = DocumentField;
+
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (DocumentField::Id, Type::int()),
+            (DocumentField::Kind, Type::string()),
+            (DocumentField::Payload, Type::json().nullable()),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        DocumentField::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![DocumentField::Kind]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![Value::Int(self.id), Value::String(self.kind), self.payload]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::Int(id), Value::String(kind), payload @ (Value::Json(_) | Value::Null)] => {
+                Document {
+                    id: *id,
+                    kind: kind.clone(),
+                    payload: payload.clone(),
+                }
+            }
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_json_values() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Document>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let payload = Value::Json(r#"{"tags":["a","b"],"size":{"w":2,"h":3}}"#.to_owned());
+    db.upsert(Document {
+        id: 1,
+        kind: "image".to_owned(),
+        payload: payload.clone(),
+    })
+    .unwrap();
+    db.upsert(Document {
+        id: 2,
+        kind: "note".to_owned(),
+        payload: Value::Null,
+    })
+    .unwrap();
+
+    let found = db
+        .find_by(&DocumentField::Kind, &Value::String("image".to_owned()))
+        .unwrap();
+    assert_eq!(found[0].payload, payload);
+    assert_eq!(
+        db.get(&Value::Int(2)).unwrap().unwrap().payload,
+        Value::Null
+    );
+
+    // JSON values have no order
+    assert!(matches!(
+        db.aggregate(&DocumentField::Payload, Aggregate::Max),
+        Err(DBError::ValidationError(_))
+    ));
+    assert!(matches!(
+        db.upsert(Document {
+            id: 3,
+            kind: "note".to_owned(),
+            payload: Value::String("{}".to_owned()),
+        }),
+        Err(DBError::ValidationError(_))
+    ));
+
+    #[cfg(feature = "json")]
+    {
+        let json = codec::serde_json::json!({"title": "hello", "lines": [1, 2]});
+        db.upsert(Document {
+            id: 3,
+            kind: "note".to_owned(),
+            payload: Value::from_json(&json),
+        })
+        .unwrap();
+        let document = db.get(&Value::Int(3)).unwrap().unwrap();
+        assert_eq!(document.payload.as_json(), Some(json));
+
+        assert!(matches!(
+            db.upsert(Document {
+                id: 4,
+                kind: "note".to_owned(),
+                payload: Value::Json("{not json".to_owned()),
+            }),
+            Err(DBError::ValidationError(_))
+        ));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Blob {
+    id: i64,
+    digest: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlobField {
+    Id,
+    Digest,
+}
+
+impl Recordable for Blob {
+    type Field = BlobField;
+
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (BlobField::Id, Type::int()),
+            (BlobField::Digest, Type::bytes_fixed(32)),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        BlobField::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![Value::Int(self.id), Value::Bytes(self.digest)]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::Int(id), Value::Bytes(digest)] => Blob {
+                id: *id,
+                digest: digest.clone(),
+            },
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_fixed_bytes() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Blob>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..3 {
+        db.upsert(Blob {
+            id,
+            digest: vec![id as u8; 32],
+        })
+        .unwrap();
+    }
+    assert!(matches!(
+        db.upsert(Blob {
+            id: 3,
+            digest: vec![0; 31],
+        }),
+        Err(DBError::ValidationError(_))
+    ));
+
+    assert_eq!(db.get(&Value::Int(2)).unwrap().unwrap().digest, vec![2; 32]);
+    let scanned = db
+        .scan_filter(|values| values[1] == Value::Bytes(vec![1; 32]))
+        .unwrap()
+        .into_iter()
+        .map(|blob| blob.id)
+        .collect::<Vec<_>>();
+    assert_eq!(scanned, vec![1]);
+
+    // Header, an int, and the digest with a one-byte length
+    let report = db.size_report(1).unwrap();
+    assert_eq!(report.largest[0].1, (1 + 8 + 8) + (1 + 8) + (1 + 1 + 32));
+
+    db.compact(SegmentSelector::All).unwrap();
+    drop(db);
+    let mut db = DB::<Blob>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(db.get(&Value::Int(0)).unwrap().unwrap().digest, vec![0; 32]);
+    assert!(db
+        .scrub_next_segment()
+        .unwrap()
+        .is_none_or(|r| r.problems.is_empty()));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Measurement {
+    id: i64,
+    reading: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MeasurementField {
+    Id,
+    Reading,
+}
+
+impl Recordable for Measurement {
+    type Field = MeasurementField;
+
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (MeasurementField::Id, Type::int()),
+            (MeasurementField::Reading, Type::float()),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        MeasurementField::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![MeasurementField::Reading]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![Value::Int(self.id), Value::Float(self.reading)]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::Int(id), Value::Float(reading)] => Measurement {
+                id: *id,
+                reading: *reading,
+            },
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_float_keys() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Measurement>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let readings = [
+        2.5,
+        -0.0,
+        f64::NAN,
+        -7.25,
+        0.0,
+        f64::NEG_INFINITY,
+        1e-9,
+        -1e-9,
+        f64::INFINITY,
+    ];
+    for (id, reading) in readings.iter().enumerate() {
+        db.upsert(Measurement {
+            id: id as i64,
+            reading: *reading,
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+
+    let ids = |measurements: Vec<Measurement>| {
+        let mut ids = measurements.iter().map(|m| m.id).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    };
+
+    let range = db
+        .range_by(
+            &MeasurementField::Reading,
+            Value::Float(-1.0)..Value::Float(1.0),
+        )
+        .unwrap();
+    assert_eq!(ids(range), vec![1, 4, 6, 7]);
+
+    // -0.0 and 0.0 are the same key, and so are all NaNs
+    let zeros = db
+        .find_by(&MeasurementField::Reading, &Value::Float(0.0))
+        .unwrap();
+    assert_eq!(ids(zeros), vec![1, 4]);
+    let nans = db
+        .find_by(&MeasurementField::Reading, &Value::Float(-f64::NAN))
+        .unwrap();
+    assert_eq!(ids(nans), vec![2]);
+
+    // NaN sorts after infinity
+    let below_nan = db
+        .range_by(&MeasurementField::Reading, ..=Value::Float(f64::INFINITY))
+        .unwrap();
+    assert_eq!(ids(below_nan).len(), readings.len() - 1);
+    assert_eq!(
+        db.first_by(&MeasurementField::Reading).unwrap().unwrap().id,
+        5
+    );
+    assert!(db
+        .last_by(&MeasurementField::Reading)
+        .unwrap()
+        .unwrap()
+        .reading
+        .is_nan());
+    assert_eq!(
+        db.aggregate(&MeasurementField::Reading, Aggregate::Max)
+            .unwrap(),
+        Value::Float(f64::NAN)
+    );
+
+    assert!(matches!(
+        db.find_by(&MeasurementField::Reading, &Value::Int(0)),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Customer {
+    id: i64,
+    address: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CustomerField {
+    Id,
+    Address,
+}
+
+impl Recordable for Customer {
+    type Field = CustomerField;
+
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (CustomerField::Id, Type::int()),
+            (
+                CustomerField::Address,
+                Type::record(&[
+                    ("street", Type::string()),
+                    ("city", Type::string()),
+                    (
+                        "geo",
+                        Type::record(&[("lat", Type::float()), ("lon", Type::float())]).nullable(),
+                    ),
+                ]),
+            ),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        CustomerField::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![Value::Int(self.id), self.address]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::Int(id), address @ Value::Record(_)] => Customer {
+                id: *id,
+                address: address.clone(),
+            },
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_nested_records() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Customer>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let address = |street: &str, city: &str, geo: Option<(f64, f64)>| {
+        Value::Record(vec![
+            Value::String(street.to_owned()),
+            Value::String(city.to_owned()),
+            match geo {
+                Some((lat, lon)) => Value::Record(vec![Value::Float(lat), Value::Float(lon)]),
+                None => Value::Null,
+            },
+        ])
+    };
+    db.upsert(Customer {
+        id: 1,
+        address: address("Hämeenkatu 1", "Tampere", Some((61.5, 23.75))),
+    })
+    .unwrap();
+    db.upsert(Customer {
+        id: 2,
+        address: address("Aleksanterinkatu 2", "Helsinki", None),
+    })
+    .unwrap();
+
+    // Nested values are validated against their own schema
+    for invalid in [
+        Value::Record(vec![Value::String("Street".to_owned())]),
+        Value::Record(vec![
+            Value::String("Street".to_owned()),
+            Value::Int(3),
+            Value::Null,
+        ]),
+        Value::String("Street".to_owned()),
+    ] {
+        assert!(matches!(
+            db.upsert(Customer {
+                id: 3,
+                address: invalid
+            }),
+            Err(DBError::ValidationError(_))
+        ));
+    }
+
+    let projection =
+        Projection::new::<Customer>(&["Id", "Address.city", "Address.geo.lat"]).unwrap();
+    let project = |db: &mut DB<Customer>, id: i64| {
+        let customer = db.get(&Value::Int(id)).unwrap().unwrap();
+        projection.apply(&customer.into_record())
+    };
+    assert_eq!(
+        project(&mut db, 1),
+        vec![
+            Value::Int(1),
+            Value::String("Tampere".to_owned()),
+            Value::Float(61.5)
+        ]
+    );
+    assert_eq!(
+        project(&mut db, 2),
+        vec![
+            Value::Int(2),
+            Value::String("Helsinki".to_owned()),
+            Value::Null
+        ]
+    );
+
+    for invalid in ["Address.country", "Id.value", "address.city", ""] {
+        assert!(matches!(
+            Projection::new::<Customer>(&[invalid]),
+            Err(DBError::ValidationError(_))
+        ));
+    }
+
+    db.compact(SegmentSelector::All).unwrap();
+    drop(db);
+    let mut db = DB::<Customer>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(
+        db.get(&Value::Int(1)).unwrap().unwrap().address,
+        address("Hämeenkatu 1", "Tampere", Some((61.5, 23.75)))
+    );
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ProductV1 {
+    id: i64,
+    name: String,
+    legacy: String,
+    status: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ProductV1Field {
+    Id,
+    Name,
+    Legacy,
+    Status,
+}
+
+impl Recordable for ProductV1 {
+    type Field = ProductV1Field;
+
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (ProductV1Field::Id, Type::int()),
+            (ProductV1Field::Name, Type::string()),
+            (ProductV1Field::Legacy, Type::string()),
+            (
+                ProductV1Field::Status,
+                Type::enum_of(&["active", "retired"]),
+            ),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        ProductV1Field::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![ProductV1Field::Name]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![
+            Value::Int(self.id),
+            Value::String(self.name),
+            Value::String(self.legacy),
+            Value::String(self.status),
+        ]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::Int(id), Value::String(name), Value::String(legacy), Value::String(status)] => {
+                ProductV1 {
+                    id: *id,
+                    name: name.clone(),
+                    legacy: legacy.clone(),
+                    status: status.clone(),
+                }
+            }
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ProductV2 {
+    title: String,
+    id: i64,
+    status: String,
+    price: i64,
+    note: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ProductV2Field {
+    Title,
+    Id,
+    Status,
+    Price,
+    Note,
+}
+
+impl Recordable for ProductV2 {
+    type Field = ProductV2Field;
+
+    fn schema() -> Vec<(Self::Field, Type)> {
+        vec![
+            (ProductV2Field::Title, Type::string()),
+            (ProductV2Field::Id, Type::int()),
+            (
+                ProductV2Field::Status,
+                Type::enum_of(&["active", "retired"]),
+            ),
+            (ProductV2Field::Price, Type::int()),
+            (ProductV2Field::Note, Type::string().nullable()),
+        ]
+    }
+    fn primary_key() -> Self::Field {
+        ProductV2Field::Id
+    }
+    fn secondary_keys() -> Vec<Self::Field> {
+        vec![ProductV2Field::Title]
+    }
+
+    fn into_record(self) -> Vec<Value> {
+        vec![
+            Value::String(self.title),
+            Value::Int(self.id),
+            Value::String(self.status),
+            Value::Int(self.price),
+            self.note.map_or(Value::Null, Value::String),
+        ]
+    }
+
+    fn from_record(record: Vec<Value>) -> Self {
+        match &record[..] {
+            [Value::String(title), Value::Int(id), Value::String(status), Value::Int(price), note] => {
+                ProductV2 {
+                    title: title.clone(),
+                    id: *id,
+                    status: status.clone(),
+                    price: *price,
+                    note: match note {
+                        Value::String(note) => Some(note.clone()),
+                        _ => None,
+                    },
+                }
+            }
+            other => panic!("Invalid record: {:?}", other),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_migrate() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<ProductV1>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let product = |id: i64, name: &str, status: &str| ProductV1 {
+        id,
+        name: name.to_owned(),
+        legacy: "x".repeat(20),
+        status: status.to_owned(),
+    };
+    for id in 1..=4 {
+        db.upsert(product(id, &format!("product {}", id), "active"))
+            .unwrap();
+    }
+    db.compact(SegmentSelector::Active).unwrap();
+    db.upsert(product(1, "product 1", "retired")).unwrap();
+    db.delete(&Value::Int(3)).unwrap();
+
+    let open_v1 = || {
+        DB::<ProductV1>::configure()
+            .data_dir(&data_dir)
+            .segment_size(200)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let list_files = || {
+        let mut files = fs::read_dir(&data_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+    let files_before = list_files();
+    assert!(files_before.contains(&"metadata.2".into()));
+
+    // A default that does not match the type of its field is rejected before anything is rewritten
+    let result = db.migrate(
+        MigrationPlan::<ProductV1, ProductV2>::new()
+            .copy(ProductV2Field::Title, ProductV1Field::Name)
+            .default(ProductV2Field::Price, Value::String("free".to_owned())),
+    );
+    assert!(matches!(result, Err(DBError::ValidationError(_))));
+
+    // A record that does not fit the new schema fails the migration, and the log is left as it was
+    let result = open_v1().migrate(
+        MigrationPlan::<ProductV1, ProductV2>::new()
+            .copy(ProductV2Field::Title, ProductV1Field::Name)
+            .copy(ProductV2Field::Price, ProductV1Field::Legacy),
+    );
+    assert!(matches!(result, Err(DBError::ValidationError(_))));
+    assert_eq!(list_files(), files_before);
+    let mut db = open_v1();
+    assert_eq!(
+        db.get(&Value::Int(1)).unwrap(),
+        Some(product(1, "product 1", "retired"))
+    );
+
+    // Name is renamed to Title, Legacy is dropped, Price is backfilled and Note is left null
+    db.migrate(
+        MigrationPlan::<ProductV1, ProductV2>::new()
+            .copy(ProductV2Field::Title, ProductV1Field::Name)
+            .default(ProductV2Field::Price, Value::Int(100)),
+    )
+    .unwrap();
+
+    let mut db = DB::<ProductV2>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    let migrated = |id: i64, status: &str| ProductV2 {
+        title: format!("product {}", id),
+        id,
+        status: status.to_owned(),
+        price: 100,
+        note: None,
+    };
+    assert_eq!(
+        db.get(&Value::Int(1)).unwrap(),
+        Some(migrated(1, "retired"))
+    );
+    assert_eq!(db.get(&Value::Int(2)).unwrap(), Some(migrated(2, "active")));
+    assert_eq!(db.get(&Value::Int(3)).unwrap(), None);
+    assert_eq!(
+        db.find_by(
+            &ProductV2Field::Title,
+            &Value::String("product 4".to_owned())
+        )
+        .unwrap(),
+        vec![migrated(4, "active")]
+    );
+
+    // Superseded versions are migrated too
+    let history = db.history(&Value::Int(1)).unwrap();
+    assert_eq!(
+        history
+            .into_iter()
+            .map(|version| version.record)
+            .collect::<Vec<_>>(),
+        vec![migrated(1, "active"), migrated(1, "retired")]
+    );
+
+    // The migrated log can be written to and compacted
+    db.upsert(migrated(5, "active")).unwrap();
+    db.compact(SegmentSelector::All).unwrap();
+    assert_eq!(db.get(&Value::Int(5)).unwrap(), Some(migrated(5, "active")));
+    assert_eq!(db.get(&Value::Int(3)).unwrap(), None);
+}
+
+#[test]
+#[serial]
+fn test_schema_file() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<ProductV1>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    db.upsert(ProductV1 {
+        id: 1,
+        name: "product 1".to_owned(),
+        legacy: String::new(),
+        status: "active".to_owned(),
+    })
+    .unwrap();
+
+    let stored = StoredSchema::read(Path::new(&data_dir)).unwrap().unwrap();
+    assert_eq!(
+        stored.fields[3],
+        (
+            "Status".to_owned(),
+            "enum(\"active\", \"retired\")".to_owned()
+        )
+    );
+    assert_eq!(stored.primary_key, "Id");
+    assert_eq!(stored.secondary_keys, vec!["Name".to_owned()]);
+
+    // A different schema is refused with both schemas described
+    match DB::<ProductV2>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+    {
+        Err(DBError::SchemaMismatch(mismatch)) => {
+            assert_eq!(mismatch.stored, stored);
+            assert_eq!(mismatch.configured.fields[0].0, "Title");
+            assert_eq!(
+                mismatch.to_string(),
+                "the data directory has 4 fields, the schema has 5"
+            );
+        }
+        _ => panic!("Expected a schema mismatch"),
+    }
+
+    // Secondary keys only affect the memtables, so they may change
+    drop(db);
+    StoredSchema {
+        secondary_keys: vec![],
+        ..stored.clone()
+    }
+    .write(Path::new(&data_dir))
+    .unwrap();
+    let mut db = DB::<ProductV1>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(
+        StoredSchema::read(Path::new(&data_dir)).unwrap(),
+        Some(stored)
+    );
+    assert_eq!(db.get(&Value::Int(1)).unwrap().unwrap().name, "product 1");
+}
+
+#[test]
+#[serial]
+fn test_segment_format_version() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    db.upsert(Inst {
+        id: 1,
+        name: Some("inst 1".to_owned()),
+        data: vec![],
+    })
+    .unwrap();
+    drop(db);
+
+    // The format version is the first byte of the metadata header
+    let metadata_path = Path::new(&data_dir).join("metadata.1");
+    let mut metadata = fs::read(&metadata_path).unwrap();
+    assert_eq!(metadata[0], SEGMENT_FORMAT_VERSION);
+
+    // A segment written by a newer version of the crate is refused instead of misread
+    metadata[0] = SEGMENT_FORMAT_VERSION + 1;
+    fs::write(&metadata_path, &metadata).unwrap();
+    let result = DB::<Inst>::configure().data_dir(&data_dir).initialize();
+    assert!(matches!(result, Err(DBError::ValidationError(_))));
+}
+
+#[test]
+#[serial]
+fn test_field_checks() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .write_transform(Field::Name, WriteTransform::NormalizeWhitespace)
+        .check(
+            Field::Name,
+            |name| matches!(name, Value::String(s) if !s.is_empty()),
+        )
+        .check(
+            Field::Data,
+            |data| matches!(data, Value::Bytes(b) if b.len() <= 4),
+        )
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let inst = |id: i64, name: Option<&str>, data: Vec<u8>| Inst {
+        id,
+        name: name.map(str::to_owned),
+        data,
+    };
+    db.upsert(inst(1, Some("first"), vec![1, 2])).unwrap();
+
+    // Checks see the transformed value, and the error names the field
+    match db.upsert(inst(2, Some("   "), vec![])) {
+        Err(DBError::ValidationError(message)) => assert!(message.contains("Name")),
+        _ => panic!("Expected a validation error"),
+    }
+    assert!(matches!(
+        db.upsert(inst(1, Some("first"), vec![1, 2, 3, 4, 5])),
+        Err(DBError::ValidationError(_))
+    ));
+
+    // A failing record fails the whole batch
+    assert!(db
+        .batch_upsert(vec![
+            inst(3, Some("third"), vec![]),
+            inst(4, Some(""), vec![])
+        ])
+        .is_err());
+    assert!(db.get(&Value::Int(3)).unwrap().is_none());
+
+    // Null values are not checked, and raw records are checked like others
+    db.upsert(inst(5, None, vec![])).unwrap();
+    let raw = codec::encode_record(&[
+        Value::Int(6),
+        Value::String("raw".to_owned()),
+        Value::Bytes(vec![0; 8]),
+    ]);
+    assert!(matches!(
+        db.append_raw(&raw),
+        Err(DBError::ValidationError(_))
+    ));
+
+    assert_eq!(db.get(&Value::Int(1)).unwrap().unwrap().data, vec![1, 2]);
+
+    // Deletions are not checked
+    db.delete(&Value::Int(1)).unwrap();
+}
+
+#[test]
+#[serial]
+fn test_computed_indexes() {
+    let data_dir = tmp_dir();
+    let configure = || {
+        let mut builder = DB::<Event>::configure();
+        builder
+            .data_dir(&data_dir)
+            .delete_mode(DeleteMode::Soft)
+            .computed_index("name_lower", |values| match &values[2] {
+                Value::String(name) => Value::String(name.to_lowercase()),
+                other => other.clone(),
+            })
+            .computed_index("day", |values| match values[1] {
+                Value::Timestamp(micros) => Value::Int(micros.div_euclid(86_400_000_000)),
+                _ => Value::Null,
+            });
+        builder
+    };
+    let mut db = configure()
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let day = 86_400_000_000;
+    let event = |id: i64, name: &str, micros: u64| Event {
+        id,
+        name: name.to_owned(),
+        at: SystemTime::UNIX_EPOCH + Duration::from_micros(micros),
+    };
+    db.upsert(event(1, "Launch", day + 1)).unwrap();
+    db.upsert(event(2, "LAUNCH", 2 * day)).unwrap();
+    db.upsert(event(3, "Review", 3 * day - 1)).unwrap();
+
+    let ids = |events: Vec<Event>| {
+        let mut ids: Vec<i64> = events.into_iter().map(|event| event.id).collect();
+        ids.sort();
+        ids
+    };
+    let launches = Value::String("launch".to_owned());
+    assert_eq!(
+        ids(db.find_by_computed("name_lower", &launches).unwrap()),
+        vec![1, 2]
+    );
+    assert_eq!(
+        ids(db.range_by_computed("day", &Value::Int(2)..).unwrap()),
+        vec![2, 3]
+    );
+
+    // Updates move records to their new keys
+    db.upsert(event(2, "Retro", 2 * day)).unwrap();
+    assert_eq!(
+        ids(db.find_by_computed("name_lower", &launches).unwrap()),
+        vec![1]
+    );
+
+    // Soft-deleted records are found with include_deleted
+    db.delete(&Value::Int(1)).unwrap();
+    assert!(db
+        .find_by_computed("name_lower", &launches)
+        .unwrap()
+        .is_empty());
+    let deleted = db
+        .include_deleted(|db| db.find_by_computed("name_lower", &launches))
+        .unwrap();
+    assert_eq!(ids(deleted), vec![1]);
+
+    // The keys are computed again when the indexes are rebuilt
+    drop(db);
+    let mut db = configure()
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(
+        ids(db.find_by_computed("day", &Value::Int(2)).unwrap()),
+        vec![2, 3]
+    );
+    let mut dump = vec![];
+    db.dump_indexes(&mut dump).unwrap();
+    assert!(String::from_utf8(dump).unwrap().contains("secondary day\n"));
+
+    assert!(matches!(
+        db.find_by_computed("missing", &Value::Int(1)),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[derive(Debug, Clone, PartialEq, Recordable)]
+struct Comment {
+    #[log_db(primary_key)]
+    id: i64,
+    #[log_db(field_type = Type::string().max_len(8))]
+    body: String,
+    #[log_db(field_type = Type::bytes().max_len(4).nullable())]
+    attachment: Option<Vec<u8>>,
+}
+
+#[test]
+#[serial]
+fn test_max_len() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Comment>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let comment = |id: i64, body: &str, attachment: Option<Vec<u8>>| Comment {
+        id,
+        body: body.to_owned(),
+        attachment,
+    };
+
+    // Values at the limit are accepted, and strings are measured in UTF-8 bytes
+    db.upsert(comment(1, "12345678", Some(vec![1, 2, 3, 4])))
+        .unwrap();
+    db.upsert(comment(2, "ääää", None)).unwrap();
+    match db.upsert(comment(3, "äääää", None)) {
+        Err(DBError::ValidationError(message)) => assert!(message.contains("at most 8")),
+        _ => panic!("Expected a validation error"),
+    }
+    assert!(matches!(
+        db.upsert(comment(3, "short", Some(vec![0; 5]))),
+        Err(DBError::ValidationError(_))
+    ));
+    assert!(db
+        .batch_upsert(vec![comment(4, "ok", None), comment(5, "too long!", None)])
+        .is_err());
+    assert!(db.get(&Value::Int(4)).unwrap().is_none());
+
+    // Raw records are held to the same limits
+    let raw = codec::encode_record(&[
+        Value::Int(6),
+        Value::String("much too long".to_owned()),
+        Value::Null,
+    ]);
+    assert!(matches!(
+        db.append_raw(&raw),
+        Err(DBError::ValidationError(_))
+    ));
+
+    assert_eq!(db.get(&Value::Int(2)).unwrap().unwrap().body, "ääää");
+    assert!(db.get(&Value::Int(3)).unwrap().is_none());
+}
+
+#[test]
+#[serial]
+fn test_collation() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .collation(Field::Name, Collation::AsciiCaseInsensitive)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let inst = |id: i64, name: &str| Inst {
+        id,
+        name: Some(name.to_owned()),
+        data: vec![],
+    };
+    let names = |records: Vec<Inst>| -> Vec<String> {
+        records.into_iter().map(|inst| inst.name.unwrap()).collect()
+    };
+    db.batch_upsert(vec![
+        inst(1, "banana"),
+        inst(2, "Apple"),
+        inst(3, "cherry"),
+        inst(4, "APPLE"),
+        inst(5, "Banana split"),
+    ])
+    .unwrap();
+
+    // Records keep their values, but are found and ordered ignoring case
+    assert_eq!(
+        names(
+            db.find_by(&Field::Name, &Value::String("apple".to_owned()))
+                .unwrap()
+        ),
+        vec!["Apple", "APPLE"]
+    );
+    let range = Value::String("B".to_owned())..Value::String("c".to_owned());
+    assert_eq!(
+        names(db.range_by(&Field::Name, range.clone()).unwrap()),
+        vec!["banana", "Banana split"]
+    );
+    let streamed: Vec<String> = db
+        .range_by_stream(&Field::Name, range)
+        .unwrap()
+        .map(|inst| inst.unwrap().name.unwrap())
+        .collect();
+    assert_eq!(streamed, vec!["banana", "Banana split"]);
+    assert_eq!(
+        names(db.top_k(&Field::Name, 1, Direction::Desc).unwrap()),
+        vec!["cherry"]
+    );
+
+    // Aggregates see the values of the field rather than the collated keys
+    assert_eq!(
+        db.aggregate(&Field::Name, Aggregate::Min).unwrap(),
+        Value::String("APPLE".to_owned())
+    );
+    drop(db);
+
+    // The collation can be changed when the database is opened again
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .collation(
+            Field::Name,
+            Collation::SortKey(|s| s.chars().rev().collect()),
+        )
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(
+        names(db.top_k(&Field::Name, 2, Direction::Asc).unwrap()),
+        vec!["APPLE", "banana"]
+    );
+
+    // Only string secondary keys can have a collation
+    drop(db);
+    assert!(matches!(
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .collation(Field::Data, Collation::AsciiCaseInsensitive)
+            .initialize(),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[derive(Debug, Clone, PartialEq, Recordable)]
+#[log_db(field_enum = OrderColumn)]
+struct Order {
+    #[log_db(primary_key)]
+    order_id: i64,
+    #[log_db(secondary_key)]
+    customer: String,
+    #[log_db(field_type = Type::enum_of(&["open", "shipped"]))]
+    status: String,
+    total: Decimal,
+    weight: Option<f64>,
+    placed_at: SystemTime,
+    #[log_db(secondary_key)]
+    coupon: Option<String>,
+}
+
+#[test]
+#[serial]
+fn test_derive_recordable() {
+    assert_eq!(Order::primary_key(), OrderColumn::OrderId);
+    assert_eq!(
+        Order::secondary_keys(),
+        vec![OrderColumn::Customer, OrderColumn::Coupon]
+    );
+    let described = StoredSchema::from_schema(
+        &Order::schema(),
+        &Order::primary_key(),
+        &Order::secondary_keys(),
+    );
+    let types: Vec<&str> = described.fields.iter().map(|(_, t)| t.as_str()).collect();
+    assert_eq!(
+        types,
+        vec![
+            "int",
+            "string",
+            "enum(\"open\", \"shipped\")",
+            "decimal",
+            "float?",
+            "timestamp",
+            "string?"
+        ]
+    );
+    assert_eq!(described.fields[0].0, "OrderId");
+
+    let data_dir = tmp_dir();
+    let mut db = DB::<Order>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    let order = Order {
+        order_id: 1,
+        customer: "ada".to_owned(),
+        status: "open".to_owned(),
+        total: Decimal::new(1999, 2),
+        weight: None,
+        placed_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        coupon: Some("WELCOME".to_owned()),
+    };
+    db.upsert(order.clone()).unwrap();
+    db.upsert(Order {
+        order_id: 2,
+        weight: Some(1.5),
+        coupon: None,
+        ..order.clone()
+    })
+    .unwrap();
+
+    assert_eq!(db.get(&Value::Int(1)).unwrap(), Some(order.clone()));
+    let by_customer = db
+        .find_by(&OrderColumn::Customer, &Value::String("ada".to_owned()))
+        .unwrap();
+    assert_eq!(by_customer.len(), 2);
+    assert_eq!(by_customer[1].weight, Some(1.5));
+    assert_eq!(
+        db.find_by(&OrderColumn::Coupon, &Value::String("WELCOME".to_owned()))
+            .unwrap(),
+        vec![order.clone()]
+    );
+
+    // The overridden type is validated like any other
+    assert!(matches!(
+        db.upsert(Order {
+            status: "lost".to_owned(),
+            ..order
+        }),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_collections() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert!(db.collection_names().unwrap().is_empty());
+
+    db.upsert(Inst {
+        id: 1,
+        name: Some("parent".to_owned()),
+        data: vec![],
+    })
+    .unwrap();
+    let comment = |id: i64, body: &str| Comment {
+        id,
+        body: body.to_owned(),
+        attachment: None,
+    };
+    let comments = db.collection::<Comment>("comments").unwrap();
+    comments.upsert(comment(1, "first")).unwrap();
+    comments.upsert(comment(2, "second")).unwrap();
+    db.collection::<Comment>("archived-comments")
+        .unwrap()
+        .upsert(comment(1, "archived"))
+        .unwrap();
+
+    // Each collection has its own records, and the parent keeps its own
+    assert_eq!(
+        db.collection::<Comment>("comments")
+            .unwrap()
+            .get(&Value::Int(1))
+            .unwrap(),
+        Some(comment(1, "first"))
+    );
+    assert_eq!(
+        db.collection::<Comment>("archived-comments")
+            .unwrap()
+            .get(&Value::Int(1))
+            .unwrap(),
+        Some(comment(1, "archived"))
+    );
+    assert_eq!(
+        db.get(&Value::Int(1)).unwrap().unwrap().name.as_deref(),
+        Some("parent")
+    );
+    assert_eq!(
+        db.collection_names().unwrap(),
+        vec!["archived-comments", "comments"]
+    );
+
+    // A collection is asked for with the type it was opened with, and names must be plain
+    assert!(matches!(
+        db.collection::<Order>("comments"),
+        Err(DBError::ValidationError(_))
+    ));
+    assert!(matches!(
+        db.collection::<Comment>("../escape"),
+        Err(DBError::ValidationError(_))
+    ));
+    drop(db);
+
+    // Collections are found again when the database is reopened, and keep their schema
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(
+        db.collection::<Comment>("comments")
+            .unwrap()
+            .get(&Value::Int(2))
+            .unwrap(),
+        Some(comment(2, "second"))
+    );
+    assert!(matches!(
+        db.collection::<Order>("archived-comments"),
+        Err(DBError::SchemaMismatch(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_transaction_across_collections() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    let inst = |id: i64| Inst {
+        id,
+        name: Some(format!("order{}", id)),
+        data: vec![],
+    };
+    let comment = |id: i64, body: &str| Comment {
+        id,
+        body: body.to_owned(),
+        attachment: None,
+    };
+
+    // Nothing is written before the transaction is committed
+    let mut transaction = db.transaction();
+    transaction.upsert(inst(1)).unwrap();
+    transaction
+        .upsert_in("outbox", comment(1, "created"))
+        .unwrap();
+    transaction.upsert(inst(2)).unwrap();
+    transaction
+        .upsert_in("audit", comment(1, "audited"))
+        .unwrap();
+    drop(transaction);
+    assert!(db.get(&Value::Int(1)).unwrap().is_none());
+    assert_eq!(
+        db.collection::<Comment>("outbox")
+            .unwrap()
+            .get(&Value::Int(1))
+            .unwrap(),
+        None
+    );
+
+    let mut transaction = db.transaction();
+    transaction.upsert(inst(1)).unwrap();
+    transaction
+        .upsert_in("outbox", comment(1, "created"))
+        .unwrap();
+    transaction.upsert(inst(2)).unwrap();
+    transaction
+        .upsert_in("outbox", comment(2, "shipped"))
+        .unwrap();
+    // A record of the wrong type for its collection is rejected when it is staged
+    assert!(matches!(
+        transaction.upsert_in("outbox", inst(3)),
+        Err(DBError::ValidationError(_))
+    ));
+    let receipts = transaction.commit().unwrap();
+
+    // Receipts are in the order the records were staged, and each database versions its own records
+    assert_eq!(receipts.len(), 4);
+    assert!(receipts[0].meta.version < receipts[2].meta.version);
+    assert!(receipts[1].meta.version < receipts[3].meta.version);
+    assert_eq!(
+        fs::read_dir(Path::new(&data_dir).join("transactions"))
+            .unwrap()
+            .count(),
+        0
+    );
+    assert_eq!(
+        db.get(&Value::Int(2)).unwrap().unwrap().name.as_deref(),
+        Some("order2")
+    );
+    drop(db);
+
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(
+        db.get(&Value::Int(1)).unwrap().unwrap().name.as_deref(),
+        Some("order1")
+    );
+    let outbox = db.collection::<Comment>("outbox").unwrap();
+    assert_eq!(
+        outbox.get(&Value::Int(1)).unwrap(),
+        Some(comment(1, "created"))
+    );
+    assert_eq!(
+        outbox.get(&Value::Int(2)).unwrap(),
+        Some(comment(2, "shipped"))
+    );
+    assert_eq!(db.transaction().commit().unwrap().len(), 0);
+}
+
+#[test]
+#[serial]
+fn test_null_secondary_keys() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .segment_size(200)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+
+    for id in 0..6 {
+        db.upsert(Inst {
+            id,
+            name: (id % 2 == 0).then(|| format!("name{}", id)),
+            data: vec![],
+        })
+        .unwrap();
+    }
+    let ids = |insts: Vec<Inst>| {
+        let mut ids: Vec<i64> = insts.into_iter().map(|inst| inst.id).collect();
+        ids.sort();
+        ids
+    };
+
+    // Null is a value of the index like any other for lookups, but not for ordered walks
+    assert_eq!(
+        ids(db
+            .find_by_any(
+                &Field::Name,
+                &[Value::Null, Value::String("name0".to_owned())]
+            )
+            .unwrap()),
+        vec![0, 1, 3, 5]
+    );
+    assert_eq!(
+        ids(db
+            .find_by_all(&[(Field::Name, Value::Null), (Field::Id, Value::Int(3))])
+            .unwrap()),
+        vec![3]
+    );
+    assert_eq!(db.distinct(&Field::Name).unwrap()[0], Value::Null);
+    assert_eq!(db.estimate_cardinality(&Field::Name).unwrap(), 4);
+    assert_eq!(
+        ids(db.top_k(&Field::Name, 10, Direction::Asc).unwrap()),
+        vec![0, 2, 4]
+    );
+
+    // Deleting a record with a null key removes it from the null entry, also after compaction
+    db.delete(&Value::Int(1)).unwrap();
+    db.compact(SegmentSelector::All).unwrap();
+    assert_eq!(
+        ids(db.find_by(&Field::Name, &Value::Null).unwrap()),
+        vec![3, 5]
+    );
+    drop(db);
+
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(
+        ids(db.find_by(&Field::Name, &Value::Null).unwrap()),
+        vec![3, 5]
+    );
+    db.delete(&Value::Int(3)).unwrap();
+    db.delete(&Value::Int(5)).unwrap();
+    assert!(db.find_by(&Field::Name, &Value::Null).unwrap().is_empty());
+    assert_ne!(db.distinct(&Field::Name).unwrap()[0], Value::Null);
+}
+
+#[derive(Debug, Clone, PartialEq, Recordable)]
+#[log_db(field_enum = ItemV1Field)]
+struct ItemV1 {
+    #[log_db(primary_key)]
+    id: i64,
+    #[log_db(secondary_key)]
+    price: i64,
+    stock: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Recordable)]
+#[log_db(field_enum = ItemV2Field)]
+struct ItemV2 {
+    #[log_db(primary_key)]
+    id: i64,
+    #[log_db(secondary_key)]
+    price: f64,
+    stock: Option<Decimal>,
+}
+
+#[test]
+#[serial]
+fn test_numeric_widening() {
+    let data_dir = tmp_dir();
+    let mut db = DB::<ItemV1>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 1..=3 {
+        db.upsert(ItemV1 {
+            id,
+            price: id * 10,
+            stock: (id != 2).then_some(id),
+        })
+        .unwrap();
+    }
+    drop(db);
+
+    // Widening must be asked for
+    assert!(matches!(
+        DB::<ItemV2>::configure().data_dir(&data_dir).initialize(),
+        Err(DBError::SchemaMismatch(_))
+    ));
+
+    let mut db = DB::<ItemV2>::configure()
+        .data_dir(&data_dir)
+        .numeric_widening(true)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(
+        db.get(&Value::Int(1)).unwrap(),
+        Some(ItemV2 {
+            id: 1,
+            price: 10.0,
+            stock: Some(Decimal::from(1)),
+        })
+    );
+    assert_eq!(db.get(&Value::Int(2)).unwrap().unwrap().stock, None);
+    db.upsert(ItemV2 {
+        id: 4,
+        price: 15.5,
+        stock: Some(Decimal::new(25, 1)),
+    })
+    .unwrap();
+
+    // Old and new values are indexed alike
+    let ids = |items: Vec<ItemV2>| items.into_iter().map(|item| item.id).collect::<Vec<_>>();
+    assert_eq!(
+        ids(db
+            .range_by(&ItemV2Field::Price, Value::Float(10.0)..Value::Float(20.0))
+            .unwrap()),
+        vec![1, 4]
+    );
+    assert_eq!(
+        ids(db
+            .find_by(&ItemV2Field::Price, &Value::Float(30.0))
+            .unwrap()),
+        vec![3]
+    );
+    drop(db);
+
+    // The stored schema remembers the widening until the log is rewritten
+    match DB::<ItemV2>::configure().data_dir(&data_dir).initialize() {
+        Err(DBError::SchemaMismatch(mismatch)) => {
+            assert!(mismatch.to_string().contains("numeric widening"))
+        }
+        _ => panic!("Expected a schema mismatch"),
+    }
+    let db = DB::<ItemV2>::configure()
+        .data_dir(&data_dir)
+        .numeric_widening(true)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    db.migrate(MigrationPlan::<ItemV2, ItemV2>::new()).unwrap();
+
+    let mut db = DB::<ItemV2>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(db.get(&Value::Int(3)).unwrap().unwrap().price, 30.0);
+}
+
+#[derive(Debug, Clone, PartialEq, Recordable)]
+#[log_db(field_enum = PlaceField)]
+struct Place {
+    #[log_db(primary_key)]
+    id: i64,
+    name: String,
+    #[log_db(secondary_key)]
+    location: (f64, f64),
+}
+
+#[test]
+#[serial]
+fn test_find_in_bbox() {
+    let data_dir = tmp_dir();
+    let open = || {
+        DB::<Place>::configure()
+            .data_dir(&data_dir)
+            .delete_mode(DeleteMode::Soft)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let mut db = open();
+    let cities = [
+        ("Helsinki", (60.17, 24.94)),
+        ("Tallinn", (59.44, 24.75)),
+        ("Stockholm", (59.33, 18.07)),
+        ("Suva", (-18.14, 178.44)),
+        ("Apia", (-13.83, -171.77)),
+    ];
+    for (id, (name, location)) in cities.iter().enumerate() {
+        db.upsert(Place {
+            id: id as i64,
+            name: name.to_string(),
+            location: *location,
+        })
+        .unwrap();
+    }
+
+    let names = |mut places: Vec<Place>| {
+        places.sort_by_key(|place| place.id);
+        places
+            .into_iter()
+            .map(|place| place.name)
+            .collect::<Vec<_>>()
+    };
+    let location = PlaceField::Location;
+    assert_eq!(
+        names(
+            db.find_in_bbox(&location, (59.0, 24.0), (61.0, 25.5))
+                .unwrap()
+        ),
+        vec!["Helsinki", "Tallinn"]
+    );
+    // Points on the edges are in the box
+    assert_eq!(
+        names(
+            db.find_in_bbox(&location, (59.33, 18.07), (59.44, 24.75))
+                .unwrap()
+        ),
+        vec!["Tallinn", "Stockholm"]
+    );
+    // The box crosses the antimeridian
+    assert_eq!(
+        names(
+            db.find_in_bbox(&location, (-20.0, 170.0), (-10.0, -170.0))
+                .unwrap()
+        ),
+        vec!["Suva", "Apia"]
+    );
+
+    assert!(matches!(
+        db.find_in_bbox(&location, (61.0, 24.0), (59.0, 25.5)),
+        Err(DBError::ValidationError(_))
+    ));
+    assert!(matches!(
+        db.find_in_bbox(&location, (59.0, 24.0), (91.0, 25.5)),
+        Err(DBError::ValidationError(_))
+    ));
+    assert!(matches!(
+        db.find_in_bbox(&PlaceField::Name, (59.0, 24.0), (61.0, 25.5)),
+        Err(DBError::ValidationError(_))
+    ));
+    assert!(matches!(
+        db.upsert(Place {
+            id: 10,
+            name: "Nowhere".to_owned(),
+            location: (95.0, 0.0),
+        }),
+        Err(DBError::ValidationError(_))
+    ));
+
+    db.delete(&Value::Int(1)).unwrap();
+    assert_eq!(
+        names(
+            db.find_in_bbox(&location, (59.0, 24.0), (61.0, 25.5))
+                .unwrap()
+        ),
+        vec!["Helsinki"]
+    );
+    assert_eq!(
+        names(
+            db.include_deleted(|db| db.find_in_bbox(&location, (59.0, 24.0), (61.0, 25.5)))
+                .unwrap()
+        ),
+        vec!["Helsinki", "Tallinn"]
+    );
+
+    // Scans that skip around the z-order index find the same points as a brute force filter
+    for lat in -8..=8 {
+        for lon in -17..=17 {
+            db.upsert(Place {
+                id: 100 + (lat + 8) * 100 + lon + 17,
+                name: format!("{},{}", lat, lon),
+                location: (lat as f64 * 10.0, lon as f64 * 10.0),
+            })
+            .unwrap();
+        }
+    }
+    drop(db);
+    let mut db = open();
+    let all = db
+        .find_in_bbox(&location, (-90.0, -180.0), (90.0, 180.0))
+        .unwrap();
+    assert_eq!(all.len(), cities.len() - 1 + 17 * 35);
+    let boxes = [
+        ((-35.0, -55.0), (42.0, 61.0)),
+        ((0.0, 0.0), (0.0, 0.0)),
+        ((-80.0, 100.0), (-20.0, 170.0)),
+        ((10.0, 150.0), (45.0, -140.0)),
+    ];
+    for (min, max) in boxes {
+        let in_box = |&(lat, lon): &(f64, f64)| {
+            (min.0..=max.0).contains(&lat)
+                && if min.1 <= max.1 {
+                    (min.1..=max.1).contains(&lon)
+                } else {
+                    lon >= min.1 || lon <= max.1
+                }
+        };
+        let expected = all
+            .iter()
+            .filter(|place| in_box(&place.location))
+            .cloned()
+            .collect();
+        assert_eq!(
+            names(db.find_in_bbox(&location, min, max).unwrap()),
+            names(expected)
+        );
+    }
+}
+
+#[test]
+#[serial]
+fn test_check_compat() {
+    let data_dir = tmp_dir();
+    let db = DB::<ItemV1>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    drop(db);
+
+    let widened = StoredSchema::from_schema(
+        &ItemV2::schema(),
+        &ItemV2::primary_key(),
+        &ItemV2::secondary_keys(),
+    );
+    let report = DB::<ItemV1>::check_compat(&data_dir, &widened).unwrap();
+    assert!(report.is_compatible());
+    assert_eq!(
+        report.changes,
+        vec![
+            SchemaChange::FieldWidened {
+                name: "Price".to_owned(),
+                from: "int".to_owned(),
+                to: "float".to_owned(),
+            },
+            SchemaChange::FieldWidened {
+                name: "Stock".to_owned(),
+                from: "int?".to_owned(),
+                to: "decimal?".to_owned(),
+            },
+        ]
+    );
+
+    #[derive(Debug)]
+    enum Proposed {
+        Stock,
+        Id,
+        Name,
+    }
+    let proposed = StoredSchema::from_schema(
+        &[
+            (Proposed::Stock, Type::int().nullable()),
+            (Proposed::Id, Type::string()),
+            (Proposed::Name, Type::string()),
+        ],
+        &Proposed::Name,
+        &[Proposed::Stock],
+    );
+    let report = DB::<ItemV1>::check_compat(&data_dir, &proposed).unwrap();
+    assert!(!report.is_compatible());
+    assert_eq!(
+        report.changes,
+        vec![
+            SchemaChange::FieldMoved {
+                name: "Id".to_owned(),
+                from: 0,
+                to: 1,
+            },
+            SchemaChange::TypeChanged {
+                name: "Id".to_owned(),
+                from: "int".to_owned(),
+                to: "string".to_owned(),
+            },
+            SchemaChange::FieldRemoved {
+                name: "Price".to_owned(),
+            },
+            SchemaChange::FieldMoved {
+                name: "Stock".to_owned(),
+                from: 2,
+                to: 0,
+            },
+            SchemaChange::FieldAdded {
+                name: "Name".to_owned(),
+                field_type: "string".to_owned(),
+            },
+            SchemaChange::PrimaryKeyChanged {
+                from: "Id".to_owned(),
+                to: "Name".to_owned(),
+            },
+            SchemaChange::SecondaryKeyAdded {
+                name: "Stock".to_owned(),
+            },
+            SchemaChange::SecondaryKeyRemoved {
+                name: "Price".to_owned(),
+            },
+        ]
+    );
+    assert_eq!(report.breaking_changes().count(), 6);
+
+    // The proposed schema is not stored
+    let stored = StoredSchema::read(Path::new(&data_dir)).unwrap().unwrap();
+    assert_eq!(stored, report.stored);
+
+    assert!(matches!(
+        DB::<ItemV1>::check_compat(&tmp_dir(), &proposed),
+        Err(DBError::ValidationError(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn test_verify_segments() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    let inst = |id: i64| Inst {
+        id,
+        name: Some(format!("inst {}", id)),
+        data: vec![1, 2, 3],
+    };
+    for id in 0..5 {
+        db.upsert(inst(id)).unwrap();
+    }
+    db.compact(SegmentSelector::Active).unwrap();
+    for id in 5..8 {
+        db.upsert(inst(id)).unwrap();
+    }
+
+    // The active segment is verified too
+    let reports = db.verify_all().unwrap();
+    let checked: Vec<(u16, u64)> = reports
+        .iter()
+        .map(|report| (report.segment_num, report.records_checked))
+        .collect();
+    assert_eq!(checked, vec![(1, 5), (2, 3)]);
+    assert!(reports.iter().all(|report| report.problems.is_empty()));
+    assert!(matches!(
+        db.verify_segment(3),
+        Err(DBError::ValidationError(_))
+    ));
+
+    // Damage the data of the record of row 1 and the length in row 2 of the active segment
+    let metadata_path = data_dir_path.join("metadata.2");
+    let mut metadata = fs::read(&metadata_path).unwrap();
+    let row = |index: usize| 24 + index * 24;
+    let offset = u64::from_be_bytes(metadata[row(1)..row(1) + 8].try_into().unwrap()) as usize;
+    let data_uuid = uuid::Uuid::from_slice(&metadata[8..24]).unwrap();
+    let data_path = data_dir_path.join(data_uuid.to_string());
+    let mut data = fs::read(&data_path).unwrap();
+    // A byte of the version, which decodes fine but does not match the checksum
+    data[offset + 3] ^= 0xFF;
+    fs::write(&data_path, data).unwrap();
+    metadata[row(2) + 15] ^= 0x01;
+    fs::write(&metadata_path, metadata).unwrap();
+
+    let report = db.verify_segment(2).unwrap();
+    let damaged: Vec<Option<u64>> = report.problems.iter().map(|problem| problem.row).collect();
+    assert_eq!(damaged, vec![Some(2), Some(1)]);
+    assert!(report.problems[0].description.contains("Metadata row 2"));
+    assert!(report.problems[1].description.contains("checksum mismatch"));
+    assert!(db.verify_segment(1).unwrap().problems.is_empty());
+}
+
+#[test]
+#[serial]
+fn test_segment_format_upgrade() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 0..3 {
+        db.upsert(Inst {
+            id,
+            name: None,
+            data: vec![id as u8],
+        })
+        .unwrap();
+    }
+    drop(db);
+
+    // Rewrite the segment in format 2, whose metadata rows have no checksums
+    let metadata_path = data_dir_path.join("metadata.1");
+    let metadata = fs::read(&metadata_path).unwrap();
+    let mut old_metadata = metadata[..24].to_vec();
+    old_metadata[0] = 2;
+    for row in metadata[24..].chunks_exact(24) {
+        old_metadata.extend(&row[..16]);
+    }
+    fs::write(&metadata_path, old_metadata).unwrap();
+
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(db.get(&Value::Int(2)).unwrap().unwrap().data, vec![2]);
+    db.upsert(Inst {
+        id: 3,
+        name: None,
+        data: vec![],
+    })
+    .unwrap();
+
+    // The old segment was sealed, and new records are written with checksums
+    let reports = db.verify_all().unwrap();
+    let checked: Vec<(u16, u64)> = reports
+        .iter()
+        .map(|report| (report.segment_num, report.records_checked))
+        .collect();
+    assert_eq!(checked, vec![(1, 3), (2, 1)]);
+    assert!(reports.iter().all(|report| report.problems.is_empty()));
+    assert_eq!(
+        fs::read(data_dir_path.join("metadata.2")).unwrap()[0],
+        SEGMENT_FORMAT_VERSION
+    );
+
+    // Compaction rewrites the old segment in the current format
+    db.compact(SegmentSelector::All).unwrap();
+    assert_eq!(fs::read(&metadata_path).unwrap()[0], SEGMENT_FORMAT_VERSION);
+    assert_eq!(db.keys().unwrap().len(), 4);
+}
+
+#[test]
+#[serial]
+fn test_segment_format_1() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 0..3 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name {}", id)),
+            data: vec![id as u8],
+        })
+        .unwrap();
+    }
+    drop(db);
+
+    // Rewrite the segment in format 1, whose records have no version and timestamp after the flag
+    let metadata_path = data_dir_path.join("metadata.1");
+    let metadata = fs::read(&metadata_path).unwrap();
+    let data_path = data_dir_path.join(
+        uuid::Uuid::from_slice(&metadata[8..24])
+            .unwrap()
+            .to_string(),
+    );
+    let data = fs::read(&data_path).unwrap();
+    let mut old_metadata = metadata[..24].to_vec();
+    old_metadata[0] = 1;
+    let mut old_data = vec![];
+    for row in metadata[24..].chunks_exact(24) {
+        let offset = u64::from_be_bytes(row[0..8].try_into().unwrap()) as usize;
+        let length = u64::from_be_bytes(row[8..16].try_into().unwrap()) as usize;
+        let record = &data[offset..offset + length];
+        old_metadata.extend((old_data.len() as u64).to_be_bytes());
+        old_metadata.extend((length as u64 - 16).to_be_bytes());
+        old_data.push(record[0]);
+        old_data.extend(&record[1 + 16..]);
+    }
+    fs::write(&metadata_path, old_metadata).unwrap();
+    fs::write(&data_path, old_data).unwrap();
+
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    let (inst, meta) = db.get_with_meta(&Value::Int(2)).unwrap().unwrap();
+    assert_eq!(inst.name, Some("name 2".to_string()));
+    assert_eq!(inst.data, vec![2]);
+    assert_eq!(meta.version, 0);
+    assert_eq!(meta.timestamp, SystemTime::UNIX_EPOCH);
+    assert_eq!(
+        db.find_by(&Field::Name, &Value::String("name 1".to_string()))
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // New writes are versioned after the upgraded records
+    db.upsert(Inst {
+        id: 2,
+        name: None,
+        data: vec![],
+    })
+    .unwrap();
+    assert!(db.get_with_meta(&Value::Int(2)).unwrap().unwrap().1.version > 0);
+
+    // Compaction rewrites the records in the current format
+    db.compact(SegmentSelector::All).unwrap();
+    assert_eq!(fs::read(&metadata_path).unwrap()[0], SEGMENT_FORMAT_VERSION);
+    let (inst, meta) = db.get_with_meta(&Value::Int(1)).unwrap().unwrap();
+    assert_eq!(inst.data, vec![1]);
+    assert_eq!(meta.version, 0);
+    assert_eq!(db.keys().unwrap().len(), 3);
+}
+
+#[test]
+#[serial]
+fn test_segment_compression() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let open = |compression: Compression, compress_appends: bool| {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .compression(compression)
+            .compress_appends(compress_appends)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let header = |segment_num: u16| {
+        let metadata = fs::read(data_dir_path.join(format!("metadata.{}", segment_num))).unwrap();
+        let data_uuid = uuid::Uuid::from_slice(&metadata[8..24]).unwrap();
+        let data_len = fs::metadata(data_dir_path.join(data_uuid.to_string()))
+            .unwrap()
+            .len();
+        (metadata[1], data_len)
+    };
+    let inst = |id: i64, data: Vec<u8>| Inst {
+        id,
+        name: Some(format!("inst {}", id)),
+        data,
+    };
+
+    let mut db = open(Compression::Lz4, false);
+    for id in 0..20 {
+        db.upsert(inst(id, vec![id as u8; 1000])).unwrap();
+    }
+    let (_, uncompressed_len) = header(1);
+
+    // Compaction compresses the segment, while appends to the new active segment are not compressed
+    db.compact(SegmentSelector::Active).unwrap();
+    let (compression, compressed_len) = header(1);
+    assert_eq!(compression, 1);
+    assert!(compressed_len * 4 < uncompressed_len);
+    assert_eq!(header(2).0, 0);
+    assert_eq!(db.get(&Value::Int(7)).unwrap().unwrap().data, vec![7; 1000]);
+    drop(db);
+
+    // Compressed segments are read without the compression configured
+    let mut db = open(Compression::None, false);
+    assert_eq!(db.keys().unwrap().len(), 20);
+    assert_eq!(db.get(&Value::Int(3)).unwrap().unwrap().data, vec![3; 1000]);
+    drop(db);
+
+    // Appends are compressed from the next active segment on, and incompressible records are stored as they are
+    let mut db = open(Compression::Lz4, true);
+    db.upsert(inst(20, vec![20; 1000])).unwrap();
+    db.compact(SegmentSelector::Active).unwrap();
+    assert_eq!(header(3).0, 1);
+    db.upsert(inst(21, vec![21; 1000])).unwrap();
+    db.upsert(inst(22, vec![])).unwrap();
+    let metadata = fs::read(data_dir_path.join("metadata.3")).unwrap();
+    let stored_len = u64::from_be_bytes(metadata[24 + 8..24 + 16].try_into().unwrap());
+    assert!(stored_len < 100);
+    assert_eq!(
+        db.get(&Value::Int(21)).unwrap().unwrap().data,
+        vec![21; 1000]
+    );
+    assert_eq!(
+        db.get(&Value::Int(22)).unwrap().unwrap().data,
+        Vec::<u8>::new()
+    );
+
+    let reports = db.verify_all().unwrap();
+    assert_eq!(reports.len(), 3);
+    assert!(reports.iter().all(|report| report.problems.is_empty()));
+    drop(db);
+
+    #[cfg(not(feature = "zstd"))]
+    assert!(matches!(
+        DB::<Inst>::configure()
+            .data_dir(&tmp_dir())
+            .compression(Compression::Zstd(3))
+            .initialize(),
+        Err(DBError::ValidationError(_))
+    ));
+    #[cfg(feature = "zstd")]
+    {
+        let mut db = open(Compression::Zstd(3), false);
+        db.compact(SegmentSelector::All).unwrap();
+        assert_eq!(header(1).0, 2);
+        assert_eq!(db.keys().unwrap().len(), 23);
+        assert_eq!(db.get(&Value::Int(9)).unwrap().unwrap().data, vec![9; 1000]);
+    }
+}
+
+#[test]
+#[serial]
+fn test_value_compression() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let stored_lengths = |segment_num: u16| -> Vec<u64> {
+        let metadata = fs::read(data_dir_path.join(format!("metadata.{}", segment_num))).unwrap();
+        metadata[24..]
+            .chunks_exact(24)
+            .map(|row| u64::from_be_bytes(row[8..16].try_into().unwrap()))
+            .collect()
+    };
+    let large = |id: i64| Inst {
+        id,
+        name: Some("x".repeat(5000)),
+        data: vec![id as u8; 10_000],
+    };
+    let small = || Inst {
+        id: 100,
+        name: Some("small".to_owned()),
+        data: vec![1, 2, 3],
+    };
+
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .compress_values_over(1024)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    db.upsert(large(1)).unwrap();
+    db.upsert(small()).unwrap();
+    // Bytes that do not compress are stored as they are
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let noise: Vec<u8> = (0..2000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    db.upsert(Inst {
+        id: 2,
+        name: None,
+        data: noise.clone(),
+    })
+    .unwrap();
+
+    let lengths = stored_lengths(1);
+    assert!(lengths[0] < 500);
+    assert!(lengths[1] < 100);
+    assert!(lengths[2] > 2000);
+    let found = db.get(&Value::Int(1)).unwrap().unwrap();
+    assert_eq!((found.name, found.data), (large(1).name, large(1).data));
+    assert_eq!(
+        db.get(&Value::Int(100)).unwrap().unwrap().data,
+        small().data
+    );
+    assert_eq!(db.get(&Value::Int(2)).unwrap().unwrap().data, noise);
+    assert!(db.verify_all().unwrap()[0].problems.is_empty());
+
+    // Compaction keeps the values compressed
+    db.compact(SegmentSelector::Active).unwrap();
+    assert!(stored_lengths(1).iter().all(|&length| length < 2500));
+    drop(db);
+
+    // Compressed values are read without the setting
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_eq!(db.get(&Value::Int(1)).unwrap().unwrap().data, large(1).data);
+    assert_eq!(db.scan_filter(|_| true).unwrap().len(), 3);
+}
+
+/// Encrypts by XORing with the key id, which is enough to tell whether records are stored
+/// encrypted and with which key.
+struct XorProvider {
+    current: u32,
+    known: Vec<u32>,
+}
+
+impl EncryptionProvider for XorProvider {
+    fn current_key_id(&self) -> u32 {
+        self.current
+    }
+
+    fn encrypt(&self, key_id: u32, plaintext: &[u8]) -> DBResult<Vec<u8>> {
+        self.decrypt(key_id, plaintext)
+    }
+
+    fn decrypt(&self, key_id: u32, ciphertext: &[u8]) -> DBResult<Vec<u8>> {
+        if !self.known.contains(&key_id) {
+            return Err(DBError::ValidationError(format!("Unknown key {}", key_id)));
+        }
+        Ok(ciphertext.iter().map(|b| b ^ key_id as u8).collect())
+    }
+}
+
+#[test]
+#[serial]
+fn test_encryption() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let open = |provider: Option<XorProvider>| {
+        let mut config = DB::<Inst>::configure();
+        config.data_dir(&data_dir);
+        if let Some(provider) = provider {
+            config.encryption(provider);
+        }
+        config.initialize()
+    };
+    let key_id = |segment_num: u16| {
+        let metadata = fs::read(data_dir_path.join(format!("metadata.{}", segment_num))).unwrap();
+        (metadata[2] != 0).then(|| u32::from_be_bytes(metadata[3..7].try_into().unwrap()))
+    };
+    let data_contains = |needle: &[u8]| {
+        fs::read_dir(data_dir_path).unwrap().any(|entry| {
+            let path = entry.unwrap().path();
+            uuid::Uuid::parse_str(&path.file_name().unwrap().to_string_lossy()).is_ok()
+                && fs::read(&path)
+                    .unwrap()
+                    .windows(needle.len())
+                    .any(|window| window == needle)
+        })
+    };
+
+    let mut db = open(Some(XorProvider {
+        current: 1,
+        known: vec![1],
+    }))
+    .expect("Failed to initialize DB instance");
+    for id in 0..5 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("secret {}", id)),
+            data: vec![],
+        })
+        .unwrap();
+    }
+    assert_eq!(key_id(1), Some(1));
+    assert!(!data_contains(b"secret"));
+    assert_eq!(
+        db.get(&Value::Int(3)).unwrap().unwrap().name.as_deref(),
+        Some("secret 3")
+    );
+    db.compact(SegmentSelector::Active).unwrap();
+    assert!(db
+        .verify_all()
+        .unwrap()
+        .iter()
+        .all(|r| r.problems.is_empty()));
+    drop(db);
+
+    // Encrypted segments cannot be read without the provider or the key
+    assert!(matches!(open(None), Err(DBError::ValidationError(_))));
+    assert!(open(Some(XorProvider {
+        current: 2,
+        known: vec![2],
+    }))
+    .is_err());
+
+    // Rotating the key re-encrypts segments as they are compacted
+    let mut db = open(Some(XorProvider {
+        current: 2,
+        known: vec![1, 2],
+    }))
+    .expect("Old keys should still be readable");
+    db.upsert(Inst {
+        id: 5,
+        name: Some("secret 5".to_owned()),
+        data: vec![],
+    })
+    .unwrap();
+    db.compact(SegmentSelector::All).unwrap();
+    assert_eq!(
+        (key_id(1), key_id(2), key_id(3)),
+        (Some(2), Some(2), Some(2))
+    );
+    drop(db);
+
+    let mut db = open(Some(XorProvider {
+        current: 2,
+        known: vec![2],
+    }))
+    .expect("Only the new key should be needed");
+    assert_eq!(db.scan_filter(|_| true).unwrap().len(), 6);
+    assert!(!data_contains(b"secret"));
+}
+
+#[cfg(feature = "aes-gcm")]
+#[test]
+#[serial]
+fn test_aes_gcm_encryption() {
+    let data_dir = tmp_dir();
+    let open = |provider: AesGcmProvider| {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .encryption(provider)
+            .initialize()
+    };
+
+    let mut db = open(AesGcmProvider::new(1, [7; 32])).expect("Failed to initialize DB instance");
+    db.upsert(Inst {
+        id: 1,
+        name: Some("secret".to_owned()),
+        data: vec![1, 2, 3],
+    })
+    .unwrap();
+    drop(db);
+
+    assert!(matches!(
+        open(AesGcmProvider::new(1, [8; 32])),
+        Err(DBError::ConsistencyError(_))
+    ));
+    let mut db = open(AesGcmProvider::new(2, [8; 32]).with_old_key(1, [7; 32]))
+        .expect("Failed to initialize DB instance");
+    assert_eq!(db.get(&Value::Int(1)).unwrap().unwrap().data, vec![1, 2, 3]);
+    db.compact(SegmentSelector::All).unwrap();
+    drop(db);
+
+    let mut db = open(AesGcmProvider::new(2, [8; 32])).expect("Failed to initialize DB instance");
+    assert_eq!(db.get(&Value::Int(1)).unwrap().unwrap().data, vec![1, 2, 3]);
+}
+
+#[test]
+#[serial]
+fn test_group_commit() {
+    let data_dir = tmp_dir();
+    let open = |data_dir: &str| {
+        DB::<InstSingleId>::configure()
+            .data_dir(data_dir)
+            .write_durability(WriteDurability::FlushSync)
+            .group_commit_window(Duration::from_millis(5))
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    open(&data_dir);
+
+    let threads_n = 20;
+    let mut threads = vec![];
+    for i in 0..threads_n {
+        let data_dir = data_dir.clone();
+        threads.push(thread::spawn(move || {
+            let mut db = open(&data_dir);
+            db.upsert(InstSingleId { id: i })
+                .expect("Failed to upsert record");
+            db.upsert(InstSingleId { id: threads_n + i })
+                .expect("Failed to upsert record");
+            db.delete(&Value::Int(threads_n + i))
+                .expect("Failed to delete record");
+        }));
+    }
+    for thread in threads {
+        thread.join().expect("Failed to join thread");
+    }
+
+    let mut db = open(&data_dir);
+    for i in 0..threads_n {
+        assert!(db.get(&Value::Int(i)).unwrap().is_some());
+        assert!(db.get(&Value::Int(threads_n + i)).unwrap().is_none());
+    }
+
+    // The last sync covered the whole active segment
+    let synced = fs::read(Path::new(&data_dir).join("synced")).unwrap();
+    let metadata_len = fs::metadata(Path::new(&data_dir).join("metadata.1"))
+        .unwrap()
+        .len();
+    assert_eq!(synced[0..2], 1u16.to_be_bytes());
+    assert_eq!(synced[2..10], metadata_len.to_be_bytes());
+}
+
+#[test]
+#[serial]
+fn test_flush_sync_segment_files() {
+    let data_dir = tmp_dir();
+    let open = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .segment_size(200)
+            .write_durability(WriteDurability::FlushSync)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let mut db = open();
+    for id in 0..20 {
+        db.upsert(Inst {
+            id: id % 8,
+            name: Some(format!("name {}", id)),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    let sealed: Vec<u16> = (1..db.end_position().unwrap().segment_num()).collect();
+    db.merge_segments(&sealed).unwrap();
+    db.compact(SegmentSelector::All).unwrap();
+    drop(db);
+
+    // Rotations, compactions and merges sync the data directory along with the files
+    let mut db = open();
+    assert_eq!(db.keys().unwrap().len(), 8);
+    assert_eq!(
+        db.get(&Value::Int(3)).unwrap().unwrap().name.unwrap(),
+        "name 19"
+    );
+    assert!(DB::<Inst>::fsck(&data_dir, FsckMode::Check)
+        .unwrap()
+        .problems
+        .is_empty());
+}
+
+#[test]
+#[serial]
+fn test_flush_data_sync() {
+    let data_dir = tmp_dir();
+    let open = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .segment_size(200)
+            .write_durability(WriteDurability::FlushDataSync)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let mut db = open();
+    let mut other = open();
+    for id in 0..20 {
+        let handle = if id % 2 == 0 { &mut db } else { &mut other };
+        handle
+            .upsert(Inst {
+                id: id % 8,
+                name: Some(format!("name {}", id)),
+                data: vec![],
+            })
+            .unwrap();
+        handle.do_maintenance_tasks().unwrap();
+    }
+    db.compact(SegmentSelector::All).unwrap();
+    drop(db);
+    drop(other);
+
+    // Appends from both handles are synced through the shared group commit
+    let mut db = open();
+    assert_eq!(db.keys().unwrap().len(), 8);
+    assert_eq!(
+        db.get(&Value::Int(4)).unwrap().unwrap().name.unwrap(),
+        "name 12"
+    );
+    assert!(DB::<Inst>::fsck(&data_dir, FsckMode::Check)
+        .unwrap()
+        .problems
+        .is_empty());
+}
+
+#[test]
+#[serial]
+fn test_preallocate() {
+    let data_dir = tmp_dir();
+    let open = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .segment_size(4096)
+            .preallocate(true)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    // Preallocated space does not count towards the size of the files
+    #[cfg(target_os = "linux")]
+    let check_allocated = |path: &Path| {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = fs::metadata(path).unwrap();
+        assert!(metadata.blocks() * 512 >= 4096, "{:?}", path);
+        assert!(metadata.len() < 4096, "{:?}", path);
+    };
+    #[cfg(target_os = "linux")]
+    let check_active_allocated = |check_data: bool| {
+        let metadata_path = fs::canonicalize(Path::new(&data_dir).join("active")).unwrap();
+        let metadata = fs::read(&metadata_path).unwrap();
+        let uuid = uuid::Uuid::from_slice(&metadata[8..24]).unwrap();
+        check_allocated(&metadata_path);
+        if check_data {
+            check_allocated(&Path::new(&data_dir).join(uuid.to_string()));
+        }
+    };
+
+    let mut db = open();
+    #[cfg(target_os = "linux")]
+    check_active_allocated(true);
+
+    for id in 0..300 {
+        db.upsert(Inst {
+            id: id % 50,
+            name: Some(format!("name {}", id)),
+            data: vec![],
+        })
+        .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    let active_segment = db.end_position().unwrap().segment_num();
+    assert!(active_segment > 1);
+    // The data file of a rotated segment is the compacted data file, which may exceed the size
+    #[cfg(target_os = "linux")]
+    check_active_allocated(false);
+    drop(db);
+
+    let mut db = open();
+    assert_eq!(db.keys().unwrap().len(), 50);
+    assert_eq!(
+        db.get(&Value::Int(49)).unwrap().unwrap().name.unwrap(),
+        "name 299"
+    );
+    assert!(DB::<Inst>::fsck(&data_dir, FsckMode::Check)
+        .unwrap()
+        .problems
+        .is_empty());
+}
+
+#[test]
+#[serial]
+fn test_mmap_reads() {
+    let data_dir = tmp_dir();
+    let open = |mmap_reads: bool| {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .segment_size(200)
+            .mmap_reads(mmap_reads)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let upsert = |db: &mut DB<Inst>, id: i64, name: &str| {
+        db.upsert(Inst {
+            id,
+            name: Some(name.to_string()),
+            data: vec![id as u8; 3],
+        })
+        .unwrap();
+    };
+
+    let mut mapped = open(true);
+    let mut writer = open(false);
+    for id in 0..20 {
+        upsert(&mut writer, id, "first");
+        writer.do_maintenance_tasks().unwrap();
+        // Reading after each write remaps the active segment as it grows
+        assert_eq!(
+            mapped.get(&Value::Int(id)).unwrap().unwrap().data,
+            vec![id as u8; 3]
+        );
+    }
+    assert_eq!(
+        mapped
+            .find_by(&Field::Name, &Value::String("first".to_string()))
+            .unwrap()
+            .len(),
+        20
+    );
+
+    // Records moved by another handle's compaction and merges are read from the new files
+    for id in 0..10 {
+        upsert(&mut writer, id, "second");
+    }
+    writer.compact(SegmentSelector::All).unwrap();
+    let sealed: Vec<u16> = (1..writer.end_position().unwrap().segment_num()).collect();
+    writer.merge_segments(&sealed).unwrap();
+    for id in 0..20 {
+        let name = if id < 10 { "second" } else { "first" };
+        let record = mapped.get(&Value::Int(id)).unwrap().unwrap();
+        assert_eq!(record.name.unwrap(), name);
+        assert_eq!(record.data, vec![id as u8; 3]);
+    }
+
+    // The handle's own compaction replaces its maps too
+    upsert(&mut mapped, 5, "third");
+    mapped.compact(SegmentSelector::All).unwrap();
+    assert_eq!(
+        mapped.get(&Value::Int(5)).unwrap().unwrap().name.unwrap(),
+        "third"
+    );
+    assert_eq!(mapped.keys().unwrap(), writer.keys().unwrap());
+    let names = |db: &mut DB<Inst>| -> Vec<Option<String>> {
+        db.range_by(&Field::Id, Value::Int(0)..Value::Int(20))
+            .unwrap()
+            .into_iter()
+            .map(|record| record.name)
+            .collect()
+    };
+    assert_eq!(names(&mut mapped), names(&mut writer));
+}
+
+#[test]
+#[serial]
+fn test_io_uring() {
+    let data_dir = tmp_dir();
+    // Uses io_uring with the io-uring feature on Linux, and standard I/O otherwise
+    let open = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .segment_size(500)
+            .io_uring(true)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let inst = |id: i64, name: &str| Inst {
+        id,
+        name: Some(name.to_string()),
+        data: vec![id as u8; id as usize % 7],
+    };
+
+    let mut db = open();
+    for chunk in (0..100).collect::<Vec<i64>>().chunks(10) {
+        db.batch_upsert(chunk.iter().map(|&id| inst(id, "batch")).collect())
+            .unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    for id in (0..100).step_by(3) {
+        db.upsert(inst(id, "single")).unwrap();
+        db.do_maintenance_tasks().unwrap();
+    }
+    assert!(db.end_position().unwrap().segment_num() > 2);
+
+    // Lookups of more keys than are submitted at once, spread across segments
+    let ids: Vec<Value> = (0..100).map(Value::Int).collect();
+    let check = |db: &mut DB<Inst>| {
+        let found = db.batch_find_by(&Field::Id, &ids).unwrap();
+        assert_eq!(found.len(), 100);
+        for (tag, record) in found {
+            let id = tag as i64;
+            let name = if id % 3 == 0 { "single" } else { "batch" };
+            assert_eq!(record.id, id);
+            assert_eq!(record.name.unwrap(), name);
+            assert_eq!(record.data, vec![id as u8; id as usize % 7]);
+        }
+    };
+    check(&mut db);
+    db.compact(SegmentSelector::All).unwrap();
+    check(&mut db);
+    drop(db);
+
+    let mut db = open();
+    check(&mut db);
+}
+
+#[test]
+#[serial]
+fn test_compaction_strategies() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let metadata_rows = |segment_num: u16| {
+        let len = fs::metadata(data_dir_path.join(format!("metadata.{}", segment_num)))
+            .unwrap()
+            .len();
+        (len - 24) / 24
+    };
+
+    // Segments are rotated by age, however small they are
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .compaction_strategy(TimeCompaction {
+            max_age: Duration::ZERO,
+        })
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 0..10 {
+        db.upsert(Inst {
+            id,
+            name: Some("Alice".to_string()),
+            data: vec![0; 100],
+        })
+        .unwrap();
+    }
+    db.do_maintenance_tasks().unwrap();
+    assert!(data_dir_path.join("metadata.2").exists());
+    db.do_maintenance_tasks().unwrap();
+    assert!(!data_dir_path.join("metadata.3").exists());
+    drop(db);
+
+    // A sealed segment of mostly tombstones gets the earlier segments compacted, once
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .compaction_strategy(TombstoneCompaction { ratio: 0.5 })
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 0..6 {
+        db.delete(&Value::Int(id)).unwrap();
+    }
+    db.do_maintenance_tasks().unwrap();
+    assert_eq!(metadata_rows(1), 10);
+
+    db.compact(SegmentSelector::Active).unwrap();
+    db.do_maintenance_tasks().unwrap();
+    assert_eq!(metadata_rows(1), 4);
+    let compacted = fs::read(data_dir_path.join("metadata.1")).unwrap();
+    db.do_maintenance_tasks().unwrap();
+    assert_eq!(
+        fs::read(data_dir_path.join("metadata.1")).unwrap(),
+        compacted
+    );
+
+    assert_eq!(db.scan_filter(|_| true).unwrap().len(), 4);
+}
+
+#[test]
+#[serial]
+fn test_segment_limits() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let upsert = |db: &mut DB<Inst>, id: i64| {
+        db.upsert(Inst {
+            id,
+            name: Some("Alice".to_string()),
+            data: vec![],
+        })
+        .unwrap();
+    };
+
+    // Rotated by record count, however small the segment is
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .max_segment_records(5)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 0..4 {
+        upsert(&mut db, id);
+    }
+    db.do_maintenance_tasks().unwrap();
+    assert!(!data_dir_path.join("metadata.2").exists());
+    upsert(&mut db, 4);
+    db.do_maintenance_tasks().unwrap();
+    assert!(data_dir_path.join("metadata.2").exists());
+    // The new segment is empty and not rotated again
+    db.do_maintenance_tasks().unwrap();
+    assert!(!data_dir_path.join("metadata.3").exists());
+    drop(db);
+
+    // Rotated by age, with the default strategy
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .max_segment_age(Duration::from_millis(50))
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    upsert(&mut db, 5);
+    db.do_maintenance_tasks().unwrap();
+    assert!(!data_dir_path.join("metadata.3").exists());
+    thread::sleep(Duration::from_millis(60));
+    db.do_maintenance_tasks().unwrap();
+    assert!(data_dir_path.join("metadata.3").exists());
+    db.do_maintenance_tasks().unwrap();
+    assert!(!data_dir_path.join("metadata.4").exists());
+    assert_eq!(db.keys().unwrap().len(), 6);
+}
+
+static RETENTION_HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+#[test]
+#[serial]
+fn test_retention() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let open = |retention: RetentionPolicy| {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .max_segment_records(5)
+            .retention(retention)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let upsert = |db: &mut DB<Inst>, id: i64, round: usize| {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("round {}", round)),
+            data: vec![0; 100],
+        })
+        .unwrap();
+    };
+
+    // Each round rewrites all keys, so the segments of earlier rounds hold no current records and
+    // are dropped without rewriting anything
+    let mut db = open(RetentionPolicy {
+        max_segments: Some(3),
+        ..Default::default()
+    });
+    for round in 0..6 {
+        for id in 0..5 {
+            upsert(&mut db, id, round);
+        }
+        db.do_maintenance_tasks().unwrap();
+        assert!(db.end_position().unwrap().segment_num() as usize > round);
+    }
+    let segments = || {
+        fs::read_dir(data_dir_path)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_str().unwrap().starts_with("metadata.")
+            })
+            .count()
+    };
+    assert_eq!(segments(), 3);
+    let end = db.end_position().unwrap().segment_num();
+    for segment_num in end - 2..=end {
+        assert!(data_dir_path
+            .join(format!("metadata.{}", segment_num))
+            .exists());
+    }
+    // A merge would have dropped the superseded records of the oldest remaining segment
+    let oldest = data_dir_path.join(format!("metadata.{}", end - 2));
+    assert_eq!(fs::metadata(oldest).unwrap().len(), 24 + 5 * 24);
+    assert_eq!(
+        db.get(&Value::Int(2)).unwrap().unwrap().name.unwrap(),
+        "round 5"
+    );
+    drop(db);
+
+    // Segments with current records are merged instead, keeping their records
+    let mut db = open(RetentionPolicy {
+        max_segments: Some(2),
+        ..Default::default()
+    });
+    for id in 5..10 {
+        upsert(&mut db, id, 6);
+    }
+    db.do_maintenance_tasks().unwrap();
+    upsert(&mut db, 10, 7);
+    db.do_maintenance_tasks().unwrap();
+    assert_eq!(segments(), 2);
+    assert_eq!(db.keys().unwrap().len(), 11);
+    assert!(DB::<Inst>::fsck(&data_dir, FsckMode::Check)
+        .unwrap()
+        .problems
+        .is_empty());
+    drop(db);
+
+    // Live records alone exceed the size limit, which is an error without a hook
+    let too_small = RetentionPolicy {
+        max_total_bytes: Some(1000),
+        ..Default::default()
+    };
+    let mut db = open(too_small.clone());
+    let err = db.do_maintenance_tasks().unwrap_err();
+    assert!(matches!(err, DBError::QuotaExceeded(_)), "{:?}", err);
+    assert_eq!(db.keys().unwrap().len(), 11);
+    drop(db);
+
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .retention(too_small)
+        .retention_hook(|report| {
+            assert!(report.total_bytes > 1000);
+            RETENTION_HOOK_CALLS.fetch_add(1, AtomicOrdering::SeqCst);
+        })
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    db.do_maintenance_tasks().unwrap();
+    assert_eq!(RETENTION_HOOK_CALLS.load(AtomicOrdering::SeqCst), 1);
+    assert_eq!(db.keys().unwrap().len(), 11);
+}
+
+#[test]
+#[serial]
+fn test_merge_segments() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let open = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .verify_manifest(ManifestVerification::Refuse)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let upsert = |db: &mut DB<Inst>, id: i64, name: &str| {
+        db.upsert(Inst {
+            id,
+            name: Some(name.to_string()),
+            data: vec![],
+        })
+        .unwrap();
+    };
+    let names = |db: &mut DB<Inst>, name: &str| {
+        let mut ids: Vec<i64> = db
+            .find_by(&Field::Name, &Value::String(name.to_string()))
+            .unwrap()
+            .iter()
+            .map(|inst| inst.id)
+            .collect();
+        ids.sort();
+        ids
+    };
+
+    let mut db = open();
+    for id in 0..10 {
+        upsert(&mut db, id, "Alice");
+    }
+    db.compact(SegmentSelector::Active).unwrap();
+    for id in 0..5 {
+        upsert(&mut db, id, "Bob");
+    }
+    db.delete(&Value::Int(9)).unwrap();
+    db.compact(SegmentSelector::Active).unwrap();
+    db.delete(&Value::Int(8)).unwrap();
+    db.compact(SegmentSelector::Active).unwrap();
+    let mut other = open();
+    assert_eq!(names(&mut other, "Alice"), vec![5, 6, 7]);
+
+    assert!(db.merge_segments(&[1, 3]).is_err());
+    assert!(db.merge_segments(&[3, 4]).is_err());
+
+    // The superseded versions and the tombstone of 9 are dropped, and 8 is superseded by a later segment
+    let report = db.merge_segments(&[1, 2]).unwrap();
+    assert_eq!(report.segment_num, 2);
+    assert_eq!(report.records_before, 16);
+    assert_eq!(report.records_after, 8);
+    assert!(!data_dir_path.join("metadata.1").exists());
+    for db in [&mut db, &mut other] {
+        assert_eq!(
+            db.get(&Value::Int(0)).unwrap().unwrap().name.unwrap(),
+            "Bob"
+        );
+        assert!(db.get(&Value::Int(8)).unwrap().is_none());
+        assert!(db.get(&Value::Int(9)).unwrap().is_none());
+        assert_eq!(names(db, "Alice"), vec![5, 6, 7]);
+        assert_eq!(names(db, "Bob"), vec![0, 1, 2, 3, 4]);
+    }
+
+    let report = db.merge_segments(&[2, 3]).unwrap();
+    assert_eq!(report.records_after, 8);
+    drop(db);
+    drop(other);
+
+    let mut db = open();
+    assert_eq!(list_segments(data_dir_path), vec![3, 4]);
+    assert_eq!(names(&mut db, "Alice"), vec![5, 6, 7]);
+    upsert(&mut db, 10, "Carol");
+    assert_eq!(db.scan_filter(|_| true).unwrap().len(), 9);
+    drop(db);
+
+    // Tiered compaction merges pairs of equally sized segments
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .compaction_strategy(TieredCompaction { fan_in: 2 })
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    db.compact(SegmentSelector::Active).unwrap();
+    for id in 20..22 {
+        upsert(&mut db, id, "Dave");
+        db.compact(SegmentSelector::Active).unwrap();
+    }
+    assert_eq!(list_segments(data_dir_path), vec![3, 4, 5, 6, 7]);
+    db.do_maintenance_tasks().unwrap();
+    assert_eq!(list_segments(data_dir_path), vec![3, 5, 6, 7]);
+    assert_eq!(db.scan_filter(|_| true).unwrap().len(), 11);
+}
+
+fn list_segments(data_dir_path: &Path) -> Vec<u16> {
+    let mut segment_nums: Vec<u16> = fs::read_dir(data_dir_path)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            name.strip_prefix("metadata.")?.parse().ok()
+        })
+        .collect();
+    segment_nums.sort();
+    segment_nums
+}
+
+#[test]
+#[serial]
+fn test_archive() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let archive_dir = tmp_dir();
+    let open = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .max_segment_records(5)
+            .archive(
+                std::sync::Arc::new(DirectoryArchive {
+                    path: archive_dir.clone().into(),
+                }),
+                Duration::ZERO,
+            )
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let data_files = |path: &Path| {
+        let mut names: Vec<String> = fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.len() == 36)
+            .collect();
+        names.sort();
+        names
+    };
+
+    // Maintenance rotates the active segment after every five records
+    let mut db = open();
+    for id in 0..12 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name {}", id)),
+            data: vec![id as u8; 100],
+        })
+        .unwrap();
+        if id % 5 == 4 {
+            db.do_maintenance_tasks().unwrap();
+        }
+    }
+    thread::sleep(Duration::from_millis(10));
+    db.do_maintenance_tasks().unwrap();
+
+    // The data files of the sealed segments are moved to the archive
+    let archived = data_files(Path::new(&archive_dir));
+    assert!(!archived.is_empty());
+    for name in &archived {
+        assert!(!data_dir_path.join(name).exists());
+    }
+    assert!(DB::<Inst>::fsck(&data_dir, FsckMode::Check)
+        .unwrap()
+        .problems
+        .is_empty());
+    assert!(db
+        .verify_all()
+        .unwrap()
+        .iter()
+        .all(|report| report.problems.is_empty()));
+
+    // Reads fetch them back, and maintenance removes the local copies again
+    for id in 0..12 {
+        let inst = db.get(&Value::Int(id)).unwrap().unwrap();
+        assert_eq!(inst.data, vec![id as u8; 100]);
+    }
+    for name in &archived {
+        assert!(data_dir_path.join(name).exists());
+    }
+    thread::sleep(Duration::from_millis(10));
+    db.do_maintenance_tasks().unwrap();
+    assert!(!data_dir_path.join(&archived[0]).exists());
+    drop(db);
+
+    // Opening the database indexes the archived segments from their keys without fetching them,
+    // and a read fetches only the data file it needs
+    let mut db = open();
+    for name in &archived {
+        assert!(!data_dir_path.join(name).exists());
+    }
+    assert_eq!(
+        db.get(&Value::Int(3)).unwrap().unwrap().name.unwrap(),
+        "name 3"
+    );
+    assert_eq!(
+        archived
+            .iter()
+            .filter(|name| data_dir_path.join(name).exists())
+            .count(),
+        1
+    );
+    assert_eq!(
+        db.find_by(&Field::Name, &Value::String("name 7".to_owned()))
+            .unwrap()
+            .len(),
+        1
+    );
+    thread::sleep(Duration::from_millis(10));
+    db.do_maintenance_tasks().unwrap();
+
+    // A handle without the archive store cannot read archived records
+    let err = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .err()
+        .unwrap();
+    assert!(matches!(err, DBError::ValidationError(_)));
+
+    // Merging the archived segments replaces their data files, whose objects are then removed
+    let sealed: Vec<u16> = (1..db.end_position().unwrap().segment_num()).collect();
+    db.merge_segments(&sealed).unwrap();
+    db.do_maintenance_tasks().unwrap();
+    for name in &archived {
+        assert!(!Path::new(&archive_dir).join(name).exists());
+    }
+    for id in 0..12 {
+        assert!(db.get(&Value::Int(id)).unwrap().is_some());
+    }
+}
+
+#[test]
+#[serial]
+fn test_read_only() {
+    let data_dir = tmp_dir();
+    let data_dir_path = Path::new(&data_dir);
+    let open_read_only = || {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .read_only(true)
+            .initialize()
+    };
+
+    // An uninitialized data directory cannot be opened read-only
+    assert!(matches!(
+        open_read_only().err().unwrap(),
+        DBError::ValidationError(_)
+    ));
+
+    let mut db = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    for id in 0..5 {
+        db.upsert(Inst {
+            id,
+            name: Some(format!("name {}", id)),
+            data: vec![],
+        })
+        .unwrap();
+    }
+    drop(db);
+
+    // Nothing is created or written, not even the lock files
+    for filename in ["lock", "excl_lock_req", "sync_lock", "synced"] {
+        fs::remove_file(data_dir_path.join(filename)).unwrap();
+    }
+    let entries = || {
+        let mut entries: Vec<(String, u64)> = fs::read_dir(data_dir_path)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let len = entry.metadata().unwrap().len();
+                (entry.file_name().into_string().unwrap(), len)
+            })
+            .collect();
+        entries.sort();
+        entries
+    };
+    let before = entries();
+
+    let mut db = open_read_only().unwrap();
+    assert_eq!(
+        db.get(&Value::Int(3)).unwrap().unwrap().name.unwrap(),
+        "name 3"
+    );
+    let err = db
+        .upsert(Inst {
+            id: 5,
+            name: None,
+            data: vec![],
+        })
+        .unwrap_err();
+    assert!(matches!(err, DBError::ReadOnly(_)));
+    assert!(matches!(
+        db.delete(&Value::Int(1)),
+        Err(DBError::ReadOnly(_))
+    ));
+    assert!(matches!(
+        db.do_maintenance_tasks().unwrap_err(),
+        DBError::ReadOnly(_)
+    ));
+    assert!(matches!(
+        db.compact(SegmentSelector::All).unwrap_err(),
+        DBError::ReadOnly(_)
+    ));
+    assert_eq!(db.get(&Value::Int(1)).unwrap().unwrap().id, 1);
+    drop(db);
+    assert_eq!(entries(), before);
+
+    // A read-only handle sees the writes of a writable one, e.g. as a replica
+    let mut writer = DB::<Inst>::configure()
+        .data_dir(&data_dir)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    let mut db = open_read_only().unwrap();
+    writer
+        .upsert(Inst {
+            id: 5,
+            name: Some("name 5".to_owned()),
+            data: vec![],
+        })
+        .unwrap();
+    writer.do_maintenance_tasks().unwrap();
+    assert_eq!(
+        db.get(&Value::Int(5)).unwrap().unwrap().name.unwrap(),
+        "name 5"
+    );
+}
+
+#[test]
+#[serial]
+fn test_in_memory() {
+    let mut db = DB::<Inst>::configure()
+        .in_memory()
+        .segment_size(500)
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    // Nothing is written to disk
+    let data_dir = db.data_dir().to_path_buf();
+    assert!(!data_dir.exists());
+
+    for round in 0..3 {
+        for id in 0..10 {
+            db.upsert(Inst {
+                id,
+                name: Some(format!("round {}", round)),
+                data: vec![0; 50],
+            })
+            .unwrap();
+        }
+        db.do_maintenance_tasks().unwrap();
+    }
+    assert!(db.end_position().unwrap().segment_num() > 1);
+    assert_eq!(
+        db.get(&Value::Int(7)).unwrap().unwrap().name.unwrap(),
+        "round 2"
+    );
+    let other = db.collection::<Inst>("other").unwrap();
+    assert!(other.get(&Value::Int(7)).unwrap().is_none());
+
+    // Each in-memory database has data of its own
+    let mut second_db = DB::<Inst>::configure()
+        .in_memory()
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_ne!(second_db.data_dir(), data_dir);
+    assert!(second_db.get(&Value::Int(7)).unwrap().is_none());
+    second_db
+        .upsert(Inst {
+            id: 7,
+            name: Some("second".to_owned()),
+            data: vec![],
+        })
+        .unwrap();
+    assert_eq!(
+        db.get(&Value::Int(7)).unwrap().unwrap().name.unwrap(),
+        "round 2"
+    );
+    drop(db);
+    assert!(!data_dir.exists());
+
+    assert!(matches!(
+        DB::<Inst>::configure()
+            .in_memory()
+            .data_dir("db_data")
+            .initialize()
+            .err()
+            .unwrap(),
+        DBError::ValidationError(_)
+    ));
+}
+
+#[test]
+#[serial]
+fn test_ephemeral() {
+    let mut db = DB::<Inst>::configure()
+        .ephemeral()
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    let data_dir = db.data_dir().to_path_buf();
+    assert!(data_dir.starts_with(std::env::temp_dir()));
+
+    db.upsert(Inst {
+        id: 1,
+        name: Some("one".to_owned()),
+        data: vec![],
+    })
+    .unwrap();
+    assert_eq!(
+        db.get(&Value::Int(1)).unwrap().unwrap().name.unwrap(),
+        "one"
+    );
+
+    // Each handle gets a directory of its own
+    let other = DB::<Inst>::configure()
+        .ephemeral()
+        .initialize()
+        .expect("Failed to initialize DB instance");
+    assert_ne!(other.data_dir(), data_dir);
+    drop(other);
+
+    assert!(data_dir.exists());
+    drop(db);
+    assert!(!data_dir.exists());
+
+    assert!(matches!(
+        DB::<Inst>::configure()
+            .ephemeral()
+            .data_dir("db_data")
+            .initialize()
+            .err()
+            .unwrap(),
+        DBError::ValidationError(_)
+    ));
+}
+
+#[test]
+#[serial]
+fn test_compact_on_open() {
+    let data_dir = tmp_dir();
+    let open = |garbage_ratio: f64| {
+        DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .max_segment_records(10)
+            .compact_on_open(garbage_ratio)
+            .initialize()
+            .expect("Failed to initialize DB instance")
+    };
+    let segments = || {
+        let mut segments: Vec<String> = fs::read_dir(&data_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("metadata."))
+            .collect();
+        segments.sort();
+        segments
+    };
+
+    // Each round supersedes the records of the previous ones, leaving three quarters as garbage
+    let mut db = open(0.9);
+    for round in 0..4 {
+        for id in 0..10 {
+            db.upsert(Inst {
+                id,
+                name: Some(format!("round {}", round)),
+                data: vec![0; 100],
+            })
+            .unwrap();
+        }
+        db.do_maintenance_tasks().unwrap();
+    }
+    let size_before = db.disk_usage().unwrap();
+    drop(db);
+    assert_eq!(segments().len(), 5);
+
+    // Below the threshold nothing is compacted
+    drop(open(0.9));
+    assert_eq!(segments().len(), 5);
+
+    let mut db = open(0.5);
+    assert_eq!(segments().len(), 2);
+    assert!(db.disk_usage().unwrap() < size_before);
+    for id in 0..10 {
+        assert_eq!(
+            db.get(&Value::Int(id)).unwrap().unwrap().name.unwrap(),
+            "round 3"
+        );
+    }
+    drop(db);
+
+    // The compacted log has no garbage left to compact
+    let compacted = segments();
+    drop(open(0.0));
+    assert_eq!(segments(), compacted);
+}
+
+#[test]
+#[serial]
+fn test_parallel_compaction() {
+    let compact = |threads: usize| {
+        let data_dir = tmp_dir();
+        let mut db = DB::<Inst>::configure()
+            .data_dir(&data_dir)
+            .segment_size(100_000_000)
+            .compaction_threads(threads)
+            .initialize()
+            .expect("Failed to initialize DB instance");
+        // Enough rows and records for several ranges to read and batches to encode
+        for round in 0..3 {
+            db.batch_upsert(
+                (0..5000)
+                    .map(|id| Inst {
+                        id,
+                        name: Some(format!("round {} id {}", round, id)),
+                        data: vec![(id % 256) as u8; 20],
+                    })
+                    .collect(),
+            )
+            .unwrap();
+        }
+        db.delete(&Value::Int(7)).unwrap();
+        let reports = db.compact(SegmentSelector::Active).unwrap();
+        assert_eq!(reports[0].records_before, 15001);
+        assert_eq!(reports[0].records_after, 5000);
+        for id in [0, 7, 4095, 4096, 4999] {
+            let inst = db.get(&Value::Int(id)).unwrap();
+            if id == 7 {
+                assert!(inst.is_none());
+            } else {
+                let inst = inst.unwrap();
+                assert_eq!(inst.name.unwrap(), format!("round 2 id {}", id));
+                assert_eq!(inst.data, vec![(id % 256) as u8; 20]);
+            }
+        }
+        reports[0].bytes_after
+    };
+
+    // The compacted data files are laid out the same whatever the number of threads
+    assert_eq!(compact(4), compact(1));
+}
@@ -0,0 +1,736 @@
+//! The encoding of log_db record values. The codec only depends on `alloc`, so with the default
+//! `std` feature disabled it can be used in `no_std` environments, e.g. to encode records on a
+//! device and append them on a host with `DB::append_preencoded`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+#[cfg(feature = "json")]
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+use rust_decimal::Decimal;
+#[cfg(feature = "json")]
+pub use serde_json;
+
+// Serialized value tags
+pub const B_NULL: u8 = 0x0;
+pub const B_INT: u8 = 0x1;
+pub const B_DECIMAL: u8 = 0x2;
+pub const B_STRING: u8 = 0x3;
+pub const B_BYTES: u8 = 0x4;
+pub const B_TIMESTAMP: u8 = 0x5;
+pub const B_JSON: u8 = 0x6;
+pub const B_SHORT_BYTES: u8 = 0x7;
+pub const B_FLOAT: u8 = 0x8;
+pub const B_RECORD: u8 = 0x9;
+pub const B_GEO_POINT: u8 = 0xA;
+/// Set in the tag of a value stored compressed in the log. Such values are expanded before they are
+/// decoded, so the codec never sees them.
+pub const B_COMPRESSED: u8 = 0x80;
+// Tombstone marker tags
+pub const B_LIVE: u8 = 0x0;
+pub const B_MERGE: u8 = 0x1;
+pub const B_DELETED: u8 = 0x2;
+pub const B_TOMBSTONE: u8 = 0xFF;
+
+/// Maximum depth of records nested in record values. Deeper values are rejected when decoding, so
+/// that malformed input cannot exhaust the stack.
+pub const MAX_RECORD_DEPTH: usize = 32;
+
+/// Maximum length of bytes serialized with `serialize_short_bytes_into`.
+pub const SHORT_BYTES_MAX_LEN: usize = u8::MAX as usize;
+
+/// Size of the flag, version and timestamp that precede the values of a record in the log.
+pub const RECORD_HEADER_SIZE: usize = 1 + 8 + 8;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Null,
+    Int(i64),
+    Decimal(Decimal),
+    String(String),
+    Bytes(Vec<u8>),
+    /// Microseconds since the Unix epoch, negative for times before it.
+    Timestamp(i64),
+    /// A JSON document as text, see `Value::from_json` with the `json` feature.
+    Json(String),
+    /// A 64-bit floating point number. Floats compare and index by `float_order_key`, so all NaNs
+    /// are equal to each other and greater than any number, and `-0.0` equals `0.0`.
+    Float(f64),
+    /// A nested record, with its values in the order of the fields of its type.
+    Record(Vec<Value>),
+    /// A point on the globe as its latitude and longitude in degrees. Coordinates compare like
+    /// floats.
+    GeoPoint(f64, f64),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Decimal(a), Value::Decimal(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Bytes(a), Value::Bytes(b)) => a == b,
+            (Value::Timestamp(a), Value::Timestamp(b)) => a == b,
+            (Value::Json(a), Value::Json(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => float_order_key(*a) == float_order_key(*b),
+            (Value::Record(a), Value::Record(b)) => a == b,
+            (Value::GeoPoint(a_lat, a_lon), Value::GeoPoint(b_lat, b_lon)) => {
+                float_order_key(*a_lat) == float_order_key(*b_lat)
+                    && float_order_key(*a_lon) == float_order_key(*b_lon)
+            }
+            (Value::Null, Value::Null) => true,
+            _ => false,
+        }
+    }
+}
+impl Eq for Value {}
+
+impl Value {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.serialize_into(&mut bytes);
+        bytes
+    }
+
+    /// Append the serialized value to `bytes`.
+    pub fn serialize_into(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Value::Null => {
+                bytes.push(B_NULL);
+            }
+            Value::Int(i) => {
+                bytes.push(B_INT);
+                bytes.extend(i.to_be_bytes());
+            }
+            Value::Decimal(d) => {
+                bytes.push(B_DECIMAL);
+                bytes.extend(d.serialize()); // 16 bytes
+            }
+            Value::String(s) => {
+                bytes.push(B_STRING);
+                let length = s.len() as u64;
+                bytes.extend(length.to_be_bytes());
+                bytes.extend(s.as_bytes());
+            }
+            Value::Json(s) => {
+                bytes.push(B_JSON);
+                let length = s.len() as u64;
+                bytes.extend(length.to_be_bytes());
+                bytes.extend(s.as_bytes());
+            }
+            Value::Bytes(b) => {
+                bytes.push(B_BYTES);
+                let length = b.len() as u64;
+                bytes.extend(length.to_be_bytes());
+                bytes.extend(b);
+            }
+            Value::Timestamp(t) => {
+                bytes.push(B_TIMESTAMP);
+                bytes.extend(t.to_be_bytes());
+            }
+            Value::Float(f) => {
+                bytes.push(B_FLOAT);
+                bytes.extend(f.to_be_bytes());
+            }
+            Value::GeoPoint(lat, lon) => {
+                bytes.push(B_GEO_POINT);
+                bytes.extend(lat.to_be_bytes());
+                bytes.extend(lon.to_be_bytes());
+            }
+            Value::Record(values) => {
+                // The length is filled in after the values, whose size is not known in advance
+                bytes.push(B_RECORD);
+                let length_at = bytes.len();
+                bytes.extend(0u64.to_be_bytes());
+                for value in values {
+                    value.serialize_into(bytes);
+                }
+                let length = (bytes.len() - length_at - 8) as u64;
+                bytes[length_at..length_at + 8].copy_from_slice(&length.to_be_bytes());
+            }
+        }
+    }
+
+    /// Deserialize a Value from a byte slice.
+    /// Returns the deserialized Value and the number of bytes consumed.
+    pub fn deserialize(bytes: &[u8]) -> (Value, usize) {
+        let (value, consumed) = ValueRef::deserialize(bytes);
+        (value.to_value(), consumed)
+    }
+
+    pub fn as_indexable(&self) -> Option<IndexableValue> {
+        match self {
+            Value::Null => Some(IndexableValue::Null),
+            Value::Int(i) => Some(IndexableValue::Int(*i)),
+            Value::Decimal(d) => Some(IndexableValue::Decimal(*d)),
+            Value::String(s) => Some(IndexableValue::String(s.clone())),
+            Value::Timestamp(t) => Some(IndexableValue::Timestamp(*t)),
+            Value::Float(f) => Some(IndexableValue::Float(float_order_key(*f))),
+            Value::GeoPoint(lat, lon) => Some(IndexableValue::geo_point(*lat, *lon)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum IndexableValue {
+    Null,
+    Int(i64),
+    Decimal(Decimal),
+    String(String),
+    Timestamp(i64),
+    /// The `float_order_key` of a float.
+    Float(u64),
+    /// The `geo_point_key` of a point, followed by the `float_order_key`s of its latitude and
+    /// longitude, so that nearby points are mostly near each other in an index.
+    GeoPoint(u64, u64, u64),
+}
+
+impl IndexableValue {
+    pub fn geo_point(lat: f64, lon: f64) -> IndexableValue {
+        IndexableValue::GeoPoint(
+            geo_point_key(lat, lon),
+            float_order_key(lat),
+            float_order_key(lon),
+        )
+    }
+}
+
+impl From<IndexableValue> for Value {
+    fn from(value: IndexableValue) -> Self {
+        match value {
+            IndexableValue::Null => Value::Null,
+            IndexableValue::Int(i) => Value::Int(i),
+            IndexableValue::Decimal(d) => Value::Decimal(d),
+            IndexableValue::String(s) => Value::String(s),
+            IndexableValue::Timestamp(t) => Value::Timestamp(t),
+            IndexableValue::Float(key) => Value::Float(float_from_order_key(key)),
+            IndexableValue::GeoPoint(_, lat, lon) => {
+                Value::GeoPoint(float_from_order_key(lat), float_from_order_key(lon))
+            }
+        }
+    }
+}
+
+/// A `Value` borrowing its string or bytes from the buffer it was decoded from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueRef<'a> {
+    Null,
+    Int(i64),
+    Decimal(Decimal),
+    String(&'a str),
+    Bytes(&'a [u8]),
+    Timestamp(i64),
+    Json(&'a str),
+    Float(f64),
+    /// A nested record as its encoded values, which have been checked to decode.
+    Record(&'a [u8]),
+    GeoPoint(f64, f64),
+}
+
+impl<'a> ValueRef<'a> {
+    /// Deserialize a value from a byte slice without copying its contents.
+    /// Returns the value and the number of bytes consumed. Panics if the bytes are not a valid
+    /// value, see `try_deserialize` for untrusted input.
+    pub fn deserialize(bytes: &'a [u8]) -> (ValueRef<'a>, usize) {
+        ValueRef::try_deserialize(bytes).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `deserialize`, but returns an error if the bytes are not a valid value.
+    pub fn try_deserialize(bytes: &'a [u8]) -> Result<(ValueRef<'a>, usize), DecodeError> {
+        ValueRef::try_deserialize_at(bytes, 0)
+    }
+
+    fn try_deserialize_at(
+        bytes: &'a [u8],
+        depth: usize,
+    ) -> Result<(ValueRef<'a>, usize), DecodeError> {
+        let tag = *bytes.first().ok_or(DecodeError::UnexpectedEnd)?;
+        let payload = |length: usize| bytes.get(1..1 + length).ok_or(DecodeError::UnexpectedEnd);
+        let length_prefixed = || {
+            let length_bytes = payload(8)?;
+            let length = u64::from_be_bytes(length_bytes.try_into().unwrap());
+            let end = usize::try_from(length)
+                .ok()
+                .and_then(|length| length.checked_add(1 + 8))
+                .ok_or(DecodeError::UnexpectedEnd)?;
+            bytes.get(1 + 8..end).ok_or(DecodeError::UnexpectedEnd)
+        };
+
+        match tag {
+            B_NULL => Ok((ValueRef::Null, 1)),
+            B_INT => {
+                let int_bytes = payload(8)?.try_into().unwrap();
+                Ok((ValueRef::Int(i64::from_be_bytes(int_bytes)), 1 + 8))
+            }
+            B_DECIMAL => {
+                let decimal_bytes = payload(16)?.try_into().unwrap();
+                Ok((
+                    ValueRef::Decimal(Decimal::deserialize(decimal_bytes)),
+                    1 + 16,
+                ))
+            }
+            B_STRING => {
+                let string_bytes = length_prefixed()?;
+                let string =
+                    core::str::from_utf8(string_bytes).map_err(|_| DecodeError::InvalidUtf8)?;
+                Ok((ValueRef::String(string), 1 + 8 + string_bytes.len()))
+            }
+            B_JSON => {
+                let json_bytes = length_prefixed()?;
+                let json =
+                    core::str::from_utf8(json_bytes).map_err(|_| DecodeError::InvalidUtf8)?;
+                Ok((ValueRef::Json(json), 1 + 8 + json_bytes.len()))
+            }
+            B_BYTES => {
+                let bytes = length_prefixed()?;
+                Ok((ValueRef::Bytes(bytes), 1 + 8 + bytes.len()))
+            }
+            B_RECORD => {
+                if depth >= MAX_RECORD_DEPTH {
+                    return Err(DecodeError::TooDeep);
+                }
+                let record_bytes = length_prefixed()?;
+                let mut rest = record_bytes;
+                while !rest.is_empty() {
+                    let (_, consumed) = ValueRef::try_deserialize_at(rest, depth + 1)?;
+                    rest = &rest[consumed..];
+                }
+                Ok((ValueRef::Record(record_bytes), 1 + 8 + record_bytes.len()))
+            }
+            B_FLOAT => {
+                let float_bytes = payload(8)?.try_into().unwrap();
+                Ok((ValueRef::Float(f64::from_be_bytes(float_bytes)), 1 + 8))
+            }
+            B_GEO_POINT => {
+                let point_bytes = payload(16)?;
+                let lat = f64::from_be_bytes(point_bytes[..8].try_into().unwrap());
+                let lon = f64::from_be_bytes(point_bytes[8..].try_into().unwrap());
+                Ok((ValueRef::GeoPoint(lat, lon), 1 + 16))
+            }
+            B_SHORT_BYTES => {
+                let length = *payload(1)?.first().unwrap() as usize;
+                let bytes = bytes.get(2..2 + length).ok_or(DecodeError::UnexpectedEnd)?;
+                Ok((ValueRef::Bytes(bytes), 1 + 1 + length))
+            }
+            B_TIMESTAMP => {
+                let timestamp_bytes = payload(8)?.try_into().unwrap();
+                Ok((
+                    ValueRef::Timestamp(i64::from_be_bytes(timestamp_bytes)),
+                    1 + 8,
+                ))
+            }
+            _ => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            ValueRef::Null => Value::Null,
+            ValueRef::Int(i) => Value::Int(*i),
+            ValueRef::Decimal(d) => Value::Decimal(*d),
+            ValueRef::String(s) => Value::String((*s).to_owned()),
+            ValueRef::Bytes(b) => Value::Bytes(b.to_vec()),
+            ValueRef::Timestamp(t) => Value::Timestamp(*t),
+            ValueRef::Json(s) => Value::Json((*s).to_owned()),
+            ValueRef::Float(f) => Value::Float(*f),
+            ValueRef::GeoPoint(lat, lon) => Value::GeoPoint(*lat, *lon),
+            ValueRef::Record(_) => {
+                Value::Record(self.record_values().map(|v| v.to_value()).collect())
+            }
+        }
+    }
+
+    pub fn as_indexable(&self) -> Option<IndexableValue> {
+        match self {
+            ValueRef::Null => Some(IndexableValue::Null),
+            ValueRef::Int(i) => Some(IndexableValue::Int(*i)),
+            ValueRef::Decimal(d) => Some(IndexableValue::Decimal(*d)),
+            ValueRef::String(s) => Some(IndexableValue::String((*s).to_owned())),
+            ValueRef::Timestamp(t) => Some(IndexableValue::Timestamp(*t)),
+            ValueRef::Float(f) => Some(IndexableValue::Float(float_order_key(*f))),
+            ValueRef::GeoPoint(lat, lon) => Some(IndexableValue::geo_point(*lat, *lon)),
+            ValueRef::Bytes(_) | ValueRef::Json(_) | ValueRef::Record(_) => None,
+        }
+    }
+
+    /// The values of a nested record, or nothing for other values.
+    pub fn record_values(&self) -> impl Iterator<Item = ValueRef<'a>> {
+        let mut rest = match self {
+            ValueRef::Record(bytes) => *bytes,
+            _ => &[],
+        };
+        core::iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            let (value, consumed) = ValueRef::deserialize(rest);
+            rest = &rest[consumed..];
+            Some(value)
+        })
+    }
+}
+
+impl PartialEq<Value> for ValueRef<'_> {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (ValueRef::Null, Value::Null) => true,
+            (ValueRef::Int(a), Value::Int(b)) => a == b,
+            (ValueRef::Decimal(a), Value::Decimal(b)) => a == b,
+            (ValueRef::String(a), Value::String(b)) => a == b,
+            (ValueRef::Bytes(a), Value::Bytes(b)) => a == b,
+            (ValueRef::Timestamp(a), Value::Timestamp(b)) => a == b,
+            (ValueRef::Json(a), Value::Json(b)) => a == b,
+            (ValueRef::Float(a), Value::Float(b)) => float_order_key(*a) == float_order_key(*b),
+            (ValueRef::GeoPoint(a_lat, a_lon), Value::GeoPoint(b_lat, b_lon)) => {
+                float_order_key(*a_lat) == float_order_key(*b_lat)
+                    && float_order_key(*a_lon) == float_order_key(*b_lon)
+            }
+            (ValueRef::Record(_), Value::Record(b)) => {
+                self.record_values().count() == b.len()
+                    && self.record_values().zip(b).all(|(a, b)| a == *b)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Value {
+    /// A timestamp value for `time`, truncated to microseconds.
+    pub fn from_system_time(time: std::time::SystemTime) -> Value {
+        let micros = match time.duration_since(std::time::UNIX_EPOCH) {
+            Ok(since) => i64::try_from(since.as_micros()).unwrap_or(i64::MAX),
+            Err(e) => i64::try_from(e.duration().as_micros()).map_or(i64::MIN, |micros| -micros),
+        };
+        Value::Timestamp(micros)
+    }
+
+    /// The time of a timestamp value, or `None` for other values.
+    pub fn as_system_time(&self) -> Option<std::time::SystemTime> {
+        let Value::Timestamp(micros) = *self else {
+            return None;
+        };
+        let magnitude = std::time::Duration::from_micros(micros.unsigned_abs());
+        if micros >= 0 {
+            std::time::UNIX_EPOCH.checked_add(magnitude)
+        } else {
+            std::time::UNIX_EPOCH.checked_sub(magnitude)
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl Value {
+    /// A JSON value for `json`, stored as its compact text.
+    pub fn from_json(json: &serde_json::Value) -> Value {
+        Value::Json(json.to_string())
+    }
+
+    /// The parsed document of a JSON value, or `None` for other values and for text that is not
+    /// valid JSON.
+    pub fn as_json(&self) -> Option<serde_json::Value> {
+        let Value::Json(text) = self else {
+            return None;
+        };
+        serde_json::from_str(text).ok()
+    }
+}
+
+/// A key for `f` that orders floats numerically as unsigned integers, for indexing. The sign bit
+/// of a positive float is set, and all bits of a negative one are flipped, so that negative floats
+/// come first in reverse order of magnitude. All NaNs map to the key of the positive quiet NaN,
+/// which orders after positive infinity, and `-0.0` maps to the key of `0.0`.
+pub fn float_order_key(f: f64) -> u64 {
+    let f = if f.is_nan() {
+        f64::NAN
+    } else if f == 0.0 {
+        0.0
+    } else {
+        f
+    };
+    let bits = f.to_bits();
+    if bits >> 63 == 0 {
+        bits | 1 << 63
+    } else {
+        !bits
+    }
+}
+
+/// The float of a key returned by `float_order_key`.
+pub fn float_from_order_key(key: u64) -> f64 {
+    if key >> 63 == 1 {
+        f64::from_bits(key & !(1 << 63))
+    } else {
+        f64::from_bits(!key)
+    }
+}
+
+/// A z-order key of a point, which interleaves the bits of its latitude and longitude quantized to
+/// 32 bits each. Keys grow with both coordinates, so the keys of the points in a bounding box are
+/// between the keys of its corners. Coordinates out of range are clamped to the range.
+pub fn geo_point_key(lat: f64, lon: f64) -> u64 {
+    let quantize = |degrees: f64, max: f64| {
+        let fraction = ((degrees + max) / (2.0 * max)).clamp(0.0, 1.0);
+        // NaN quantizes to 0 when cast
+        (fraction * u32::MAX as f64) as u32
+    };
+    spread_bits(quantize(lat, 90.0)) << 1 | spread_bits(quantize(lon, 180.0))
+}
+
+/// Spread the bits of `x` to the even bits of a `u64`.
+fn spread_bits(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | x << 16) & 0x0000_ffff_0000_ffff;
+    x = (x | x << 8) & 0x00ff_00ff_00ff_00ff;
+    x = (x | x << 4) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | x << 2) & 0x3333_3333_3333_3333;
+    x = (x | x << 1) & 0x5555_5555_5555_5555;
+    x
+}
+
+/// The length of the value at the start of `bytes`, found from its tag and length prefix without
+/// decoding it. Values with the `B_COMPRESSED` flag are length-prefixed like strings.
+pub fn encoded_len(bytes: &[u8]) -> Result<usize, DecodeError> {
+    let tag = *bytes.first().ok_or(DecodeError::UnexpectedEnd)?;
+    let len = match tag {
+        B_NULL => 1,
+        B_INT | B_TIMESTAMP | B_FLOAT => 1 + 8,
+        B_DECIMAL | B_GEO_POINT => 1 + 16,
+        B_SHORT_BYTES => 1 + 1 + *bytes.get(1).ok_or(DecodeError::UnexpectedEnd)? as usize,
+        B_STRING | B_JSON | B_BYTES | B_RECORD => length_prefixed_len(bytes)?,
+        _ if tag & B_COMPRESSED != 0 => length_prefixed_len(bytes)?,
+        _ => return Err(DecodeError::InvalidTag(tag)),
+    };
+    if len > bytes.len() {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    Ok(len)
+}
+
+fn length_prefixed_len(bytes: &[u8]) -> Result<usize, DecodeError> {
+    let length_bytes = bytes.get(1..1 + 8).ok_or(DecodeError::UnexpectedEnd)?;
+    usize::try_from(u64::from_be_bytes(length_bytes.try_into().unwrap()))
+        .ok()
+        .and_then(|length| length.checked_add(1 + 8))
+        .ok_or(DecodeError::UnexpectedEnd)
+}
+
+/// Append bytes of at most `SHORT_BYTES_MAX_LEN` bytes to `bytes` with a one-byte length prefix.
+/// They decode into the same `Value::Bytes` as bytes serialized with `Value::serialize_into`.
+pub fn serialize_short_bytes_into(b: &[u8], bytes: &mut Vec<u8>) {
+    let length = u8::try_from(b.len()).expect("Short bytes must be at most 255 bytes long");
+    bytes.push(B_SHORT_BYTES);
+    bytes.push(length);
+    bytes.extend(b);
+}
+
+/// Encode the values of a record in schema order, as accepted by `DB::append_preencoded`.
+pub fn encode_values(values: &[Value]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for value in values {
+        value.serialize_into(&mut bytes);
+    }
+    bytes
+}
+
+/// Encode a record in the format it is stored in the log, as accepted by `DB::append_raw`. The
+/// version and timestamp of the record are left zero, since they are assigned when it is appended.
+pub fn encode_record(values: &[Value]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(RECORD_HEADER_SIZE);
+    bytes.push(B_LIVE);
+    bytes.resize(RECORD_HEADER_SIZE, 0);
+    for value in values {
+        value.serialize_into(&mut bytes);
+    }
+    bytes
+}
+
+/// Decode values encoded with `encode_values`.
+pub fn decode_values(mut bytes: &[u8]) -> Result<Vec<Value>, DecodeError> {
+    let mut values = Vec::new();
+    while !bytes.is_empty() {
+        let (value, consumed) = ValueRef::try_deserialize(bytes)?;
+        values.push(value.to_value());
+        bytes = &bytes[consumed..];
+    }
+    Ok(values)
+}
+
+/// An error decoding bytes that are not validly encoded values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes end in the middle of a value.
+    UnexpectedEnd,
+    /// A value starts with an unknown tag.
+    InvalidTag(u8),
+    /// A string value is not valid UTF-8.
+    InvalidUtf8,
+    /// Records are nested deeper than `MAX_RECORD_DEPTH`.
+    TooDeep,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "Unexpected end of encoded value"),
+            DecodeError::InvalidTag(tag) => write!(f, "Invalid tag: {}", tag),
+            DecodeError::InvalidUtf8 => write!(f, "String value is not valid UTF-8"),
+            DecodeError::TooDeep => write!(f, "Records are nested too deep"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_encode_decode_values() {
+        let values = vec![
+            Value::Null,
+            Value::Int(-3),
+            Value::Decimal(Decimal::new(12345, 2)),
+            Value::String("hello".to_owned()),
+            Value::Bytes(vec![0, 1, 2]),
+            Value::Timestamp(-1_000_001),
+            Value::Json("{\"a\": [1, null]}".to_owned()),
+            Value::Float(-2.5),
+            Value::GeoPoint(60.17, 24.94),
+            Value::Record(vec![
+                Value::String("nested".to_owned()),
+                Value::Record(vec![Value::Null, Value::Int(1)]),
+            ]),
+        ];
+        let encoded = encode_values(&values);
+        assert_eq!(decode_values(&encoded), Ok(values.clone()));
+
+        let mut rest = &encoded[..];
+        for value in &values {
+            let len = encoded_len(rest).unwrap();
+            assert_eq!(len, value.serialize().len());
+            rest = &rest[len..];
+        }
+
+        let record = encode_record(&values);
+        assert_eq!(record[0], B_LIVE);
+        assert_eq!(&record[RECORD_HEADER_SIZE..], &encoded[..]);
+
+        assert_eq!(
+            decode_values(&encoded[..encoded.len() - 1]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(decode_values(&[0x7F]), Err(DecodeError::InvalidTag(0x7F)));
+        let mut invalid_utf8 = vec![B_STRING];
+        invalid_utf8.extend(1u64.to_be_bytes());
+        invalid_utf8.push(0xFF);
+        assert_eq!(decode_values(&invalid_utf8), Err(DecodeError::InvalidUtf8));
+        let mut huge_length = vec![B_BYTES];
+        huge_length.extend(u64::MAX.to_be_bytes());
+        assert_eq!(decode_values(&huge_length), Err(DecodeError::UnexpectedEnd));
+
+        let mut too_deep = Value::Int(0);
+        for _ in 0..MAX_RECORD_DEPTH + 1 {
+            too_deep = Value::Record(vec![too_deep]);
+        }
+        assert_eq!(
+            decode_values(&too_deep.serialize()),
+            Err(DecodeError::TooDeep)
+        );
+
+        let mut short_bytes = vec![];
+        serialize_short_bytes_into(&[7; 32], &mut short_bytes);
+        assert_eq!(short_bytes.len(), 1 + 1 + 32);
+        assert_eq!(
+            decode_values(&short_bytes),
+            Ok(vec![Value::Bytes(vec![7; 32])])
+        );
+        assert_eq!(
+            decode_values(&short_bytes[..10]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_timestamp_system_time() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        for time in [
+            UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456),
+            UNIX_EPOCH - Duration::from_micros(1_500_000),
+        ] {
+            let value = Value::from_system_time(time);
+            assert_eq!(value.as_system_time(), Some(time));
+        }
+        assert_eq!(
+            Value::from_system_time(UNIX_EPOCH - Duration::from_micros(1_500_000)),
+            Value::Timestamp(-1_500_000)
+        );
+        assert!(IndexableValue::Timestamp(-1) < IndexableValue::Timestamp(0));
+        assert_eq!(Value::Int(0).as_system_time(), None);
+    }
+
+    #[test]
+    fn test_float_order_key() {
+        let floats = [
+            f64::NEG_INFINITY,
+            -1e300,
+            -1.5,
+            -f64::MIN_POSITIVE,
+            0.0,
+            f64::MIN_POSITIVE,
+            1.5,
+            1e300,
+            f64::INFINITY,
+            f64::NAN,
+        ];
+        for pair in floats.windows(2) {
+            assert!(float_order_key(pair[0]) < float_order_key(pair[1]));
+        }
+        for f in floats {
+            assert_eq!(
+                float_from_order_key(float_order_key(f)).to_bits(),
+                f.to_bits()
+            );
+        }
+        assert_eq!(float_order_key(-0.0), float_order_key(0.0));
+        assert_eq!(float_order_key(-f64::NAN), float_order_key(f64::NAN));
+        assert_eq!(Value::Float(f64::NAN), Value::Float(-f64::NAN));
+    }
+
+    #[test]
+    fn test_geo_point_key() {
+        // Keys grow with each coordinate while the other is fixed
+        let degrees = [-90.0, -45.5, -0.001, 0.0, 12.25, 89.999, 90.0];
+        for pair in degrees.windows(2) {
+            assert!(geo_point_key(pair[0], 10.0) < geo_point_key(pair[1], 10.0));
+            assert!(geo_point_key(-10.0, pair[0] * 2.0) < geo_point_key(-10.0, pair[1] * 2.0));
+        }
+        assert_eq!(geo_point_key(-90.0, -180.0), 0);
+        assert_eq!(geo_point_key(90.0, 180.0), u64::MAX);
+        assert_eq!(geo_point_key(100.0, 200.0), u64::MAX);
+
+        let point = IndexableValue::geo_point(60.17, 24.94);
+        assert_eq!(Value::from(point), Value::GeoPoint(60.17, 24.94));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        let json = serde_json::json!({"tags": ["a", "b"], "count": 2});
+        let value = Value::from_json(&json);
+        assert_eq!(value.as_json(), Some(json));
+        assert_eq!(Value::Json("{".to_owned()).as_json(), None);
+        assert_eq!(value.as_indexable(), None);
+    }
+}
@@ -0,0 +1,175 @@
+//! `#[derive(Recordable)]` for log_db, re-exported by the `log_db` crate. See the documentation of
+//! `log_db::Recordable` for the attributes it accepts.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, Ident};
+
+#[proc_macro_derive(Recordable, attributes(log_db))]
+pub fn derive_recordable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    derive(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// A field of the struct and the options given to it with `#[log_db(...)]`.
+struct RecordField {
+    ident: Ident,
+    variant: Ident,
+    field_type: proc_macro2::TokenStream,
+    primary_key: bool,
+    secondary_key: bool,
+}
+
+fn derive(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(not_a_struct(name)),
+        },
+        _ => return Err(not_a_struct(name)),
+    };
+
+    let mut field_enum = format_ident!("{}Field", name);
+    for attr in &input.attrs {
+        if !attr.path().is_ident("log_db") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("field_enum") {
+                field_enum = meta.value()?.parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("expected `field_enum = Name`"))
+            }
+        })?;
+    }
+
+    let mut record_fields = vec![];
+    for field in fields {
+        let ident = field.ident.clone().expect("Named fields have identifiers");
+        let ty = &field.ty;
+        let mut record_field = RecordField {
+            variant: Ident::new(&to_camel_case(&ident.to_string()), ident.span()),
+            ident,
+            field_type: quote!(<#ty as ::log_db::FieldValue>::field_type()),
+            primary_key: false,
+            secondary_key: false,
+        };
+
+        for attr in &field.attrs {
+            if !attr.path().is_ident("log_db") {
+                continue;
+            }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("primary_key") {
+                    record_field.primary_key = true;
+                } else if meta.path.is_ident("secondary_key") {
+                    record_field.secondary_key = true;
+                } else if meta.path.is_ident("field_type") {
+                    let field_type: Expr = meta.value()?.parse()?;
+                    record_field.field_type = quote!(#field_type);
+                } else {
+                    return Err(meta.error(
+                        "expected `primary_key`, `secondary_key` or `field_type = <Type>`",
+                    ));
+                }
+                Ok(())
+            })?;
+        }
+        record_fields.push(record_field);
+    }
+
+    let mut primary_keys = record_fields.iter().filter(|field| field.primary_key);
+    let primary_key = match (primary_keys.next(), primary_keys.next()) {
+        (Some(field), None) => &field.variant,
+        (_, Some(field)) => {
+            return Err(syn::Error::new(
+                field.ident.span(),
+                "only one field can be the primary key",
+            ))
+        }
+        (None, None) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "one field must be marked with `#[log_db(primary_key)]`",
+            ))
+        }
+    };
+
+    let vis = &input.vis;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let variants: Vec<&Ident> = record_fields.iter().map(|field| &field.variant).collect();
+    let idents: Vec<&Ident> = record_fields.iter().map(|field| &field.ident).collect();
+    let field_types = record_fields.iter().map(|field| &field.field_type);
+    let secondary_keys = record_fields
+        .iter()
+        .filter(|field| field.secondary_key)
+        .map(|field| &field.variant);
+    let invalid_field = idents.iter().map(|ident| {
+        format!(
+            "Invalid value of field {} of {}, the record does not match the schema",
+            ident, name
+        )
+    });
+
+    Ok(quote! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #vis enum #field_enum {
+            #(#variants),*
+        }
+
+        impl #impl_generics ::log_db::Recordable for #name #ty_generics #where_clause {
+            type Field = #field_enum;
+
+            fn schema() -> ::std::vec::Vec<(Self::Field, ::log_db::Type)> {
+                ::std::vec![#((#field_enum::#variants, #field_types)),*]
+            }
+            fn primary_key() -> Self::Field {
+                #field_enum::#primary_key
+            }
+            fn secondary_keys() -> ::std::vec::Vec<Self::Field> {
+                ::std::vec![#(#field_enum::#secondary_keys),*]
+            }
+
+            fn into_record(self) -> ::std::vec::Vec<::log_db::Value> {
+                ::std::vec![#(::log_db::FieldValue::into_value(self.#idents)),*]
+            }
+
+            fn from_record(record: ::std::vec::Vec<::log_db::Value>) -> Self {
+                let mut values = record.into_iter();
+                #name {
+                    #(#idents: values
+                        .next()
+                        .and_then(::log_db::FieldValue::from_value)
+                        .expect(#invalid_field)),*
+                }
+            }
+        }
+    })
+}
+
+fn not_a_struct(name: &Ident) -> syn::Error {
+    syn::Error::new(
+        name.span(),
+        "Recordable can only be derived for structs with named fields",
+    )
+}
+
+/// Convert a snake case field name into the camel case name of its variant, e.g. `created_at`
+/// into `CreatedAt`.
+fn to_camel_case(name: &str) -> String {
+    name.trim_start_matches("r#")
+        .split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}